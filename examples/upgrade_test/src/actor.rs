@@ -0,0 +1,38 @@
+use ic_cdk_macros::{init, post_upgrade, pre_upgrade, query, update};
+use ic_stable_memory::utils::upgrade_test::{self, StateFingerprint};
+use ic_stable_memory::{persist, restore};
+
+#[init]
+fn init() {
+    restore();
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    persist();
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    restore();
+}
+
+#[update]
+fn fill(seed: u64, count: u64) {
+    upgrade_test::fill(seed, count);
+}
+
+#[update]
+fn reset() {
+    upgrade_test::reset();
+}
+
+#[query]
+fn fingerprint() -> StateFingerprint {
+    upgrade_test::fingerprint()
+}
+
+#[query]
+fn verify() -> Result<(), String> {
+    upgrade_test::verify()
+}
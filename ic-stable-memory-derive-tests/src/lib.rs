@@ -23,6 +23,18 @@ mod derive_tests {
         Z { a: u64, b: u16 },
     }
 
+    #[derive(StableType, AsFixedSizeBytes, PartialEq, Eq, Debug)]
+    struct D<K, V> {
+        key: K,
+        value: Option<V>,
+    }
+
+    #[derive(StableType, AsFixedSizeBytes, PartialEq, Eq, Debug)]
+    enum E<T> {
+        None,
+        Some(T, u8),
+    }
+
     #[derive(StableType, CandidType, Deserialize, CandidAsDynSizeBytes, PartialEq, Eq, Debug)]
     struct C {
         x: u32,
@@ -78,6 +90,31 @@ mod derive_tests {
 
         assert_eq!(b_3, b_3_copy);
 
+        assert_eq!(D::<u64, u32>::SIZE, u64::SIZE + Option::<u32>::SIZE);
+
+        let d = D {
+            key: 1u64,
+            value: Some(B::Z { a: 1, b: 2 }),
+        };
+        let d_buf = d.as_new_fixed_size_bytes();
+        let d_copy = D::from_fixed_size_bytes(&d_buf);
+
+        assert_eq!(d, d_copy);
+
+        assert_eq!(E::<u16>::SIZE, u8::SIZE + u16::SIZE + u8::SIZE);
+
+        let e_1 = E::<u16>::None;
+        let e_1_buf = e_1.as_new_fixed_size_bytes();
+        let e_1_copy = E::from_fixed_size_bytes(&e_1_buf);
+
+        assert_eq!(e_1, e_1_copy);
+
+        let e_2 = E::Some(A2(1, 2, 3), 4);
+        let e_2_buf = e_2.as_new_fixed_size_bytes();
+        let e_2_copy = E::from_fixed_size_bytes(&e_2_buf);
+
+        assert_eq!(e_2, e_2_copy);
+
         let c = C {
             x: 10,
             y: 20,
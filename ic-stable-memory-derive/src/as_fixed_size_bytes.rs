@@ -1,16 +1,13 @@
+use crate::with_bound;
 use proc_macro2::{self, TokenStream};
 use quote::{format_ident, quote};
-use syn::{Data, Fields, Generics, Ident, Index};
+use syn::{parse_quote, Data, Fields, Generics, Ident, Index};
 
 pub fn derive_as_fixed_size_bytes_impl(
     ident: &Ident,
     data: &Data,
     generics: &Generics,
 ) -> TokenStream {
-    if !generics.params.is_empty() {
-        panic!("Generics not supported");
-    }
-
     let (as_fixed_size_body, from_fixed_size_body, size) = match data {
         Data::Struct(d) => {
             let mut before = quote! { 0 };
@@ -66,6 +63,10 @@ pub fn derive_as_fixed_size_bytes_impl(
             (as_fixed_size_body, from_fixed_size_body, size)
         }
         Data::Enum(d) => {
+            if d.variants.len() > u8::MAX as usize + 1 {
+                panic!("Enums with more than 256 variants not supported");
+            }
+
             let mut as_fixed_size_body_total = quote! {};
             let mut from_fixed_size_body_total = quote! {};
 
@@ -216,10 +217,19 @@ pub fn derive_as_fixed_size_bytes_impl(
         _ => panic!("Unions not supported!"),
     };
 
+    let buf = if generics.params.is_empty() {
+        quote! { [u8; Self::SIZE] }
+    } else {
+        quote! { Vec<u8> }
+    };
+
+    let generics = with_bound(generics, parse_quote!(ic_stable_memory::AsFixedSizeBytes));
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
     quote! {
-        impl ic_stable_memory::AsFixedSizeBytes for #ident {
+        impl #impl_generics ic_stable_memory::AsFixedSizeBytes for #ident #ty_generics #where_clause {
             const SIZE: usize = #size;
-            type Buf = [u8; Self::SIZE];
+            type Buf = #buf;
 
             fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
                 use ic_stable_memory::AsFixedSizeBytes;
@@ -5,14 +5,32 @@ use crate::stable_type::derive_stable_type_impl;
 use proc_macro::TokenStream as Tokens;
 use proc_macro2::{self, TokenStream};
 use quote::quote;
-use syn::{parse_macro_input, DeriveInput, Fields, Ident, Index};
+use syn::{
+    parse_macro_input, DeriveInput, Fields, GenericParam, Generics, Ident, Index, TypeParamBound,
+};
 
 mod as_fixed_size_bytes;
 mod candid_as_dyn_size_bytes;
 mod fixed_size_as_dyn_size_bytes;
 mod stable_type;
 
+/// Adds `bound` to every type parameter of `generics`, so the derived impl only applies, when all
+/// the fields implement the derived trait
+pub(crate) fn with_bound(generics: &Generics, bound: TypeParamBound) -> Generics {
+    let mut generics = generics.clone();
+
+    for param in &mut generics.params {
+        if let GenericParam::Type(param) = param {
+            param.bounds.push(bound.clone());
+        }
+    }
+
+    generics
+}
+
 /// Derives [ic_stable_memory::StableType] proxying flag toggling calls
+///
+/// Generic types are supported, each type parameter is required to implement [ic_stable_memory::StableType].
 #[proc_macro_derive(StableType)]
 pub fn derive_stable_type(input: Tokens) -> Tokens {
     let DeriveInput {
@@ -25,7 +43,12 @@ pub fn derive_stable_type(input: Tokens) -> Tokens {
     derive_stable_type_impl(&ident, &data, &generics).into()
 }
 
-/// Derives [ic_stable_memory::AsFixedSizeBytes]. Does not support generics at the moment.
+/// Derives [ic_stable_memory::AsFixedSizeBytes] for structs and enums, by concatenating the encodings of their fields.
+///
+/// Enums are encoded as a one byte variant index, followed by the fields of the variant, so they can't
+/// have more than 256 variants. Generic types are supported, each type parameter is required to implement
+/// [ic_stable_memory::AsFixedSizeBytes]. Since `[u8; Self::SIZE]` can't depend on generic parameters,
+/// a [Vec] is used as a buffer for generic types.
 #[proc_macro_derive(AsFixedSizeBytes)]
 pub fn derive_as_fixed_size_bytes(input: Tokens) -> Tokens {
     let DeriveInput {
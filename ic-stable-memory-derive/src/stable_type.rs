@@ -1,12 +1,9 @@
+use crate::with_bound;
 use proc_macro2::{self, TokenStream};
 use quote::{format_ident, quote};
-use syn::{Data, Fields, Generics, Ident, Index};
+use syn::{parse_quote, Data, Fields, Generics, Ident, Index};
 
 pub fn derive_stable_type_impl(ident: &Ident, data: &Data, generics: &Generics) -> TokenStream {
-    if !generics.params.is_empty() {
-        panic!("Generics not supported");
-    }
-
     let (flag_off_body, flag_on_body) = match data {
         Data::Struct(d) => {
             let mut flag_off_body = quote! {};
@@ -130,8 +127,11 @@ pub fn derive_stable_type_impl(ident: &Ident, data: &Data, generics: &Generics)
         _ => panic!("Unions not supported!"),
     };
 
+    let generics = with_bound(generics, parse_quote!(ic_stable_memory::StableType));
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
     quote! {
-        impl ic_stable_memory::StableType for #ident {
+        impl #impl_generics ic_stable_memory::StableType for #ident #ty_generics #where_clause {
             #[inline]
             unsafe fn stable_drop_flag_off(&mut self) {
                 #flag_off_body
@@ -0,0 +1,187 @@
+//! PocketIC harness for the upgrade round-trip test canister (`examples/upgrade_test`)
+//!
+//! Build the canister first:
+//! ```text
+//! cd ../examples/upgrade_test && cargo build --target wasm32-unknown-unknown --release
+//! ```
+//! then point `POCKET_IC_BIN` to a PocketIC server binary and run `cargo test -- --ignored`.
+//! A different canister wasm can be passed with `UPGRADE_TEST_WASM`.
+
+use candid::{decode_one, encode_args, CandidType, Deserialize, Principal};
+use pocket_ic::{PocketIc, WasmResult};
+
+pub const DEFAULT_WASM_PATH: &str =
+    "../examples/upgrade_test/target/wasm32-unknown-unknown/release/upgrade_test.wasm";
+
+/// Mirror of `ic_stable_memory::utils::upgrade_test::StateFingerprint`
+#[derive(Debug, Clone, CandidType, Deserialize, PartialEq, Eq)]
+pub struct StateFingerprint {
+    pub vec_len: u64,
+    pub log_len: u64,
+    pub btree_map_len: u64,
+    pub btree_set_len: u64,
+    pub hash_map_len: u64,
+    pub hash_set_len: u64,
+    pub contents_hash: Vec<u8>,
+    pub allocated_size: u64,
+}
+
+/// An upgrade test canister, installed into a fresh PocketIC instance
+pub struct UpgradeTestKit {
+    pub pic: PocketIc,
+    pub canister_id: Principal,
+    pub wasm: Vec<u8>,
+}
+
+impl UpgradeTestKit {
+    /// Installs the canister from `UPGRADE_TEST_WASM` (or [DEFAULT_WASM_PATH])
+    pub fn new() -> Self {
+        let path =
+            std::env::var("UPGRADE_TEST_WASM").unwrap_or_else(|_| DEFAULT_WASM_PATH.to_string());
+        let wasm = std::fs::read(&path).unwrap_or_else(|e| {
+            panic!(
+                "Unable to read the test canister at {}: {} (see the crate docs)",
+                path, e
+            )
+        });
+
+        Self::with_wasm(wasm)
+    }
+
+    /// Installs the canister from the module
+    pub fn with_wasm(wasm: Vec<u8>) -> Self {
+        let pic = PocketIc::new();
+        let canister_id = pic.create_canister();
+        pic.add_cycles(canister_id, 2_000_000_000_000);
+        pic.install_canister(canister_id, wasm.clone(), encode_args(()).unwrap(), None);
+
+        Self {
+            pic,
+            canister_id,
+            wasm,
+        }
+    }
+
+    /// Upgrades the canister to the same module, running its `pre_upgrade` and `post_upgrade`
+    pub fn upgrade(&self) {
+        self.upgrade_to(self.wasm.clone());
+    }
+
+    /// Upgrades the canister to another module, e.g. one built with another version of `ic-stable-memory`
+    pub fn upgrade_to(&self, wasm: Vec<u8>) {
+        self.pic
+            .upgrade_canister(self.canister_id, wasm, encode_args(()).unwrap(), None)
+            .expect("Upgrade failed");
+    }
+
+    pub fn fill(&self, seed: u64, count: u64) {
+        let res = self.pic.update_call(
+            self.canister_id,
+            Principal::anonymous(),
+            "fill",
+            encode_args((seed, count)).unwrap(),
+        );
+
+        reply(res);
+    }
+
+    pub fn reset(&self) {
+        let res = self.pic.update_call(
+            self.canister_id,
+            Principal::anonymous(),
+            "reset",
+            encode_args(()).unwrap(),
+        );
+
+        reply(res);
+    }
+
+    pub fn fingerprint(&self) -> StateFingerprint {
+        let res = self.pic.query_call(
+            self.canister_id,
+            Principal::anonymous(),
+            "fingerprint",
+            encode_args(()).unwrap(),
+        );
+
+        decode_one(&reply(res)).unwrap()
+    }
+
+    pub fn verify(&self) -> Result<(), String> {
+        let res = self.pic.query_call(
+            self.canister_id,
+            Principal::anonymous(),
+            "verify",
+            encode_args(()).unwrap(),
+        );
+
+        decode_one(&reply(res)).unwrap()
+    }
+
+    /// Fills the collections, upgrades the canister and checks, that nothing has changed
+    ///
+    /// Repeats `rounds` times, each time with a different seed, so the state keeps growing and
+    /// shrinking across upgrades.
+    ///
+    /// # Panics
+    /// Panics if the state after an upgrade differs from the state before it, or if it is corrupted.
+    pub fn assert_round_trips(&self, seed: u64, ops_per_round: u64, rounds: u64) {
+        for round in 0..rounds {
+            self.fill(seed.wrapping_add(round), ops_per_round);
+
+            let before = self.fingerprint();
+            self.upgrade();
+            let after = self.fingerprint();
+
+            assert_eq!(before, after, "State changed after upgrade #{}", round);
+            self.verify()
+                .unwrap_or_else(|e| panic!("Corrupted after upgrade #{}: {}", round, e));
+        }
+    }
+}
+
+impl Default for UpgradeTestKit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn reply(res: Result<WasmResult, pocket_ic::UserError>) -> Vec<u8> {
+    match res.expect("Call failed") {
+        WasmResult::Reply(bytes) => bytes,
+        WasmResult::Reject(msg) => panic!("Call rejected: {}", msg),
+    }
+}
+
+#[cfg(test)]
+mod upgrade_tests {
+    use crate::UpgradeTestKit;
+
+    #[test]
+    #[ignore = "requires POCKET_IC_BIN and a built test canister"]
+    fn collections_survive_upgrades() {
+        let kit = UpgradeTestKit::new();
+
+        let empty = kit.fingerprint();
+        kit.upgrade();
+        assert_eq!(kit.fingerprint(), empty);
+
+        kit.assert_round_trips(42, 2_000, 5);
+
+        kit.reset();
+        kit.upgrade();
+
+        let after_reset = kit.fingerprint();
+        assert_eq!(after_reset.vec_len, 0);
+        assert_eq!(after_reset.allocated_size, empty.allocated_size);
+        kit.verify().unwrap();
+    }
+
+    #[test]
+    #[ignore = "requires POCKET_IC_BIN and a built test canister"]
+    fn large_state_survives_upgrades() {
+        let kit = UpgradeTestKit::new();
+
+        kit.assert_round_trips(7, 50_000, 3);
+    }
+}
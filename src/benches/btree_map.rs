@@ -1,7 +1,8 @@
 #[cfg(test)]
 mod btree_map_benchmark {
+    use crate::benches::count_traffic;
     use crate::collections::btree_map::SBTreeMap;
-    use crate::{measure, stable, stable_memory_init};
+    use crate::{measure, stable_memory_init};
     use rand::seq::SliceRandom;
     use rand::thread_rng;
     use std::collections::BTreeMap;
@@ -40,7 +41,7 @@ mod btree_map_benchmark {
         }
 
         {
-            stable::clear();
+            count_traffic();
             stable_memory_init();
 
             let mut stable_btree_map = SBTreeMap::new();
@@ -1,8 +1,9 @@
 #[cfg(test)]
 mod certified_btree_map_benchmark {
+    use crate::benches::count_traffic;
     use crate::collections::certified_btree_map::SCertifiedBTreeMap;
     use crate::utils::certification::{leaf, AsHashTree as MyAsHashTree, AsHashableBytes};
-    use crate::{measure, stable, stable_memory_init};
+    use crate::{measure, stable_memory_init};
     use ic_certified_map::{leaf_hash, AsHashTree, Hash, HashTree, RbTree};
     use rand::seq::SliceRandom;
     use rand::thread_rng;
@@ -76,7 +77,7 @@ mod certified_btree_map_benchmark {
         }
 
         {
-            stable::clear();
+            count_traffic();
             stable_memory_init();
 
             let mut stable_certified_btree_map = SCertifiedBTreeMap::new();
@@ -136,7 +137,7 @@ mod certified_btree_map_benchmark {
         }
 
         {
-            stable::clear();
+            count_traffic();
             stable_memory_init();
 
             let mut stable_certified_btree_map = SCertifiedBTreeMap::new();
@@ -1,7 +1,8 @@
 #[cfg(test)]
 mod hash_set_benchmark {
+    use crate::benches::count_traffic;
     use crate::collections::hash_set::SHashSet;
-    use crate::{measure, stable, stable_memory_init};
+    use crate::{measure, stable_memory_init};
     use rand::seq::SliceRandom;
     use rand::thread_rng;
     use std::collections::HashSet;
@@ -40,7 +41,7 @@ mod hash_set_benchmark {
         }
 
         {
-            stable::clear();
+            count_traffic();
             stable_memory_init();
 
             let mut stable_hash_set = SHashSet::new();
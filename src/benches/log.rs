@@ -1,7 +1,7 @@
 #[cfg(test)]
 mod log_benchmark {
+    use crate::benches::count_traffic;
     use crate::collections::log::SLog;
-    use crate::{stable};
     use crate::{measure, stable_memory_init};
 
     const ITERATIONS: usize = 1_000_000;
@@ -32,7 +32,7 @@ mod log_benchmark {
         }
 
         {
-            stable::clear();
+            count_traffic();
             stable_memory_init();
 
             let mut stable_log = SLog::new();
@@ -1,3 +1,20 @@
+//! Benchmarks of collections and the [measure] harness
+//!
+//! Benchmarks themselves are `#[ignore]`-d tests. The harness can also be used from a benchmark
+//! canister, where it reports the number of executed instructions per operation.
+//!
+//! Only available with the `benches` feature.
+
+use crate::stable;
+use crate::utils::isoprint;
+#[cfg(target_family = "wasm")]
+use crate::utils::mem_context::StableMemContext;
+#[cfg(not(target_family = "wasm"))]
+use crate::utils::mem_context::TestMemContext;
+use crate::utils::mem_context::{CountingMemContext, MemAccessStats};
+use std::cell::RefCell;
+use std::rc::Rc;
+#[cfg(not(target_family = "wasm"))]
 use std::time::{SystemTime, UNIX_EPOCH};
 
 mod btree_map;
@@ -8,7 +25,40 @@ mod hash_set;
 mod log;
 mod vec;
 
+/// Results of a single [measure] block
+#[derive(Debug, Clone)]
+pub struct BenchResult {
+    pub name: &'static str,
+    pub iterations: u64,
+    pub millis: u128,
+    /// Number of executed wasm instructions, only available on a canister
+    pub instructions: Option<u64>,
+    /// Stable memory traffic, only available after [count_traffic]
+    pub traffic: Option<MemAccessStats>,
+}
+
+impl BenchResult {
+    /// Average number of instructions per iteration
+    pub fn instructions_per_op(&self) -> Option<f64> {
+        self.instructions
+            .map(|it| it as f64 / self.iterations.max(1) as f64)
+    }
+
+    /// Average number of (read, written) stable memory bytes per iteration
+    pub fn bytes_per_op(&self) -> Option<(f64, f64)> {
+        self.traffic.as_ref().map(|it| {
+            let iterations = self.iterations.max(1) as f64;
+
+            (
+                it.bytes_read as f64 / iterations,
+                it.bytes_written as f64 / iterations,
+            )
+        })
+    }
+}
+
 #[ignore]
+#[cfg(not(target_family = "wasm"))]
 pub fn now_milli() -> u128 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -16,18 +66,107 @@ pub fn now_milli() -> u128 {
         .as_millis()
 }
 
+#[ignore]
+#[cfg(target_family = "wasm")]
+pub fn now_milli() -> u128 {
+    (ic_cdk::api::time() / 1_000_000) as u128
+}
+
+thread_local! {
+    static TRAFFIC: RefCell<Option<Rc<RefCell<MemAccessStats>>>> = RefCell::new(None);
+    static RESULTS: RefCell<Vec<BenchResult>> = RefCell::new(Vec::new());
+}
+
+/// Clears stable memory and starts counting its traffic, which [measure] then reports for each block
+///
+/// On a canister, stable memory is not cleared - only its traffic is counted.
+#[ignore]
+pub fn count_traffic() {
+    #[cfg(not(target_family = "wasm"))]
+    let context = {
+        stable::clear();
+        CountingMemContext::new(TestMemContext::new())
+    };
+
+    #[cfg(target_family = "wasm")]
+    let context = CountingMemContext::new(StableMemContext);
+
+    TRAFFIC.with(|it| *it.borrow_mut() = Some(context.stats()));
+    stable::set_context(context);
+}
+
+#[ignore]
+pub fn take_traffic() -> Option<MemAccessStats> {
+    TRAFFIC.with(|it| {
+        it.borrow()
+            .as_ref()
+            .map(|stats| std::mem::take(&mut *stats.borrow_mut()))
+    })
+}
+
+/// Prints the result of a [measure] block and saves it, see [take_results]
+#[ignore]
+pub fn report(result: BenchResult) {
+    isoprint(&format!(
+        "{} {} iterations: {} ms",
+        result.name, result.iterations, result.millis
+    ));
+
+    if let (Some(instructions), Some(per_op)) = (result.instructions, result.instructions_per_op())
+    {
+        isoprint(&format!(
+            "\t{} instructions ({:.1} per op)",
+            instructions, per_op
+        ));
+    }
+
+    if let (Some(traffic), Some((read_per_op, written_per_op))) =
+        (&result.traffic, result.bytes_per_op())
+    {
+        isoprint(&format!(
+            "\t{} reads ({} bytes, {:.1} per op), {} writes ({} bytes, {:.1} per op), {} pages touched",
+            traffic.reads,
+            traffic.bytes_read,
+            read_per_op,
+            traffic.writes,
+            traffic.bytes_written,
+            written_per_op,
+            traffic.pages_touched()
+        ));
+    }
+
+    RESULTS.with(|it| it.borrow_mut().push(result));
+}
+
+/// Returns results of all [measure] blocks, executed since the previous call
+///
+/// Lets a benchmark canister return its results from a method, so regressions can be tracked in
+/// instructions, which is what the canister pays cycles for.
+#[ignore]
+pub fn take_results() -> Vec<BenchResult> {
+    RESULTS.with(|it| std::mem::take(&mut *it.borrow_mut()))
+}
+
+/// Runs the block, reporting how long it took, how many instructions it executed (on a canister)
+/// and how much stable memory traffic it caused (after [count_traffic])
 #[macro_export]
 macro_rules! measure {
     ($name:literal, $iterations:expr, $it:block) => {
+        $crate::benches::take_traffic();
         let before = $crate::benches::now_milli();
+        let instructions_before = $crate::utils::instruction_counter();
         $it;
+        let instructions_after = $crate::utils::instruction_counter();
         let after = $crate::benches::now_milli();
 
-        println!(
-            "{} {} iterations: {} ms",
-            stringify!($name),
-            $iterations,
-            after - before
-        );
+        $crate::benches::report($crate::benches::BenchResult {
+            name: stringify!($name),
+            iterations: $iterations as u64,
+            millis: after - before,
+            instructions: instructions_after
+                .zip(instructions_before)
+                .map(|(after, before)| after - before),
+            traffic: $crate::benches::take_traffic(),
+        });
     };
 }
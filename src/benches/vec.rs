@@ -1,7 +1,7 @@
 #[cfg(test)]
 mod vec_benchmark {
+    use crate::benches::count_traffic;
     use crate::collections::vec::SVec;
-    use crate::{stable};
     use crate::{measure, stable_memory_init};
 
     const ITERATIONS: usize = 1_000_000;
@@ -44,7 +44,7 @@ mod vec_benchmark {
         }
 
         {
-            stable::clear();
+            count_traffic();
             stable_memory_init();
 
             let mut stable_vec = SVec::new();
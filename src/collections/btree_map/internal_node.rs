@@ -3,11 +3,11 @@ use crate::collections::btree_map::{
     B, CAPACITY, CHILDREN_CAPACITY, CHILDREN_MIN_LEN_AFTER_SPLIT, MIN_LEN_AFTER_SPLIT,
     NODE_TYPE_INTERNAL, NODE_TYPE_OFFSET,
 };
-use crate::encoding::{AsFixedSizeBytes, Buffer};
+use crate::encoding::{AsFixedSizeBytes, Buffer, CorruptData};
 use crate::mem::{stable_ptr_buf, StablePtr, StablePtrBuf};
 use crate::primitive::StableType;
 use crate::utils::certification::{AsHashTree, AsHashableBytes, Hash, EMPTY_HASH};
-use crate::{allocate, deallocate, OutOfMemory, SSlice};
+use crate::{allocate_slot, deallocate_slot, OutOfMemory, SSlice};
 use std::borrow::Borrow;
 use std::cmp::Ordering;
 use std::fmt::Debug;
@@ -19,6 +19,7 @@ use std::marker::PhantomData;
 // children: [u64; CHILDREN_CAPACITY]
 // keys: [K; CAPACITY]
 // root_hash: Hash -- ONLY IF certified == true
+// child_counts: [u64; CHILDREN_CAPACITY] -- ONLY IF counted == true (never together with root_hash)
 
 const LEN_OFFSET: u64 = NODE_TYPE_OFFSET + u8::SIZE as u64;
 const CHILDREN_OFFSET: u64 = LEN_OFFSET + usize::SIZE as u64;
@@ -35,20 +36,24 @@ pub struct InternalBTreeNode<K> {
 
 impl<K: StableType + AsFixedSizeBytes + Ord> InternalBTreeNode<K> {
     #[inline]
-    pub const fn calc_byte_size(certified: bool) -> u64 {
+    pub const fn calc_byte_size(certified: bool, counted: bool) -> u64 {
         let mut size = root_hash_offset::<K>();
 
         if certified {
             size += Hash::SIZE as u64
         }
 
+        if counted {
+            size += (u64::SIZE * CHILDREN_CAPACITY) as u64
+        }
+
         size
     }
 
-    pub fn create_empty(certified: bool) -> Result<Self, OutOfMemory> {
-        let slice = unsafe { allocate(Self::calc_byte_size(certified))? };
+    pub fn create_empty(certified: bool, counted: bool) -> Result<Self, OutOfMemory> {
+        let ptr = unsafe { allocate_slot(Self::calc_byte_size(certified, counted))? };
         let mut it = Self {
-            ptr: slice.as_ptr(),
+            ptr,
             _marker_k: PhantomData::default(),
         };
 
@@ -63,10 +68,11 @@ impl<K: StableType + AsFixedSizeBytes + Ord> InternalBTreeNode<K> {
         lcp: &StablePtrBuf,
         rcp: &StablePtrBuf,
         certified: bool,
+        counted: bool,
     ) -> Result<Self, OutOfMemory> {
-        let slice = unsafe { allocate(Self::calc_byte_size(certified))? };
+        let ptr = unsafe { allocate_slot(Self::calc_byte_size(certified, counted))? };
         let mut it = Self {
-            ptr: slice.as_ptr(),
+            ptr,
             _marker_k: PhantomData::default(),
         };
 
@@ -83,14 +89,22 @@ impl<K: StableType + AsFixedSizeBytes + Ord> InternalBTreeNode<K> {
 
     #[inline]
     pub fn destroy(self) {
-        let slice = unsafe { SSlice::from_ptr(self.ptr).unwrap() };
-        deallocate(slice);
+        deallocate_slot(self.ptr);
     }
 
+    #[inline]
     pub fn binary_search<Q>(&self, k: &Q, len: usize) -> Result<usize, usize>
     where
         K: Borrow<Q>,
         Q: Ord + ?Sized,
+    {
+        self.binary_search_by(|key| key.borrow().cmp(k), len)
+    }
+
+    // `f` returns the ordering of the key relative to the target
+    pub fn binary_search_by<F>(&self, mut f: F, len: usize) -> Result<usize, usize>
+    where
+        F: FnMut(&K) -> Ordering,
     {
         let mut min = 0;
         let mut max = len;
@@ -101,7 +115,7 @@ impl<K: StableType + AsFixedSizeBytes + Ord> InternalBTreeNode<K> {
 
             let key: K = unsafe { crate::mem::read_fixed_for_reference(ptr) };
 
-            match key.borrow().cmp(k) {
+            match f(&key) {
                 Ordering::Equal => return Ok(mid),
                 // actually LESS
                 Ordering::Greater => {
@@ -194,28 +208,22 @@ impl<K: StableType + AsFixedSizeBytes + Ord> InternalBTreeNode<K> {
 
     pub fn split_max_len(
         &mut self,
-        buf: &mut Vec<u8>,
         certified: bool,
+        counted: bool,
     ) -> Result<(InternalBTreeNode<K>, K::Buf), OutOfMemory> {
-        let mut right = InternalBTreeNode::<K>::create_empty(certified)?;
-
-        self.read_many_keys_to_buf(B, MIN_LEN_AFTER_SPLIT, buf);
-        right.write_many_keys_from_buf(0, buf);
+        let mut right = InternalBTreeNode::<K>::create_empty(certified, counted)?;
 
-        self.read_many_child_ptrs_to_buf(B, CHILDREN_MIN_LEN_AFTER_SPLIT, buf);
-        right.write_many_child_ptrs_from_buf(0, buf);
+        self.copy_many_keys_to(B, &right, 0, MIN_LEN_AFTER_SPLIT);
+        self.copy_many_child_ptrs_to(B, &right, 0, CHILDREN_MIN_LEN_AFTER_SPLIT);
 
         Ok((right, self.read_key_buf(MIN_LEN_AFTER_SPLIT)))
     }
 
-    pub fn merge_min_len(&mut self, mid: &K::Buf, right: InternalBTreeNode<K>, buf: &mut Vec<u8>) {
+    pub fn merge_min_len(&mut self, mid: &K::Buf, right: InternalBTreeNode<K>) {
         self.push_key_buf(mid, MIN_LEN_AFTER_SPLIT);
 
-        right.read_many_keys_to_buf(0, MIN_LEN_AFTER_SPLIT, buf);
-        self.write_many_keys_from_buf(B, buf);
-
-        right.read_many_child_ptrs_to_buf(0, CHILDREN_MIN_LEN_AFTER_SPLIT, buf);
-        self.write_many_child_ptrs_from_buf(B, buf);
+        right.copy_many_keys_to(0, self, B, MIN_LEN_AFTER_SPLIT);
+        right.copy_many_child_ptrs_to(0, self, B, CHILDREN_MIN_LEN_AFTER_SPLIT);
 
         right.destroy();
     }
@@ -278,26 +286,6 @@ impl<K: StableType + AsFixedSizeBytes + Ord> InternalBTreeNode<K> {
         self.write_many_child_ptrs_from_buf(idx, buf);
     }
 
-    pub(crate) fn read_left_sibling<T: IBTreeNode>(&self, idx: usize) -> Option<T> {
-        if idx == 0 {
-            return None;
-        }
-
-        let left_sibling_ptr = StablePtr::from_fixed_size_bytes(&self.read_child_ptr_buf(idx - 1));
-
-        unsafe { Some(T::from_ptr(left_sibling_ptr)) }
-    }
-
-    pub(crate) fn read_right_sibling<T: IBTreeNode>(&self, idx: usize, len: usize) -> Option<T> {
-        if idx == len {
-            return None;
-        }
-
-        let right_sibling_ptr = StablePtr::from_fixed_size_bytes(&self.read_child_ptr_buf(idx + 1));
-
-        unsafe { Some(T::from_ptr(right_sibling_ptr)) }
-    }
-
     #[inline]
     pub fn read_key_buf(&self, idx: usize) -> K::Buf {
         let mut b = K::Buf::new(K::SIZE);
@@ -372,6 +360,22 @@ impl<K: StableType + AsFixedSizeBytes + Ord> InternalBTreeNode<K> {
         unsafe { crate::mem::write_bytes(ptr, buf) };
     }
 
+    #[inline]
+    fn copy_many_keys_to(&self, from_idx: usize, to: &Self, to_idx: usize, len: usize) {
+        let from = SSlice::_offset(self.ptr, KEYS_OFFSET + (from_idx * K::SIZE) as u64);
+        let to = SSlice::_offset(to.ptr, KEYS_OFFSET + (to_idx * K::SIZE) as u64);
+
+        unsafe { crate::mem::copy_bytes(from, to, (len * K::SIZE) as u64) };
+    }
+
+    #[inline]
+    fn copy_many_child_ptrs_to(&self, from_idx: usize, to: &Self, to_idx: usize, len: usize) {
+        let from = SSlice::_offset(self.ptr, CHILDREN_OFFSET + (from_idx * u64::SIZE) as u64);
+        let to = SSlice::_offset(to.ptr, CHILDREN_OFFSET + (to_idx * u64::SIZE) as u64);
+
+        unsafe { crate::mem::copy_bytes(from, to, (len * u64::SIZE) as u64) };
+    }
+
     #[inline]
     pub fn write_root_hash(&mut self, root_hash: &Hash, certified: bool) {
         debug_assert!(certified);
@@ -391,6 +395,23 @@ impl<K: StableType + AsFixedSizeBytes + Ord> InternalBTreeNode<K> {
         buf
     }
 
+    // counts share the offset with the root hash, since a counted map is never certified
+    #[inline]
+    pub fn write_child_count(&mut self, idx: usize, mut count: u64, counted: bool) {
+        debug_assert!(counted);
+
+        let ptr = SSlice::_offset(self.ptr, root_hash_offset::<K>() + (idx * u64::SIZE) as u64);
+        unsafe { crate::mem::write_fixed(ptr, &mut count) };
+    }
+
+    #[inline]
+    pub fn read_child_count(&self, idx: usize, counted: bool) -> u64 {
+        debug_assert!(counted);
+
+        let ptr = SSlice::_offset(self.ptr, root_hash_offset::<K>() + (idx * u64::SIZE) as u64);
+        unsafe { crate::mem::read_fixed_for_reference(ptr) }
+    }
+
     #[inline]
     pub fn write_len(&mut self, mut len: usize) {
         let ptr = SSlice::_offset(self.ptr, LEN_OFFSET);
@@ -405,6 +426,23 @@ impl<K: StableType + AsFixedSizeBytes + Ord> InternalBTreeNode<K> {
         unsafe { crate::mem::read_fixed_for_reference(ptr) }
     }
 
+    // decodes the length and all keys of this node, returns the length
+    pub fn try_validate(&self) -> Result<usize, CorruptData> {
+        let len_ptr = SSlice::_offset(self.ptr, LEN_OFFSET);
+        let len: usize = unsafe { crate::mem::try_read_fixed_for_reference(len_ptr)? };
+
+        if len == 0 || len > CAPACITY {
+            return Err(CorruptData::new(len_ptr, "Invalid B-tree node length"));
+        }
+
+        for idx in 0..len {
+            let ptr = SSlice::_offset(self.ptr, KEYS_OFFSET + (idx * K::SIZE) as u64);
+            unsafe { crate::mem::try_read_fixed_for_reference::<K>(ptr)? };
+        }
+
+        Ok(len)
+    }
+
     #[inline]
     fn init_node_type(&mut self) {
         let ptr = SSlice::_offset(self.ptr, NODE_TYPE_OFFSET);
@@ -527,7 +565,7 @@ mod tests {
             println!("{}", node.to_string());
             println!();
 
-            let (mut right, mid) = node.split_max_len(&mut buf, false).unwrap();
+            let (mut right, mid) = node.split_max_len(false, false).unwrap();
 
             node.write_len(MIN_LEN_AFTER_SPLIT);
             right.write_len(MIN_LEN_AFTER_SPLIT);
@@ -561,7 +599,7 @@ mod tests {
             let c = right.read_child_ptr_buf(CHILDREN_MIN_LEN_AFTER_SPLIT - 1);
             assert_eq!(c, 1u64.as_new_fixed_size_bytes());
 
-            node.merge_min_len(&mid, right, &mut buf);
+            node.merge_min_len(&mid, right);
 
             node.write_len(CAPACITY);
             assert_eq!(node.read_len(), CAPACITY);
@@ -3,6 +3,7 @@ use crate::collections::btree_map::{BTreeNode, IBTreeNode, SBTreeMap};
 use crate::encoding::AsFixedSizeBytes;
 use crate::primitive::s_ref::SRef;
 use crate::primitive::StableType;
+use std::marker::PhantomData;
 
 pub struct SBTreeMapIter<'a, K, V> {
     root: &'a Option<BTreeNode<K, V>>,
@@ -138,3 +139,124 @@ impl<'a, K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeByte
         }
     }
 }
+
+pub struct SBTreeMapRangeIter<'a, K, V> {
+    front: Option<LeafBTreeNode<K, V>>,
+    front_idx: usize,
+    front_len: usize,
+    back: Option<LeafBTreeNode<K, V>>,
+    back_idx: usize,
+    _marker: PhantomData<&'a SBTreeMap<K, V>>,
+}
+
+impl<'a, K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes>
+    SBTreeMapRangeIter<'a, K, V>
+{
+    // both positions are leaf-index pairs: `front` points to the next element to return from the
+    // front, `back` points right after the next element to return from the back
+    #[inline]
+    pub(crate) fn new(
+        front: Option<(LeafBTreeNode<K, V>, usize)>,
+        back: Option<(LeafBTreeNode<K, V>, usize)>,
+    ) -> Self {
+        let (front, front_idx, front_len) = match front {
+            Some((node, idx)) => {
+                let len = node.read_len();
+                (Some(node), idx, len)
+            }
+            None => (None, 0, 0),
+        };
+
+        let (back, back_idx) = match back {
+            Some((node, idx)) => (Some(node), idx),
+            None => (None, 0),
+        };
+
+        Self {
+            front,
+            front_idx,
+            front_len,
+            back,
+            back_idx,
+            _marker: PhantomData::default(),
+        }
+    }
+
+    #[inline]
+    fn is_finished(&self) -> bool {
+        match (&self.front, &self.back) {
+            (Some(f), Some(b)) => f.as_ptr() == b.as_ptr() && self.front_idx == self.back_idx,
+            _ => true,
+        }
+    }
+}
+
+impl<'a, K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> Iterator
+    for SBTreeMapRangeIter<'a, K, V>
+{
+    type Item = (SRef<'a, K>, SRef<'a, V>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.is_finished() {
+                return None;
+            }
+
+            let node = self.front.as_ref()?;
+
+            if self.front_idx == self.front_len {
+                let ptr = u64::from_fixed_size_bytes(&node.read_next_ptr_buf());
+
+                if ptr == 0 {
+                    return None;
+                }
+
+                let new_node = unsafe { LeafBTreeNode::<K, V>::from_ptr(ptr) };
+
+                self.front_len = new_node.read_len();
+                self.front_idx = 0;
+                self.front = Some(new_node);
+
+                continue;
+            }
+
+            let res = (node.get_key(self.front_idx), node.get_value(self.front_idx));
+            self.front_idx += 1;
+
+            return Some(res);
+        }
+    }
+}
+
+impl<'a, K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes>
+    DoubleEndedIterator for SBTreeMapRangeIter<'a, K, V>
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.is_finished() {
+                return None;
+            }
+
+            let node = self.back.as_ref()?;
+
+            if self.back_idx == 0 {
+                let ptr = u64::from_fixed_size_bytes(&node.read_prev_ptr_buf());
+
+                if ptr == 0 {
+                    return None;
+                }
+
+                let new_node = unsafe { LeafBTreeNode::<K, V>::from_ptr(ptr) };
+
+                self.back_idx = new_node.read_len();
+                self.back = Some(new_node);
+
+                continue;
+            }
+
+            self.back_idx -= 1;
+
+            return Some((node.get_key(self.back_idx), node.get_value(self.back_idx)));
+        }
+    }
+}
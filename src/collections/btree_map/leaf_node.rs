@@ -2,13 +2,13 @@ use crate::collections::btree_map::internal_node::InternalBTreeNode;
 use crate::collections::btree_map::{
     IBTreeNode, B, CAPACITY, MIN_LEN_AFTER_SPLIT, NODE_TYPE_LEAF, NODE_TYPE_OFFSET,
 };
-use crate::encoding::{AsFixedSizeBytes, Buffer};
+use crate::encoding::{AsFixedSizeBytes, Buffer, CorruptData};
 use crate::mem::{stable_ptr_buf, StablePtrBuf};
 use crate::primitive::s_ref::SRef;
 use crate::primitive::s_ref_mut::SRefMut;
 use crate::primitive::StableType;
 use crate::utils::certification::{Hash, EMPTY_HASH};
-use crate::{allocate, deallocate, OutOfMemory, SSlice};
+use crate::{allocate_slot, deallocate_slot, OutOfMemory, SSlice};
 use std::borrow::Borrow;
 use std::cmp::Ordering;
 use std::fmt::Debug;
@@ -53,8 +53,8 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> L
     }
 
     pub fn create(certified: bool) -> Result<Self, OutOfMemory> {
-        let slice = unsafe { allocate(Self::calc_size_bytes(certified))? };
-        let mut it = unsafe { Self::from_ptr(slice.as_ptr()) };
+        let ptr = unsafe { allocate_slot(Self::calc_size_bytes(certified))? };
+        let mut it = unsafe { Self::from_ptr(ptr) };
 
         it.init_node_type();
         it.write_len(0);
@@ -68,14 +68,22 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> L
 
     #[inline]
     pub fn destroy(self) {
-        let slice = unsafe { SSlice::from_ptr(self.ptr).unwrap() };
-        deallocate(slice);
+        deallocate_slot(self.ptr);
     }
 
+    #[inline]
     pub fn binary_search<Q>(&self, k: &Q, len: usize) -> Result<usize, usize>
     where
         K: Borrow<Q>,
         Q: Ord + ?Sized,
+    {
+        self.binary_search_by(|key| key.borrow().cmp(k), len)
+    }
+
+    // `f` returns the ordering of the key relative to the target
+    pub fn binary_search_by<F>(&self, mut f: F, len: usize) -> Result<usize, usize>
+    where
+        F: FnMut(&K) -> Ordering,
     {
         if len == 0 {
             return Err(0);
@@ -89,7 +97,7 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> L
             let ptr = SSlice::_offset(self.ptr, KEYS_OFFSET + (mid * K::SIZE) as u64);
             let key: K = unsafe { crate::mem::read_fixed_for_reference(ptr) };
 
-            match key.borrow().cmp(k) {
+            match f(&key) {
                 Ordering::Equal => return Ok(mid),
                 // actually LESS
                 Ordering::Greater => {
@@ -178,18 +186,14 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> L
     pub fn split_max_len(
         &mut self,
         right_biased: bool,
-        buf: &mut Vec<u8>,
         certified: bool,
     ) -> Result<Self, OutOfMemory> {
         let mut right = Self::create(certified)?;
 
         let min_idx = if right_biased { MIN_LEN_AFTER_SPLIT } else { B };
 
-        self.read_many_keys_to_buf(min_idx, CAPACITY - min_idx, buf);
-        right.write_many_keys_from_buf(0, buf);
-
-        self.read_many_values_to_buf(min_idx, CAPACITY - min_idx, buf);
-        right.write_many_values_from_buf(0, buf);
+        self.copy_many_keys_to(min_idx, &right, 0, CAPACITY - min_idx);
+        self.copy_many_values_to(min_idx, &right, 0, CAPACITY - min_idx);
 
         let self_next = self.read_next_ptr_buf();
         let mut buf = <u64 as AsFixedSizeBytes>::Buf::new(<u64 as AsFixedSizeBytes>::SIZE);
@@ -204,12 +208,9 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> L
         Ok(right)
     }
 
-    pub fn merge_min_len(&mut self, right: Self, buf: &mut Vec<u8>) {
-        right.read_many_keys_to_buf(0, MIN_LEN_AFTER_SPLIT, buf);
-        self.write_many_keys_from_buf(MIN_LEN_AFTER_SPLIT, buf);
-
-        right.read_many_values_to_buf(0, MIN_LEN_AFTER_SPLIT, buf);
-        self.write_many_values_from_buf(MIN_LEN_AFTER_SPLIT, buf);
+    pub fn merge_min_len(&mut self, right: Self) {
+        right.copy_many_keys_to(0, self, MIN_LEN_AFTER_SPLIT, MIN_LEN_AFTER_SPLIT);
+        right.copy_many_values_to(0, self, MIN_LEN_AFTER_SPLIT, MIN_LEN_AFTER_SPLIT);
 
         let right_next_buf = right.read_next_ptr_buf();
         self.write_next_ptr_buf(&right_next_buf);
@@ -332,6 +333,17 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> L
         unsafe { crate::mem::write_bytes(self.get_key_ptr(from_idx), buf) };
     }
 
+    #[inline]
+    fn copy_many_keys_to(&self, from_idx: usize, to: &Self, to_idx: usize, len: usize) {
+        unsafe {
+            crate::mem::copy_bytes(
+                self.get_key_ptr(from_idx),
+                to.get_key_ptr(to_idx),
+                (len * K::SIZE) as u64,
+            )
+        };
+    }
+
     #[inline]
     fn get_key_ptr(&self, idx: usize) -> u64 {
         SSlice::_offset(self.ptr, KEYS_OFFSET + (idx * K::SIZE) as u64)
@@ -374,6 +386,17 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> L
         unsafe { crate::mem::write_bytes(self.get_value_ptr(from_idx), buf) };
     }
 
+    #[inline]
+    fn copy_many_values_to(&self, from_idx: usize, to: &Self, to_idx: usize, len: usize) {
+        unsafe {
+            crate::mem::copy_bytes(
+                self.get_value_ptr(from_idx),
+                to.get_value_ptr(to_idx),
+                (len * V::SIZE) as u64,
+            )
+        };
+    }
+
     #[inline]
     fn get_value_ptr(&self, idx: usize) -> u64 {
         SSlice::_offset(self.ptr, values_offset::<K>() + (idx * V::SIZE) as u64)
@@ -473,6 +496,25 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> L
         unsafe { crate::mem::read_fixed_for_reference(ptr) }
     }
 
+    // decodes the length and all keys and values of this node, returns the length
+    pub fn try_validate(&self) -> Result<usize, CorruptData> {
+        let len_ptr = SSlice::_offset(self.ptr, LEN_OFFSET);
+        let len: usize = unsafe { crate::mem::try_read_fixed_for_reference(len_ptr)? };
+
+        if len > CAPACITY {
+            return Err(CorruptData::new(len_ptr, "Invalid B-tree node length"));
+        }
+
+        for idx in 0..len {
+            unsafe {
+                crate::mem::try_read_fixed_for_reference::<K>(self.get_key_ptr(idx))?;
+                crate::mem::try_read_fixed_for_reference::<V>(self.get_value_ptr(idx))?;
+            }
+        }
+
+        Ok(len)
+    }
+
     #[inline]
     fn init_node_type(&mut self) {
         let ptr = SSlice::_offset(self.ptr, NODE_TYPE_OFFSET);
@@ -583,7 +625,7 @@ mod tests {
                 node.insert_value_buf(i, &v, CAPACITY - 1, &mut buf);
             }
 
-            let right = node.split_max_len(true, &mut buf, false).unwrap();
+            let right = node.split_max_len(true, false).unwrap();
 
             for i in 0..MIN_LEN_AFTER_SPLIT {
                 let k = node.read_key_buf(i);
@@ -607,7 +649,7 @@ mod tests {
                 );
             }
 
-            node.merge_min_len(right, &mut buf);
+            node.merge_min_len(right);
 
             for i in 0..CAPACITY {
                 let k = node.read_key_buf(i);
@@ -1,18 +1,43 @@
 use crate::collections::btree_map::internal_node::InternalBTreeNode;
-use crate::collections::btree_map::iter::SBTreeMapIter;
+use crate::collections::btree_map::iter::{SBTreeMapIter, SBTreeMapRangeIter};
 use crate::collections::btree_map::leaf_node::LeafBTreeNode;
-use crate::encoding::AsFixedSizeBytes;
+use crate::collections::btree_map::prefix::KeyPrefix;
+use crate::collections::btree_map::shadow::ShadowState;
+use crate::encoding::{AsDynSizeBytes, AsFixedSizeBytes, Buffer, CorruptData};
 use crate::mem::allocator::EMPTY_PTR;
-use crate::mem::free_block::FreeBlock;
+#[cfg(feature = "orphan_collector")]
+use crate::mem::orphan_collector::{memory_usage, MemoryUsage, Trace};
 use crate::mem::{StablePtr, StablePtrBuf};
+use crate::primitive::s_box::SBox;
 use crate::primitive::s_ref::SRef;
 use crate::primitive::s_ref_mut::SRefMut;
-use crate::primitive::StableType;
+use crate::primitive::{DeepCopy, StableType};
+use crate::utils::budget::{Budget, ResumableIter};
+#[cfg(feature = "candid_chunks")]
+use crate::utils::candid_chunks::{encode_chunks, CandidChunksError};
+#[cfg(feature = "debug_structure")]
+use crate::utils::heap_dump::BTreeNodeDump;
 use crate::utils::math::shuffle_bits;
-use crate::{isoprint, make_sure_can_allocate, OutOfMemory, SSlice};
+use crate::utils::DEBUG_ELEMENTS_LIMIT;
+use crate::{isoprint, make_sure_can_allocate_slots, OutOfMemory, SSlice};
+#[cfg(feature = "candid_chunks")]
+use candid::CandidType;
+#[cfg(feature = "candid_chunks")]
+use serde::de::DeserializeOwned;
+#[cfg(feature = "serde_collections")]
+use serde::de::{self, MapAccess, Visitor};
+#[cfg(feature = "serde_collections")]
+use serde::ser::SerializeMap;
+#[cfg(feature = "serde_collections")]
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::borrow::Borrow;
+use std::cmp::Ordering;
 use std::fmt::{Debug, Formatter};
+use std::iter::Rev;
+#[cfg(feature = "serde_collections")]
+use std::marker::PhantomData;
 use std::mem;
+use std::ops::{Bound, RangeBounds};
 
 pub(crate) const B: usize = 8;
 pub(crate) const CAPACITY: usize = 2 * B - 1;
@@ -28,6 +53,8 @@ pub(crate) const NODE_TYPE_OFFSET: u64 = 0;
 pub(crate) mod internal_node;
 pub mod iter;
 pub(crate) mod leaf_node;
+pub mod prefix;
+pub mod shadow;
 
 /// Right-biased B-plus tree based map data structure
 ///
@@ -46,9 +73,11 @@ pub struct SBTreeMap<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsF
     root: Option<BTreeNode<K, V>>,
     len: u64,
     certified: bool,
+    counted: bool,
     stable_drop_flag: bool,
     _stack: Vec<(InternalBTreeNode<K>, usize, usize)>,
     _buf: Vec<u8>,
+    _shadow: Option<Box<ShadowState>>,
 }
 
 impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> SBTreeMap<K, V> {
@@ -61,9 +90,11 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
             root: None,
             len: 0,
             certified: false,
+            counted: false,
             stable_drop_flag: true,
             _stack: Vec::default(),
             _buf: Vec::default(),
+            _shadow: None,
         }
     }
 
@@ -73,9 +104,25 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
             root: None,
             len: 0,
             certified: true,
+            counted: false,
             stable_drop_flag: true,
             _stack: Vec::default(),
             _buf: Vec::default(),
+            _shadow: None,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn new_counted() -> Self {
+        Self {
+            root: None,
+            len: 0,
+            certified: false,
+            counted: true,
+            stable_drop_flag: true,
+            _stack: Vec::default(),
+            _buf: Vec::default(),
+            _shadow: None,
         }
     }
 
@@ -105,93 +152,287 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
         self._insert(key, value, &mut LeveledList::None)
     }
 
+    /// Same as [SBTreeMap::insert], but returns [OutOfMemory] instead of the key-value pair
+    ///
+    /// Before splitting the leaf, makes sure there is enough stable memory for the worst case, when
+    /// every node on the path, collected while descending to this leaf, gets split too. So on
+    /// [OutOfMemory] the map is guaranteed to stay exactly as it was. Useful for propagating the
+    /// error with `?`. The pair gets dropped, if it can't be inserted.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use ic_stable_memory::collections::SBTreeMap;
+    /// # use ic_stable_memory::{stable_memory_init, OutOfMemory};
+    /// # unsafe { ic_stable_memory::mem::clear(); }
+    /// # stable_memory_init();
+    /// fn register(map: &mut SBTreeMap<u64, u64>, id: u64) -> Result<(), OutOfMemory> {
+    ///     map.try_insert(id, 0)?;
+    ///
+    ///     Ok(())
+    /// }
+    ///
+    /// let mut map = SBTreeMap::new();
+    /// register(&mut map, 10).expect("Out of memory");
+    /// ```
+    #[inline]
+    pub fn try_insert(&mut self, key: K, value: V) -> Result<Option<V>, OutOfMemory> {
+        self.insert(key, value).map_err(|_| OutOfMemory)
+    }
+
     pub(crate) fn _insert(
         &mut self,
         key: K,
         value: V,
         modified: &mut LeveledList,
     ) -> Result<Option<V>, (K, V)> {
-        if let Ok(mut node) = self.get_or_create_root() {
-            let mut leaf = loop {
-                match unsafe { node.copy() } {
-                    BTreeNode::Internal(internal_node) => {
-                        let node_len = internal_node.read_len();
-                        let child_idx = match internal_node.binary_search(&key, node_len) {
-                            Ok(idx) => idx + 1,
-                            Err(idx) => idx,
-                        };
+        let mut leaf = match self.find_leaf_for_insert(&key) {
+            Some(it) => it,
+            None => return Err((key, value)),
+        };
 
-                        let child_ptr = internal_node.read_child_ptr_buf(child_idx);
-                        self.push_stack(internal_node, node_len, child_idx);
+        // this call makes sure there is enough free stable memory to allocate everything else
+        // if it returns Ok - every other allocation after that should simply .unwrap()
+        match self.insert_leaf(&mut leaf, key, value, modified)? {
+            Ok(v) => {
+                self.clear_stack(modified);
 
-                        node = BTreeNode::<K, V>::from_ptr(u64::from_fixed_size_bytes(&child_ptr));
-                    }
-                    BTreeNode::Leaf(leaf_node) => break unsafe { leaf_node.copy() },
-                }
-            };
+                Ok(Some(v))
+            }
+            Err(right_leaf_opt) => {
+                self.finish_insert(leaf, right_leaf_opt, modified);
 
-            // this call makes sure there is enough free stable memory to allocate everything else
-            // if it returns Ok - every other allocation after that should simply .unwrap()
-            let right_leaf = match self.insert_leaf(&mut leaf, key, value, modified)? {
-                Ok(v) => {
-                    self.clear_stack(modified);
+                Ok(None)
+            }
+        }
+    }
 
-                    return Ok(Some(v));
-                }
-                Err(right_leaf_opt) => {
-                    if let Some(right_leaf) = right_leaf_opt {
-                        right_leaf
-                    } else {
-                        self.clear_stack(modified);
-                        self.len += 1;
+    /// Inserts a value produced by `default` under the provided key, or, if there is already a value
+    /// stored by this key, updates it in place with `modify`
+    ///
+    /// Only descends the tree once, which makes it a cheaper alternative to [SBTreeMap::get] followed
+    /// by [SBTreeMap::insert] for counters and other aggregates. `default` is only called, when the
+    /// key is missing.
+    ///
+    /// May allocate stable and heap memory. If your canister is out of stable memory, will return
+    /// [Err] with the key and the default value, leaving the map untouched.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use ic_stable_memory::collections::SBTreeMap;
+    /// # use ic_stable_memory::stable_memory_init;
+    /// # unsafe { ic_stable_memory::mem::clear(); }
+    /// # stable_memory_init();
+    /// let mut counters = SBTreeMap::new();
+    ///
+    /// for word in [1u64, 2, 1, 1] {
+    ///     counters.insert_with(word, || 1u64, |it| *it += 1).expect("Out of memory");
+    /// }
+    ///
+    /// assert_eq!(*counters.get(&1).unwrap(), 3);
+    /// assert_eq!(*counters.get(&2).unwrap(), 1);
+    /// ```
+    #[inline]
+    pub fn insert_with<D, M>(&mut self, key: K, default: D, modify: M) -> Result<(), (K, V)>
+    where
+        D: FnOnce() -> V,
+        M: FnOnce(&mut V),
+    {
+        self._insert_with(key, default, modify, &mut LeveledList::None)
+    }
 
-                        return Ok(None);
-                    }
-                }
-            };
+    pub(crate) fn _insert_with<D, M>(
+        &mut self,
+        key: K,
+        default: D,
+        modify: M,
+        modified: &mut LeveledList,
+    ) -> Result<(), (K, V)>
+    where
+        D: FnOnce() -> V,
+        M: FnOnce(&mut V),
+    {
+        let mut leaf = match self.find_leaf_for_insert(&key) {
+            Some(it) => it,
+            None => return Err((key, default())),
+        };
 
-            let mut key_to_index = right_leaf.read_key_buf(0);
-            let mut ptr = right_leaf.as_ptr();
+        if let Ok(idx) = leaf.binary_search(&key, leaf.read_len()) {
+            modify(&mut leaf.get_value_mut(idx));
 
-            while let Some((mut parent, parent_len, idx)) = self.pop_stack() {
-                if let Some((right, _k)) = self.insert_internal(
-                    &mut parent,
-                    parent_len,
-                    idx,
-                    key_to_index,
-                    ptr.as_new_fixed_size_bytes(),
-                    modified,
-                ) {
-                    key_to_index = _k;
-                    ptr = right.as_ptr();
-                    node = BTreeNode::Internal(parent);
-                } else {
-                    self.clear_stack(modified);
-                    self.len += 1;
+            modified.push(self.current_depth(), leaf.as_ptr());
+            self.clear_stack(modified);
+
+            return Ok(());
+        }
+
+        match self.insert_leaf(&mut leaf, key, default(), modified)? {
+            Ok(_) => unreachable!("The key is not present in this leaf"),
+            Err(right_leaf_opt) => {
+                self.finish_insert(leaf, right_leaf_opt, modified);
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns a mutable reference [SRefMut] to a value stored by the key, inserting a value
+    /// produced by `default` first, if there is none
+    ///
+    /// Makes "ensure the entry exists, then use it" a single operation. `default` is only called,
+    /// when the key is missing.
+    ///
+    /// May allocate stable and heap memory. If your canister is out of stable memory, will return
+    /// [Err] with the key and the default value, leaving the map untouched.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use ic_stable_memory::collections::SBTreeMap;
+    /// # use ic_stable_memory::stable_memory_init;
+    /// # unsafe { ic_stable_memory::mem::clear(); }
+    /// # stable_memory_init();
+    /// let mut sessions = SBTreeMap::new();
+    ///
+    /// for _ in 0..2 {
+    ///     let mut requests = sessions.get_or_insert_with(10u64, || 0u64).expect("Out of memory");
+    ///     *requests += 1;
+    /// }
+    ///
+    /// assert_eq!(*sessions.get(&10).unwrap(), 2);
+    /// ```
+    pub fn get_or_insert_with<D>(&mut self, key: K, default: D) -> Result<SRefMut<V>, (K, V)>
+    where
+        D: FnOnce() -> V,
+    {
+        let mut leaf = match self.find_leaf_for_insert(&key) {
+            Some(it) => it,
+            None => return Err((key, default())),
+        };
+
+        if let Ok(idx) = leaf.binary_search(&key, leaf.read_len()) {
+            self.clear_stack(&mut LeveledList::None);
+
+            return Ok(leaf.get_value_mut(idx));
+        }
+
+        // the new pair may end up in a sibling or in a new leaf, so it is looked up again by a
+        // non-owning copy of the key
+        let mut key_ref = K::from_fixed_size_bytes(key.as_new_fixed_size_bytes()._deref());
+        unsafe { key_ref.stable_drop_flag_off() };
+
+        match self.insert_leaf(&mut leaf, key, default(), &mut LeveledList::None)? {
+            Ok(_) => unreachable!("The key is not present in this leaf"),
+            Err(right_leaf_opt) => self.finish_insert(leaf, right_leaf_opt, &mut LeveledList::None),
+        }
+
+        let (mut leaf, idx) = self.lookup(&key_ref, false).unwrap();
+
+        Ok(leaf.get_value_mut(idx))
+    }
+
+    // number of internal nodes on any path from the root to a leaf
+    fn height(&self) -> usize {
+        let mut height = 0;
+        let mut node = self.get_root();
+
+        while let Some(BTreeNode::Internal(internal_node)) = node {
+            let child_ptr = u64::from_fixed_size_bytes(&internal_node.read_child_ptr_buf(0));
+            node = Some(BTreeNode::from_ptr(child_ptr));
+            height += 1;
+        }
+
+        height
+    }
+
+    // worst case of an insertion: a new leaf, a new internal node for each of `height` levels and a
+    // new root
+    fn make_sure_can_split(&self, height: usize) -> bool {
+        let mut sizes =
+            vec![InternalBTreeNode::<K>::calc_byte_size(self.certified, self.counted); height + 1];
+        sizes.push(LeafBTreeNode::<K, V>::calc_size_bytes(self.certified));
+
+        make_sure_can_allocate_slots(&sizes)
+    }
+
+    // descends to the leaf, where the key should be inserted, filling the stack along the way
+    fn find_leaf_for_insert(&mut self, key: &K) -> Option<LeafBTreeNode<K, V>> {
+        let mut node = self.get_or_create_root().ok()?;
+
+        loop {
+            match node {
+                BTreeNode::Internal(mut internal_node) => {
+                    let node_len = internal_node.read_len();
+                    let child_idx = match internal_node.binary_search(key, node_len) {
+                        Ok(idx) => idx + 1,
+                        Err(idx) => idx,
+                    };
+
+                    let child_ptr = self.own_child_ptr_buf(&mut internal_node, child_idx);
+                    self.push_stack(internal_node, node_len, child_idx);
 
-                    return Ok(None);
+                    node = BTreeNode::<K, V>::from_ptr(u64::from_fixed_size_bytes(&child_ptr));
                 }
+                BTreeNode::Leaf(leaf_node) => break Some(leaf_node),
             }
+        }
+    }
 
-            // stack is empty now
+    // propagates the split of the leaf (if there was one) up to the root
+    fn finish_insert(
+        &mut self,
+        leaf: LeafBTreeNode<K, V>,
+        right_leaf_opt: Option<LeafBTreeNode<K, V>>,
+        modified: &mut LeveledList,
+    ) {
+        let right_leaf = match right_leaf_opt {
+            Some(it) => it,
+            None => {
+                self.clear_stack(modified);
+                self.len += 1;
 
-            let new_root = InternalBTreeNode::<K>::create(
-                &key_to_index,
-                &node.as_ptr().as_new_fixed_size_bytes(),
-                &ptr.as_new_fixed_size_bytes(),
-                self.certified,
-            )
-            .unwrap();
+                return;
+            }
+        };
 
-            modified.insert_root(new_root.as_ptr());
+        let mut node = BTreeNode::Leaf(leaf);
+        let mut key_to_index = right_leaf.read_key_buf(0);
+        let mut ptr = right_leaf.as_ptr();
 
-            self.root = Some(BTreeNode::Internal(new_root));
-            self.len += 1;
+        while let Some((mut parent, parent_len, idx)) = self.pop_stack() {
+            if let Some((right, _k)) = self.insert_internal(
+                &mut parent,
+                parent_len,
+                idx,
+                key_to_index,
+                ptr.as_new_fixed_size_bytes(),
+                modified,
+            ) {
+                key_to_index = _k;
+                ptr = right.as_ptr();
+                node = BTreeNode::Internal(parent);
+            } else {
+                self.clear_stack(modified);
+                self.len += 1;
 
-            Ok(None)
-        } else {
-            Err((key, value))
+                return;
+            }
         }
+
+        // stack is empty now
+
+        let new_root = InternalBTreeNode::<K>::create(
+            &key_to_index,
+            &node.as_ptr().as_new_fixed_size_bytes(),
+            &ptr.as_new_fixed_size_bytes(),
+            self.certified,
+            self.counted,
+        )
+        .unwrap();
+
+        modified.insert_root(new_root.as_ptr());
+        self.track_new_node(new_root.as_ptr());
+
+        self.root = Some(BTreeNode::Internal(new_root));
+        self.len += 1;
     }
 
     /// Removes a key-value pair by the provided key
@@ -250,7 +491,7 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
         // lookup for the leaf that may contain the key
         let mut leaf = loop {
             match node {
-                BTreeNode::Internal(internal_node) => {
+                BTreeNode::Internal(mut internal_node) => {
                     let node_len = internal_node.read_len();
                     let child_idx = match internal_node.binary_search(key, node_len) {
                         Ok(idx) => {
@@ -262,7 +503,7 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
                         Err(idx) => idx,
                     };
 
-                    let child_ptr = internal_node.read_child_ptr_buf(child_idx);
+                    let child_ptr = self.own_child_ptr_buf(&mut internal_node, child_idx);
                     self.push_stack(internal_node, node_len, child_idx);
 
                     node = BTreeNode::<K, V>::from_ptr(u64::from_fixed_size_bytes(&child_ptr));
@@ -312,6 +553,158 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
         )
     }
 
+    /// Removes all entries, which keys are inside the provided range, returning their number
+    ///
+    /// Subtrees, which lie entirely inside the range, are detached and released as a whole, when it
+    /// doesn't make their parent node underfull. The rest of the entries are removed one by one, the
+    /// same way [SBTreeMap::remove] does. This makes retention policies (e.g. removing everything
+    /// older than a cutoff timestamp) much cheaper, than calling [SBTreeMap::remove] for each key.
+    ///
+    /// Removed keys and values are released. Empty and inverted ranges remove nothing. Borrowed type
+    /// is also accepted.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use ic_stable_memory::collections::SBTreeMap;
+    /// # use ic_stable_memory::stable_memory_init;
+    /// # unsafe { ic_stable_memory::mem::clear(); }
+    /// # stable_memory_init();
+    /// let mut map = SBTreeMap::new();
+    ///
+    /// for timestamp in 0..1000u64 {
+    ///     map.insert(timestamp, timestamp).expect("Out of memory");
+    /// }
+    ///
+    /// assert_eq!(map.remove_range(..900), 900);
+    /// assert_eq!(map.len(), 100);
+    /// assert_eq!(map.iter().next().map(|(k, _)| *k), Some(900));
+    /// ```
+    #[inline]
+    pub fn remove_range<Q, R>(&mut self, range: R) -> u64
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        self._remove_range(range, &mut LeveledList::None)
+    }
+
+    pub(crate) fn _remove_range<Q, R>(&mut self, range: R, modified: &mut LeveledList) -> u64
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        // shadowed nodes may be shared with the base, and certified nodes would need rehashing
+        let can_detach = self._shadow.is_none() && !self.certified;
+        let mut removed = 0;
+
+        'outer: loop {
+            let mut node = match self.get_root() {
+                Some(it) => it,
+                None => return removed,
+            };
+
+            let mut path = Vec::new();
+
+            // whether all keys of the current node are greater than the start (or less than the end)
+            let mut start_ok = matches!(range.start_bound(), Bound::Unbounded);
+            let mut end_ok = matches!(range.end_bound(), Bound::Unbounded);
+
+            let leaf = loop {
+                match node {
+                    BTreeNode::Internal(internal_node) => {
+                        let len = internal_node.read_len();
+
+                        if can_detach {
+                            let detachable = Self::find_detachable_children(
+                                &internal_node,
+                                len,
+                                path.is_empty(),
+                                start_ok,
+                                end_ok,
+                                &range,
+                            );
+
+                            if let Some((from, count)) = detachable {
+                                removed += self.detach_children(
+                                    internal_node,
+                                    len,
+                                    from,
+                                    count,
+                                    &path,
+                                    modified,
+                                );
+
+                                continue 'outer;
+                            }
+                        }
+
+                        let child_idx = match range.start_bound() {
+                            Bound::Included(k) | Bound::Excluded(k) => {
+                                match internal_node.binary_search(k, len) {
+                                    Ok(idx) => idx + 1,
+                                    Err(idx) => idx,
+                                }
+                            }
+                            Bound::Unbounded => 0,
+                        };
+
+                        if child_idx > 0 {
+                            let key = internal_node.read_key_as_reference(child_idx - 1);
+                            start_ok = starts_before(&range, <K as Borrow<Q>>::borrow(&key));
+                        }
+
+                        if child_idx < len {
+                            let key = internal_node.read_key_as_reference(child_idx);
+                            end_ok = ends_after(&range, <K as Borrow<Q>>::borrow(&key));
+                        }
+
+                        let child_ptr = u64::from_fixed_size_bytes(
+                            &internal_node.read_child_ptr_buf(child_idx),
+                        );
+                        path.push((internal_node, child_idx));
+
+                        node = BTreeNode::from_ptr(child_ptr);
+                    }
+                    BTreeNode::Leaf(leaf_node) => break leaf_node,
+                }
+            };
+
+            let len = leaf.read_len();
+            let idx = match range.start_bound() {
+                Bound::Included(k) => match leaf.binary_search(k, len) {
+                    Ok(idx) | Err(idx) => idx,
+                },
+                Bound::Excluded(k) => match leaf.binary_search(k, len) {
+                    Ok(idx) => idx + 1,
+                    Err(idx) => idx,
+                },
+                Bound::Unbounded => 0,
+            };
+
+            // the first key of the range may be in the next leaf
+            let (leaf, idx) = if idx < len {
+                (leaf, idx)
+            } else {
+                let next_ptr = u64::from_fixed_size_bytes(&leaf.read_next_ptr_buf());
+                if next_ptr == 0 {
+                    return removed;
+                }
+
+                (unsafe { LeafBTreeNode::<K, V>::from_ptr(next_ptr) }, 0)
+            };
+
+            let key = leaf.read_key_as_reference(idx);
+            if !range.contains(<K as Borrow<Q>>::borrow(&key)) {
+                return removed;
+            }
+
+            self._remove(&key, modified);
+            removed += 1;
+        }
+    }
+
     /// Returns an immutable reference [SRef] to a value stored by the key
     ///
     /// See also [SBTreeMap::get_mut].
@@ -520,44 +913,325 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
         SBTreeMapIter::<K, V>::new(self)
     }
 
-    /// Returns the length of this [SBTreeMap]
-    #[inline]
-    pub fn len(&self) -> u64 {
-        self.len
-    }
-
-    /// Returns [true] if the length of this [SBTreeMap] is `0`
-    #[inline]
-    pub fn is_empty(&self) -> bool {
-        self.len() == 0
-    }
-
-    /// Removes all key-value pairs from this collection, releasing all occupied stable memory
-    #[inline]
-    pub fn clear(&mut self) {
-        let mut old = mem::replace(self, Self::new());
-        self.stable_drop_flag = old.stable_drop_flag;
-        self.certified = old.certified;
-
-        unsafe { old.stable_drop() };
-    }
-
+    /// Returns an iterator over entries of this [SBTreeMap] in descending order of their keys
+    ///
+    /// The same as `.iter().rev()`. Use `.range(..).rev()` to traverse a range in descending order -
+    /// neither of them visits the keys, which are out of the range.
     #[inline]
-    fn clear_stack(&mut self, modified: &mut LeveledList) {
-        match modified {
-            LeveledList::None => {
-                self._stack.clear();
-            }
-            LeveledList::Some(_) => {
-                while let Some((p, _, _)) = self._stack.pop() {
-                    modified.push(self.current_depth(), p.as_ptr());
-                }
-            }
-        }
+    pub fn iter_rev(&self) -> Rev<SBTreeMapIter<K, V>> {
+        self.iter().rev()
     }
 
-    #[inline]
-    fn current_depth(&self) -> usize {
+    /// Returns an iterator over entries of this [SBTreeMap] which keys are inside the provided range
+    ///
+    /// Elements of this iterator are presented in ascending order. Locates both ends of the range
+    /// in `O(logN)`, after that each iteration step is `O(1)`. Borrowed type is also accepted.
+    ///
+    /// # Panics
+    /// Panics if range `start > end` or if range `start == end` and both bounds are [Bound::Excluded].
+    ///
+    /// # Example
+    /// ```rust
+    /// # use ic_stable_memory::collections::SBTreeMap;
+    /// # use ic_stable_memory::stable_memory_init;
+    /// # unsafe { ic_stable_memory::mem::clear(); }
+    /// # stable_memory_init();
+    /// let mut map = SBTreeMap::new();
+    ///
+    /// for i in 0..100 {
+    ///     map.insert(i, i).expect("Out of memory");
+    /// }
+    ///
+    /// let mut i = 10;
+    /// for (k, _) in map.range(10..20) {
+    ///     assert_eq!(*k, i);
+    ///     i += 1;
+    /// }
+    ///
+    /// assert_eq!(i, 20);
+    /// assert_eq!(map.range(..=5).rev().next().map(|(k, _)| *k), Some(5));
+    /// ```
+    pub fn range<Q, R>(&self, range: R) -> SBTreeMapRangeIter<K, V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        match (range.start_bound(), range.end_bound()) {
+            (Bound::Excluded(s), Bound::Excluded(e)) if s == e => {
+                panic!("range start and end are equal and excluded in SBTreeMap")
+            }
+            (Bound::Included(s) | Bound::Excluded(s), Bound::Included(e) | Bound::Excluded(e))
+                if s > e =>
+            {
+                panic!("range start is greater than range end in SBTreeMap")
+            }
+            _ => {}
+        };
+
+        if self.is_empty() {
+            return SBTreeMapRangeIter::new(None, None);
+        }
+
+        let front = match range.start_bound() {
+            Bound::Included(k) => self.find_leaf(k).map(|(leaf, idx)| match idx {
+                Ok(i) | Err(i) => (leaf, i),
+            }),
+            Bound::Excluded(k) => self.find_leaf(k).map(|(leaf, idx)| match idx {
+                Ok(i) => (leaf, i + 1),
+                Err(i) => (leaf, i),
+            }),
+            Bound::Unbounded => self.find_edge_leaf(false).map(|leaf| (leaf, 0)),
+        };
+
+        let back = match range.end_bound() {
+            Bound::Included(k) => self.find_leaf(k).map(|(leaf, idx)| match idx {
+                Ok(i) => (leaf, i + 1),
+                Err(i) => (leaf, i),
+            }),
+            Bound::Excluded(k) => self.find_leaf(k).map(|(leaf, idx)| match idx {
+                Ok(i) | Err(i) => (leaf, i),
+            }),
+            Bound::Unbounded => self.find_edge_leaf(true).map(|leaf| {
+                let len = leaf.read_len();
+                (leaf, len)
+            }),
+        };
+
+        SBTreeMapRangeIter::new(front, back)
+    }
+
+    /// Returns a double-ended iterator over entries, which keys start with the provided prefix
+    ///
+    /// Only the entries with the prefix are visited, so it is `O(logN + M)`, where `M` is the number
+    /// of such entries. See [KeyPrefix] for supported key types.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use ic_stable_memory::collections::SBTreeMap;
+    /// # use ic_stable_memory::stable_memory_init;
+    /// # unsafe { ic_stable_memory::mem::clear(); }
+    /// # stable_memory_init();
+    /// let mut map = SBTreeMap::new();
+    ///
+    /// for owner in 0..10u64 {
+    ///     for id in 0..10u64 {
+    ///         map.insert((owner, id), owner * id).expect("Out of memory");
+    ///     }
+    /// }
+    ///
+    /// let ids = map.iter_prefix(&5).map(|(k, _)| k.1).collect::<Vec<_>>();
+    /// assert_eq!(ids, (0..10).collect::<Vec<_>>());
+    /// assert_eq!(map.iter_prefix(&10).count(), 0);
+    /// ```
+    pub fn iter_prefix<P>(&self, prefix: &P) -> SBTreeMapRangeIter<K, V>
+    where
+        K: KeyPrefix<P>,
+        P: ?Sized,
+    {
+        // keys are never equal to the target, so both searches return the boundaries of the run
+        let front = self.find_leaf_by(|k| k.cmp_prefix(prefix).then(Ordering::Greater));
+        let back = self.find_leaf_by(|k| k.cmp_prefix(prefix).then(Ordering::Less));
+
+        SBTreeMapRangeIter::new(front, back)
+    }
+
+    /// Returns a page of at most `limit` entries, starting from `offset_key` (inclusive), together
+    /// with the key, the next page starts from
+    ///
+    /// Passing [None] starts from the smallest key. The continuation key is [None], if there are no
+    /// more entries. Pages are stable against concurrent modifications - inserted and removed keys
+    /// simply shift to or from the following pages. `O(logN + limit)`.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use ic_stable_memory::collections::SBTreeMap;
+    /// # use ic_stable_memory::stable_memory_init;
+    /// # unsafe { ic_stable_memory::mem::clear(); }
+    /// # stable_memory_init();
+    /// let mut map = SBTreeMap::new();
+    ///
+    /// for i in 0..25u64 {
+    ///     map.insert(i, i).expect("Out of memory");
+    /// }
+    ///
+    /// let mut pages = 0;
+    /// let mut next = None;
+    /// loop {
+    ///     let (page, continuation) = map.get_page(next, 10);
+    ///     pages += 1;
+    ///
+    ///     next = match continuation {
+    ///         Some(key) => Some(key),
+    ///         None => break,
+    ///     };
+    /// }
+    ///
+    /// assert_eq!(pages, 3);
+    /// ```
+    pub fn get_page(&self, offset_key: Option<K>, limit: usize) -> (Vec<(K, V)>, Option<K>)
+    where
+        K: Clone,
+        V: Clone,
+    {
+        let range = match &offset_key {
+            Some(k) => (Bound::Included(k), Bound::Unbounded),
+            None => (Bound::Unbounded, Bound::Unbounded),
+        };
+
+        let mut page = Vec::with_capacity(limit);
+        let mut next = None;
+
+        for (k, v) in self.range(range) {
+            if page.len() == limit {
+                next = Some((*k).clone());
+                break;
+            }
+
+            page.push(((*k).clone(), (*v).clone()));
+        }
+
+        (page, next)
+    }
+
+    /// Returns an iterator over entries, starting from the key `from` (inclusive), that stops
+    /// once the `budget` is exhausted
+    ///
+    /// Passing [None] starts from the smallest key. The
+    /// [continuation](ResumableIter::continuation) of the iterator is the key the next call should
+    /// start from, or [None] if there are no more entries. Modifications between calls are
+    /// allowed - inserted and removed keys are simply visited or skipped by the following calls.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use ic_stable_memory::collections::SBTreeMap;
+    /// # use ic_stable_memory::stable_memory_init;
+    /// # use ic_stable_memory::utils::budget::Budget;
+    /// # unsafe { ic_stable_memory::mem::clear(); }
+    /// # stable_memory_init();
+    /// let mut map = SBTreeMap::new();
+    ///
+    /// for i in 0..100u64 {
+    ///     map.insert(i, i).expect("Out of memory");
+    /// }
+    ///
+    /// let mut sum = 0;
+    /// let mut from = None;
+    /// loop {
+    ///     let mut iter = map.iter_resumable(from, Budget::Elements(30));
+    ///     for (_, v) in &mut iter {
+    ///         sum += *v;
+    ///     }
+    ///
+    ///     from = match iter.into_continuation() {
+    ///         Some(key) => Some(key),
+    ///         None => break,
+    ///     };
+    /// }
+    ///
+    /// assert_eq!(sum, 4950);
+    /// ```
+    pub fn iter_resumable<'a>(
+        &'a self,
+        from: Option<K>,
+        budget: Budget,
+    ) -> ResumableIter<SBTreeMapRangeIter<'a, K, V>, K, fn(&(SRef<'a, K>, SRef<'a, V>)) -> K>
+    where
+        K: Clone,
+    {
+        let inner = match &from {
+            Some(k) => self.range((Bound::Included(k), Bound::Unbounded)),
+            None => self.range::<K, _>(..),
+        };
+
+        let token_of: fn(&(SRef<'a, K>, SRef<'a, V>)) -> K = |(k, _)| (**k).clone();
+
+        ResumableIter::new(inner, budget, token_of)
+    }
+
+    /// Returns the length of this [SBTreeMap]
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns [true] if the length of this [SBTreeMap] is `0`
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Walks through every node of this [SBTreeMap], decoding all keys and values
+    ///
+    /// Returns a [CorruptData] error, pointing to the first invalid piece of stable memory, instead
+    /// of panicking in the middle of some later operation. Useful to check the map right after it was
+    /// retrieved after an upgrade. Stable structures, nested inside keys or values, are not walked
+    /// through.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use ic_stable_memory::collections::SBTreeMap;
+    /// # use ic_stable_memory::stable_memory_init;
+    /// # unsafe { ic_stable_memory::mem::clear(); }
+    /// # stable_memory_init();
+    /// let mut map = SBTreeMap::new();
+    /// map.insert(1u64, Some(true)).expect("Out of memory");
+    ///
+    /// assert!(map.validate().is_ok());
+    /// ```
+    pub fn validate(&self) -> Result<(), CorruptData> {
+        let mut stack = match &self.root {
+            Some(root) => vec![root.as_ptr()],
+            None => return Ok(()),
+        };
+
+        while let Some(ptr) = stack.pop() {
+            match BTreeNode::<K, V>::try_from_ptr(ptr)? {
+                BTreeNode::Internal(node) => {
+                    let len = node.try_validate()?;
+
+                    for idx in 0..=len {
+                        stack.push(StablePtr::from_fixed_size_bytes(
+                            &node.read_child_ptr_buf(idx),
+                        ));
+                    }
+                }
+                BTreeNode::Leaf(node) => {
+                    node.try_validate()?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Removes all key-value pairs from this collection, releasing all occupied stable memory
+    #[inline]
+    pub fn clear(&mut self) {
+        let mut old = mem::replace(self, Self::new());
+        self.stable_drop_flag = old.stable_drop_flag;
+        self.certified = old.certified;
+        self.counted = old.counted;
+
+        unsafe { old.stable_drop() };
+    }
+
+    #[inline]
+    fn clear_stack(&mut self, modified: &mut LeveledList) {
+        match modified {
+            LeveledList::None => {
+                self._stack.clear();
+            }
+            LeveledList::Some(_) => {
+                while let Some((p, _, _)) = self._stack.pop() {
+                    modified.push(self.current_depth(), p.as_ptr());
+                }
+            }
+        }
+    }
+
+    #[inline]
+    fn current_depth(&self) -> usize {
         self._stack.len()
     }
 
@@ -579,8 +1253,12 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
         self.certified = val;
     }
 
-    // WARNING: return_early == true will return nonsense leaf node and idx
-    fn lookup<Q>(&self, key: &Q, return_early: bool) -> Option<(LeafBTreeNode<K, V>, usize)>
+    pub(crate) fn set_counted(&mut self, val: bool) {
+        self.counted = val;
+    }
+
+    // returns the leaf, where the key is (or would be) stored, and the result of the binary search
+    fn find_leaf<Q>(&self, key: &Q) -> Option<(LeafBTreeNode<K, V>, Result<usize, usize>)>
     where
         K: Borrow<Q>,
         Q: Ord + ?Sized,
@@ -591,13 +1269,7 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
                 BTreeNode::Internal(internal_node) => {
                     let child_idx = match internal_node.binary_search(key, internal_node.read_len())
                     {
-                        Ok(idx) => {
-                            if return_early {
-                                return unsafe { Some((LeafBTreeNode::from_ptr(0), 0)) };
-                            } else {
-                                idx + 1
-                            }
-                        }
+                        Ok(idx) => idx + 1,
                         Err(idx) => idx,
                     };
 
@@ -606,88 +1278,325 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
                     node = BTreeNode::from_ptr(child_ptr);
                 }
                 BTreeNode::Leaf(leaf_node) => {
-                    return match leaf_node.binary_search(key, leaf_node.read_len()) {
-                        Ok(idx) => Some((leaf_node, idx)),
-                        _ => None,
-                    }
+                    let idx = leaf_node.binary_search(key, leaf_node.read_len());
+
+                    return Some((leaf_node, idx));
                 }
             }
         }
     }
 
-    fn insert_leaf(
-        &mut self,
-        leaf_node: &mut LeafBTreeNode<K, V>,
-        mut key: K,
-        mut value: V,
-        modified: &mut LeveledList,
-    ) -> Result<Result<V, Option<LeafBTreeNode<K, V>>>, (K, V)> {
-        let leaf_node_len = leaf_node.read_len();
-        let insert_idx = match leaf_node.binary_search(&key, leaf_node_len) {
-            Ok(existing_idx) => {
-                // if there is already a key like that, return early
-                let prev_value: V = leaf_node.read_and_disown_value(existing_idx);
-                leaf_node.write_and_own_value(existing_idx, value);
+    // `f` should never return `Ordering::Equal`, returns the position, the target would be at
+    fn find_leaf_by<F>(&self, mut f: F) -> Option<(LeafBTreeNode<K, V>, usize)>
+    where
+        F: FnMut(&K) -> Ordering,
+    {
+        let mut node = self.get_root()?;
+        loop {
+            match node {
+                BTreeNode::Internal(internal_node) => {
+                    let child_idx = internal_node
+                        .binary_search_by(&mut f, internal_node.read_len())
+                        .unwrap_or_else(|idx| idx);
 
-                modified.push(self.current_depth(), leaf_node.as_ptr());
+                    let child_ptr =
+                        u64::from_fixed_size_bytes(&internal_node.read_child_ptr_buf(child_idx));
+                    node = BTreeNode::from_ptr(child_ptr);
+                }
+                BTreeNode::Leaf(leaf_node) => {
+                    let idx = leaf_node
+                        .binary_search_by(&mut f, leaf_node.read_len())
+                        .unwrap_or_else(|idx| idx);
 
-                return Ok(Ok(prev_value));
+                    return Some((leaf_node, idx));
+                }
             }
-            Err(idx) => idx,
+        }
+    }
+
+    // returns the index of the first child and the number of children, which lie entirely inside
+    // the range and can be detached without making the node underfull
+    fn find_detachable_children<Q, R>(
+        node: &InternalBTreeNode<K>,
+        len: usize,
+        is_root: bool,
+        start_ok: bool,
+        end_ok: bool,
+        range: &R,
+    ) -> Option<(usize, usize)>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        let min_children = if is_root {
+            2
+        } else {
+            CHILDREN_MIN_LEN_AFTER_SPLIT
         };
 
-        let k = key.as_new_fixed_size_bytes();
-        let v = value.as_new_fixed_size_bytes();
+        let spare = (len + 1).checked_sub(min_children).filter(|it| *it > 0)?;
 
-        // if there is enough space - simply insert and return early
-        if leaf_node_len < CAPACITY {
-            leaf_node.insert_key_buf(insert_idx, &k, leaf_node_len, &mut self._buf);
-            leaf_node.insert_value_buf(insert_idx, &v, leaf_node_len, &mut self._buf);
+        // child `i` holds keys between the keys `i - 1` and `i`
+        let is_covered = |i: usize| {
+            let after_start = if i == 0 {
+                start_ok
+            } else {
+                let key = node.read_key_as_reference(i - 1);
+                starts_before(range, <K as Borrow<Q>>::borrow(&key))
+            };
 
-            leaf_node.write_len(leaf_node_len + 1);
+            let before_end = if i == len {
+                end_ok
+            } else {
+                let key = node.read_key_as_reference(i);
+                ends_after(range, <K as Borrow<Q>>::borrow(&key))
+            };
 
-            modified.push(self.current_depth(), leaf_node.as_ptr());
+            after_start && before_end
+        };
 
-            unsafe { key.stable_drop_flag_off() };
-            unsafe { value.stable_drop_flag_off() };
+        let from = (0..=len).find(|i| is_covered(*i))?;
+        let count = (from..=len)
+            .take_while(|i| is_covered(*i))
+            .count()
+            .min(spare);
 
-            return Ok(Err(None));
-        }
+        Some((from, count))
+    }
 
-        // try passing an element to a neighbor, to make room for a new one
-        if self.pass_elem_to_sibling_leaf(leaf_node, &k, &v, insert_idx, modified) {
-            unsafe { key.stable_drop_flag_off() };
-            unsafe { value.stable_drop_flag_off() };
+    // releases the children and relinks leaves around them, returns the number of removed entries
+    fn detach_children(
+        &mut self,
+        mut node: InternalBTreeNode<K>,
+        len: usize,
+        from: usize,
+        count: usize,
+        path: &[(InternalBTreeNode<K>, usize)],
+        modified: &mut LeveledList,
+    ) -> u64 {
+        let to = from + count - 1;
+        let child_ptr = |idx: usize| u64::from_fixed_size_bytes(&node.read_child_ptr_buf(idx));
 
-            return Ok(Err(None));
-        }
+        let first_leaf = Self::find_edge_leaf_of(child_ptr(from), false);
+        let last_leaf = Self::find_edge_leaf_of(child_ptr(to), true);
 
-        // cheking if it is possible to allocate worst-case scenario amount of memory
-        let memory_to_allocate = (self._stack.len() + 1) as u64
-            * FreeBlock::to_total_size(InternalBTreeNode::<K>::calc_byte_size(self.certified))
-            + FreeBlock::to_total_size(LeafBTreeNode::<K, V>::calc_size_bytes(self.certified));
+        let prev_ptr_buf = first_leaf.read_prev_ptr_buf();
+        let next_ptr_buf = last_leaf.read_next_ptr_buf();
+        let prev_ptr = u64::from_fixed_size_bytes(&prev_ptr_buf);
+        let next_ptr = u64::from_fixed_size_bytes(&next_ptr_buf);
 
-        // we can unwrap all OutOfMemory errors if this check passes, without any consequences
-        if !make_sure_can_allocate(memory_to_allocate) {
-            return Err((key, value));
+        if prev_ptr != 0 {
+            unsafe { LeafBTreeNode::<K, V>::from_ptr(prev_ptr) }.write_next_ptr_buf(&next_ptr_buf);
         }
 
-        unsafe { key.stable_drop_flag_off() };
-        unsafe { value.stable_drop_flag_off() };
+        if next_ptr != 0 {
+            unsafe { LeafBTreeNode::<K, V>::from_ptr(next_ptr) }.write_prev_ptr_buf(&prev_ptr_buf);
+        }
+
+        let depth = path.len() + 1;
+        let mut removed = 0;
+
+        for idx in from..=to {
+            removed += Self::destroy_subtree(BTreeNode::from_ptr(child_ptr(idx)), depth, modified);
+        }
+
+        // if there are children to the right, their separators are kept, the left ones otherwise
+        let key_idx = if to < len { from } else { from - 1 };
+
+        for i in 0..count {
+            node.remove_child_ptr_buf(from, len + 1 - i, &mut self._buf);
+            node.remove_key_buf(key_idx, len - i, &mut self._buf);
+        }
+
+        node.write_len(len - count);
+
+        // the separator before the gap should now point to the first key after it
+        if to < len {
+            let first_key = unsafe { LeafBTreeNode::<K, V>::from_ptr(next_ptr) }.read_key_buf(0);
+
+            if from > 0 {
+                node.write_key_buf(from - 1, &first_key);
+            } else if let Some((ancestor, idx)) = path.iter().rev().find(|(_, idx)| *idx > 0) {
+                let mut ancestor = unsafe { ancestor.copy() };
+                ancestor.write_key_buf(idx - 1, &first_key);
+            }
+        }
+
+        modified.push(path.len(), node.as_ptr());
+        for (depth, (ancestor, _)) in path.iter().enumerate() {
+            modified.push(depth, ancestor.as_ptr());
+        }
+
+        self.len -= removed;
+
+        removed
+    }
+
+    // releases all nodes of the subtree, with their keys and values, returns the number of entries
+    fn destroy_subtree(root: BTreeNode<K, V>, depth: usize, modified: &mut LeveledList) -> u64 {
+        let mut nodes = vec![(root, depth)];
+        let mut removed = 0;
+
+        while let Some((node, depth)) = nodes.pop() {
+            modified.remove(depth, node.as_ptr());
+
+            match node {
+                BTreeNode::Internal(internal) => {
+                    for j in 0..(internal.read_len() + 1) {
+                        let child_ptr = u64::from_fixed_size_bytes(&internal.read_child_ptr_buf(j));
+                        nodes.push((BTreeNode::from_ptr(child_ptr), depth + 1));
+                    }
+
+                    internal.destroy();
+                }
+                BTreeNode::Leaf(mut leaf) => {
+                    let len = leaf.read_len();
+
+                    for j in 0..len {
+                        leaf.read_and_disown_key(j);
+                        leaf.read_and_disown_value(j);
+                    }
+
+                    removed += len as u64;
+                    leaf.destroy();
+                }
+            }
+        }
+
+        removed
+    }
+
+    // returns the leftmost (or the rightmost, if `last == true`) leaf of the subtree
+    fn find_edge_leaf_of(mut ptr: StablePtr, last: bool) -> LeafBTreeNode<K, V> {
+        loop {
+            match BTreeNode::<K, V>::from_ptr(ptr) {
+                BTreeNode::Internal(internal_node) => {
+                    let child_idx = if last { internal_node.read_len() } else { 0 };
+                    ptr = u64::from_fixed_size_bytes(&internal_node.read_child_ptr_buf(child_idx));
+                }
+                BTreeNode::Leaf(leaf_node) => return leaf_node,
+            }
+        }
+    }
+
+    // returns the leftmost (or the rightmost, if `last == true`) leaf of the tree
+    fn find_edge_leaf(&self, last: bool) -> Option<LeafBTreeNode<K, V>> {
+        let mut node = self.get_root()?;
+        loop {
+            match node {
+                BTreeNode::Internal(internal_node) => {
+                    let child_idx = if last { internal_node.read_len() } else { 0 };
+                    let child_ptr =
+                        u64::from_fixed_size_bytes(&internal_node.read_child_ptr_buf(child_idx));
+
+                    node = BTreeNode::from_ptr(child_ptr);
+                }
+                BTreeNode::Leaf(leaf_node) => return Some(leaf_node),
+            }
+        }
+    }
+
+    // WARNING: return_early == true will return nonsense leaf node and idx
+    fn lookup<Q>(&self, key: &Q, return_early: bool) -> Option<(LeafBTreeNode<K, V>, usize)>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut node = self.get_root()?;
+        loop {
+            match node {
+                BTreeNode::Internal(internal_node) => {
+                    let child_idx = match internal_node.binary_search(key, internal_node.read_len())
+                    {
+                        Ok(idx) => {
+                            if return_early {
+                                return unsafe { Some((LeafBTreeNode::from_ptr(0), 0)) };
+                            } else {
+                                idx + 1
+                            }
+                        }
+                        Err(idx) => idx,
+                    };
+
+                    let child_ptr =
+                        u64::from_fixed_size_bytes(&internal_node.read_child_ptr_buf(child_idx));
+                    node = BTreeNode::from_ptr(child_ptr);
+                }
+                BTreeNode::Leaf(leaf_node) => {
+                    return match leaf_node.binary_search(key, leaf_node.read_len()) {
+                        Ok(idx) => Some((leaf_node, idx)),
+                        _ => None,
+                    }
+                }
+            }
+        }
+    }
+
+    fn insert_leaf(
+        &mut self,
+        leaf_node: &mut LeafBTreeNode<K, V>,
+        mut key: K,
+        mut value: V,
+        modified: &mut LeveledList,
+    ) -> Result<Result<V, Option<LeafBTreeNode<K, V>>>, (K, V)> {
+        let leaf_node_len = leaf_node.read_len();
+        let insert_idx = match leaf_node.binary_search(&key, leaf_node_len) {
+            Ok(existing_idx) => {
+                // if there is already a key like that, return early
+                let prev_value: V = leaf_node.read_and_disown_value(existing_idx);
+                leaf_node.write_and_own_value(existing_idx, value);
+
+                modified.push(self.current_depth(), leaf_node.as_ptr());
+
+                return Ok(Ok(prev_value));
+            }
+            Err(idx) => idx,
+        };
+
+        let k = key.as_new_fixed_size_bytes();
+        let v = value.as_new_fixed_size_bytes();
+
+        // if there is enough space - simply insert and return early
+        if leaf_node_len < CAPACITY {
+            leaf_node.insert_key_buf(insert_idx, &k, leaf_node_len, &mut self._buf);
+            leaf_node.insert_value_buf(insert_idx, &v, leaf_node_len, &mut self._buf);
+
+            leaf_node.write_len(leaf_node_len + 1);
+
+            modified.push(self.current_depth(), leaf_node.as_ptr());
+
+            unsafe { key.stable_drop_flag_off() };
+            unsafe { value.stable_drop_flag_off() };
+
+            return Ok(Err(None));
+        }
+
+        // try passing an element to a neighbor, to make room for a new one
+        if self.pass_elem_to_sibling_leaf(leaf_node, &k, &v, insert_idx, modified) {
+            unsafe { key.stable_drop_flag_off() };
+            unsafe { value.stable_drop_flag_off() };
+
+            return Ok(Err(None));
+        }
+
+        // we can unwrap all OutOfMemory errors if this check passes, without any consequences
+        if !self.make_sure_can_split(self._stack.len()) {
+            return Err((key, value));
+        }
+
+        unsafe { key.stable_drop_flag_off() };
+        unsafe { value.stable_drop_flag_off() };
 
         // split the leaf and insert so both leaves now have length of B
         let mut right = if insert_idx < B {
-            let right = leaf_node
-                .split_max_len(true, &mut self._buf, self.certified)
-                .unwrap();
+            let right = leaf_node.split_max_len(true, self.certified).unwrap();
             leaf_node.insert_key_buf(insert_idx, &k, MIN_LEN_AFTER_SPLIT, &mut self._buf);
             leaf_node.insert_value_buf(insert_idx, &v, MIN_LEN_AFTER_SPLIT, &mut self._buf);
 
             right
         } else {
-            let mut right = leaf_node
-                .split_max_len(false, &mut self._buf, self.certified)
-                .unwrap();
+            let mut right = leaf_node.split_max_len(false, self.certified).unwrap();
             right.insert_key_buf(insert_idx - B, &k, MIN_LEN_AFTER_SPLIT, &mut self._buf);
             right.insert_value_buf(insert_idx - B, &v, MIN_LEN_AFTER_SPLIT, &mut self._buf);
 
@@ -697,6 +1606,8 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
         leaf_node.write_len(B);
         right.write_len(B);
 
+        self.track_new_node(right.as_ptr());
+
         modified.push(self.current_depth(), leaf_node.as_ptr());
         modified.push(self.current_depth(), right.as_ptr());
 
@@ -729,8 +1640,9 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
 
         // TODO: possible to optimize when idx == MIN_LEN_AFTER_SPLIT
         let (mut right, mid) = internal_node
-            .split_max_len(&mut self._buf, self.certified)
+            .split_max_len(self.certified, self.counted)
             .unwrap();
+        self.track_new_node(right.as_ptr());
 
         if idx <= MIN_LEN_AFTER_SPLIT {
             internal_node.insert_key_buf(idx, &key, MIN_LEN_AFTER_SPLIT, &mut self._buf);
@@ -767,7 +1679,8 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
 
         let (mut parent, parent_len, parent_idx) = unsafe { stack_top_frame.unwrap_unchecked() };
 
-        if let Some(mut left_sibling) = parent.read_left_sibling::<LeafBTreeNode<K, V>>(parent_idx)
+        if let Some(mut left_sibling) =
+            self.own_left_sibling::<LeafBTreeNode<K, V>>(&mut parent, parent_idx)
         {
             let left_sibling_len = left_sibling.read_len();
 
@@ -792,7 +1705,7 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
         }
 
         if let Some(mut right_sibling) =
-            parent.read_right_sibling::<LeafBTreeNode<K, V>>(parent_idx, parent_len)
+            self.own_right_sibling::<LeafBTreeNode<K, V>>(&mut parent, parent_idx, parent_len)
         {
             let right_sibling_len = right_sibling.read_len();
 
@@ -885,7 +1798,8 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
 
         let (mut parent, parent_len, parent_idx) = unsafe { stack_top_frame.unwrap_unchecked() };
 
-        if let Some(mut left_sibling) = parent.read_left_sibling::<InternalBTreeNode<K>>(parent_idx)
+        if let Some(mut left_sibling) =
+            self.own_left_sibling::<InternalBTreeNode<K>>(&mut parent, parent_idx)
         {
             let left_sibling_len = left_sibling.read_len();
 
@@ -909,7 +1823,7 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
         }
 
         if let Some(mut right_sibling) =
-            parent.read_right_sibling::<InternalBTreeNode<K>>(parent_idx, parent_len)
+            self.own_right_sibling::<InternalBTreeNode<K>>(&mut parent, parent_idx, parent_len)
         {
             let right_sibling_len = right_sibling.read_len();
 
@@ -997,7 +1911,8 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
     ) -> Option<V> {
         let (mut parent, parent_len, parent_idx) = unsafe { stack_top_frame.unwrap_unchecked() };
 
-        if let Some(mut left_sibling) = parent.read_left_sibling::<LeafBTreeNode<K, V>>(parent_idx)
+        if let Some(mut left_sibling) =
+            self.own_left_sibling::<LeafBTreeNode<K, V>>(&mut parent, parent_idx)
         {
             let left_sibling_len = left_sibling.read_len();
 
@@ -1026,7 +1941,7 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
             }
 
             if let Some(mut right_sibling) =
-                parent.read_right_sibling::<LeafBTreeNode<K, V>>(parent_idx, parent_len)
+                self.own_right_sibling::<LeafBTreeNode<K, V>>(&mut parent, parent_idx, parent_len)
             {
                 let right_sibling_len = right_sibling.read_len();
 
@@ -1067,7 +1982,7 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
         }
 
         if let Some(mut right_sibling) =
-            parent.read_right_sibling::<LeafBTreeNode<K, V>>(parent_idx, parent_len)
+            self.own_right_sibling::<LeafBTreeNode<K, V>>(&mut parent, parent_idx, parent_len)
         {
             let right_sibling_len = right_sibling.read_len();
 
@@ -1119,7 +2034,7 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
         modified.push(self.current_depth(), leaf.as_ptr());
 
         // otherwise merge with right
-        leaf.merge_min_len(right_sibling, &mut self._buf);
+        self.merge_leaves(&mut leaf, right_sibling);
 
         // just idx, because leaf keys stay unchanged
         let v = leaf.remove_and_disown_by_idx(idx, CAPACITY - 1, &mut self._buf);
@@ -1145,7 +2060,7 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
         modified.push(self.current_depth(), left_sibling.as_ptr());
 
         // if there is no right sibling - merge with left
-        left_sibling.merge_min_len(leaf, &mut self._buf);
+        self.merge_leaves(&mut left_sibling, leaf);
         // idx + MIN_LEN_AFTER_SPLIT, because all keys of leaf are added to the
         // end of left_sibling
         let v = left_sibling.remove_and_disown_by_idx(
@@ -1241,6 +2156,7 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
                 if node_len == 1 {
                     modified.remove_root();
 
+                    self.forget_node(node.as_ptr());
                     node.destroy();
                     self.root = Some(prev_node);
 
@@ -1261,7 +2177,7 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
                 unsafe { stack_top_frame.unwrap_unchecked() };
 
             if let Some(mut left_sibling) =
-                parent.read_left_sibling::<InternalBTreeNode<K>>(parent_idx)
+                self.own_left_sibling::<InternalBTreeNode<K>>(&mut parent, parent_idx)
             {
                 let left_sibling_len = left_sibling.read_len();
 
@@ -1286,9 +2202,11 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
                     return;
                 }
 
-                if let Some(right_sibling) =
-                    parent.read_right_sibling::<InternalBTreeNode<K>>(parent_idx, parent_len)
-                {
+                if let Some(right_sibling) = self.own_right_sibling::<InternalBTreeNode<K>>(
+                    &mut parent,
+                    parent_idx,
+                    parent_len,
+                ) {
                     let right_sibling_len = right_sibling.read_len();
 
                     // steal from right if it's possible
@@ -1347,7 +2265,7 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
             }
 
             if let Some(right_sibling) =
-                parent.read_right_sibling::<InternalBTreeNode<K>>(parent_idx, parent_len)
+                self.own_right_sibling::<InternalBTreeNode<K>>(&mut parent, parent_idx, parent_len)
             {
                 let right_sibling_len = right_sibling.read_len();
 
@@ -1454,8 +2372,10 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
         modified.remove(self.current_depth(), right_sibling.as_ptr());
         modified.push(self.current_depth(), node.as_ptr());
 
+        self.forget_node(right_sibling.as_ptr());
+
         let mid_element = parent.read_key_buf(parent_idx);
-        node.merge_min_len(&mid_element, right_sibling, &mut self._buf);
+        node.merge_min_len(&mid_element, right_sibling);
         node.remove_key_buf(idx_to_remove, CAPACITY, &mut self._buf);
         node.remove_child_ptr_buf(child_idx_to_remove, CHILDREN_CAPACITY, &mut self._buf);
         node.write_len(CAPACITY - 1);
@@ -1474,8 +2394,10 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
         modified.remove(self.current_depth(), node.as_ptr());
         modified.push(self.current_depth(), left_sibling.as_ptr());
 
+        self.forget_node(node.as_ptr());
+
         let mid_element = parent.read_key_buf(parent_idx - 1);
-        left_sibling.merge_min_len(&mid_element, node, &mut self._buf);
+        left_sibling.merge_min_len(&mid_element, node);
         left_sibling.remove_key_buf(idx_to_remove + B, CAPACITY, &mut self._buf);
         left_sibling.remove_child_ptr_buf(
             child_idx_to_remove + B,
@@ -1492,10 +2414,13 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
     }
 
     fn get_or_create_root(&mut self) -> Result<BTreeNode<K, V>, OutOfMemory> {
+        self.own_root();
+
         match &self.root {
             Some(r) => unsafe { Ok(r.copy()) },
             None => {
                 let new_root = BTreeNode::<K, V>::Leaf(LeafBTreeNode::create(self.certified)?);
+                self.track_new_node(new_root.as_ptr());
 
                 self.root = Some(new_root);
                 unsafe { Ok(self.root.as_ref().unwrap_unchecked().copy()) }
@@ -1527,41 +2452,8 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
             return;
         }
 
-        let mut nodes = vec![unsafe { self.root.take().unwrap_unchecked() }];
-        let mut new_nodes = Vec::new();
-
-        loop {
-            if nodes.is_empty() {
-                return;
-            }
-
-            for _ in 0..nodes.len() {
-                match unsafe { nodes.pop().unwrap_unchecked() } {
-                    BTreeNode::Internal(internal) => {
-                        for j in 0..(internal.read_len() + 1) {
-                            let child_ptr_raw = internal.read_child_ptr_buf(j);
-                            let child_ptr = u64::from_fixed_size_bytes(&child_ptr_raw);
-                            let child = BTreeNode::<K, V>::from_ptr(child_ptr);
-
-                            new_nodes.push(child);
-                        }
-
-                        internal.destroy();
-                    }
-                    BTreeNode::Leaf(mut leaf) => {
-                        for j in 0..leaf.read_len() {
-                            leaf.read_and_disown_key(j);
-                            leaf.read_and_disown_value(j);
-                        }
-
-                        leaf.destroy();
-                    }
-                }
-            }
-
-            nodes = new_nodes;
-            new_nodes = Vec::new();
-        }
+        let root = unsafe { self.root.take().unwrap_unchecked() };
+        Self::destroy_subtree(root, 0, &mut LeveledList::None);
     }
 }
 
@@ -1590,17 +2482,31 @@ impl<K: StableType + AsFixedSizeBytes + Ord + Debug, V: StableType + AsFixedSize
         ));
     }
 
+    /// Returns nodes of this [SBTreeMap], level by level, starting from the root
+    ///
+    /// Each level is on a separate line. At most [DEBUG_ELEMENTS_LIMIT] nodes of each level are
+    /// printed. Reads every node from stable memory, so it is only meant for debugging.
+    #[cfg(feature = "debug_structure")]
+    #[inline]
+    pub fn debug_structure(&self) -> String {
+        self.structure_to_string()
+    }
+
     pub fn debug_print(&self) {
+        isoprint(&self.structure_to_string());
+    }
+
+    fn structure_to_string(&self) -> String {
         if self.len == 0 {
-            isoprint("EMPTY BTREEMAP");
-            return;
+            return String::from("EMPTY BTREEMAP");
         }
 
+        let mut result = String::new();
         let mut level = Vec::new();
         level.push(unsafe { self.root.as_ref().unwrap_unchecked().copy() });
 
         loop {
-            Self::print_level(&level);
+            Self::level_to_string(&level, &mut result);
 
             let mut new_level = Vec::new();
             for node in level {
@@ -1621,19 +2527,226 @@ impl<K: StableType + AsFixedSizeBytes + Ord + Debug, V: StableType + AsFixedSize
                 level = new_level;
             }
         }
+
+        result
     }
 
-    fn print_level(level: &Vec<BTreeNode<K, V>>) {
-        let mut result = String::new();
+    fn level_to_string(level: &[BTreeNode<K, V>], result: &mut String) {
+        if !result.is_empty() {
+            result.push('\n');
+        }
 
-        for node in level {
-            result += &match node {
+        for node in level.iter().take(DEBUG_ELEMENTS_LIMIT) {
+            *result += &match node {
                 BTreeNode::Internal(i) => i.to_string(),
                 BTreeNode::Leaf(l) => l.to_string(),
             }
         }
 
-        isoprint(&result);
+        if level.len() > DEBUG_ELEMENTS_LIMIT {
+            *result += &format!("...{} more", level.len() - DEBUG_ELEMENTS_LIMIT);
+        }
+    }
+}
+
+#[cfg(feature = "debug_structure")]
+impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> SBTreeMap<K, V> {
+    /// Returns every node of this [SBTreeMap] in breadth-first order, starting from the root
+    ///
+    /// Reads every node from stable memory, so it is only meant for diagnostics. See
+    /// [HeapDump](crate::utils::heap_dump::HeapDump).
+    pub fn dump_nodes(&self) -> Vec<BTreeNodeDump> {
+        let mut result = Vec::new();
+        let mut queue = std::collections::VecDeque::new();
+
+        if let Some(root) = &self.root {
+            queue.push_back(root.as_ptr());
+        }
+
+        while let Some(ptr) = queue.pop_front() {
+            let (len, children) = match BTreeNode::<K, V>::from_ptr(ptr) {
+                BTreeNode::Internal(node) => {
+                    let len = node.read_len();
+                    let children = (0..=len)
+                        .map(|idx| StablePtr::from_fixed_size_bytes(&node.read_child_ptr_buf(idx)))
+                        .collect::<Vec<_>>();
+
+                    (len, children)
+                }
+                BTreeNode::Leaf(node) => (node.read_len(), Vec::new()),
+            };
+
+            queue.extend(children.iter().copied());
+            result.push(BTreeNodeDump { ptr, len, children });
+        }
+
+        result
+    }
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord, T: StableType + AsDynSizeBytes> SBTreeMap<K, SBox<T>> {
+    /// Inserts a value, which is shared with its other owners, without copying it
+    ///
+    /// The map becomes one more owner of the value's memory block (see [SBox::share]), so the block
+    /// is only released, once the map and all other owners drop it. Use it to deduplicate large
+    /// identical values, stored in multiple collections.
+    ///
+    /// Returns the same as [SBTreeMap::insert].
+    ///
+    /// # Example
+    /// ```rust
+    /// # use ic_stable_memory::collections::SBTreeMap;
+    /// # use ic_stable_memory::{SBox, stable_memory_init};
+    /// # unsafe { ic_stable_memory::mem::clear(); }
+    /// # stable_memory_init();
+    /// let mut by_name = SBTreeMap::new();
+    /// let mut by_date = SBTreeMap::new();
+    ///
+    /// let asset = SBox::new(vec![0u8; 1000]).expect("Out of memory");
+    /// by_name.insert_shared(1u64, &asset).expect("Out of memory");
+    /// by_date.insert_shared(2u64, &asset).expect("Out of memory");
+    /// drop(asset);
+    ///
+    /// drop(by_name);
+    ///
+    /// // still there
+    /// assert_eq!(by_date.get(&2).unwrap().len(), 1000);
+    /// ```
+    #[inline]
+    pub fn insert_shared(
+        &mut self,
+        key: K,
+        value: &SBox<T>,
+    ) -> Result<Option<SBox<T>>, (K, SBox<T>)> {
+        self.insert(key, value.share())
+    }
+}
+
+#[cfg(feature = "candid_chunks")]
+impl<
+        K: StableType + AsFixedSizeBytes + Ord + CandidType + DeserializeOwned,
+        V: StableType + AsFixedSizeBytes + CandidType + DeserializeOwned,
+    > SBTreeMap<K, V>
+{
+    /// Encodes entries of this [SBTreeMap] into Candid `vec`-s of `chunk_len` `(key, value)` tuples
+    /// each
+    ///
+    /// Entries are read lazily in ascending order of their keys, one chunk per iteration. See
+    /// [crate::utils::candid_chunks].
+    ///
+    /// # Panics
+    /// Panics if `chunk_len` is zero.
+    pub fn to_candid_chunks(&self, chunk_len: usize) -> impl Iterator<Item = Vec<u8>> + '_ {
+        encode_chunks(self.iter(), chunk_len, |chunk| {
+            candid::encode_one(chunk.iter().map(|(k, v)| (&**k, &**v)).collect::<Vec<_>>())
+        })
+    }
+
+    /// Creates a new [SBTreeMap] from chunks, produced by [SBTreeMap::to_candid_chunks]
+    ///
+    /// If an error is returned, all the stable memory taken so far is released.
+    pub fn from_candid_chunks<C: AsRef<[u8]>>(
+        chunks: impl IntoIterator<Item = C>,
+    ) -> Result<Self, CandidChunksError> {
+        let mut it = Self::new();
+
+        for chunk in chunks {
+            it.insert_candid_chunk(chunk.as_ref())?;
+        }
+
+        Ok(it)
+    }
+
+    /// Inserts the entries of a single chunk, produced by [SBTreeMap::to_candid_chunks], into this
+    /// [SBTreeMap]
+    ///
+    /// Useful, when chunks arrive one at a time (e.g. one per call). Entries with existing keys
+    /// replace the previous values. If the chunk is invalid, nothing is inserted. If there is not
+    /// enough stable memory, the entries, that fit, remain inserted.
+    pub fn insert_candid_chunk(&mut self, chunk: &[u8]) -> Result<(), CandidChunksError> {
+        let entries: Vec<(K, V)> = candid::decode_one(chunk)?;
+
+        for (k, v) in entries {
+            self.insert(k, v)
+                .map_err(|_| CandidChunksError::OutOfMemory)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Serializes entries of this [SBTreeMap] as a map, in ascending order of keys
+#[cfg(feature = "serde_collections")]
+impl<
+        K: StableType + AsFixedSizeBytes + Ord + Serialize,
+        V: StableType + AsFixedSizeBytes + Serialize,
+    > Serialize for SBTreeMap<K, V>
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.len() as usize))?;
+
+        for (k, v) in self.iter() {
+            map.serialize_entry(&*k, &*v)?;
+        }
+
+        map.end()
+    }
+}
+
+/// Deserializes a map into a new [SBTreeMap], fails if there is not enough stable memory
+///
+/// If a key is repeated, the last value wins.
+#[cfg(feature = "serde_collections")]
+impl<
+        'de,
+        K: StableType + AsFixedSizeBytes + Ord + Deserialize<'de>,
+        V: StableType + AsFixedSizeBytes + Deserialize<'de>,
+    > Deserialize<'de> for SBTreeMap<K, V>
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct SBTreeMapVisitor<K, V>(PhantomData<(K, V)>);
+
+        impl<
+                'de,
+                K: StableType + AsFixedSizeBytes + Ord + Deserialize<'de>,
+                V: StableType + AsFixedSizeBytes + Deserialize<'de>,
+            > Visitor<'de> for SBTreeMapVisitor<K, V>
+        {
+            type Value = SBTreeMap<K, V>;
+
+            fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
+                formatter.write_str("a map")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut it = SBTreeMap::new();
+
+                while let Some((k, v)) = map.next_entry()? {
+                    it.insert(k, v)
+                        .map_err(|_| de::Error::custom("Out of stable memory"))?;
+                }
+
+                Ok(it)
+            }
+        }
+
+        deserializer.deserialize_map(SBTreeMapVisitor(PhantomData))
+    }
+}
+
+impl<
+        K: StableType + AsFixedSizeBytes + Ord + DeepCopy,
+        V: StableType + AsFixedSizeBytes + DeepCopy,
+    > DeepCopy for SBTreeMap<K, V>
+{
+    fn deep_copy(&self) -> Result<Self, OutOfMemory> {
+        let mut it = Self::new();
+
+        for (k, v) in self.iter() {
+            it.try_insert((*k).deep_copy()?, (*v).deep_copy()?)?;
+        }
+
+        Ok(it)
     }
 }
 
@@ -1674,10 +2787,12 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> A
                 Some(BTreeNode::from_ptr(ptr))
             },
             certified: false,
+            counted: false,
             len,
             stable_drop_flag: false,
             _buf: Vec::default(),
             _stack: Vec::default(),
+            _shadow: None,
         }
     }
 }
@@ -1798,166 +2913,712 @@ impl<K: StableType + AsFixedSizeBytes + Ord + Debug, V: StableType + AsFixedSize
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.write_str("{")?;
 
-        for (idx, (k, v)) in self.iter().enumerate() {
+        for (idx, (k, v)) in self.iter().take(DEBUG_ELEMENTS_LIMIT).enumerate() {
+            if idx > 0 {
+                f.write_str(", ")?;
+            }
+
             k.fmt(f)?;
             f.write_str(": ")?;
             v.fmt(f)?;
+        }
 
-            if (idx as u64) < self.len() - 1 {
-                f.write_str(", ")?;
-            }
+        if self.len() > DEBUG_ELEMENTS_LIMIT as u64 {
+            write!(f, ", ...{} more", self.len() - DEBUG_ELEMENTS_LIMIT as u64)?;
         }
 
         f.write_str("}")
     }
 }
 
-pub(crate) trait IBTreeNode {
-    unsafe fn from_ptr(ptr: StablePtr) -> Self;
-    fn as_ptr(&self) -> StablePtr;
-    unsafe fn copy(&self) -> Self;
-}
+// `true`, if all keys greater than or equal to this one are after the start of the range
+fn starts_before<Q: Ord + ?Sized, R: RangeBounds<Q>>(range: &R, key: &Q) -> bool {
+    match range.start_bound() {
+        Bound::Included(start) => start <= key,
+        Bound::Excluded(start) => start < key,
+        Bound::Unbounded => true,
+    }
+}
+
+// `true`, if all keys less than this one are before the end of the range
+fn ends_after<Q: Ord + ?Sized, R: RangeBounds<Q>>(range: &R, key: &Q) -> bool {
+    match range.end_bound() {
+        Bound::Included(end) | Bound::Excluded(end) => key <= end,
+        Bound::Unbounded => true,
+    }
+}
+
+pub(crate) trait IBTreeNode {
+    unsafe fn from_ptr(ptr: StablePtr) -> Self;
+    fn as_ptr(&self) -> StablePtr;
+    unsafe fn copy(&self) -> Self;
+}
+
+pub(crate) enum BTreeNode<K, V> {
+    Internal(InternalBTreeNode<K>),
+    Leaf(LeafBTreeNode<K, V>),
+}
+
+impl<K, V> BTreeNode<K, V> {
+    pub(crate) fn from_ptr(ptr: StablePtr) -> Self {
+        Self::try_from_ptr(ptr).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub(crate) fn try_from_ptr(ptr: StablePtr) -> Result<Self, CorruptData> {
+        let node_type_ptr = SSlice::_offset(ptr, NODE_TYPE_OFFSET);
+        let node_type: u8 = unsafe { crate::mem::read_fixed_for_reference(node_type_ptr) };
+
+        unsafe {
+            match node_type {
+                NODE_TYPE_INTERNAL => Ok(Self::Internal(InternalBTreeNode::<K>::from_ptr(ptr))),
+                NODE_TYPE_LEAF => Ok(Self::Leaf(LeafBTreeNode::<K, V>::from_ptr(ptr))),
+                _ => Err(CorruptData::new(node_type_ptr, "Invalid B-tree node type")),
+            }
+        }
+    }
+
+    pub(crate) fn as_ptr(&self) -> StablePtr {
+        match self {
+            Self::Internal(i) => i.as_ptr(),
+            Self::Leaf(l) => l.as_ptr(),
+        }
+    }
+
+    pub(crate) unsafe fn copy(&self) -> Self {
+        match self {
+            Self::Internal(i) => Self::Internal(i.copy()),
+            Self::Leaf(l) => Self::Leaf(l.copy()),
+        }
+    }
+}
+
+#[cfg(feature = "orphan_collector")]
+impl<K: StableType + AsFixedSizeBytes + Ord + Trace, V: StableType + AsFixedSizeBytes + Trace>
+    SBTreeMap<K, V>
+{
+    /// Returns the size of stable memory, owned by this collection
+    ///
+    /// Includes its nodes, plus the memory owned by its keys and values, see [MemoryUsage].
+    ///
+    /// Only available with the `orphan_collector` feature.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        memory_usage(self, self.len())
+    }
+}
+
+#[cfg(feature = "orphan_collector")]
+impl<K: StableType + AsFixedSizeBytes + Ord + Trace, V: StableType + AsFixedSizeBytes + Trace> Trace
+    for SBTreeMap<K, V>
+{
+    fn trace(&self, ptrs: &mut Vec<StablePtr>) {
+        let mut stack = match &self.root {
+            Some(root) => vec![root.as_ptr()],
+            None => return,
+        };
+
+        while let Some(ptr) = stack.pop() {
+            ptrs.push(ptr);
+
+            if let BTreeNode::Internal(node) = BTreeNode::<K, V>::from_ptr(ptr) {
+                for idx in 0..=node.read_len() {
+                    stack.push(StablePtr::from_fixed_size_bytes(
+                        &node.read_child_ptr_buf(idx),
+                    ));
+                }
+            }
+        }
+
+        if K::OWNS_MEMORY || V::OWNS_MEMORY {
+            for (k, v) in self.iter() {
+                k.trace(ptrs);
+                v.trace(ptrs);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::btree_map::{
+        BTreeNode, SBTreeMap, MIN_LEN_AFTER_SPLIT, NODE_TYPE_INTERNAL,
+    };
+    use crate::encoding::AsFixedSizeBytes;
+    use crate::primitive::StableType;
+    use crate::utils::budget::Budget;
+    use crate::utils::mem_context::FaultInjection;
+    use crate::utils::test::generate_random_string;
+    use crate::utils::DEBUG_ELEMENTS_LIMIT;
+    use crate::{
+        _debug_validate_allocator, get_allocated_size, get_ref_count, init_allocator,
+        retrieve_custom_data, stable, stable_memory_init, stable_memory_post_upgrade,
+        stable_memory_pre_upgrade, store_custom_data, SBox, SStrKey,
+    };
+    #[cfg(feature = "heavy-tests")]
+    use rand::rngs::StdRng;
+    use rand::rngs::ThreadRng;
+    use rand::seq::SliceRandom;
+    use rand::{thread_rng, Rng};
+    use std::collections::BTreeMap;
+    use std::ops::Bound;
+
+    #[test]
+    fn random_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let iterations = 1000;
+            let mut map = SBTreeMap::<u64, u64>::default();
+
+            let mut example = Vec::new();
+            for i in 0..iterations {
+                example.push(i as u64);
+            }
+            example.shuffle(&mut thread_rng());
+
+            for i in 0..iterations {
+                map.debug_print_stack();
+                assert!(map._stack.is_empty());
+                assert!(map.insert(example[i], example[i]).unwrap().is_none());
+
+                for j in 0..i {
+                    assert!(
+                        map.contains_key(&example[j]),
+                        "don't contain {}",
+                        example[j]
+                    );
+                    assert_eq!(
+                        *map.get(&example[j]).unwrap(),
+                        example[j],
+                        "unable to get {}",
+                        example[j]
+                    );
+                }
+            }
+
+            assert_eq!(map.insert(0, 1).unwrap().unwrap(), 0);
+            assert_eq!(map.insert(0, 0).unwrap().unwrap(), 1);
+
+            map.debug_print();
+
+            example.shuffle(&mut thread_rng());
+            for i in 0..iterations {
+                assert!(map._stack.is_empty());
+
+                assert_eq!(map.remove(&example[i]), Some(example[i]));
+
+                for j in (i + 1)..iterations {
+                    assert!(
+                        map.contains_key(&example[j]),
+                        "don't contain {}",
+                        example[j]
+                    );
+                    assert_eq!(
+                        *map.get(&example[j]).unwrap(),
+                        example[j],
+                        "unable to get {}",
+                        example[j]
+                    );
+                }
+            }
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn iters_work_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut map = SBTreeMap::<u64, u64>::default();
+
+            for i in 0..200 {
+                map.insert(i, i);
+            }
+
+            let mut i = 0u64;
+
+            for (mut k, mut v) in map.iter() {
+                assert_eq!(i, *k);
+                assert_eq!(i, *v);
+
+                print!("({:?}, {:?}), ", *k, *v);
+
+                i += 1;
+            }
+
+            println!();
+
+            assert_eq!(i, 200);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn get_page_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut map = SBTreeMap::<u64, u64>::default();
+            assert_eq!(map.get_page(None, 10), (vec![], None));
+
+            for i in 0..95 {
+                map.insert(i * 2, i).unwrap();
+            }
+
+            let mut entries = Vec::new();
+            let mut next = None;
+            loop {
+                let (page, continuation) = map.get_page(next, 10);
+                assert!(page.len() <= 10);
+                entries.extend(page);
+
+                next = match continuation {
+                    Some(k) => Some(k),
+                    None => break,
+                };
+            }
+
+            assert_eq!(entries, (0..95).map(|i| (i * 2, i)).collect::<Vec<_>>());
+
+            let (page, next) = map.get_page(Some(5), 2);
+            assert_eq!(page, vec![(6, 3), (8, 4)]);
+            assert_eq!(next, Some(10));
+
+            assert_eq!(map.get_page(Some(188), 2), (vec![(188, 94)], None));
+            assert_eq!(map.get_page(Some(189), 2), (vec![], None));
+            assert_eq!(map.get_page(Some(0), 0), (vec![], Some(0)));
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn iter_resumable_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut map = SBTreeMap::<u64, u64>::default();
+            assert!(map
+                .iter_resumable(None, Budget::Elements(10))
+                .into_continuation()
+                .is_none());
+
+            for i in 0..95 {
+                map.insert(i * 2, i).unwrap();
+            }
+
+            let mut entries = Vec::new();
+            let mut calls = 0;
+            let mut from = None;
+            loop {
+                let mut iter = map.iter_resumable(from, Budget::Elements(10));
+                entries.extend((&mut iter).map(|(k, v)| (*k, *v)));
+                calls += 1;
+
+                from = match iter.into_continuation() {
+                    Some(k) => Some(k),
+                    None => break,
+                };
+
+                // entries inserted before the continuation key are not visited
+                map.insert(1, 1).unwrap();
+            }
+
+            assert_eq!(calls, 10);
+            assert_eq!(entries, (0..95).map(|i| (i * 2, i)).collect::<Vec<_>>());
+
+            let mut iter = map.iter_resumable(Some(5), Budget::Elements(2));
+            assert_eq!((&mut iter).map(|(k, _)| *k).collect::<Vec<_>>(), vec![6, 8]);
+            assert_eq!(iter.continuation(), Some(&10));
+
+            assert_eq!(
+                map.iter_resumable(Some(189), Budget::Elements(2)).count(),
+                0
+            );
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn range_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut map = SBTreeMap::<u64, u64>::default();
+            let mut example = BTreeMap::new();
+
+            assert!(map.range(..).next().is_none());
+
+            for i in 0..300 {
+                map.insert(i * 2, i).unwrap();
+                example.insert(i * 2, i);
+            }
+
+            let bounds = [0u64, 1, 2, 15, 16, 17, 255, 300, 597, 598, 599, 1000];
+
+            for from in bounds {
+                for to in bounds {
+                    if from > to {
+                        continue;
+                    }
+
+                    let actual = map.range(from..to).map(|(k, _)| *k).collect::<Vec<_>>();
+                    let expected = example.range(from..to).map(|(k, _)| *k).collect::<Vec<_>>();
+                    assert_eq!(actual, expected);
+
+                    let actual = map
+                        .range(from..=to)
+                        .rev()
+                        .map(|(k, _)| *k)
+                        .collect::<Vec<_>>();
+                    let expected = example
+                        .range(from..=to)
+                        .rev()
+                        .map(|(k, _)| *k)
+                        .collect::<Vec<_>>();
+                    assert_eq!(actual, expected);
+                }
+
+                assert_eq!(map.range(from..).count(), example.range(from..).count());
+                assert_eq!(map.range(..from).count(), example.range(..from).count());
+            }
+
+            let mut iter = map.range(10..20);
+            assert_eq!(*iter.next().unwrap().0, 10);
+            assert_eq!(*iter.next_back().unwrap().0, 18);
+            assert_eq!(iter.map(|(k, _)| *k).collect::<Vec<_>>(), vec![12, 14, 16]);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn try_insert_fails_cleanly() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut map = SBTreeMap::<u64, u64>::new();
+
+            stable::inject_faults(FaultInjection {
+                max_pages: Some(stable::size_pages()),
+                panic_after_writes: None,
+            });
+
+            let mut inserted = 0;
+            while map.try_insert(inserted, inserted).is_ok() {
+                inserted += 1;
+            }
+
+            assert!(inserted > 0);
+            assert!(map.try_insert(inserted, inserted).is_err());
+
+            map.validate().unwrap();
+            assert_eq!(map.len(), inserted);
+            assert!(map.iter().map(|(k, _)| *k).eq(0..inserted));
+
+            stable::inject_faults(FaultInjection::default());
+
+            assert!(map.try_insert(inserted, inserted).is_ok());
+            map.validate().unwrap();
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn insert_with_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut map = SBTreeMap::<SBox<u64>, u64>::default();
+            let mut example = BTreeMap::new();
+            let mut rng = thread_rng();
+
+            for _ in 0..5000 {
+                let key = rng.gen_range(0..500u64);
+
+                map.insert_with(SBox::new(key).unwrap(), || 1, |it| *it += 1)
+                    .unwrap();
+                *example.entry(key).or_insert(0) += 1;
+            }
+
+            map.validate().unwrap();
+            assert_eq!(map.len(), example.len() as u64);
+
+            for ((k1, v1), (k2, v2)) in map.iter().zip(example.iter()) {
+                assert_eq!(**k1, *k2);
+                assert_eq!(*v1, *v2);
+            }
+
+            let mut called = false;
+            map.insert_with(SBox::new(1000).unwrap(), || 7, |_| called = true)
+                .unwrap();
+            assert!(!called);
+            assert_eq!(*map.get(&1000).unwrap(), 7);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn get_or_insert_with_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut map = SBTreeMap::<SBox<u64>, SBox<String>>::default();
+            let mut example = BTreeMap::new();
+            let mut rng = thread_rng();
 
-pub(crate) enum BTreeNode<K, V> {
-    Internal(InternalBTreeNode<K>),
-    Leaf(LeafBTreeNode<K, V>),
-}
+            for _ in 0..3000 {
+                let key = rng.gen_range(0..300u64);
 
-impl<K, V> BTreeNode<K, V> {
-    pub(crate) fn from_ptr(ptr: StablePtr) -> Self {
-        let node_type: u8 =
-            unsafe { crate::mem::read_fixed_for_reference(SSlice::_offset(ptr, NODE_TYPE_OFFSET)) };
+                let mut value = map
+                    .get_or_insert_with(SBox::new(key).unwrap(), || {
+                        SBox::new(String::new()).unwrap()
+                    })
+                    .unwrap();
+                let updated = format!("{}{}", String::clone(&value), key % 10);
+                *value = SBox::new(updated).unwrap();
 
-        unsafe {
-            match node_type {
-                NODE_TYPE_INTERNAL => Self::Internal(InternalBTreeNode::<K>::from_ptr(ptr)),
-                NODE_TYPE_LEAF => Self::Leaf(LeafBTreeNode::<K, V>::from_ptr(ptr)),
-                _ => unreachable!(),
+                example
+                    .entry(key)
+                    .or_insert_with(String::new)
+                    .push_str(&(key % 10).to_string());
             }
-        }
-    }
 
-    pub(crate) fn as_ptr(&self) -> StablePtr {
-        match self {
-            Self::Internal(i) => i.as_ptr(),
-            Self::Leaf(l) => l.as_ptr(),
-        }
-    }
+            map.validate().unwrap();
+            assert_eq!(map.len(), example.len() as u64);
 
-    pub(crate) unsafe fn copy(&self) -> Self {
-        match self {
-            Self::Internal(i) => Self::Internal(i.copy()),
-            Self::Leaf(l) => Self::Leaf(l.copy()),
+            for ((k1, v1), (k2, v2)) in map.iter().zip(example.iter()) {
+                assert_eq!(**k1, *k2);
+                assert_eq!(String::clone(&v1), *v2);
+            }
         }
-    }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::collections::btree_map::SBTreeMap;
-    use crate::utils::test::generate_random_string;
-    use crate::{
-        _debug_validate_allocator, get_allocated_size, init_allocator, retrieve_custom_data,
-        stable, stable_memory_init, stable_memory_post_upgrade, stable_memory_pre_upgrade,
-        store_custom_data, SBox,
-    };
-    use rand::rngs::ThreadRng;
-    use rand::seq::SliceRandom;
-    use rand::{thread_rng, Rng};
-    use std::collections::BTreeMap;
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
 
     #[test]
-    fn random_works_fine() {
+    fn iter_prefix_works_fine() {
         stable::clear();
         stable_memory_init();
 
         {
-            let iterations = 1000;
-            let mut map = SBTreeMap::<u64, u64>::default();
+            let mut map = SBTreeMap::<(u64, u64), u64>::default();
+            assert!(map.iter_prefix(&1).next().is_none());
 
-            let mut example = Vec::new();
-            for i in 0..iterations {
-                example.push(i as u64);
+            for owner in 0..30u64 {
+                for id in 0..(owner % 7) * 10 {
+                    map.insert((owner, id), owner + id).unwrap();
+                }
             }
-            example.shuffle(&mut thread_rng());
 
-            for i in 0..iterations {
-                map.debug_print_stack();
-                assert!(map._stack.is_empty());
-                assert!(map.insert(example[i], example[i]).unwrap().is_none());
+            for owner in 0..31u64 {
+                let ids = map
+                    .iter_prefix(&owner)
+                    .map(|(k, _)| k.1)
+                    .collect::<Vec<_>>();
+                let expected = (0..(owner % 7) * 10).collect::<Vec<_>>();
+                assert_eq!(ids, if owner < 30 { expected } else { vec![] });
 
-                for j in 0..i {
-                    assert!(
-                        map.contains_key(&example[j]),
-                        "don't contain {}",
-                        example[j]
-                    );
-                    assert_eq!(
-                        *map.get(&example[j]).unwrap(),
-                        example[j],
-                        "unable to get {}",
-                        example[j]
-                    );
-                }
+                let last = map.iter_prefix(&owner).next_back().map(|(k, _)| *k);
+                assert_eq!(
+                    last,
+                    map.range((owner, 0)..(owner + 1, 0))
+                        .next_back()
+                        .map(|(k, _)| *k)
+                );
             }
 
-            assert_eq!(map.insert(0, 1).unwrap().unwrap(), 0);
-            assert_eq!(map.insert(0, 0).unwrap().unwrap(), 1);
-
-            map.debug_print();
+            let mut names = SBTreeMap::<SStrKey<8>, u64>::default();
+            for (i, name) in ["a", "ab", "abc", "abd", "b", "ba", ""].iter().enumerate() {
+                names.insert(SStrKey::new(name).unwrap(), i as u64).unwrap();
+            }
 
-            example.shuffle(&mut thread_rng());
-            for i in 0..iterations {
-                assert!(map._stack.is_empty());
+            let collect = |prefix: &str| {
+                names
+                    .iter_prefix(prefix)
+                    .map(|(k, _)| k.as_str().to_string())
+                    .collect::<Vec<_>>()
+            };
 
-                assert_eq!(map.remove(&example[i]), Some(example[i]));
+            assert_eq!(collect("ab"), vec!["ab", "abc", "abd"]);
+            assert_eq!(collect("a"), vec!["a", "ab", "abc", "abd"]);
+            assert_eq!(collect("abc"), vec!["abc"]);
+            assert_eq!(collect("c"), Vec::<String>::new());
+            assert_eq!(collect("").len(), 7);
 
-                for j in (i + 1)..iterations {
-                    assert!(
-                        map.contains_key(&example[j]),
-                        "don't contain {}",
-                        example[j]
-                    );
-                    assert_eq!(
-                        *map.get(&example[j]).unwrap(),
-                        example[j],
-                        "unable to get {}",
-                        example[j]
-                    );
-                }
+            let mut bytes = SBTreeMap::<[u8; 2], u64>::default();
+            for i in 0..=u8::MAX {
+                bytes.insert([i / 16, i], i as u64).unwrap();
             }
+
+            assert_eq!(bytes.iter_prefix(&[3u8][..]).count(), 16);
+            assert_eq!(
+                bytes
+                    .iter_prefix(&[3u8, 50][..])
+                    .map(|(_, v)| *v)
+                    .collect::<Vec<_>>(),
+                vec![50]
+            );
+            assert_eq!(bytes.iter_prefix(&[3u8, 64][..]).count(), 0);
         }
 
         _debug_validate_allocator();
         assert_eq!(get_allocated_size(), 0);
     }
 
+    // checks that all leaves are at the same depth, that no node except the root is underfull and
+    // that keys of each child are between the separators around it, returns the min and max keys
+    fn check_structure<V: StableType + AsFixedSizeBytes>(
+        node: BTreeNode<u64, V>,
+        depth: usize,
+        leaf_depth: &mut Option<usize>,
+    ) -> Option<(u64, u64)> {
+        match node {
+            BTreeNode::Internal(node) => {
+                let len = node.read_len();
+                assert!(len >= if depth == 0 { 1 } else { MIN_LEN_AFTER_SPLIT });
+
+                let mut min = None;
+                let mut max = None;
+
+                for i in 0..=len {
+                    let ptr = u64::from_fixed_size_bytes(&node.read_child_ptr_buf(i));
+                    let (child_min, child_max) =
+                        check_structure(BTreeNode::from_ptr(ptr), depth + 1, leaf_depth).unwrap();
+
+                    if i > 0 {
+                        assert!(node.read_key_as_reference(i - 1) <= child_min);
+                    }
+                    if i < len {
+                        assert!(child_max < node.read_key_as_reference(i));
+                    }
+
+                    min = min.or(Some(child_min));
+                    max = Some(child_max);
+                }
+
+                Some((min.unwrap(), max.unwrap()))
+            }
+            BTreeNode::Leaf(node) => {
+                let len = node.read_len();
+                assert!(depth == 0 || len >= MIN_LEN_AFTER_SPLIT);
+
+                match leaf_depth {
+                    Some(d) => assert_eq!(*d, depth),
+                    None => *leaf_depth = Some(depth),
+                }
+
+                if len == 0 {
+                    None
+                } else {
+                    Some((*node.get_key(0), *node.get_key(len - 1)))
+                }
+            }
+        }
+    }
+
     #[test]
-    fn iters_work_fine() {
+    fn remove_range_works_fine() {
         stable::clear();
         stable_memory_init();
 
         {
-            let mut map = SBTreeMap::<u64, u64>::default();
+            let mut map = SBTreeMap::<u64, SBox<String>>::default();
+            let mut example = BTreeMap::new();
+            let mut rng = thread_rng();
 
-            for i in 0..200 {
-                map.insert(i, i);
+            assert_eq!(map.remove_range(..), 0);
+
+            for i in 0..5000u64 {
+                map.insert(i, SBox::new(format!("value {}", i)).unwrap())
+                    .unwrap();
+                example.insert(i, format!("value {}", i));
             }
 
-            let mut i = 0u64;
+            assert_eq!(map.remove_range(10..5), 0);
+            assert_eq!(map.remove_range(10..10), 0);
+
+            for round in 0..40 {
+                let a = rng.gen_range(0..5200u64);
+                let b = rng.gen_range(a..(a + rng.gen_range(1..2000u64)));
+
+                let (actual, expected) = match round % 4 {
+                    0 => (map.remove_range(a..b), example.range(a..b).count() as u64),
+                    1 => (map.remove_range(a..=b), example.range(a..=b).count() as u64),
+                    2 => (
+                        map.remove_range(..a / 8),
+                        example.range(..a / 8).count() as u64,
+                    ),
+                    _ => (
+                        map.remove_range((Bound::Excluded(a), Bound::Unbounded)),
+                        example
+                            .range((Bound::Excluded(a), Bound::Unbounded))
+                            .count() as u64,
+                    ),
+                };
 
-            for (mut k, mut v) in map.iter() {
-                assert_eq!(i, *k);
-                assert_eq!(i, *v);
+                assert_eq!(actual, expected);
+                example.retain(|k, _| match round % 4 {
+                    0 => !(a..b).contains(k),
+                    1 => !(a..=b).contains(k),
+                    2 => *k >= a / 8,
+                    _ => *k <= a,
+                });
+
+                // refill some of the removed keys
+                for _ in 0..300 {
+                    let k = rng.gen_range(0..5200u64);
+                    map.insert(k, SBox::new(format!("value {}", k)).unwrap())
+                        .unwrap();
+                    example.insert(k, format!("value {}", k));
+                }
 
-                print!("({:?}, {:?}), ", *k, *v);
+                map.validate().unwrap();
+                if let Some(root) = map.get_root() {
+                    check_structure(root, 0, &mut None);
+                }
 
-                i += 1;
+                assert_eq!(map.len(), example.len() as u64);
+
+                let actual = map
+                    .iter()
+                    .map(|(k, v)| (*k, String::clone(&v)))
+                    .collect::<Vec<_>>();
+                let expected = example
+                    .iter()
+                    .map(|(k, v)| (*k, v.clone()))
+                    .collect::<Vec<_>>();
+                assert_eq!(actual, expected);
+
+                let actual = map.iter_rev().map(|(k, _)| *k).collect::<Vec<_>>();
+                let expected = example.keys().rev().copied().collect::<Vec<_>>();
+                assert_eq!(actual, expected);
             }
 
-            println!();
-
-            assert_eq!(i, 200);
+            assert_eq!(map.remove_range(..), example.len() as u64);
+            assert!(map.is_empty());
+            assert!(map.iter().next().is_none());
         }
 
         _debug_validate_allocator();
@@ -2112,6 +3773,80 @@ mod tests {
         }
     }
 
+    #[cfg(feature = "candid_chunks")]
+    #[test]
+    fn candid_chunks_work_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut map = SBTreeMap::<u64, u32>::new();
+            for i in 0..1000 {
+                map.insert(i, i as u32 * 2).unwrap();
+            }
+
+            let chunks = map.to_candid_chunks(300).collect::<Vec<_>>();
+            assert_eq!(chunks.len(), 4);
+
+            let entries: Vec<(u64, u32)> = candid::decode_one(&chunks[3]).unwrap();
+            assert_eq!(entries.len(), 100);
+            assert_eq!(entries[0], (900, 1800));
+
+            let copy = SBTreeMap::<u64, u32>::from_candid_chunks(&chunks).unwrap();
+            assert_eq!(copy.len(), 1000);
+            for ((k1, v1), (k2, v2)) in map.iter().zip(copy.iter()) {
+                assert_eq!(*k1, *k2);
+                assert_eq!(*v1, *v2);
+            }
+
+            let mut copy = SBTreeMap::<u64, u32>::new();
+            assert!(copy.insert_candid_chunk(&[1, 2, 3]).is_err());
+            assert!(copy.insert_candid_chunk(&chunks[1]).is_ok());
+            assert_eq!(copy.len(), 300);
+        }
+
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[cfg(feature = "serde_collections")]
+    #[test]
+    fn serde_works_fine() {
+        use serde::de::value::{Error, MapDeserializer};
+        use serde::Deserialize;
+        use serde_test::{assert_ser_tokens, Token};
+
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut map = SBTreeMap::<u64, u32>::new();
+            map.insert(2, 20).unwrap();
+            map.insert(1, 10).unwrap();
+
+            assert_ser_tokens(
+                &map,
+                &[
+                    Token::Map { len: Some(2) },
+                    Token::U64(1),
+                    Token::U32(10),
+                    Token::U64(2),
+                    Token::U32(20),
+                    Token::MapEnd,
+                ],
+            );
+
+            let de =
+                MapDeserializer::<_, Error>::new(vec![(3u64, 30u32), (1, 10), (3, 33)].into_iter());
+            let copy = SBTreeMap::<u64, u32>::deserialize(de).unwrap();
+
+            assert_eq!(copy.len(), 2);
+            assert_eq!(*copy.get(&1).unwrap(), 10);
+            assert_eq!(*copy.get(&3).unwrap(), 33);
+        }
+
+        assert_eq!(get_allocated_size(), 0);
+    }
+
     #[test]
     fn fuzzer_works_fine() {
         stable::clear();
@@ -2128,6 +3863,113 @@ mod tests {
         assert_eq!(get_allocated_size(), 0);
     }
 
+    #[test]
+    fn debug_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut map = SBTreeMap::<u64, u64>::new();
+            assert_eq!(format!("{:?}", map), "{}");
+
+            map.insert(1, 10).unwrap();
+            map.insert(2, 20).unwrap();
+            assert_eq!(format!("{:?}", map), "{1: 10, 2: 20}");
+
+            for i in 3..=(DEBUG_ELEMENTS_LIMIT as u64 + 10) {
+                map.insert(i, i * 10).unwrap();
+            }
+
+            let it = format!("{:?}", map);
+            assert!(it.starts_with("{1: 10, 2: 20, 3: 30"));
+            assert!(it.ends_with(", 100: 1000, ...10 more}"));
+
+            #[cfg(feature = "debug_structure")]
+            {
+                let structure = map.debug_structure();
+                assert!(structure.lines().count() > 1);
+                assert!(structure.starts_with("InternalBTreeNode"));
+            }
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn validate_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut map = SBTreeMap::<u64, bool>::new();
+            assert!(map.validate().is_ok());
+
+            for i in 0..1000u64 {
+                map.insert(i, i % 2 == 0).unwrap();
+            }
+            assert!(map.validate().is_ok());
+
+            let value_ptr = map.get(&500).unwrap()._ptr();
+            unsafe { crate::mem::write_bytes(value_ptr, &[2]) };
+
+            let err = map.validate().err().unwrap();
+            assert_eq!(err.offset, value_ptr);
+            assert_eq!(err.reason, "Invalid bool");
+
+            unsafe { crate::mem::write_bytes(value_ptr, &[1]) };
+
+            let node_type_ptr = map.root.as_ref().unwrap().as_ptr();
+            unsafe { crate::mem::write_bytes(node_type_ptr, &[0]) };
+
+            let err = map.validate().err().unwrap();
+            assert_eq!(err.offset, node_type_ptr);
+
+            unsafe { crate::mem::write_bytes(node_type_ptr, &[NODE_TYPE_INTERNAL]) };
+            assert!(map.validate().is_ok());
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn shared_values_work_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut a = SBTreeMap::new();
+            let mut b = SBTreeMap::new();
+
+            let value = SBox::new(generate_random_string(&mut thread_rng())).unwrap();
+            let expected = value.as_str().to_string();
+
+            for i in 0..100u64 {
+                a.insert_shared(i, &value).unwrap();
+                b.insert_shared(i, &value).unwrap();
+            }
+
+            assert_eq!(get_ref_count(value.as_ptr()), 201);
+            drop(value);
+
+            for i in 0..50u64 {
+                assert_eq!(a.remove(&i).unwrap().as_str(), expected);
+            }
+
+            drop(a);
+
+            for i in 0..100u64 {
+                assert_eq!(b.get(&i).unwrap().as_str(), expected);
+            }
+
+            assert_eq!(get_ref_count(b.get(&0).unwrap().as_ptr()), 100);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
     #[test]
     fn fuzzer_works_fine_limited_memory() {
         stable::clear();
@@ -2143,4 +3985,135 @@ mod tests {
 
         assert_eq!(get_allocated_size(), 0);
     }
+
+    #[cfg(feature = "heavy-tests")]
+    fn assert_same(map: &SBTreeMap<u64, u64>, example: &BTreeMap<u64, u64>, rng: &mut StdRng) {
+        map.validate().unwrap();
+        assert_eq!(map.len(), example.len() as u64);
+
+        assert!(map
+            .iter()
+            .map(|(k, v)| (*k, *v))
+            .eq(example.iter().map(|(k, v)| (*k, *v))));
+        assert!(map
+            .iter()
+            .rev()
+            .map(|(k, v)| (*k, *v))
+            .eq(example.iter().rev().map(|(k, v)| (*k, *v))));
+
+        for _ in 0..100 {
+            let from = rng.gen_range(0..HEAVY_KEY_RANGE);
+            let to = rng.gen_range(from..=HEAVY_KEY_RANGE);
+
+            assert!(map
+                .range(from..to)
+                .map(|(k, _)| *k)
+                .eq(example.range(from..to).map(|(k, _)| *k)));
+            assert!(map
+                .range(from..=to)
+                .rev()
+                .map(|(k, _)| *k)
+                .eq(example.range(from..=to).rev().map(|(k, _)| *k)));
+        }
+    }
+
+    #[cfg(feature = "heavy-tests")]
+    const HEAVY_KEY_RANGE: u64 = 200_000;
+
+    #[cfg(feature = "heavy-tests")]
+    fn heavy_key(rng: &mut StdRng, wave: u64, i: u64) -> u64 {
+        match wave % 4 {
+            // uniform
+            0 => rng.gen_range(0..HEAVY_KEY_RANGE),
+            // ascending, splits and merges only happen at the right edge
+            1 => i % HEAVY_KEY_RANGE,
+            // descending, same at the left edge
+            2 => HEAVY_KEY_RANGE - 1 - i % HEAVY_KEY_RANGE,
+            // clustered, a few hot subtrees get most of the traffic
+            _ => {
+                let cluster = rng.gen_range(0..8u64) * (HEAVY_KEY_RANGE / 8);
+                cluster + rng.gen_range(0..1000)
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "heavy-tests")]
+    fn differential_stress_works_fine() {
+        use rand::SeedableRng;
+
+        let seed = thread_rng().gen::<u64>();
+        println!("differential_stress_works_fine seed: {}", seed);
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut map = SBTreeMap::<u64, u64>::new();
+            let mut example = BTreeMap::new();
+
+            for wave in 0..8u64 {
+                // growing phase, up to tens of thousands of keys
+                let target_len = rng.gen_range(20_000..60_000usize);
+                let mut i = 0u64;
+
+                while example.len() < target_len {
+                    let key = heavy_key(&mut rng, wave, i);
+                    let value = rng.gen::<u64>();
+                    i += 1;
+
+                    if rng.gen_bool(0.75) {
+                        assert_eq!(
+                            map.insert(key, value).unwrap(),
+                            example.insert(key, value),
+                            "seed {}",
+                            seed
+                        );
+                    } else {
+                        assert_eq!(map.remove(&key), example.remove(&key), "seed {}", seed);
+                    }
+
+                    if i % 10_000 == 0 {
+                        assert_same(&map, &example, &mut rng);
+                    }
+                }
+
+                assert_same(&map, &example, &mut rng);
+
+                // shrinking phase, down to a few keys, removing existing ones most of the time
+                let target_len = rng.gen_range(0..100usize);
+                let mut keys = example.keys().copied().collect::<Vec<_>>();
+                keys.shuffle(&mut rng);
+
+                for (j, key) in keys.into_iter().enumerate() {
+                    if example.len() <= target_len {
+                        break;
+                    }
+
+                    assert_eq!(map.remove(&key), example.remove(&key), "seed {}", seed);
+
+                    let other = heavy_key(&mut rng, wave + 1, j as u64);
+                    if rng.gen_bool(0.1) {
+                        assert_eq!(
+                            map.insert(other, other).unwrap(),
+                            example.insert(other, other),
+                            "seed {}",
+                            seed
+                        );
+                    }
+
+                    if j % 10_000 == 0 {
+                        assert_same(&map, &example, &mut rng);
+                    }
+                }
+
+                assert_same(&map, &example, &mut rng);
+                _debug_validate_allocator();
+            }
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
 }
@@ -0,0 +1,65 @@
+use crate::primitive::s_str_key::SStrKey;
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+
+/// Key, which can be scanned by its prefix, see [SBTreeMap::iter_prefix](crate::collections::SBTreeMap::iter_prefix)
+///
+/// Keys, starting with the same prefix, should form a contiguous run in the order of keys - this
+/// is what makes a prefix scan a bounded range, instead of a full scan.
+///
+/// Implemented for:
+/// * tuples - prefixed by their first element (or anything it can be borrowed as), so
+///   `((owner, kind), id)` keys are scanned by `(owner, kind)` and `(owner, (kind, id))` keys are
+///   scanned by `owner`;
+/// * [SStrKey] - prefixed by a [str];
+/// * byte arrays - prefixed by a byte slice.
+pub trait KeyPrefix<P: ?Sized> {
+    /// Returns [Ordering::Equal], if this key starts with the prefix, or the ordering of this key
+    /// relative to all the keys, which do
+    fn cmp_prefix(&self, prefix: &P) -> Ordering;
+}
+
+impl<P: Ord + ?Sized, A: Borrow<P>, B> KeyPrefix<P> for (A, B) {
+    #[inline]
+    fn cmp_prefix(&self, prefix: &P) -> Ordering {
+        self.0.borrow().cmp(prefix)
+    }
+}
+
+impl<P: Ord + ?Sized, A: Borrow<P>, B, C> KeyPrefix<P> for (A, B, C) {
+    #[inline]
+    fn cmp_prefix(&self, prefix: &P) -> Ordering {
+        self.0.borrow().cmp(prefix)
+    }
+}
+
+impl<P: Ord + ?Sized, A: Borrow<P>, B, C, D> KeyPrefix<P> for (A, B, C, D) {
+    #[inline]
+    fn cmp_prefix(&self, prefix: &P) -> Ordering {
+        self.0.borrow().cmp(prefix)
+    }
+}
+
+impl<const N: usize> KeyPrefix<str> for SStrKey<N> {
+    #[inline]
+    fn cmp_prefix(&self, prefix: &str) -> Ordering {
+        cmp_bytes_prefix(self.as_str().as_bytes(), prefix.as_bytes())
+    }
+}
+
+impl<const N: usize> KeyPrefix<[u8]> for [u8; N] {
+    #[inline]
+    fn cmp_prefix(&self, prefix: &[u8]) -> Ordering {
+        cmp_bytes_prefix(self, prefix)
+    }
+}
+
+// if the key doesn't start with the prefix, it is less than all the keys which do, iff it is less
+// than the prefix itself
+fn cmp_bytes_prefix(key: &[u8], prefix: &[u8]) -> Ordering {
+    if key.starts_with(prefix) {
+        Ordering::Equal
+    } else {
+        key.cmp(prefix)
+    }
+}
@@ -0,0 +1,577 @@
+use crate::collections::btree_map::internal_node::InternalBTreeNode;
+use crate::collections::btree_map::leaf_node::LeafBTreeNode;
+use crate::collections::btree_map::{BTreeNode, IBTreeNode, SBTreeMap};
+use crate::encoding::AsFixedSizeBytes;
+use crate::mem::allocator::EMPTY_PTR;
+#[cfg(feature = "orphan_collector")]
+use crate::mem::orphan_collector::Trace;
+use crate::mem::{StablePtr, StablePtrBuf};
+use crate::primitive::s_ref::SRef;
+use crate::primitive::StableType;
+use crate::{allocate_slot, deallocate_slot, make_sure_can_allocate_slots, OutOfMemory, SSlice};
+use std::borrow::Borrow;
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter};
+
+/// Returned by [SBTreeMap::commit_shadow], if the map was modified after the shadow was opened
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ShadowConflict;
+
+impl Display for ShadowConflict {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("The map was modified after the shadow was opened")
+    }
+}
+
+// nodes, allocated by the shadow, and nodes of the original map, which the shadow has copied
+#[derive(Default)]
+pub(crate) struct ShadowState {
+    private: BTreeSet<StablePtr>,
+    replaced: Vec<StablePtr>,
+}
+
+impl ShadowState {
+    fn copy_node<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes>(
+        &mut self,
+        ptr: StablePtr,
+    ) -> StablePtr {
+        let size = match BTreeNode::<K, V>::from_ptr(ptr) {
+            BTreeNode::Internal(_) => InternalBTreeNode::<K>::calc_byte_size(false, false),
+            BTreeNode::Leaf(_) => LeafBTreeNode::<K, V>::calc_size_bytes(false),
+        };
+
+        // the caller has already made sure there is enough memory
+        let copy_ptr = unsafe { allocate_slot(size).unwrap() };
+
+        let mut buf = vec![0u8; size as usize];
+        unsafe {
+            crate::mem::read_bytes(SSlice::_offset(ptr, 0), &mut buf);
+            crate::mem::write_bytes(SSlice::_offset(copy_ptr, 0), &buf);
+        }
+
+        self.private.insert(copy_ptr);
+        self.replaced.push(ptr);
+
+        copy_ptr
+    }
+}
+
+/// Shadow copy of an [SBTreeMap], which gets applied to the map atomically
+///
+/// Opened with [SBTreeMap::shadow]. Every update of the shadow copies the nodes it touches
+/// (copy-on-write), so the nodes of the map itself are never modified and the map stays readable
+/// as it was, while the shadow is being updated. [SBTreeMap::commit_shadow] then replaces the root of
+/// the map with the root of the shadow within a single call. This makes it possible to spread a batch
+/// of updates across several `await` points: if the canister traps (or the batch is simply
+/// abandoned) halfway through, the map is never observed half-updated or half-rebalanced.
+///
+/// Dropping the shadow without committing it releases all the nodes it has copied. If a shadow is
+/// lost without being dropped (for example, because a trap prevented the cleanup of a future), its
+/// nodes leak.
+///
+/// Only maps of [Copy] keys and values can be shadowed, because the shadow shares them with the
+/// original map. The shadow is a heap-only object, it can't be stored in stable memory and is lost
+/// after an upgrade. The map should not be modified directly or dropped while a shadow of it is open.
+/// Iteration over the shadow is not supported.
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::collections::SBTreeMap;
+/// # use ic_stable_memory::stable_memory_init;
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// let mut map = SBTreeMap::new();
+/// map.insert(1u64, 10u64).expect("Out of memory");
+///
+/// let mut shadow = map.shadow();
+/// shadow.insert(2, 20).expect("Out of memory");
+/// shadow.remove(&1).expect("Out of memory");
+///
+/// // the map is not affected, until the shadow is committed
+/// assert!(map.contains_key(&1));
+/// assert!(!map.contains_key(&2));
+///
+/// map.commit_shadow(shadow).expect("Conflict");
+///
+/// assert!(!map.contains_key(&1));
+/// assert_eq!(*map.get(&2).unwrap(), 20);
+/// ```
+pub struct SBTreeMapShadow<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes>
+{
+    tree: SBTreeMap<K, V>,
+    base_root: StablePtr,
+    base_len: u64,
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes>
+    SBTreeMapShadow<K, V>
+{
+    /// Same as [SBTreeMap::insert], but only updates the shadow
+    ///
+    /// # Errors
+    /// Returns [Err] with the key-value pair, if the canister is out of stable memory.
+    pub fn insert(&mut self, key: K, value: V) -> Result<Option<V>, (K, V)> {
+        if !self.tree.make_sure_can_copy_path() {
+            return Err((key, value));
+        }
+
+        self.tree.insert(key, value)
+    }
+
+    /// Same as [SBTreeMap::remove], but only updates the shadow
+    ///
+    /// # Errors
+    /// Returns [OutOfMemory], if there is not enough stable memory to copy the nodes, affected by
+    /// the removal.
+    pub fn remove<Q>(&mut self, key: &Q) -> Result<Option<V>, OutOfMemory>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        if !self.tree.contains_key(key) {
+            return Ok(None);
+        }
+
+        if !self.tree.make_sure_can_copy_path() {
+            return Err(OutOfMemory);
+        }
+
+        Ok(self.tree.remove(key))
+    }
+
+    /// Returns an immutable reference to the value by this key, as it is in the shadow
+    #[inline]
+    pub fn get<Q>(&self, key: &Q) -> Option<SRef<'_, V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.tree.get(key)
+    }
+
+    /// Returns `true` if there is an entry with this key in the shadow
+    #[inline]
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.tree.contains_key(key)
+    }
+
+    /// Returns the length of the shadow
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.tree.len()
+    }
+
+    /// Returns `true` if the shadow is empty
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> Drop
+    for SBTreeMapShadow<K, V>
+{
+    fn drop(&mut self) {
+        if let Some(state) = self.tree._shadow.take() {
+            for ptr in state.private {
+                deallocate_slot(ptr);
+            }
+        }
+
+        // the rest of the nodes belong to the original map
+        self.tree.root = None;
+    }
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord + Copy, V: StableType + AsFixedSizeBytes + Copy>
+    SBTreeMap<K, V>
+{
+    /// Opens a [SBTreeMapShadow] of this map
+    ///
+    /// Does not allocate any stable memory by itself.
+    pub fn shadow(&self) -> SBTreeMapShadow<K, V> {
+        debug_assert!(!self.certified);
+
+        let mut tree = Self::new();
+        tree.root = self.get_root();
+        tree.len = self.len;
+        tree.stable_drop_flag = false;
+        tree._shadow = Some(Box::default());
+
+        SBTreeMapShadow {
+            tree,
+            base_root: self.root_ptr(),
+            base_len: self.len,
+        }
+    }
+
+    /// Applies all updates of the shadow to this map at once, by replacing the root of the map with
+    /// the root of the shadow
+    ///
+    /// Releases the nodes of the map, which were replaced by their copies.
+    ///
+    /// # Errors
+    /// Returns [ShadowConflict], if the root or the length of this map have changed after the shadow
+    /// was opened (for example, because the shadow was opened from another map, or the map was
+    /// modified directly). In this case the shadow is dropped and the map stays unchanged. In-place
+    /// updates of values (e.g. via [SBTreeMap::get_mut]) are not detected.
+    pub fn commit_shadow(
+        &mut self,
+        mut shadow: SBTreeMapShadow<K, V>,
+    ) -> Result<(), ShadowConflict> {
+        if self.root_ptr() != shadow.base_root || self.len != shadow.base_len {
+            return Err(ShadowConflict);
+        }
+
+        let state = unsafe { shadow.tree._shadow.take().unwrap_unchecked() };
+
+        for ptr in &state.private {
+            if let BTreeNode::Leaf(leaf) = BTreeNode::<K, V>::from_ptr(*ptr) {
+                shadow.tree.relink_leaf(leaf);
+            }
+        }
+
+        for ptr in state.replaced {
+            deallocate_slot(ptr);
+        }
+
+        self.root = shadow.tree.root.take();
+        self.len = shadow.tree.len;
+
+        Ok(())
+    }
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> SBTreeMap<K, V> {
+    fn root_ptr(&self) -> StablePtr {
+        self.root
+            .as_ref()
+            .map(|it| it.as_ptr())
+            .unwrap_or(EMPTY_PTR)
+    }
+
+    // copies the root, if this map is a shadow and the root is still shared with the original map
+    pub(super) fn own_root(&mut self) {
+        let ptr = match &self.root {
+            Some(root) => root.as_ptr(),
+            None => return,
+        };
+
+        if let Some(state) = &mut self._shadow {
+            if !state.private.contains(&ptr) {
+                self.root = Some(BTreeNode::from_ptr(state.copy_node::<K, V>(ptr)));
+            }
+        }
+    }
+
+    // same as InternalBTreeNode::read_child_ptr_buf, but copies the child first, if this map is a
+    // shadow and the child is still shared with the original map
+    pub(super) fn own_child_ptr_buf(
+        &mut self,
+        parent: &mut InternalBTreeNode<K>,
+        idx: usize,
+    ) -> StablePtrBuf {
+        let child_ptr_buf = parent.read_child_ptr_buf(idx);
+
+        let state = match &mut self._shadow {
+            Some(state) => state,
+            None => return child_ptr_buf,
+        };
+
+        let child_ptr = u64::from_fixed_size_bytes(&child_ptr_buf);
+        if state.private.contains(&child_ptr) {
+            return child_ptr_buf;
+        }
+
+        let copy_ptr_buf = state.copy_node::<K, V>(child_ptr).as_new_fixed_size_bytes();
+        parent.write_child_ptr_buf(idx, &copy_ptr_buf);
+
+        copy_ptr_buf
+    }
+
+    pub(super) fn own_left_sibling<T: IBTreeNode>(
+        &mut self,
+        parent: &mut InternalBTreeNode<K>,
+        idx: usize,
+    ) -> Option<T> {
+        if idx == 0 {
+            return None;
+        }
+
+        let ptr = u64::from_fixed_size_bytes(&self.own_child_ptr_buf(parent, idx - 1));
+
+        unsafe { Some(T::from_ptr(ptr)) }
+    }
+
+    pub(super) fn own_right_sibling<T: IBTreeNode>(
+        &mut self,
+        parent: &mut InternalBTreeNode<K>,
+        idx: usize,
+        len: usize,
+    ) -> Option<T> {
+        if idx == len {
+            return None;
+        }
+
+        let ptr = u64::from_fixed_size_bytes(&self.own_child_ptr_buf(parent, idx + 1));
+
+        unsafe { Some(T::from_ptr(ptr)) }
+    }
+
+    #[inline]
+    pub(super) fn track_new_node(&mut self, ptr: StablePtr) {
+        if let Some(state) = &mut self._shadow {
+            state.private.insert(ptr);
+        }
+    }
+
+    #[inline]
+    pub(super) fn forget_node(&mut self, ptr: StablePtr) {
+        if let Some(state) = &mut self._shadow {
+            state.private.remove(&ptr);
+        }
+    }
+
+    // LeafBTreeNode::merge_min_len also updates the next leaf, which may still be shared with the
+    // original map - in a shadow such updates are reverted and redone by relink_leaf() at commit
+    pub(super) fn merge_leaves(
+        &mut self,
+        left: &mut LeafBTreeNode<K, V>,
+        right: LeafBTreeNode<K, V>,
+    ) {
+        self.forget_node(right.as_ptr());
+
+        let next_ptr = u64::from_fixed_size_bytes(&right.read_next_ptr_buf());
+        let shared_next = match &self._shadow {
+            Some(state) if next_ptr != 0 && !state.private.contains(&next_ptr) => {
+                let next = unsafe { LeafBTreeNode::<K, V>::from_ptr(next_ptr) };
+                let prev_buf = next.read_prev_ptr_buf();
+
+                Some((next, prev_buf))
+            }
+            _ => None,
+        };
+
+        left.merge_min_len(right);
+
+        if let Some((mut next, prev_buf)) = shared_next {
+            next.write_prev_ptr_buf(&prev_buf);
+        }
+    }
+
+    // worst case: the whole path from the root to a leaf and both siblings on each level get copied
+    fn make_sure_can_copy_path(&self) -> bool {
+        let internal_size = InternalBTreeNode::<K>::calc_byte_size(false, false);
+        let node_size = internal_size.max(LeafBTreeNode::<K, V>::calc_size_bytes(false));
+
+        make_sure_can_allocate_slots(&vec![node_size; 3 * (self.height() + 1)])
+    }
+
+    // writes correct prev and next pointers to the leaf and to its neighbors, which may still point
+    // to the replaced versions of each other
+    fn relink_leaf(&self, mut leaf: LeafBTreeNode<K, V>) {
+        if leaf.read_len() == 0 {
+            return;
+        }
+
+        let key = leaf.read_key_as_reference(0);
+
+        let mut left_subtree = None;
+        let mut right_subtree = None;
+        let mut node = unsafe { self.get_root().unwrap_unchecked() };
+
+        while let BTreeNode::Internal(internal_node) = node {
+            let len = internal_node.read_len();
+            let child_idx = match internal_node.binary_search(&key, len) {
+                Ok(idx) => idx + 1,
+                Err(idx) => idx,
+            };
+
+            if child_idx > 0 {
+                left_subtree = Some(internal_node.read_child_ptr_buf(child_idx - 1));
+            }
+            if child_idx < len {
+                right_subtree = Some(internal_node.read_child_ptr_buf(child_idx + 1));
+            }
+
+            let child_ptr =
+                u64::from_fixed_size_bytes(&internal_node.read_child_ptr_buf(child_idx));
+            node = BTreeNode::from_ptr(child_ptr);
+        }
+
+        debug_assert_eq!(node.as_ptr(), leaf.as_ptr());
+
+        let leaf_ptr_buf = leaf.as_ptr().as_new_fixed_size_bytes();
+
+        let prev_ptr = left_subtree.map_or(0, |it| edge_leaf::<K, V>(&it, true));
+        leaf.write_prev_ptr_buf(&prev_ptr.as_new_fixed_size_bytes());
+        if prev_ptr != 0 {
+            unsafe { LeafBTreeNode::<K, V>::from_ptr(prev_ptr).write_next_ptr_buf(&leaf_ptr_buf) };
+        }
+
+        let next_ptr = right_subtree.map_or(0, |it| edge_leaf::<K, V>(&it, false));
+        leaf.write_next_ptr_buf(&next_ptr.as_new_fixed_size_bytes());
+        if next_ptr != 0 {
+            unsafe { LeafBTreeNode::<K, V>::from_ptr(next_ptr).write_prev_ptr_buf(&leaf_ptr_buf) };
+        }
+    }
+}
+
+// returns the leftmost (or the rightmost, if `last == true`) leaf of the subtree
+fn edge_leaf<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes>(
+    subtree_ptr: &StablePtrBuf,
+    last: bool,
+) -> StablePtr {
+    let mut node = BTreeNode::<K, V>::from_ptr(u64::from_fixed_size_bytes(subtree_ptr));
+
+    loop {
+        match node {
+            BTreeNode::Internal(internal_node) => {
+                let child_idx = if last { internal_node.read_len() } else { 0 };
+                let child_ptr =
+                    u64::from_fixed_size_bytes(&internal_node.read_child_ptr_buf(child_idx));
+
+                node = BTreeNode::from_ptr(child_ptr);
+            }
+            BTreeNode::Leaf(leaf_node) => return leaf_node.as_ptr(),
+        }
+    }
+}
+
+#[cfg(feature = "orphan_collector")]
+impl<K: StableType + AsFixedSizeBytes + Ord + Trace, V: StableType + AsFixedSizeBytes + Trace> Trace
+    for SBTreeMapShadow<K, V>
+{
+    #[inline]
+    fn trace(&self, ptrs: &mut Vec<StablePtr>) {
+        self.tree.trace(ptrs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::btree_map::shadow::ShadowConflict;
+    use crate::collections::SBTreeMap;
+    use crate::{_debug_validate_allocator, get_allocated_size, stable, stable_memory_init};
+    use rand::rngs::ThreadRng;
+    use rand::seq::SliceRandom;
+    use rand::{thread_rng, Rng};
+    use std::collections::BTreeMap;
+
+    fn assert_same(map: &SBTreeMap<u64, u64>, example: &BTreeMap<u64, u64>) {
+        assert_eq!(map.len(), example.len() as u64);
+        map.validate().unwrap();
+
+        let entries = map.iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>();
+        let example_entries = example.iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>();
+        assert_eq!(entries, example_entries);
+    }
+
+    fn random_batch(rng: &mut ThreadRng, len: usize) -> Vec<(bool, u64)> {
+        (0..len)
+            .map(|_| (rng.gen_bool(0.6), rng.gen_range(0..2000)))
+            .collect()
+    }
+
+    #[test]
+    fn works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut rng = thread_rng();
+            let mut map = SBTreeMap::<u64, u64>::new();
+            let mut example = BTreeMap::new();
+
+            for i in 0..50 {
+                let batch = random_batch(&mut rng, 300);
+                let before = map.iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>();
+
+                let mut shadow = map.shadow();
+                let mut shadow_example = example.clone();
+
+                for (insert, key) in batch {
+                    if insert {
+                        assert_eq!(
+                            shadow.insert(key, i).unwrap(),
+                            shadow_example.insert(key, i)
+                        );
+                    } else {
+                        assert_eq!(shadow.remove(&key).unwrap(), shadow_example.remove(&key));
+                    }
+
+                    assert_eq!(shadow.len(), shadow_example.len() as u64);
+                    assert_eq!(shadow.contains_key(&key), shadow_example.contains_key(&key));
+                }
+
+                // the original map is never touched by the shadow
+                assert_same(&map, &example);
+                assert_eq!(
+                    map.iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>(),
+                    before
+                );
+
+                if i % 3 == 0 {
+                    drop(shadow);
+                } else {
+                    map.commit_shadow(shadow).unwrap();
+                    example = shadow_example;
+                }
+
+                assert_same(&map, &example);
+                _debug_validate_allocator();
+            }
+
+            let mut keys = example.keys().copied().collect::<Vec<_>>();
+            keys.shuffle(&mut rng);
+
+            let mut shadow = map.shadow();
+            for key in keys {
+                assert!(shadow.remove(&key).unwrap().is_some());
+            }
+            assert!(shadow.is_empty());
+
+            map.commit_shadow(shadow).unwrap();
+            assert_same(&map, &BTreeMap::new());
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn conflicts_work_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut map = SBTreeMap::<u64, u64>::new();
+            for i in 0..100 {
+                map.insert(i, i).unwrap();
+            }
+
+            let mut shadow = map.shadow();
+            for i in 100..200 {
+                shadow.insert(i, i).unwrap();
+            }
+
+            map.remove(&0);
+            assert_eq!(map.commit_shadow(shadow), Err(ShadowConflict));
+            assert_eq!(map.len(), 99);
+            map.validate().unwrap();
+
+            let mut other = SBTreeMap::<u64, u64>::new();
+            other.insert(1, 1).unwrap();
+
+            let shadow = other.shadow();
+            assert_eq!(map.commit_shadow(shadow), Err(ShadowConflict));
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
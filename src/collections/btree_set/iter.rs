@@ -1,4 +1,4 @@
-use crate::collections::btree_map::iter::SBTreeMapIter;
+use crate::collections::btree_map::iter::{SBTreeMapIter, SBTreeMapRangeIter};
 use crate::collections::btree_set::SBTreeSet;
 use crate::encoding::AsFixedSizeBytes;
 use crate::primitive::s_ref::SRef;
@@ -29,3 +29,30 @@ impl<'a, T: StableType + AsFixedSizeBytes + Ord> DoubleEndedIterator for SBTreeS
         self.iter.next_back().map(|it| it.0)
     }
 }
+
+pub struct SBTreeSetRangeIter<'a, T> {
+    iter: SBTreeMapRangeIter<'a, T, ()>,
+}
+
+impl<'a, T: StableType + AsFixedSizeBytes + Ord> SBTreeSetRangeIter<'a, T> {
+    #[inline]
+    pub(crate) fn new(iter: SBTreeMapRangeIter<'a, T, ()>) -> Self {
+        Self { iter }
+    }
+}
+
+impl<'a, T: StableType + AsFixedSizeBytes + Ord> Iterator for SBTreeSetRangeIter<'a, T> {
+    type Item = SRef<'a, T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next().map(|it| it.0)
+    }
+}
+
+impl<'a, T: StableType + AsFixedSizeBytes + Ord> DoubleEndedIterator
+    for SBTreeSetRangeIter<'a, T>
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.iter.next_back().map(|it| it.0)
+    }
+}
@@ -1,16 +1,25 @@
+use crate::collections::btree_map::prefix::KeyPrefix;
 use crate::collections::btree_map::SBTreeMap;
-use crate::collections::btree_set::iter::SBTreeSetIter;
+use crate::collections::btree_set::iter::{SBTreeSetIter, SBTreeSetRangeIter};
 use crate::encoding::AsFixedSizeBytes;
+#[cfg(feature = "orphan_collector")]
+use crate::mem::orphan_collector::{memory_usage, MemoryUsage, Trace};
+#[cfg(feature = "orphan_collector")]
+use crate::mem::StablePtr;
 use crate::primitive::s_ref::SRef;
-use crate::primitive::StableType;
+use crate::primitive::{DeepCopy, StableType};
+use crate::OutOfMemory;
 use std::borrow::Borrow;
 use std::fmt::{Debug, Formatter};
+use std::iter::Rev;
+use std::ops::RangeBounds;
 
 pub mod iter;
 
 /// B-plus tree based set data structure
 ///
-/// This is just a wrapper around [SBTreeMap]`<T, ()>`, read its documentation for more info on the internals
+/// This is just a wrapper around [SBTreeMap]`<T, ()>`, read its documentation for more info on the internals.
+/// Since `()` encodes into zero bytes, leaves of this set don't reserve any space for values.
 pub struct SBTreeSet<T: StableType + AsFixedSizeBytes + Ord> {
     map: SBTreeMap<T, ()>,
 }
@@ -45,6 +54,12 @@ impl<T: Ord + StableType + AsFixedSizeBytes> SBTreeSet<T> {
             .map_err(|(k, _)| k)
     }
 
+    /// See [SBTreeMap::try_insert]
+    #[inline]
+    pub fn try_insert(&mut self, value: T) -> Result<bool, OutOfMemory> {
+        self.map.try_insert(value, ()).map(|it| it.is_some())
+    }
+
     /// See [SBTreeMap::remove]
     #[inline]
     pub fn remove<Q>(&mut self, value: &Q) -> bool
@@ -55,6 +70,17 @@ impl<T: Ord + StableType + AsFixedSizeBytes> SBTreeSet<T> {
         self.map.remove(value).is_some()
     }
 
+    /// See [SBTreeMap::remove_range]
+    #[inline]
+    pub fn remove_range<Q, R>(&mut self, range: R) -> u64
+    where
+        T: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        self.map.remove_range(range)
+    }
+
     /// See [SBTreeMap::clear]
     #[inline]
     pub fn clear(&mut self) {
@@ -82,6 +108,42 @@ impl<T: Ord + StableType + AsFixedSizeBytes> SBTreeSet<T> {
     pub fn iter(&self) -> SBTreeSetIter<T> {
         SBTreeSetIter::new(self)
     }
+
+    /// See [SBTreeMap::iter_rev]
+    #[inline]
+    pub fn iter_rev(&self) -> Rev<SBTreeSetIter<T>> {
+        self.iter().rev()
+    }
+
+    /// See [SBTreeMap::range]
+    #[inline]
+    pub fn range<Q, R>(&self, range: R) -> SBTreeSetRangeIter<T>
+    where
+        T: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        SBTreeSetRangeIter::new(self.map.range(range))
+    }
+
+    /// See [SBTreeMap::iter_prefix]
+    #[inline]
+    pub fn iter_prefix<P>(&self, prefix: &P) -> SBTreeSetRangeIter<T>
+    where
+        T: KeyPrefix<P>,
+        P: ?Sized,
+    {
+        SBTreeSetRangeIter::new(self.map.iter_prefix(prefix))
+    }
+}
+
+impl<T: Ord + StableType + AsFixedSizeBytes + DeepCopy> DeepCopy for SBTreeSet<T> {
+    #[inline]
+    fn deep_copy(&self) -> Result<Self, OutOfMemory> {
+        Ok(Self {
+            map: self.map.deep_copy()?,
+        })
+    }
 }
 
 impl<T: Ord + StableType + AsFixedSizeBytes> Default for SBTreeSet<T> {
@@ -133,6 +195,26 @@ impl<T: StableType + AsFixedSizeBytes + Ord + Debug> Debug for SBTreeSet<T> {
     }
 }
 
+#[cfg(feature = "orphan_collector")]
+impl<T: StableType + AsFixedSizeBytes + Ord + Trace> SBTreeSet<T> {
+    /// Returns the size of stable memory, owned by this collection
+    ///
+    /// Includes its nodes, plus the memory owned by its elements, see [MemoryUsage].
+    ///
+    /// Only available with the `orphan_collector` feature.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        memory_usage(self, self.len())
+    }
+}
+
+#[cfg(feature = "orphan_collector")]
+impl<T: StableType + AsFixedSizeBytes + Ord + Trace> Trace for SBTreeSet<T> {
+    #[inline]
+    fn trace(&self, ptrs: &mut Vec<StablePtr>) {
+        self.map.trace(ptrs)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::collections::btree_set::SBTreeSet;
@@ -207,6 +289,30 @@ mod tests {
         assert_eq!(get_allocated_size(), 0);
     }
 
+    #[test]
+    fn range_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut set = SBTreeSet::<u32>::default();
+            for i in 0..100 {
+                set.insert(i * 10).unwrap();
+            }
+
+            let range = set.range(95..=150).map(|it| *it).collect::<Vec<_>>();
+            assert_eq!(range, vec![100, 110, 120, 130, 140, 150]);
+
+            let range = set.range(..30).rev().map(|it| *it).collect::<Vec<_>>();
+            assert_eq!(range, vec![20, 10, 0]);
+
+            assert_eq!(set.range(991..).count(), 0);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
     #[derive(Debug)]
     enum Action {
         Insert,
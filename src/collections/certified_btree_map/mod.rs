@@ -3,6 +3,10 @@ use crate::collections::btree_map::iter::SBTreeMapIter;
 use crate::collections::btree_map::leaf_node::LeafBTreeNode;
 use crate::collections::btree_map::{BTreeNode, LeveledList, SBTreeMap};
 use crate::encoding::AsFixedSizeBytes;
+#[cfg(feature = "orphan_collector")]
+use crate::mem::orphan_collector::Trace;
+#[cfg(feature = "orphan_collector")]
+use crate::mem::StablePtr;
 use crate::primitive::s_ref::SRef;
 use crate::primitive::s_ref_mut::SRefMut;
 use crate::primitive::StableType;
@@ -10,6 +14,7 @@ use crate::utils::certification::{
     empty_hash, labeled, labeled_hash, pruned, AsHashTree, AsHashableBytes, Hash, HashForker,
     HashTree, WitnessForker,
 };
+use crate::OutOfMemory;
 use std::borrow::Borrow;
 use std::fmt::{Debug, Formatter};
 use std::ops::Deref;
@@ -226,6 +231,36 @@ impl<
         res
     }
 
+    /// Same as [SCertifiedBTreeMap::insert], but returns [OutOfMemory] instead of the key-value pair
+    ///
+    /// Useful for propagating the error with `?`. The pair gets dropped, if it can't be inserted.
+    #[inline]
+    pub fn try_insert(&mut self, key: K, value: V) -> Result<Option<V>, OutOfMemory> {
+        self.insert(key, value).map_err(|_| OutOfMemory)
+    }
+
+    /// Inserts or updates a value by the provided key in a single descent, leaving this
+    /// [SCertifiedBTreeMap] in the `uncommited` state, if the operation was successful
+    ///
+    /// * See also [SCertifiedBTreeMap::commit]
+    /// * See also [SBTreeMap::insert_with]
+    #[inline]
+    pub fn insert_with<D, M>(&mut self, key: K, default: D, modify: M) -> Result<(), (K, V)>
+    where
+        D: FnOnce() -> V,
+        M: FnOnce(&mut V),
+    {
+        let res = self
+            .inner
+            ._insert_with(key, default, modify, &mut self.modified);
+
+        if res.is_ok() && !self.uncommited {
+            self.uncommited = true;
+        }
+
+        res
+    }
+
     /// Inserts a new key-value pair into this [SCertifiedBTreeMap], immediately commiting changes to
     /// the underlying Merkle tree, if the insertion was successful
     ///
@@ -238,6 +273,13 @@ impl<
         Ok(it)
     }
 
+    /// Same as [SCertifiedBTreeMap::insert_and_commit], but returns [OutOfMemory] instead of the
+    /// key-value pair
+    #[inline]
+    pub fn try_insert_and_commit(&mut self, key: K, value: V) -> Result<Option<V>, OutOfMemory> {
+        self.insert_and_commit(key, value).map_err(|_| OutOfMemory)
+    }
+
     /// Removes a key-value pair from this [SCertifiedBTreeMap], leaving it in the `uncommited` state,
     /// if the removal was successful
     ///
@@ -561,6 +603,13 @@ impl<
         V: StableType + AsFixedSizeBytes + AsHashTree + Debug,
     > SCertifiedBTreeMap<K, V>
 {
+    /// See [SBTreeMap::debug_structure]
+    #[cfg(feature = "debug_structure")]
+    #[inline]
+    pub fn debug_structure(&self) -> String {
+        self.inner.debug_structure()
+    }
+
     #[inline]
     pub fn debug_print(&self) {
         self.inner.debug_print();
@@ -626,18 +675,9 @@ impl<
         V: StableType + AsFixedSizeBytes + AsHashTree + Debug,
     > Debug for SCertifiedBTreeMap<K, V>
 {
+    #[inline]
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        f.write_str("{")?;
-        for (idx, (k, v)) in self.iter().enumerate() {
-            k.fmt(f)?;
-            f.write_str(": ")?;
-            v.fmt(f)?;
-
-            if idx < (self.len() - 1) as usize {
-                f.write_str(", ")?;
-            }
-        }
-        f.write_str("}")
+        self.inner.fmt(f)
     }
 }
 
@@ -950,6 +990,18 @@ impl<K: StableType + AsFixedSizeBytes + Ord + AsHashableBytes> InternalBTreeNode
     }
 }
 
+#[cfg(feature = "orphan_collector")]
+impl<
+        K: StableType + AsFixedSizeBytes + Ord + AsHashableBytes + Trace,
+        V: StableType + AsFixedSizeBytes + AsHashTree + Trace,
+    > Trace for SCertifiedBTreeMap<K, V>
+{
+    #[inline]
+    fn trace(&self, ptrs: &mut Vec<StablePtr>) {
+        self.inner.trace(ptrs)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::collections::certified_btree_map::SCertifiedBTreeMap;
@@ -1,10 +1,14 @@
 use crate::collections::certified_btree_map::SCertifiedBTreeMap;
 use crate::collections::certified_btree_set::iter::SCertifiedBTreeSetIter;
 use crate::encoding::AsFixedSizeBytes;
+#[cfg(feature = "orphan_collector")]
+use crate::mem::orphan_collector::Trace;
+#[cfg(feature = "orphan_collector")]
+use crate::mem::StablePtr;
 use crate::primitive::s_ref::SRef;
 use crate::primitive::StableType;
 use crate::utils::certification::HashTree;
-use crate::{AsHashTree, AsHashableBytes};
+use crate::{AsHashTree, AsHashableBytes, OutOfMemory};
 use std::borrow::Borrow;
 use std::fmt::{Debug, Formatter};
 
@@ -48,6 +52,12 @@ impl<T: Ord + StableType + AsFixedSizeBytes + AsHashableBytes> SCertifiedBTreeSe
             .map_err(|(k, _)| k)
     }
 
+    /// See [SCertifiedBTreeMap::try_insert]
+    #[inline]
+    pub fn try_insert(&mut self, value: T) -> Result<bool, OutOfMemory> {
+        self.map.try_insert(value, ()).map(|it| it.is_some())
+    }
+
     /// See [SCertifiedBTreeMap::insert_and_commit]
     #[inline]
     pub fn insert_and_commit(&mut self, value: T) -> Result<bool, T> {
@@ -57,6 +67,14 @@ impl<T: Ord + StableType + AsFixedSizeBytes + AsHashableBytes> SCertifiedBTreeSe
             .map_err(|(k, _)| k)
     }
 
+    /// See [SCertifiedBTreeMap::try_insert_and_commit]
+    #[inline]
+    pub fn try_insert_and_commit(&mut self, value: T) -> Result<bool, OutOfMemory> {
+        self.map
+            .try_insert_and_commit(value, ())
+            .map(|it| it.is_some())
+    }
+
     /// See [SCertifiedBTreeMap::remove]
     #[inline]
     pub fn remove<Q>(&mut self, value: &Q) -> bool
@@ -211,6 +229,16 @@ impl<T: StableType + AsFixedSizeBytes + Ord + Debug + AsHashableBytes> Debug
     }
 }
 
+#[cfg(feature = "orphan_collector")]
+impl<T: StableType + AsFixedSizeBytes + Ord + AsHashableBytes + Trace> Trace
+    for SCertifiedBTreeSet<T>
+{
+    #[inline]
+    fn trace(&self, ptrs: &mut Vec<StablePtr>) {
+        self.map.trace(ptrs)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::collections::certified_btree_set::SCertifiedBTreeSet;
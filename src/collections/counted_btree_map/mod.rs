@@ -0,0 +1,657 @@
+use crate::collections::btree_map::internal_node::InternalBTreeNode;
+use crate::collections::btree_map::iter::{SBTreeMapIter, SBTreeMapRangeIter};
+use crate::collections::btree_map::leaf_node::LeafBTreeNode;
+use crate::collections::btree_map::{BTreeNode, IBTreeNode, LeveledList, SBTreeMap};
+use crate::encoding::{AsFixedSizeBytes, CorruptData};
+#[cfg(feature = "orphan_collector")]
+use crate::mem::orphan_collector::Trace;
+use crate::mem::StablePtr;
+use crate::primitive::s_ref::SRef;
+use crate::primitive::s_ref_mut::SRefMut;
+use crate::primitive::StableType;
+use crate::OutOfMemory;
+use std::borrow::Borrow;
+use std::fmt::{Debug, Formatter};
+use std::ops::{Bound, RangeBounds};
+
+/// [SBTreeMap], which also maintains the number of entries in every subtree
+///
+/// Each internal node stores the entry count of each of its children, which makes
+/// [SCountedBTreeMap::rank], [SCountedBTreeMap::count_in_range] and positional access (like
+/// [SCountedBTreeMap::get_by_index]) `O(logN)`, instead of scanning the entries. Counts are
+/// updated on every insertion and removal, which makes them a little slower than the same
+/// operations of [SBTreeMap], and internal nodes are `128` bytes bigger.
+///
+/// All other logic is simply proxied from the underlying [SBTreeMap], read its documentation for
+/// more details. An [SBTreeMap] can't be turned into an [SCountedBTreeMap] in place (or vice versa),
+/// since the layout of their nodes is different - move the entries instead.
+///
+/// Both `K` and `V` have to implement [StableType] and [AsFixedSizeBytes] traits. [SCountedBTreeMap]
+/// also implements both these traits, so you can nest it into other stable structures.
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::collections::SCountedBTreeMap;
+/// # use ic_stable_memory::stable_memory_init;
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// let mut map = SCountedBTreeMap::new();
+///
+/// for i in 0..100u64 {
+///     map.insert(i * 10, i).expect("Out of memory");
+/// }
+///
+/// assert_eq!(map.rank(&500), 50);
+/// assert_eq!(map.count_in_range(100..=200), 11);
+/// assert_eq!(*map.get_by_index(42).unwrap().1, 42);
+/// assert_eq!(map.index_of(&420), Some(42));
+///
+/// let page = map.range_by_index(20..25).map(|(k, _)| *k).collect::<Vec<_>>();
+/// assert_eq!(page, vec![200, 210, 220, 230, 240]);
+/// ```
+pub struct SCountedBTreeMap<
+    K: StableType + AsFixedSizeBytes + Ord,
+    V: StableType + AsFixedSizeBytes,
+> {
+    inner: SBTreeMap<K, V>,
+    modified: LeveledList,
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes>
+    SCountedBTreeMap<K, V>
+{
+    /// Creates a new [SCountedBTreeMap]
+    ///
+    /// Allocates a small amount of heap memory.
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            inner: SBTreeMap::new_counted(),
+            modified: LeveledList::new(),
+        }
+    }
+
+    /// See [SBTreeMap::insert]
+    #[inline]
+    pub fn insert(&mut self, key: K, value: V) -> Result<Option<V>, (K, V)> {
+        let res = self.inner._insert(key, value, &mut self.modified);
+        self.recount();
+
+        res
+    }
+
+    /// Same as [SCountedBTreeMap::insert], but returns [OutOfMemory] instead of the key-value pair
+    ///
+    /// Useful for propagating the error with `?`. The pair gets dropped, if it can't be inserted.
+    #[inline]
+    pub fn try_insert(&mut self, key: K, value: V) -> Result<Option<V>, OutOfMemory> {
+        self.insert(key, value).map_err(|_| OutOfMemory)
+    }
+
+    /// See [SBTreeMap::insert_with]
+    #[inline]
+    pub fn insert_with<D, M>(&mut self, key: K, default: D, modify: M) -> Result<(), (K, V)>
+    where
+        D: FnOnce() -> V,
+        M: FnOnce(&mut V),
+    {
+        let res = self
+            .inner
+            ._insert_with(key, default, modify, &mut self.modified);
+        self.recount();
+
+        res
+    }
+
+    /// See [SBTreeMap::remove]
+    #[inline]
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let res = self.inner._remove(key, &mut self.modified);
+        self.recount();
+
+        res
+    }
+
+    /// See [SBTreeMap::remove_range]
+    #[inline]
+    pub fn remove_range<Q, R>(&mut self, range: R) -> u64
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        let res = self.inner._remove_range(range, &mut self.modified);
+        self.recount();
+
+        res
+    }
+
+    /// See [SBTreeMap::get]
+    #[inline]
+    pub fn get<Q>(&self, key: &Q) -> Option<SRef<V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.inner.get(key)
+    }
+
+    /// See [SBTreeMap::get_mut]
+    #[inline]
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<SRefMut<V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.inner.get_mut(key)
+    }
+
+    /// See [SBTreeMap::contains_key]
+    #[inline]
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.inner.contains_key(key)
+    }
+
+    /// See [SBTreeMap::iter]
+    #[inline]
+    pub fn iter(&self) -> SBTreeMapIter<K, V> {
+        self.inner.iter()
+    }
+
+    /// See [SBTreeMap::range]
+    #[inline]
+    pub fn range<Q, R>(&self, range: R) -> SBTreeMapRangeIter<K, V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        self.inner.range(range)
+    }
+
+    /// See [SBTreeMap::len]
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.inner.len()
+    }
+
+    /// See [SBTreeMap::is_empty]
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    /// See [SBTreeMap::clear]
+    #[inline]
+    pub fn clear(&mut self) {
+        self.modified = LeveledList::new();
+        self.inner.clear();
+    }
+
+    /// Returns the number of keys, which are less than the provided one, in `O(logN)`
+    ///
+    /// This is the index of the key in [SCountedBTreeMap::iter], if the key is present, or the index
+    /// it would be inserted at, otherwise. Borrowed type is also accepted.
+    #[inline]
+    pub fn rank<Q>(&self, key: &Q) -> u64
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.rank_of(key).0
+    }
+
+    /// Returns the number of entries, which keys are inside the provided range, in `O(logN)`
+    ///
+    /// Returns `0` for empty and inverted ranges. Borrowed type is also accepted.
+    pub fn count_in_range<Q, R>(&self, range: R) -> u64
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        let start = match range.start_bound() {
+            Bound::Included(k) => self.rank_of(k).0,
+            Bound::Excluded(k) => {
+                let (rank, found) = self.rank_of(k);
+                rank + found as u64
+            }
+            Bound::Unbounded => 0,
+        };
+
+        let end = match range.end_bound() {
+            Bound::Included(k) => {
+                let (rank, found) = self.rank_of(k);
+                rank + found as u64
+            }
+            Bound::Excluded(k) => self.rank_of(k).0,
+            Bound::Unbounded => self.len(),
+        };
+
+        end.saturating_sub(start)
+    }
+
+    /// Returns the entry at the provided position in [SCountedBTreeMap::iter], in `O(logN)`
+    ///
+    /// Returns [None], if the index is out of bounds.
+    pub fn get_by_index(&self, idx: u64) -> Option<(SRef<K>, SRef<V>)> {
+        let (leaf, i) = self.locate(idx)?;
+
+        Some((leaf.get_key(i), leaf.get_value(i)))
+    }
+
+    /// Returns the position of the key in [SCountedBTreeMap::iter], in `O(logN)`
+    ///
+    /// Returns [None], if there is no such key. Borrowed type is also accepted.
+    pub fn index_of<Q>(&self, key: &Q) -> Option<u64>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        match self.rank_of(key) {
+            (rank, true) => Some(rank),
+            _ => None,
+        }
+    }
+
+    /// Returns a double-ended iterator over entries, which positions in [SCountedBTreeMap::iter] are
+    /// inside the provided range
+    ///
+    /// Finding the bounds takes `O(logN)`, so fetching a page of entries in the middle of a big map
+    /// doesn't require to iterate from the start. Indices out of bounds are clamped to the length
+    /// of the map.
+    pub fn range_by_index<R: RangeBounds<u64>>(&self, range: R) -> SBTreeMapRangeIter<K, V> {
+        let start = match range.start_bound() {
+            Bound::Included(i) => *i,
+            Bound::Excluded(i) => i.saturating_add(1),
+            Bound::Unbounded => 0,
+        };
+
+        let end = match range.end_bound() {
+            Bound::Included(i) => i.saturating_add(1),
+            Bound::Excluded(i) => *i,
+            Bound::Unbounded => self.len(),
+        }
+        .min(self.len());
+
+        if start >= end {
+            return SBTreeMapRangeIter::new(None, None);
+        }
+
+        let front = self.locate(start);
+        let back = self.locate(end - 1).map(|(leaf, i)| (leaf, i + 1));
+
+        SBTreeMapRangeIter::new(front, back)
+    }
+
+    /// Checks that the count of every subtree matches the number of entries in it, see [SBTreeMap::validate]
+    pub fn validate(&self) -> Result<(), CorruptData> {
+        self.inner.validate()?;
+
+        if let Some(BTreeNode::Internal(root)) = self.inner.get_root() {
+            if root.validate_counts::<V>()? != self.len() {
+                return Err(CorruptData::new(
+                    root.as_ptr(),
+                    "Total count of a counted B-tree doesn't match its length",
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    // returns the number of keys less than the provided one and whether the key is present
+    fn rank_of<Q>(&self, key: &Q) -> (u64, bool)
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        if self.is_empty() {
+            return (0, false);
+        }
+
+        let mut node = unsafe { self.inner.get_root().unwrap_unchecked() };
+        let mut rank = 0;
+
+        loop {
+            match node {
+                BTreeNode::Internal(n) => {
+                    let len = n.read_len();
+                    let child_idx = match n.binary_search(key, len) {
+                        Ok(idx) => idx + 1,
+                        Err(idx) => idx,
+                    };
+
+                    for i in 0..child_idx {
+                        rank += n.read_child_count(i, true);
+                    }
+
+                    let ptr = StablePtr::from_fixed_size_bytes(&n.read_child_ptr_buf(child_idx));
+                    node = BTreeNode::from_ptr(ptr);
+                }
+                BTreeNode::Leaf(n) => {
+                    let len = n.read_len();
+
+                    return match n.binary_search(key, len) {
+                        Ok(idx) => (rank + idx as u64, true),
+                        Err(idx) => (rank + idx as u64, false),
+                    };
+                }
+            }
+        }
+    }
+
+    // returns the leaf and the position in it of the entry with the provided index
+    fn locate(&self, mut idx: u64) -> Option<(LeafBTreeNode<K, V>, usize)> {
+        if idx >= self.len() {
+            return None;
+        }
+
+        let mut node = self.inner.get_root()?;
+
+        loop {
+            match node {
+                BTreeNode::Internal(n) => {
+                    let len = n.read_len();
+                    let mut child_idx = 0;
+
+                    while child_idx < len {
+                        let count = n.read_child_count(child_idx, true);
+                        if idx < count {
+                            break;
+                        }
+
+                        idx -= count;
+                        child_idx += 1;
+                    }
+
+                    let ptr = StablePtr::from_fixed_size_bytes(&n.read_child_ptr_buf(child_idx));
+                    node = BTreeNode::from_ptr(ptr);
+                }
+                BTreeNode::Leaf(n) => return Some((n, idx as usize)),
+            }
+        }
+    }
+
+    // deeper nodes are popped first, so counts of children are always up to date
+    fn recount(&mut self) {
+        while let Some(ptr) = self.modified.pop() {
+            if let BTreeNode::Internal(mut n) = BTreeNode::<K, V>::from_ptr(ptr) {
+                n.recount::<V>();
+            }
+        }
+    }
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord> InternalBTreeNode<K> {
+    pub(crate) fn recount<V: StableType + AsFixedSizeBytes>(&mut self) {
+        for i in 0..(self.read_len() + 1) {
+            let ptr = StablePtr::from_fixed_size_bytes(&self.read_child_ptr_buf(i));
+
+            let count = match BTreeNode::<K, V>::from_ptr(ptr) {
+                BTreeNode::Internal(n) => n.total_count(),
+                BTreeNode::Leaf(n) => n.read_len() as u64,
+            };
+
+            self.write_child_count(i, count, true);
+        }
+    }
+
+    #[inline]
+    pub(crate) fn total_count(&self) -> u64 {
+        (0..(self.read_len() + 1))
+            .map(|i| self.read_child_count(i, true))
+            .sum()
+    }
+
+    // returns the actual number of entries in this subtree
+    fn validate_counts<V: StableType + AsFixedSizeBytes>(&self) -> Result<u64, CorruptData> {
+        let mut total = 0;
+
+        for i in 0..(self.read_len() + 1) {
+            let ptr = StablePtr::from_fixed_size_bytes(&self.read_child_ptr_buf(i));
+
+            let actual = match BTreeNode::<K, V>::from_ptr(ptr) {
+                BTreeNode::Internal(n) => n.validate_counts::<V>()?,
+                BTreeNode::Leaf(n) => n.read_len() as u64,
+            };
+
+            if self.read_child_count(i, true) != actual {
+                return Err(CorruptData::new(
+                    self.as_ptr(),
+                    "Invalid subtree count of a counted B-tree node",
+                ));
+            }
+
+            total += actual;
+        }
+
+        Ok(total)
+    }
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> Default
+    for SCountedBTreeMap<K, V>
+{
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> AsFixedSizeBytes
+    for SCountedBTreeMap<K, V>
+{
+    const SIZE: usize = SBTreeMap::<K, V>::SIZE;
+    type Buf = <SBTreeMap<K, V> as AsFixedSizeBytes>::Buf;
+
+    #[inline]
+    fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+        self.inner.as_fixed_size_bytes(buf)
+    }
+
+    fn from_fixed_size_bytes(buf: &[u8]) -> Self {
+        let mut inner = SBTreeMap::<K, V>::from_fixed_size_bytes(buf);
+        inner.set_counted(true);
+
+        Self {
+            inner,
+            modified: LeveledList::new(),
+        }
+    }
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> StableType
+    for SCountedBTreeMap<K, V>
+{
+    #[inline]
+    unsafe fn stable_drop_flag_on(&mut self) {
+        self.inner.stable_drop_flag_on();
+    }
+
+    #[inline]
+    unsafe fn stable_drop_flag_off(&mut self) {
+        self.inner.stable_drop_flag_off();
+    }
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord + Debug, V: StableType + AsFixedSizeBytes + Debug> Debug
+    for SCountedBTreeMap<K, V>
+{
+    #[inline]
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+#[cfg(feature = "orphan_collector")]
+impl<K: StableType + AsFixedSizeBytes + Ord + Trace, V: StableType + AsFixedSizeBytes + Trace> Trace
+    for SCountedBTreeMap<K, V>
+{
+    #[inline]
+    fn trace(&self, ptrs: &mut Vec<StablePtr>) {
+        self.inner.trace(ptrs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::counted_btree_map::SCountedBTreeMap;
+    use crate::encoding::AsFixedSizeBytes;
+    use crate::primitive::StableType;
+    use crate::{_debug_validate_allocator, get_allocated_size, stable, stable_memory_init};
+    use rand::rngs::ThreadRng;
+    use rand::seq::SliceRandom;
+    use rand::{thread_rng, Rng};
+    use std::collections::BTreeMap;
+
+    fn check(map: &SCountedBTreeMap<u64, u64>, example: &BTreeMap<u64, u64>, rng: &mut ThreadRng) {
+        map.validate().unwrap();
+
+        for _ in 0..20 {
+            let a = rng.gen_range(0..1100u64);
+            let b = rng.gen_range(a..1100u64);
+
+            assert_eq!(map.rank(&a), example.range(..a).count() as u64);
+            assert_eq!(map.count_in_range(a..b), example.range(a..b).count() as u64);
+            assert_eq!(
+                map.count_in_range(a..=b),
+                example.range(a..=b).count() as u64
+            );
+            assert_eq!(map.count_in_range(..b), example.range(..b).count() as u64);
+            assert_eq!(map.count_in_range(a..), example.range(a..).count() as u64);
+
+            let idx = rng.gen_range(0..(example.len() as u64 + 5));
+            let expected = example.iter().nth(idx as usize);
+            let actual = map.get_by_index(idx);
+            assert_eq!(actual.as_ref().map(|(k, v)| (&**k, &**v)), expected);
+
+            assert_eq!(
+                map.index_of(&a),
+                example
+                    .contains_key(&a)
+                    .then(|| example.range(..a).count() as u64)
+            );
+
+            let from = rng.gen_range(0..(example.len() as u64 + 5));
+            let to = from + rng.gen_range(0..30u64);
+            let actual = map
+                .range_by_index(from..to)
+                .map(|(k, v)| (*k, *v))
+                .collect::<Vec<_>>();
+            let expected = example
+                .iter()
+                .skip(from as usize)
+                .take((to - from) as usize)
+                .map(|(k, v)| (*k, *v))
+                .collect::<Vec<_>>();
+            assert_eq!(actual, expected);
+
+            let actual = map
+                .range_by_index(from..=to)
+                .rev()
+                .map(|(k, _)| *k)
+                .collect::<Vec<_>>();
+            let expected = example
+                .keys()
+                .skip(from as usize)
+                .take((to - from + 1) as usize)
+                .rev()
+                .copied()
+                .collect::<Vec<_>>();
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[test]
+    fn works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut map = SCountedBTreeMap::<u64, u64>::new();
+            let mut example = BTreeMap::new();
+            let mut rng = thread_rng();
+
+            assert_eq!(map.rank(&10), 0);
+            assert_eq!(map.count_in_range(..), 0);
+            assert!(map.get_by_index(0).is_none());
+            assert_eq!(map.range_by_index(..).count(), 0);
+
+            let mut keys = (0..1000u64).collect::<Vec<_>>();
+            keys.shuffle(&mut rng);
+
+            for (i, key) in keys.iter().enumerate() {
+                map.insert(*key, *key).unwrap();
+                example.insert(*key, *key);
+
+                if i % 100 == 0 {
+                    check(&map, &example, &mut rng);
+                }
+            }
+
+            check(&map, &example, &mut rng);
+            assert_eq!(map.count_in_range(..), 1000);
+            assert_eq!(map.count_in_range(10..5), 0);
+
+            assert_eq!(map.remove_range(200..700), 500);
+            example.retain(|k, _| !(200..700).contains(k));
+            check(&map, &example, &mut rng);
+            assert_eq!(map.count_in_range(..), 500);
+
+            keys.shuffle(&mut rng);
+
+            for (i, key) in keys.iter().enumerate() {
+                assert_eq!(map.remove(key), example.remove(key));
+
+                if i % 100 == 0 {
+                    check(&map, &example, &mut rng);
+                }
+            }
+
+            check(&map, &example, &mut rng);
+            assert!(map.is_empty());
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn persists_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut map = SCountedBTreeMap::<u64, u64>::new();
+            for i in 0..500 {
+                map.insert(i, i).unwrap();
+            }
+
+            let buf = map.as_new_fixed_size_bytes();
+            unsafe { map.stable_drop_flag_off() };
+            drop(map);
+
+            let mut map = SCountedBTreeMap::<u64, u64>::from_fixed_size_bytes(&buf);
+            unsafe { map.stable_drop_flag_on() };
+
+            for i in 500..1000 {
+                map.insert(i, i).unwrap();
+            }
+
+            map.validate().unwrap();
+            assert_eq!(map.rank(&700), 700);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
@@ -0,0 +1,54 @@
+use crate::collections::dyn_vec::SDynVec;
+use crate::encoding::AsDynSizeBytes;
+use crate::primitive::StableType;
+
+pub struct SDynVecIter<'a, T: StableType + AsDynSizeBytes> {
+    vec: &'a SDynVec<T>,
+    front: usize,
+    back: usize,
+}
+
+impl<'a, T: StableType + AsDynSizeBytes> SDynVecIter<'a, T> {
+    #[inline]
+    pub(crate) fn new(vec: &'a SDynVec<T>) -> Self {
+        Self {
+            vec,
+            front: 0,
+            back: vec.len(),
+        }
+    }
+}
+
+impl<'a, T: StableType + AsDynSizeBytes> Iterator for SDynVecIter<'a, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.front >= self.back {
+            return None;
+        }
+
+        let it = self.vec.get(self.front);
+        self.front += 1;
+
+        it
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.back - self.front;
+
+        (len, Some(len))
+    }
+}
+
+impl<'a, T: StableType + AsDynSizeBytes> DoubleEndedIterator for SDynVecIter<'a, T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.front >= self.back {
+            return None;
+        }
+
+        self.back -= 1;
+
+        self.vec.get(self.back)
+    }
+}
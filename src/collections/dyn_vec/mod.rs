@@ -0,0 +1,489 @@
+use crate::collections::dyn_vec::iter::SDynVecIter;
+use crate::collections::vec::SVec;
+use crate::encoding::{AsDynSizeBytes, AsFixedSizeBytes};
+use crate::mem::allocator::EMPTY_PTR;
+#[cfg(feature = "orphan_collector")]
+use crate::mem::orphan_collector::Trace;
+use crate::mem::StablePtr;
+use crate::primitive::StableType;
+use crate::utils::DEBUG_ELEMENTS_LIMIT;
+use crate::{allocate, deallocate, OutOfMemory, SSlice};
+#[cfg(feature = "orphan_collector")]
+use std::collections::BTreeSet;
+use std::fmt::{Debug, Formatter};
+use std::marker::PhantomData;
+
+#[doc(hidden)]
+pub mod iter;
+
+// size of the data part of a regular block, bigger elements get a block of their own
+pub(crate) const BLOCK_CAPACITY: u64 = 4 * 1024;
+
+// Block layout:
+// used: u64 - number of data bytes, taken by both alive and removed elements
+// alive: u64 - number of alive elements, stored in this block
+// data: [u8; capacity]
+const USED_OFFSET: u64 = 0;
+const ALIVE_OFFSET: u64 = u64::SIZE as u64;
+const DATA_OFFSET: u64 = (u64::SIZE * 2) as u64;
+
+// block pointer, offset inside the data part of the block, length
+type Entry = (StablePtr, u32, u32);
+
+/// Stable analog of [Vec] for elements of variable size
+///
+/// Elements of an [SVec](crate::collections::SVec) have to be encoded with the same number of bytes.
+/// [SDynVec] lifts this restriction - its elements only have to implement [AsDynSizeBytes], which
+/// makes it suitable for lists of strings or records.
+///
+/// Encoded elements are stored out-of-line, one after another, in data blocks of `4 KiB` (an element,
+/// which doesn't fit into a regular block, gets a block of its own). An [SVec] of fixed-size entries
+/// (a block pointer, an offset and a length of each element) serves as an offset table, so any element
+/// is located in O(1).
+///
+/// Removing an element only shifts the offset table, the data itself is never moved. Instead, each
+/// block counts its alive elements and gets released, once all of them are removed.
+///
+/// Elements are decoded on each access, so they are returned by value, not by reference.
+///
+/// `T` has to implement both [StableType] and [AsDynSizeBytes]. [SDynVec] itself implements
+/// [StableType] and [AsFixedSizeBytes], so you can nest it into other stable structures.
+///
+/// # Examples
+/// ```rust
+/// # use ic_stable_memory::collections::SDynVec;
+/// # use ic_stable_memory::stable_memory_init;
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// let mut names = SDynVec::new();
+///
+/// names.push(String::from("Alice")).expect("Out of memory");
+/// names.push(String::from("Bob")).expect("Out of memory");
+/// names.push(String::from("Charlie")).expect("Out of memory");
+///
+/// assert_eq!(names.get(1).unwrap(), "Bob");
+/// assert_eq!(names.remove(0), "Alice");
+///
+/// let all: Vec<_> = names.iter().collect();
+/// assert_eq!(all, vec!["Bob", "Charlie"]);
+/// ```
+pub struct SDynVec<T: StableType + AsDynSizeBytes> {
+    table: SVec<Entry>,
+    cur_block: StablePtr,
+    _marker: PhantomData<T>,
+}
+
+impl<T: StableType + AsDynSizeBytes> SDynVec<T> {
+    /// Creates a new [SDynVec]
+    ///
+    /// Does not allocate any heap or stable memory.
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            table: SVec::new(),
+            cur_block: EMPTY_PTR,
+            _marker: PhantomData::default(),
+        }
+    }
+
+    /// Returns the length of this [SDynVec]
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.table.len()
+    }
+
+    /// Returns [true] if the length of this [SDynVec] is `0`
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.table.is_empty()
+    }
+
+    /// Inserts a new element at the end of this [SDynVec]
+    ///
+    /// May allocate a new data block and reallocate the offset table. If the canister is out of
+    /// stable memory, will return [Err] with the element that was about to get inserted.
+    ///
+    /// # Panics
+    /// Panics if the encoded element is bigger than [u32::MAX] bytes.
+    pub fn push(&mut self, mut element: T) -> Result<(), T> {
+        let buf = element.as_dyn_size_bytes();
+        assert!(buf.len() <= u32::MAX as usize, "The element is too big");
+
+        let len = buf.len() as u64;
+
+        let (block, is_new) =
+            if self.cur_block != EMPTY_PTR && Self::room_left(self.cur_block) >= len {
+                (self.cur_block, false)
+            } else {
+                match unsafe { allocate(DATA_OFFSET + len.max(BLOCK_CAPACITY)) } {
+                    Ok(slice) => {
+                        Self::write_counter(slice.as_ptr(), USED_OFFSET, 0);
+                        Self::write_counter(slice.as_ptr(), ALIVE_OFFSET, 0);
+
+                        (slice.as_ptr(), true)
+                    }
+                    Err(_) => return Err(element),
+                }
+            };
+
+        let offset = Self::read_counter(block, USED_OFFSET);
+
+        if self.table.push((block, offset as u32, len as u32)).is_err() {
+            if is_new {
+                deallocate(unsafe { SSlice::from_ptr(block).unwrap() });
+            }
+
+            return Err(element);
+        }
+
+        unsafe { crate::mem::write_bytes(SSlice::_offset(block, DATA_OFFSET + offset), &buf) };
+
+        Self::write_counter(block, USED_OFFSET, offset + len);
+        Self::write_counter(
+            block,
+            ALIVE_OFFSET,
+            Self::read_counter(block, ALIVE_OFFSET) + 1,
+        );
+
+        if is_new {
+            self.switch_cur_block(block);
+        }
+
+        unsafe { element.stable_drop_flag_off() };
+
+        Ok(())
+    }
+
+    /// Same as [SDynVec::push], but returns [OutOfMemory] instead of the element
+    ///
+    /// Useful for propagating the error with `?`. The element gets dropped, if it can't be inserted.
+    #[inline]
+    pub fn try_push(&mut self, element: T) -> Result<(), OutOfMemory> {
+        self.push(element).map_err(|_| OutOfMemory)
+    }
+
+    /// Returns a copy of the element at requested index
+    ///
+    /// The element is decoded from stable memory on each call. If out of bounds, returns [None].
+    #[inline]
+    pub fn get(&self, idx: usize) -> Option<T> {
+        let entry = *self.table.get(idx)?;
+
+        let mut it = Self::read_element(entry);
+        unsafe { it.stable_drop_flag_off() };
+
+        Some(it)
+    }
+
+    /// Removes the element at requested index, back-shifting the offset table after it
+    ///
+    /// Never moves the data of other elements. Releases the data block of the element, if it was
+    /// the last alive element there.
+    ///
+    /// # Panics
+    /// Panics if out of bounds.
+    pub fn remove(&mut self, idx: usize) -> T {
+        assert!(idx < self.len(), "out of bounds");
+
+        let entry = self.table.remove(idx);
+
+        let mut it = Self::read_element(entry);
+        unsafe { it.stable_drop_flag_on() };
+
+        self.release(entry);
+
+        it
+    }
+
+    /// Removes the last element of this [SDynVec]
+    ///
+    /// If the [SDynVec] is empty, returns [None].
+    #[inline]
+    pub fn pop(&mut self) -> Option<T> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(self.remove(self.len() - 1))
+        }
+    }
+
+    /// Removes all elements from this [SDynVec]
+    ///
+    /// Releases all data blocks, but the current one.
+    #[inline]
+    pub fn clear(&mut self) {
+        while self.pop().is_some() {}
+    }
+
+    /// Returns an iterator over copies of the elements of this [SDynVec]
+    #[inline]
+    pub fn iter(&self) -> SDynVecIter<'_, T> {
+        SDynVecIter::new(self)
+    }
+
+    fn read_element((block, offset, len): Entry) -> T {
+        let mut buf = vec![0u8; len as usize];
+        unsafe {
+            crate::mem::read_bytes(
+                SSlice::_offset(block, DATA_OFFSET + offset as u64),
+                &mut buf,
+            )
+        };
+
+        T::from_dyn_size_bytes(&buf)
+    }
+
+    fn release(&mut self, (block, offset, len): Entry) {
+        let alive = Self::read_counter(block, ALIVE_OFFSET) - 1;
+
+        if block == self.cur_block {
+            // the space at the end of the current block can be reused right away
+            let used = Self::read_counter(block, USED_OFFSET);
+
+            if alive == 0 {
+                Self::write_counter(block, USED_OFFSET, 0);
+            } else if offset as u64 + len as u64 == used {
+                Self::write_counter(block, USED_OFFSET, offset as u64);
+            }
+        } else if alive == 0 {
+            deallocate(unsafe { SSlice::from_ptr(block).unwrap() });
+
+            return;
+        }
+
+        Self::write_counter(block, ALIVE_OFFSET, alive);
+    }
+
+    // the previous block is released, if all of its elements are already removed
+    fn switch_cur_block(&mut self, block: StablePtr) {
+        if self.cur_block != EMPTY_PTR && Self::read_counter(self.cur_block, ALIVE_OFFSET) == 0 {
+            deallocate(unsafe { SSlice::from_ptr(self.cur_block).unwrap() });
+        }
+
+        self.cur_block = block;
+    }
+
+    #[inline]
+    fn room_left(block: StablePtr) -> u64 {
+        let slice = unsafe { SSlice::from_ptr(block).unwrap() };
+
+        slice.get_size_bytes() - DATA_OFFSET - Self::read_counter(block, USED_OFFSET)
+    }
+
+    #[inline]
+    fn read_counter(block: StablePtr, offset: u64) -> u64 {
+        unsafe { crate::mem::read_fixed_for_reference(SSlice::_offset(block, offset)) }
+    }
+
+    #[inline]
+    fn write_counter(block: StablePtr, offset: u64, mut value: u64) {
+        unsafe { crate::mem::write_fixed(SSlice::_offset(block, offset), &mut value) };
+    }
+}
+
+impl<T: StableType + AsDynSizeBytes> Default for SDynVec<T> {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: StableType + AsDynSizeBytes + Debug> Debug for SDynVec<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("[")?;
+        for (idx, item) in self.iter().take(DEBUG_ELEMENTS_LIMIT).enumerate() {
+            if idx > 0 {
+                f.write_str(", ")?;
+            }
+
+            item.fmt(f)?;
+        }
+
+        if self.len() > DEBUG_ELEMENTS_LIMIT {
+            write!(f, ", ...{} more", self.len() - DEBUG_ELEMENTS_LIMIT)?;
+        }
+
+        f.write_str("]")
+    }
+}
+
+impl<T: StableType + AsDynSizeBytes> AsFixedSizeBytes for SDynVec<T> {
+    const SIZE: usize = SVec::<Entry>::SIZE + u64::SIZE;
+    type Buf = [u8; SVec::<Entry>::SIZE + u64::SIZE];
+
+    fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+        self.table
+            .as_fixed_size_bytes(&mut buf[0..SVec::<Entry>::SIZE]);
+        self.cur_block
+            .as_fixed_size_bytes(&mut buf[SVec::<Entry>::SIZE..Self::SIZE]);
+    }
+
+    fn from_fixed_size_bytes(buf: &[u8]) -> Self {
+        let table = SVec::<Entry>::from_fixed_size_bytes(&buf[0..SVec::<Entry>::SIZE]);
+        let cur_block = u64::from_fixed_size_bytes(&buf[SVec::<Entry>::SIZE..Self::SIZE]);
+
+        Self {
+            table,
+            cur_block,
+            _marker: PhantomData::default(),
+        }
+    }
+}
+
+impl<T: StableType + AsDynSizeBytes> StableType for SDynVec<T> {
+    #[inline]
+    unsafe fn stable_drop_flag_off(&mut self) {
+        self.table.stable_drop_flag_off();
+    }
+
+    #[inline]
+    unsafe fn stable_drop_flag_on(&mut self) {
+        self.table.stable_drop_flag_on();
+    }
+
+    #[inline]
+    fn should_stable_drop(&self) -> bool {
+        self.table.should_stable_drop()
+    }
+
+    // the offset table itself is released by its own Drop
+    unsafe fn stable_drop(&mut self) {
+        self.clear();
+
+        if self.cur_block != EMPTY_PTR {
+            deallocate(SSlice::from_ptr(self.cur_block).unwrap());
+            self.cur_block = EMPTY_PTR;
+        }
+    }
+}
+
+impl<T: StableType + AsDynSizeBytes> Drop for SDynVec<T> {
+    fn drop(&mut self) {
+        if self.should_stable_drop() {
+            unsafe {
+                self.stable_drop();
+            }
+        }
+    }
+}
+
+#[cfg(feature = "orphan_collector")]
+impl<T: StableType + AsDynSizeBytes + Trace> Trace for SDynVec<T> {
+    fn trace(&self, ptrs: &mut Vec<StablePtr>) {
+        self.table.trace(ptrs);
+
+        // each block holds many elements, but is only reported once
+        let mut blocks = BTreeSet::new();
+        if self.cur_block != EMPTY_PTR {
+            blocks.insert(self.cur_block);
+        }
+
+        for entry in self.table.iter() {
+            blocks.insert(entry.0);
+        }
+
+        ptrs.extend(blocks);
+
+        if T::OWNS_MEMORY {
+            for elem in self.iter() {
+                elem.trace(ptrs);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::dyn_vec::{SDynVec, BLOCK_CAPACITY};
+    use crate::utils::test::generate_random_string;
+    use crate::{
+        _debug_validate_allocator, get_allocated_size, retrieve_custom_data, stable,
+        stable_memory_init, store_custom_data, SBox,
+    };
+    use rand::{thread_rng, Rng};
+
+    #[test]
+    fn works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut vec = SDynVec::<String>::new();
+            assert!(vec.is_empty());
+            assert!(vec.get(0).is_none());
+            assert!(vec.pop().is_none());
+
+            vec.push(String::new()).unwrap();
+            vec.push(String::from("short")).unwrap();
+            vec.push("long".repeat(BLOCK_CAPACITY as usize)).unwrap();
+            vec.push(String::from("after the long one")).unwrap();
+
+            assert_eq!(vec.len(), 4);
+            assert_eq!(vec.get(0).unwrap(), "");
+            assert_eq!(vec.get(1).unwrap(), "short");
+            assert_eq!(vec.get(2).unwrap().len(), 4 * BLOCK_CAPACITY as usize);
+            assert_eq!(vec.get(3).unwrap(), "after the long one");
+            assert!(vec.get(4).is_none());
+
+            assert_eq!(vec.remove(1), "short");
+            assert_eq!(vec.pop().unwrap(), "after the long one");
+            assert_eq!(
+                vec.iter().map(|it| it.len()).collect::<Vec<_>>(),
+                vec![0, 4 * BLOCK_CAPACITY as usize]
+            );
+
+            store_custom_data(0, SBox::new(vec).unwrap());
+            let mut vec = retrieve_custom_data::<SDynVec<String>>(0)
+                .unwrap()
+                .into_inner();
+
+            assert_eq!(vec.len(), 2);
+            assert_eq!(vec.get(0).unwrap(), "");
+
+            vec.clear();
+            assert!(vec.is_empty());
+
+            vec.push(String::from("again")).unwrap();
+            assert_eq!(format!("{:?}", vec), "[\"again\"]");
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn random_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut vec = SDynVec::<String>::default();
+            let mut example = Vec::new();
+            let mut rng = thread_rng();
+
+            for _ in 0..5000 {
+                if example.is_empty() || rng.gen_bool(0.6) {
+                    let it = generate_random_string(&mut rng);
+
+                    vec.push(it.clone()).unwrap();
+                    example.push(it);
+                } else {
+                    let idx = rng.gen_range(0..example.len());
+
+                    assert_eq!(vec.remove(idx), example.remove(idx));
+                }
+            }
+
+            assert_eq!(vec.len(), example.len());
+            assert!(vec.iter().eq(example.iter().cloned()));
+            assert!(vec.iter().rev().eq(example.iter().rev().cloned()));
+
+            while let Some(it) = vec.pop() {
+                assert_eq!(it, example.pop().unwrap());
+            }
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
@@ -0,0 +1,35 @@
+use crate::collections::btree_map::iter::SBTreeMapRangeIter;
+use crate::encoding::AsFixedSizeBytes;
+use crate::primitive::s_ref::SRef;
+use crate::primitive::StableType;
+
+pub struct SGraphEdgesIter<'a, E> {
+    inner: SBTreeMapRangeIter<'a, (u64, u64), E>,
+}
+
+impl<'a, E> SGraphEdgesIter<'a, E> {
+    #[inline]
+    pub(crate) fn new(inner: SBTreeMapRangeIter<'a, (u64, u64), E>) -> Self {
+        Self { inner }
+    }
+}
+
+impl<'a, E: StableType + AsFixedSizeBytes> Iterator for SGraphEdgesIter<'a, E> {
+    type Item = (u64, SRef<'a, E>);
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        let (k, e) = self.inner.next()?;
+
+        Some((k.1, e))
+    }
+}
+
+impl<'a, E: StableType + AsFixedSizeBytes> DoubleEndedIterator for SGraphEdgesIter<'a, E> {
+    #[inline]
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let (k, e) = self.inner.next_back()?;
+
+        Some((k.1, e))
+    }
+}
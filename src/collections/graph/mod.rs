@@ -0,0 +1,615 @@
+use crate::collections::btree_map::iter::SBTreeMapIter;
+use crate::collections::btree_map::SBTreeMap;
+use crate::collections::graph::iter::SGraphEdgesIter;
+use crate::encoding::AsFixedSizeBytes;
+#[cfg(feature = "orphan_collector")]
+use crate::mem::orphan_collector::Trace;
+#[cfg(feature = "orphan_collector")]
+use crate::mem::StablePtr;
+use crate::primitive::s_ref::SRef;
+use crate::primitive::s_ref_mut::SRefMut;
+use crate::primitive::StableType;
+use crate::OutOfMemory;
+use std::fmt::{Debug, Formatter};
+
+#[doc(hidden)]
+pub mod iter;
+
+/// Directed graph with data attached to both nodes and edges
+///
+/// Consists of a node table and adjacency lists of outgoing and incoming edges. Adjacency lists are
+/// stored in [SBTreeMap]s, keyed by `(node, neighbor)` pairs, so all edges of a single node are laid
+/// out next to each other and any of them can be found, inserted or removed in O(logN). Iterating over
+/// neighbors of a node takes O(logN + M), where `M` is the number of neighbors.
+///
+/// Nodes are identified by `u64` ids, which are assigned automatically and never reused. To model an
+/// undirected graph, simply add edges in both directions.
+///
+/// Both `N` and `E` have to implement [StableType] and [AsFixedSizeBytes]. [SGraph] also implements
+/// these traits, so you can nest it into other stable structures.
+///
+/// # Examples
+/// ```rust
+/// # use ic_stable_memory::collections::SGraph;
+/// # use ic_stable_memory::stable_memory_init;
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// let mut social = SGraph::new();
+///
+/// let alice = social.add_node(25u32).expect("Out of memory");
+/// let bob = social.add_node(30u32).expect("Out of memory");
+/// let carol = social.add_node(35u32).expect("Out of memory");
+///
+/// // alice follows bob and carol since these timestamps
+/// social.add_edge(alice, bob, 1000u64).expect("Out of memory");
+/// social.add_edge(alice, carol, 2000u64).expect("Out of memory");
+///
+/// let following: Vec<_> = social.neighbors(alice).map(|(id, _)| id).collect();
+/// assert_eq!(following, vec![bob, carol]);
+///
+/// let followers: Vec<_> = social.incoming(carol).map(|(id, _)| id).collect();
+/// assert_eq!(followers, vec![alice]);
+/// ```
+pub struct SGraph<N: StableType + AsFixedSizeBytes, E: StableType + AsFixedSizeBytes> {
+    nodes: SBTreeMap<u64, N>,
+    out_edges: SBTreeMap<(u64, u64), E>,
+    in_edges: SBTreeMap<(u64, u64), ()>,
+    next_id: u64,
+}
+
+impl<N: StableType + AsFixedSizeBytes, E: StableType + AsFixedSizeBytes> SGraph<N, E> {
+    /// Creates a new [SGraph]
+    ///
+    /// Does not allocate any heap or stable memory.
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            nodes: SBTreeMap::new(),
+            out_edges: SBTreeMap::new(),
+            in_edges: SBTreeMap::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Adds a new node, returning its id
+    ///
+    /// May allocate stable memory. If the canister is out of stable memory, returns [Err] with the
+    /// node data.
+    pub fn add_node(&mut self, data: N) -> Result<u64, N> {
+        let id = self.next_id;
+
+        self.nodes.insert(id, data).map_err(|(_, data)| data)?;
+        self.next_id += 1;
+
+        Ok(id)
+    }
+
+    /// Same as [SGraph::add_node], but returns [OutOfMemory] instead of the node data
+    ///
+    /// Useful for propagating the error with `?`. The data gets dropped, if the node can't be added.
+    #[inline]
+    pub fn try_add_node(&mut self, data: N) -> Result<u64, OutOfMemory> {
+        self.add_node(data).map_err(|_| OutOfMemory)
+    }
+
+    /// Removes the node and all its incoming and outgoing edges, returning the node data
+    ///
+    /// Never allocates.
+    pub fn remove_node(&mut self, id: u64) -> Option<N> {
+        let data = self.nodes.remove(&id)?;
+
+        let outgoing: Vec<u64> = self.neighbors(id).map(|(to, _)| to).collect();
+        for to in outgoing {
+            self.out_edges.remove(&(id, to));
+            self.in_edges.remove(&(to, id));
+        }
+
+        let incoming: Vec<u64> = self.incoming(id).map(|(from, _)| from).collect();
+        for from in incoming {
+            self.in_edges.remove(&(id, from));
+            self.out_edges.remove(&(from, id));
+        }
+
+        Some(data)
+    }
+
+    /// Returns an immutable reference to the node data
+    #[inline]
+    pub fn get_node(&self, id: u64) -> Option<SRef<'_, N>> {
+        self.nodes.get(&id)
+    }
+
+    /// Returns a mutable reference to the node data
+    #[inline]
+    pub fn get_node_mut(&mut self, id: u64) -> Option<SRefMut<'_, N>> {
+        self.nodes.get_mut(&id)
+    }
+
+    /// Returns `true` if there is a node with this id
+    #[inline]
+    pub fn contains_node(&self, id: u64) -> bool {
+        self.nodes.contains_key(&id)
+    }
+
+    /// Adds an edge from one node to another, returning the data of the previous edge between these
+    /// nodes, if there was one
+    ///
+    /// May allocate stable memory. If the canister is out of stable memory, returns [Err] with the
+    /// edge data and leaves the graph untouched.
+    ///
+    /// # Panics
+    /// Panics if any of the nodes is not present in this graph.
+    pub fn add_edge(&mut self, from: u64, to: u64, data: E) -> Result<Option<E>, E> {
+        assert!(
+            self.contains_node(from) && self.contains_node(to),
+            "Node not found"
+        );
+
+        if self.in_edges.insert((to, from), ()).is_err() {
+            return Err(data);
+        }
+
+        match self.out_edges.insert((from, to), data) {
+            Ok(prev) => Ok(prev),
+            Err((_, data)) => {
+                // the edge was not present, otherwise the insertion would not allocate
+                self.in_edges.remove(&(to, from));
+
+                Err(data)
+            }
+        }
+    }
+
+    /// Same as [SGraph::add_edge], but returns [OutOfMemory] instead of the edge data
+    ///
+    /// Useful for propagating the error with `?`. The data gets dropped, if the edge can't be added.
+    ///
+    /// # Panics
+    /// Panics if any of the nodes is not present in this graph.
+    #[inline]
+    pub fn try_add_edge(&mut self, from: u64, to: u64, data: E) -> Result<Option<E>, OutOfMemory> {
+        self.add_edge(from, to, data).map_err(|_| OutOfMemory)
+    }
+
+    /// Removes the edge, returning its data
+    ///
+    /// Never allocates.
+    pub fn remove_edge(&mut self, from: u64, to: u64) -> Option<E> {
+        let data = self.out_edges.remove(&(from, to))?;
+        self.in_edges.remove(&(to, from));
+
+        Some(data)
+    }
+
+    /// Returns an immutable reference to the edge data
+    #[inline]
+    pub fn get_edge(&self, from: u64, to: u64) -> Option<SRef<'_, E>> {
+        self.out_edges.get(&(from, to))
+    }
+
+    /// Returns a mutable reference to the edge data
+    #[inline]
+    pub fn get_edge_mut(&mut self, from: u64, to: u64) -> Option<SRefMut<'_, E>> {
+        self.out_edges.get_mut(&(from, to))
+    }
+
+    /// Returns `true` if there is an edge from one node to another
+    #[inline]
+    pub fn contains_edge(&self, from: u64, to: u64) -> bool {
+        self.out_edges.contains_key(&(from, to))
+    }
+
+    /// Returns an iterator over targets of outgoing edges of the node (and these edges' data), in
+    /// ascending order of ids
+    #[inline]
+    pub fn neighbors(&self, id: u64) -> SGraphEdgesIter<'_, E> {
+        SGraphEdgesIter::new(self.out_edges.range((id, 0)..=(id, u64::MAX)))
+    }
+
+    /// Returns an iterator over sources of incoming edges of the node, in ascending order of ids
+    ///
+    /// Edge data can be accessed via [SGraph::get_edge].
+    #[inline]
+    pub fn incoming(&self, id: u64) -> SGraphEdgesIter<'_, ()> {
+        SGraphEdgesIter::new(self.in_edges.range((id, 0)..=(id, u64::MAX)))
+    }
+
+    /// Returns an iterator over all nodes in ascending order of their ids
+    #[inline]
+    pub fn nodes(&self) -> SBTreeMapIter<'_, u64, N> {
+        self.nodes.iter()
+    }
+
+    /// Returns the number of nodes in this [SGraph]
+    #[inline]
+    pub fn node_count(&self) -> u64 {
+        self.nodes.len()
+    }
+
+    /// Returns the number of edges in this [SGraph]
+    #[inline]
+    pub fn edge_count(&self) -> u64 {
+        self.out_edges.len()
+    }
+
+    /// Returns `true` if there are no nodes in this [SGraph]
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Removes all nodes and edges from this [SGraph], releasing all occupied stable memory
+    ///
+    /// Ids of removed nodes are still not reused.
+    #[inline]
+    pub fn clear(&mut self) {
+        self.nodes.clear();
+        self.out_edges.clear();
+        self.in_edges.clear();
+    }
+}
+
+impl<N: StableType + AsFixedSizeBytes, E: StableType + AsFixedSizeBytes> Default for SGraph<N, E> {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<N: StableType + AsFixedSizeBytes, E: StableType + AsFixedSizeBytes> AsFixedSizeBytes
+    for SGraph<N, E>
+{
+    const SIZE: usize = SBTreeMap::<u64, u64>::SIZE * 3 + u64::SIZE;
+    type Buf = [u8; SBTreeMap::<u64, u64>::SIZE * 3 + u64::SIZE];
+
+    fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+        let map_size = SBTreeMap::<u64, u64>::SIZE;
+
+        self.nodes.as_fixed_size_bytes(&mut buf[0..map_size]);
+        self.out_edges
+            .as_fixed_size_bytes(&mut buf[map_size..(map_size * 2)]);
+        self.in_edges
+            .as_fixed_size_bytes(&mut buf[(map_size * 2)..(map_size * 3)]);
+        self.next_id
+            .as_fixed_size_bytes(&mut buf[(map_size * 3)..Self::SIZE]);
+    }
+
+    fn from_fixed_size_bytes(buf: &[u8]) -> Self {
+        let map_size = SBTreeMap::<u64, u64>::SIZE;
+
+        Self {
+            nodes: SBTreeMap::from_fixed_size_bytes(&buf[0..map_size]),
+            out_edges: SBTreeMap::from_fixed_size_bytes(&buf[map_size..(map_size * 2)]),
+            in_edges: SBTreeMap::from_fixed_size_bytes(&buf[(map_size * 2)..(map_size * 3)]),
+            next_id: u64::from_fixed_size_bytes(&buf[(map_size * 3)..Self::SIZE]),
+        }
+    }
+}
+
+impl<N: StableType + AsFixedSizeBytes, E: StableType + AsFixedSizeBytes> StableType
+    for SGraph<N, E>
+{
+    #[inline]
+    unsafe fn stable_drop_flag_off(&mut self) {
+        self.nodes.stable_drop_flag_off();
+        self.out_edges.stable_drop_flag_off();
+        self.in_edges.stable_drop_flag_off();
+    }
+
+    #[inline]
+    unsafe fn stable_drop_flag_on(&mut self) {
+        self.nodes.stable_drop_flag_on();
+        self.out_edges.stable_drop_flag_on();
+        self.in_edges.stable_drop_flag_on();
+    }
+}
+
+impl<N: StableType + AsFixedSizeBytes + Debug, E: StableType + AsFixedSizeBytes + Debug> Debug
+    for SGraph<N, E>
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("{")?;
+        for (idx, (id, data)) in self.nodes().enumerate() {
+            id.fmt(f)?;
+            f.write_str(": ")?;
+            data.fmt(f)?;
+            f.write_str(" -> [")?;
+
+            let mut edges = self.neighbors(*id).peekable();
+            while let Some((to, e)) = edges.next() {
+                to.fmt(f)?;
+                f.write_str(": ")?;
+                e.fmt(f)?;
+
+                if edges.peek().is_some() {
+                    f.write_str(", ")?;
+                }
+            }
+
+            f.write_str("]")?;
+
+            if idx < (self.node_count() - 1) as usize {
+                f.write_str(", ")?;
+            }
+        }
+        f.write_str("}")
+    }
+}
+
+#[cfg(feature = "orphan_collector")]
+impl<N: StableType + AsFixedSizeBytes + Trace, E: StableType + AsFixedSizeBytes + Trace> Trace
+    for SGraph<N, E>
+{
+    fn trace(&self, ptrs: &mut Vec<StablePtr>) {
+        self.nodes.trace(ptrs);
+        self.out_edges.trace(ptrs);
+        self.in_edges.trace(ptrs);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::graph::SGraph;
+    use crate::utils::test::generate_random_string;
+    use crate::{
+        _debug_validate_allocator, get_allocated_size, init_allocator, retrieve_custom_data,
+        stable, stable_memory_init, stable_memory_post_upgrade, stable_memory_pre_upgrade,
+        store_custom_data, SBox,
+    };
+    use rand::rngs::ThreadRng;
+    use rand::seq::SliceRandom;
+    use rand::{thread_rng, Rng};
+    use std::collections::{BTreeMap, BTreeSet};
+
+    #[test]
+    fn works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut graph = SGraph::new();
+
+            let a = graph.add_node(1u64).unwrap();
+            let b = graph.add_node(2u64).unwrap();
+            let c = graph.add_node(3u64).unwrap();
+
+            assert!(graph.add_edge(a, b, 10u64).unwrap().is_none());
+            assert!(graph.add_edge(a, c, 20u64).unwrap().is_none());
+            assert!(graph.add_edge(b, c, 30u64).unwrap().is_none());
+            assert!(graph.add_edge(c, c, 40u64).unwrap().is_none());
+            assert_eq!(graph.add_edge(a, b, 11u64).unwrap(), Some(10));
+
+            assert_eq!(graph.edge_count(), 4);
+            assert_eq!(*graph.get_edge(a, b).unwrap(), 11);
+            assert!(graph.get_edge(b, a).is_none());
+
+            let res: Vec<_> = graph.neighbors(a).map(|(id, e)| (id, *e)).collect();
+            assert_eq!(res, vec![(b, 11), (c, 20)]);
+
+            let res: Vec<_> = graph.incoming(c).map(|(id, _)| id).collect();
+            assert_eq!(res, vec![a, b, c]);
+
+            store_custom_data(0, SBox::new(graph).unwrap());
+            let mut graph = retrieve_custom_data::<SGraph<u64, u64>>(0)
+                .unwrap()
+                .into_inner();
+
+            assert_eq!(graph.remove_node(c), Some(3));
+            assert_eq!(graph.edge_count(), 1);
+            assert_eq!(graph.neighbors(a).count(), 1);
+            assert_eq!(graph.incoming(b).count(), 1);
+
+            let d = graph.add_node(4).unwrap();
+            assert_ne!(d, c);
+
+            assert_eq!(graph.remove_edge(a, b), Some(11));
+            assert!(graph.remove_edge(a, b).is_none());
+            assert_eq!(graph.edge_count(), 0);
+            assert_eq!(graph.node_count(), 3);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn edges_to_absent_nodes_panic() {
+        stable::clear();
+        stable_memory_init();
+
+        let mut graph = SGraph::new();
+        let a = graph.add_node(1u64).unwrap();
+
+        graph.add_edge(a, a + 1, 0u64).unwrap();
+    }
+
+    enum Action {
+        AddNode,
+        RemoveNode,
+        AddEdge,
+        RemoveEdge,
+        Clear,
+        CanisterUpgrade,
+    }
+
+    struct Fuzzer {
+        state: Option<SGraph<u64, SBox<String>>>,
+        nodes: BTreeSet<u64>,
+        edges: BTreeMap<(u64, u64), String>,
+        rng: ThreadRng,
+        log: Vec<Action>,
+    }
+
+    impl Fuzzer {
+        fn new() -> Self {
+            Self {
+                state: Some(SGraph::default()),
+                nodes: BTreeSet::default(),
+                edges: BTreeMap::default(),
+                rng: thread_rng(),
+                log: Vec::default(),
+            }
+        }
+
+        fn it(&mut self) -> &mut SGraph<u64, SBox<String>> {
+            self.state.as_mut().unwrap()
+        }
+
+        fn random_node(&mut self) -> Option<u64> {
+            let nodes: Vec<_> = self.nodes.iter().copied().collect();
+
+            nodes.choose(&mut self.rng).copied()
+        }
+
+        fn next(&mut self) {
+            let action = self.rng.gen_range(0..101);
+
+            match action {
+                // ADD NODE ~20%
+                0..=19 => {
+                    if let Ok(id) = self.it().add_node(0) {
+                        assert!(self.nodes.insert(id));
+                    }
+
+                    self.log.push(Action::AddNode);
+                }
+                // REMOVE NODE ~5%
+                20..=24 => {
+                    if let Some(id) = self.random_node() {
+                        assert!(self.it().remove_node(id).is_some());
+
+                        self.nodes.remove(&id);
+                        self.edges.retain(|(from, to), _| *from != id && *to != id);
+                    }
+
+                    self.log.push(Action::RemoveNode);
+                }
+                // ADD EDGE ~50%
+                25..=74 => {
+                    let (from, to) = match (self.random_node(), self.random_node()) {
+                        (Some(from), Some(to)) => (from, to),
+                        _ => return,
+                    };
+
+                    let str = generate_random_string(&mut self.rng);
+
+                    if let Ok(data) = SBox::new(str.clone()) {
+                        if let Ok(prev) = self.it().add_edge(from, to, data) {
+                            assert_eq!(
+                                prev.map(|it| it.clone()),
+                                self.edges.insert((from, to), str)
+                            );
+                        }
+
+                        self.log.push(Action::AddEdge);
+                    }
+                }
+                // REMOVE EDGE ~20%
+                75..=94 => {
+                    let edges: Vec<_> = self.edges.keys().copied().collect();
+
+                    if let Some((from, to)) = edges.choose(&mut self.rng).copied() {
+                        assert_eq!(
+                            self.it().remove_edge(from, to).map(|it| it.clone()),
+                            self.edges.remove(&(from, to))
+                        );
+                    }
+
+                    self.log.push(Action::RemoveEdge);
+                }
+                // CLEAR
+                95..=95 => {
+                    self.it().clear();
+                    self.nodes.clear();
+                    self.edges.clear();
+
+                    self.log.push(Action::Clear);
+                }
+                // CANISTER UPGRADE
+                _ => match SBox::new(self.state.take().unwrap()) {
+                    Ok(data) => {
+                        store_custom_data(1, data);
+
+                        if stable_memory_pre_upgrade().is_ok() {
+                            stable_memory_post_upgrade();
+                        }
+
+                        self.state = retrieve_custom_data::<SGraph<u64, SBox<String>>>(1)
+                            .map(|it| it.into_inner());
+
+                        self.log.push(Action::CanisterUpgrade);
+                    }
+                    Err(graph) => {
+                        self.state = Some(graph);
+                    }
+                },
+            }
+
+            _debug_validate_allocator();
+            assert_eq!(self.it().node_count(), self.nodes.len() as u64);
+            assert_eq!(self.it().edge_count(), self.edges.len() as u64);
+
+            if let Some(id) = self.random_node() {
+                let expected_out: Vec<_> = self
+                    .edges
+                    .iter()
+                    .filter(|((from, _), _)| *from == id)
+                    .map(|((_, to), data)| (*to, data.clone()))
+                    .collect();
+
+                let actual_out: Vec<_> = self
+                    .it()
+                    .neighbors(id)
+                    .map(|(to, data)| (to, String::clone(&data)))
+                    .collect();
+
+                assert_eq!(actual_out, expected_out);
+
+                let mut expected_in: Vec<_> = self
+                    .edges
+                    .keys()
+                    .filter(|(_, to)| *to == id)
+                    .map(|(from, _)| *from)
+                    .collect();
+                expected_in.sort();
+
+                let actual_in: Vec<_> = self.it().incoming(id).map(|(from, _)| from).collect();
+
+                assert_eq!(actual_in, expected_in);
+            }
+        }
+    }
+
+    #[test]
+    fn fuzzer_works_fine() {
+        stable::clear();
+        init_allocator(0);
+
+        {
+            let mut fuzzer = Fuzzer::new();
+
+            for _ in 0..10_000 {
+                fuzzer.next();
+            }
+        }
+
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn fuzzer_works_fine_limited_memory() {
+        stable::clear();
+        init_allocator(10);
+
+        {
+            let mut fuzzer = Fuzzer::new();
+
+            for _ in 0..10_000 {
+                fuzzer.next();
+            }
+        }
+
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
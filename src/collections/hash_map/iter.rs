@@ -19,6 +19,14 @@ impl<'a, K: StableType + AsFixedSizeBytes + Hash + Eq, V: StableType + AsFixedSi
     pub fn new(map: &'a SHashMap<K, V>) -> Self {
         Self { map, i: 0 }
     }
+
+    #[inline]
+    pub(crate) fn new_from(map: &'a SHashMap<K, V>, i: usize) -> Self {
+        Self {
+            map,
+            i: i.min(map.capacity()),
+        }
+    }
 }
 
 impl<'a, K: StableType + AsFixedSizeBytes + Eq + Hash, V: StableType + AsFixedSizeBytes> Iterator
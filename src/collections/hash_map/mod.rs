@@ -1,15 +1,20 @@
 use crate::collections::hash_map::iter::SHashMapIter;
-use crate::encoding::{AsFixedSizeBytes, Buffer};
+use crate::encoding::{AsDynSizeBytes, AsFixedSizeBytes, Buffer, CorruptData};
 use crate::mem::allocator::EMPTY_PTR;
+#[cfg(feature = "orphan_collector")]
+use crate::mem::orphan_collector::{memory_usage, MemoryUsage, Trace};
 use crate::mem::StablePtr;
+use crate::primitive::s_box::SBox;
 use crate::primitive::s_ref::SRef;
 use crate::primitive::s_ref_mut::SRefMut;
-use crate::primitive::StableType;
+use crate::primitive::{DeepCopy, StableType};
+use crate::utils::budget::{Budget, ResumableIter};
 use crate::utils::DebuglessUnwrap;
 use crate::{allocate, deallocate, OutOfMemory, SSlice};
 use std::borrow::Borrow;
 use std::fmt::{Debug, Formatter};
 use std::hash::{Hash, Hasher};
+use std::io::IoSlice;
 use std::marker::PhantomData;
 use zwohash::ZwoHasher;
 
@@ -216,6 +221,137 @@ impl<K: StableType + AsFixedSizeBytes + Hash + Eq, V: StableType + AsFixedSizeBy
         }
     }
 
+    /// Same as [SHashMap::insert], but returns [OutOfMemory] instead of the key-value pair
+    ///
+    /// Useful for propagating the error with `?`. The pair gets dropped, if it can't be inserted.
+    #[inline]
+    pub fn try_insert(&mut self, key: K, value: V) -> Result<Option<V>, OutOfMemory> {
+        self.insert(key, value).map_err(|_| OutOfMemory)
+    }
+
+    /// Inserts a value produced by `default` under the provided key, or, if there is already a value
+    /// stored by this key, updates it in place with `modify`
+    ///
+    /// Only probes the table once (unless it has to grow), which makes it a cheaper alternative to
+    /// [SHashMap::get] followed by [SHashMap::insert]. `default` is only called, when the key is missing.
+    ///
+    /// If your canister is out of stable memory, will return [Err] with the key and the default value.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use ic_stable_memory::collections::SHashMap;
+    /// # use ic_stable_memory::stable_memory_init;
+    /// # unsafe { ic_stable_memory::mem::clear(); }
+    /// # stable_memory_init();
+    /// let mut counters = SHashMap::new();
+    ///
+    /// for word in [1u64, 2, 1, 1] {
+    ///     counters.insert_with(word, || 1u64, |it| *it += 1).expect("Out of memory");
+    /// }
+    ///
+    /// assert_eq!(*counters.get(&1).unwrap(), 3);
+    /// assert_eq!(*counters.get(&2).unwrap(), 1);
+    /// ```
+    pub fn insert_with<D, M>(&mut self, key: K, default: D, modify: M) -> Result<(), (K, V)>
+    where
+        D: FnOnce() -> V,
+        M: FnOnce(&mut V),
+    {
+        if self.table_ptr == EMPTY_PTR {
+            return self.insert(key, default()).map(|_| ());
+        }
+
+        let key_hash = Self::hash(&key);
+        let mut i = key_hash % self.capacity();
+
+        loop {
+            match self.get_key(i) {
+                Some(prev_key) => {
+                    if (*prev_key).eq(&key) {
+                        modify(&mut self.get_val_mut(i));
+
+                        return Ok(());
+                    } else {
+                        i = (i + 1) % self.capacity();
+                    }
+                }
+                // growing rehashes every element anyway, so let insert() handle it
+                None if self.is_full() => return self.insert(key, default()).map(|_| ()),
+                None => {
+                    self.write_and_own_key(i, Some(key));
+                    self.write_and_own_val(i, default());
+
+                    self.len += 1;
+
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Returns a mutable reference [SRefMut] to a value stored by the key, inserting a value
+    /// produced by `default` first, if there is none
+    ///
+    /// Makes "ensure the entry exists, then use it" a single operation. `default` is only called,
+    /// when the key is missing. If your canister is out of stable memory, will return [Err] with
+    /// the key and the default value.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use ic_stable_memory::collections::SHashMap;
+    /// # use ic_stable_memory::stable_memory_init;
+    /// # unsafe { ic_stable_memory::mem::clear(); }
+    /// # stable_memory_init();
+    /// let mut sessions = SHashMap::new();
+    ///
+    /// for _ in 0..2 {
+    ///     let mut requests = sessions.get_or_insert_with(10u64, || 0u64).expect("Out of memory");
+    ///     *requests += 1;
+    /// }
+    ///
+    /// assert_eq!(*sessions.get(&10).unwrap(), 2);
+    /// ```
+    pub fn get_or_insert_with<D>(&mut self, key: K, default: D) -> Result<SRefMut<V>, (K, V)>
+    where
+        D: FnOnce() -> V,
+    {
+        if self.table_ptr != EMPTY_PTR {
+            let key_hash = Self::hash(&key);
+            let mut i = key_hash % self.capacity();
+
+            loop {
+                match self.get_key(i) {
+                    Some(prev_key) => {
+                        if (*prev_key).eq(&key) {
+                            return Ok(self.get_val_mut(i));
+                        } else {
+                            i = (i + 1) % self.capacity();
+                        }
+                    }
+                    None if self.is_full() => break,
+                    None => {
+                        self.write_and_own_key(i, Some(key));
+                        self.write_and_own_val(i, default());
+
+                        self.len += 1;
+
+                        return Ok(self.get_val_mut(i));
+                    }
+                }
+            }
+        }
+
+        // the table is either missing or has to grow - the pair is looked up again after the
+        // insertion by a non-owning copy of the key
+        let mut key_ref = K::from_fixed_size_bytes(key.as_new_fixed_size_bytes()._deref());
+        unsafe { key_ref.stable_drop_flag_off() };
+
+        self.insert(key, default())?;
+        let i = self.find_inner_idx(&key_ref).unwrap();
+
+        Ok(self.get_val_mut(i))
+    }
+
     /// Removes a key-value pair by the provided key
     ///
     /// Returns [None] if no pair was found by this key
@@ -374,6 +510,62 @@ impl<K: StableType + AsFixedSizeBytes + Hash + Eq, V: StableType + AsFixedSizeBy
         SHashMapIter::new(self)
     }
 
+    /// Returns an iterator over entries, starting from the slot `from`, that stops once the
+    /// `budget` is exhausted
+    ///
+    /// Pass `0` to start from the beginning. The [continuation](ResumableIter::continuation) of
+    /// the iterator is the slot the next call should start from, or [None] if there are no more
+    /// entries.
+    ///
+    /// A slot becomes invalid once this map is resized, so an insertion between calls may cause
+    /// some entries to be skipped or visited twice. So may a removal, which shifts the following
+    /// entries back.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use ic_stable_memory::collections::SHashMap;
+    /// # use ic_stable_memory::stable_memory_init;
+    /// # use ic_stable_memory::utils::budget::Budget;
+    /// # unsafe { ic_stable_memory::mem::clear(); }
+    /// # stable_memory_init();
+    /// let mut map = SHashMap::new();
+    ///
+    /// for i in 0..100u64 {
+    ///     map.insert(i, i).expect("Out of memory");
+    /// }
+    ///
+    /// let mut sum = 0;
+    /// let mut from = 0;
+    /// loop {
+    ///     let mut iter = map.iter_resumable(from, Budget::Elements(30));
+    ///     for (_, v) in &mut iter {
+    ///         sum += *v;
+    ///     }
+    ///
+    ///     from = match iter.into_continuation() {
+    ///         Some(slot) => slot,
+    ///         None => break,
+    ///     };
+    /// }
+    ///
+    /// assert_eq!(sum, 4950);
+    /// ```
+    pub fn iter_resumable<'a>(
+        &'a self,
+        from: usize,
+        budget: Budget,
+    ) -> ResumableIter<
+        SHashMapIter<'a, K, V>,
+        usize,
+        impl Fn(&(SRef<'a, K>, SRef<'a, V>)) -> usize + 'a,
+    > {
+        // only called once per iterator, for the first entry that didn't fit into the budget
+        let token_of =
+            move |(k, _): &(SRef<'a, K>, SRef<'a, V>)| self.find_inner_idx::<K>(k).unwrap();
+
+        ResumableIter::new(SHashMapIter::new_from(self, from), budget, token_of)
+    }
+
     /// Removes all elements from this [SHashMap]
     pub fn clear(&mut self) {
         if self.is_empty() {
@@ -490,7 +682,7 @@ impl<K: StableType + AsFixedSizeBytes + Hash + Eq, V: StableType + AsFixedSizeBy
         match flag {
             EMPTY => None,
             OCCUPIED => Some(unsafe { SRef::new(ptr + 1) }),
-            _ => unreachable!(),
+            _ => panic!("{}", CorruptData::new(ptr, "Invalid SHashMap key flag")),
         }
     }
 
@@ -501,7 +693,7 @@ impl<K: StableType + AsFixedSizeBytes + Hash + Eq, V: StableType + AsFixedSizeBy
         match flag {
             EMPTY => None,
             OCCUPIED => Some(unsafe { crate::mem::read_fixed_for_move(ptr + 1) }),
-            _ => unreachable!(),
+            _ => panic!("{}", CorruptData::new(ptr, "Invalid SHashMap key flag")),
         }
     }
 
@@ -512,7 +704,7 @@ impl<K: StableType + AsFixedSizeBytes + Hash + Eq, V: StableType + AsFixedSizeBy
         match flag {
             EMPTY => None,
             OCCUPIED => Some(unsafe { crate::mem::read_fixed_for_reference(ptr + 1) }),
-            _ => unreachable!(),
+            _ => panic!("{}", CorruptData::new(ptr, "Invalid SHashMap key flag")),
         }
     }
 
@@ -520,8 +712,17 @@ impl<K: StableType + AsFixedSizeBytes + Hash + Eq, V: StableType + AsFixedSizeBy
         let ptr = self.get_key_flag_ptr(idx);
 
         if let Some(mut k) = key {
-            unsafe { crate::mem::write_fixed(ptr, &mut OCCUPIED) };
-            unsafe { crate::mem::write_fixed(ptr + 1, &mut k) };
+            unsafe {
+                k.stable_drop_flag_off();
+
+                crate::mem::write_bytes_vectored(
+                    ptr,
+                    &[
+                        IoSlice::new(&[OCCUPIED]),
+                        IoSlice::new(k.as_new_fixed_size_bytes()._deref()),
+                    ],
+                );
+            }
 
             return;
         }
@@ -602,6 +803,22 @@ impl<K: StableType + AsFixedSizeBytes + Hash + Eq, V: StableType + AsFixedSizeBy
     }
 }
 
+impl<K: StableType + AsFixedSizeBytes + Hash + Eq, T: StableType + AsDynSizeBytes>
+    SHashMap<K, SBox<T>>
+{
+    /// Inserts a value, which is shared with its other owners, without copying it
+    ///
+    /// See [SBTreeMap::insert_shared](crate::collections::SBTreeMap::insert_shared).
+    #[inline]
+    pub fn insert_shared(
+        &mut self,
+        key: K,
+        value: &SBox<T>,
+    ) -> Result<Option<SBox<T>>, (K, SBox<T>)> {
+        self.insert(key, value.share())
+    }
+}
+
 impl<
         K: StableType + AsFixedSizeBytes + Hash + Eq + Debug,
         V: StableType + AsFixedSizeBytes + Debug,
@@ -622,6 +839,22 @@ impl<
     }
 }
 
+impl<
+        K: StableType + AsFixedSizeBytes + Hash + Eq + DeepCopy,
+        V: StableType + AsFixedSizeBytes + DeepCopy,
+    > DeepCopy for SHashMap<K, V>
+{
+    fn deep_copy(&self) -> Result<Self, OutOfMemory> {
+        let mut it = Self::new_with_capacity(self.capacity())?;
+
+        for (k, v) in self.iter() {
+            it.try_insert((*k).deep_copy()?, (*v).deep_copy()?)?;
+        }
+
+        Ok(it)
+    }
+}
+
 impl<K: StableType + AsFixedSizeBytes + Hash + Eq, V: StableType + AsFixedSizeBytes> Default
     for SHashMap<K, V>
 {
@@ -704,12 +937,51 @@ impl<K: StableType + AsFixedSizeBytes + Hash + Eq, V: StableType + AsFixedSizeBy
     }
 }
 
+#[cfg(feature = "orphan_collector")]
+impl<
+        K: StableType + AsFixedSizeBytes + Hash + Eq + Trace,
+        V: StableType + AsFixedSizeBytes + Trace,
+    > SHashMap<K, V>
+{
+    /// Returns the size of stable memory, owned by this collection
+    ///
+    /// Includes its table, plus the memory owned by its keys and values, see [MemoryUsage].
+    ///
+    /// Only available with the `orphan_collector` feature.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        memory_usage(self, self.len() as u64)
+    }
+}
+
+#[cfg(feature = "orphan_collector")]
+impl<
+        K: StableType + AsFixedSizeBytes + Hash + Eq + Trace,
+        V: StableType + AsFixedSizeBytes + Trace,
+    > Trace for SHashMap<K, V>
+{
+    fn trace(&self, ptrs: &mut Vec<StablePtr>) {
+        if self.table_ptr == EMPTY_PTR {
+            return;
+        }
+
+        ptrs.push(self.table_ptr);
+
+        if K::OWNS_MEMORY || V::OWNS_MEMORY {
+            for (k, v) in self.iter() {
+                k.trace(ptrs);
+                v.trace(ptrs);
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::collections::hash_map::SHashMap;
     use crate::encoding::AsFixedSizeBytes;
     use crate::primitive::s_box::SBox;
     use crate::primitive::StableType;
+    use crate::utils::budget::Budget;
     use crate::utils::mem_context::stable;
     use crate::utils::test::generate_random_string;
     use crate::utils::DebuglessUnwrap;
@@ -892,6 +1164,56 @@ mod tests {
         assert_eq!(get_allocated_size(), 0);
     }
 
+    #[test]
+    fn insert_with_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut map = SHashMap::<u64, u64>::new();
+
+            for i in 0..1000 {
+                map.insert_with(i % 100, || 1, |it| *it += 1).unwrap();
+            }
+
+            assert_eq!(map.len(), 100);
+
+            for i in 0..100 {
+                assert_eq!(*map.get(&i).unwrap(), 10);
+            }
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn get_or_insert_with_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut map = SHashMap::<SBox<u64>, u64>::new();
+
+            for i in 0..1000 {
+                *map.get_or_insert_with(SBox::new(i % 100).unwrap(), || 0)
+                    .unwrap() += i;
+            }
+
+            assert_eq!(map.len(), 100);
+
+            for i in 0..100 {
+                assert_eq!(
+                    *map.get(&i).unwrap(),
+                    (0..10).map(|j| j * 100 + i).sum::<u64>()
+                );
+            }
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
     #[test]
     fn iter_works_fine() {
         stable::clear();
@@ -917,6 +1239,55 @@ mod tests {
         assert_eq!(get_allocated_size(), 0);
     }
 
+    #[test]
+    fn iter_resumable_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut map = SHashMap::<u64, u64>::new();
+            assert!(map
+                .iter_resumable(0, Budget::Elements(10))
+                .into_continuation()
+                .is_none());
+
+            for i in 0..95 {
+                map.insert(i, i);
+            }
+
+            let mut visited = Vec::new();
+            let mut calls = 0;
+            let mut from = 0;
+            loop {
+                let mut iter = map.iter_resumable(from, Budget::Elements(10));
+                for (k, v) in &mut iter {
+                    assert_eq!(*k, *v);
+                    visited.push(*k);
+                }
+                calls += 1;
+
+                from = match iter.into_continuation() {
+                    Some(slot) => slot,
+                    None => break,
+                };
+            }
+
+            visited.sort();
+
+            assert_eq!(calls, 10);
+            assert_eq!(visited, (0..95).collect::<Vec<_>>());
+
+            assert_eq!(
+                map.iter_resumable(map.capacity() + 1, Budget::Elements(10))
+                    .count(),
+                0
+            );
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
     #[test]
     fn sboxes_work_fine() {
         stable::clear();
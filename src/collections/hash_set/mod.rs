@@ -1,7 +1,11 @@
 use crate::collections::hash_map::SHashMap;
 use crate::collections::hash_set::iter::SHashSetIter;
 use crate::encoding::AsFixedSizeBytes;
-use crate::primitive::StableType;
+#[cfg(feature = "orphan_collector")]
+use crate::mem::orphan_collector::{memory_usage, MemoryUsage, Trace};
+#[cfg(feature = "orphan_collector")]
+use crate::mem::StablePtr;
+use crate::primitive::{DeepCopy, StableType};
 use crate::OutOfMemory;
 use std::borrow::Borrow;
 use std::fmt::{Debug, Formatter};
@@ -43,6 +47,12 @@ impl<T: StableType + AsFixedSizeBytes + Hash + Eq> SHashSet<T> {
             .map_err(|(k, _)| k)
     }
 
+    /// See [SHashMap::try_insert]
+    #[inline]
+    pub fn try_insert(&mut self, value: T) -> Result<bool, OutOfMemory> {
+        self.map.try_insert(value, ()).map(|it| it.is_some())
+    }
+
     /// See [SHashMap::remove]
     #[inline]
     pub fn remove<Q>(&mut self, value: &Q) -> bool
@@ -100,6 +110,15 @@ impl<T: StableType + AsFixedSizeBytes + Hash + Eq> SHashSet<T> {
     }
 }
 
+impl<T: StableType + AsFixedSizeBytes + Hash + Eq + DeepCopy> DeepCopy for SHashSet<T> {
+    #[inline]
+    fn deep_copy(&self) -> Result<Self, OutOfMemory> {
+        Ok(Self {
+            map: self.map.deep_copy()?,
+        })
+    }
+}
+
 impl<T: StableType + AsFixedSizeBytes + Hash + Eq> Default for SHashSet<T> {
     #[inline]
     fn default() -> Self {
@@ -149,6 +168,26 @@ impl<T: StableType + AsFixedSizeBytes + Hash + Eq + Debug> Debug for SHashSet<T>
     }
 }
 
+#[cfg(feature = "orphan_collector")]
+impl<T: StableType + AsFixedSizeBytes + Hash + Eq + Trace> SHashSet<T> {
+    /// Returns the size of stable memory, owned by this collection
+    ///
+    /// Includes its table, plus the memory owned by its elements, see [MemoryUsage].
+    ///
+    /// Only available with the `orphan_collector` feature.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        memory_usage(self, self.len() as u64)
+    }
+}
+
+#[cfg(feature = "orphan_collector")]
+impl<T: StableType + AsFixedSizeBytes + Hash + Eq + Trace> Trace for SHashSet<T> {
+    #[inline]
+    fn trace(&self, ptrs: &mut Vec<StablePtr>) {
+        self.map.trace(ptrs)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::collections::hash_set::SHashSet;
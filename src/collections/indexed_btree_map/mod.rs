@@ -0,0 +1,701 @@
+use crate::collections::btree_map::iter::SBTreeMapIter;
+use crate::collections::btree_map::SBTreeMap;
+use crate::collections::btree_set::SBTreeSet;
+use crate::encoding::AsFixedSizeBytes;
+#[cfg(feature = "orphan_collector")]
+use crate::mem::orphan_collector::Trace;
+#[cfg(feature = "orphan_collector")]
+use crate::mem::StablePtr;
+use crate::primitive::s_ref::SRef;
+use crate::primitive::StableType;
+use crate::OutOfMemory;
+use std::fmt::{Debug, Formatter};
+
+/// Kind of a secondary index of [SIndexedBTreeMap]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IndexKind {
+    /// Each index key points to exactly one entry. Insertions, which would make two entries share
+    /// the same index key, are rejected.
+    Unique,
+    /// Each index key may point to any number of entries.
+    NonUnique,
+}
+
+/// Describes secondary indexes of values stored in [SIndexedBTreeMap]
+///
+/// All indexes share the same index key type `I`. If you need to index by keys of different types,
+/// wrap them into a single type, or index by their hashes.
+pub trait IndexedValue<I> {
+    /// Kinds of indexes of this value. The position of a kind in this slice is the id of the index.
+    const INDEXES: &'static [IndexKind];
+
+    /// Returns index keys of this value - one key per each index from [IndexedValue::INDEXES], in the
+    /// same order.
+    fn index_keys(&self) -> Vec<I>;
+}
+
+/// Error returned by [SIndexedBTreeMap::insert]
+///
+/// Both variants return the entry back, so it can be reused.
+#[derive(Debug)]
+pub enum IndexedInsertError<K, V> {
+    /// The canister is out of stable memory
+    OutOfMemory(K, V),
+    /// Another entry already owns the same key of this unique index
+    UniqueViolation { index: usize, key: K, value: V },
+}
+
+/// [SBTreeMap] with secondary indexes, which are always kept in sync with the entries
+///
+/// Each value describes its own index keys by implementing [IndexedValue]. Every insertion or
+/// removal updates the primary map and all secondary indexes within the same call - if any of these
+/// updates fails (because of a unique index violation or because the canister is out of stable
+/// memory), nothing is changed at all.
+///
+/// Unique indexes are stored in an [SBTreeMap]`<(u32, I), K>`, non-unique ones - in an
+/// [SBTreeMap]`<(u32, I), `[SBTreeSet]`<K>>`, where `u32` is the id of the index. So, lookups by an index
+/// key take O(logN). Each index entry stores a copy of the primary key, so keys should be small.
+///
+/// There is no `get_mut()` method - mutating a value in place could make its index keys outdated.
+/// Insert an updated value by the same key instead.
+///
+/// `K` and `I` have to implement [Clone], since they are copied into indexes. Both of them, as well as
+/// `V`, should also implement [StableType] and [AsFixedSizeBytes]. [SIndexedBTreeMap] also implements
+/// these traits, so you can nest it into other stable structures.
+///
+/// # Examples
+/// ```rust
+/// # use ic_stable_memory::collections::{IndexKind, IndexedValue, SIndexedBTreeMap};
+/// # use ic_stable_memory::derive::{StableType, AsFixedSizeBytes};
+/// # use ic_stable_memory::stable_memory_init;
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// #[derive(StableType, AsFixedSizeBytes)]
+/// struct User {
+///     email_hash: u64,
+///     country: u64,
+/// }
+///
+/// impl IndexedValue<u64> for User {
+///     const INDEXES: &'static [IndexKind] = &[IndexKind::Unique, IndexKind::NonUnique];
+///
+///     fn index_keys(&self) -> Vec<u64> {
+///         vec![self.email_hash, self.country]
+///     }
+/// }
+///
+/// let mut users = SIndexedBTreeMap::new();
+///
+/// users.insert(1u64, User { email_hash: 100, country: 7 }).ok().expect("Out of memory");
+/// users.insert(2u64, User { email_hash: 200, country: 7 }).ok().expect("Out of memory");
+///
+/// // the same email can't be used twice
+/// assert!(users.insert(3u64, User { email_hash: 100, country: 1 }).is_err());
+///
+/// assert_eq!(users.get_by_unique(0, &200).unwrap().country, 7);
+/// assert_eq!(users.keys_by_index(1, &7), vec![1, 2]);
+///
+/// users.remove(&1);
+/// assert!(users.get_by_unique(0, &100).is_none());
+/// assert_eq!(users.keys_by_index(1, &7), vec![2]);
+/// ```
+pub struct SIndexedBTreeMap<
+    K: StableType + AsFixedSizeBytes + Ord + Clone,
+    V: StableType + AsFixedSizeBytes + IndexedValue<I>,
+    I: StableType + AsFixedSizeBytes + Ord + Clone,
+> {
+    primary: SBTreeMap<K, V>,
+    unique: SBTreeMap<(u32, I), K>,
+    non_unique: SBTreeMap<(u32, I), SBTreeSet<K>>,
+}
+
+impl<
+        K: StableType + AsFixedSizeBytes + Ord + Clone,
+        V: StableType + AsFixedSizeBytes + IndexedValue<I>,
+        I: StableType + AsFixedSizeBytes + Ord + Clone,
+    > SIndexedBTreeMap<K, V, I>
+{
+    /// Creates a new [SIndexedBTreeMap]
+    ///
+    /// Does not allocate any heap or stable memory.
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            primary: SBTreeMap::new(),
+            unique: SBTreeMap::new(),
+            non_unique: SBTreeMap::new(),
+        }
+    }
+
+    /// Inserts a new entry, updating all secondary indexes, and returns the previous value by this key,
+    /// if there was one
+    ///
+    /// May allocate stable memory. If the canister is out of stable memory, or if a unique index key of
+    /// the value is already owned by another entry, returns [Err] with the entry and leaves the map
+    /// untouched.
+    ///
+    /// # Panics
+    /// Panics if [IndexedValue::index_keys] returns a wrong number of keys.
+    pub fn insert(&mut self, key: K, value: V) -> Result<Option<V>, IndexedInsertError<K, V>> {
+        let new_keys = value.index_keys();
+        assert_eq!(
+            new_keys.len(),
+            V::INDEXES.len(),
+            "Invalid number of index keys"
+        );
+
+        for (index, kind) in V::INDEXES.iter().enumerate() {
+            if *kind != IndexKind::Unique {
+                continue;
+            }
+
+            let occupied = self
+                .unique
+                .get(&(index as u32, new_keys[index].clone()))
+                .map(|owner| *owner != key)
+                .unwrap_or_default();
+
+            if occupied {
+                return Err(IndexedInsertError::UniqueViolation { index, key, value });
+            }
+        }
+
+        let old_keys = self.primary.get(&key).map(|it| it.index_keys());
+        let mut inserted = Vec::new();
+
+        for (index, kind) in V::INDEXES.iter().enumerate() {
+            if let Some(old_keys) = &old_keys {
+                if old_keys[index] == new_keys[index] {
+                    continue;
+                }
+            }
+
+            let index_key = (index as u32, new_keys[index].clone());
+            let res = match kind {
+                IndexKind::Unique => self.unique.insert(index_key, key.clone()).is_ok(),
+                IndexKind::NonUnique => self.insert_non_unique(index_key, key.clone()),
+            };
+
+            if !res {
+                self.remove_index_entries(&key, &new_keys, inserted);
+
+                return Err(IndexedInsertError::OutOfMemory(key, value));
+            }
+
+            inserted.push(index);
+        }
+
+        // the entry is replaced in place, so its old index entries are removed only after that
+        let key_copy = old_keys.as_ref().map(|_| key.clone());
+
+        match self.primary.insert(key, value) {
+            Ok(prev) => {
+                if let (Some(old_keys), Some(key)) = (old_keys, key_copy) {
+                    let outdated = (0..old_keys.len())
+                        .filter(|it| old_keys[*it] != new_keys[*it])
+                        .collect();
+
+                    self.remove_index_entries(&key, &old_keys, outdated);
+                }
+
+                Ok(prev)
+            }
+            Err((key, value)) => {
+                self.remove_index_entries(&key, &new_keys, inserted);
+
+                Err(IndexedInsertError::OutOfMemory(key, value))
+            }
+        }
+    }
+
+    /// Same as [SIndexedBTreeMap::insert], but returns [OutOfMemory] instead of the entry, so it can be
+    /// propagated with `?`
+    ///
+    /// A unique index violation is returned as the inner [Err] with the index number. Either way,
+    /// the entry gets dropped, if it can't be inserted.
+    ///
+    /// # Panics
+    /// Panics if [IndexedValue::index_keys] returns a wrong number of keys.
+    pub fn try_insert(
+        &mut self,
+        key: K,
+        value: V,
+    ) -> Result<Result<Option<V>, usize>, OutOfMemory> {
+        match self.insert(key, value) {
+            Ok(prev) => Ok(Ok(prev)),
+            Err(IndexedInsertError::UniqueViolation { index, .. }) => Ok(Err(index)),
+            Err(IndexedInsertError::OutOfMemory(..)) => Err(OutOfMemory),
+        }
+    }
+
+    /// Removes the entry by its key, updating all secondary indexes, and returns its value
+    ///
+    /// Never allocates.
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let value = self.primary.remove(key)?;
+        let keys = value.index_keys();
+
+        self.remove_index_entries(key, &keys, (0..keys.len()).collect());
+
+        Some(value)
+    }
+
+    /// Returns an immutable reference to the value by its key
+    #[inline]
+    pub fn get(&self, key: &K) -> Option<SRef<'_, V>> {
+        self.primary.get(key)
+    }
+
+    /// Returns `true` if there is an entry with this key
+    #[inline]
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.primary.contains_key(key)
+    }
+
+    /// Returns the key of the entry, which owns this key of a unique index
+    ///
+    /// # Panics
+    /// Panics if there is no unique index with this id.
+    pub fn key_by_unique(&self, index: usize, index_key: &I) -> Option<SRef<'_, K>> {
+        assert_eq!(
+            V::INDEXES.get(index),
+            Some(&IndexKind::Unique),
+            "Not a unique index"
+        );
+
+        self.unique.get(&(index as u32, index_key.clone()))
+    }
+
+    /// Returns an immutable reference to the value of the entry, which owns this key of a unique index
+    ///
+    /// # Panics
+    /// Panics if there is no unique index with this id.
+    #[inline]
+    pub fn get_by_unique(&self, index: usize, index_key: &I) -> Option<SRef<'_, V>> {
+        let key = self.key_by_unique(index, index_key)?;
+
+        self.primary.get(&*key)
+    }
+
+    /// Returns keys of all entries with this key of a non-unique index, in ascending order
+    ///
+    /// # Panics
+    /// Panics if there is no non-unique index with this id.
+    pub fn keys_by_index(&self, index: usize, index_key: &I) -> Vec<K> {
+        assert_eq!(
+            V::INDEXES.get(index),
+            Some(&IndexKind::NonUnique),
+            "Not a non-unique index"
+        );
+
+        self.non_unique
+            .get(&(index as u32, index_key.clone()))
+            .map(|set| set.iter().map(|it| K::clone(&it)).collect())
+            .unwrap_or_default()
+    }
+
+    /// Returns the number of entries with this key of a non-unique index
+    ///
+    /// # Panics
+    /// Panics if there is no non-unique index with this id.
+    pub fn count_by_index(&self, index: usize, index_key: &I) -> u64 {
+        assert_eq!(
+            V::INDEXES.get(index),
+            Some(&IndexKind::NonUnique),
+            "Not a non-unique index"
+        );
+
+        self.non_unique
+            .get(&(index as u32, index_key.clone()))
+            .map(|set| set.len())
+            .unwrap_or_default()
+    }
+
+    /// Returns an iterator over all entries in ascending order of their keys
+    #[inline]
+    pub fn iter(&self) -> SBTreeMapIter<'_, K, V> {
+        self.primary.iter()
+    }
+
+    /// Returns the number of entries in this [SIndexedBTreeMap]
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.primary.len()
+    }
+
+    /// Returns `true` if there are no entries in this [SIndexedBTreeMap]
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.primary.is_empty()
+    }
+
+    /// Removes all entries and index entries, releasing all occupied stable memory
+    #[inline]
+    pub fn clear(&mut self) {
+        self.primary.clear();
+        self.unique.clear();
+        self.non_unique.clear();
+    }
+
+    // returns `false` if the canister is out of stable memory
+    fn insert_non_unique(&mut self, index_key: (u32, I), key: K) -> bool {
+        if let Some(mut set) = self.non_unique.get_mut(&index_key) {
+            return set.insert(key).is_ok();
+        }
+
+        let mut set = SBTreeSet::new();
+        if set.insert(key).is_err() {
+            return false;
+        }
+
+        self.non_unique.insert(index_key, set).is_ok()
+    }
+
+    // never allocates
+    fn remove_index_entries(&mut self, key: &K, index_keys: &[I], indexes: Vec<usize>) {
+        for index in indexes {
+            let index_key = (index as u32, index_keys[index].clone());
+
+            match V::INDEXES[index] {
+                IndexKind::Unique => {
+                    self.unique.remove(&index_key);
+                }
+                IndexKind::NonUnique => {
+                    let is_empty = match self.non_unique.get_mut(&index_key) {
+                        Some(mut set) => {
+                            set.remove(key);
+                            set.is_empty()
+                        }
+                        None => continue,
+                    };
+
+                    if is_empty {
+                        self.non_unique.remove(&index_key);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<
+        K: StableType + AsFixedSizeBytes + Ord + Clone,
+        V: StableType + AsFixedSizeBytes + IndexedValue<I>,
+        I: StableType + AsFixedSizeBytes + Ord + Clone,
+    > Default for SIndexedBTreeMap<K, V, I>
+{
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<
+        K: StableType + AsFixedSizeBytes + Ord + Clone,
+        V: StableType + AsFixedSizeBytes + IndexedValue<I>,
+        I: StableType + AsFixedSizeBytes + Ord + Clone,
+    > AsFixedSizeBytes for SIndexedBTreeMap<K, V, I>
+{
+    const SIZE: usize = SBTreeMap::<u64, u64>::SIZE * 3;
+    type Buf = [u8; SBTreeMap::<u64, u64>::SIZE * 3];
+
+    fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+        let map_size = SBTreeMap::<u64, u64>::SIZE;
+
+        self.primary.as_fixed_size_bytes(&mut buf[0..map_size]);
+        self.unique
+            .as_fixed_size_bytes(&mut buf[map_size..(map_size * 2)]);
+        self.non_unique
+            .as_fixed_size_bytes(&mut buf[(map_size * 2)..Self::SIZE]);
+    }
+
+    fn from_fixed_size_bytes(buf: &[u8]) -> Self {
+        let map_size = SBTreeMap::<u64, u64>::SIZE;
+
+        Self {
+            primary: SBTreeMap::from_fixed_size_bytes(&buf[0..map_size]),
+            unique: SBTreeMap::from_fixed_size_bytes(&buf[map_size..(map_size * 2)]),
+            non_unique: SBTreeMap::from_fixed_size_bytes(&buf[(map_size * 2)..Self::SIZE]),
+        }
+    }
+}
+
+impl<
+        K: StableType + AsFixedSizeBytes + Ord + Clone,
+        V: StableType + AsFixedSizeBytes + IndexedValue<I>,
+        I: StableType + AsFixedSizeBytes + Ord + Clone,
+    > StableType for SIndexedBTreeMap<K, V, I>
+{
+    #[inline]
+    unsafe fn stable_drop_flag_off(&mut self) {
+        self.primary.stable_drop_flag_off();
+        self.unique.stable_drop_flag_off();
+        self.non_unique.stable_drop_flag_off();
+    }
+
+    #[inline]
+    unsafe fn stable_drop_flag_on(&mut self) {
+        self.primary.stable_drop_flag_on();
+        self.unique.stable_drop_flag_on();
+        self.non_unique.stable_drop_flag_on();
+    }
+}
+
+impl<
+        K: StableType + AsFixedSizeBytes + Ord + Clone + Debug,
+        V: StableType + AsFixedSizeBytes + IndexedValue<I> + Debug,
+        I: StableType + AsFixedSizeBytes + Ord + Clone,
+    > Debug for SIndexedBTreeMap<K, V, I>
+{
+    #[inline]
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.primary.fmt(f)
+    }
+}
+
+#[cfg(feature = "orphan_collector")]
+impl<
+        K: StableType + AsFixedSizeBytes + Ord + Clone + Trace,
+        V: StableType + AsFixedSizeBytes + IndexedValue<I> + Trace,
+        I: StableType + AsFixedSizeBytes + Ord + Clone + Trace,
+    > Trace for SIndexedBTreeMap<K, V, I>
+{
+    fn trace(&self, ptrs: &mut Vec<StablePtr>) {
+        self.primary.trace(ptrs);
+        self.unique.trace(ptrs);
+        self.non_unique.trace(ptrs);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::indexed_btree_map::{
+        IndexKind, IndexedInsertError, IndexedValue, SIndexedBTreeMap,
+    };
+    use crate::{
+        _debug_validate_allocator, get_allocated_size, init_allocator, retrieve_custom_data,
+        stable, stable_memory_init, stable_memory_post_upgrade, stable_memory_pre_upgrade,
+        store_custom_data, SBox,
+    };
+    use rand::rngs::ThreadRng;
+    use rand::{thread_rng, Rng};
+    use std::collections::BTreeMap;
+
+    // (unique, non-unique)
+    impl IndexedValue<u64> for (u64, u64) {
+        const INDEXES: &'static [IndexKind] = &[IndexKind::Unique, IndexKind::NonUnique];
+
+        fn index_keys(&self) -> Vec<u64> {
+            vec![self.0, self.1]
+        }
+    }
+
+    #[test]
+    fn works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut map = SIndexedBTreeMap::new();
+
+            assert!(map.insert(1u64, (10u64, 100u64)).unwrap().is_none());
+            assert!(map.insert(2, (20, 100)).unwrap().is_none());
+            assert!(map.insert(3, (30, 200)).unwrap().is_none());
+
+            match map.insert(4, (10, 300)) {
+                Err(IndexedInsertError::UniqueViolation { index, key, value }) => {
+                    assert_eq!(index, 0);
+                    assert_eq!(key, 4);
+                    assert_eq!(value, (10, 300));
+                }
+                _ => unreachable!(),
+            }
+
+            assert_eq!(map.len(), 3);
+            assert_eq!(*map.key_by_unique(0, &20).unwrap(), 2);
+            assert_eq!(*map.get_by_unique(0, &30).unwrap(), (30, 200));
+            assert_eq!(map.keys_by_index(1, &100), vec![1, 2]);
+            assert_eq!(map.count_by_index(1, &200), 1);
+
+            // re-inserting with the same unique key is fine
+            assert_eq!(map.insert(1, (10, 200)).unwrap(), Some((10, 100)));
+            assert_eq!(map.keys_by_index(1, &100), vec![2]);
+            assert_eq!(map.keys_by_index(1, &200), vec![1, 3]);
+
+            assert_eq!(map.insert(1, (40, 200)).unwrap(), Some((10, 200)));
+            assert!(map.get_by_unique(0, &10).is_none());
+            assert_eq!(*map.key_by_unique(0, &40).unwrap(), 1);
+
+            store_custom_data(0, SBox::new(map).unwrap());
+            let mut map = retrieve_custom_data::<SIndexedBTreeMap<u64, (u64, u64), u64>>(0)
+                .unwrap()
+                .into_inner();
+
+            assert_eq!(map.remove(&2), Some((20, 100)));
+            assert!(map.remove(&2).is_none());
+            assert!(map.get_by_unique(0, &20).is_none());
+            assert!(map.keys_by_index(1, &100).is_empty());
+
+            map.insert(2, (20, 100)).unwrap();
+            assert_eq!(map.len(), 3);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn wrong_index_kind_panics() {
+        stable::clear();
+        stable_memory_init();
+
+        let map = SIndexedBTreeMap::<u64, (u64, u64), u64>::new();
+        map.keys_by_index(0, &10);
+    }
+
+    enum Action {
+        Insert,
+        Remove,
+        Clear,
+        CanisterUpgrade,
+    }
+
+    struct Fuzzer {
+        state: Option<SIndexedBTreeMap<u64, (u64, u64), u64>>,
+        example: BTreeMap<u64, (u64, u64)>,
+        rng: ThreadRng,
+        log: Vec<Action>,
+    }
+
+    impl Fuzzer {
+        fn new() -> Self {
+            Self {
+                state: Some(SIndexedBTreeMap::default()),
+                example: BTreeMap::default(),
+                rng: thread_rng(),
+                log: Vec::default(),
+            }
+        }
+
+        fn it(&mut self) -> &mut SIndexedBTreeMap<u64, (u64, u64), u64> {
+            self.state.as_mut().unwrap()
+        }
+
+        fn next(&mut self) {
+            let action = self.rng.gen_range(0..101);
+
+            match action {
+                // INSERT ~60%
+                0..=59 => {
+                    let key = self.rng.gen_range(0..200);
+                    let value = (self.rng.gen_range(0..400), self.rng.gen_range(0..20));
+
+                    let violates = self
+                        .example
+                        .iter()
+                        .any(|(k, v)| *k != key && v.0 == value.0);
+
+                    match self.it().insert(key, value) {
+                        Ok(prev) => {
+                            assert!(!violates);
+                            assert_eq!(prev, self.example.insert(key, value));
+                        }
+                        Err(IndexedInsertError::UniqueViolation { .. }) => {
+                            assert!(violates);
+                        }
+                        Err(IndexedInsertError::OutOfMemory(..)) => {}
+                    }
+
+                    self.log.push(Action::Insert);
+                }
+                // REMOVE
+                60..=89 => {
+                    let key = self.rng.gen_range(0..200);
+
+                    assert_eq!(self.it().remove(&key), self.example.remove(&key));
+
+                    self.log.push(Action::Remove);
+                }
+                // CLEAR
+                90..=90 => {
+                    self.it().clear();
+                    self.example.clear();
+
+                    self.log.push(Action::Clear);
+                }
+                // CANISTER UPGRADE
+                _ => match SBox::new(self.state.take().unwrap()) {
+                    Ok(data) => {
+                        store_custom_data(1, data);
+
+                        if stable_memory_pre_upgrade().is_ok() {
+                            stable_memory_post_upgrade();
+                        }
+
+                        self.state =
+                            retrieve_custom_data::<SIndexedBTreeMap<u64, (u64, u64), u64>>(1)
+                                .map(|it| it.into_inner());
+
+                        self.log.push(Action::CanisterUpgrade);
+                    }
+                    Err(map) => {
+                        self.state = Some(map);
+                    }
+                },
+            }
+
+            _debug_validate_allocator();
+            assert_eq!(self.it().len(), self.example.len() as u64);
+
+            let probe = self.rng.gen_range(0..400);
+            let expected = self
+                .example
+                .iter()
+                .find(|(_, v)| v.0 == probe)
+                .map(|(k, _)| *k);
+            assert_eq!(self.it().key_by_unique(0, &probe).map(|it| *it), expected);
+
+            let probe = self.rng.gen_range(0..20);
+            let expected: Vec<_> = self
+                .example
+                .iter()
+                .filter(|(_, v)| v.1 == probe)
+                .map(|(k, _)| *k)
+                .collect();
+            assert_eq!(self.it().keys_by_index(1, &probe), expected);
+        }
+    }
+
+    #[test]
+    fn fuzzer_works_fine() {
+        stable::clear();
+        init_allocator(0);
+
+        {
+            let mut fuzzer = Fuzzer::new();
+
+            for _ in 0..10_000 {
+                fuzzer.next();
+            }
+        }
+
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn fuzzer_works_fine_limited_memory() {
+        stable::clear();
+        init_allocator(10);
+
+        {
+            let mut fuzzer = Fuzzer::new();
+
+            for _ in 0..10_000 {
+                fuzzer.next();
+            }
+        }
+
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
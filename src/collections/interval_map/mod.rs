@@ -0,0 +1,518 @@
+use crate::collections::btree_map::internal_node::InternalBTreeNode;
+use crate::collections::btree_map::iter::SBTreeMapIter;
+use crate::collections::btree_map::leaf_node::LeafBTreeNode;
+use crate::collections::btree_map::{BTreeNode, LeveledList, SBTreeMap};
+use crate::encoding::AsFixedSizeBytes;
+#[cfg(feature = "orphan_collector")]
+use crate::mem::orphan_collector::Trace;
+#[cfg(feature = "orphan_collector")]
+use crate::mem::StablePtr;
+use crate::primitive::s_ref::SRef;
+use crate::primitive::s_ref_mut::SRefMut;
+use crate::primitive::StableType;
+use crate::utils::certification::{Hash, EMPTY_HASH};
+use crate::OutOfMemory;
+use std::fmt::{Debug, Formatter};
+use std::ops::{Bound, Range};
+
+/// Interval map on top of [SBTreeMap], augmented with max-endpoints
+///
+/// Intervals are half-open (`start..end`) and stored in ascending order of `(start, end)`. Each node
+/// of the underlying B-tree additionally stores the maximum `end` of all intervals below it (the
+/// space, which [SCertifiedBTreeMap](crate::collections::SCertifiedBTreeMap) uses for hashes, is reused
+/// for that). This allows stabbing and overlap queries to skip whole subtrees, instead of scanning
+/// every interval - these queries take O(logN + M), where `M` is the number of matching intervals.
+///
+/// Two intervals with exactly the same bounds are treated as the same key - inserting the second one
+/// replaces the value of the first one.
+///
+/// `K` has to be [Copy] and its [AsFixedSizeBytes::SIZE] should be no bigger than `32` bytes (all
+/// numeric primitives qualify). `V` has to implement [StableType] and [AsFixedSizeBytes]. [SIntervalMap]
+/// also implements both these traits, so you can nest it into other stable structures.
+///
+/// # Examples
+/// ```rust
+/// # use ic_stable_memory::collections::SIntervalMap;
+/// # use ic_stable_memory::stable_memory_init;
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// let mut reservations = SIntervalMap::new();
+///
+/// reservations.insert(10u64..20, 1u64).expect("Out of memory");
+/// reservations.insert(15u64..30, 2u64).expect("Out of memory");
+/// reservations.insert(40u64..50, 3u64).expect("Out of memory");
+///
+/// let at_17: Vec<_> = reservations.stab(&17).into_iter().map(|(_, v)| *v).collect();
+/// assert_eq!(at_17, vec![1, 2]);
+///
+/// let overlap: Vec<_> = reservations.overlapping(&(25..45)).into_iter().map(|(r, _)| r).collect();
+/// assert_eq!(overlap, vec![15..30, 40..50]);
+/// ```
+pub struct SIntervalMap<
+    K: StableType + AsFixedSizeBytes + Ord + Copy,
+    V: StableType + AsFixedSizeBytes,
+> {
+    inner: SBTreeMap<(K, K), V>,
+    modified: LeveledList,
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord + Copy, V: StableType + AsFixedSizeBytes>
+    SIntervalMap<K, V>
+{
+    /// Creates a new [SIntervalMap]
+    ///
+    /// Allocates a small amount of heap memory.
+    ///
+    /// # Panics
+    /// Panics if `K` is bigger than `32` bytes.
+    #[inline]
+    pub fn new() -> Self {
+        assert!(K::SIZE <= EMPTY_HASH.len(), "Interval bound type is too big");
+
+        Self {
+            inner: SBTreeMap::new_certified(),
+            modified: LeveledList::new(),
+        }
+    }
+
+    /// Inserts the provided interval-value pair into this [SIntervalMap]
+    ///
+    /// May allocate stable and heap memory. If your canister is out of stable memory, will return
+    /// [Err] with the interval-value pair that was about to get inserted.
+    ///
+    /// If the insertion is successful, returns [Option] with a value, that was previously stored
+    /// under exactly the same interval.
+    ///
+    /// # Panics
+    /// Panics if the interval is empty (`start >= end`).
+    pub fn insert(&mut self, range: Range<K>, value: V) -> Result<Option<V>, (Range<K>, V)> {
+        assert!(range.start < range.end, "Empty interval");
+
+        let res = self
+            .inner
+            ._insert((range.start, range.end), value, &mut self.modified)
+            .map_err(|((start, end), value)| (start..end, value));
+
+        self.commit();
+
+        res
+    }
+
+    /// Same as [SIntervalMap::insert], but returns [OutOfMemory] instead of the interval-value pair
+    ///
+    /// Useful for propagating the error with `?`. The pair gets dropped, if it can't be inserted.
+    ///
+    /// # Panics
+    /// Panics if the interval is empty (`start >= end`).
+    #[inline]
+    pub fn try_insert(&mut self, range: Range<K>, value: V) -> Result<Option<V>, OutOfMemory> {
+        self.insert(range, value).map_err(|_| OutOfMemory)
+    }
+
+    /// Removes the interval with exactly the same bounds, returning its value
+    pub fn remove(&mut self, range: &Range<K>) -> Option<V> {
+        let res = self
+            .inner
+            ._remove(&(range.start, range.end), &mut self.modified);
+
+        self.commit();
+
+        res
+    }
+
+    /// Returns an immutable reference to the value stored by the interval with exactly the same bounds
+    #[inline]
+    pub fn get(&self, range: &Range<K>) -> Option<SRef<'_, V>> {
+        self.inner.get(&(range.start, range.end))
+    }
+
+    /// Returns a mutable reference to the value stored by the interval with exactly the same bounds
+    #[inline]
+    pub fn get_mut(&mut self, range: &Range<K>) -> Option<SRefMut<'_, V>> {
+        self.inner.get_mut(&(range.start, range.end))
+    }
+
+    /// Returns `true` if there is an interval with exactly the same bounds
+    #[inline]
+    pub fn contains(&self, range: &Range<K>) -> bool {
+        self.inner.contains_key(&(range.start, range.end))
+    }
+
+    /// Returns all intervals (with their values), which contain the provided point, in ascending order
+    pub fn stab(&self, point: &K) -> Vec<(Range<K>, SRef<'_, V>)> {
+        let mut result = Vec::new();
+
+        if let Some(root) = self.inner.get_root() {
+            Self::collect(root, Bound::Included(point), point, &mut result);
+        }
+
+        result
+    }
+
+    /// Returns all intervals (with their values), which overlap with the provided one, in ascending order
+    pub fn overlapping(&self, range: &Range<K>) -> Vec<(Range<K>, SRef<'_, V>)> {
+        let mut result = Vec::new();
+
+        if range.start >= range.end {
+            return result;
+        }
+
+        if let Some(root) = self.inner.get_root() {
+            Self::collect(root, Bound::Excluded(&range.end), &range.start, &mut result);
+        }
+
+        result
+    }
+
+    /// Returns an iterator over all intervals (as `(start, end)` pairs) and their values, in ascending order
+    #[inline]
+    pub fn iter(&self) -> SBTreeMapIter<'_, (K, K), V> {
+        self.inner.iter()
+    }
+
+    /// Returns the number of intervals in this [SIntervalMap]
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.inner.len()
+    }
+
+    /// Returns `true` if there are no intervals in this [SIntervalMap]
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    /// Removes all intervals from this [SIntervalMap], releasing all occupied stable memory
+    #[inline]
+    pub fn clear(&mut self) {
+        self.modified = LeveledList::new();
+        self.inner.clear();
+    }
+
+    // collects all intervals with `start` below the `upper` bound and `end` strictly greater than `lower`
+    fn collect<'a>(
+        node: BTreeNode<(K, K), V>,
+        upper: Bound<&K>,
+        lower: &K,
+        result: &mut Vec<(Range<K>, SRef<'a, V>)>,
+    ) {
+        match node {
+            BTreeNode::Internal(n) => {
+                let len = n.read_len();
+
+                for i in 0..(len + 1) {
+                    // all intervals of the i-th child start at least at the (i-1)-th separator
+                    if i > 0 && !is_below(&n.read_key_as_reference(i - 1).0, upper) {
+                        break;
+                    }
+
+                    let child = n.read_child::<V>(i);
+                    if child.read_max_end() <= *lower {
+                        continue;
+                    }
+
+                    Self::collect(child, upper, lower, result);
+                }
+            }
+            BTreeNode::Leaf(n) => {
+                let len = n.read_len();
+
+                for i in 0..len {
+                    let (start, end) = n.read_key_as_reference(i);
+
+                    if !is_below(&start, upper) {
+                        break;
+                    }
+
+                    if end > *lower {
+                        result.push((start..end, n.get_value(i)));
+                    }
+                }
+            }
+        }
+    }
+
+    // recalculates max-endpoints of all modified nodes, from leaves to the root
+    fn commit(&mut self) {
+        while let Some(ptr) = self.modified.pop() {
+            let mut node = BTreeNode::<(K, K), V>::from_ptr(ptr);
+            match &mut node {
+                BTreeNode::Internal(n) => n.commit_max_end::<V>(),
+                BTreeNode::Leaf(n) => n.commit_max_end(),
+            };
+        }
+    }
+}
+
+#[inline]
+fn is_below<K: Ord>(start: &K, upper: Bound<&K>) -> bool {
+    match upper {
+        Bound::Included(p) => start <= p,
+        Bound::Excluded(p) => start < p,
+        Bound::Unbounded => true,
+    }
+}
+
+#[inline]
+fn encode_max_end<K: AsFixedSizeBytes>(max_end: &K) -> Hash {
+    let mut buf = EMPTY_HASH;
+    max_end.as_fixed_size_bytes(&mut buf[..K::SIZE]);
+
+    buf
+}
+
+#[inline]
+fn decode_max_end<K: AsFixedSizeBytes>(buf: &Hash) -> K {
+    K::from_fixed_size_bytes(&buf[..K::SIZE])
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord + Copy, V: StableType + AsFixedSizeBytes>
+    BTreeNode<(K, K), V>
+{
+    #[inline]
+    fn read_max_end(&self) -> K {
+        match self {
+            BTreeNode::Internal(n) => decode_max_end(&n.read_root_hash(true)),
+            BTreeNode::Leaf(n) => decode_max_end(&n.read_root_hash(true)),
+        }
+    }
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord + Copy, V: StableType + AsFixedSizeBytes>
+    LeafBTreeNode<(K, K), V>
+{
+    fn commit_max_end(&mut self) {
+        let len = self.read_len();
+
+        let max_end = (0..len).map(|i| self.read_key_as_reference(i).1).max();
+
+        // only an empty root can have no entries
+        if let Some(max_end) = max_end {
+            self.write_root_hash(&encode_max_end(&max_end), true);
+        }
+    }
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord + Copy> InternalBTreeNode<(K, K)> {
+    #[inline]
+    fn read_child<V: StableType + AsFixedSizeBytes>(&self, idx: usize) -> BTreeNode<(K, K), V> {
+        BTreeNode::from_ptr(u64::from_fixed_size_bytes(&self.read_child_ptr_buf(idx)))
+    }
+
+    fn commit_max_end<V: StableType + AsFixedSizeBytes>(&mut self) {
+        let len = self.read_len();
+
+        let max_end = (0..(len + 1))
+            .map(|i| self.read_child::<V>(i).read_max_end())
+            .max()
+            .unwrap();
+
+        self.write_root_hash(&encode_max_end(&max_end), true);
+    }
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord + Copy, V: StableType + AsFixedSizeBytes> Default
+    for SIntervalMap<K, V>
+{
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord + Copy, V: StableType + AsFixedSizeBytes>
+    AsFixedSizeBytes for SIntervalMap<K, V>
+{
+    const SIZE: usize = SBTreeMap::<(K, K), V>::SIZE;
+    type Buf = <SBTreeMap<(K, K), V> as AsFixedSizeBytes>::Buf;
+
+    #[inline]
+    fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+        self.inner.as_fixed_size_bytes(buf)
+    }
+
+    fn from_fixed_size_bytes(buf: &[u8]) -> Self {
+        let mut inner = SBTreeMap::<(K, K), V>::from_fixed_size_bytes(buf);
+        inner.set_certified(true);
+
+        Self {
+            inner,
+            modified: LeveledList::new(),
+        }
+    }
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord + Copy, V: StableType + AsFixedSizeBytes> StableType
+    for SIntervalMap<K, V>
+{
+    #[inline]
+    unsafe fn stable_drop_flag_on(&mut self) {
+        self.inner.stable_drop_flag_on();
+    }
+
+    #[inline]
+    unsafe fn stable_drop_flag_off(&mut self) {
+        self.inner.stable_drop_flag_off();
+    }
+}
+
+impl<
+        K: StableType + AsFixedSizeBytes + Ord + Copy + Debug,
+        V: StableType + AsFixedSizeBytes + Debug,
+    > Debug for SIntervalMap<K, V>
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("{")?;
+        for (idx, (k, v)) in self.iter().enumerate() {
+            let (start, end) = *k;
+            (start..end).fmt(f)?;
+            f.write_str(": ")?;
+            v.fmt(f)?;
+
+            if idx < (self.len() - 1) as usize {
+                f.write_str(", ")?;
+            }
+        }
+        f.write_str("}")
+    }
+}
+
+#[cfg(feature = "orphan_collector")]
+impl<
+        K: StableType + AsFixedSizeBytes + Ord + Copy + Trace,
+        V: StableType + AsFixedSizeBytes + Trace,
+    > Trace for SIntervalMap<K, V>
+{
+    #[inline]
+    fn trace(&self, ptrs: &mut Vec<StablePtr>) {
+        self.inner.trace(ptrs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::interval_map::SIntervalMap;
+    use crate::{
+        _debug_validate_allocator, get_allocated_size, retrieve_custom_data, stable,
+        stable_memory_init, store_custom_data, SBox,
+    };
+    use rand::{thread_rng, Rng};
+    use std::ops::Range;
+
+    #[test]
+    fn it_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut map = SIntervalMap::new();
+
+            assert!(map.stab(&10).is_empty());
+
+            assert!(map.insert(10u64..20, 1u64).unwrap().is_none());
+            assert!(map.insert(15u64..30, 2u64).unwrap().is_none());
+            assert!(map.insert(0u64..5, 3u64).unwrap().is_none());
+            assert_eq!(map.insert(10u64..20, 4u64).unwrap(), Some(1));
+
+            assert_eq!(map.len(), 3);
+            assert_eq!(*map.get(&(10..20)).unwrap(), 4);
+            assert!(map.contains(&(0..5)));
+            assert!(!map.contains(&(0..6)));
+
+            let res: Vec<_> = map.stab(&15).into_iter().map(|(r, v)| (r, *v)).collect();
+            assert_eq!(res, vec![(10..20, 4), (15..30, 2)]);
+
+            assert!(map.stab(&5).is_empty());
+            assert!(map.stab(&30).is_empty());
+
+            let res: Vec<_> = map.overlapping(&(4..11)).into_iter().map(|(r, _)| r).collect();
+            assert_eq!(res, vec![0..5, 10..20]);
+
+            *map.get_mut(&(0..5)).unwrap() = 10;
+            assert_eq!(map.remove(&(0..5)), Some(10));
+            assert!(map.remove(&(0..5)).is_none());
+
+            store_custom_data(0, SBox::new(map).unwrap());
+            let mut map = retrieve_custom_data::<SIntervalMap<u64, u64>>(0)
+                .unwrap()
+                .into_inner();
+
+            map.insert(25..26, 5).unwrap();
+
+            let res: Vec<_> = map.stab(&25).into_iter().map(|(_, v)| *v).collect();
+            assert_eq!(res, vec![2, 5]);
+
+            map.clear();
+            assert!(map.is_empty());
+            assert!(map.stab(&15).is_empty());
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    fn brute_force(intervals: &[(Range<u64>, u64)], query: &Range<u64>) -> Vec<(Range<u64>, u64)> {
+        let mut res: Vec<_> = intervals
+            .iter()
+            .filter(|(r, _)| r.start < query.end && r.end > query.start)
+            .cloned()
+            .collect();
+
+        res.sort_by_key(|(r, _)| (r.start, r.end));
+        res
+    }
+
+    #[test]
+    fn random_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut rng = thread_rng();
+            let mut map = SIntervalMap::<u64, u64>::new();
+            let mut example = Vec::<(Range<u64>, u64)>::new();
+
+            for i in 0..3000u64 {
+                if example.is_empty() || rng.gen_bool(0.7) {
+                    let start = rng.gen_range(0..10_000u64);
+                    let end = start + rng.gen_range(1..500u64);
+
+                    let prev = map.insert(start..end, i).unwrap();
+                    match example.iter_mut().find(|(r, _)| *r == (start..end)) {
+                        Some(entry) => {
+                            assert_eq!(prev, Some(entry.1));
+                            entry.1 = i;
+                        }
+                        None => {
+                            assert!(prev.is_none());
+                            example.push((start..end, i));
+                        }
+                    }
+                } else {
+                    let idx = rng.gen_range(0..example.len());
+                    let (range, value) = example.swap_remove(idx);
+
+                    assert_eq!(map.remove(&range), Some(value));
+                }
+
+                let point = rng.gen_range(0..10_500u64);
+                let stabbed: Vec<_> = map.stab(&point).into_iter().map(|(r, v)| (r, *v)).collect();
+                assert_eq!(stabbed, brute_force(&example, &(point..point + 1)));
+
+                let start = rng.gen_range(0..10_500u64);
+                let query = start..(start + rng.gen_range(1..1000u64));
+                let overlapping: Vec<_> = map
+                    .overlapping(&query)
+                    .into_iter()
+                    .map(|(r, v)| (r, *v))
+                    .collect();
+                assert_eq!(overlapping, brute_force(&example, &query));
+            }
+
+            assert_eq!(map.len(), example.len() as u64);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
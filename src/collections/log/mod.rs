@@ -1,6 +1,9 @@
 use crate::collections::log::iter::SLogIter;
 use crate::encoding::AsFixedSizeBytes;
 use crate::mem::allocator::EMPTY_PTR;
+#[cfg(feature = "orphan_collector")]
+use crate::mem::orphan_collector::{memory_usage, MemoryUsage, Trace};
+use crate::mem::typed_slice::{ArrayField, Field, TypedSlice};
 use crate::mem::StablePtr;
 use crate::primitive::s_ref::SRef;
 use crate::primitive::s_ref_mut::SRefMut;
@@ -93,6 +96,14 @@ impl<T: StableType + AsFixedSizeBytes> SLog<T> {
         }
     }
 
+    /// Same as [SLog::push], but returns [OutOfMemory] instead of the element
+    ///
+    /// Useful for propagating the error with `?`. The element gets dropped, if it can't be inserted.
+    #[inline]
+    pub fn try_push(&mut self, it: T) -> Result<(), OutOfMemory> {
+        self.push(it).map_err(|_| OutOfMemory)
+    }
+
     /// Removes an element from the end of the [SLog]
     ///
     /// If the [SLog] is empty, returns [None]. If it was the last element of the last `Sector` and
@@ -364,18 +375,19 @@ impl<T: StableType + AsFixedSizeBytes> Default for SLog<T> {
     }
 }
 
-const PREV_OFFSET: u64 = 0;
-const NEXT_OFFSET: u64 = PREV_OFFSET + u64::SIZE as u64;
-const CAPACITY_OFFSET: u64 = NEXT_OFFSET + u64::SIZE as u64;
-const ELEMENTS_OFFSET: u64 = CAPACITY_OFFSET + u64::SIZE as u64;
+const PREV: Field<StablePtr> = Field::first();
+const NEXT: Field<StablePtr> = PREV.next();
+const CAPACITY: Field<u64> = NEXT.next();
 
-struct Sector<T>(u64, PhantomData<T>);
+struct Sector<T>(TypedSlice<Sector<T>>);
 
 impl<T: StableType + AsFixedSizeBytes> Sector<T> {
+    const ELEMENTS: ArrayField<T> = CAPACITY.next_array();
+
     fn new(cap: u64, prev: StablePtr) -> Result<Self, OutOfMemory> {
-        let slice = unsafe { allocate(u64::SIZE as u64 * 3 + cap * T::SIZE as u64)? };
+        let slice = unsafe { allocate(Self::ELEMENTS.end(cap))? };
 
-        let mut it = Self(slice.as_ptr(), PhantomData::default());
+        let mut it = Self::from_ptr(slice.as_ptr());
         it.write_prev_ptr(prev);
         it.write_next_ptr(EMPTY_PTR);
         it.write_capacity(cap);
@@ -384,53 +396,54 @@ impl<T: StableType + AsFixedSizeBytes> Sector<T> {
     }
 
     fn destroy(self) {
-        let slice = unsafe { SSlice::from_ptr(self.0).unwrap() };
+        let slice = unsafe { SSlice::from_ptr(self.as_ptr()).unwrap() };
         deallocate(slice);
     }
 
     #[inline]
     fn as_ptr(&self) -> StablePtr {
-        self.0
+        self.0.as_ptr()
     }
 
     #[inline]
     fn from_ptr(ptr: u64) -> Self {
-        Self(ptr, PhantomData::default())
+        Self(unsafe { TypedSlice::from_ptr(ptr) })
     }
 
     #[inline]
     fn read_prev_ptr(&self) -> StablePtr {
-        unsafe { crate::mem::read_fixed_for_reference(SSlice::_offset(self.0, PREV_OFFSET)) }
+        self.0.read_field(PREV)
     }
 
     #[inline]
-    fn write_prev_ptr(&mut self, mut ptr: StablePtr) {
-        unsafe { crate::mem::write_fixed(SSlice::_offset(self.0, PREV_OFFSET), &mut ptr) }
+    fn write_prev_ptr(&mut self, ptr: StablePtr) {
+        self.0.write_field(PREV, ptr)
     }
 
     #[inline]
     fn read_next_ptr(&self) -> StablePtr {
-        unsafe { crate::mem::read_fixed_for_reference(SSlice::_offset(self.0, NEXT_OFFSET)) }
+        self.0.read_field(NEXT)
     }
 
     #[inline]
-    fn write_next_ptr(&mut self, mut ptr: StablePtr) {
-        unsafe { crate::mem::write_fixed(SSlice::_offset(self.0, NEXT_OFFSET), &mut ptr) }
+    fn write_next_ptr(&mut self, ptr: StablePtr) {
+        self.0.write_field(NEXT, ptr)
     }
 
     #[inline]
     fn read_capacity(&self) -> u64 {
-        unsafe { crate::mem::read_fixed_for_reference(SSlice::_offset(self.0, CAPACITY_OFFSET)) }
+        self.0.read_field(CAPACITY)
     }
 
     #[inline]
-    fn write_capacity(&mut self, mut cap: u64) {
-        unsafe { crate::mem::write_fixed(SSlice::_offset(self.0, CAPACITY_OFFSET), &mut cap) }
+    fn write_capacity(&mut self, cap: u64) {
+        self.0.write_field(CAPACITY, cap)
     }
 
+    // elements are addressed by their offset in bytes
     #[inline]
     fn get_element_ptr(&self, offset: u64) -> u64 {
-        SSlice::_offset(self.0, ELEMENTS_OFFSET + offset)
+        SSlice::_offset(self.as_ptr(), Self::ELEMENTS.offset() + offset)
     }
 
     #[inline]
@@ -595,6 +608,36 @@ impl<T: StableType + AsFixedSizeBytes> Drop for SLog<T> {
     }
 }
 
+#[cfg(feature = "orphan_collector")]
+impl<T: StableType + AsFixedSizeBytes + Trace> SLog<T> {
+    /// Returns the size of stable memory, owned by this collection
+    ///
+    /// Includes its sectors, plus the memory owned by its entries, see [MemoryUsage].
+    ///
+    /// Only available with the `orphan_collector` feature.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        memory_usage(self, self.len())
+    }
+}
+
+#[cfg(feature = "orphan_collector")]
+impl<T: StableType + AsFixedSizeBytes + Trace> Trace for SLog<T> {
+    fn trace(&self, ptrs: &mut Vec<StablePtr>) {
+        let mut sector_ptr = self.first_sector_ptr;
+
+        while sector_ptr != EMPTY_PTR {
+            ptrs.push(sector_ptr);
+            sector_ptr = Sector::<T>::from_ptr(sector_ptr).read_next_ptr();
+        }
+
+        if T::OWNS_MEMORY {
+            for elem in self.rev_iter() {
+                elem.trace(ptrs);
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::collections::log::SLog;
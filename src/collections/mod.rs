@@ -7,19 +7,55 @@ pub mod certified_btree_map;
 #[doc(hidden)]
 pub mod certified_btree_set;
 #[doc(hidden)]
+pub mod counted_btree_map;
+#[doc(hidden)]
+pub mod dyn_vec;
+#[doc(hidden)]
+pub mod graph;
+#[doc(hidden)]
 pub mod hash_map;
 #[doc(hidden)]
 pub mod hash_set;
 #[doc(hidden)]
+pub mod indexed_btree_map;
+#[doc(hidden)]
+pub mod interval_map;
+#[doc(hidden)]
 pub mod log;
 #[doc(hidden)]
+pub mod priority_queue;
+#[doc(hidden)]
+pub mod radix_tree;
+#[doc(hidden)]
+pub mod roaring_bitmap;
+#[doc(hidden)]
+pub mod time_series;
+#[doc(hidden)]
+pub mod ttl_map;
+#[doc(hidden)]
 pub mod vec;
+#[doc(hidden)]
+pub mod versioned_map;
 
+pub use btree_map::prefix::KeyPrefix;
+pub use btree_map::shadow::{SBTreeMapShadow, ShadowConflict};
 pub use btree_map::SBTreeMap;
 pub use btree_set::SBTreeSet;
 pub use certified_btree_map::SCertifiedBTreeMap;
 pub use certified_btree_set::SCertifiedBTreeSet;
+pub use counted_btree_map::SCountedBTreeMap;
+pub use dyn_vec::SDynVec;
+pub use graph::SGraph;
 pub use hash_map::SHashMap;
 pub use hash_set::SHashSet;
+pub use indexed_btree_map::{IndexKind, IndexedInsertError, IndexedValue, SIndexedBTreeMap};
+pub use interval_map::SIntervalMap;
 pub use log::SLog;
+pub use priority_queue::SPriorityQueue;
+pub use radix_tree::SRadixTree;
+pub use roaring_bitmap::SRoaringBitmap;
+pub use time_series::STimeSeries;
+pub use ttl_map::STtlMap;
 pub use vec::SVec;
+pub use versioned_map::snapshot::SVersionedMapSnapshot;
+pub use versioned_map::SVersionedMap;
@@ -0,0 +1,576 @@
+use crate::collections::hash_map::SHashMap;
+use crate::collections::vec::iter::SVecIter;
+use crate::collections::vec::SVec;
+use crate::encoding::AsFixedSizeBytes;
+#[cfg(feature = "orphan_collector")]
+use crate::mem::orphan_collector::Trace;
+use crate::mem::StablePtr;
+use crate::primitive::s_ref::SRef;
+use crate::primitive::StableType;
+use crate::{OutOfMemory, SSlice};
+use std::fmt::{Debug, Formatter};
+use std::hash::Hash;
+
+/// Indexed priority queue (min-heap), which allows changing priorities and removing arbitrary keys
+///
+/// Entries are stored in a binary heap on top of an [SVec], ordered by their priorities - the entry
+/// with the **smallest** priority is always on top, which fits scheduling by deadlines or timestamps.
+/// Additionally, an [SHashMap] keeps the position of each key inside the heap, so
+/// [SPriorityQueue::change_priority] and [SPriorityQueue::remove] take O(logN), instead of a linear
+/// scan, which a plain binary heap requires.
+///
+/// Each key can only be present once. Keys are stored twice (in the heap and in the position index),
+/// this is why `K` has to implement [Clone] (which also means that keys can't own stable memory).
+/// `K` and `P` have to implement [StableType] and [AsFixedSizeBytes]. [SPriorityQueue] also
+/// implements these traits, so you can nest it into other stable structures.
+///
+/// # Examples
+/// ```rust
+/// # use ic_stable_memory::collections::SPriorityQueue;
+/// # use ic_stable_memory::stable_memory_init;
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// let mut tasks = SPriorityQueue::new();
+///
+/// tasks.push(1u64, 300u64).expect("Out of memory");
+/// tasks.push(2u64, 100u64).expect("Out of memory");
+/// tasks.push(3u64, 200u64).expect("Out of memory");
+///
+/// // task 3 got rescheduled
+/// tasks.change_priority(&3, 50);
+///
+/// assert_eq!(tasks.pop(), Some((3, 50)));
+/// assert_eq!(tasks.pop(), Some((2, 100)));
+/// ```
+pub struct SPriorityQueue<
+    K: StableType + AsFixedSizeBytes + Hash + Eq + Clone,
+    P: StableType + AsFixedSizeBytes + Ord,
+> {
+    heap: SVec<(P, K)>,
+    positions: SHashMap<K, usize>,
+}
+
+impl<
+        K: StableType + AsFixedSizeBytes + Hash + Eq + Clone,
+        P: StableType + AsFixedSizeBytes + Ord,
+    > SPriorityQueue<K, P>
+{
+    /// Creates a new [SPriorityQueue]
+    ///
+    /// Does not allocate any heap or stable memory.
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            heap: SVec::new(),
+            positions: SHashMap::new(),
+        }
+    }
+
+    /// Inserts a key with the provided priority
+    ///
+    /// If the key is already present, only updates its priority and returns the previous one (see
+    /// [SPriorityQueue::change_priority]). May allocate stable memory. If the canister is out of stable
+    /// memory, returns [Err] with the key and the priority that were about to get inserted.
+    pub fn push(&mut self, key: K, priority: P) -> Result<Option<P>, (K, P)> {
+        if self.positions.contains_key(&key) {
+            return Ok(self.change_priority(&key, priority));
+        }
+
+        let idx = self.heap.len();
+
+        if let Err((key, _)) = self.positions.insert(key.clone(), idx) {
+            return Err((key, priority));
+        }
+
+        if let Err((priority, key)) = self.heap.push((priority, key)) {
+            self.positions.remove(&key);
+
+            return Err((key, priority));
+        }
+
+        self.sift_up(idx);
+
+        Ok(None)
+    }
+
+    /// Same as [SPriorityQueue::push], but returns [OutOfMemory] instead of the key and the priority
+    ///
+    /// Useful for propagating the error with `?`. The key gets dropped, if it can't be inserted.
+    #[inline]
+    pub fn try_push(&mut self, key: K, priority: P) -> Result<Option<P>, OutOfMemory> {
+        self.push(key, priority).map_err(|_| OutOfMemory)
+    }
+
+    /// Removes the key with the smallest priority, returning it together with its priority
+    pub fn pop(&mut self) -> Option<(K, P)> {
+        if self.heap.is_empty() {
+            return None;
+        }
+
+        self.take(0)
+    }
+
+    /// Returns the key with the smallest priority and its priority, without removing it
+    pub fn peek(&self) -> Option<(SRef<'_, K>, SRef<'_, P>)> {
+        let ptr = self.heap.get_element_ptr(0)?;
+
+        unsafe { Some((Self::key_ref(ptr), SRef::new(ptr))) }
+    }
+
+    /// Sets a new priority for the key, returning the previous one
+    ///
+    /// If the key is not present, returns [None] and does nothing. Never allocates.
+    pub fn change_priority(&mut self, key: &K, mut priority: P) -> Option<P> {
+        let idx = *self.positions.get(key)?;
+        let ptr = self.heap.get_element_ptr(idx).unwrap();
+
+        let prev: P = unsafe { crate::mem::read_fixed_for_move(ptr) };
+        unsafe { crate::mem::write_fixed(ptr, &mut priority) };
+
+        let idx = self.sift_up(idx);
+        self.sift_down(idx);
+
+        Some(prev)
+    }
+
+    /// Removes the key, returning its priority
+    ///
+    /// If the key is not present, returns [None]. Never allocates.
+    pub fn remove(&mut self, key: &K) -> Option<P> {
+        let idx = *self.positions.get(key)?;
+
+        self.take(idx).map(|(_, priority)| priority)
+    }
+
+    /// Returns the priority of the key
+    pub fn get_priority(&self, key: &K) -> Option<SRef<'_, P>> {
+        let idx = *self.positions.get(key)?;
+        let ptr = self.heap.get_element_ptr(idx).unwrap();
+
+        unsafe { Some(SRef::new(ptr)) }
+    }
+
+    /// Returns `true` if the key is present in this [SPriorityQueue]
+    #[inline]
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.positions.contains_key(key)
+    }
+
+    /// Returns the number of keys in this [SPriorityQueue]
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    /// Returns `true` if there are no keys in this [SPriorityQueue]
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.heap.is_empty()
+    }
+
+    /// Returns an iterator over all `(priority, key)` pairs in **arbitrary** order
+    #[inline]
+    pub fn iter(&self) -> SVecIter<'_, (P, K)> {
+        self.heap.iter()
+    }
+
+    /// Removes all keys from this [SPriorityQueue]
+    #[inline]
+    pub fn clear(&mut self) {
+        self.heap.clear();
+        self.positions.clear();
+    }
+
+    fn take(&mut self, idx: usize) -> Option<(K, P)> {
+        let last = self.heap.len() - 1;
+        self.swap_entries(idx, last);
+
+        let (priority, key) = self.heap.pop()?;
+        self.positions.remove(&key);
+
+        if idx < self.heap.len() {
+            let idx = self.sift_up(idx);
+            self.sift_down(idx);
+        }
+
+        Some((key, priority))
+    }
+
+    // returns the new position of the entry
+    fn sift_up(&mut self, mut idx: usize) -> usize {
+        while idx > 0 {
+            let parent = (idx - 1) / 2;
+
+            if self.read_priority(idx) >= self.read_priority(parent) {
+                break;
+            }
+
+            self.swap_entries(idx, parent);
+            idx = parent;
+        }
+
+        idx
+    }
+
+    fn sift_down(&mut self, mut idx: usize) {
+        let len = self.heap.len();
+
+        loop {
+            let left = idx * 2 + 1;
+            let right = left + 1;
+            let mut smallest = idx;
+
+            if left < len && self.read_priority(left) < self.read_priority(smallest) {
+                smallest = left;
+            }
+
+            if right < len && self.read_priority(right) < self.read_priority(smallest) {
+                smallest = right;
+            }
+
+            if smallest == idx {
+                break;
+            }
+
+            self.swap_entries(idx, smallest);
+            idx = smallest;
+        }
+    }
+
+    fn swap_entries(&mut self, idx1: usize, idx2: usize) {
+        if idx1 == idx2 {
+            return;
+        }
+
+        self.heap.swap(idx1, idx2);
+
+        self.write_position(idx1);
+        self.write_position(idx2);
+    }
+
+    #[inline]
+    fn write_position(&mut self, idx: usize) {
+        let key = unsafe { Self::key_ref(self.heap.get_element_ptr(idx).unwrap()) };
+
+        *self.positions.get_mut(&*key).unwrap() = idx;
+    }
+
+    #[inline]
+    fn read_priority(&self, idx: usize) -> P {
+        unsafe { crate::mem::read_fixed_for_reference(self.heap.get_element_ptr(idx).unwrap()) }
+    }
+
+    #[inline]
+    unsafe fn key_ref<'a>(entry_ptr: StablePtr) -> SRef<'a, K> {
+        SRef::new(SSlice::_offset(entry_ptr, P::SIZE as u64))
+    }
+}
+
+impl<
+        K: StableType + AsFixedSizeBytes + Hash + Eq + Clone,
+        P: StableType + AsFixedSizeBytes + Ord,
+    > Default for SPriorityQueue<K, P>
+{
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<
+        K: StableType + AsFixedSizeBytes + Hash + Eq + Clone,
+        P: StableType + AsFixedSizeBytes + Ord,
+    > AsFixedSizeBytes for SPriorityQueue<K, P>
+{
+    const SIZE: usize = SVec::<(P, K)>::SIZE + SHashMap::<K, usize>::SIZE;
+    type Buf = [u8; SVec::<u64>::SIZE + SHashMap::<u64, usize>::SIZE];
+
+    fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+        let heap_size = SVec::<(P, K)>::SIZE;
+
+        self.heap.as_fixed_size_bytes(&mut buf[0..heap_size]);
+        self.positions
+            .as_fixed_size_bytes(&mut buf[heap_size..Self::SIZE]);
+    }
+
+    fn from_fixed_size_bytes(buf: &[u8]) -> Self {
+        let heap_size = SVec::<(P, K)>::SIZE;
+
+        Self {
+            heap: SVec::from_fixed_size_bytes(&buf[0..heap_size]),
+            positions: SHashMap::from_fixed_size_bytes(&buf[heap_size..Self::SIZE]),
+        }
+    }
+}
+
+impl<
+        K: StableType + AsFixedSizeBytes + Hash + Eq + Clone,
+        P: StableType + AsFixedSizeBytes + Ord,
+    > StableType for SPriorityQueue<K, P>
+{
+    #[inline]
+    unsafe fn stable_drop_flag_off(&mut self) {
+        self.heap.stable_drop_flag_off();
+        self.positions.stable_drop_flag_off();
+    }
+
+    #[inline]
+    unsafe fn stable_drop_flag_on(&mut self) {
+        self.heap.stable_drop_flag_on();
+        self.positions.stable_drop_flag_on();
+    }
+}
+
+impl<
+        K: StableType + AsFixedSizeBytes + Hash + Eq + Clone + Debug,
+        P: StableType + AsFixedSizeBytes + Ord + Debug,
+    > Debug for SPriorityQueue<K, P>
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("[")?;
+        for (idx, entry) in self.iter().enumerate() {
+            let (priority, key) = &*entry;
+
+            key.fmt(f)?;
+            f.write_str(": ")?;
+            priority.fmt(f)?;
+
+            if idx < self.len() - 1 {
+                f.write_str(", ")?;
+            }
+        }
+        f.write_str("]")
+    }
+}
+
+#[cfg(feature = "orphan_collector")]
+impl<
+        K: StableType + AsFixedSizeBytes + Hash + Eq + Clone + Trace,
+        P: StableType + AsFixedSizeBytes + Ord + Trace,
+    > Trace for SPriorityQueue<K, P>
+{
+    fn trace(&self, ptrs: &mut Vec<StablePtr>) {
+        self.heap.trace(ptrs);
+        self.positions.trace(ptrs);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::priority_queue::SPriorityQueue;
+    use crate::{
+        _debug_validate_allocator, get_allocated_size, init_allocator, retrieve_custom_data,
+        stable, stable_memory_init, stable_memory_post_upgrade, stable_memory_pre_upgrade,
+        store_custom_data, SBox,
+    };
+    use rand::rngs::ThreadRng;
+    use rand::{thread_rng, Rng};
+    use std::collections::HashMap;
+
+    #[test]
+    fn works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut queue = SPriorityQueue::new();
+            assert!(queue.pop().is_none());
+            assert!(queue.peek().is_none());
+
+            for i in 0..100u64 {
+                assert!(queue.push(i, 1000 - i).unwrap().is_none());
+            }
+
+            assert_eq!(queue.len(), 100);
+            assert_eq!(*queue.peek().unwrap().0, 99);
+
+            assert_eq!(queue.push(10, 0).unwrap(), Some(990));
+            assert_eq!(queue.change_priority(&99, 5000), Some(901));
+            assert_eq!(queue.remove(&98), Some(902));
+            assert!(queue.remove(&98).is_none());
+            assert_eq!(*queue.get_priority(&99).unwrap(), 5000);
+
+            store_custom_data(0, SBox::new(queue).unwrap());
+            let mut queue = retrieve_custom_data::<SPriorityQueue<u64, u64>>(0)
+                .unwrap()
+                .into_inner();
+
+            assert_eq!(queue.pop(), Some((10, 0)));
+
+            let mut prev = 0;
+            let mut count = 0;
+            while let Some((key, priority)) = queue.pop() {
+                assert!(priority >= prev);
+                assert!(!queue.contains_key(&key));
+
+                prev = priority;
+                count += 1;
+            }
+
+            assert_eq!(count, 97);
+            assert!(queue.is_empty());
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    enum Action {
+        Push,
+        Pop,
+        ChangePriority,
+        Remove,
+        Clear,
+        CanisterUpgrade,
+    }
+
+    struct Fuzzer {
+        state: Option<SPriorityQueue<u64, SBox<String>>>,
+        example: HashMap<u64, String>,
+        rng: ThreadRng,
+        log: Vec<Action>,
+    }
+
+    impl Fuzzer {
+        fn new() -> Self {
+            Self {
+                state: Some(SPriorityQueue::default()),
+                example: HashMap::default(),
+                rng: thread_rng(),
+                log: Vec::default(),
+            }
+        }
+
+        fn it(&mut self) -> &mut SPriorityQueue<u64, SBox<String>> {
+            self.state.as_mut().unwrap()
+        }
+
+        fn random_priority(&mut self) -> String {
+            format!("{:05}", self.rng.gen_range(0..100_000))
+        }
+
+        fn next(&mut self) {
+            let action = self.rng.gen_range(0..101);
+            let key = self.rng.gen_range(0..1000u64);
+
+            match action {
+                // PUSH ~50%
+                0..=49 => {
+                    let priority = self.random_priority();
+
+                    if let Ok(data) = SBox::new(priority.clone()) {
+                        if let Ok(prev) = self.it().push(key, data) {
+                            let expected = self.example.insert(key, priority);
+                            assert_eq!(prev.map(|it| it.clone()), expected);
+                        }
+
+                        self.log.push(Action::Push);
+                    }
+                }
+                // POP ~20%
+                50..=69 => {
+                    let expected_min = self.example.values().min().cloned();
+
+                    match self.it().pop() {
+                        Some((key, priority)) => {
+                            assert_eq!(Some(priority.clone()), expected_min);
+                            assert_eq!(self.example.remove(&key), expected_min);
+                        }
+                        None => assert!(expected_min.is_none()),
+                    }
+
+                    self.log.push(Action::Pop);
+                }
+                // CHANGE PRIORITY ~15%
+                70..=84 => {
+                    let priority = self.random_priority();
+
+                    if let Ok(data) = SBox::new(priority.clone()) {
+                        let prev = self.it().change_priority(&key, data);
+
+                        let expected = match self.example.get_mut(&key) {
+                            Some(it) => Some(std::mem::replace(it, priority)),
+                            None => None,
+                        };
+
+                        assert_eq!(prev.map(|it| it.clone()), expected);
+
+                        self.log.push(Action::ChangePriority);
+                    }
+                }
+                // REMOVE ~10%
+                85..=94 => {
+                    let prev = self.it().remove(&key);
+                    assert_eq!(prev.map(|it| it.clone()), self.example.remove(&key));
+
+                    self.log.push(Action::Remove);
+                }
+                // CLEAR
+                95..=96 => {
+                    self.it().clear();
+                    self.example.clear();
+
+                    self.log.push(Action::Clear);
+                }
+                // CANISTER UPGRADE
+                _ => match SBox::new(self.state.take().unwrap()) {
+                    Ok(data) => {
+                        store_custom_data(1, data);
+
+                        if stable_memory_pre_upgrade().is_ok() {
+                            stable_memory_post_upgrade();
+                        }
+
+                        self.state = retrieve_custom_data::<SPriorityQueue<u64, SBox<String>>>(1)
+                            .map(|it| it.into_inner());
+
+                        self.log.push(Action::CanisterUpgrade);
+                    }
+                    Err(queue) => {
+                        self.state = Some(queue);
+                    }
+                },
+            }
+
+            _debug_validate_allocator();
+            assert_eq!(self.it().len(), self.example.len());
+
+            let top = self.it().peek().map(|(key, priority)| (*key, priority.clone()));
+            if let Some((key, priority)) = top {
+                assert_eq!(self.example.get(&key), Some(&priority));
+            }
+        }
+    }
+
+    #[test]
+    fn fuzzer_works_fine() {
+        stable::clear();
+        init_allocator(0);
+
+        {
+            let mut fuzzer = Fuzzer::new();
+
+            for _ in 0..10_000 {
+                fuzzer.next();
+            }
+        }
+
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn fuzzer_works_fine_limited_memory() {
+        stable::clear();
+        init_allocator(10);
+
+        {
+            let mut fuzzer = Fuzzer::new();
+
+            for _ in 0..10_000 {
+                fuzzer.next();
+            }
+        }
+
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
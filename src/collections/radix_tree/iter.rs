@@ -0,0 +1,43 @@
+use crate::collections::radix_tree::node::RadixNode;
+use crate::encoding::AsFixedSizeBytes;
+use crate::mem::StablePtr;
+use crate::primitive::s_ref::SRef;
+use crate::primitive::StableType;
+use std::marker::PhantomData;
+
+pub struct SRadixTreeIter<'a, V> {
+    // pointers to nodes, which are yet to be visited, with keys of their parents
+    stack: Vec<(StablePtr, Vec<u8>)>,
+    _marker: PhantomData<&'a V>,
+}
+
+impl<'a, V: StableType + AsFixedSizeBytes> SRadixTreeIter<'a, V> {
+    #[inline]
+    pub(crate) fn new(start: Option<(StablePtr, Vec<u8>)>) -> Self {
+        Self {
+            stack: start.into_iter().collect(),
+            _marker: PhantomData::default(),
+        }
+    }
+}
+
+impl<'a, V: StableType + AsFixedSizeBytes> Iterator for SRadixTreeIter<'a, V> {
+    type Item = (Vec<u8>, SRef<'a, V>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (ptr, mut key) = self.stack.pop()?;
+            let node = RadixNode::<V>::read(ptr);
+
+            key.extend_from_slice(&node.label);
+
+            for (_, child_ptr) in node.children.iter().rev() {
+                self.stack.push((*child_ptr, key.clone()));
+            }
+
+            if node.has_value {
+                return Some((key, unsafe { SRef::new(node.value_ptr()) }));
+            }
+        }
+    }
+}
@@ -0,0 +1,749 @@
+use crate::collections::radix_tree::iter::SRadixTreeIter;
+use crate::collections::radix_tree::node::{common_prefix_len, RadixNode};
+use crate::encoding::AsFixedSizeBytes;
+use crate::mem::allocator::EMPTY_PTR;
+#[cfg(feature = "orphan_collector")]
+use crate::mem::orphan_collector::Trace;
+use crate::mem::StablePtr;
+use crate::primitive::s_ref::SRef;
+use crate::primitive::s_ref_mut::SRefMut;
+use crate::primitive::StableType;
+use crate::OutOfMemory;
+use std::fmt::{Debug, Formatter};
+use std::marker::PhantomData;
+
+pub mod iter;
+pub(crate) mod node;
+
+/// Radix tree (compressed byte-trie) based map data structure with byte string keys
+///
+/// Entries are stored in lexicographic order of their keys. Each node of the tree stores a label -
+/// a non-empty chunk of the key, shared by all entries of the node's subtree - so keys with common
+/// prefixes share the space. This makes [SRadixTree] a good fit for URL/path routing or principal-prefix
+/// queries, which don't map well onto fixed size keys of [SBTreeMap](crate::collections::SBTreeMap).
+///
+/// Unlike other maps, it owns keys as plain bytes - there is no need to put them in [SBox](crate::SBox).
+/// Keys are accepted by reference and returned as [Vec] of [u8] during iteration.
+///
+/// Nodes are never merged back if it would require reallocation, which means, that removing entries
+/// never requires stable memory.
+///
+/// `V` has to implement [StableType] and [AsFixedSizeBytes] traits. [SRadixTree] also
+/// implements these trait, so you can nest it in other stable structures.
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::collections::SRadixTree;
+/// # use ic_stable_memory::stable_memory_init;
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// let mut routes = SRadixTree::new();
+///
+/// routes.insert(b"/api/users", 1u32).expect("Out of memory");
+/// routes.insert(b"/api/users/me", 2).expect("Out of memory");
+/// routes.insert(b"/static/index.html", 3).expect("Out of memory");
+///
+/// assert_eq!(*routes.get(b"/api/users").unwrap(), 1);
+///
+/// let api = routes
+///     .iter_prefix(b"/api")
+///     .map(|(k, v)| (k, *v))
+///     .collect::<Vec<_>>();
+///
+/// assert_eq!(api, vec![(b"/api/users".to_vec(), 1), (b"/api/users/me".to_vec(), 2)]);
+/// ```
+pub struct SRadixTree<V: StableType + AsFixedSizeBytes> {
+    root: StablePtr,
+    len: u64,
+    stable_drop_flag: bool,
+    _marker_v: PhantomData<V>,
+}
+
+impl<V: StableType + AsFixedSizeBytes> SRadixTree<V> {
+    /// Creates a new [SRadixTree]
+    ///
+    /// Does not allocate any heap or stable memory.
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            root: EMPTY_PTR,
+            len: 0,
+            stable_drop_flag: true,
+            _marker_v: PhantomData::default(),
+        }
+    }
+
+    /// Inserts the provided key-value pair into this [SRadixTree]
+    ///
+    /// May allocate stable memory. If your canister is out of stable memory, will return [Err] with
+    /// the value that was about to get inserted. In that case the map stays unchanged.
+    ///
+    /// If the insertion is successful, returns [Option] with a value, that was previously stored
+    /// under this key.
+    pub fn insert(&mut self, key: &[u8], mut value: V) -> Result<Option<V>, V> {
+        if self.root == EMPTY_PTR {
+            let mut root = RadixNode::<V>::new(Vec::new());
+            if root.persist().is_err() {
+                return Err(value);
+            }
+
+            self.root = root.ptr;
+        }
+
+        let mut parent: Option<(RadixNode<V>, usize)> = None;
+        let mut node = RadixNode::<V>::read(self.root);
+        let mut rest = key;
+
+        loop {
+            if rest.is_empty() {
+                let prev = if node.has_value {
+                    Some(unsafe { crate::mem::read_fixed_for_move(node.value_ptr()) })
+                } else {
+                    self.len += 1;
+                    node.write_has_value(true);
+
+                    None
+                };
+
+                unsafe { crate::mem::write_fixed(node.value_ptr(), &mut value) };
+
+                return Ok(prev);
+            }
+
+            let idx = match node.find_child(rest[0]) {
+                Ok(idx) => idx,
+                Err(idx) => {
+                    let mut leaf = RadixNode::<V>::new(rest.to_vec());
+                    if leaf.persist().is_err() {
+                        return Err(value);
+                    }
+
+                    node.children.insert(idx, (rest[0], leaf.ptr));
+
+                    match node.persist() {
+                        Ok(moved) => {
+                            if moved {
+                                self.relink(&mut parent, node.ptr);
+                            }
+                        }
+                        Err(_) => {
+                            leaf.destroy();
+                            return Err(value);
+                        }
+                    }
+
+                    leaf.write_has_value(true);
+                    unsafe { crate::mem::write_fixed(leaf.value_ptr(), &mut value) };
+
+                    self.len += 1;
+
+                    return Ok(None);
+                }
+            };
+
+            let mut child = RadixNode::<V>::read(node.children[idx].1);
+            let common = common_prefix_len(&child.label, rest);
+
+            // the key diverges in the middle of the child's label - split the child in two
+            if common < child.label.len() {
+                let mut mid = RadixNode::<V>::new(child.label[..common].to_vec());
+                mid.children.push((child.label[common], child.ptr));
+
+                if mid.persist().is_err() {
+                    return Err(value);
+                }
+
+                // shrinking never moves
+                child.label.drain(..common);
+                child.persist().unwrap();
+
+                node.write_child_ptr(idx, mid.ptr);
+                child = mid;
+            }
+
+            rest = &rest[common..];
+            parent = Some((node, idx));
+            node = child;
+        }
+    }
+
+    /// Same as [SRadixTree::insert], but returns [OutOfMemory] instead of the value
+    ///
+    /// Useful for propagating the error with `?`. The value gets dropped, if it can't be inserted.
+    #[inline]
+    pub fn try_insert(&mut self, key: &[u8], value: V) -> Result<Option<V>, OutOfMemory> {
+        self.insert(key, value).map_err(|_| OutOfMemory)
+    }
+
+    /// Removes a key-value pair by the provided key
+    ///
+    /// Returns [None] if no pair was found by this key. May release some of stable memory occupied
+    /// by this stable structure.
+    pub fn remove(&mut self, key: &[u8]) -> Option<V> {
+        let mut path = Vec::new();
+        let mut node = self.find_node(key, Some(&mut path))?;
+
+        if !node.has_value {
+            return None;
+        }
+
+        let value = unsafe { crate::mem::read_fixed_for_move(node.value_ptr()) };
+        node.write_has_value(false);
+        self.len -= 1;
+
+        if self.len == 0 {
+            self.destroy_nodes();
+
+            return Some(value);
+        }
+
+        let (mut parent, idx) = match path.pop() {
+            Some(it) => it,
+            None => return Some(value),
+        };
+
+        if node.children.is_empty() {
+            parent.children.remove(idx);
+            // shrinking never moves
+            parent.persist().unwrap();
+
+            node.destroy();
+
+            if !parent.has_value && parent.children.len() == 1 {
+                if let Some((mut grandparent, parent_idx)) = path.pop() {
+                    Self::try_merge_with_child(parent, &mut grandparent, parent_idx);
+                }
+            }
+        } else if node.children.len() == 1 {
+            Self::try_merge_with_child(node, &mut parent, idx);
+        }
+
+        Some(value)
+    }
+
+    /// Returns an immutable reference [SRef] to a value stored by the key
+    ///
+    /// See also [SRadixTree::get_mut].
+    ///
+    /// If no such key-value pair is found, returns [None]
+    #[inline]
+    pub fn get(&self, key: &[u8]) -> Option<SRef<V>> {
+        let node = self.find_node(key, None)?;
+
+        if node.has_value {
+            Some(unsafe { SRef::new(node.value_ptr()) })
+        } else {
+            None
+        }
+    }
+
+    /// Returns a mutable reference [SRefMut] to a value stored by the key
+    ///
+    /// See also [SRadixTree::get].
+    ///
+    /// If no such key-value pair is found, returns [None]
+    #[inline]
+    pub fn get_mut(&mut self, key: &[u8]) -> Option<SRefMut<V>> {
+        let node = self.find_node(key, None)?;
+
+        if node.has_value {
+            Some(unsafe { SRefMut::new(node.value_ptr()) })
+        } else {
+            None
+        }
+    }
+
+    /// Returns true if there exists a key-value pair stored by the provided key
+    #[inline]
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Returns an iterator over all entries, which keys start with the provided prefix
+    ///
+    /// Elements of this iterator are presented in lexicographic order of their keys. Only visits
+    /// nodes of the subtree under the prefix.
+    pub fn iter_prefix(&self, prefix: &[u8]) -> SRadixTreeIter<V> {
+        if self.root == EMPTY_PTR {
+            return SRadixTreeIter::new(None);
+        }
+
+        let mut node = RadixNode::<V>::read(self.root);
+        let mut key = Vec::new();
+        let mut rest = prefix;
+
+        loop {
+            if rest.is_empty() {
+                return SRadixTreeIter::new(Some((node.ptr, key)));
+            }
+
+            let idx = match node.find_child(rest[0]) {
+                Ok(idx) => idx,
+                Err(_) => return SRadixTreeIter::new(None),
+            };
+
+            let child = RadixNode::<V>::read(node.children[idx].1);
+            let common = common_prefix_len(&child.label, rest);
+
+            // the prefix ends inside the child's label - the whole subtree matches
+            if common == rest.len() {
+                key.extend_from_slice(&node.label);
+
+                return SRadixTreeIter::new(Some((child.ptr, key)));
+            }
+
+            if common < child.label.len() {
+                return SRadixTreeIter::new(None);
+            }
+
+            key.extend_from_slice(&node.label);
+            rest = &rest[common..];
+            node = child;
+        }
+    }
+
+    /// Returns an iterator over all entries of this [SRadixTree]
+    ///
+    /// Elements of this iterator are presented in lexicographic order of their keys.
+    #[inline]
+    pub fn iter(&self) -> SRadixTreeIter<V> {
+        self.iter_prefix(&[])
+    }
+
+    /// Returns the length of this [SRadixTree]
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns [true] if the length of this [SRadixTree] is `0`
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Removes all key-value pairs from this collection, releasing all occupied stable memory
+    #[inline]
+    pub fn clear(&mut self) {
+        self.destroy_nodes();
+    }
+
+    // returns the node exactly matching the key, optionally collecting the path to it
+    fn find_node(
+        &self,
+        key: &[u8],
+        mut path: Option<&mut Vec<(RadixNode<V>, usize)>>,
+    ) -> Option<RadixNode<V>> {
+        if self.root == EMPTY_PTR {
+            return None;
+        }
+
+        let mut node = RadixNode::<V>::read(self.root);
+        let mut rest = key;
+
+        while !rest.is_empty() {
+            let idx = node.find_child(rest[0]).ok()?;
+            let child = RadixNode::<V>::read(node.children[idx].1);
+
+            if !rest.starts_with(&child.label) {
+                return None;
+            }
+
+            rest = &rest[child.label.len()..];
+
+            if let Some(p) = path.as_mut() {
+                p.push((node, idx));
+            }
+
+            node = child;
+        }
+
+        Some(node)
+    }
+
+    // merges a valueless node with its only child, if the child has enough space to fit both labels
+    fn try_merge_with_child(node: RadixNode<V>, parent: &mut RadixNode<V>, idx: usize) {
+        debug_assert!(!node.has_value);
+        debug_assert_eq!(node.children.len(), 1);
+
+        let mut child = RadixNode::<V>::read(node.children[0].1);
+
+        if !child.fits(node.label.len() + child.label.len(), child.children.len()) {
+            return;
+        }
+
+        let mut label = node.label.clone();
+        label.extend_from_slice(&child.label);
+        child.label = label;
+
+        // fits - never moves
+        child.persist().unwrap();
+
+        parent.write_child_ptr(idx, child.ptr);
+        node.destroy();
+    }
+
+    fn relink(&mut self, parent: &mut Option<(RadixNode<V>, usize)>, ptr: StablePtr) {
+        match parent {
+            Some((p, idx)) => p.write_child_ptr(*idx, ptr),
+            None => self.root = ptr,
+        }
+    }
+
+    fn destroy_nodes(&mut self) {
+        if self.root == EMPTY_PTR {
+            return;
+        }
+
+        let mut nodes = vec![self.root];
+
+        while let Some(ptr) = nodes.pop() {
+            let node = RadixNode::<V>::read(ptr);
+
+            if node.has_value {
+                let _: V = unsafe { crate::mem::read_fixed_for_move(node.value_ptr()) };
+            }
+
+            nodes.extend(node.children.iter().map(|(_, p)| *p));
+            node.destroy();
+        }
+
+        self.root = EMPTY_PTR;
+        self.len = 0;
+    }
+}
+
+impl<V: StableType + AsFixedSizeBytes> Default for SRadixTree<V> {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V: StableType + AsFixedSizeBytes> AsFixedSizeBytes for SRadixTree<V> {
+    const SIZE: usize = u64::SIZE * 2;
+    type Buf = [u8; u64::SIZE * 2];
+
+    fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+        self.root.as_fixed_size_bytes(&mut buf[0..u64::SIZE]);
+        self.len
+            .as_fixed_size_bytes(&mut buf[u64::SIZE..(u64::SIZE * 2)]);
+    }
+
+    fn from_fixed_size_bytes(buf: &[u8]) -> Self {
+        let root = u64::from_fixed_size_bytes(&buf[0..u64::SIZE]);
+        let len = u64::from_fixed_size_bytes(&buf[u64::SIZE..(u64::SIZE * 2)]);
+
+        Self {
+            root,
+            len,
+            stable_drop_flag: false,
+            _marker_v: PhantomData::default(),
+        }
+    }
+}
+
+impl<V: StableType + AsFixedSizeBytes> StableType for SRadixTree<V> {
+    #[inline]
+    unsafe fn stable_drop_flag_off(&mut self) {
+        self.stable_drop_flag = false;
+    }
+
+    #[inline]
+    unsafe fn stable_drop_flag_on(&mut self) {
+        self.stable_drop_flag = true;
+    }
+
+    #[inline]
+    fn should_stable_drop(&self) -> bool {
+        self.stable_drop_flag
+    }
+
+    #[inline]
+    unsafe fn stable_drop(&mut self) {
+        self.destroy_nodes();
+    }
+}
+
+impl<V: StableType + AsFixedSizeBytes> Drop for SRadixTree<V> {
+    fn drop(&mut self) {
+        if self.should_stable_drop() {
+            unsafe {
+                self.stable_drop();
+            }
+        }
+    }
+}
+
+impl<V: StableType + AsFixedSizeBytes + Debug> Debug for SRadixTree<V> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("{")?;
+        for (idx, (k, v)) in self.iter().enumerate() {
+            k.fmt(f)?;
+            f.write_str(": ")?;
+            v.fmt(f)?;
+
+            if (idx as u64) < self.len() - 1 {
+                f.write_str(", ")?;
+            }
+        }
+        f.write_str("}")
+    }
+}
+
+#[cfg(feature = "orphan_collector")]
+impl<V: StableType + AsFixedSizeBytes + Trace> Trace for SRadixTree<V> {
+    fn trace(&self, ptrs: &mut Vec<StablePtr>) {
+        if self.root == EMPTY_PTR {
+            return;
+        }
+
+        let mut nodes = vec![self.root];
+
+        while let Some(ptr) = nodes.pop() {
+            let node = RadixNode::<V>::read(ptr);
+
+            if V::OWNS_MEMORY && node.has_value {
+                unsafe { SRef::<V>::new(node.value_ptr()) }.trace(ptrs);
+            }
+
+            nodes.extend(node.children.iter().map(|(_, p)| *p));
+            ptrs.push(ptr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::radix_tree::SRadixTree;
+    use crate::encoding::{AsFixedSizeBytes, Buffer};
+    use crate::{
+        _debug_validate_allocator, get_allocated_size, init_allocator, retrieve_custom_data,
+        stable, stable_memory_init, stable_memory_post_upgrade, stable_memory_pre_upgrade,
+        store_custom_data, SBox,
+    };
+    use rand::rngs::ThreadRng;
+    use rand::{thread_rng, Rng};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn it_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut tree = SRadixTree::<u64>::new();
+
+            assert!(tree.insert(b"romane", 1).unwrap().is_none());
+            assert!(tree.insert(b"romanus", 2).unwrap().is_none());
+            assert!(tree.insert(b"romulus", 3).unwrap().is_none());
+            assert!(tree.insert(b"rubens", 4).unwrap().is_none());
+            assert!(tree.insert(b"ruber", 5).unwrap().is_none());
+            assert!(tree.insert(b"rom", 6).unwrap().is_none());
+            assert!(tree.insert(b"", 7).unwrap().is_none());
+            assert_eq!(tree.insert(b"ruber", 50).unwrap(), Some(5));
+
+            assert_eq!(tree.len(), 7);
+            assert_eq!(*tree.get(b"ruber").unwrap(), 50);
+            assert_eq!(*tree.get(b"").unwrap(), 7);
+            assert!(tree.get(b"ro").is_none());
+            assert!(tree.get(b"romanes").is_none());
+
+            *tree.get_mut(b"rom").unwrap() = 60;
+            assert_eq!(*tree.get(b"rom").unwrap(), 60);
+
+            let keys = tree.iter_prefix(b"rom").map(|(k, _)| k).collect::<Vec<_>>();
+            assert_eq!(
+                keys,
+                vec![
+                    b"rom".to_vec(),
+                    b"romane".to_vec(),
+                    b"romanus".to_vec(),
+                    b"romulus".to_vec()
+                ]
+            );
+
+            let keys = tree.iter_prefix(b"ru").map(|(k, _)| k).collect::<Vec<_>>();
+            assert_eq!(keys, vec![b"rubens".to_vec(), b"ruber".to_vec()]);
+
+            assert_eq!(tree.iter_prefix(b"rub").count(), 2);
+            assert_eq!(tree.iter_prefix(b"x").count(), 0);
+            assert_eq!(tree.iter().count(), 7);
+
+            assert_eq!(tree.remove(b"rom"), Some(60));
+            assert_eq!(tree.remove(b"rom"), None);
+            assert_eq!(tree.remove(b"ro"), None);
+            assert_eq!(tree.remove(b"romane"), Some(1));
+            assert_eq!(tree.iter_prefix(b"rom").count(), 2);
+
+            println!("{:?}", tree);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn serialization_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut tree = SRadixTree::<u32>::new();
+            tree.insert(b"key", 10).unwrap();
+
+            let buf = tree.as_new_fixed_size_bytes();
+            let tree1 = SRadixTree::<u32>::from_fixed_size_bytes(buf._deref());
+
+            assert_eq!(*tree1.get(b"key").unwrap(), 10);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[derive(Debug)]
+    enum Action {
+        Insert,
+        Remove,
+        Clear,
+        CanisterUpgrade,
+    }
+
+    struct Fuzzer {
+        tree: Option<SRadixTree<SBox<String>>>,
+        example: BTreeMap<Vec<u8>, String>,
+        rng: ThreadRng,
+        log: Vec<Action>,
+    }
+
+    impl Fuzzer {
+        fn new() -> Fuzzer {
+            Fuzzer {
+                tree: Some(SRadixTree::new()),
+                example: BTreeMap::new(),
+                rng: thread_rng(),
+                log: Vec::new(),
+            }
+        }
+
+        fn tree(&mut self) -> &mut SRadixTree<SBox<String>> {
+            self.tree.as_mut().unwrap()
+        }
+
+        // short keys over a tiny alphabet, so they share a lot of prefixes
+        fn gen_key(&mut self) -> Vec<u8> {
+            let len = self.rng.gen_range(0..8);
+            (0..len).map(|_| self.rng.gen_range(b'a'..b'd')).collect()
+        }
+
+        fn next(&mut self) {
+            let action = self.rng.gen_range(0..101);
+
+            match action {
+                // INSERT ~60%
+                0..=59 => {
+                    let key = self.gen_key();
+                    let value = format!("{:?}", self.rng.gen::<u64>());
+
+                    if let Ok(data) = SBox::new(value.clone()) {
+                        if self.tree().insert(&key, data).is_err() {
+                            return;
+                        }
+
+                        self.example.insert(key, value);
+                        self.log.push(Action::Insert);
+                    }
+                }
+                // REMOVE
+                60..=89 => {
+                    let key = self.gen_key();
+
+                    assert_eq!(
+                        self.tree().remove(&key).map(|it| it.into_inner()),
+                        self.example.remove(&key)
+                    );
+
+                    self.log.push(Action::Remove);
+                }
+                // CLEAR
+                90..=91 => {
+                    self.tree().clear();
+                    self.example.clear();
+
+                    self.log.push(Action::Clear);
+                }
+                // CANISTER UPGRADE
+                _ => match SBox::new(self.tree.take().unwrap()) {
+                    Ok(data) => {
+                        store_custom_data(1, data);
+
+                        if stable_memory_pre_upgrade().is_ok() {
+                            stable_memory_post_upgrade();
+                        }
+
+                        self.tree = retrieve_custom_data::<SRadixTree<SBox<String>>>(1)
+                            .map(|it| it.into_inner());
+
+                        self.log.push(Action::CanisterUpgrade);
+                    }
+                    Err(tree) => {
+                        self.tree = Some(tree);
+                    }
+                },
+            }
+
+            _debug_validate_allocator();
+            assert_eq!(self.tree().len() as usize, self.example.len());
+
+            let prefix = self.gen_key();
+            let actual = self
+                .tree
+                .as_ref()
+                .unwrap()
+                .iter_prefix(&prefix)
+                .map(|(k, v)| (k, (*v).clone()))
+                .collect::<Vec<_>>();
+            let expected = self
+                .example
+                .iter()
+                .filter(|(k, _)| k.starts_with(&prefix))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect::<Vec<_>>();
+
+            assert_eq!(actual, expected, "{:?}", self.log.last());
+        }
+    }
+
+    #[test]
+    fn fuzzer_works_fine() {
+        stable::clear();
+        init_allocator(0);
+
+        {
+            let mut fuzzer = Fuzzer::new();
+
+            for _ in 0..5_000 {
+                fuzzer.next();
+            }
+        }
+
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn fuzzer_works_fine_limited_memory() {
+        stable::clear();
+        init_allocator(10);
+
+        {
+            let mut fuzzer = Fuzzer::new();
+
+            for _ in 0..5_000 {
+                fuzzer.next();
+            }
+        }
+
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
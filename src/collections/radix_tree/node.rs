@@ -0,0 +1,179 @@
+use crate::encoding::AsFixedSizeBytes;
+use crate::mem::allocator::EMPTY_PTR;
+use crate::mem::StablePtr;
+use crate::{allocate, deallocate, reallocate, OutOfMemory, SSlice};
+use std::marker::PhantomData;
+
+// LAYOUT:
+// has_value: bool
+// value: V
+// label_len: u32
+// children_len: u16
+// label: [u8; label_len]
+// children: [(u8, u64); children_len] -- sorted by the first byte of the child's label
+
+const HAS_VALUE_OFFSET: u64 = 0;
+const VALUE_OFFSET: u64 = HAS_VALUE_OFFSET + bool::SIZE as u64;
+const CHILD_SIZE: u64 = (u8::SIZE + u64::SIZE) as u64;
+
+const fn label_len_offset<V: AsFixedSizeBytes>() -> u64 {
+    VALUE_OFFSET + V::SIZE as u64
+}
+const fn children_len_offset<V: AsFixedSizeBytes>() -> u64 {
+    label_len_offset::<V>() + u32::SIZE as u64
+}
+const fn label_offset<V: AsFixedSizeBytes>() -> u64 {
+    children_len_offset::<V>() + u16::SIZE as u64
+}
+
+/// A heap copy of a radix tree node's metadata. The value is never copied - it is only accessed
+/// by its pointer.
+pub(crate) struct RadixNode<V> {
+    pub ptr: StablePtr,
+    pub has_value: bool,
+    pub label: Vec<u8>,
+    pub children: Vec<(u8, StablePtr)>,
+    _marker_v: PhantomData<V>,
+}
+
+impl<V: AsFixedSizeBytes> RadixNode<V> {
+    #[inline]
+    pub fn new(label: Vec<u8>) -> Self {
+        Self {
+            ptr: EMPTY_PTR,
+            has_value: false,
+            label,
+            children: Vec::new(),
+            _marker_v: PhantomData::default(),
+        }
+    }
+
+    #[inline]
+    pub const fn calc_size_bytes(label_len: usize, children_len: usize) -> u64 {
+        label_offset::<V>() + label_len as u64 + children_len as u64 * CHILD_SIZE
+    }
+
+    pub fn read(ptr: StablePtr) -> Self {
+        let has_value: bool = unsafe {
+            crate::mem::read_fixed_for_reference(SSlice::_offset(ptr, HAS_VALUE_OFFSET))
+        };
+        let label_len: u32 = unsafe {
+            crate::mem::read_fixed_for_reference(SSlice::_offset(ptr, label_len_offset::<V>()))
+        };
+        let children_len: u16 = unsafe {
+            crate::mem::read_fixed_for_reference(SSlice::_offset(ptr, children_len_offset::<V>()))
+        };
+
+        let mut buf = vec![0u8; label_len as usize + children_len as usize * CHILD_SIZE as usize];
+        unsafe { crate::mem::read_bytes(SSlice::_offset(ptr, label_offset::<V>()), &mut buf) };
+
+        let children_buf = buf.split_off(label_len as usize);
+        let children = children_buf
+            .chunks_exact(CHILD_SIZE as usize)
+            .map(|it| (it[0], u64::from_fixed_size_bytes(&it[u8::SIZE..])))
+            .collect();
+
+        Self {
+            ptr,
+            has_value,
+            label: buf,
+            children,
+            _marker_v: PhantomData::default(),
+        }
+    }
+
+    /// Writes the node to stable memory, (re)allocating it if needed.
+    ///
+    /// Returns `true` if the node has moved to a new location. Never moves the node, if its size
+    /// did not grow.
+    pub fn persist(&mut self) -> Result<bool, OutOfMemory> {
+        let size = Self::calc_size_bytes(self.label.len(), self.children.len());
+        let mut moved = false;
+
+        if self.ptr == EMPTY_PTR {
+            self.ptr = unsafe { allocate(size)?.as_ptr() };
+            moved = true;
+        } else {
+            let slice = unsafe { SSlice::from_ptr(self.ptr).unwrap() };
+
+            if slice.get_size_bytes() < size {
+                let new_slice = unsafe { reallocate(slice, size)? };
+
+                moved = new_slice.as_ptr() != self.ptr;
+                self.ptr = new_slice.as_ptr();
+            }
+        }
+
+        self.write_has_value(self.has_value);
+
+        let mut buf = Vec::with_capacity(
+            u32::SIZE + u16::SIZE + self.label.len() + self.children.len() * CHILD_SIZE as usize,
+        );
+        buf.extend_from_slice(&(self.label.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&(self.children.len() as u16).to_le_bytes());
+        buf.extend_from_slice(&self.label);
+
+        for (b, ptr) in &self.children {
+            buf.push(*b);
+            buf.extend_from_slice(&ptr.to_le_bytes());
+        }
+
+        unsafe {
+            crate::mem::write_bytes(SSlice::_offset(self.ptr, label_len_offset::<V>()), &buf)
+        };
+
+        Ok(moved)
+    }
+
+    /// Returns `true` if the node can be persisted with the provided label and children lengths
+    /// without reallocation.
+    #[inline]
+    pub fn fits(&self, label_len: usize, children_len: usize) -> bool {
+        let slice = unsafe { SSlice::from_ptr(self.ptr).unwrap() };
+
+        slice.get_size_bytes() >= Self::calc_size_bytes(label_len, children_len)
+    }
+
+    #[inline]
+    pub fn destroy(self) {
+        let slice = unsafe { SSlice::from_ptr(self.ptr).unwrap() };
+        deallocate(slice);
+    }
+
+    #[inline]
+    pub fn find_child(&self, b: u8) -> Result<usize, usize> {
+        self.children.binary_search_by(|(it, _)| it.cmp(&b))
+    }
+
+    #[inline]
+    pub fn value_ptr(&self) -> StablePtr {
+        SSlice::_offset(self.ptr, VALUE_OFFSET)
+    }
+
+    #[inline]
+    pub fn write_has_value(&mut self, mut has_value: bool) {
+        self.has_value = has_value;
+
+        unsafe {
+            crate::mem::write_fixed(SSlice::_offset(self.ptr, HAS_VALUE_OFFSET), &mut has_value)
+        };
+    }
+
+    /// Updates a pointer to a child in-place, without rewriting the whole node
+    #[inline]
+    pub fn write_child_ptr(&mut self, idx: usize, mut ptr: StablePtr) {
+        self.children[idx].1 = ptr;
+
+        let offset = label_offset::<V>()
+            + self.label.len() as u64
+            + idx as u64 * CHILD_SIZE
+            + u8::SIZE as u64;
+
+        unsafe { crate::mem::write_fixed(SSlice::_offset(self.ptr, offset), &mut ptr) };
+    }
+}
+
+#[inline]
+pub(crate) fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b.iter()).take_while(|(x, y)| x == y).count()
+}
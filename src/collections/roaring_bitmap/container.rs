@@ -0,0 +1,391 @@
+use crate::encoding::AsFixedSizeBytes;
+use crate::mem::StablePtr;
+use crate::{allocate, deallocate, reallocate, OutOfMemory, SSlice};
+
+// LAYOUT:
+// array container (len <= ARRAY_MAX_LEN): [u16; len] -- sorted
+// bitmap container (len > ARRAY_MAX_LEN): [u8; BITMAP_SIZE] -- bit N is set if N is present
+//
+// Both kinds have the same maximum size, so a container is converted from one kind to another
+// in-place, without reallocation.
+
+pub(crate) const ARRAY_MAX_LEN: u32 = 4096;
+pub(crate) const BITMAP_SIZE: u64 = 8192;
+const ARRAY_MIN_SIZE: u64 = 8;
+
+/// A heap copy of a container's contents
+pub(crate) enum ContainerData {
+    Array(Vec<u16>),
+    Bitmap(Vec<u8>),
+}
+
+impl ContainerData {
+    pub fn len(&self) -> u32 {
+        match self {
+            ContainerData::Array(a) => a.len() as u32,
+            ContainerData::Bitmap(b) => b.iter().map(|it| it.count_ones()).sum(),
+        }
+    }
+
+    pub fn into_values(self) -> Vec<u16> {
+        match self {
+            ContainerData::Array(a) => a,
+            ContainerData::Bitmap(b) => bits_to_array(&b),
+        }
+    }
+
+    pub fn contains(&self, low: u16) -> bool {
+        match self {
+            ContainerData::Array(a) => a.binary_search(&low).is_ok(),
+            ContainerData::Bitmap(b) => b[(low / 8) as usize] & (1 << (low % 8)) != 0,
+        }
+    }
+
+    pub fn union(self, other: Self) -> Self {
+        match (self, other) {
+            (ContainerData::Array(a), ContainerData::Array(b)) => {
+                let mut result = Vec::with_capacity(a.len() + b.len());
+                let (mut i, mut j) = (0, 0);
+
+                while i < a.len() && j < b.len() {
+                    if a[i] < b[j] {
+                        result.push(a[i]);
+                        i += 1;
+                    } else if a[i] > b[j] {
+                        result.push(b[j]);
+                        j += 1;
+                    } else {
+                        result.push(a[i]);
+                        i += 1;
+                        j += 1;
+                    }
+                }
+
+                result.extend_from_slice(&a[i..]);
+                result.extend_from_slice(&b[j..]);
+
+                Self::from_array(result)
+            }
+            (a, b) => {
+                let mut bits = a.into_bits();
+                for (x, y) in bits.iter_mut().zip(b.into_bits()) {
+                    *x |= y;
+                }
+
+                ContainerData::Bitmap(bits)
+            }
+        }
+    }
+
+    pub fn intersection(self, other: Self) -> Self {
+        match (self, other) {
+            (ContainerData::Array(a), b) | (b, ContainerData::Array(a)) => {
+                ContainerData::Array(a.into_iter().filter(|it| b.contains(*it)).collect())
+            }
+            (ContainerData::Bitmap(mut a), ContainerData::Bitmap(b)) => {
+                for (x, y) in a.iter_mut().zip(b) {
+                    *x &= y;
+                }
+
+                Self::from_bits(a)
+            }
+        }
+    }
+
+    fn from_array(values: Vec<u16>) -> Self {
+        if values.len() as u32 <= ARRAY_MAX_LEN {
+            ContainerData::Array(values)
+        } else {
+            ContainerData::Bitmap(array_to_bits(&values))
+        }
+    }
+
+    fn from_bits(bits: Vec<u8>) -> Self {
+        let it = ContainerData::Bitmap(bits);
+
+        if it.len() <= ARRAY_MAX_LEN {
+            ContainerData::Array(it.into_values())
+        } else {
+            it
+        }
+    }
+
+    fn into_bits(self) -> Vec<u8> {
+        match self {
+            ContainerData::Array(a) => array_to_bits(&a),
+            ContainerData::Bitmap(b) => b,
+        }
+    }
+}
+
+/// A single container of a roaring bitmap, holding lower 16 bits of all values sharing the same
+/// upper bits
+pub(crate) struct Container {
+    pub ptr: StablePtr,
+    pub len: u32,
+}
+
+impl Container {
+    pub fn new(low: u16) -> Result<Self, OutOfMemory> {
+        let slice = unsafe { allocate(ARRAY_MIN_SIZE)? };
+        let mut it = Self {
+            ptr: slice.as_ptr(),
+            len: 1,
+        };
+
+        it.write_array_elem(0, low);
+
+        Ok(it)
+    }
+
+    /// Allocates a new container with the provided (non-empty) contents
+    pub fn create(data: &ContainerData) -> Result<Self, OutOfMemory> {
+        let slice = unsafe { allocate(Self::required_size(data))? };
+        let mut it = Self {
+            ptr: slice.as_ptr(),
+            len: 0,
+        };
+
+        it.write(data);
+
+        Ok(it)
+    }
+
+    #[inline]
+    pub fn from_parts(ptr: StablePtr, len: u32) -> Self {
+        Self { ptr, len }
+    }
+
+    #[inline]
+    pub fn is_array(&self) -> bool {
+        self.len <= ARRAY_MAX_LEN
+    }
+
+    pub fn contains(&self, low: u16) -> bool {
+        if self.is_array() {
+            self.search(low).is_ok()
+        } else {
+            self.read_byte(low) & (1 << (low % 8)) != 0
+        }
+    }
+
+    /// Returns `Ok(true)` if the value was not present before
+    pub fn insert(&mut self, low: u16) -> Result<bool, OutOfMemory> {
+        if !self.is_array() {
+            let byte = self.read_byte(low);
+            if byte & (1 << (low % 8)) != 0 {
+                return Ok(false);
+            }
+
+            self.write_byte(low, byte | (1 << (low % 8)));
+            self.len += 1;
+
+            return Ok(true);
+        }
+
+        let idx = match self.search(low) {
+            Ok(_) => return Ok(false),
+            Err(idx) => idx,
+        };
+
+        if self.len == ARRAY_MAX_LEN {
+            let mut bits = array_to_bits(&self.read_array());
+            bits[(low / 8) as usize] |= 1 << (low % 8);
+
+            unsafe { crate::mem::write_bytes(self.ptr, &bits) };
+            self.len += 1;
+
+            return Ok(true);
+        }
+
+        let slice = unsafe { SSlice::from_ptr(self.ptr).unwrap() };
+        let required_size = (self.len as u64 + 1) * u16::SIZE as u64;
+
+        if slice.get_size_bytes() < required_size {
+            let new_size = (required_size * 2).min(BITMAP_SIZE);
+            self.ptr = unsafe { reallocate(slice, new_size)?.as_ptr() };
+        }
+
+        let tail_len = (self.len - idx) as u64 * u16::SIZE as u64;
+        unsafe { crate::mem::copy_bytes(self.elem_ptr(idx), self.elem_ptr(idx + 1), tail_len) };
+
+        self.write_array_elem(idx, low);
+        self.len += 1;
+
+        Ok(true)
+    }
+
+    /// Returns `true` if the value was present
+    pub fn remove(&mut self, low: u16) -> bool {
+        if !self.is_array() {
+            let byte = self.read_byte(low);
+            if byte & (1 << (low % 8)) == 0 {
+                return false;
+            }
+
+            self.write_byte(low, byte & !(1 << (low % 8)));
+            self.len -= 1;
+
+            if self.len == ARRAY_MAX_LEN {
+                let mut bits = vec![0u8; BITMAP_SIZE as usize];
+                unsafe { crate::mem::read_bytes(self.ptr, &mut bits) };
+
+                let values = bits_to_array(&bits);
+                self.write_array(&values);
+            }
+
+            return true;
+        }
+
+        let idx = match self.search(low) {
+            Ok(idx) => idx,
+            Err(_) => return false,
+        };
+
+        let tail_len = (self.len - idx - 1) as u64 * u16::SIZE as u64;
+        unsafe { crate::mem::copy_bytes(self.elem_ptr(idx + 1), self.elem_ptr(idx), tail_len) };
+
+        self.len -= 1;
+
+        true
+    }
+
+    pub fn read(&self) -> ContainerData {
+        if self.is_array() {
+            ContainerData::Array(self.read_array())
+        } else {
+            let mut bits = vec![0u8; BITMAP_SIZE as usize];
+            unsafe { crate::mem::read_bytes(self.ptr, &mut bits) };
+
+            ContainerData::Bitmap(bits)
+        }
+    }
+
+    /// Replaces the contents of this container, reallocating it, if needed
+    ///
+    /// Leaves the container untouched, if the canister is out of stable memory.
+    pub fn replace(&mut self, data: &ContainerData) -> Result<(), OutOfMemory> {
+        let slice = unsafe { SSlice::from_ptr(self.ptr).unwrap() };
+        let required_size = Self::required_size(data);
+
+        if slice.get_size_bytes() < required_size {
+            self.ptr = unsafe { reallocate(slice, required_size)?.as_ptr() };
+        }
+
+        self.write(data);
+
+        Ok(())
+    }
+
+    #[inline]
+    pub fn destroy(self) {
+        let slice = unsafe { SSlice::from_ptr(self.ptr).unwrap() };
+        deallocate(slice);
+    }
+
+    fn write(&mut self, data: &ContainerData) {
+        match data {
+            ContainerData::Array(a) => self.write_array(a),
+            ContainerData::Bitmap(b) => {
+                unsafe { crate::mem::write_bytes(self.ptr, b) };
+                self.len = data.len();
+            }
+        }
+    }
+
+    fn required_size(data: &ContainerData) -> u64 {
+        match data {
+            ContainerData::Array(a) => (a.len() as u64 * u16::SIZE as u64).max(ARRAY_MIN_SIZE),
+            ContainerData::Bitmap(_) => BITMAP_SIZE,
+        }
+    }
+
+    fn search(&self, low: u16) -> Result<u32, u32> {
+        let mut lo = 0;
+        let mut hi = self.len;
+
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let it = self.read_array_elem(mid);
+
+            if it == low {
+                return Ok(mid);
+            }
+
+            if it < low {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+
+        Err(lo)
+    }
+
+    fn read_array(&self) -> Vec<u16> {
+        let mut buf = vec![0u8; self.len as usize * u16::SIZE];
+        unsafe { crate::mem::read_bytes(self.ptr, &mut buf) };
+
+        buf.chunks_exact(u16::SIZE)
+            .map(u16::from_fixed_size_bytes)
+            .collect()
+    }
+
+    fn write_array(&mut self, values: &[u16]) {
+        let mut buf = Vec::with_capacity(values.len() * u16::SIZE);
+        for it in values {
+            buf.extend_from_slice(&it.to_le_bytes());
+        }
+
+        unsafe { crate::mem::write_bytes(self.ptr, &buf) };
+        self.len = values.len() as u32;
+    }
+
+    #[inline]
+    fn elem_ptr(&self, idx: u32) -> StablePtr {
+        SSlice::_offset(self.ptr, idx as u64 * u16::SIZE as u64)
+    }
+
+    #[inline]
+    fn read_array_elem(&self, idx: u32) -> u16 {
+        unsafe { crate::mem::read_fixed_for_reference(self.elem_ptr(idx)) }
+    }
+
+    #[inline]
+    fn write_array_elem(&mut self, idx: u32, mut low: u16) {
+        unsafe { crate::mem::write_fixed(self.elem_ptr(idx), &mut low) };
+    }
+
+    #[inline]
+    fn read_byte(&self, low: u16) -> u8 {
+        unsafe { crate::mem::read_fixed_for_reference(SSlice::_offset(self.ptr, (low / 8) as u64)) }
+    }
+
+    #[inline]
+    fn write_byte(&mut self, low: u16, mut byte: u8) {
+        unsafe { crate::mem::write_fixed(SSlice::_offset(self.ptr, (low / 8) as u64), &mut byte) };
+    }
+}
+
+fn array_to_bits(values: &[u16]) -> Vec<u8> {
+    let mut bits = vec![0u8; BITMAP_SIZE as usize];
+    for it in values {
+        bits[(it / 8) as usize] |= 1 << (it % 8);
+    }
+
+    bits
+}
+
+fn bits_to_array(bits: &[u8]) -> Vec<u16> {
+    let mut values = Vec::new();
+
+    for (byte_idx, byte) in bits.iter().enumerate() {
+        for bit in 0..8 {
+            if byte & (1 << bit) != 0 {
+                values.push((byte_idx * 8 + bit) as u16);
+            }
+        }
+    }
+
+    values
+}
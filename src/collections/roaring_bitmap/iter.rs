@@ -0,0 +1,41 @@
+use crate::collections::btree_map::iter::SBTreeMapIter;
+use crate::collections::roaring_bitmap::container::Container;
+use crate::mem::StablePtr;
+
+pub struct SRoaringBitmapIter<'a> {
+    containers: SBTreeMapIter<'a, u64, (StablePtr, u32)>,
+    high: u64,
+    cur: std::vec::IntoIter<u16>,
+}
+
+impl<'a> SRoaringBitmapIter<'a> {
+    #[inline]
+    pub(crate) fn new(containers: SBTreeMapIter<'a, u64, (StablePtr, u32)>) -> Self {
+        Self {
+            containers,
+            high: 0,
+            cur: Vec::new().into_iter(),
+        }
+    }
+}
+
+impl<'a> Iterator for SRoaringBitmapIter<'a> {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(low) = self.cur.next() {
+                return Some((self.high << 16) | low as u64);
+            }
+
+            let (high, container) = self.containers.next()?;
+            let (ptr, len) = *container;
+
+            self.high = *high;
+            self.cur = Container::from_parts(ptr, len)
+                .read()
+                .into_values()
+                .into_iter();
+        }
+    }
+}
@@ -0,0 +1,534 @@
+use crate::collections::btree_map::SBTreeMap;
+use crate::collections::roaring_bitmap::container::{Container, ContainerData};
+use crate::collections::roaring_bitmap::iter::SRoaringBitmapIter;
+use crate::encoding::AsFixedSizeBytes;
+#[cfg(feature = "orphan_collector")]
+use crate::mem::orphan_collector::Trace;
+use crate::mem::StablePtr;
+use crate::primitive::StableType;
+use crate::OutOfMemory;
+use std::fmt::{Debug, Formatter};
+
+pub(crate) mod container;
+#[doc(hidden)]
+pub mod iter;
+
+/// Compressed (roaring-style) bitmap of `u64` identifiers
+///
+/// Identifiers are split into the upper 48 bits and the lower 16 bits. All identifiers sharing the
+/// same upper bits are stored in a single container in stable memory, which is either a sorted array
+/// of lower bits (if there are no more than `4096` of them), or a plain `8 KB` bitmap otherwise.
+/// Containers are indexed by an [SBTreeMap]. This way both sparse and dense sets of identifiers take
+/// a reasonable amount of stable memory, and unions and intersections are performed container by
+/// container, without touching individual bits.
+///
+/// `u32` identifiers can be stored by simply casting them to `u64`.
+///
+/// [SRoaringBitmap] implements [StableType] and [AsFixedSizeBytes], so you can nest it into other
+/// stable structures.
+///
+/// # Examples
+/// ```rust
+/// # use ic_stable_memory::collections::SRoaringBitmap;
+/// # use ic_stable_memory::stable_memory_init;
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// let mut rust = SRoaringBitmap::new();
+/// let mut wasm = SRoaringBitmap::new();
+///
+/// for doc_id in [1u64, 10, 100_000, 5_000_000_000] {
+///     rust.insert(doc_id).expect("Out of memory");
+/// }
+///
+/// for doc_id in [10u64, 20, 5_000_000_000] {
+///     wasm.insert(doc_id).expect("Out of memory");
+/// }
+///
+/// rust.intersect_with(&wasm);
+///
+/// assert_eq!(rust.iter().collect::<Vec<_>>(), vec![10, 5_000_000_000]);
+/// ```
+pub struct SRoaringBitmap {
+    containers: SBTreeMap<u64, (StablePtr, u32)>,
+    len: u64,
+}
+
+impl SRoaringBitmap {
+    /// Creates a new [SRoaringBitmap]
+    ///
+    /// Does not allocate any heap or stable memory.
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            containers: SBTreeMap::new(),
+            len: 0,
+        }
+    }
+
+    /// Adds the identifier to this [SRoaringBitmap]
+    ///
+    /// Returns `Ok(true)` if the identifier was not present before. If the canister is out of stable
+    /// memory, returns [Err] and leaves the bitmap untouched.
+    pub fn insert(&mut self, id: u64) -> Result<bool, OutOfMemory> {
+        let (high, low) = split(id);
+
+        if let Some(mut entry) = self.containers.get_mut(&high) {
+            let (ptr, len) = *entry;
+            let mut container = Container::from_parts(ptr, len);
+
+            let inserted = container.insert(low)?;
+            *entry = (container.ptr, container.len);
+
+            if inserted {
+                self.len += 1;
+            }
+
+            return Ok(inserted);
+        }
+
+        let container = Container::new(low)?;
+        if self
+            .containers
+            .insert(high, (container.ptr, container.len))
+            .is_err()
+        {
+            container.destroy();
+
+            return Err(OutOfMemory);
+        }
+
+        self.len += 1;
+
+        Ok(true)
+    }
+
+    /// Same as [SRoaringBitmap::insert], which already returns [OutOfMemory]
+    ///
+    /// Exists, so every collection has a `try_` variant of its inserting methods.
+    #[inline]
+    pub fn try_insert(&mut self, id: u64) -> Result<bool, OutOfMemory> {
+        self.insert(id)
+    }
+
+    /// Removes the identifier from this [SRoaringBitmap]
+    ///
+    /// Returns `true` if the identifier was present. Releases the container, once it becomes empty.
+    pub fn remove(&mut self, id: u64) -> bool {
+        let (high, low) = split(id);
+
+        let mut container = match self.containers.get(&high) {
+            Some(entry) => {
+                let (ptr, len) = *entry;
+                Container::from_parts(ptr, len)
+            }
+            None => return false,
+        };
+
+        if !container.remove(low) {
+            return false;
+        }
+
+        self.len -= 1;
+
+        if container.len == 0 {
+            container.destroy();
+            self.containers.remove(&high);
+        } else {
+            *self.containers.get_mut(&high).unwrap() = (container.ptr, container.len);
+        }
+
+        true
+    }
+
+    /// Returns `true` if the identifier is present in this [SRoaringBitmap]
+    pub fn contains(&self, id: u64) -> bool {
+        let (high, low) = split(id);
+
+        match self.containers.get(&high) {
+            Some(entry) => {
+                let (ptr, len) = *entry;
+                Container::from_parts(ptr, len).contains(low)
+            }
+            None => false,
+        }
+    }
+
+    /// Adds all identifiers of the other bitmap to this one
+    ///
+    /// If the canister is out of stable memory, returns [Err]. In that case this bitmap stays
+    /// consistent, but may only contain a part of the other bitmap's identifiers.
+    pub fn union_with(&mut self, other: &SRoaringBitmap) -> Result<(), OutOfMemory> {
+        for (high, entry) in other.containers.iter() {
+            let high = *high;
+            let (other_ptr, other_len) = *entry;
+            let other_data = Container::from_parts(other_ptr, other_len).read();
+
+            if let Some(mut entry) = self.containers.get_mut(&high) {
+                let (ptr, len) = *entry;
+                let mut container = Container::from_parts(ptr, len);
+
+                let data = container.read().union(other_data);
+                container.replace(&data)?;
+
+                *entry = (container.ptr, container.len);
+                self.len += (container.len - len) as u64;
+
+                continue;
+            }
+
+            let container = Container::create(&other_data)?;
+            if self
+                .containers
+                .insert(high, (container.ptr, container.len))
+                .is_err()
+            {
+                container.destroy();
+
+                return Err(OutOfMemory);
+            }
+
+            self.len += container.len as u64;
+        }
+
+        Ok(())
+    }
+
+    /// Removes all identifiers, which are not present in the other bitmap, from this one
+    ///
+    /// Never allocates.
+    pub fn intersect_with(&mut self, other: &SRoaringBitmap) {
+        let highs: Vec<u64> = self.containers.iter().map(|(high, _)| *high).collect();
+
+        for high in highs {
+            let (ptr, len) = *self.containers.get(&high).unwrap();
+            let mut container = Container::from_parts(ptr, len);
+
+            let data = match other.containers.get(&high) {
+                Some(entry) => {
+                    let (other_ptr, other_len) = *entry;
+                    let other_data = Container::from_parts(other_ptr, other_len).read();
+
+                    container.read().intersection(other_data)
+                }
+                None => ContainerData::Array(Vec::new()),
+            };
+
+            let new_len = data.len();
+            self.len -= (len - new_len) as u64;
+
+            if new_len == 0 {
+                container.destroy();
+                self.containers.remove(&high);
+
+                continue;
+            }
+
+            // an intersection never takes more space than the original container
+            container
+                .replace(&data)
+                .expect("Intersection should fit into the original container");
+
+            *self.containers.get_mut(&high).unwrap() = (container.ptr, container.len);
+        }
+    }
+
+    /// Returns the number of identifiers present in both bitmaps, without modifying any of them
+    pub fn intersection_len(&self, other: &SRoaringBitmap) -> u64 {
+        let (smaller, bigger) = if self.containers.len() <= other.containers.len() {
+            (self, other)
+        } else {
+            (other, self)
+        };
+
+        let mut result = 0;
+
+        for (high, entry) in smaller.containers.iter() {
+            let other_entry = match bigger.containers.get(&*high) {
+                Some(e) => e,
+                None => continue,
+            };
+
+            let (ptr, len) = *entry;
+            let (other_ptr, other_len) = *other_entry;
+
+            let data = Container::from_parts(ptr, len).read();
+            let other_data = Container::from_parts(other_ptr, other_len).read();
+
+            result += data.intersection(other_data).len() as u64;
+        }
+
+        result
+    }
+
+    /// Returns an iterator over all identifiers of this [SRoaringBitmap] in ascending order
+    #[inline]
+    pub fn iter(&self) -> SRoaringBitmapIter<'_> {
+        SRoaringBitmapIter::new(self.containers.iter())
+    }
+
+    /// Returns the number of identifiers in this [SRoaringBitmap]
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns `true` if there are no identifiers in this [SRoaringBitmap]
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Removes all identifiers from this [SRoaringBitmap], releasing all containers
+    pub fn clear(&mut self) {
+        for (_, entry) in self.containers.iter() {
+            let (ptr, len) = *entry;
+            Container::from_parts(ptr, len).destroy();
+        }
+
+        self.containers.clear();
+        self.len = 0;
+    }
+}
+
+#[inline]
+fn split(id: u64) -> (u64, u16) {
+    (id >> 16, id as u16)
+}
+
+impl Default for SRoaringBitmap {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AsFixedSizeBytes for SRoaringBitmap {
+    const SIZE: usize = SBTreeMap::<u64, (StablePtr, u32)>::SIZE + u64::SIZE;
+    type Buf = [u8; SBTreeMap::<u64, (StablePtr, u32)>::SIZE + u64::SIZE];
+
+    fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+        let map_size = SBTreeMap::<u64, (StablePtr, u32)>::SIZE;
+
+        self.containers.as_fixed_size_bytes(&mut buf[0..map_size]);
+        self.len
+            .as_fixed_size_bytes(&mut buf[map_size..(map_size + u64::SIZE)]);
+    }
+
+    fn from_fixed_size_bytes(buf: &[u8]) -> Self {
+        let map_size = SBTreeMap::<u64, (StablePtr, u32)>::SIZE;
+
+        Self {
+            containers: SBTreeMap::from_fixed_size_bytes(&buf[0..map_size]),
+            len: u64::from_fixed_size_bytes(&buf[map_size..(map_size + u64::SIZE)]),
+        }
+    }
+}
+
+impl StableType for SRoaringBitmap {
+    #[inline]
+    unsafe fn stable_drop_flag_off(&mut self) {
+        self.containers.stable_drop_flag_off();
+    }
+
+    #[inline]
+    unsafe fn stable_drop_flag_on(&mut self) {
+        self.containers.stable_drop_flag_on();
+    }
+
+    #[inline]
+    fn should_stable_drop(&self) -> bool {
+        self.containers.should_stable_drop()
+    }
+
+    // B-tree nodes are released by the container index itself
+    #[inline]
+    unsafe fn stable_drop(&mut self) {
+        self.clear();
+    }
+}
+
+impl Drop for SRoaringBitmap {
+    fn drop(&mut self) {
+        if self.should_stable_drop() {
+            unsafe {
+                self.stable_drop();
+            }
+        }
+    }
+}
+
+impl Debug for SRoaringBitmap {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("{")?;
+        for (idx, id) in self.iter().enumerate() {
+            id.fmt(f)?;
+
+            if idx < (self.len() - 1) as usize {
+                f.write_str(", ")?;
+            }
+        }
+        f.write_str("}")
+    }
+}
+
+#[cfg(feature = "orphan_collector")]
+impl Trace for SRoaringBitmap {
+    fn trace(&self, ptrs: &mut Vec<StablePtr>) {
+        self.containers.trace(ptrs);
+
+        for (_, container) in self.containers.iter() {
+            ptrs.push(container.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::roaring_bitmap::SRoaringBitmap;
+    use crate::{
+        _debug_validate_allocator, get_allocated_size, init_allocator, retrieve_custom_data,
+        stable, stable_memory_init, store_custom_data, SBox,
+    };
+    use rand::rngs::ThreadRng;
+    use rand::{thread_rng, Rng};
+    use std::collections::BTreeSet;
+
+    fn random_ids(rng: &mut ThreadRng, count: usize) -> Vec<u64> {
+        (0..count)
+            .map(|_| match rng.gen_range(0..3) {
+                // dense
+                0 => rng.gen_range(0..10_000u64),
+                // sparse
+                1 => rng.gen_range(0..u64::MAX),
+                // somewhere in between
+                _ => rng.gen_range(0..1_000_000u64),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut bitmap = SRoaringBitmap::new();
+            let mut example = BTreeSet::new();
+            let mut rng = thread_rng();
+
+            assert!(bitmap.is_empty());
+            assert!(!bitmap.contains(10));
+
+            for id in random_ids(&mut rng, 20_000) {
+                assert_eq!(bitmap.insert(id).unwrap(), example.insert(id));
+            }
+
+            // enough to switch the first container to the bitmap form and back
+            for id in 0..6000 {
+                assert_eq!(bitmap.insert(id).unwrap(), example.insert(id));
+            }
+
+            assert_eq!(bitmap.len(), example.len() as u64);
+            assert!(bitmap.iter().eq(example.iter().copied()));
+
+            for id in 0..6000 {
+                assert!(bitmap.contains(id));
+            }
+
+            store_custom_data(0, SBox::new(bitmap).unwrap());
+            let mut bitmap = retrieve_custom_data::<SRoaringBitmap>(0)
+                .unwrap()
+                .into_inner();
+
+            let ids: Vec<_> = example.iter().copied().collect();
+            for (idx, id) in ids.into_iter().enumerate() {
+                if idx % 3 == 0 {
+                    continue;
+                }
+
+                assert!(bitmap.remove(id));
+                assert!(!bitmap.remove(id));
+                example.remove(&id);
+            }
+
+            assert_eq!(bitmap.len(), example.len() as u64);
+            assert!(bitmap.iter().eq(example.iter().copied()));
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn set_operations_work_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut rng = thread_rng();
+
+            for _ in 0..10 {
+                let mut a = SRoaringBitmap::new();
+                let mut b = SRoaringBitmap::new();
+
+                let ids_a = random_ids(&mut rng, 5_000);
+                let ids_b = random_ids(&mut rng, 5_000);
+
+                let example_a: BTreeSet<_> = ids_a.iter().copied().collect();
+                let example_b: BTreeSet<_> = ids_b.iter().copied().collect();
+
+                for id in ids_a {
+                    a.insert(id).unwrap();
+                }
+
+                for id in ids_b {
+                    b.insert(id).unwrap();
+                }
+
+                let intersection: Vec<_> = example_a.intersection(&example_b).copied().collect();
+                assert_eq!(a.intersection_len(&b), intersection.len() as u64);
+
+                let mut c = SRoaringBitmap::new();
+                c.union_with(&a).unwrap();
+                c.intersect_with(&b);
+
+                assert_eq!(c.len(), intersection.len() as u64);
+                assert!(c.iter().eq(intersection.into_iter()));
+
+                a.union_with(&b).unwrap();
+
+                let union: Vec<_> = example_a.union(&example_b).copied().collect();
+                assert_eq!(a.len(), union.len() as u64);
+                assert!(a.iter().eq(union.into_iter()));
+            }
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn works_fine_limited_memory() {
+        stable::clear();
+        init_allocator(10);
+
+        {
+            let mut bitmap = SRoaringBitmap::new();
+            let mut example = BTreeSet::new();
+            let mut rng = thread_rng();
+
+            for id in random_ids(&mut rng, 100_000) {
+                match bitmap.insert(id) {
+                    Ok(inserted) => assert_eq!(inserted, example.insert(id)),
+                    Err(_) => assert!(!example.contains(&id)),
+                }
+            }
+
+            _debug_validate_allocator();
+            assert_eq!(bitmap.len(), example.len() as u64);
+            assert!(bitmap.iter().eq(example.iter().copied()));
+        }
+
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
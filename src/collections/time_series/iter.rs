@@ -0,0 +1,55 @@
+use crate::collections::time_series::STimeSeries;
+use crate::encoding::AsFixedSizeBytes;
+use crate::primitive::s_ref::SRef;
+use crate::primitive::StableType;
+
+pub struct STimeSeriesIter<'a, T: StableType + AsFixedSizeBytes> {
+    series: &'a STimeSeries<T>,
+    front: u64,
+    back: u64,
+}
+
+impl<'a, T: StableType + AsFixedSizeBytes> STimeSeriesIter<'a, T> {
+    #[inline]
+    pub(crate) fn new(series: &'a STimeSeries<T>, front: u64, back: u64) -> Self {
+        Self {
+            series,
+            front,
+            back,
+        }
+    }
+}
+
+impl<'a, T: StableType + AsFixedSizeBytes> Iterator for STimeSeriesIter<'a, T> {
+    type Item = (u64, SRef<'a, T>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.front >= self.back {
+            return None;
+        }
+
+        let it = self.series.get_entry(self.front);
+        self.front += 1;
+
+        Some(it)
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = (self.back - self.front) as usize;
+
+        (len, Some(len))
+    }
+}
+
+impl<'a, T: StableType + AsFixedSizeBytes> DoubleEndedIterator for STimeSeriesIter<'a, T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.front >= self.back {
+            return None;
+        }
+
+        self.back -= 1;
+
+        Some(self.series.get_entry(self.back))
+    }
+}
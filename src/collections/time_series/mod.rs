@@ -0,0 +1,675 @@
+use crate::collections::time_series::iter::STimeSeriesIter;
+use crate::collections::vec::SVec;
+use crate::encoding::{AsFixedSizeBytes, Buffer};
+#[cfg(feature = "orphan_collector")]
+use crate::mem::orphan_collector::Trace;
+use crate::mem::StablePtr;
+use crate::primitive::s_ref::SRef;
+use crate::primitive::StableType;
+use crate::{allocate, deallocate, OutOfMemory, SSlice};
+use std::fmt::{Debug, Formatter};
+use std::io::IoSlice;
+use std::marker::PhantomData;
+
+#[doc(hidden)]
+pub mod iter;
+
+pub(crate) const BLOCK_CAPACITY: u64 = 64;
+
+/// Append-optimized collection of values, indexed by `u64` timestamps
+///
+/// Entries are stored in sequential fixed-size blocks of stable memory (each holding up to `64`
+/// timestamp-value pairs), in the order they were appended. Pointers to these blocks are stored in
+/// an [SVec]. Since every block, but the first and the last one, is full, any entry can be located in
+/// O(1), and any timestamp can be located by a binary search in O(logN), without general B-tree
+/// nodes.
+///
+/// Timestamps should be appended in non-decreasing order. Old entries can be removed from the front
+/// with [STimeSeries::truncate_before] (e.g. to implement a retention policy) - fully emptied blocks
+/// are released immediately.
+///
+/// `T` has to implement both [StableType] and [AsFixedSizeBytes]. [STimeSeries] itself also implements
+/// these traits, so you can nest it into other stable structures.
+///
+/// # Examples
+/// ```rust
+/// # use ic_stable_memory::collections::STimeSeries;
+/// # use ic_stable_memory::stable_memory_init;
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// let mut metrics = STimeSeries::new();
+///
+/// for ts in 0..100u64 {
+///     metrics.append(ts * 10, ts).expect("Out of memory");
+/// }
+///
+/// let values: Vec<_> = metrics.range(200, 250).map(|(_, v)| *v).collect();
+/// assert_eq!(values, vec![20, 21, 22, 23, 24]);
+///
+/// // retention
+/// metrics.truncate_before(500);
+/// assert_eq!(metrics.first_timestamp(), Some(500));
+/// ```
+pub struct STimeSeries<T: StableType + AsFixedSizeBytes> {
+    blocks: SVec<StablePtr>,
+    first_block: u64,
+    head: u64,
+    len: u64,
+    _marker: PhantomData<T>,
+}
+
+impl<T: StableType + AsFixedSizeBytes> STimeSeries<T> {
+    /// Creates a new [STimeSeries]
+    ///
+    /// Does not allocate any heap or stable memory.
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            blocks: SVec::new(),
+            first_block: 0,
+            head: 0,
+            len: 0,
+            _marker: PhantomData::default(),
+        }
+    }
+
+    /// Appends a new entry to the end of this [STimeSeries]
+    ///
+    /// May allocate a new block. If the canister is out of stable memory, will return [Err] with
+    /// the value that was about to get appended.
+    ///
+    /// # Panics
+    /// Panics if the timestamp is less than the timestamp of the last entry.
+    pub fn append(&mut self, timestamp: u64, mut value: T) -> Result<(), T> {
+        if let Some(last) = self.last_timestamp() {
+            assert!(timestamp >= last, "Timestamps should be non-decreasing");
+        }
+
+        let pos = self.head + self.len;
+        let block_idx = self.first_block + pos / BLOCK_CAPACITY;
+
+        if block_idx == self.blocks.len() as u64 {
+            let block = match unsafe { allocate(BLOCK_CAPACITY * Self::entry_size()) } {
+                Ok(b) => b,
+                Err(_) => return Err(value),
+            };
+
+            if self.blocks.push(block.as_ptr()).is_err() {
+                deallocate(block);
+
+                return Err(value);
+            }
+        }
+
+        let ptr = self.entry_ptr(self.len);
+        self.len += 1;
+
+        // the timestamp and the value are adjacent, so they're written at once
+        unsafe {
+            value.stable_drop_flag_off();
+
+            crate::mem::write_bytes_vectored(
+                ptr,
+                &[
+                    IoSlice::new(timestamp.as_new_fixed_size_bytes()._deref()),
+                    IoSlice::new(value.as_new_fixed_size_bytes()._deref()),
+                ],
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Same as [STimeSeries::append], but returns [OutOfMemory] instead of the value
+    ///
+    /// Useful for propagating the error with `?`. The value gets dropped, if it can't be appended.
+    ///
+    /// # Panics
+    /// Panics if the timestamp is less than the timestamp of the last entry.
+    #[inline]
+    pub fn try_append(&mut self, timestamp: u64, value: T) -> Result<(), OutOfMemory> {
+        self.append(timestamp, value).map_err(|_| OutOfMemory)
+    }
+
+    /// Returns an iterator over entries with timestamps in `from..to`, in ascending order
+    ///
+    /// Locating the first entry takes O(logN).
+    #[inline]
+    pub fn range(&self, from: u64, to: u64) -> STimeSeriesIter<'_, T> {
+        let front = self.partition_point(from);
+        let back = if to > from {
+            self.partition_point(to)
+        } else {
+            front
+        };
+
+        STimeSeriesIter::new(self, front, back)
+    }
+
+    /// Returns an iterator over all entries, in ascending order of their timestamps
+    #[inline]
+    pub fn iter(&self) -> STimeSeriesIter<'_, T> {
+        STimeSeriesIter::new(self, 0, self.len)
+    }
+
+    /// Returns the entry at the requested index (`0` is the oldest one)
+    ///
+    /// If out of bounds, returns [None].
+    #[inline]
+    pub fn get(&self, idx: u64) -> Option<(u64, SRef<'_, T>)> {
+        if idx >= self.len {
+            return None;
+        }
+
+        Some(self.get_entry(idx))
+    }
+
+    /// Returns the timestamp of the oldest entry
+    #[inline]
+    pub fn first_timestamp(&self) -> Option<u64> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(self.read_timestamp(0))
+        }
+    }
+
+    /// Returns the timestamp of the most recent entry
+    #[inline]
+    pub fn last_timestamp(&self) -> Option<u64> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(self.read_timestamp(self.len - 1))
+        }
+    }
+
+    /// Removes all entries with timestamps less than the provided one, returning the number of removed
+    /// entries
+    ///
+    /// Releases all blocks that become empty. Never allocates.
+    #[inline]
+    pub fn truncate_before(&mut self, timestamp: u64) -> u64 {
+        let count = self.partition_point(timestamp);
+        self.truncate_front(count);
+
+        count
+    }
+
+    /// Removes `count` oldest entries (or all of them, if there are less entries)
+    ///
+    /// Releases all blocks that become empty. Never allocates.
+    pub fn truncate_front(&mut self, count: u64) {
+        let count = count.min(self.len);
+
+        for idx in 0..count {
+            let ptr = SSlice::_offset(self.entry_ptr(idx), u64::SIZE as u64);
+            let value: T = unsafe { crate::mem::read_fixed_for_move(ptr) };
+
+            drop(value);
+        }
+
+        let pos = self.head + count;
+        let dead_blocks = pos / BLOCK_CAPACITY;
+
+        for i in 0..dead_blocks {
+            let ptr = *self.blocks.get((self.first_block + i) as usize).unwrap();
+            deallocate(unsafe { SSlice::from_ptr(ptr).unwrap() });
+        }
+
+        self.first_block += dead_blocks;
+        self.head = pos % BLOCK_CAPACITY;
+        self.len -= count;
+
+        self.compact_blocks();
+    }
+
+    /// Removes all entries from this [STimeSeries], releasing all blocks
+    #[inline]
+    pub fn clear(&mut self) {
+        self.truncate_front(self.len);
+
+        if self.first_block < self.blocks.len() as u64 {
+            let ptr = *self.blocks.get(self.first_block as usize).unwrap();
+            deallocate(unsafe { SSlice::from_ptr(ptr).unwrap() });
+        }
+
+        self.blocks.clear();
+        self.first_block = 0;
+        self.head = 0;
+    }
+
+    /// Returns the number of entries in this [STimeSeries]
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns `true` if there are no entries in this [STimeSeries]
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    #[inline]
+    pub(crate) fn get_entry<'a>(&self, idx: u64) -> (u64, SRef<'a, T>) {
+        let ptr = self.entry_ptr(idx);
+        let timestamp = unsafe { crate::mem::read_fixed_for_reference(ptr) };
+
+        (timestamp, unsafe {
+            SRef::new(SSlice::_offset(ptr, u64::SIZE as u64))
+        })
+    }
+
+    #[inline]
+    fn read_timestamp(&self, idx: u64) -> u64 {
+        unsafe { crate::mem::read_fixed_for_reference(self.entry_ptr(idx)) }
+    }
+
+    #[inline]
+    fn entry_ptr(&self, idx: u64) -> StablePtr {
+        let pos = self.head + idx;
+        let block_ptr = *self
+            .blocks
+            .get((self.first_block + pos / BLOCK_CAPACITY) as usize)
+            .unwrap();
+
+        SSlice::_offset(block_ptr, (pos % BLOCK_CAPACITY) * Self::entry_size())
+    }
+
+    #[inline]
+    const fn entry_size() -> u64 {
+        (u64::SIZE + T::SIZE) as u64
+    }
+
+    // index of the first entry with timestamp >= the provided one
+    fn partition_point(&self, timestamp: u64) -> u64 {
+        let mut lo = 0;
+        let mut hi = self.len;
+
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+
+            if self.read_timestamp(mid) < timestamp {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+
+        lo
+    }
+
+    // removes pointers to released blocks, once they take at least a half of the block list
+    fn compact_blocks(&mut self) {
+        if self.first_block == 0 || self.first_block * 2 < self.blocks.len() as u64 {
+            return;
+        }
+
+        let first_block = self.first_block as usize;
+
+        for i in first_block..self.blocks.len() {
+            let ptr = *self.blocks.get(i).unwrap();
+            self.blocks.replace(i - first_block, ptr);
+        }
+
+        for _ in 0..first_block {
+            self.blocks.pop();
+        }
+
+        self.first_block = 0;
+    }
+}
+
+impl<T: StableType + AsFixedSizeBytes> Default for STimeSeries<T> {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: StableType + AsFixedSizeBytes> AsFixedSizeBytes for STimeSeries<T> {
+    const SIZE: usize = SVec::<StablePtr>::SIZE + u64::SIZE * 3;
+    type Buf = [u8; SVec::<StablePtr>::SIZE + u64::SIZE * 3];
+
+    fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+        let mut offset = 0;
+
+        self.blocks
+            .as_fixed_size_bytes(&mut buf[offset..(offset + SVec::<StablePtr>::SIZE)]);
+        offset += SVec::<StablePtr>::SIZE;
+
+        self.first_block
+            .as_fixed_size_bytes(&mut buf[offset..(offset + u64::SIZE)]);
+        offset += u64::SIZE;
+
+        self.head
+            .as_fixed_size_bytes(&mut buf[offset..(offset + u64::SIZE)]);
+        offset += u64::SIZE;
+
+        self.len
+            .as_fixed_size_bytes(&mut buf[offset..(offset + u64::SIZE)]);
+    }
+
+    fn from_fixed_size_bytes(buf: &[u8]) -> Self {
+        let mut offset = 0;
+
+        let blocks = SVec::<StablePtr>::from_fixed_size_bytes(
+            &buf[offset..(offset + SVec::<StablePtr>::SIZE)],
+        );
+        offset += SVec::<StablePtr>::SIZE;
+
+        let first_block = u64::from_fixed_size_bytes(&buf[offset..(offset + u64::SIZE)]);
+        offset += u64::SIZE;
+
+        let head = u64::from_fixed_size_bytes(&buf[offset..(offset + u64::SIZE)]);
+        offset += u64::SIZE;
+
+        let len = u64::from_fixed_size_bytes(&buf[offset..(offset + u64::SIZE)]);
+
+        Self {
+            blocks,
+            first_block,
+            head,
+            len,
+            _marker: PhantomData::default(),
+        }
+    }
+}
+
+impl<T: StableType + AsFixedSizeBytes> StableType for STimeSeries<T> {
+    #[inline]
+    unsafe fn stable_drop_flag_off(&mut self) {
+        self.blocks.stable_drop_flag_off();
+    }
+
+    #[inline]
+    unsafe fn stable_drop_flag_on(&mut self) {
+        self.blocks.stable_drop_flag_on();
+    }
+
+    #[inline]
+    fn should_stable_drop(&self) -> bool {
+        self.blocks.should_stable_drop()
+    }
+
+    // the block list itself is released by its own Drop
+    #[inline]
+    unsafe fn stable_drop(&mut self) {
+        self.clear();
+    }
+}
+
+impl<T: StableType + AsFixedSizeBytes> Drop for STimeSeries<T> {
+    fn drop(&mut self) {
+        if self.should_stable_drop() {
+            unsafe {
+                self.stable_drop();
+            }
+        }
+    }
+}
+
+impl<T: StableType + AsFixedSizeBytes + Debug> Debug for STimeSeries<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("[")?;
+        for (idx, (ts, v)) in self.iter().enumerate() {
+            ts.fmt(f)?;
+            f.write_str(": ")?;
+            v.fmt(f)?;
+
+            if idx < (self.len() - 1) as usize {
+                f.write_str(", ")?;
+            }
+        }
+        f.write_str("]")
+    }
+}
+
+#[cfg(feature = "orphan_collector")]
+impl<T: StableType + AsFixedSizeBytes + Trace> Trace for STimeSeries<T> {
+    fn trace(&self, ptrs: &mut Vec<StablePtr>) {
+        self.blocks.trace(ptrs);
+
+        for block in self.blocks.iter() {
+            ptrs.push(*block);
+        }
+
+        if T::OWNS_MEMORY {
+            for (_, value) in self.iter() {
+                value.trace(ptrs);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::time_series::{STimeSeries, BLOCK_CAPACITY};
+    use crate::utils::test::generate_random_string;
+    use crate::{
+        _debug_validate_allocator, get_allocated_size, init_allocator, retrieve_custom_data,
+        stable, stable_memory_init, stable_memory_post_upgrade, stable_memory_pre_upgrade,
+        store_custom_data, SBox,
+    };
+    use rand::rngs::ThreadRng;
+    use rand::{thread_rng, Rng};
+    use std::collections::VecDeque;
+
+    #[test]
+    fn works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut series = STimeSeries::new();
+            assert!(series.is_empty());
+            assert!(series.first_timestamp().is_none());
+            assert_eq!(series.range(0, 100).count(), 0);
+
+            for i in 0..1000u64 {
+                series.append(i * 2, i).unwrap();
+            }
+
+            assert_eq!(series.len(), 1000);
+            assert_eq!(series.first_timestamp(), Some(0));
+            assert_eq!(series.last_timestamp(), Some(1998));
+
+            let res: Vec<_> = series.range(99, 110).map(|(ts, v)| (ts, *v)).collect();
+            assert_eq!(
+                res,
+                vec![(100, 50), (102, 51), (104, 52), (106, 53), (108, 54)]
+            );
+
+            let res: Vec<_> = series.range(1990, 5000).rev().map(|(_, v)| *v).collect();
+            assert_eq!(res, vec![999, 998, 997, 996, 995]);
+
+            assert_eq!(series.range(10, 10).count(), 0);
+            assert_eq!(series.range(5000, 6000).count(), 0);
+
+            assert_eq!(
+                series.truncate_before(BLOCK_CAPACITY * 2 + 10),
+                BLOCK_CAPACITY + 5
+            );
+            assert_eq!(series.first_timestamp(), Some(BLOCK_CAPACITY * 2 + 10));
+            assert_eq!(*series.get(0).unwrap().1, BLOCK_CAPACITY + 5);
+
+            store_custom_data(0, SBox::new(series).unwrap());
+            let mut series = retrieve_custom_data::<STimeSeries<u64>>(0)
+                .unwrap()
+                .into_inner();
+
+            assert_eq!(series.len(), 1000 - BLOCK_CAPACITY - 5);
+            for (idx, (ts, v)) in series.iter().enumerate() {
+                assert_eq!(ts, *v * 2);
+                assert_eq!(*v, idx as u64 + BLOCK_CAPACITY + 5);
+            }
+
+            assert_eq!(series.truncate_before(u64::MAX), 1000 - BLOCK_CAPACITY - 5);
+            assert!(series.is_empty());
+
+            series.append(5000, 1).unwrap();
+            assert_eq!(series.last_timestamp(), Some(5000));
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn decreasing_timestamps_panic() {
+        stable::clear();
+        stable_memory_init();
+
+        let mut series = STimeSeries::new();
+        series.append(10, 1u64).unwrap();
+        series.append(9, 2u64).unwrap();
+    }
+
+    enum Action {
+        Append,
+        Truncate,
+        Clear,
+        CanisterUpgrade,
+    }
+
+    struct Fuzzer {
+        state: Option<STimeSeries<SBox<String>>>,
+        example: VecDeque<(u64, String)>,
+        now: u64,
+        rng: ThreadRng,
+        log: Vec<Action>,
+    }
+
+    impl Fuzzer {
+        fn new() -> Self {
+            Self {
+                state: Some(STimeSeries::default()),
+                example: VecDeque::default(),
+                now: 0,
+                rng: thread_rng(),
+                log: Vec::default(),
+            }
+        }
+
+        fn it(&mut self) -> &mut STimeSeries<SBox<String>> {
+            self.state.as_mut().unwrap()
+        }
+
+        fn next(&mut self) {
+            let action = self.rng.gen_range(0..101);
+
+            match action {
+                // APPEND ~80%
+                0..=80 => {
+                    let str = generate_random_string(&mut self.rng);
+                    self.now += self.rng.gen_range(0..3);
+
+                    if let Ok(data) = SBox::new(str.clone()) {
+                        let now = self.now;
+
+                        if self.it().append(now, data).is_ok() {
+                            self.example.push_back((now, str));
+                        }
+
+                        self.log.push(Action::Append);
+                    }
+                }
+                // TRUNCATE ~10%
+                81..=90 => {
+                    let cutoff = self.now.saturating_sub(self.rng.gen_range(0..200));
+                    let count = self.it().truncate_before(cutoff);
+
+                    let mut expected = 0;
+                    while matches!(self.example.front(), Some((ts, _)) if *ts < cutoff) {
+                        self.example.pop_front();
+                        expected += 1;
+                    }
+
+                    assert_eq!(count, expected);
+
+                    self.log.push(Action::Truncate);
+                }
+                // CLEAR
+                91..=92 => {
+                    self.it().clear();
+                    self.example.clear();
+
+                    self.log.push(Action::Clear);
+                }
+                // CANISTER UPGRADE
+                _ => match SBox::new(self.state.take().unwrap()) {
+                    Ok(data) => {
+                        store_custom_data(1, data);
+
+                        if stable_memory_pre_upgrade().is_ok() {
+                            stable_memory_post_upgrade();
+                        }
+
+                        self.state = retrieve_custom_data::<STimeSeries<SBox<String>>>(1)
+                            .map(|it| it.into_inner());
+
+                        self.log.push(Action::CanisterUpgrade);
+                    }
+                    Err(series) => {
+                        self.state = Some(series);
+                    }
+                },
+            }
+
+            _debug_validate_allocator();
+            assert_eq!(self.it().len(), self.example.len() as u64);
+
+            let from = self.now.saturating_sub(self.rng.gen_range(0..300));
+            let to = from + self.rng.gen_range(0..100);
+
+            let expected: Vec<_> = self
+                .example
+                .iter()
+                .filter(|(ts, _)| *ts >= from && *ts < to)
+                .cloned()
+                .collect();
+
+            let actual: Vec<_> = self
+                .it()
+                .range(from, to)
+                .map(|(ts, v)| (ts, v.clone()))
+                .collect();
+
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[test]
+    fn fuzzer_works_fine() {
+        stable::clear();
+        init_allocator(0);
+
+        {
+            let mut fuzzer = Fuzzer::new();
+
+            for _ in 0..10_000 {
+                fuzzer.next();
+            }
+        }
+
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn fuzzer_works_fine_limited_memory() {
+        stable::clear();
+        init_allocator(10);
+
+        {
+            let mut fuzzer = Fuzzer::new();
+
+            for _ in 0..10_000 {
+                fuzzer.next();
+            }
+        }
+
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
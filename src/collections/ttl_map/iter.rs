@@ -0,0 +1,58 @@
+use crate::collections::btree_map::iter::SBTreeMapIter;
+use crate::encoding::AsFixedSizeBytes;
+use crate::mem::s_slice::SSlice;
+use crate::primitive::s_ref::SRef;
+use crate::primitive::StableType;
+
+pub struct STtlMapIter<'a, K, V> {
+    inner: SBTreeMapIter<'a, K, (u64, V)>,
+    now: u64,
+}
+
+impl<'a, K, V> STtlMapIter<'a, K, V> {
+    #[inline]
+    pub(crate) fn new(inner: SBTreeMapIter<'a, K, (u64, V)>, now: u64) -> Self {
+        Self { inner, now }
+    }
+
+    // returns the value of the entry, if it has not expired yet
+    fn alive(&self, entry: &SRef<'a, (u64, V)>) -> Option<SRef<'a, V>> {
+        let expires_at: u64 = unsafe { crate::mem::read_fixed_for_reference(entry._ptr()) };
+
+        if expires_at > self.now {
+            Some(unsafe { SRef::new(SSlice::_offset(entry._ptr(), u64::SIZE as u64)) })
+        } else {
+            None
+        }
+    }
+}
+
+impl<'a, K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> Iterator
+    for STtlMapIter<'a, K, V>
+{
+    type Item = (SRef<'a, K>, SRef<'a, V>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (k, entry) = self.inner.next()?;
+
+            if let Some(v) = self.alive(&entry) {
+                return Some((k, v));
+            }
+        }
+    }
+}
+
+impl<'a, K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes>
+    DoubleEndedIterator for STtlMapIter<'a, K, V>
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        loop {
+            let (k, entry) = self.inner.next_back()?;
+
+            if let Some(v) = self.alive(&entry) {
+                return Some((k, v));
+            }
+        }
+    }
+}
@@ -0,0 +1,608 @@
+use crate::collections::btree_map::SBTreeMap;
+use crate::collections::ttl_map::iter::STtlMapIter;
+use crate::encoding::AsFixedSizeBytes;
+#[cfg(feature = "orphan_collector")]
+use crate::mem::orphan_collector::Trace;
+#[cfg(feature = "orphan_collector")]
+use crate::mem::StablePtr;
+use crate::primitive::s_ref::SRef;
+use crate::primitive::s_ref_mut::SRefMut;
+use crate::primitive::StableType;
+use crate::{OutOfMemory, SSlice};
+use std::fmt::{Debug, Formatter};
+use std::iter::Rev;
+
+#[doc(hidden)]
+pub mod iter;
+
+/// Map, which entries expire after some time
+///
+/// Each entry carries an expiration timestamp. This data structure has no notion of time by itself -
+/// all methods accept the current time as an argument (e.g. [ic_cdk::api::time]), so it works the same
+/// way in canisters and in tests.
+///
+/// Expired entries are treated as absent by all reading methods ("lazy expiry"), but their stable
+/// memory is only released when they are overwritten, removed or swept. Call [STtlMap::sweep] from a
+/// timer, to reclaim memory of expired entries in batches of limited size, without exceeding the
+/// instruction limit.
+///
+/// Internally uses two [SBTreeMap]s - one for the entries and one for the expiration queue, so
+/// keys are stored twice. This is why `K` has to implement [Clone] (which also means that keys can't
+/// own stable memory). Both `K` and `V` have to implement [StableType] and [AsFixedSizeBytes]. [STtlMap]
+/// also implements these traits, so you can nest it into other stable structures.
+///
+/// # Examples
+/// ```rust
+/// # use ic_stable_memory::collections::STtlMap;
+/// # use ic_stable_memory::stable_memory_init;
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// let mut sessions = STtlMap::new();
+/// let now = 1_000u64;
+///
+/// sessions.insert_with_ttl(1u64, 100u64, now, 60).expect("Out of memory");
+///
+/// assert_eq!(*sessions.get(&1, now + 59).unwrap(), 100);
+/// assert!(sessions.get(&1, now + 60).is_none());
+///
+/// // somewhere in a timer
+/// assert_eq!(sessions.sweep(now + 60, 1000), 1);
+/// assert!(sessions.is_empty());
+/// ```
+pub struct STtlMap<
+    K: StableType + AsFixedSizeBytes + Ord + Clone,
+    V: StableType + AsFixedSizeBytes,
+> {
+    entries: SBTreeMap<K, (u64, V)>,
+    expirations: SBTreeMap<(u64, K), ()>,
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord + Clone, V: StableType + AsFixedSizeBytes>
+    STtlMap<K, V>
+{
+    /// Creates a new [STtlMap]
+    ///
+    /// Does not allocate any heap or stable memory.
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            entries: SBTreeMap::new(),
+            expirations: SBTreeMap::new(),
+        }
+    }
+
+    /// Inserts a key-value pair, which expires at `now + ttl`
+    ///
+    /// May allocate stable and heap memory. If your canister is out of stable memory, will return
+    /// [Err] with the key-value pair that was about to get inserted.
+    ///
+    /// If the insertion is successful, returns [Option] with a value, that was previously stored
+    /// under this key and has not expired yet.
+    #[inline]
+    pub fn insert_with_ttl(
+        &mut self,
+        key: K,
+        value: V,
+        now: u64,
+        ttl: u64,
+    ) -> Result<Option<V>, (K, V)> {
+        self.insert_with_deadline(key, value, now.saturating_add(ttl), now)
+    }
+
+    /// Inserts a key-value pair, which never expires
+    ///
+    /// See also [STtlMap::insert_with_ttl].
+    #[inline]
+    pub fn insert(&mut self, key: K, value: V, now: u64) -> Result<Option<V>, (K, V)> {
+        self.insert_with_deadline(key, value, u64::MAX, now)
+    }
+
+    /// Same as [STtlMap::insert_with_ttl], but returns [OutOfMemory] instead of the key-value pair
+    ///
+    /// Useful for propagating the error with `?`. The pair gets dropped, if it can't be inserted.
+    #[inline]
+    pub fn try_insert_with_ttl(
+        &mut self,
+        key: K,
+        value: V,
+        now: u64,
+        ttl: u64,
+    ) -> Result<Option<V>, OutOfMemory> {
+        self.insert_with_ttl(key, value, now, ttl)
+            .map_err(|_| OutOfMemory)
+    }
+
+    /// Same as [STtlMap::insert], but returns [OutOfMemory] instead of the key-value pair
+    ///
+    /// Useful for propagating the error with `?`. The pair gets dropped, if it can't be inserted.
+    #[inline]
+    pub fn try_insert(&mut self, key: K, value: V, now: u64) -> Result<Option<V>, OutOfMemory> {
+        self.insert(key, value, now).map_err(|_| OutOfMemory)
+    }
+
+    fn insert_with_deadline(
+        &mut self,
+        key: K,
+        value: V,
+        expires_at: u64,
+        now: u64,
+    ) -> Result<Option<V>, (K, V)> {
+        if self.expirations.insert((expires_at, key.clone()), ()).is_err() {
+            return Err((key, value));
+        }
+
+        let key_copy = key.clone();
+
+        match self.entries.insert(key, (expires_at, value)) {
+            Ok(None) => Ok(None),
+            Ok(Some((prev_expires_at, prev_value))) => {
+                if prev_expires_at != expires_at {
+                    self.expirations.remove(&(prev_expires_at, key_copy));
+                }
+
+                if prev_expires_at > now {
+                    Ok(Some(prev_value))
+                } else {
+                    Ok(None)
+                }
+            }
+            // the key was not present, otherwise the insertion would not allocate
+            Err((key, (_, value))) => {
+                self.expirations.remove(&(expires_at, key_copy));
+
+                Err((key, value))
+            }
+        }
+    }
+
+    /// Removes the entry by its key, returning its value, if it has not expired yet
+    ///
+    /// Releases the memory of the entry even if it has expired.
+    pub fn remove(&mut self, key: &K, now: u64) -> Option<V> {
+        let (expires_at, value) = self.entries.remove(key)?;
+        self.expirations.remove(&(expires_at, key.clone()));
+
+        if expires_at > now {
+            Some(value)
+        } else {
+            None
+        }
+    }
+
+    /// Returns an immutable reference to the value stored by the key, if it has not expired yet
+    pub fn get(&self, key: &K, now: u64) -> Option<SRef<'_, V>> {
+        let ptr = self.find_alive_value_ptr(key, now)?;
+
+        unsafe { Some(SRef::new(ptr)) }
+    }
+
+    /// Returns a mutable reference to the value stored by the key, if it has not expired yet
+    pub fn get_mut(&mut self, key: &K, now: u64) -> Option<SRefMut<'_, V>> {
+        let ptr = self.find_alive_value_ptr(key, now)?;
+
+        unsafe { Some(SRefMut::new(ptr)) }
+    }
+
+    /// Returns `true` if there is an entry by this key, which has not expired yet
+    #[inline]
+    pub fn contains_key(&self, key: &K, now: u64) -> bool {
+        self.find_alive_value_ptr(key, now).is_some()
+    }
+
+    /// Returns the expiration timestamp of the entry, if it has not expired yet
+    pub fn expires_at(&self, key: &K, now: u64) -> Option<u64> {
+        let entry = self.entries.get(key)?;
+        let expires_at: u64 = unsafe { crate::mem::read_fixed_for_reference(entry._ptr()) };
+
+        if expires_at > now {
+            Some(expires_at)
+        } else {
+            None
+        }
+    }
+
+    /// Removes up to `max_items` expired entries, releasing their memory
+    ///
+    /// Returns the number of removed entries. Entries are removed in the order of their expiration,
+    /// so calling this method repeatedly (e.g. from a timer) eventually reclaims all expired entries,
+    /// while each call performs a bounded amount of work. Never allocates.
+    pub fn sweep(&mut self, now: u64, max_items: u64) -> u64 {
+        let mut removed = 0;
+
+        while removed < max_items {
+            let (expires_at, key) = match self.expirations.iter().next() {
+                Some((it, _)) => it.clone(),
+                None => break,
+            };
+
+            if expires_at > now {
+                break;
+            }
+
+            self.expirations.remove(&(expires_at, key.clone()));
+            self.entries.remove(&key);
+
+            removed += 1;
+        }
+
+        removed
+    }
+
+    /// Returns an iterator over all entries, which have not expired yet, in ascending order of keys
+    #[inline]
+    pub fn iter(&self, now: u64) -> STtlMapIter<'_, K, V> {
+        STtlMapIter::new(self.entries.iter(), now)
+    }
+
+    /// Returns an iterator over all entries, which have not expired yet, in descending order of keys
+    #[inline]
+    pub fn iter_rev(&self, now: u64) -> Rev<STtlMapIter<'_, K, V>> {
+        self.iter(now).rev()
+    }
+
+    /// Returns the number of entries in this [STtlMap], including expired ones, which were not swept yet
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.entries.len()
+    }
+
+    /// Returns `true` if there are no entries in this [STtlMap], including expired ones
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Removes all entries from this [STtlMap], releasing all occupied stable memory
+    #[inline]
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.expirations.clear();
+    }
+
+    fn find_alive_value_ptr(&self, key: &K, now: u64) -> Option<u64> {
+        let entry = self.entries.get(key)?;
+        let expires_at: u64 = unsafe { crate::mem::read_fixed_for_reference(entry._ptr()) };
+
+        if expires_at > now {
+            Some(SSlice::_offset(entry._ptr(), u64::SIZE as u64))
+        } else {
+            None
+        }
+    }
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord + Clone, V: StableType + AsFixedSizeBytes> Default
+    for STtlMap<K, V>
+{
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord + Clone, V: StableType + AsFixedSizeBytes>
+    AsFixedSizeBytes for STtlMap<K, V>
+{
+    const SIZE: usize = u64::SIZE * 4;
+    type Buf = [u8; u64::SIZE * 4];
+
+    fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+        let size = u64::SIZE * 2;
+
+        self.entries.as_fixed_size_bytes(&mut buf[0..size]);
+        self.expirations
+            .as_fixed_size_bytes(&mut buf[size..(size * 2)]);
+    }
+
+    fn from_fixed_size_bytes(buf: &[u8]) -> Self {
+        let size = u64::SIZE * 2;
+
+        Self {
+            entries: SBTreeMap::from_fixed_size_bytes(&buf[0..size]),
+            expirations: SBTreeMap::from_fixed_size_bytes(&buf[size..(size * 2)]),
+        }
+    }
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord + Clone, V: StableType + AsFixedSizeBytes> StableType
+    for STtlMap<K, V>
+{
+    #[inline]
+    unsafe fn stable_drop_flag_off(&mut self) {
+        self.entries.stable_drop_flag_off();
+        self.expirations.stable_drop_flag_off();
+    }
+
+    #[inline]
+    unsafe fn stable_drop_flag_on(&mut self) {
+        self.entries.stable_drop_flag_on();
+        self.expirations.stable_drop_flag_on();
+    }
+}
+
+impl<
+        K: StableType + AsFixedSizeBytes + Ord + Clone + Debug,
+        V: StableType + AsFixedSizeBytes + Debug,
+    > Debug for STtlMap<K, V>
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("{")?;
+        for (idx, (k, entry)) in self.entries.iter().enumerate() {
+            let (expires_at, v) = &*entry;
+
+            k.fmt(f)?;
+            f.write_str(": ")?;
+            v.fmt(f)?;
+            f.write_str(" (expires at ")?;
+            expires_at.fmt(f)?;
+            f.write_str(")")?;
+
+            if idx < (self.len() - 1) as usize {
+                f.write_str(", ")?;
+            }
+        }
+        f.write_str("}")
+    }
+}
+
+#[cfg(feature = "orphan_collector")]
+impl<
+        K: StableType + AsFixedSizeBytes + Ord + Clone + Trace,
+        V: StableType + AsFixedSizeBytes + Trace,
+    > Trace for STtlMap<K, V>
+{
+    fn trace(&self, ptrs: &mut Vec<StablePtr>) {
+        self.entries.trace(ptrs);
+        self.expirations.trace(ptrs);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::ttl_map::STtlMap;
+    use crate::utils::test::generate_random_string;
+    use crate::{
+        _debug_validate_allocator, get_allocated_size, init_allocator, retrieve_custom_data,
+        stable, stable_memory_init, stable_memory_post_upgrade, stable_memory_pre_upgrade,
+        store_custom_data, SBox,
+    };
+    use rand::rngs::ThreadRng;
+    use rand::{thread_rng, Rng};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut map = STtlMap::new();
+
+            assert!(map.insert_with_ttl(1u64, 10u64, 0, 100).unwrap().is_none());
+            assert!(map.insert_with_ttl(2u64, 20u64, 0, 50).unwrap().is_none());
+            assert!(map.insert(3u64, 30u64, 0).unwrap().is_none());
+
+            assert_eq!(*map.get(&2, 49).unwrap(), 20);
+            assert!(map.get(&2, 50).is_none());
+            assert!(!map.contains_key(&2, 50));
+            assert_eq!(map.expires_at(&1, 0), Some(100));
+
+            // overwriting an expired entry does not return it
+            assert!(map.insert_with_ttl(2u64, 21u64, 60, 50).unwrap().is_none());
+            assert_eq!(map.insert_with_ttl(2u64, 22u64, 60, 50).unwrap(), Some(21));
+            assert_eq!(map.len(), 3);
+
+            *map.get_mut(&3, 1000).unwrap() = 31;
+
+            let alive: Vec<_> = map.iter(105).map(|(k, v)| (*k, *v)).collect();
+            assert_eq!(alive, vec![(2, 22), (3, 31)]);
+
+            let alive: Vec<_> = map.iter_rev(105).map(|(k, _)| *k).collect();
+            assert_eq!(alive, vec![3, 2]);
+            assert_eq!(
+                map.iter_rev(50).map(|(k, _)| *k).collect::<Vec<_>>(),
+                vec![3, 2, 1]
+            );
+
+            store_custom_data(0, SBox::new(map).unwrap());
+            let mut map = retrieve_custom_data::<STtlMap<u64, u64>>(0)
+                .unwrap()
+                .into_inner();
+
+            assert_eq!(map.sweep(105, 10), 1);
+            assert_eq!(map.len(), 2);
+
+            assert_eq!(map.sweep(u64::MAX - 1, 10), 1);
+            assert_eq!(map.len(), 1);
+            assert_eq!(map.remove(&3, u64::MAX - 1), Some(31));
+
+            assert!(map.is_empty());
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    enum Action {
+        Insert,
+        Remove,
+        Sweep,
+        Clear,
+        CanisterUpgrade,
+    }
+
+    struct Fuzzer {
+        state: Option<STtlMap<u64, SBox<String>>>,
+        example: BTreeMap<u64, (u64, String)>,
+        now: u64,
+        keys: Vec<u64>,
+        rng: ThreadRng,
+        log: Vec<Action>,
+    }
+
+    impl Fuzzer {
+        fn new() -> Self {
+            Self {
+                state: Some(STtlMap::default()),
+                example: BTreeMap::default(),
+                now: 0,
+                keys: Vec::default(),
+                rng: thread_rng(),
+                log: Vec::default(),
+            }
+        }
+
+        fn it(&mut self) -> &mut STtlMap<u64, SBox<String>> {
+            self.state.as_mut().unwrap()
+        }
+
+        fn next(&mut self) {
+            let action = self.rng.gen_range(0..101);
+            self.now += self.rng.gen_range(0..5);
+            let now = self.now;
+
+            match action {
+                // INSERT ~60%
+                0..=59 => {
+                    let key = self.rng.gen_range(0..500u64);
+                    let ttl = self.rng.gen_range(0..100u64);
+                    let str = generate_random_string(&mut self.rng);
+
+                    if let Ok(data) = SBox::new(str.clone()) {
+                        if let Ok(prev) = self.it().insert_with_ttl(key, data, now, ttl) {
+                            let expected = self
+                                .example
+                                .insert(key, (now + ttl, str))
+                                .filter(|(expires_at, _)| *expires_at > now)
+                                .map(|(_, it)| it);
+
+                            assert_eq!(prev.map(|it| it.clone()), expected);
+                            self.keys.push(key);
+                        }
+
+                        self.log.push(Action::Insert);
+                    }
+                }
+                // REMOVE ~20%
+                60..=79 => {
+                    if self.keys.is_empty() {
+                        return;
+                    }
+
+                    let key = self.keys[self.rng.gen_range(0..self.keys.len())];
+
+                    let expected = self
+                        .example
+                        .remove(&key)
+                        .filter(|(expires_at, _)| *expires_at > now)
+                        .map(|(_, it)| it);
+
+                    assert_eq!(self.it().remove(&key, now).map(|it| it.clone()), expected);
+
+                    self.log.push(Action::Remove);
+                }
+                // SWEEP ~10%
+                80..=89 => {
+                    let max_items = self.rng.gen_range(0..20);
+                    let expired = self
+                        .example
+                        .values()
+                        .filter(|(expires_at, _)| *expires_at <= now)
+                        .count() as u64;
+
+                    let removed = self.it().sweep(now, max_items);
+                    assert_eq!(removed, expired.min(max_items));
+
+                    let mut expired_keys: Vec<_> = self
+                        .example
+                        .iter()
+                        .filter(|(_, (expires_at, _))| *expires_at <= now)
+                        .map(|(k, (expires_at, _))| (*expires_at, *k))
+                        .collect();
+                    expired_keys.sort();
+
+                    for (_, k) in expired_keys.into_iter().take(removed as usize) {
+                        self.example.remove(&k);
+                    }
+
+                    self.log.push(Action::Sweep);
+                }
+                // CLEAR
+                90..=91 => {
+                    self.it().clear();
+                    self.example.clear();
+
+                    self.log.push(Action::Clear);
+                }
+                // CANISTER UPGRADE
+                _ => match SBox::new(self.state.take().unwrap()) {
+                    Ok(data) => {
+                        store_custom_data(1, data);
+
+                        if stable_memory_pre_upgrade().is_ok() {
+                            stable_memory_post_upgrade();
+                        }
+
+                        self.state = retrieve_custom_data::<STtlMap<u64, SBox<String>>>(1)
+                            .map(|it| it.into_inner());
+
+                        self.log.push(Action::CanisterUpgrade);
+                    }
+                    Err(map) => {
+                        self.state = Some(map);
+                    }
+                },
+            }
+
+            _debug_validate_allocator();
+            assert_eq!(self.it().len(), self.example.len() as u64);
+
+            let expected: Vec<_> = self
+                .example
+                .iter()
+                .filter(|(_, (expires_at, _))| *expires_at > now)
+                .map(|(k, (_, v))| (*k, v.clone()))
+                .collect();
+
+            let actual: Vec<_> = self
+                .it()
+                .iter(now)
+                .map(|(k, v)| (*k, v.clone()))
+                .collect();
+
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[test]
+    fn fuzzer_works_fine() {
+        stable::clear();
+        init_allocator(0);
+
+        {
+            let mut fuzzer = Fuzzer::new();
+
+            for _ in 0..10_000 {
+                fuzzer.next();
+            }
+        }
+
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn fuzzer_works_fine_limited_memory() {
+        stable::clear();
+        init_allocator(10);
+
+        {
+            let mut fuzzer = Fuzzer::new();
+
+            for _ in 0..10_000 {
+                fuzzer.next();
+            }
+        }
+
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
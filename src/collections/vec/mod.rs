@@ -1,12 +1,28 @@
 use crate::collections::vec::iter::SVecIter;
 use crate::encoding::{AsFixedSizeBytes, Buffer};
 use crate::mem::allocator::EMPTY_PTR;
+#[cfg(feature = "orphan_collector")]
+use crate::mem::orphan_collector::{memory_usage, MemoryUsage, Trace};
 use crate::mem::s_slice::SSlice;
 use crate::mem::StablePtr;
 use crate::primitive::s_ref::SRef;
 use crate::primitive::s_ref_mut::SRefMut;
-use crate::primitive::StableType;
+use crate::primitive::{DeepCopy, StableType};
+use crate::utils::budget::{Budget, ResumableIter};
+#[cfg(feature = "candid_chunks")]
+use crate::utils::candid_chunks::{encode_chunks, CandidChunksError};
+use crate::utils::DEBUG_ELEMENTS_LIMIT;
 use crate::{allocate, deallocate, reallocate, OutOfMemory};
+#[cfg(feature = "candid_chunks")]
+use candid::CandidType;
+#[cfg(feature = "candid_chunks")]
+use serde::de::DeserializeOwned;
+#[cfg(feature = "serde_collections")]
+use serde::de::{self, SeqAccess, Visitor};
+#[cfg(feature = "serde_collections")]
+use serde::ser::SerializeSeq;
+#[cfg(feature = "serde_collections")]
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::cmp::Ordering;
 use std::fmt::{Debug, Formatter};
 use std::marker::PhantomData;
@@ -130,6 +146,14 @@ impl<T: StableType + AsFixedSizeBytes> SVec<T> {
         }
     }
 
+    /// Same as [SVec::push], but returns [OutOfMemory] instead of the element
+    ///
+    /// Useful for propagating the error with `?`. The element gets dropped, if it can't be inserted.
+    #[inline]
+    pub fn try_push(&mut self, element: T) -> Result<(), OutOfMemory> {
+        self.push(element).map_err(|_| OutOfMemory)
+    }
+
     /// Removes the last element of the [SVec]
     ///
     /// If the [SVec] is empty, returns [None].
@@ -259,6 +283,17 @@ impl<T: StableType + AsFixedSizeBytes> SVec<T> {
         }
     }
 
+    /// Same as [SVec::insert], but returns [OutOfMemory] instead of the element
+    ///
+    /// The element gets dropped, if it can't be inserted.
+    ///
+    /// # Panics
+    /// Panics if out of bounds.
+    #[inline]
+    pub fn try_insert(&mut self, idx: usize, element: T) -> Result<(), OutOfMemory> {
+        self.insert(idx, element).map_err(|_| OutOfMemory)
+    }
+
     /// Removes element at the requested index, back-shifting all elements after it
     ///
     /// # Panics
@@ -313,6 +348,79 @@ impl<T: StableType + AsFixedSizeBytes> SVec<T> {
         while self.pop().is_some() {}
     }
 
+    /// Shrinks the capacity of this [SVec] to its length, releasing unused stable memory
+    ///
+    /// Never allocates and never moves the data. Useful after removing a lot of elements, since
+    /// [SVec] never shrinks on its own.
+    pub fn shrink_to_fit(&mut self) {
+        if self.ptr == EMPTY_PTR || self.len == self.cap {
+            return;
+        }
+
+        let slice = unsafe { SSlice::from_ptr(self.ptr).unwrap() };
+
+        if self.is_empty() {
+            deallocate(slice);
+
+            self.ptr = EMPTY_PTR;
+            self.cap = DEFAULT_CAPACITY;
+
+            return;
+        }
+
+        self.ptr = unsafe { reallocate(slice, (self.len * T::SIZE) as u64) }
+            .expect("Shrinking never allocates")
+            .as_ptr();
+        self.cap = self.len;
+    }
+
+    /// Splits this [SVec] into two at the given index, returning the elements `[at, len)` in a new
+    /// [SVec]
+    ///
+    /// The tail is moved with [copy_bytes](crate::mem::copy_bytes) into a freshly allocated block of
+    /// exactly its length, without buffering it on the heap. This [SVec] keeps its capacity. If the canister is out of stable memory, returns
+    /// [OutOfMemory] and leaves this [SVec] untouched.
+    ///
+    /// # Panics
+    /// Panics if `at > len`.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use ic_stable_memory::collections::SVec;
+    /// # use ic_stable_memory::stable_memory_init;
+    /// # unsafe { ic_stable_memory::mem::clear(); }
+    /// # stable_memory_init();
+    /// let mut vec = SVec::new();
+    ///
+    /// for i in 0..10 {
+    ///     vec.push(i).expect("Out of memory");
+    /// }
+    ///
+    /// let tail = vec.split_off(7).expect("Out of memory");
+    ///
+    /// assert_eq!(vec.len(), 7);
+    /// assert_eq!(tail.iter().map(|it| *it).collect::<Vec<_>>(), vec![7, 8, 9]);
+    /// ```
+    pub fn split_off(&mut self, at: usize) -> Result<Self, OutOfMemory> {
+        assert!(at <= self.len, "out of bounds");
+
+        if at == self.len {
+            return Ok(Self::new());
+        }
+
+        let tail_len = self.len - at;
+        let mut tail = Self::new_with_capacity(tail_len)?;
+
+        let from_ptr = SSlice::_offset(self.ptr, (at * T::SIZE) as u64);
+
+        unsafe { crate::mem::copy_bytes(from_ptr, tail.ptr, (tail_len * T::SIZE) as u64) };
+
+        tail.len = tail_len;
+        self.len = at;
+
+        Ok(tail)
+    }
+
     /// Performs binary search on a sorted [SVec], using the provided lambda
     ///
     /// Works the same way as in [Vec].
@@ -402,6 +510,57 @@ impl<T: StableType + AsFixedSizeBytes> SVec<T> {
         SVecIter::new(self)
     }
 
+    /// Returns an iterator over `(index, element)` pairs, starting from index `from`, that stops
+    /// once the `budget` is exhausted
+    ///
+    /// The [continuation](ResumableIter::continuation) of the iterator is the index the next call
+    /// should start from, or [None] if the end of this collection was reached.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use ic_stable_memory::collections::SVec;
+    /// # use ic_stable_memory::stable_memory_init;
+    /// # use ic_stable_memory::utils::budget::Budget;
+    /// # unsafe { ic_stable_memory::mem::clear(); }
+    /// # stable_memory_init();
+    /// let mut vec = SVec::new();
+    ///
+    /// for i in 0..100 {
+    ///     vec.push(i).expect("Out of memory");
+    /// }
+    ///
+    /// let mut sum = 0;
+    /// let mut from = 0;
+    /// loop {
+    ///     let mut iter = vec.iter_resumable(from, Budget::Elements(30));
+    ///     for (_, elem) in &mut iter {
+    ///         sum += *elem;
+    ///     }
+    ///
+    ///     from = match iter.into_continuation() {
+    ///         Some(idx) => idx,
+    ///         None => break,
+    ///     };
+    /// }
+    ///
+    /// assert_eq!(sum, 4950);
+    /// ```
+    pub fn iter_resumable<'a>(
+        &'a self,
+        from: usize,
+        budget: Budget,
+    ) -> ResumableIter<
+        impl Iterator<Item = (usize, SRef<'a, T>)> + 'a,
+        usize,
+        fn(&(usize, SRef<'a, T>)) -> usize,
+    > {
+        let inner = (from..self.len()).map(move |idx| (idx, self.get(idx).unwrap()));
+
+        let token_of: fn(&(usize, SRef<'a, T>)) -> usize = |(idx, _)| *idx;
+
+        ResumableIter::new(inner, budget, token_of)
+    }
+
     /// Prints byte representation of this collection
     ///
     /// Useful for tests
@@ -433,12 +592,14 @@ impl<T: StableType + AsFixedSizeBytes> SVec<T> {
         }
 
         if self.len() == self.capacity() {
-            self.cap = self.cap.checked_mul(2).unwrap();
-            assert!(self.cap <= Self::max_capacity());
+            let new_cap = self.cap.checked_mul(2).unwrap();
+            assert!(new_cap <= Self::max_capacity());
 
             let slice = unsafe { SSlice::from_ptr(self.ptr).unwrap() };
 
-            self.ptr = unsafe { reallocate(slice, (self.cap * T::SIZE) as u64)?.as_ptr() };
+            // the capacity is only updated on success, so the vec stays usable after OutOfMemory
+            self.ptr = unsafe { reallocate(slice, (new_cap * T::SIZE) as u64)?.as_ptr() };
+            self.cap = new_cap;
         }
 
         Ok(())
@@ -453,6 +614,124 @@ impl<T: StableType + AsFixedSizeBytes> SVec<T> {
     }
 }
 
+#[cfg(feature = "candid_chunks")]
+impl<T: StableType + AsFixedSizeBytes + CandidType + DeserializeOwned> SVec<T> {
+    /// Encodes elements of this [SVec] into Candid `vec`-s of `chunk_len` elements each
+    ///
+    /// Elements are read lazily, one chunk per iteration. See [crate::utils::candid_chunks].
+    ///
+    /// # Panics
+    /// Panics if `chunk_len` is zero.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use ic_stable_memory::collections::SVec;
+    /// # use ic_stable_memory::stable_memory_init;
+    /// # stable_memory_init();
+    /// let mut vec = SVec::<u64>::new();
+    /// for i in 0..100 {
+    ///     vec.push(i).expect("Out of memory");
+    /// }
+    ///
+    /// let chunks = vec.to_candid_chunks(30).collect::<Vec<_>>();
+    /// assert_eq!(chunks.len(), 4);
+    ///
+    /// let copy = SVec::<u64>::from_candid_chunks(chunks).expect("Invalid chunks");
+    /// assert_eq!(copy.len(), 100);
+    /// ```
+    pub fn to_candid_chunks(&self, chunk_len: usize) -> impl Iterator<Item = Vec<u8>> + '_ {
+        encode_chunks(self.iter(), chunk_len, |chunk| {
+            candid::encode_one(chunk.iter().map(|it| &**it).collect::<Vec<_>>())
+        })
+    }
+
+    /// Creates a new [SVec] from chunks, produced by [SVec::to_candid_chunks]
+    ///
+    /// If an error is returned, all the stable memory taken so far is released.
+    pub fn from_candid_chunks<C: AsRef<[u8]>>(
+        chunks: impl IntoIterator<Item = C>,
+    ) -> Result<Self, CandidChunksError> {
+        let mut it = Self::new();
+
+        for chunk in chunks {
+            it.append_candid_chunk(chunk.as_ref())?;
+        }
+
+        Ok(it)
+    }
+
+    /// Pushes the elements of a single chunk, produced by [SVec::to_candid_chunks], to the end of
+    /// this [SVec]
+    ///
+    /// Useful, when chunks arrive one at a time (e.g. one per call). If the chunk is invalid,
+    /// nothing is pushed. If there is not enough stable memory, the elements, that fit, remain pushed.
+    pub fn append_candid_chunk(&mut self, chunk: &[u8]) -> Result<(), CandidChunksError> {
+        let elements: Vec<T> = candid::decode_one(chunk)?;
+
+        for element in elements {
+            self.push(element)
+                .map_err(|_| CandidChunksError::OutOfMemory)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Serializes elements of this [SVec] as a sequence
+#[cfg(feature = "serde_collections")]
+impl<T: StableType + AsFixedSizeBytes + Serialize> Serialize for SVec<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.len()))?;
+
+        for elem in self.iter() {
+            seq.serialize_element(&*elem)?;
+        }
+
+        seq.end()
+    }
+}
+
+/// Deserializes a sequence into a new [SVec], fails if there is not enough stable memory
+#[cfg(feature = "serde_collections")]
+impl<'de, T: StableType + AsFixedSizeBytes + Deserialize<'de>> Deserialize<'de> for SVec<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct SVecVisitor<T>(PhantomData<T>);
+
+        impl<'de, T: StableType + AsFixedSizeBytes + Deserialize<'de>> Visitor<'de> for SVecVisitor<T> {
+            type Value = SVec<T>;
+
+            fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
+                formatter.write_str("a sequence")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut it = SVec::new();
+
+                while let Some(elem) = seq.next_element()? {
+                    it.push(elem)
+                        .map_err(|_| de::Error::custom("Out of stable memory"))?;
+                }
+
+                Ok(it)
+            }
+        }
+
+        deserializer.deserialize_seq(SVecVisitor(PhantomData))
+    }
+}
+
+impl<T: StableType + AsFixedSizeBytes + DeepCopy> DeepCopy for SVec<T> {
+    fn deep_copy(&self) -> Result<Self, OutOfMemory> {
+        let mut it = Self::new_with_capacity(self.len)?;
+
+        for elem in self.iter() {
+            it.try_push((*elem).deep_copy()?)?;
+        }
+
+        Ok(it)
+    }
+}
+
 impl<T: StableType + AsFixedSizeBytes> Default for SVec<T> {
     #[inline]
     fn default() -> Self {
@@ -463,13 +742,18 @@ impl<T: StableType + AsFixedSizeBytes> Default for SVec<T> {
 impl<T: StableType + AsFixedSizeBytes + Debug> Debug for SVec<T> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.write_str("[")?;
-        for (idx, item) in self.iter().enumerate() {
-            item.fmt(f)?;
-
-            if idx < self.len - 1 {
+        for (idx, item) in self.iter().take(DEBUG_ELEMENTS_LIMIT).enumerate() {
+            if idx > 0 {
                 f.write_str(", ")?;
             }
+
+            item.fmt(f)?;
+        }
+
+        if self.len > DEBUG_ELEMENTS_LIMIT {
+            write!(f, ", ...{} more", self.len - DEBUG_ELEMENTS_LIMIT)?;
         }
+
         f.write_str("]")
     }
 }
@@ -541,12 +825,43 @@ impl<T: StableType + AsFixedSizeBytes> Drop for SVec<T> {
     }
 }
 
+#[cfg(feature = "orphan_collector")]
+impl<T: StableType + AsFixedSizeBytes + Trace> SVec<T> {
+    /// Returns the size of stable memory, owned by this collection
+    ///
+    /// Includes its buffer, plus the memory owned by its elements, see [MemoryUsage].
+    ///
+    /// Only available with the `orphan_collector` feature.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        memory_usage(self, self.len() as u64)
+    }
+}
+
+#[cfg(feature = "orphan_collector")]
+impl<T: StableType + AsFixedSizeBytes + Trace> Trace for SVec<T> {
+    fn trace(&self, ptrs: &mut Vec<StablePtr>) {
+        if self.ptr == EMPTY_PTR {
+            return;
+        }
+
+        ptrs.push(self.ptr);
+
+        if T::OWNS_MEMORY {
+            for elem in self.iter() {
+                elem.trace(ptrs);
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use crate::collections::btree_map::SBTreeMap;
     use crate::collections::vec::{SVec, DEFAULT_CAPACITY};
     use crate::encoding::{AsFixedSizeBytes, Buffer};
     use crate::primitive::s_box::SBox;
-    use crate::primitive::StableType;
+    use crate::primitive::{DeepCopy, StableType};
+    use crate::utils::budget::Budget;
     use crate::utils::mem_context::stable;
     use crate::utils::test::generate_random_string;
     use crate::utils::DebuglessUnwrap;
@@ -606,6 +921,45 @@ mod tests {
         assert_eq!(get_allocated_size(), 0);
     }
 
+    #[test]
+    fn shrink_to_fit_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut stable_vec = SVec::new();
+
+            for i in 0..1000u64 {
+                stable_vec.push(i).unwrap();
+            }
+
+            let allocated_size = get_allocated_size();
+
+            for _ in 0..900 {
+                stable_vec.pop();
+            }
+
+            stable_vec.shrink_to_fit();
+            assert_eq!(stable_vec.capacity(), 100);
+            assert!(get_allocated_size() < allocated_size);
+
+            for i in 0..100u64 {
+                assert_eq!(*stable_vec.get(i as usize).unwrap(), i);
+            }
+
+            stable_vec.push(100).unwrap();
+            assert_eq!(stable_vec.capacity(), 200);
+
+            stable_vec.clear();
+            stable_vec.shrink_to_fit();
+            assert_eq!(stable_vec.capacity(), DEFAULT_CAPACITY);
+            assert_eq!(get_allocated_size(), 0);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
     #[test]
     fn push_pop_work_fine() {
         stable::clear();
@@ -716,6 +1070,47 @@ mod tests {
         assert_eq!(get_allocated_size(), 0);
     }
 
+    #[test]
+    fn split_off_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut vec = SVec::new();
+            for i in 0..100 {
+                vec.push(SBox::new(i).unwrap()).unwrap();
+            }
+
+            let tail = vec.split_off(100).unwrap();
+            assert!(tail.is_empty());
+            assert_eq!(tail.capacity(), DEFAULT_CAPACITY);
+
+            let mut tail = vec.split_off(60).unwrap();
+            assert_eq!(vec.len(), 60);
+            assert_eq!(tail.len(), 40);
+            assert_eq!(tail.capacity(), 40);
+
+            for (idx, elem) in vec.iter().enumerate() {
+                assert_eq!(**elem, idx);
+            }
+            for (idx, elem) in tail.iter().enumerate() {
+                assert_eq!(**elem, idx + 60);
+            }
+
+            tail.push(SBox::new(100).unwrap()).unwrap();
+            assert_eq!(**tail.get(40).unwrap(), 100);
+
+            let head = vec.split_off(0).unwrap();
+            assert!(vec.is_empty());
+            assert_eq!(head.len(), 60);
+
+            vec.push(SBox::new(0).unwrap()).unwrap();
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
     #[test]
     fn binary_search_work_fine() {
         stable::clear();
@@ -855,6 +1250,54 @@ mod tests {
         assert_eq!(get_allocated_size(), 0);
     }
 
+    #[test]
+    fn iter_resumable_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut vec = SVec::new();
+            assert!(vec
+                .iter_resumable(0, Budget::Elements(10))
+                .into_continuation()
+                .is_none());
+
+            for i in 0..95 {
+                vec.push(i);
+            }
+
+            let mut visited = Vec::new();
+            let mut calls = 0;
+            let mut from = 0;
+            loop {
+                let mut iter = vec.iter_resumable(from, Budget::Elements(10));
+                for (idx, elem) in &mut iter {
+                    assert_eq!(idx as i32, *elem);
+                    visited.push(*elem);
+                }
+                calls += 1;
+
+                from = match iter.into_continuation() {
+                    Some(idx) => idx,
+                    None => break,
+                };
+            }
+
+            assert_eq!(calls, 10);
+            assert_eq!(visited, (0..95).collect::<Vec<_>>());
+
+            let mut iter = vec.iter_resumable(90, Budget::Elements(0));
+            assert_eq!(iter.next().map(|(idx, _)| idx), Some(90));
+            assert!(iter.next().is_none());
+            assert_eq!(iter.continuation(), Some(&91));
+
+            assert_eq!(vec.iter_resumable(200, Budget::Elements(10)).count(), 0);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
     #[test]
     fn random_works_fine() {
         stable::clear();
@@ -1182,6 +1625,79 @@ mod tests {
         }
     }
 
+    #[cfg(feature = "candid_chunks")]
+    #[test]
+    fn candid_chunks_work_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut vec = SVec::<u64>::new();
+            for i in 0..1000 {
+                vec.push(i).unwrap();
+            }
+
+            let chunks = vec.to_candid_chunks(300).collect::<Vec<_>>();
+            assert_eq!(chunks.len(), 4);
+
+            let elements: Vec<u64> = candid::decode_one(&chunks[3]).unwrap();
+            assert_eq!(elements, (900..1000).collect::<Vec<_>>());
+
+            let copy = SVec::<u64>::from_candid_chunks(&chunks).unwrap();
+            assert_eq!(copy.len(), 1000);
+            for i in 0..1000 {
+                assert_eq!(*copy.get(i).unwrap(), i as u64);
+            }
+
+            let mut copy = SVec::<u64>::new();
+            assert!(copy.append_candid_chunk(&[1, 2, 3]).is_err());
+            assert!(copy.append_candid_chunk(&chunks[3]).is_ok());
+            assert_eq!(copy.len(), 100);
+
+            assert!(SVec::<u64>::new().to_candid_chunks(10).next().is_none());
+        }
+
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[cfg(feature = "serde_collections")]
+    #[test]
+    fn serde_works_fine() {
+        use serde::de::value::{Error, SeqDeserializer};
+        use serde::Deserialize;
+        use serde_test::{assert_ser_tokens, Token};
+
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut vec = SVec::<u64>::new();
+            for i in 0..3 {
+                vec.push(i).unwrap();
+            }
+
+            assert_ser_tokens(
+                &vec,
+                &[
+                    Token::Seq { len: Some(3) },
+                    Token::U64(0),
+                    Token::U64(1),
+                    Token::U64(2),
+                    Token::SeqEnd,
+                ],
+            );
+
+            let de = SeqDeserializer::<_, Error>::new(vec![10u64, 20, 30].into_iter());
+            let copy = SVec::<u64>::deserialize(de).unwrap();
+
+            assert_eq!(copy.len(), 3);
+            assert_eq!(*copy.get(0).unwrap(), 10);
+            assert_eq!(*copy.get(2).unwrap(), 30);
+        }
+
+        assert_eq!(get_allocated_size(), 0);
+    }
+
     #[test]
     fn fuzzer_works_fine() {
         stable::clear();
@@ -1213,4 +1729,42 @@ mod tests {
 
         assert_eq!(get_allocated_size(), 0);
     }
+
+    #[test]
+    fn deep_copy_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut vec = SVec::<SBTreeMap<u64, SBox<String>>>::new();
+
+            for i in 0..10u64 {
+                let mut map = SBTreeMap::new();
+                for j in 0..10u64 {
+                    map.insert(j, SBox::new(format!("{} {}", i, j)).unwrap())
+                        .unwrap();
+                }
+
+                vec.push(map).unwrap();
+            }
+
+            let copy = vec.deep_copy().unwrap();
+            assert_eq!(copy.len(), 10);
+
+            // the original is released, the copy stays valid
+            drop(vec);
+            _debug_validate_allocator();
+
+            for i in 0..10u64 {
+                let map = copy.get(i as usize).unwrap();
+
+                for j in 0..10u64 {
+                    assert_eq!(map.get(&j).unwrap().as_str(), format!("{} {}", i, j));
+                }
+            }
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
 }
@@ -0,0 +1,149 @@
+use crate::collections::versioned_map::node::{key_ptr, read_key, value_ptr, Node};
+use crate::encoding::AsFixedSizeBytes;
+use crate::mem::allocator::EMPTY_PTR;
+use crate::mem::StablePtr;
+use crate::primitive::s_ref::SRef;
+use crate::primitive::StableType;
+use std::borrow::Borrow;
+use std::marker::PhantomData;
+
+pub struct SVersionedMapIter<'a, K, V> {
+    // nodes, which are yet to be visited from the front, the next one is on top
+    stack: Vec<StablePtr>,
+    // nodes, which are yet to be visited from the back, the next one is on top
+    back_stack: Vec<StablePtr>,
+    finished: bool,
+    _marker: PhantomData<&'a (K, V)>,
+}
+
+impl<'a, K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes>
+    SVersionedMapIter<'a, K, V>
+{
+    #[inline]
+    pub(crate) fn new(root: StablePtr) -> Self {
+        let mut it = Self {
+            stack: Vec::new(),
+            back_stack: Vec::new(),
+            finished: false,
+            _marker: PhantomData::default(),
+        };
+        it.push_left_spine(root);
+        it.push_right_spine(root);
+
+        it
+    }
+
+    // starts from the first key, which is greater than or equal to this one
+    pub(crate) fn new_from<Q>(mut ptr: StablePtr, key: &Q) -> Self
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut it = Self::new(EMPTY_PTR);
+        it.push_right_spine(ptr);
+
+        while ptr != EMPTY_PTR {
+            let node = Node::read(ptr);
+
+            if key <= read_key::<K>(node.entry).borrow() {
+                it.stack.push(ptr);
+                ptr = node.left;
+            } else {
+                ptr = node.right;
+            }
+        }
+
+        it
+    }
+
+    // ends with the last key, which is less than or equal to this one
+    pub(crate) fn new_until<Q>(mut ptr: StablePtr, key: &Q) -> Self
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut it = Self::new(EMPTY_PTR);
+        it.push_left_spine(ptr);
+
+        while ptr != EMPTY_PTR {
+            let node = Node::read(ptr);
+
+            if key >= read_key::<K>(node.entry).borrow() {
+                it.back_stack.push(ptr);
+                ptr = node.right;
+            } else {
+                ptr = node.left;
+            }
+        }
+
+        it
+    }
+
+    fn push_left_spine(&mut self, mut ptr: StablePtr) {
+        while ptr != EMPTY_PTR {
+            self.stack.push(ptr);
+            ptr = Node::read(ptr).left;
+        }
+    }
+
+    fn push_right_spine(&mut self, mut ptr: StablePtr) {
+        while ptr != EMPTY_PTR {
+            self.back_stack.push(ptr);
+            ptr = Node::read(ptr).right;
+        }
+    }
+
+    // both ends meet, once one of them returns the node, which is the next one for the other
+    #[inline]
+    fn is_finished(&self) -> bool {
+        self.finished || self.stack.is_empty() || self.back_stack.is_empty()
+    }
+}
+
+impl<'a, K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> Iterator
+    for SVersionedMapIter<'a, K, V>
+{
+    type Item = (SRef<'a, K>, SRef<'a, V>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.is_finished() {
+            return None;
+        }
+
+        let ptr = self.stack.pop()?;
+        self.finished = self.back_stack.last() == Some(&ptr);
+
+        let node = Node::read(ptr);
+        self.push_left_spine(node.right);
+
+        unsafe {
+            Some((
+                SRef::new(key_ptr(node.entry)),
+                SRef::new(value_ptr::<K>(node.entry)),
+            ))
+        }
+    }
+}
+
+impl<'a, K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes>
+    DoubleEndedIterator for SVersionedMapIter<'a, K, V>
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.is_finished() {
+            return None;
+        }
+
+        let ptr = self.back_stack.pop()?;
+        self.finished = self.stack.last() == Some(&ptr);
+
+        let node = Node::read(ptr);
+        self.push_right_spine(node.left);
+
+        unsafe {
+            Some((
+                SRef::new(key_ptr(node.entry)),
+                SRef::new(value_ptr::<K>(node.entry)),
+            ))
+        }
+    }
+}
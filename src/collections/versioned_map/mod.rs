@@ -0,0 +1,672 @@
+use crate::collections::versioned_map::iter::SVersionedMapIter;
+use crate::collections::versioned_map::node::{
+    create_entry, find, insert_cost, release_entry, release_node, remove_cost, retain, value_ptr,
+    NodePool,
+};
+use crate::collections::versioned_map::snapshot::SVersionedMapSnapshot;
+use crate::encoding::AsFixedSizeBytes;
+use crate::mem::allocator::EMPTY_PTR;
+#[cfg(feature = "orphan_collector")]
+use crate::mem::orphan_collector::Trace;
+use crate::mem::StablePtr;
+use crate::primitive::s_ref::SRef;
+use crate::primitive::StableType;
+use crate::OutOfMemory;
+use std::borrow::Borrow;
+use std::fmt::{Debug, Formatter};
+use std::iter::Rev;
+use std::marker::PhantomData;
+
+#[doc(hidden)]
+pub mod iter;
+pub(crate) mod node;
+#[doc(hidden)]
+pub mod snapshot;
+
+/// Multi-version ordered map, which allows reading a consistent snapshot while updates continue
+///
+/// Internally this is a persistent [treap](https://en.wikipedia.org/wiki/Treap) - a randomized binary
+/// search tree, which stays balanced with high probability, so all operations take O(logN) on average.
+/// Taking a snapshot is O(1): it simply pins the current root node. Nodes are reference counted and
+/// copied on write - an update mutates nodes in place, unless they are shared with some snapshot, in
+/// which case only the nodes on the path to the updated key are copied. Keys and values are never
+/// copied, they are shared between versions by reference.
+///
+/// Because of that, updates can't return the replaced or removed value (some snapshot may still use
+/// it) and removals may allocate stable memory (to copy shared nodes). A snapshot holds all nodes of
+/// its version alive, so drop snapshots as soon as they are no longer needed.
+///
+/// There is no `get_mut()` method for the same reason - the value may be shared with a snapshot.
+///
+/// Both `K` and `V` have to implement [StableType] and [AsFixedSizeBytes]. [SVersionedMap] and
+/// [SVersionedMapSnapshot] also implement these traits, so you can nest them into other stable
+/// structures.
+///
+/// # Examples
+/// ```rust
+/// # use ic_stable_memory::collections::SVersionedMap;
+/// # use ic_stable_memory::stable_memory_init;
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// let mut balances = SVersionedMap::new();
+///
+/// for i in 0..100u64 {
+///     balances.insert(i, 100u64).expect("Out of memory");
+/// }
+///
+/// let snapshot = balances.snapshot();
+///
+/// // updates continue
+/// balances.insert(0, 0).expect("Out of memory");
+/// balances.remove(&99).expect("Out of memory");
+///
+/// // but the snapshot still sees the old state
+/// assert_eq!(*snapshot.get(&0).unwrap(), 100);
+/// assert_eq!(snapshot.len(), 100);
+///
+/// // paginated export
+/// let page: Vec<_> = snapshot.iter_from(&90).map(|(k, _)| *k).collect();
+/// assert_eq!(page, (90..100).collect::<Vec<_>>());
+/// ```
+pub struct SVersionedMap<K, V> {
+    root: StablePtr,
+    len: u64,
+    seed: u64,
+    stable_drop_flag: bool,
+    _marker: PhantomData<(K, V)>,
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> SVersionedMap<K, V> {
+    /// Creates a new [SVersionedMap]
+    ///
+    /// Does not allocate any heap or stable memory.
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            root: EMPTY_PTR,
+            len: 0,
+            seed: 0,
+            stable_drop_flag: true,
+            _marker: PhantomData::default(),
+        }
+    }
+
+    /// Inserts a new entry, returning `true` if an existing entry with this key was replaced
+    ///
+    /// The replaced value is dropped, unless some snapshot still uses it.
+    ///
+    /// May allocate stable memory. If the canister is out of stable memory, returns [Err] with the
+    /// entry and leaves the map untouched.
+    pub fn insert(&mut self, mut key: K, mut value: V) -> Result<bool, (K, V)> {
+        let (cost, present) = insert_cost(self.root, &key);
+        let cost = if present { cost } else { cost + 1 };
+
+        let mut pool = match NodePool::allocate(cost) {
+            Ok(p) => p,
+            Err(_) => return Err((key, value)),
+        };
+
+        let entry = match create_entry(&mut key, &mut value) {
+            Ok(e) => e,
+            Err(_) => return Err((key, value)),
+        };
+
+        let (root, replaced) =
+            node::insert(self.root, &key, entry, self.next_priority(), &mut pool);
+        self.root = root;
+
+        match replaced {
+            Some(replaced) => {
+                release_entry::<K, V>(replaced);
+
+                Ok(true)
+            }
+            None => {
+                self.len += 1;
+
+                Ok(false)
+            }
+        }
+    }
+
+    /// Same as [SVersionedMap::insert], but returns [OutOfMemory] instead of the entry
+    ///
+    /// Useful for propagating the error with `?`. The entry gets dropped, if it can't be inserted.
+    #[inline]
+    pub fn try_insert(&mut self, key: K, value: V) -> Result<bool, OutOfMemory> {
+        self.insert(key, value).map_err(|_| OutOfMemory)
+    }
+
+    /// Removes the entry by this key, returning `true` if it was present
+    ///
+    /// The removed value is dropped, unless some snapshot still uses it.
+    ///
+    /// Allocates stable memory, only if the removed entry is shared with some snapshot. If the canister
+    /// is out of stable memory, returns [Err] and leaves the map untouched.
+    pub fn remove<Q>(&mut self, key: &Q) -> Result<bool, OutOfMemory>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let cost = match remove_cost::<K, Q>(self.root, key) {
+            Some(c) => c,
+            None => return Ok(false),
+        };
+
+        let mut pool = NodePool::allocate(cost)?;
+
+        let (root, removed) = node::remove::<K, Q>(self.root, key, &mut pool);
+        self.root = root;
+        self.len -= 1;
+
+        release_entry::<K, V>(removed);
+
+        Ok(true)
+    }
+
+    /// Returns an immutable reference to the value by this key
+    #[inline]
+    pub fn get<Q>(&self, key: &Q) -> Option<SRef<'_, V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let entry = find::<K, Q>(self.root, key)?;
+
+        unsafe { Some(SRef::new(value_ptr::<K>(entry))) }
+    }
+
+    /// Returns `true` if there is an entry with this key
+    #[inline]
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        find::<K, Q>(self.root, key).is_some()
+    }
+
+    /// Pins the current version of the map
+    ///
+    /// Does not allocate any stable memory. The returned snapshot is not affected by subsequent
+    /// updates of the map.
+    #[inline]
+    pub fn snapshot(&self) -> SVersionedMapSnapshot<K, V> {
+        retain(self.root);
+
+        SVersionedMapSnapshot::new(self.root, self.len)
+    }
+
+    /// Returns an iterator over all entries in ascending order of their keys
+    #[inline]
+    pub fn iter(&self) -> SVersionedMapIter<'_, K, V> {
+        SVersionedMapIter::new(self.root)
+    }
+
+    /// Returns an iterator over entries with keys greater than or equal to this key, in ascending
+    /// order
+    #[inline]
+    pub fn iter_from<Q>(&self, key: &Q) -> SVersionedMapIter<'_, K, V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        SVersionedMapIter::new_from(self.root, key)
+    }
+
+    /// Returns an iterator over all entries in descending order of their keys
+    #[inline]
+    pub fn iter_rev(&self) -> Rev<SVersionedMapIter<'_, K, V>> {
+        self.iter().rev()
+    }
+
+    /// Returns an iterator over entries with keys less than or equal to this key, in descending
+    /// order
+    #[inline]
+    pub fn iter_rev_from<Q>(&self, key: &Q) -> Rev<SVersionedMapIter<'_, K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        SVersionedMapIter::new_until(self.root, key).rev()
+    }
+
+    /// Returns the number of entries in the current version
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns `true` if the current version has no entries
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Removes all entries from the current version
+    ///
+    /// Releases all stable memory, which is not used by snapshots.
+    #[inline]
+    pub fn clear(&mut self) {
+        if self.root != EMPTY_PTR {
+            release_node::<K, V>(self.root);
+        }
+
+        self.root = EMPTY_PTR;
+        self.len = 0;
+    }
+
+    // splitmix64
+    fn next_priority(&mut self) -> u64 {
+        self.seed = self.seed.wrapping_add(0x9E3779B97F4A7C15);
+
+        let mut z = self.seed;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+
+        z ^ (z >> 31)
+    }
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> Default
+    for SVersionedMap<K, V>
+{
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: StableType + AsFixedSizeBytes, V: StableType + AsFixedSizeBytes> AsFixedSizeBytes
+    for SVersionedMap<K, V>
+{
+    const SIZE: usize = u64::SIZE * 3;
+    type Buf = [u8; u64::SIZE * 3];
+
+    fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+        self.root.as_fixed_size_bytes(&mut buf[0..u64::SIZE]);
+        self.len
+            .as_fixed_size_bytes(&mut buf[u64::SIZE..(u64::SIZE * 2)]);
+        self.seed
+            .as_fixed_size_bytes(&mut buf[(u64::SIZE * 2)..(u64::SIZE * 3)]);
+    }
+
+    fn from_fixed_size_bytes(buf: &[u8]) -> Self {
+        Self {
+            root: u64::from_fixed_size_bytes(&buf[0..u64::SIZE]),
+            len: u64::from_fixed_size_bytes(&buf[u64::SIZE..(u64::SIZE * 2)]),
+            seed: u64::from_fixed_size_bytes(&buf[(u64::SIZE * 2)..(u64::SIZE * 3)]),
+            stable_drop_flag: false,
+            _marker: PhantomData::default(),
+        }
+    }
+}
+
+impl<K: StableType + AsFixedSizeBytes, V: StableType + AsFixedSizeBytes> StableType
+    for SVersionedMap<K, V>
+{
+    #[inline]
+    unsafe fn stable_drop_flag_off(&mut self) {
+        self.stable_drop_flag = false;
+    }
+
+    #[inline]
+    unsafe fn stable_drop_flag_on(&mut self) {
+        self.stable_drop_flag = true;
+    }
+
+    #[inline]
+    fn should_stable_drop(&self) -> bool {
+        self.stable_drop_flag
+    }
+
+    #[inline]
+    unsafe fn stable_drop(&mut self) {
+        if self.root != EMPTY_PTR {
+            release_node::<K, V>(self.root);
+            self.root = EMPTY_PTR;
+        }
+    }
+}
+
+impl<K: StableType + AsFixedSizeBytes, V: StableType + AsFixedSizeBytes> Drop
+    for SVersionedMap<K, V>
+{
+    fn drop(&mut self) {
+        if self.should_stable_drop() {
+            unsafe {
+                self.stable_drop();
+            }
+        }
+    }
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord + Debug, V: StableType + AsFixedSizeBytes + Debug> Debug
+    for SVersionedMap<K, V>
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("{")?;
+
+        for (idx, (k, v)) in self.iter().enumerate() {
+            k.fmt(f)?;
+            f.write_str(": ")?;
+            v.fmt(f)?;
+
+            if (idx as u64) < self.len - 1 {
+                f.write_str(", ")?;
+            }
+        }
+
+        f.write_str("}")
+    }
+}
+
+#[cfg(feature = "orphan_collector")]
+impl<K: StableType + AsFixedSizeBytes + Trace, V: StableType + AsFixedSizeBytes + Trace> Trace
+    for SVersionedMap<K, V>
+{
+    #[inline]
+    fn trace(&self, ptrs: &mut Vec<StablePtr>) {
+        node::trace::<K, V>(self.root, ptrs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::versioned_map::snapshot::SVersionedMapSnapshot;
+    use crate::collections::versioned_map::SVersionedMap;
+    use crate::utils::test::generate_random_string;
+    use crate::{
+        _debug_validate_allocator, get_allocated_size, init_allocator, retrieve_custom_data,
+        stable, stable_memory_init, stable_memory_post_upgrade, stable_memory_pre_upgrade,
+        store_custom_data, SBox,
+    };
+    use rand::rngs::ThreadRng;
+    use rand::{thread_rng, Rng};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut map = SVersionedMap::new();
+
+            for i in 0..100u64 {
+                assert!(!map.insert(i, i).unwrap());
+            }
+
+            let snapshot = map.snapshot();
+
+            for i in 0..50u64 {
+                assert!(map.insert(i, i * 10).unwrap());
+                assert!(map.remove(&(i + 50)).unwrap());
+            }
+            assert!(!map.remove(&1000).unwrap());
+
+            assert_eq!(map.len(), 50);
+            assert_eq!(snapshot.len(), 100);
+
+            for (i, (k, v)) in snapshot.iter().enumerate() {
+                assert_eq!(*k, i as u64);
+                assert_eq!(*v, i as u64);
+            }
+
+            for (i, (k, v)) in map.iter().enumerate() {
+                assert_eq!(*k, i as u64);
+                assert_eq!(*v, i as u64 * 10);
+            }
+
+            let page: Vec<_> = snapshot.iter_from(&95).map(|(k, _)| *k).collect();
+            assert_eq!(page, vec![95, 96, 97, 98, 99]);
+            assert_eq!(map.iter_from(&95).count(), 0);
+
+            let latest: Vec<_> = snapshot.iter_rev().take(3).map(|(k, _)| *k).collect();
+            assert_eq!(latest, vec![99, 98, 97]);
+            let page: Vec<_> = snapshot.iter_rev_from(&4).map(|(k, _)| *k).collect();
+            assert_eq!(page, vec![4, 3, 2, 1, 0]);
+            assert_eq!(map.iter_rev_from(&1000).next().map(|(k, _)| *k), Some(49));
+            assert_eq!(map.iter_rev().count(), 50);
+
+            let mut iter = snapshot.iter_from(&90);
+            assert_eq!(iter.next_back().map(|(k, _)| *k), Some(99));
+            assert_eq!(iter.next().map(|(k, _)| *k), Some(90));
+            let rest: Vec<_> = iter.rev().map(|(k, _)| *k).collect();
+            assert_eq!(rest, vec![98, 97, 96, 95, 94, 93, 92, 91]);
+
+            store_custom_data(0, SBox::new(snapshot).unwrap());
+            store_custom_data(1, SBox::new(map).unwrap());
+
+            let snapshot = retrieve_custom_data::<SVersionedMapSnapshot<u64, u64>>(0)
+                .unwrap()
+                .into_inner();
+            let mut map = retrieve_custom_data::<SVersionedMap<u64, u64>>(1)
+                .unwrap()
+                .into_inner();
+
+            assert_eq!(*snapshot.get(&75).unwrap(), 75);
+            assert!(map.get(&75).is_none());
+
+            let snapshot1 = snapshot.clone();
+            drop(snapshot);
+
+            assert_eq!(*snapshot1.get(&10).unwrap(), 10);
+            assert_eq!(*map.get(&10).unwrap(), 100);
+
+            drop(snapshot1);
+
+            map.clear();
+            assert!(map.is_empty());
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn boxed_values_work_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut map = SVersionedMap::new();
+
+            for i in 0..100u64 {
+                map.insert(i, SBox::new(i.to_string()).unwrap()).unwrap();
+            }
+
+            let snapshots: Vec<_> = (0..5)
+                .map(|j| {
+                    let snapshot = map.snapshot();
+
+                    for i in (j * 20)..((j + 1) * 20) {
+                        map.remove(&i).unwrap();
+                    }
+
+                    snapshot
+                })
+                .collect();
+
+            assert!(map.is_empty());
+
+            for (j, snapshot) in snapshots.iter().enumerate() {
+                assert_eq!(snapshot.len(), 100 - j as u64 * 20);
+                assert_eq!(**snapshot.get(&99).unwrap(), "99");
+            }
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    enum Action {
+        Insert,
+        Remove,
+        TakeSnapshot,
+        DropSnapshot,
+        Clear,
+        CanisterUpgrade,
+    }
+
+    struct Fuzzer {
+        map: Option<SVersionedMap<u64, SBox<String>>>,
+        snapshots: Vec<SVersionedMapSnapshot<u64, SBox<String>>>,
+        example: BTreeMap<u64, String>,
+        example_snapshots: Vec<BTreeMap<u64, String>>,
+        rng: ThreadRng,
+        log: Vec<Action>,
+    }
+
+    impl Fuzzer {
+        fn new() -> Self {
+            Self {
+                map: Some(SVersionedMap::default()),
+                snapshots: Vec::default(),
+                example: BTreeMap::default(),
+                example_snapshots: Vec::default(),
+                rng: thread_rng(),
+                log: Vec::default(),
+            }
+        }
+
+        fn it(&mut self) -> &mut SVersionedMap<u64, SBox<String>> {
+            self.map.as_mut().unwrap()
+        }
+
+        fn next(&mut self) {
+            let action = self.rng.gen_range(0..101);
+
+            match action {
+                // INSERT ~50%
+                0..=49 => {
+                    let key = self.rng.gen_range(0..300);
+                    let str = generate_random_string(&mut self.rng);
+
+                    if let Ok(data) = SBox::new(str.clone()) {
+                        if let Ok(replaced) = self.it().insert(key, data) {
+                            assert_eq!(replaced, self.example.insert(key, str).is_some());
+                        }
+
+                        self.log.push(Action::Insert);
+                    }
+                }
+                // REMOVE ~40%
+                50..=89 => {
+                    let key = self.rng.gen_range(0..300);
+
+                    if let Ok(removed) = self.it().remove(&key) {
+                        assert_eq!(removed, self.example.remove(&key).is_some());
+                    }
+
+                    self.log.push(Action::Remove);
+                }
+                // TAKE SNAPSHOT
+                90..=93 => {
+                    let snapshot = self.it().snapshot();
+
+                    self.snapshots.push(snapshot);
+                    self.example_snapshots.push(self.example.clone());
+
+                    self.log.push(Action::TakeSnapshot);
+                }
+                // DROP SNAPSHOT
+                94..=97 => {
+                    if !self.snapshots.is_empty() {
+                        let idx = self.rng.gen_range(0..self.snapshots.len());
+
+                        self.snapshots.remove(idx);
+                        self.example_snapshots.remove(idx);
+                    }
+
+                    self.log.push(Action::DropSnapshot);
+                }
+                // CLEAR
+                98 => {
+                    self.it().clear();
+                    self.example.clear();
+
+                    self.log.push(Action::Clear);
+                }
+                // CANISTER UPGRADE
+                _ => match SBox::new(self.map.take().unwrap()) {
+                    Ok(data) => {
+                        store_custom_data(1, data);
+
+                        if stable_memory_pre_upgrade().is_ok() {
+                            stable_memory_post_upgrade();
+                        }
+
+                        self.map = retrieve_custom_data::<SVersionedMap<u64, SBox<String>>>(1)
+                            .map(|it| it.into_inner());
+
+                        self.log.push(Action::CanisterUpgrade);
+                    }
+                    Err(map) => {
+                        self.map = Some(map);
+                    }
+                },
+            }
+
+            _debug_validate_allocator();
+            assert_eq!(self.it().len(), self.example.len() as u64);
+
+            let actual: Vec<_> = self
+                .it()
+                .iter()
+                .map(|(k, v)| (*k, String::clone(&v)))
+                .collect();
+            let expected: Vec<_> = self.example.clone().into_iter().collect();
+            assert_eq!(actual, expected);
+
+            let actual: Vec<_> = self.it().iter_rev().map(|(k, _)| *k).collect();
+            let expected: Vec<_> = self.example.keys().rev().copied().collect();
+            assert_eq!(actual, expected);
+
+            if !self.snapshots.is_empty() {
+                let idx = self.rng.gen_range(0..self.snapshots.len());
+
+                let actual: Vec<_> = self.snapshots[idx]
+                    .iter()
+                    .map(|(k, v)| (*k, String::clone(&v)))
+                    .collect();
+                let expected: Vec<_> = self.example_snapshots[idx].clone().into_iter().collect();
+
+                assert_eq!(self.snapshots[idx].len(), expected.len() as u64);
+                assert_eq!(actual, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn fuzzer_works_fine() {
+        stable::clear();
+        init_allocator(0);
+
+        {
+            let mut fuzzer = Fuzzer::new();
+
+            for _ in 0..5_000 {
+                fuzzer.next();
+            }
+        }
+
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn fuzzer_works_fine_limited_memory() {
+        stable::clear();
+        init_allocator(10);
+
+        {
+            let mut fuzzer = Fuzzer::new();
+
+            for _ in 0..5_000 {
+                fuzzer.next();
+            }
+        }
+
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
@@ -0,0 +1,495 @@
+use crate::encoding::AsFixedSizeBytes;
+use crate::mem::allocator::EMPTY_PTR;
+#[cfg(feature = "orphan_collector")]
+use crate::mem::orphan_collector::Trace;
+use crate::mem::StablePtr;
+#[cfg(feature = "orphan_collector")]
+use crate::primitive::s_ref::SRef;
+use crate::primitive::StableType;
+use crate::{allocate, deallocate, OutOfMemory, SSlice};
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+
+// NODE LAYOUT:
+// rc: u64
+// priority: u64
+// left: u64
+// right: u64
+// entry: u64
+//
+// ENTRY LAYOUT:
+// rc: u64
+// key: K
+// value: V
+//
+// Both nodes and entries are reference counted. A node is referenced by its parent node or by a
+// version root, an entry - by nodes. Nodes with `rc == 1` belong to a single version only and can be
+// mutated in place, shared nodes are copied on write.
+
+const RC_OFFSET: u64 = 0;
+const KEY_OFFSET: u64 = u64::SIZE as u64;
+const NODE_SIZE: u64 = (u64::SIZE * 5) as u64;
+
+#[inline]
+pub(crate) const fn entry_size<K: AsFixedSizeBytes, V: AsFixedSizeBytes>() -> u64 {
+    KEY_OFFSET + K::SIZE as u64 + V::SIZE as u64
+}
+
+#[inline]
+pub(crate) fn key_ptr(entry: StablePtr) -> StablePtr {
+    SSlice::_offset(entry, KEY_OFFSET)
+}
+
+#[inline]
+pub(crate) fn value_ptr<K: AsFixedSizeBytes>(entry: StablePtr) -> StablePtr {
+    SSlice::_offset(entry, KEY_OFFSET + K::SIZE as u64)
+}
+
+#[inline]
+pub(crate) fn read_key<K: StableType + AsFixedSizeBytes>(entry: StablePtr) -> K {
+    unsafe { crate::mem::read_fixed_for_reference(key_ptr(entry)) }
+}
+
+#[inline]
+fn read_rc(ptr: StablePtr) -> u64 {
+    unsafe { crate::mem::read_fixed_for_reference(SSlice::_offset(ptr, RC_OFFSET)) }
+}
+
+#[inline]
+fn write_rc(ptr: StablePtr, mut rc: u64) {
+    unsafe { crate::mem::write_fixed(SSlice::_offset(ptr, RC_OFFSET), &mut rc) };
+}
+
+/// Increments the reference counter of a node or an entry
+#[inline]
+pub(crate) fn retain(ptr: StablePtr) {
+    if ptr != EMPTY_PTR {
+        write_rc(ptr, read_rc(ptr) + 1);
+    }
+}
+
+/// A heap copy of a treap node
+#[derive(Copy, Clone)]
+pub(crate) struct Node {
+    pub ptr: StablePtr,
+    pub rc: u64,
+    pub priority: u64,
+    pub left: StablePtr,
+    pub right: StablePtr,
+    pub entry: StablePtr,
+}
+
+impl Node {
+    pub fn read(ptr: StablePtr) -> Self {
+        let mut buf = [0u8; NODE_SIZE as usize];
+        unsafe { crate::mem::read_bytes(SSlice::_offset(ptr, 0), &mut buf) };
+
+        let field =
+            |idx: usize| u64::from_fixed_size_bytes(&buf[(idx * u64::SIZE)..((idx + 1) * u64::SIZE)]);
+
+        Self {
+            ptr,
+            rc: field(0),
+            priority: field(1),
+            left: field(2),
+            right: field(3),
+            entry: field(4),
+        }
+    }
+
+    pub fn write(&self) {
+        let mut buf = [0u8; NODE_SIZE as usize];
+
+        for (idx, it) in [self.rc, self.priority, self.left, self.right, self.entry]
+            .iter()
+            .enumerate()
+        {
+            it.as_fixed_size_bytes(&mut buf[(idx * u64::SIZE)..((idx + 1) * u64::SIZE)]);
+        }
+
+        unsafe { crate::mem::write_bytes(SSlice::_offset(self.ptr, 0), &buf) };
+    }
+}
+
+/// Pre-allocated node slices, so copying nodes in the middle of an update never runs out of memory
+pub(crate) struct NodePool(Vec<SSlice>);
+
+impl NodePool {
+    pub fn allocate(count: usize) -> Result<Self, OutOfMemory> {
+        let mut pool = Self(Vec::with_capacity(count));
+
+        for _ in 0..count {
+            // on error the pool drops, releasing already allocated slices
+            pool.0.push(unsafe { allocate(NODE_SIZE)? });
+        }
+
+        Ok(pool)
+    }
+
+    #[inline]
+    fn take(&mut self) -> StablePtr {
+        self.0.pop().expect("Node pool is exhausted").as_ptr()
+    }
+}
+
+impl Drop for NodePool {
+    fn drop(&mut self) {
+        for slice in self.0.drain(..) {
+            deallocate(slice);
+        }
+    }
+}
+
+/// Allocates a new entry with `rc == 1`
+///
+/// On success, the key and the value are moved into stable memory.
+pub(crate) fn create_entry<K: StableType + AsFixedSizeBytes, V: StableType + AsFixedSizeBytes>(
+    key: &mut K,
+    value: &mut V,
+) -> Result<StablePtr, OutOfMemory> {
+    let slice = unsafe { allocate(entry_size::<K, V>())? };
+    let ptr = slice.as_ptr();
+
+    write_rc(ptr, 1);
+    unsafe {
+        crate::mem::write_fixed(key_ptr(ptr), key);
+        crate::mem::write_fixed(value_ptr::<K>(ptr), value);
+    }
+
+    Ok(ptr)
+}
+
+/// Decrements the reference counter of an entry, dropping it when it reaches zero
+pub(crate) fn release_entry<K: StableType + AsFixedSizeBytes, V: StableType + AsFixedSizeBytes>(
+    entry: StablePtr,
+) {
+    let rc = read_rc(entry);
+    if rc > 1 {
+        write_rc(entry, rc - 1);
+        return;
+    }
+
+    unsafe {
+        crate::mem::read_fixed_for_move::<K>(key_ptr(entry));
+        crate::mem::read_fixed_for_move::<V>(value_ptr::<K>(entry));
+
+        deallocate(SSlice::from_ptr(entry).unwrap());
+    }
+}
+
+/// Decrements the reference counter of a node, dropping the whole subtree, which is not shared with
+/// other versions, when it reaches zero
+pub(crate) fn release_node<K: StableType + AsFixedSizeBytes, V: StableType + AsFixedSizeBytes>(
+    ptr: StablePtr,
+) {
+    let mut stack = vec![ptr];
+
+    while let Some(ptr) = stack.pop() {
+        if ptr == EMPTY_PTR {
+            continue;
+        }
+
+        let rc = read_rc(ptr);
+        if rc > 1 {
+            write_rc(ptr, rc - 1);
+            continue;
+        }
+
+        let node = Node::read(ptr);
+        stack.push(node.left);
+        stack.push(node.right);
+
+        release_entry::<K, V>(node.entry);
+        deallocate(unsafe { SSlice::from_ptr(ptr).unwrap() });
+    }
+}
+
+/// Reports all nodes and entries of a version (plus memory owned by keys and values) to the
+/// [orphan collector](crate::mem::orphan_collector)
+#[cfg(feature = "orphan_collector")]
+pub(crate) fn trace<
+    K: StableType + AsFixedSizeBytes + Trace,
+    V: StableType + AsFixedSizeBytes + Trace,
+>(
+    root: StablePtr,
+    ptrs: &mut Vec<StablePtr>,
+) {
+    let mut stack = vec![root];
+
+    while let Some(ptr) = stack.pop() {
+        if ptr == EMPTY_PTR {
+            continue;
+        }
+
+        let node = Node::read(ptr);
+        stack.push(node.left);
+        stack.push(node.right);
+
+        ptrs.push(ptr);
+        ptrs.push(node.entry);
+
+        if K::OWNS_MEMORY {
+            unsafe { SRef::<K>::new(key_ptr(node.entry)) }.trace(ptrs);
+        }
+
+        if V::OWNS_MEMORY {
+            unsafe { SRef::<V>::new(value_ptr::<K>(node.entry)) }.trace(ptrs);
+        }
+    }
+}
+
+/// Returns a version of the node, which is exclusively owned by the caller, copying the node into a
+/// slice from the pool if it is shared
+fn make_mut(ptr: StablePtr, pool: &mut NodePool) -> Node {
+    let mut node = Node::read(ptr);
+    if node.rc == 1 {
+        return node;
+    }
+
+    write_rc(ptr, node.rc - 1);
+    retain(node.left);
+    retain(node.right);
+    retain(node.entry);
+
+    node.ptr = pool.take();
+    node.rc = 1;
+    node.write();
+
+    node
+}
+
+/// Returns the entry with this key
+pub(crate) fn find<K, Q>(mut ptr: StablePtr, key: &Q) -> Option<StablePtr>
+where
+    K: StableType + AsFixedSizeBytes + Borrow<Q>,
+    Q: Ord + ?Sized,
+{
+    while ptr != EMPTY_PTR {
+        let node = Node::read(ptr);
+        let node_key = read_key::<K>(node.entry);
+
+        ptr = match key.cmp(node_key.borrow()) {
+            Ordering::Equal => return Some(node.entry),
+            Ordering::Less => node.left,
+            Ordering::Greater => node.right,
+        };
+    }
+
+    None
+}
+
+/// Returns the number of nodes, which have to be copied in order to insert this key, and whether the
+/// key is already present
+///
+/// Once a shared node is met on the way down, all nodes below it are shared as well.
+pub(crate) fn insert_cost<K: StableType + AsFixedSizeBytes + Ord>(
+    mut ptr: StablePtr,
+    key: &K,
+) -> (usize, bool) {
+    let mut shared = false;
+    let mut cost = 0;
+
+    while ptr != EMPTY_PTR {
+        let node = Node::read(ptr);
+
+        shared |= node.rc > 1;
+        if shared {
+            cost += 1;
+        }
+
+        ptr = match key.cmp(&read_key::<K>(node.entry)) {
+            Ordering::Equal => return (cost, true),
+            Ordering::Less => node.left,
+            Ordering::Greater => node.right,
+        };
+    }
+
+    (cost, false)
+}
+
+/// Returns the number of nodes, which have to be copied in order to remove this key, or [None] if the
+/// key is not present
+pub(crate) fn remove_cost<K, Q>(mut ptr: StablePtr, key: &Q) -> Option<usize>
+where
+    K: StableType + AsFixedSizeBytes + Borrow<Q>,
+    Q: Ord + ?Sized,
+{
+    let mut shared = false;
+    let mut cost = 0;
+
+    let target = loop {
+        if ptr == EMPTY_PTR {
+            return None;
+        }
+
+        let node = Node::read(ptr);
+
+        shared |= node.rc > 1;
+        if shared {
+            cost += 1;
+        }
+
+        ptr = match key.cmp(read_key::<K>(node.entry).borrow()) {
+            Ordering::Equal => break node,
+            Ordering::Less => node.left,
+            Ordering::Greater => node.right,
+        };
+    };
+
+    // merging children of the removed node walks down their inner spines
+    for (mut ptr, right_spine) in [(target.left, true), (target.right, false)] {
+        let mut shared = shared;
+
+        while ptr != EMPTY_PTR {
+            let node = Node::read(ptr);
+
+            shared |= node.rc > 1;
+            if shared {
+                cost += 1;
+            }
+
+            ptr = if right_spine { node.right } else { node.left };
+        }
+    }
+
+    Some(cost)
+}
+
+/// Inserts the entry into the subtree, consuming the reference to it
+///
+/// Returns the new root of the subtree and the replaced entry, if the key was already present.
+pub(crate) fn insert<K: StableType + AsFixedSizeBytes + Ord>(
+    ptr: StablePtr,
+    key: &K,
+    entry: StablePtr,
+    priority: u64,
+    pool: &mut NodePool,
+) -> (StablePtr, Option<StablePtr>) {
+    if ptr == EMPTY_PTR {
+        let node = Node {
+            ptr: pool.take(),
+            rc: 1,
+            priority,
+            left: EMPTY_PTR,
+            right: EMPTY_PTR,
+            entry,
+        };
+        node.write();
+
+        return (node.ptr, None);
+    }
+
+    let mut node = make_mut(ptr, pool);
+
+    match key.cmp(&read_key::<K>(node.entry)) {
+        Ordering::Equal => {
+            let replaced = node.entry;
+
+            node.entry = entry;
+            node.write();
+
+            (node.ptr, Some(replaced))
+        }
+        Ordering::Less => {
+            let (child_ptr, replaced) = insert(node.left, key, entry, priority, pool);
+            let mut child = Node::read(child_ptr);
+
+            // rotate right, both nodes are exclusively owned at this point
+            if child.priority > node.priority {
+                node.left = child.right;
+                node.write();
+
+                child.right = node.ptr;
+                child.write();
+
+                return (child.ptr, replaced);
+            }
+
+            node.left = child_ptr;
+            node.write();
+
+            (node.ptr, replaced)
+        }
+        Ordering::Greater => {
+            let (child_ptr, replaced) = insert(node.right, key, entry, priority, pool);
+            let mut child = Node::read(child_ptr);
+
+            // rotate left
+            if child.priority > node.priority {
+                node.right = child.left;
+                node.write();
+
+                child.left = node.ptr;
+                child.write();
+
+                return (child.ptr, replaced);
+            }
+
+            node.right = child_ptr;
+            node.write();
+
+            (node.ptr, replaced)
+        }
+    }
+}
+
+/// Removes the key from the subtree, consuming the reference to it
+///
+/// Returns the new root of the subtree and the removed entry. The key should be present.
+pub(crate) fn remove<K, Q>(ptr: StablePtr, key: &Q, pool: &mut NodePool) -> (StablePtr, StablePtr)
+where
+    K: StableType + AsFixedSizeBytes + Borrow<Q>,
+    Q: Ord + ?Sized,
+{
+    let mut node = make_mut(ptr, pool);
+
+    match key.cmp(read_key::<K>(node.entry).borrow()) {
+        Ordering::Equal => {
+            let merged = merge(node.left, node.right, pool);
+            deallocate(unsafe { SSlice::from_ptr(node.ptr).unwrap() });
+
+            (merged, node.entry)
+        }
+        Ordering::Less => {
+            let (child, removed) = remove::<K, Q>(node.left, key, pool);
+
+            node.left = child;
+            node.write();
+
+            (node.ptr, removed)
+        }
+        Ordering::Greater => {
+            let (child, removed) = remove::<K, Q>(node.right, key, pool);
+
+            node.right = child;
+            node.write();
+
+            (node.ptr, removed)
+        }
+    }
+}
+
+// all keys of `left` are less than all keys of `right`
+fn merge(left: StablePtr, right: StablePtr, pool: &mut NodePool) -> StablePtr {
+    if left == EMPTY_PTR {
+        return right;
+    }
+
+    if right == EMPTY_PTR {
+        return left;
+    }
+
+    if Node::read(left).priority > Node::read(right).priority {
+        let mut node = make_mut(left, pool);
+        node.right = merge(node.right, right, pool);
+        node.write();
+
+        node.ptr
+    } else {
+        let mut node = make_mut(right, pool);
+        node.left = merge(left, node.left, pool);
+        node.write();
+
+        node.ptr
+    }
+}
@@ -0,0 +1,221 @@
+use crate::collections::versioned_map::iter::SVersionedMapIter;
+use crate::collections::versioned_map::node::{find, release_node, retain, value_ptr};
+use crate::encoding::AsFixedSizeBytes;
+use crate::mem::allocator::EMPTY_PTR;
+#[cfg(feature = "orphan_collector")]
+use crate::mem::orphan_collector::Trace;
+use crate::mem::StablePtr;
+use crate::primitive::s_ref::SRef;
+use crate::primitive::StableType;
+use std::borrow::Borrow;
+use std::fmt::{Debug, Formatter};
+use std::iter::Rev;
+use std::marker::PhantomData;
+
+/// Read-only version of [SVersionedMap](crate::collections::SVersionedMap), pinned at the moment it
+/// was taken
+///
+/// Keeps all the nodes of that version alive, until dropped. Subsequent updates of the map are not
+/// visible through the snapshot.
+///
+/// Implements [StableType] and [AsFixedSizeBytes], so it can be stored in stable memory (for example,
+/// to continue a paginated read in the next call to the canister).
+pub struct SVersionedMapSnapshot<K, V> {
+    root: StablePtr,
+    len: u64,
+    stable_drop_flag: bool,
+    _marker: PhantomData<(K, V)>,
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes>
+    SVersionedMapSnapshot<K, V>
+{
+    // the caller should have already retained the root
+    #[inline]
+    pub(crate) fn new(root: StablePtr, len: u64) -> Self {
+        Self {
+            root,
+            len,
+            stable_drop_flag: true,
+            _marker: PhantomData::default(),
+        }
+    }
+
+    /// Returns an immutable reference to the value by this key, as it was at the moment of taking
+    /// the snapshot
+    #[inline]
+    pub fn get<Q>(&self, key: &Q) -> Option<SRef<'_, V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let entry = find::<K, Q>(self.root, key)?;
+
+        unsafe { Some(SRef::new(value_ptr::<K>(entry))) }
+    }
+
+    /// Returns `true` if there was an entry with this key at the moment of taking the snapshot
+    #[inline]
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        find::<K, Q>(self.root, key).is_some()
+    }
+
+    /// Returns an iterator over all entries of the snapshot in ascending order of their keys
+    #[inline]
+    pub fn iter(&self) -> SVersionedMapIter<'_, K, V> {
+        SVersionedMapIter::new(self.root)
+    }
+
+    /// Returns an iterator over entries of the snapshot with keys greater than or equal to this key,
+    /// in ascending order
+    ///
+    /// Useful for paginated reads: pass the key, right after the last one from the previous page.
+    #[inline]
+    pub fn iter_from<Q>(&self, key: &Q) -> SVersionedMapIter<'_, K, V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        SVersionedMapIter::new_from(self.root, key)
+    }
+
+    /// Returns an iterator over all entries of the snapshot in descending order of their keys
+    #[inline]
+    pub fn iter_rev(&self) -> Rev<SVersionedMapIter<'_, K, V>> {
+        self.iter().rev()
+    }
+
+    /// Returns an iterator over entries of the snapshot with keys less than or equal to this key, in descending
+    /// order
+    #[inline]
+    pub fn iter_rev_from<Q>(&self, key: &Q) -> Rev<SVersionedMapIter<'_, K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        SVersionedMapIter::new_until(self.root, key).rev()
+    }
+
+    /// Returns the number of entries in the snapshot
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns `true` if the snapshot has no entries
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> Clone
+    for SVersionedMapSnapshot<K, V>
+{
+    /// Pins the same version once again
+    ///
+    /// Does not allocate any stable memory.
+    #[inline]
+    fn clone(&self) -> Self {
+        retain(self.root);
+
+        Self::new(self.root, self.len)
+    }
+}
+
+impl<K: StableType + AsFixedSizeBytes, V: StableType + AsFixedSizeBytes> AsFixedSizeBytes
+    for SVersionedMapSnapshot<K, V>
+{
+    const SIZE: usize = u64::SIZE * 2;
+    type Buf = [u8; u64::SIZE * 2];
+
+    #[inline]
+    fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+        self.root.as_fixed_size_bytes(&mut buf[0..u64::SIZE]);
+        self.len
+            .as_fixed_size_bytes(&mut buf[u64::SIZE..(u64::SIZE * 2)]);
+    }
+
+    #[inline]
+    fn from_fixed_size_bytes(buf: &[u8]) -> Self {
+        Self {
+            root: u64::from_fixed_size_bytes(&buf[0..u64::SIZE]),
+            len: u64::from_fixed_size_bytes(&buf[u64::SIZE..(u64::SIZE * 2)]),
+            stable_drop_flag: false,
+            _marker: PhantomData::default(),
+        }
+    }
+}
+
+impl<K: StableType + AsFixedSizeBytes, V: StableType + AsFixedSizeBytes> StableType
+    for SVersionedMapSnapshot<K, V>
+{
+    #[inline]
+    unsafe fn stable_drop_flag_off(&mut self) {
+        self.stable_drop_flag = false;
+    }
+
+    #[inline]
+    unsafe fn stable_drop_flag_on(&mut self) {
+        self.stable_drop_flag = true;
+    }
+
+    #[inline]
+    fn should_stable_drop(&self) -> bool {
+        self.stable_drop_flag
+    }
+
+    #[inline]
+    unsafe fn stable_drop(&mut self) {
+        if self.root != EMPTY_PTR {
+            release_node::<K, V>(self.root);
+            self.root = EMPTY_PTR;
+        }
+    }
+}
+
+impl<K: StableType + AsFixedSizeBytes, V: StableType + AsFixedSizeBytes> Drop
+    for SVersionedMapSnapshot<K, V>
+{
+    fn drop(&mut self) {
+        if self.should_stable_drop() {
+            unsafe {
+                self.stable_drop();
+            }
+        }
+    }
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord + Debug, V: StableType + AsFixedSizeBytes + Debug> Debug
+    for SVersionedMapSnapshot<K, V>
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("{")?;
+
+        for (idx, (k, v)) in self.iter().enumerate() {
+            k.fmt(f)?;
+            f.write_str(": ")?;
+            v.fmt(f)?;
+
+            if (idx as u64) < self.len - 1 {
+                f.write_str(", ")?;
+            }
+        }
+
+        f.write_str("}")
+    }
+}
+
+#[cfg(feature = "orphan_collector")]
+impl<K: StableType + AsFixedSizeBytes + Trace, V: StableType + AsFixedSizeBytes + Trace> Trace
+    for SVersionedMapSnapshot<K, V>
+{
+    #[inline]
+    fn trace(&self, ptrs: &mut Vec<StablePtr>) {
+        crate::collections::versioned_map::node::trace::<K, V>(self.root, ptrs)
+    }
+}
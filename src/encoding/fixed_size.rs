@@ -15,16 +15,20 @@ use candid::{Int, Nat, Principal};
 use ic_stable_memory_derive::{AsFixedSizeBytes, StableType};
 use num_bigint::{BigInt, BigUint, Sign};
 use ic_ledger_types::Subaccount;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
 
 /// Allows fast and space-efficient fixed size data encoding.
 ///
 /// This trait can be implemented by using [derive::AsFixedSizeBytes] macro.
 /// By default it is implemented for the following types:
-/// 1. All primitive types: [i8], [u8], [i16], [u16], [i32], [u32], [i64], [u64], [i128], [u128], [f32], [f64], [bool], [()]
+/// 1. All primitive types: [i8], [u8], [i16], [u16], [i32], [u32], [i64], [u64], [i128], [u128], [isize], [usize], [f32], [f64], [bool], [()]
+/// ([isize] and [usize] are encoded with their native width, or always as 8 bytes with `portable_usize` feature)
 /// 2. Primitive type generic arrays: [i8; N], [u8; N], [i16; N], [u16; N], [i32; N], [u32; N], [i64: N], [u64; N], [i128; N], [u128; N], [f32; N], [f64; N], [bool; N], [(); N]
-/// 3. Tuples up to 6 elements, where each element implements [AsFixedSizeBytes]
+/// 3. Tuples up to 8 elements, where each element implements [AsFixedSizeBytes]
 /// 4. [Option] of `T`, where `T`: [AsFixedSizeBytes]
-/// 5. IC native types: [candid::Principal], [candid::Nat], [candid::Int]
+/// 5. IC native types: [candid::Principal], [candid::Nat], [candid::Int], [ic_ledger_types::Subaccount]
+/// 6. [std::time::Duration]
 pub trait AsFixedSizeBytes {
     /// Size of self when encoded
     const SIZE: usize;
@@ -44,6 +48,23 @@ pub trait AsFixedSizeBytes {
     /// Will panic if out of bounds.
     fn from_fixed_size_bytes(buf: &[u8]) -> Self;
 
+    /// Decodes itself from a slice of bytes, validating them.
+    ///
+    /// Unlike [AsFixedSizeBytes::from_fixed_size_bytes], returns a [CorruptData] error instead of
+    /// panicking, when the slice is too short or is not a valid encoding (e.g. contains an unknown
+    /// discriminant). The default implementation only checks the length of the slice, types, which
+    /// encodings can be invalid, should override it.
+    fn try_from_fixed_size_bytes(buf: &[u8]) -> Result<Self, CorruptData>
+    where
+        Self: Sized,
+    {
+        if buf.len() < Self::SIZE {
+            return Err(CorruptData::new(buf.len() as u64, "Unexpected end of data"));
+        }
+
+        Ok(Self::from_fixed_size_bytes(&buf[0..Self::SIZE]))
+    }
+
     /// Encodes itself into a new [Self::Buf] of size == [Self::SIZE]
     fn as_new_fixed_size_bytes(&self) -> Self::Buf {
         let mut buf = Self::Buf::new(Self::SIZE);
@@ -53,6 +74,51 @@ pub trait AsFixedSizeBytes {
     }
 }
 
+/// Indicates that some bytes are not a valid encoding of the expected type
+///
+/// See [AsFixedSizeBytes::try_from_fixed_size_bytes] and [try_read_fixed_for_reference](crate::mem::try_read_fixed_for_reference).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct CorruptData {
+    /// Offset of the invalid bytes - relative to the beginning of the decoded slice, when returned by
+    /// [AsFixedSizeBytes::try_from_fixed_size_bytes], or a stable memory pointer, when returned by
+    /// functions reading stable memory
+    pub offset: u64,
+    /// What exactly is wrong with the bytes
+    pub reason: &'static str,
+}
+
+impl CorruptData {
+    #[inline]
+    pub(crate) fn new(offset: u64, reason: &'static str) -> Self {
+        Self { offset, reason }
+    }
+
+    #[inline]
+    pub(crate) fn shifted(mut self, by: u64) -> Self {
+        self.offset += by;
+        self
+    }
+}
+
+impl Display for CorruptData {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Corrupt data at offset {}: {}", self.offset, self.reason)
+    }
+}
+
+// decodes a field, starting at `from`, moving `from` to the end of the field
+#[inline]
+fn try_field<T: AsFixedSizeBytes>(buf: &[u8], from: &mut usize) -> Result<T, CorruptData> {
+    let start = *from;
+    *from += T::SIZE;
+
+    if buf.len() < *from {
+        return Err(CorruptData::new(buf.len() as u64, "Unexpected end of data"));
+    }
+
+    T::try_from_fixed_size_bytes(&buf[start..*from]).map_err(|e| e.shifted(start as u64))
+}
+
 macro_rules! impl_for_number {
     ($ty:ty) => {
         impl AsFixedSizeBytes for $ty {
@@ -85,11 +151,50 @@ impl_for_number!(i64);
 impl_for_number!(u64);
 impl_for_number!(i128);
 impl_for_number!(u128);
-impl_for_number!(isize);
-impl_for_number!(usize);
 impl_for_number!(f32);
 impl_for_number!(f64);
 
+#[cfg(not(feature = "portable_usize"))]
+impl_for_number!(isize);
+#[cfg(not(feature = "portable_usize"))]
+impl_for_number!(usize);
+
+// with `portable_usize` feature pointer-sized integers are always encoded as 64-bit ones, so stable
+// memory layout is the same on wasm32, wasm64 and in native tests
+#[cfg(feature = "portable_usize")]
+macro_rules! impl_for_pointer_sized_number {
+    ($ty:ty, $as:ty) => {
+        impl AsFixedSizeBytes for $ty {
+            const SIZE: usize = <$as>::SIZE;
+            type Buf = [u8; Self::SIZE];
+
+            #[inline]
+            fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+                (*self as $as).as_fixed_size_bytes(buf)
+            }
+
+            #[inline]
+            fn from_fixed_size_bytes(buf: &[u8]) -> Self {
+                <$ty>::try_from(<$as>::from_fixed_size_bytes(buf))
+                    .expect("The value doesn't fit into a pointer-sized integer")
+            }
+
+            fn try_from_fixed_size_bytes(buf: &[u8]) -> Result<Self, CorruptData> {
+                let it = try_field::<$as>(buf, &mut 0)?;
+
+                <$ty>::try_from(it).map_err(|_| {
+                    CorruptData::new(0, "The value doesn't fit into a pointer-sized integer")
+                })
+            }
+        }
+    };
+}
+
+#[cfg(feature = "portable_usize")]
+impl_for_pointer_sized_number!(isize, i64);
+#[cfg(feature = "portable_usize")]
+impl_for_pointer_sized_number!(usize, u64);
+
 impl AsFixedSizeBytes for char {
     const SIZE: usize = u32::SIZE;
     type Buf = [u8; Self::SIZE];
@@ -101,6 +206,12 @@ impl AsFixedSizeBytes for char {
     fn from_fixed_size_bytes(buf: &[u8]) -> Self {
         char::try_from(u32::from_fixed_size_bytes(buf)).unwrap()
     }
+
+    fn try_from_fixed_size_bytes(buf: &[u8]) -> Result<Self, CorruptData> {
+        let it = try_field::<u32>(buf, &mut 0)?;
+
+        char::try_from(it).map_err(|_| CorruptData::new(0, "Invalid char"))
+    }
 }
 
 impl AsFixedSizeBytes for () {
@@ -133,6 +244,16 @@ impl AsFixedSizeBytes for bool {
 
         buf[0] == 1
     }
+
+    #[inline]
+    fn try_from_fixed_size_bytes(buf: &[u8]) -> Result<Self, CorruptData> {
+        match buf.first() {
+            Some(0) => Ok(false),
+            Some(1) => Ok(true),
+            Some(_) => Err(CorruptData::new(0, "Invalid bool")),
+            None => Err(CorruptData::new(0, "Unexpected end of data")),
+        }
+    }
 }
 
 impl<T: AsFixedSizeBytes> AsFixedSizeBytes for Option<T> {
@@ -157,6 +278,15 @@ impl<T: AsFixedSizeBytes> AsFixedSizeBytes for Option<T> {
             None
         }
     }
+
+    fn try_from_fixed_size_bytes(buf: &[u8]) -> Result<Self, CorruptData> {
+        match buf.first() {
+            Some(0) => Ok(None),
+            Some(1) => try_field::<T>(buf, &mut 1).map(Some),
+            Some(_) => Err(CorruptData::new(0, "Invalid Option discriminant")),
+            None => Err(CorruptData::new(0, "Unexpected end of data")),
+        }
+    }
 }
 
 impl<const N: usize> AsFixedSizeBytes for [(); N] {
@@ -199,6 +329,17 @@ macro_rules! impl_for_single_byte_type_arr {
 
                 it
             }
+
+            fn try_from_fixed_size_bytes(buf: &[u8]) -> Result<Self, CorruptData> {
+                let mut it = [$zero; N];
+                let mut from = 0;
+
+                for i in 0..N {
+                    it[i] = try_field::<$ty>(buf, &mut from)?;
+                }
+
+                Ok(it)
+            }
         }
     };
 }
@@ -210,7 +351,7 @@ impl_for_single_byte_type_arr!(u8, 0);
 macro_rules! impl_for_number_arr {
     ($ty:ty, $zero:expr) => {
         impl<const N: usize> AsFixedSizeBytes for [$ty; N] {
-            const SIZE: usize = N;
+            const SIZE: usize = N * <$ty>::SIZE;
             type Buf = Vec<u8>;
 
             fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
@@ -234,6 +375,17 @@ macro_rules! impl_for_number_arr {
 
                 it
             }
+
+            fn try_from_fixed_size_bytes(buf: &[u8]) -> Result<Self, CorruptData> {
+                let mut it = [$zero; N];
+                let mut from = 0;
+
+                for i in 0..N {
+                    it[i] = try_field::<$ty>(buf, &mut from)?;
+                }
+
+                Ok(it)
+            }
         }
     };
 }
@@ -274,6 +426,17 @@ impl<const N: usize> AsFixedSizeBytes for [char; N] {
 
         s
     }
+
+    fn try_from_fixed_size_bytes(buf: &[u8]) -> Result<Self, CorruptData> {
+        let mut s = [char::default(); N];
+        let mut from = 0;
+
+        for c in s.iter_mut() {
+            *c = try_field::<char>(buf, &mut from)?;
+        }
+
+        Ok(s)
+    }
 }
 
 impl<A: AsFixedSizeBytes> AsFixedSizeBytes for (A,) {
@@ -287,6 +450,12 @@ impl<A: AsFixedSizeBytes> AsFixedSizeBytes for (A,) {
     fn from_fixed_size_bytes(buf: &[u8]) -> Self {
         (A::from_fixed_size_bytes(buf),)
     }
+
+    fn try_from_fixed_size_bytes(buf: &[u8]) -> Result<Self, CorruptData> {
+        let mut from = 0;
+
+        Ok((try_field::<A>(buf, &mut from)?,))
+    }
 }
 impl<A: AsFixedSizeBytes, B: AsFixedSizeBytes> AsFixedSizeBytes for (A, B) {
     const SIZE: usize = A::SIZE + B::SIZE;
@@ -304,6 +473,15 @@ impl<A: AsFixedSizeBytes, B: AsFixedSizeBytes> AsFixedSizeBytes for (A, B) {
             B::from_fixed_size_bytes(&buf[A::SIZE..(A::SIZE + B::SIZE)]),
         )
     }
+
+    fn try_from_fixed_size_bytes(buf: &[u8]) -> Result<Self, CorruptData> {
+        let mut from = 0;
+
+        Ok((
+            try_field::<A>(buf, &mut from)?,
+            try_field::<B>(buf, &mut from)?,
+        ))
+    }
 }
 impl<A: AsFixedSizeBytes, B: AsFixedSizeBytes, C: AsFixedSizeBytes> AsFixedSizeBytes for (A, B, C) {
     const SIZE: usize = A::SIZE + B::SIZE + C::SIZE;
@@ -324,6 +502,16 @@ impl<A: AsFixedSizeBytes, B: AsFixedSizeBytes, C: AsFixedSizeBytes> AsFixedSizeB
             C::from_fixed_size_bytes(&buf[(A::SIZE + B::SIZE)..(A::SIZE + B::SIZE + C::SIZE)]),
         )
     }
+
+    fn try_from_fixed_size_bytes(buf: &[u8]) -> Result<Self, CorruptData> {
+        let mut from = 0;
+
+        Ok((
+            try_field::<A>(buf, &mut from)?,
+            try_field::<B>(buf, &mut from)?,
+            try_field::<C>(buf, &mut from)?,
+        ))
+    }
 }
 impl<A: AsFixedSizeBytes, B: AsFixedSizeBytes, C: AsFixedSizeBytes, D: AsFixedSizeBytes>
     AsFixedSizeBytes for (A, B, C, D)
@@ -352,6 +540,17 @@ impl<A: AsFixedSizeBytes, B: AsFixedSizeBytes, C: AsFixedSizeBytes, D: AsFixedSi
             ),
         )
     }
+
+    fn try_from_fixed_size_bytes(buf: &[u8]) -> Result<Self, CorruptData> {
+        let mut from = 0;
+
+        Ok((
+            try_field::<A>(buf, &mut from)?,
+            try_field::<B>(buf, &mut from)?,
+            try_field::<C>(buf, &mut from)?,
+            try_field::<D>(buf, &mut from)?,
+        ))
+    }
 }
 impl<
         A: AsFixedSizeBytes,
@@ -393,6 +592,18 @@ impl<
             ),
         )
     }
+
+    fn try_from_fixed_size_bytes(buf: &[u8]) -> Result<Self, CorruptData> {
+        let mut from = 0;
+
+        Ok((
+            try_field::<A>(buf, &mut from)?,
+            try_field::<B>(buf, &mut from)?,
+            try_field::<C>(buf, &mut from)?,
+            try_field::<D>(buf, &mut from)?,
+            try_field::<E>(buf, &mut from)?,
+        ))
+    }
 }
 impl<
         A: AsFixedSizeBytes,
@@ -443,6 +654,187 @@ impl<
             ),
         )
     }
+
+    fn try_from_fixed_size_bytes(buf: &[u8]) -> Result<Self, CorruptData> {
+        let mut from = 0;
+
+        Ok((
+            try_field::<A>(buf, &mut from)?,
+            try_field::<B>(buf, &mut from)?,
+            try_field::<C>(buf, &mut from)?,
+            try_field::<D>(buf, &mut from)?,
+            try_field::<E>(buf, &mut from)?,
+            try_field::<F>(buf, &mut from)?,
+        ))
+    }
+}
+
+impl<
+        A: AsFixedSizeBytes,
+        B: AsFixedSizeBytes,
+        C: AsFixedSizeBytes,
+        D: AsFixedSizeBytes,
+        E: AsFixedSizeBytes,
+        F: AsFixedSizeBytes,
+        G: AsFixedSizeBytes,
+    > AsFixedSizeBytes for (A, B, C, D, E, F, G)
+{
+    const SIZE: usize = A::SIZE + B::SIZE + C::SIZE + D::SIZE + E::SIZE + F::SIZE + G::SIZE;
+    type Buf = Vec<u8>;
+
+    fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+        let (a, buf) = buf.split_at_mut(A::SIZE);
+        let (b, buf) = buf.split_at_mut(B::SIZE);
+        let (c, buf) = buf.split_at_mut(C::SIZE);
+        let (d, buf) = buf.split_at_mut(D::SIZE);
+        let (e, buf) = buf.split_at_mut(E::SIZE);
+        let (f, buf) = buf.split_at_mut(F::SIZE);
+
+        self.0.as_fixed_size_bytes(a);
+        self.1.as_fixed_size_bytes(b);
+        self.2.as_fixed_size_bytes(c);
+        self.3.as_fixed_size_bytes(d);
+        self.4.as_fixed_size_bytes(e);
+        self.5.as_fixed_size_bytes(f);
+        self.6.as_fixed_size_bytes(&mut buf[0..G::SIZE]);
+    }
+
+    fn from_fixed_size_bytes(buf: &[u8]) -> Self {
+        let (a, buf) = buf.split_at(A::SIZE);
+        let (b, buf) = buf.split_at(B::SIZE);
+        let (c, buf) = buf.split_at(C::SIZE);
+        let (d, buf) = buf.split_at(D::SIZE);
+        let (e, buf) = buf.split_at(E::SIZE);
+        let (f, buf) = buf.split_at(F::SIZE);
+
+        (
+            A::from_fixed_size_bytes(a),
+            B::from_fixed_size_bytes(b),
+            C::from_fixed_size_bytes(c),
+            D::from_fixed_size_bytes(d),
+            E::from_fixed_size_bytes(e),
+            F::from_fixed_size_bytes(f),
+            G::from_fixed_size_bytes(&buf[0..G::SIZE]),
+        )
+    }
+
+    fn try_from_fixed_size_bytes(buf: &[u8]) -> Result<Self, CorruptData> {
+        let mut from = 0;
+
+        Ok((
+            try_field::<A>(buf, &mut from)?,
+            try_field::<B>(buf, &mut from)?,
+            try_field::<C>(buf, &mut from)?,
+            try_field::<D>(buf, &mut from)?,
+            try_field::<E>(buf, &mut from)?,
+            try_field::<F>(buf, &mut from)?,
+            try_field::<G>(buf, &mut from)?,
+        ))
+    }
+}
+
+impl<
+        A: AsFixedSizeBytes,
+        B: AsFixedSizeBytes,
+        C: AsFixedSizeBytes,
+        D: AsFixedSizeBytes,
+        E: AsFixedSizeBytes,
+        F: AsFixedSizeBytes,
+        G: AsFixedSizeBytes,
+        H: AsFixedSizeBytes,
+    > AsFixedSizeBytes for (A, B, C, D, E, F, G, H)
+{
+    const SIZE: usize =
+        A::SIZE + B::SIZE + C::SIZE + D::SIZE + E::SIZE + F::SIZE + G::SIZE + H::SIZE;
+    type Buf = Vec<u8>;
+
+    fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+        let (a, buf) = buf.split_at_mut(A::SIZE);
+        let (b, buf) = buf.split_at_mut(B::SIZE);
+        let (c, buf) = buf.split_at_mut(C::SIZE);
+        let (d, buf) = buf.split_at_mut(D::SIZE);
+        let (e, buf) = buf.split_at_mut(E::SIZE);
+        let (f, buf) = buf.split_at_mut(F::SIZE);
+        let (g, buf) = buf.split_at_mut(G::SIZE);
+
+        self.0.as_fixed_size_bytes(a);
+        self.1.as_fixed_size_bytes(b);
+        self.2.as_fixed_size_bytes(c);
+        self.3.as_fixed_size_bytes(d);
+        self.4.as_fixed_size_bytes(e);
+        self.5.as_fixed_size_bytes(f);
+        self.6.as_fixed_size_bytes(g);
+        self.7.as_fixed_size_bytes(&mut buf[0..H::SIZE]);
+    }
+
+    fn from_fixed_size_bytes(buf: &[u8]) -> Self {
+        let (a, buf) = buf.split_at(A::SIZE);
+        let (b, buf) = buf.split_at(B::SIZE);
+        let (c, buf) = buf.split_at(C::SIZE);
+        let (d, buf) = buf.split_at(D::SIZE);
+        let (e, buf) = buf.split_at(E::SIZE);
+        let (f, buf) = buf.split_at(F::SIZE);
+        let (g, buf) = buf.split_at(G::SIZE);
+
+        (
+            A::from_fixed_size_bytes(a),
+            B::from_fixed_size_bytes(b),
+            C::from_fixed_size_bytes(c),
+            D::from_fixed_size_bytes(d),
+            E::from_fixed_size_bytes(e),
+            F::from_fixed_size_bytes(f),
+            G::from_fixed_size_bytes(g),
+            H::from_fixed_size_bytes(&buf[0..H::SIZE]),
+        )
+    }
+
+    fn try_from_fixed_size_bytes(buf: &[u8]) -> Result<Self, CorruptData> {
+        let mut from = 0;
+
+        Ok((
+            try_field::<A>(buf, &mut from)?,
+            try_field::<B>(buf, &mut from)?,
+            try_field::<C>(buf, &mut from)?,
+            try_field::<D>(buf, &mut from)?,
+            try_field::<E>(buf, &mut from)?,
+            try_field::<F>(buf, &mut from)?,
+            try_field::<G>(buf, &mut from)?,
+            try_field::<H>(buf, &mut from)?,
+        ))
+    }
+}
+
+/// Encoded as whole seconds ([u64]), followed by subsecond nanoseconds ([u32])
+impl AsFixedSizeBytes for Duration {
+    const SIZE: usize = u64::SIZE + u32::SIZE;
+    type Buf = [u8; Self::SIZE];
+
+    #[inline]
+    fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+        self.as_secs().as_fixed_size_bytes(&mut buf[0..u64::SIZE]);
+        self.subsec_nanos()
+            .as_fixed_size_bytes(&mut buf[u64::SIZE..Self::SIZE]);
+    }
+
+    #[inline]
+    fn from_fixed_size_bytes(buf: &[u8]) -> Self {
+        let secs = u64::from_fixed_size_bytes(&buf[0..u64::SIZE]);
+        let nanos = u32::from_fixed_size_bytes(&buf[u64::SIZE..Self::SIZE]);
+
+        Duration::new(secs, nanos)
+    }
+
+    fn try_from_fixed_size_bytes(buf: &[u8]) -> Result<Self, CorruptData> {
+        let mut from = 0;
+        let secs = try_field::<u64>(buf, &mut from)?;
+        let nanos = try_field::<u32>(buf, &mut from)?;
+
+        if nanos >= 1_000_000_000 {
+            return Err(CorruptData::new(u64::SIZE as u64, "Invalid Duration nanoseconds"));
+        }
+
+        Ok(Duration::new(secs, nanos))
+    }
 }
 
 impl AsFixedSizeBytes for Principal {
@@ -461,6 +853,20 @@ impl AsFixedSizeBytes for Principal {
 
         Principal::from_slice(&buf[1..(1 + len)])
     }
+
+    fn try_from_fixed_size_bytes(buf: &[u8]) -> Result<Self, CorruptData> {
+        if buf.len() < Self::SIZE {
+            return Err(CorruptData::new(buf.len() as u64, "Unexpected end of data"));
+        }
+
+        // principals are at most 29 bytes long
+        let len = buf[0] as usize;
+        if len > Self::SIZE - 1 {
+            return Err(CorruptData::new(0, "Invalid Principal length"));
+        }
+
+        Ok(Principal::from_slice(&buf[1..(1 + len)]))
+    }
 }
 
 impl AsFixedSizeBytes for Subaccount{
@@ -516,13 +922,25 @@ impl AsFixedSizeBytes for Int {
             0 => Sign::Plus,
             1 => Sign::Minus,
             2 => Sign::NoSign,
-            _ => unreachable!(),
+            _ => panic!("Invalid Int sign"),
         };
 
         let it = BigInt::from_bytes_le(sign, &buf[1..]);
 
         Int(it)
     }
+
+    fn try_from_fixed_size_bytes(buf: &[u8]) -> Result<Self, CorruptData> {
+        if buf.len() < Self::SIZE {
+            return Err(CorruptData::new(buf.len() as u64, "Unexpected end of data"));
+        }
+
+        if buf[0] > 2 {
+            return Err(CorruptData::new(0, "Invalid Int sign"));
+        }
+
+        Ok(Self::from_fixed_size_bytes(&buf[0..Self::SIZE]))
+    }
 }
 
 /// Either [u8; N] or [Vec] of [u8]
@@ -586,4 +1004,100 @@ fn subaccount_test() {
   let acc_copy = Subaccount::from_fixed_size_bytes(&buf);
 
   assert_eq!(acc, acc_copy);
-}
\ No newline at end of file
+}
+#[test]
+fn pointer_sized_numbers_test() {
+  assert_eq!(usize::SIZE, std::mem::size_of::<usize>());
+  assert_eq!(isize::SIZE, std::mem::size_of::<isize>());
+
+  let buf = 123usize.as_new_fixed_size_bytes();
+  assert_eq!(usize::from_fixed_size_bytes(&buf), 123);
+
+  let buf = (-123isize).as_new_fixed_size_bytes();
+  assert_eq!(isize::from_fixed_size_bytes(&buf), -123);
+
+  assert_eq!(<[usize; 3]>::SIZE, usize::SIZE * 3);
+  let arr = [1usize, 2, 3];
+  let buf = arr.as_new_fixed_size_bytes();
+  assert_eq!(buf.len(), usize::SIZE * 3);
+  assert_eq!(<[usize; 3]>::from_fixed_size_bytes(&buf), arr);
+}
+
+#[cfg(feature = "portable_usize")]
+#[test]
+fn portable_pointer_sized_numbers_test() {
+  assert_eq!(usize::SIZE, 8);
+  assert_eq!(isize::SIZE, 8);
+
+  assert_eq!(123usize.as_new_fixed_size_bytes(), 123u64.as_new_fixed_size_bytes());
+  assert_eq!((-123isize).as_new_fixed_size_bytes(), (-123i64).as_new_fixed_size_bytes());
+}
+#[test]
+fn number_arrays_test() {
+  // the size of a number array is the size of all its elements in bytes
+  assert_eq!(<[u16; 4]>::SIZE, 8);
+  assert_eq!(<[i32; 3]>::SIZE, 12);
+  assert_eq!(<[u128; 2]>::SIZE, 32);
+  assert_eq!(<[f64; 5]>::SIZE, 40);
+
+  let arr = [1u16, 2, 3, 4];
+  let buf = arr.as_new_fixed_size_bytes();
+  assert_eq!(buf.len(), 8);
+  assert_eq!(<[u16; 4]>::from_fixed_size_bytes(&buf), arr);
+
+  let arr = [-1i32, 0, i32::MAX];
+  assert_eq!(<[i32; 3]>::from_fixed_size_bytes(&arr.as_new_fixed_size_bytes()), arr);
+
+  // an array nested into a tuple doesn't overlap its neighbours
+  let t = ([u64::MAX; 2], 7u8);
+  assert_eq!(<([u64; 2], u8)>::SIZE, 17);
+  assert_eq!(<([u64; 2], u8)>::from_fixed_size_bytes(&t.as_new_fixed_size_bytes()), t);
+}
+#[test]
+fn tuples_and_duration_test() {
+  let t7 = (1u8, 2u16, 3u32, 4u64, 5u128, true, 'x');
+  assert_eq!(<(u8, u16, u32, u64, u128, bool, char)>::SIZE, 1 + 2 + 4 + 8 + 16 + 1 + 4);
+  assert_eq!(<(u8, u16, u32, u64, u128, bool, char)>::from_fixed_size_bytes(&t7.as_new_fixed_size_bytes()), t7);
+
+  let t8 = (1u8, 2u16, 3u32, 4u64, 5u128, true, 'x', Some(-6i128));
+  assert_eq!(
+    <(u8, u16, u32, u64, u128, bool, char, Option<i128>)>::from_fixed_size_bytes(&t8.as_new_fixed_size_bytes()),
+    t8
+  );
+
+  let d = Duration::new(123, 456_789);
+  assert_eq!(Duration::SIZE, 12);
+  assert_eq!(Duration::from_fixed_size_bytes(&d.as_new_fixed_size_bytes()), d);
+  assert_eq!(Duration::from_fixed_size_bytes(&Duration::MAX.as_new_fixed_size_bytes()), Duration::MAX);
+}
+#[test]
+fn try_decode_test() {
+  let t = (1u64, Some(true), 'x', Duration::new(1, 2));
+  let mut buf = t.as_new_fixed_size_bytes();
+  assert_eq!(<(u64, Option<bool>, char, Duration)>::try_from_fixed_size_bytes(&buf), Ok(t));
+
+  buf[9] = 2;
+  assert_eq!(
+    <(u64, Option<bool>, char, Duration)>::try_from_fixed_size_bytes(&buf),
+    Err(CorruptData::new(9, "Invalid bool"))
+  );
+
+  buf[8] = 3;
+  assert_eq!(
+    <(u64, Option<bool>, char, Duration)>::try_from_fixed_size_bytes(&buf),
+    Err(CorruptData::new(8, "Invalid Option discriminant"))
+  );
+
+  buf[8] = 0;
+  buf[10..14].copy_from_slice(&0xD800u32.to_le_bytes());
+  assert_eq!(
+    <(u64, Option<bool>, char, Duration)>::try_from_fixed_size_bytes(&buf).err().unwrap().offset,
+    10
+  );
+
+  assert_eq!(
+    <[bool; 3]>::try_from_fixed_size_bytes(&[1, 0, 7]),
+    Err(CorruptData::new(2, "Invalid bool"))
+  );
+  assert_eq!(u64::try_from_fixed_size_bytes(&[1, 2, 3]), Err(CorruptData::new(3, "Unexpected end of data")));
+}
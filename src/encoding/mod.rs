@@ -20,6 +20,7 @@
 
 pub mod dyn_size;
 pub mod fixed_size;
+pub mod ordered;
 
 pub use dyn_size::AsDynSizeBytes;
-pub use fixed_size::{AsFixedSizeBytes, Buffer};
+pub use fixed_size::{AsFixedSizeBytes, Buffer, CorruptData};
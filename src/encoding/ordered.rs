@@ -0,0 +1,273 @@
+//! Order-preserving encoding for composite keys.
+//!
+//! [AsOrderedBytes] encodes values in such a way, that lexicographic order of their encodings is
+//! the same as their [Ord] order. This is what byte-keyed structures, like [SRadixTree](crate::collections::SRadixTree),
+//! need in order to make range and prefix scans over composite keys (e.g. `(tenant, timestamp)`)
+//! behave as expected:
+//! * unsigned integers are encoded as big-endian bytes;
+//! * signed integers are encoded as big-endian bytes with the sign bit flipped;
+//! * strings and byte strings are escaped (`0x00` becomes `0x00 0xFF`) and terminated with `0x00 0x00`,
+//! so a string always sorts before any longer string it is a prefix of;
+//! * tuples are encoded as a concatenation of their elements.
+//!
+//! Since each encoding is self-delimiting, the encoding of a tuple's leading elements is a prefix of
+//! the encoding of the whole tuple.
+//!
+//! # Example
+//! ```rust
+//! # use ic_stable_memory::collections::SRadixTree;
+//! # use ic_stable_memory::encoding::ordered::{from_ordered_bytes, to_ordered_bytes};
+//! # use ic_stable_memory::stable_memory_init;
+//! # unsafe { ic_stable_memory::mem::clear(); }
+//! # stable_memory_init();
+//! let mut events = SRadixTree::new();
+//!
+//! for (tenant, timestamp) in [("b", 20u64), ("a", 300), ("a", 5), ("b", 10)] {
+//!     events.insert(&to_ordered_bytes(&(tenant.to_string(), timestamp)), 0u32).expect("Out of memory");
+//! }
+//!
+//! let of_a = events
+//!     .iter_prefix(&to_ordered_bytes(&String::from("a")))
+//!     .map(|(k, _)| from_ordered_bytes::<(String, u64)>(&k).1)
+//!     .collect::<Vec<_>>();
+//!
+//! assert_eq!(of_a, vec![5, 300]);
+//! ```
+
+/// Allows encoding a value into bytes, which lexicographic order matches the value's [Ord] order.
+///
+/// See the [module-level documentation](crate::encoding::ordered) for details.
+pub trait AsOrderedBytes: Sized {
+    /// Appends the encoding of this value to the buffer
+    fn write_ordered_bytes(&self, buf: &mut Vec<u8>);
+
+    /// Decodes a value from the beginning of the buffer, advancing it past the value
+    ///
+    /// # Panics
+    /// Should panic if the buffer doesn't start with a valid encoding.
+    fn read_ordered_bytes(buf: &mut &[u8]) -> Self;
+}
+
+/// Encodes the value with [AsOrderedBytes]
+#[inline]
+pub fn to_ordered_bytes<T: AsOrderedBytes>(it: &T) -> Vec<u8> {
+    let mut buf = Vec::new();
+    it.write_ordered_bytes(&mut buf);
+
+    buf
+}
+
+/// Decodes a value, encoded with [AsOrderedBytes]
+///
+/// # Panics
+/// Panics if the buffer is not a valid encoding of `T` or contains trailing bytes.
+#[inline]
+pub fn from_ordered_bytes<T: AsOrderedBytes>(mut buf: &[u8]) -> T {
+    let it = T::read_ordered_bytes(&mut buf);
+    assert!(buf.is_empty(), "Trailing bytes after an ordered encoding");
+
+    it
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> &'a [u8] {
+    assert!(buf.len() >= len, "Unexpected end of an ordered encoding");
+
+    let (res, rest) = buf.split_at(len);
+    *buf = rest;
+
+    res
+}
+
+macro_rules! impl_for_unsigned {
+    ($($ty:ty),*) => {
+        $(
+            impl AsOrderedBytes for $ty {
+                #[inline]
+                fn write_ordered_bytes(&self, buf: &mut Vec<u8>) {
+                    buf.extend_from_slice(&self.to_be_bytes());
+                }
+
+                #[inline]
+                fn read_ordered_bytes(buf: &mut &[u8]) -> Self {
+                    let bytes = take(buf, std::mem::size_of::<$ty>());
+
+                    <$ty>::from_be_bytes(bytes.try_into().unwrap())
+                }
+            }
+        )*
+    };
+}
+
+macro_rules! impl_for_signed {
+    ($($ty:ty => $uty:ty),*) => {
+        $(
+            impl AsOrderedBytes for $ty {
+                #[inline]
+                fn write_ordered_bytes(&self, buf: &mut Vec<u8>) {
+                    ((*self as $uty) ^ (1 << (<$uty>::BITS - 1))).write_ordered_bytes(buf);
+                }
+
+                #[inline]
+                fn read_ordered_bytes(buf: &mut &[u8]) -> Self {
+                    (<$uty>::read_ordered_bytes(buf) ^ (1 << (<$uty>::BITS - 1))) as $ty
+                }
+            }
+        )*
+    };
+}
+
+impl_for_unsigned!(u8, u16, u32, u64, u128);
+impl_for_signed!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128);
+
+impl AsOrderedBytes for bool {
+    #[inline]
+    fn write_ordered_bytes(&self, buf: &mut Vec<u8>) {
+        buf.push(u8::from(*self));
+    }
+
+    #[inline]
+    fn read_ordered_bytes(buf: &mut &[u8]) -> Self {
+        match take(buf, 1)[0] {
+            0 => false,
+            1 => true,
+            _ => panic!("Invalid bool in an ordered encoding"),
+        }
+    }
+}
+
+fn write_escaped(bytes: &[u8], buf: &mut Vec<u8>) {
+    for b in bytes {
+        buf.push(*b);
+
+        if *b == 0 {
+            buf.push(0xFF);
+        }
+    }
+
+    buf.extend_from_slice(&[0, 0]);
+}
+
+fn read_escaped(buf: &mut &[u8]) -> Vec<u8> {
+    let mut res = Vec::new();
+
+    loop {
+        let b = take(buf, 1)[0];
+
+        if b != 0 {
+            res.push(b);
+            continue;
+        }
+
+        match take(buf, 1)[0] {
+            0 => return res,
+            0xFF => res.push(0),
+            _ => panic!("Invalid escape sequence in an ordered encoding"),
+        }
+    }
+}
+
+impl AsOrderedBytes for Vec<u8> {
+    #[inline]
+    fn write_ordered_bytes(&self, buf: &mut Vec<u8>) {
+        write_escaped(self, buf);
+    }
+
+    #[inline]
+    fn read_ordered_bytes(buf: &mut &[u8]) -> Self {
+        read_escaped(buf)
+    }
+}
+
+impl AsOrderedBytes for String {
+    #[inline]
+    fn write_ordered_bytes(&self, buf: &mut Vec<u8>) {
+        write_escaped(self.as_bytes(), buf);
+    }
+
+    #[inline]
+    fn read_ordered_bytes(buf: &mut &[u8]) -> Self {
+        String::from_utf8(read_escaped(buf)).expect("Invalid UTF-8 in an ordered encoding")
+    }
+}
+
+macro_rules! impl_for_tuple {
+    ($($name:ident),+) => {
+        impl<$($name: AsOrderedBytes),+> AsOrderedBytes for ($($name,)+) {
+            #[inline]
+            #[allow(non_snake_case)]
+            fn write_ordered_bytes(&self, buf: &mut Vec<u8>) {
+                let ($($name,)+) = self;
+                $($name.write_ordered_bytes(buf);)+
+            }
+
+            #[inline]
+            fn read_ordered_bytes(buf: &mut &[u8]) -> Self {
+                ($($name::read_ordered_bytes(buf),)+)
+            }
+        }
+    };
+}
+
+impl_for_tuple!(A, B);
+impl_for_tuple!(A, B, C);
+impl_for_tuple!(A, B, C, D);
+
+#[cfg(test)]
+mod tests {
+    use crate::encoding::ordered::{from_ordered_bytes, to_ordered_bytes, AsOrderedBytes};
+    use std::fmt::Debug;
+
+    fn check_order<T: AsOrderedBytes + Ord + Debug + Clone>(mut values: Vec<T>) {
+        for it in &values {
+            assert_eq!(&from_ordered_bytes::<T>(&to_ordered_bytes(it)), it);
+        }
+
+        let mut encoded = values.iter().map(to_ordered_bytes).collect::<Vec<_>>();
+
+        values.sort();
+        encoded.sort();
+
+        let decoded = encoded
+            .iter()
+            .map(|it| from_ordered_bytes::<T>(it))
+            .collect::<Vec<_>>();
+
+        assert_eq!(decoded, values);
+    }
+
+    #[test]
+    fn works_fine() {
+        check_order(vec![0u64, 255, 256, u64::MAX, 1, 65536]);
+        check_order(vec![0i32, -1, 1, i32::MIN, i32::MAX, -256, 255]);
+        check_order(vec![0i128, -1, i128::MIN, i128::MAX]);
+        check_order(vec![true, false]);
+        check_order(vec![
+            String::new(),
+            String::from("a"),
+            String::from("a\0"),
+            String::from("a\0b"),
+            String::from("ab"),
+            String::from("b"),
+            String::from("\u{ff}"),
+        ]);
+        check_order(vec![vec![0u8], vec![], vec![0, 0], vec![0, 255], vec![1]]);
+        check_order(vec![
+            (String::from("b"), 20u64),
+            (String::from("a"), 300),
+            (String::from("ab"), 1),
+            (String::from("a"), 5),
+            (String::from(""), 10),
+        ]);
+        check_order(vec![
+            (1u8, -5i64, false, vec![1u8]),
+            (1, -5, true, vec![]),
+            (0, 100, true, vec![0]),
+        ]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn trailing_bytes_panic() {
+        from_ordered_bytes::<u8>(&[1, 2]);
+    }
+}
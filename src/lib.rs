@@ -14,13 +14,23 @@
 //! 3. Each data structure is aware of the limited nature of memory in IC and allows programmatic
 //! reaction for situations when your canister is out of stable memory.
 //! 3. Each data structure's performance is reasonably close to its std's analog.
-//! 4. Supported stable data structures: box, vec, log, hash-map, hash-set, btree-map, btree-set, certified-map.
+//! 4. Supported stable data structures: box, rc, vec, log, hash-map, hash-set, btree-map, btree-set, counted-btree-map, certified-map, radix-tree, interval-map, time-series, roaring-bitmap, ttl-map, priority-queue, graph, indexed-btree-map, versioned-map.
 //! 5. In addition to these data structures, this crate provides you with a fully featured toolset
 //! to build your own data structure, if you need something more domain-specific.
-use crate::mem::allocator::StableMemoryAllocator;
+use crate::mem::allocator::{
+    AllocError, AllocatorBuilder, AllocatorStats, DefragBudget, DefragProgress, FitPolicy,
+    FragmentationReport, HeapWalker, ReinitError, SchemaMismatch, StableMemoryAllocator,
+};
+use crate::mem::allocator::{ArenaId, DEFAULT_ARENA};
+use crate::mem::StablePtr;
 use mem::s_slice::SSlice;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::ops::Range;
 
+#[cfg(feature = "benches")]
+pub mod benches;
+#[cfg(all(test, not(feature = "benches")))]
 mod benches;
 /// All collections provided by this crate
 pub mod collections;
@@ -37,9 +47,14 @@ pub use ic_stable_memory_derive as derive;
 
 use crate::utils::isoprint;
 pub use crate::utils::mem_context::{stable, OutOfMemory, PAGE_SIZE_BYTES};
-pub use encoding::{AsDynSizeBytes, AsFixedSizeBytes, Buffer};
+pub use encoding::{AsDynSizeBytes, AsFixedSizeBytes, Buffer, CorruptData};
 pub use primitive::s_box::SBox;
-pub use primitive::StableType;
+pub use primitive::s_candid::SCandid;
+pub use primitive::s_ptr::SPtr;
+pub use primitive::s_rc::SRc;
+pub use primitive::s_ref_cell::SRefCell;
+pub use primitive::s_str_key::SStrKey;
+pub use primitive::{DeepCopy, StableType};
 pub use utils::certification::{
     empty, empty_hash, fork, fork_hash, labeled, labeled_hash, leaf, leaf_hash, AsHashTree,
     AsHashableBytes,
@@ -47,6 +62,10 @@ pub use utils::certification::{
 
 thread_local! {
     static STABLE_MEMORY_ALLOCATOR: RefCell<Option<StableMemoryAllocator>> = RefCell::new(None);
+    static RELOCATION_CALLBACK: RefCell<Option<fn(StablePtr, StablePtr)>> = RefCell::new(None);
+    static ARENAS: RefCell<Vec<StableMemoryAllocator>> = RefCell::new(Vec::new());
+    static CURRENT_ARENA: Cell<ArenaId> = Cell::new(DEFAULT_ARENA);
+    static AUTO_INIT: Cell<Option<u64>> = Cell::new(None);
 }
 
 /// Initializes the [memory allocator](mem::allocator::StableMemoryAllocator).
@@ -110,7 +129,8 @@ pub fn stable_memory_init() {
 ///
 /// It works by first writing the allocator to an `SBox` and then writing a pointer to that `SBox` into
 /// frist 8 bytes of stable memory (offsets [0..8)). `thread_local!` static variable that stores the
-/// allocator also gets cleared, if this function is executed successfully.
+/// allocator also gets cleared and writes, buffered by the current memory context, get flushed (see
+/// [stable::flush]), if this function is executed successfully.
 ///
 /// If it was impossible to allocate a memory block of required size, this function returns an [OutOfMemory]
 /// error. For tips on possible ways of resolving an [OutOfMemory] error visit [this page](https://github.com/seniorjoinu/ic-stable-memory/docs/out-of-memory-error-handling.md).
@@ -165,12 +185,154 @@ pub fn stable_memory_pre_upgrade() -> Result<(), OutOfMemory> {
 /// 1. there is no valid pointer stored at first 8 bytes of stable memory,
 /// 2. there is no valid `SBox` was found at that location,
 /// 3. deserialization step during `SBox`'s "unboxing" failed due to invalid data stored inside this `SBox`,
-/// 4. if there was an already initialized stable memory allocator.
+/// 4. if there was an already initialized stable memory allocator,
+/// 5. if stable memory was written with a layout, that can't be migrated, or is corrupted (see [try_reinit_allocator]).
 #[inline]
 pub fn stable_memory_post_upgrade() {
     reinit_allocator();
 }
 
+/// Persists all stable memory state before a canister upgrade.
+///
+/// See also [restore].
+///
+/// Intended to be the only stable memory related call of the `#[pre_upgrade]` canister method.
+/// Everything, that is reachable from the allocator - named roots (see [declare_root] and
+/// [stable_var](crate::stable_var)), custom data (see [store_custom_data]), arenas and free blocks -
+/// gets serialized into stable memory, with a pointer to it written into first 8 bytes of stable
+/// memory, and re-attached by [restore] after the upgrade.
+///
+/// Works the same way as [stable_memory_pre_upgrade], but traps instead of returning an error,
+/// since there is nothing else a `#[pre_upgrade]` method can do in that case.
+///
+/// With the `wal` feature, an unfinished [transaction](mem::wal::Txn) is rolled back first.
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::collections::SVec;
+/// # use ic_stable_memory::{persist, restore, stable_var};
+/// stable_var!(USERS: SVec<u64>);
+///
+/// #[ic_cdk_macros::init]
+/// fn init() {
+///     restore();
+/// }
+///
+/// #[ic_cdk_macros::pre_upgrade]
+/// fn pre_upgrade() {
+///     persist();
+/// }
+///
+/// #[ic_cdk_macros::post_upgrade]
+/// fn post_upgrade() {
+///     restore();
+/// }
+/// ```
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator or if there is not enough stable
+/// memory to store it.
+#[inline]
+pub fn persist() {
+    stable_memory_pre_upgrade().expect("Out of stable memory");
+}
+
+/// Initializes the memory allocator, or re-attaches it after a canister upgrade.
+///
+/// See also [persist].
+///
+/// If stable memory is empty, works the same way as [stable_memory_init], otherwise - the same way
+/// as [stable_memory_post_upgrade], restoring everything that was saved with [persist]. So the
+/// same call can be used in both `#[init]` and `#[post_upgrade]` canister methods.
+///
+/// Canisters, which are migrating from standard data structures and already have some data in
+/// stable memory, should use [stable_memory_init] once, as described in its documentation.
+///
+/// # Panics
+/// Panics if the allocator is already initialized or if stable memory was written with an
+/// incompatible layout or is corrupted (see [try_restore]).
+#[inline]
+pub fn restore() {
+    try_restore().expect("Incompatible or corrupted stable memory layout");
+}
+
+/// Same as [restore], but returns a [ReinitError], if stable memory was written by a version of
+/// this crate with a different layout, which can't be migrated in place, or if the allocator's
+/// metadata is corrupted (see [try_reinit_allocator])
+///
+/// # Panics
+/// Panics if the allocator is already initialized.
+#[inline]
+pub fn try_restore() -> Result<(), ReinitError> {
+    if stable::size_pages() == 0 {
+        stable_memory_init();
+
+        Ok(())
+    } else {
+        try_reinit_allocator()
+    }
+}
+
+/// Enables lazy initialization of the memory allocator.
+///
+/// Once enabled, the first operation that needs the allocator (e.g. the first insertion into a
+/// collection) initializes it automatically, instead of panicking. If stable memory is empty, a new
+/// allocator is created, the same way as [init_allocator(max_pages)](init_allocator) does, otherwise
+/// the allocator is retrieved from stable memory, the same way as [reinit_allocator] does. This
+/// also covers the case, when the allocator is used after [deinit_allocator] was called.
+///
+/// Since the allocator is always located at the beginning of stable memory, `max_pages` is the only
+/// setting. It has the same meaning as the argument of [init_allocator].
+///
+/// This mode is opt-in: canisters, which are migrating from standard data structures and already
+/// have some other data in stable memory, should keep calling [stable_memory_init] explicitly.
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::collections::SVec;
+/// # use ic_stable_memory::enable_auto_init;
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// enable_auto_init(0);
+///
+/// let mut vec = SVec::new();
+/// vec.push(10u64).expect("Out of memory");
+/// ```
+#[inline]
+pub fn enable_auto_init(max_pages: u64) {
+    AUTO_INIT.with(|it| it.set(Some(max_pages)));
+}
+
+/// Disables lazy initialization of the memory allocator, see [enable_auto_init].
+///
+/// Does not affect the allocator, if it is already initialized.
+#[inline]
+pub fn disable_auto_init() {
+    AUTO_INIT.with(|it| it.set(None));
+}
+
+fn auto_init(it: &RefCell<Option<StableMemoryAllocator>>) {
+    if it.borrow().is_some() {
+        return;
+    }
+
+    let max_pages = match AUTO_INIT.with(|it| it.get()) {
+        Some(max_pages) => max_pages,
+        None => return,
+    };
+
+    let allocator = if stable::size_pages() == 0 {
+        StableMemoryAllocator::init(max_pages)
+    } else {
+        let allocator = StableMemoryAllocator::retrieve();
+        let arenas = allocator.retrieve_arenas();
+        ARENAS.with(|it| *it.borrow_mut() = arenas);
+
+        allocator
+    };
+
+    *it.borrow_mut() = Some(allocator);
+}
+
 /// An alias for [stable_memory_init], but allows limiting the maximum number of stable memory pages
 /// that the allocator can grow. [init_allocator(0)] works exactly the same as [stable_memory_init()].
 ///
@@ -188,9 +350,23 @@ pub fn stable_memory_post_upgrade() {
 /// Internally calls [StableMemoryAllocator::init](mem::allocator::StableMemoryAllocator::init).
 #[inline]
 pub fn init_allocator(max_pages: u64) {
+    init_allocator_with(AllocatorBuilder::new().max_pages(max_pages));
+}
+
+/// Same as [init_allocator], but allows configuring every setting of the allocator, see [AllocatorBuilder].
+///
+/// An allocator, initialized at a non-zero [base offset](AllocatorBuilder::base_offset), should be
+/// retrieved after an upgrade with [reinit_allocator_at].
+///
+/// Internally calls [StableMemoryAllocator::init_with](mem::allocator::StableMemoryAllocator::init_with).
+///
+/// # Panics
+/// Panics if the allocator is already initialized.
+#[inline]
+pub fn init_allocator_with(builder: AllocatorBuilder) {
     STABLE_MEMORY_ALLOCATOR.with(|it| {
         if it.borrow().is_none() {
-            let allocator = StableMemoryAllocator::init(max_pages);
+            let allocator = StableMemoryAllocator::init_with(builder);
 
             *it.borrow_mut() = Some(allocator);
         } else {
@@ -207,11 +383,40 @@ pub fn init_allocator(max_pages: u64) {
 /// Panics if there is no initialized stable memory allocator.
 #[inline]
 pub fn deinit_allocator() -> Result<(), OutOfMemory> {
+    // the new code can't resume a transaction, so it is rolled back before stable memory is unlocked
+    #[cfg(feature = "wal")]
+    mem::wal::recover();
+
     STABLE_MEMORY_ALLOCATOR.with(|it: &RefCell<Option<StableMemoryAllocator>>| {
         if let Some(mut alloc) = it.take() {
-            let res = alloc.store();
+            let mut arenas = ARENAS.with(|it| it.take());
+
+            // arenas are stored first, each inside itself
+            let mut res = Ok(());
+            let mut stored = 0;
+            for arena in arenas.iter_mut() {
+                res = arena.store();
+                if res.is_err() {
+                    break;
+                }
+
+                stored += 1;
+            }
+
+            if res.is_ok() {
+                res = alloc.store();
+            }
+
             if res.is_err() {
+                for (idx, arena) in arenas.iter_mut().enumerate().take(stored) {
+                    *arena = alloc.retrieve_arena(idx);
+                }
+
+                ARENAS.with(|it| *it.borrow_mut() = arenas);
                 *it.borrow_mut() = Some(alloc);
+            } else {
+                CURRENT_ARENA.with(|it| it.set(DEFAULT_ARENA));
+                stable::flush();
             }
 
             res
@@ -226,18 +431,64 @@ pub fn deinit_allocator() -> Result<(), OutOfMemory> {
 /// Internally calls [StableMemoryAllocator::retrieve](mem::allocator::StableMemoryAllocator::retrieve).
 ///
 /// # Panics
-/// Panics if there is no initialized stable memory allocator.
+/// Panics if there is no initialized stable memory allocator or if stable memory was written with
+/// an incompatible layout or is corrupted (see [try_reinit_allocator]).
 #[inline]
 pub fn reinit_allocator() {
+    try_reinit_allocator().expect("Incompatible or corrupted stable memory layout");
+}
+
+/// Same as [reinit_allocator], but returns a [ReinitError], if stable memory was written by a
+/// version of this crate with a different layout, which can't be migrated in place, or if the
+/// allocator's metadata is corrupted
+///
+/// Older layouts are migrated to [LAYOUT_VERSION](mem::allocator::LAYOUT_VERSION) automatically.
+/// The free-list is cross-checked with the free space summary, persisted together with it by
+/// [persist] - a mismatch is reported as [ReinitError::Corrupted], instead of handing out memory
+/// blocks, which are still in use. If an error is returned, stable memory is left untouched and the
+/// allocator stays uninitialized, so the canister can, for example, trap and let the previous
+/// version of the code handle the data.
+///
+/// Internally calls [StableMemoryAllocator::try_retrieve](mem::allocator::StableMemoryAllocator::try_retrieve).
+///
+/// # Panics
+/// Panics if the allocator is already initialized.
+#[inline]
+pub fn try_reinit_allocator() -> Result<(), ReinitError> {
+    try_reinit_allocator_at(0)
+}
+
+/// Same as [reinit_allocator], but for an allocator, initialized at a non-zero
+/// [base offset](AllocatorBuilder::base_offset) with [init_allocator_with]
+///
+/// # Panics
+/// Panics if the allocator is already initialized or if stable memory was written with an
+/// incompatible layout or is corrupted (see [try_reinit_allocator]).
+#[inline]
+pub fn reinit_allocator_at(base_offset: u64) {
+    try_reinit_allocator_at(base_offset).expect("Incompatible or corrupted stable memory layout");
+}
+
+/// Same as [try_reinit_allocator], but for an allocator, initialized at a non-zero
+/// [base offset](AllocatorBuilder::base_offset) with [init_allocator_with]
+///
+/// # Panics
+/// Panics if the allocator is already initialized.
+#[inline]
+pub fn try_reinit_allocator_at(base_offset: u64) -> Result<(), ReinitError> {
     STABLE_MEMORY_ALLOCATOR.with(|it| {
         if it.borrow().is_none() {
-            let allocator = StableMemoryAllocator::retrieve();
+            let allocator = StableMemoryAllocator::try_retrieve_at_offset(base_offset)?;
+            let arenas = allocator.retrieve_arenas();
 
             *it.borrow_mut() = Some(allocator);
+            ARENAS.with(|it| *it.borrow_mut() = arenas);
+
+            Ok(())
         } else {
             unreachable!("StableMemoryAllocator can only be initialized once");
         }
-    });
+    })
 }
 
 /// Persists a pointer to an [SBox] between canister upgrades mapped to some unique [usize] key.
@@ -251,7 +502,8 @@ pub fn reinit_allocator() {
 /// stable memory aswell.
 ///
 /// This function allows one to do that, by assigning a unique [usize] index to the stored data, which was
-/// previously stored in [SBox].
+/// previously stored in [SBox]. A fingerprint of `T` (see [type_fingerprint](mem::allocator::type_fingerprint))
+/// is stored alongside, so the data can't be silently retrieved as a value of another type.
 ///
 /// This function should be used in the `#[pre_upgrade]` canister method. Right before
 /// [stable_memory_pre_upgrade()] invocation. This function can be used multiple times, but one should
@@ -324,6 +576,8 @@ pub fn reinit_allocator() {
 #[inline]
 pub fn store_custom_data<T: StableType + AsDynSizeBytes>(idx: usize, data: SBox<T>) {
     STABLE_MEMORY_ALLOCATOR.with(|it| {
+        auto_init(it);
+
         if let Some(alloc) = &mut *it.borrow_mut() {
             alloc.store_custom_data(idx, data)
         } else {
@@ -349,10 +603,13 @@ pub fn store_custom_data<T: StableType + AsDynSizeBytes>(idx: usize, data: SBox<
 /// See examples of [store_custom_data].
 ///
 /// # Panics
-/// Panics if there is no initialized stable memory allocator.
+/// Panics if there is no initialized stable memory allocator. Panics if the data was stored as a
+/// value of another type, see [try_retrieve_custom_data].
 #[inline]
 pub fn retrieve_custom_data<T: StableType + AsDynSizeBytes>(idx: usize) -> Option<SBox<T>> {
     STABLE_MEMORY_ALLOCATOR.with(|it| {
+        auto_init(it);
+
         if let Some(alloc) = &mut *it.borrow_mut() {
             alloc.retrieve_custom_data(idx)
         } else {
@@ -361,256 +618,1211 @@ pub fn retrieve_custom_data<T: StableType + AsDynSizeBytes>(idx: usize) -> Optio
     })
 }
 
-/// Attempts to allocate a new [SSlice] of at least the required size or returns an [OutOfMemory] error
-/// if there is no continuous stable memory memory block of that size can be allocated.
+/// Same as [retrieve_custom_data], but returns a [SchemaMismatch] error, if the data was stored as a
+/// value of another type, instead of panicking.
 ///
-/// Memory block that is returned *can be bigger* than requested. This happens because:
-/// 1. Sizes for allocation are always getting rounded up to the next multiple of 8 bytes. For example,
-/// if requested 100 bytes to allocate requested, the resulting memory block can't be smaller than 104 bytes.
-/// 2. Minimum memory block size is 16 bytes. To find out more see documentation for [the allocator](mem::allocator::StableMemoryAllocator).
-/// 3. If the allocator has a free block of size less than `requested size + minimum block size`,
-/// this block won't be split and will be returned as is.
-///
-/// If the allocator only has a memory block which is bigger than `requested size + minimum block size`,
-/// that block gets split it two. The first half is returned as the result of this function, and the other
-/// half goes back to the free list.
-///
-/// If the allocator has no apropriate free memory block to allocate, it will try to grow stable memory
-/// by the number of pages enough to allocate a block of that size. If it can't grow due to lack of
-/// stable memory in a subnet or due to reaching `max_pages` limit set earlier - it will return an
-/// [OutOfMemory] error.
+/// The data is left stored, if an error is returned. Data stored by versions of this crate, which
+/// didn't record type fingerprints, is retrieved unchecked.
 ///
-/// Internally calls [StableMemoryAllocator::allocate](mem::allocator::StableMemoryAllocator::allocate).
+/// Internally calls [StableMemoryAllocator::try_retrieve_custom_data](mem::allocator::StableMemoryAllocator::try_retrieve_custom_data).
 ///
 /// # Example
 /// ```rust
-/// // slice size is in [104..136) bytes range, despite requesting for only 100 bytes
-/// # use ic_stable_memory::{allocate, stable_memory_init};
+/// # use ic_stable_memory::{try_retrieve_custom_data, SBox, stable_memory_init, store_custom_data};
+/// # use ic_stable_memory::collections::SVec;
 /// # unsafe { ic_stable_memory::mem::clear(); }
 /// # stable_memory_init();
-/// # unsafe {
-/// let slice = allocate(100).expect("Not enough stable memory");
-/// # }
-/// ```
-///
-/// # Panics
-/// Panics if there is no initialized stable memory allocator.
-///
-/// # Safety
-/// Don't forget to [deallocate] the memory block, when you're done!
-#[inline]
-pub unsafe fn allocate(size: u64) -> Result<SSlice, OutOfMemory> {
-    STABLE_MEMORY_ALLOCATOR.with(|it| {
-        if let Some(alloc) = &mut *it.borrow_mut() {
-            alloc.allocate(size)
-        } else {
-            unreachable!("StableMemoryAllocator is not initialized");
-        }
-    })
-}
-
-/// Deallocates an already allocated [SSlice] freeing it's memory.
-///
-/// Supplied [SSlice] get's transformed into [FreeBlock](mem::free_block::FreeBlock) and then an
-/// attempt to merge it with neighboring (physically) free blocks is performed.
-///
-/// Internally calls [StableMemoryAllocator::deallocate](mem::allocator::StableMemoryAllocator::deallocate).
+/// store_custom_data(1, SBox::new(SVec::<u64>::new()).expect("Out of memory"));
 ///
-/// # Example
-/// ```rust
-/// # use ic_stable_memory::{allocate, deallocate, stable_memory_init};
-/// # unsafe { ic_stable_memory::mem::clear(); }
-/// # stable_memory_init();
-/// # unsafe {
-/// let slice = allocate(100).expect("Out of memory");
-/// deallocate(slice);
-/// # }
+/// assert!(try_retrieve_custom_data::<SVec<u32>>(1).is_err());
+/// assert!(try_retrieve_custom_data::<SVec<u64>>(1).unwrap().is_some());
 /// ```
 ///
 /// # Panics
 /// Panics if there is no initialized stable memory allocator.
 #[inline]
-pub fn deallocate(slice: SSlice) {
+pub fn try_retrieve_custom_data<T: StableType + AsDynSizeBytes>(
+    idx: usize,
+) -> Result<Option<SBox<T>>, SchemaMismatch> {
     STABLE_MEMORY_ALLOCATOR.with(|it| {
+        auto_init(it);
+
         if let Some(alloc) = &mut *it.borrow_mut() {
-            alloc.deallocate(slice)
+            alloc.try_retrieve_custom_data(idx)
         } else {
             unreachable!("StableMemoryAllocator is not initialized");
         }
     })
 }
 
-/// Attempts to reallocate a memory block growing its size and possibly moving its content to a new
-/// location.
+/// Assigns a unique name to a pointer, so it can be found again after canister upgrades.
 ///
-/// At first it tries to perform an `inplace reallocation` - check if the next neighboring (physically)
-/// memory block is also free. If that is so, this neighboring (or only a chunk of it, if it's too big)
-/// free block gets merged with the one passed as an argument to this function and returned as a result.
-/// This process does not move the data.
+/// See also [get_root] and [remove_root].
 ///
-/// If there is no neighboring free block, than a sequence of operations is performed:
-/// 1. Copy the data to a heap-allocated byte buffer.
-/// 2. Deallocate the [SSlice] passed as an argument to this function.
-/// 3. Allocate a new [SSlice] of the requested size, possibly returning an [OutOfMemory] error.
-/// 4. Copy data from the byte buffer to this new [SSlice].
-/// 5. Return it as a result.
-/// This process moves the data.
+/// Unlike [store_custom_data], which is meant to be used right before an upgrade, named roots can be
+/// declared at any time and stay declared until they are removed explicitly. So a collection can be
+/// declared once, when it is created, and then re-discovered by its name after each upgrade. Declared
+/// roots are also considered reachable by leak reports and are updated, when their memory blocks
+/// are moved by [compact].
 ///
-/// If the requested new size is less than the actual size of the [SSlice] passed as an argument,
-/// the function does nothing and returns this [SSlice] as a result back.
+/// Returns the pointer, that was previously assigned to this name, if any. The pointer is not
+/// validated in any way - it is up to the caller to keep it pointing to a live memory block.
 ///
-/// Internally calls [StableMemoryAllocator::reallocate](mem::allocator::StableMemoryAllocator::reallocate).
+/// Internally calls [StableMemoryAllocator::declare_root](mem::allocator::StableMemoryAllocator::declare_root).
 ///
 /// # Example
 /// ```rust
-/// # use ic_stable_memory::{allocate, stable_memory_init, reallocate};
+/// # use ic_stable_memory::{allocate, declare_root, get_root, stable_memory_init, SSlice};
 /// # unsafe { ic_stable_memory::mem::clear(); }
 /// # stable_memory_init();
-/// # unsafe {
-/// let slice = allocate(100).expect("Out of memory");
-/// let bigger_slice = reallocate(slice, 200).expect("Out of memory");
-/// # }
+/// let slice = unsafe { allocate(100).expect("Out of memory") };
+/// declare_root("my_data", slice.as_ptr());
+///
+/// // after an upgrade
+/// let ptr = get_root("my_data").unwrap();
+/// let slice = unsafe { SSlice::from_ptr(ptr).unwrap() };
 /// ```
 ///
 /// # Panics
 /// Panics if there is no initialized stable memory allocator.
-/// Reallocating [SSlice]s bigger than [u32::MAX] bytes will also panic.
-///
-/// # Safety
-/// Don't forget to [deallocate] the memory block, when you're done!
 #[inline]
-pub unsafe fn reallocate(slice: SSlice, new_size: u64) -> Result<SSlice, OutOfMemory> {
+pub fn declare_root(id: &str, ptr: StablePtr) -> Option<StablePtr> {
     STABLE_MEMORY_ALLOCATOR.with(|it| {
+        auto_init(it);
+
         if let Some(alloc) = &mut *it.borrow_mut() {
-            alloc.reallocate(slice, new_size)
+            alloc.declare_root(id, ptr)
         } else {
             unreachable!("StableMemoryAllocator is not initialized");
         }
     })
 }
 
-/// Checks if it would be possible to allocate a block of stable memory of the provided size right now.
-///
-/// The allocator will check its free list for a block of appropriate size. If there is no such free
-/// block, it will try to grow stable memory by the number of pages enough to fit this size.
-///
-/// Returns `true` if a block was found. Returns `false` if an attempt to grow stable memory resulted in
-/// an [OutOfMemory] error.
-///
-/// Internally calls [StableMemoryAllocator::make_sure_can_allocate](mem::allocator::StableMemoryAllocator::make_sure_can_allocate).
+/// Returns the pointer, assigned to this name by [declare_root]
 ///
-/// # Example
-/// ```rust
-/// # use ic_stable_memory::{make_sure_can_allocate, stable_memory_init};
-/// # unsafe { ic_stable_memory::mem::clear(); }
-/// # stable_memory_init();
-/// if make_sure_can_allocate(1_000_000) {
-///     println!("It is possible to allocate a million bytes of stable memory");
-/// }
-/// ```
+/// Internally calls [StableMemoryAllocator::get_root](mem::allocator::StableMemoryAllocator::get_root).
 ///
 /// # Panics
 /// Panics if there is no initialized stable memory allocator.
 #[inline]
-pub fn make_sure_can_allocate(size: u64) -> bool {
+pub fn get_root(id: &str) -> Option<StablePtr> {
     STABLE_MEMORY_ALLOCATOR.with(|it| {
-        if let Some(alloc) = &mut *it.borrow_mut() {
-            alloc.make_sure_can_allocate(size)
+        auto_init(it);
+
+        if let Some(alloc) = &*it.borrow() {
+            alloc.get_root(id)
         } else {
             unreachable!("StableMemoryAllocator is not initialized");
         }
     })
 }
 
-/// Returns the amount of stable memory in bytes which is under the allocator's management.
+/// Forgets the name, assigned by [declare_root], and returns the pointer, assigned to it
 ///
-/// Always equals to [stable64_size()](ic_cdk::api::stable::stable64_size) - `8`.
+/// The memory block itself is not deallocated.
 ///
-/// Internally calls [StableMemoryAllocator::get_available_size](mem::allocator::StableMemoryAllocator::get_available_size).
+/// Internally calls [StableMemoryAllocator::remove_root](mem::allocator::StableMemoryAllocator::remove_root).
 ///
 /// # Panics
 /// Panics if there is no initialized stable memory allocator.
 #[inline]
-pub fn get_available_size() -> u64 {
+pub fn remove_root(id: &str) -> Option<StablePtr> {
     STABLE_MEMORY_ALLOCATOR.with(|it| {
-        if let Some(alloc) = &*it.borrow() {
-            alloc.get_available_size()
+        auto_init(it);
+
+        if let Some(alloc) = &mut *it.borrow_mut() {
+            alloc.remove_root(id)
         } else {
             unreachable!("StableMemoryAllocator is not initialized");
         }
     })
 }
 
-/// Returns the amount of free stable memory in bytes.
+/// Returns all names, assigned by [declare_root], and their pointers
 ///
-/// Internally calls [StableMemoryAllocator::get_free_size](mem::allocator::StableMemoryAllocator::get_free_size).
+/// Internally calls [StableMemoryAllocator::roots](mem::allocator::StableMemoryAllocator::roots).
 ///
 /// # Panics
 /// Panics if there is no initialized stable memory allocator.
 #[inline]
-pub fn get_free_size() -> u64 {
+pub fn get_roots() -> BTreeMap<String, StablePtr> {
     STABLE_MEMORY_ALLOCATOR.with(|it| {
+        auto_init(it);
+
         if let Some(alloc) = &*it.borrow() {
-            alloc.get_free_size()
+            alloc.roots().clone()
         } else {
             unreachable!("StableMemoryAllocator is not initialized");
         }
     })
 }
 
-/// Returns the amount of allocated stable memory in bytes.
+/// Encodes the metadata of the [memory allocator](mem::allocator::StableMemoryAllocator)
 ///
-/// Always equal to [get_available_size()] - [get_free_size()].
+/// See also [import_meta].
 ///
-/// Internally calls [StableMemoryAllocator::get_allocated_size](mem::allocator::StableMemoryAllocator::get_allocated_size).
+/// The metadata includes the free-list, size counters, custom data pointers and named roots, but
+/// not the contents of stable memory itself. Tooling can use it to checkpoint the allocator or to
+/// verify its state offline. Arenas (see [create_arena]) are not included.
+///
+/// Internally calls [StableMemoryAllocator::export_meta](mem::allocator::StableMemoryAllocator::export_meta).
 ///
 /// # Panics
 /// Panics if there is no initialized stable memory allocator.
 #[inline]
-pub fn get_allocated_size() -> u64 {
+pub fn export_meta() -> Vec<u8> {
     STABLE_MEMORY_ALLOCATOR.with(|it| {
+        auto_init(it);
+
         if let Some(alloc) = &*it.borrow() {
-            alloc.get_allocated_size()
+            alloc.export_meta()
         } else {
             unreachable!("StableMemoryAllocator is not initialized");
         }
     })
 }
 
-/// Returns `max_pages` parameter.
+/// Replaces the metadata of the [memory allocator](mem::allocator::StableMemoryAllocator) with the
+/// one, previously encoded by [export_meta]
 ///
-/// See [init_allocator] for more details.
+/// Returns a [ReinitError], if the metadata can't be migrated to the current layout or was encoded by
+/// a build with another [layout mode](mem::allocator::layout_mode).
+/// In that case the allocator is left unchanged.
+///
+/// Internally calls [StableMemoryAllocator::import_meta](mem::allocator::StableMemoryAllocator::import_meta).
 ///
 /// # Panics
 /// Panics if there is no initialized stable memory allocator.
+///
+/// # Safety
+/// Stable memory is not touched by this function, so the metadata should describe the current state
+/// of stable memory (for example, if stable memory was restored from the same checkpoint). Otherwise,
+/// the allocator will hand out memory blocks, which are still in use.
 #[inline]
-pub fn get_max_pages() -> u64 {
+pub unsafe fn import_meta(buf: &[u8]) -> Result<(), ReinitError> {
+    let allocator = StableMemoryAllocator::import_meta(buf)?;
+
     STABLE_MEMORY_ALLOCATOR.with(|it| {
-        if let Some(alloc) = &*it.borrow() {
-            alloc.get_max_pages()
-        } else {
-            unreachable!("StableMemoryAllocator is not initialized");
-        }
-    })
-}
+        auto_init(it);
 
-#[inline]
-pub fn _debug_validate_allocator() {
-    STABLE_MEMORY_ALLOCATOR.with(|it: &RefCell<Option<StableMemoryAllocator>>| {
-        if let Some(alloc) = &*it.borrow() {
-            alloc.debug_validate_free_blocks();
+        if let Some(alloc) = &mut *it.borrow_mut() {
+            *alloc = allocator;
+
+            Ok(())
         } else {
             unreachable!("StableMemoryAllocator is not initialized");
         }
     })
 }
 
-#[inline]
-pub fn _debug_print_allocator() {
-    STABLE_MEMORY_ALLOCATOR.with(|it| {
-        if let Some(alloc) = &*it.borrow_mut() {
-            isoprint(format!("{alloc:?}").as_str());
-        } else {
+/// Attempts to allocate a new [SSlice] of at least the required size or returns an [OutOfMemory] error
+/// if there is no continuous stable memory memory block of that size can be allocated.
+///
+/// Memory block that is returned *can be bigger* than requested. This happens because:
+/// 1. Sizes for allocation are always getting rounded up to the next multiple of 8 bytes. For example,
+/// if requested 100 bytes to allocate requested, the resulting memory block can't be smaller than 104 bytes.
+/// 2. Minimum memory block size is 16 bytes. To find out more see documentation for [the allocator](mem::allocator::StableMemoryAllocator).
+/// 3. If the allocator has a free block of size less than `requested size + minimum block size`,
+/// this block won't be split and will be returned as is.
+///
+/// If the allocator only has a memory block which is bigger than `requested size + minimum block size`,
+/// that block gets split it two. The first half is returned as the result of this function, and the other
+/// half goes back to the free list.
+///
+/// If the allocator has no apropriate free memory block to allocate, it will try to grow stable memory
+/// by the number of pages enough to allocate a block of that size. If it can't grow due to lack of
+/// stable memory in a subnet or due to reaching `max_pages` limit set earlier - it will return an
+/// [OutOfMemory] error.
+///
+/// Internally calls [StableMemoryAllocator::allocate](mem::allocator::StableMemoryAllocator::allocate).
+///
+/// # Example
+/// ```rust
+/// // slice size is in [104..136) bytes range, despite requesting for only 100 bytes
+/// # use ic_stable_memory::{allocate, stable_memory_init};
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// # unsafe {
+/// let slice = allocate(100).expect("Not enough stable memory");
+/// # }
+/// ```
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator.
+///
+/// # Safety
+/// Don't forget to [deallocate] the memory block, when you're done!
+#[inline]
+pub unsafe fn allocate(size: u64) -> Result<SSlice, OutOfMemory> {
+    let res = with_current_arena(|alloc| alloc.allocate(size));
+
+    #[cfg(feature = "alloc_trace")]
+    mem::alloc_trace::record(mem::alloc_trace::AllocEvent::Allocate {
+        size,
+        result: res.as_ref().ok().map(|it| it.as_ptr()),
+    });
+
+    #[cfg(not(target_family = "wasm"))]
+    utils::leak_check::on_allocate(res.as_ref().ok().map(|it| it.as_ptr()));
+
+    #[cfg(feature = "orphan_collector")]
+    mem::orphan_collector::on_touch(res.as_ref().ok().map(|it| it.as_ptr()));
+
+    res
+}
+
+/// Deallocates an already allocated [SSlice] freeing it's memory.
+///
+/// Supplied [SSlice] get's transformed into [FreeBlock](mem::free_block::FreeBlock) and then an
+/// attempt to merge it with neighboring (physically) free blocks is performed. If the memory block
+/// is shared (see [retain]), only one of its owners is detached from it instead.
+///
+/// Internally calls [StableMemoryAllocator::deallocate](mem::allocator::StableMemoryAllocator::deallocate).
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::{allocate, deallocate, stable_memory_init};
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// # unsafe {
+/// let slice = allocate(100).expect("Out of memory");
+/// deallocate(slice);
+/// # }
+/// ```
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator.
+#[inline]
+pub fn deallocate(slice: SSlice) {
+    #[cfg(feature = "orphan_collector")]
+    mem::orphan_collector::on_touch(Some(slice.as_ptr()));
+
+    with_owner_arena(slice.as_ptr(), |alloc| {
+        // shared memory blocks are only released by their last owner
+        if alloc.unshare(slice.as_ptr()).is_some() {
+            return;
+        }
+
+        #[cfg(feature = "alloc_trace")]
+        mem::alloc_trace::record(mem::alloc_trace::AllocEvent::Deallocate {
+            ptr: slice.as_ptr(),
+        });
+
+        alloc.deallocate(slice);
+    })
+}
+
+/// Allocates memory for a small fixed-size object (e.g. a node of a tree) and returns a pointer to it.
+///
+/// Objects of the same size are packed into slots of shared memory blocks (slabs), which removes
+/// 16 bytes of metadata per object and makes allocations faster. Objects bigger than 2KB are stored
+/// in regular memory blocks.
+///
+/// Use [SSlice::_offset] to access the object's data. The returned pointer is *not* a pointer to a
+/// memory block, so don't use it with [SSlice::from_ptr] and release it only with [deallocate_slot()].
+///
+/// Internally calls [StableMemoryAllocator::allocate_slot](mem::allocator::StableMemoryAllocator::allocate_slot).
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::{allocate_slot, deallocate_slot, mem, stable_memory_init, SSlice};
+/// # unsafe { mem::clear(); }
+/// # stable_memory_init();
+/// let ptr = unsafe { allocate_slot(100).expect("Out of memory") };
+/// unsafe { mem::write_fixed(SSlice::_offset(ptr, 20), &mut 10u64) };
+///
+/// deallocate_slot(ptr);
+/// ```
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator.
+///
+/// # Safety
+/// Don't forget to [deallocate_slot] the object, when you're done!
+#[inline]
+pub unsafe fn allocate_slot(size: u64) -> Result<StablePtr, OutOfMemory> {
+    let res = with_current_arena(|alloc| alloc.allocate_slot(size));
+
+    #[cfg(feature = "alloc_trace")]
+    mem::alloc_trace::record(mem::alloc_trace::AllocEvent::AllocateSlot {
+        size,
+        result: res.as_ref().ok().copied(),
+    });
+
+    #[cfg(feature = "orphan_collector")]
+    mem::orphan_collector::on_touch(res.as_ref().ok().copied());
+
+    res
+}
+
+/// Releases memory, allocated with [allocate_slot()].
+///
+/// Internally calls [StableMemoryAllocator::deallocate_slot](mem::allocator::StableMemoryAllocator::deallocate_slot).
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator or if the slot is already free.
+#[inline]
+pub fn deallocate_slot(ptr: StablePtr) {
+    #[cfg(feature = "alloc_trace")]
+    mem::alloc_trace::record(mem::alloc_trace::AllocEvent::DeallocateSlot { ptr });
+
+    #[cfg(feature = "orphan_collector")]
+    mem::orphan_collector::on_touch(Some(ptr));
+
+    with_owner_arena(ptr, |alloc| alloc.deallocate_slot(ptr))
+}
+
+/// Checked version of [deallocate], which takes a pointer to a memory block (as returned by [SSlice::as_ptr]).
+///
+/// Returns [AllocError::DoubleFree](mem::allocator::AllocError::DoubleFree), if the memory block
+/// is already free, and [AllocError::InvalidPointer](mem::allocator::AllocError::InvalidPointer), if
+/// the pointer doesn't point to the beginning of a memory block. Stable memory is left untouched in
+/// both cases, so an application bug can be reported, instead of corrupting the allocator.
+///
+/// The check is best-effort (see [StableMemoryAllocator::check_ptr](mem::allocator::StableMemoryAllocator::check_ptr)) -
+/// this function is not a replacement for correct memory management.
+///
+/// If the memory block is shared (see [retain]), only detaches one owner from it.
+///
+/// Internally calls [StableMemoryAllocator::release](mem::allocator::StableMemoryAllocator::release).
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::{allocate, try_deallocate, stable_memory_init};
+/// # use ic_stable_memory::mem::allocator::AllocError;
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// let slice = unsafe { allocate(100).expect("Out of memory") };
+///
+/// assert!(try_deallocate(slice.as_ptr()).is_ok());
+/// assert_eq!(try_deallocate(slice.as_ptr()), Err(AllocError::DoubleFree(slice.as_ptr())));
+/// ```
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator.
+#[inline]
+pub fn try_deallocate(ptr: StablePtr) -> Result<(), AllocError> {
+    #[cfg(feature = "orphan_collector")]
+    mem::orphan_collector::on_touch(Some(ptr));
+
+    let res = with_owner_arena(ptr, |alloc| alloc.release(ptr));
+
+    #[cfg(feature = "alloc_trace")]
+    {
+        if res == Ok(0) {
+            mem::alloc_trace::record(mem::alloc_trace::AllocEvent::Deallocate { ptr });
+        }
+    }
+
+    res.map(|_| ())
+}
+
+/// Attaches one more owner to an allocated memory block, returns the new number of its owners
+///
+/// Each memory block has a single owner by default. A shared memory block (with multiple owners) is
+/// only released, when each of its owners calls [deallocate] (or [release]) on it, so the same
+/// (possibly large) value can be stored in multiple collections without copying it, e.g. with
+/// [SBTreeMap::insert_shared](collections::SBTreeMap::insert_shared). Reference counts are kept by
+/// the allocator and survive canister upgrades.
+///
+/// Shared memory blocks can't be reallocated, and a change of its data is visible to all its owners.
+///
+/// Internally calls [StableMemoryAllocator::retain](mem::allocator::StableMemoryAllocator::retain).
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::{allocate, deallocate, get_ref_count, retain, stable_memory_init};
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// let slice = unsafe { allocate(100).expect("Out of memory") };
+///
+/// assert_eq!(retain(slice.as_ptr()), Ok(2));
+///
+/// deallocate(slice);
+/// assert_eq!(get_ref_count(slice.as_ptr()), 1);
+///
+/// deallocate(slice);
+/// ```
+///
+/// # Errors
+/// Returns the same errors as [try_deallocate], if the pointer doesn't point to an allocated memory block.
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator.
+#[inline]
+pub fn retain(ptr: StablePtr) -> Result<u64, AllocError> {
+    with_owner_arena(ptr, |alloc| alloc.retain(ptr))
+}
+
+/// Detaches an owner from an allocated memory block, releasing the block, if it was the last one
+///
+/// Returns the number of owners left. See [retain].
+///
+/// Internally calls [StableMemoryAllocator::release](mem::allocator::StableMemoryAllocator::release).
+///
+/// # Errors
+/// Returns the same errors as [try_deallocate], if the pointer doesn't point to an allocated memory block.
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator.
+#[inline]
+pub fn release(ptr: StablePtr) -> Result<u64, AllocError> {
+    let res = with_owner_arena(ptr, |alloc| alloc.release(ptr));
+
+    #[cfg(feature = "alloc_trace")]
+    {
+        if res == Ok(0) {
+            mem::alloc_trace::record(mem::alloc_trace::AllocEvent::Deallocate { ptr });
+        }
+    }
+
+    #[cfg(feature = "orphan_collector")]
+    mem::orphan_collector::on_touch(Some(ptr));
+
+    res
+}
+
+/// Returns the number of owners of an allocated memory block, see [retain]
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator.
+#[inline]
+pub fn get_ref_count(ptr: StablePtr) -> u64 {
+    with_owner_arena(ptr, |alloc| alloc.get_ref_count(ptr))
+}
+
+/// Checks that the pointer points to the beginning of an allocated memory block
+///
+/// Returns the same errors as [try_deallocate], but doesn't change anything. The check is best-effort
+/// (see [StableMemoryAllocator::check_ptr](mem::allocator::StableMemoryAllocator::check_ptr)).
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator.
+#[inline]
+pub fn check_ptr(ptr: StablePtr) -> Result<SSlice, AllocError> {
+    with_owner_arena(ptr, |alloc| alloc.check_ptr(ptr))
+}
+
+/// Attempts to reallocate a memory block growing its size and possibly moving its content to a new
+/// location.
+///
+/// At first it tries to perform an `inplace reallocation` - check if the next neighboring (physically)
+/// memory block is also free. If that is so, this neighboring (or only a chunk of it, if it's too big)
+/// free block gets merged with the one passed as an argument to this function and returned as a result.
+/// If the memory block is the last one in stable memory, the stable memory grows right under it instead.
+/// This process does not move the data.
+///
+/// If there is no neighboring free block, than a sequence of operations is performed:
+/// 1. Allocate a new [SSlice] of the requested size, possibly returning an [OutOfMemory] error.
+/// 2. Copy the data to this new [SSlice] with [copy_bytes](mem::copy_bytes), chunk by chunk, without
+/// staging the whole block on the heap.
+/// 3. Deallocate the [SSlice] passed as an argument to this function.
+/// 4. Return the new [SSlice] as a result.
+/// This process moves the data. The old [SSlice] is only released after the copy, so if the allocation
+/// fails, the original [SSlice] and its data stay intact.
+///
+/// If the requested new size is less than the actual size of the [SSlice] passed as an argument,
+/// the [SSlice] gets split at the requested size and its tail is released back to the free list. If the
+/// tail is too small to become a free block on its own, the function does nothing and returns this
+/// [SSlice] back. Shrinking never moves the data and never fails.
+///
+/// Internally calls [StableMemoryAllocator::reallocate](mem::allocator::StableMemoryAllocator::reallocate).
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::{allocate, stable_memory_init, reallocate};
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// # unsafe {
+/// let slice = allocate(100).expect("Out of memory");
+/// let bigger_slice = reallocate(slice, 200).expect("Out of memory");
+/// # }
+/// ```
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator.
+///
+/// # Safety
+/// Don't forget to [deallocate] the memory block, when you're done!
+#[inline]
+pub unsafe fn reallocate(slice: SSlice, new_size: u64) -> Result<SSlice, OutOfMemory> {
+    let res = with_owner_arena(slice.as_ptr(), |alloc| alloc.reallocate(slice, new_size));
+
+    #[cfg(feature = "alloc_trace")]
+    mem::alloc_trace::record(mem::alloc_trace::AllocEvent::Reallocate {
+        ptr: slice.as_ptr(),
+        size: new_size,
+        result: res.as_ref().ok().map(|it| it.as_ptr()),
+    });
+
+    #[cfg(not(target_family = "wasm"))]
+    utils::leak_check::on_reallocate(slice.as_ptr(), res.as_ref().ok().map(|it| it.as_ptr()));
+
+    #[cfg(feature = "orphan_collector")]
+    {
+        mem::orphan_collector::on_touch(Some(slice.as_ptr()));
+        mem::orphan_collector::on_touch(res.as_ref().ok().map(|it| it.as_ptr()));
+    }
+
+    res
+}
+
+/// Checks if it would be possible to allocate a block of stable memory of the provided size right now.
+///
+/// The allocator will check its free list for a block of appropriate size. If there is no such free
+/// block, it will try to grow stable memory by the number of pages enough to fit this size.
+///
+/// Returns `true` if a block was found. Returns `false` if an attempt to grow stable memory resulted in
+/// an [OutOfMemory] error.
+///
+/// Internally calls [StableMemoryAllocator::make_sure_can_allocate](mem::allocator::StableMemoryAllocator::make_sure_can_allocate).
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::{make_sure_can_allocate, stable_memory_init};
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// if make_sure_can_allocate(1_000_000) {
+///     println!("It is possible to allocate a million bytes of stable memory");
+/// }
+/// ```
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator.
+#[inline]
+pub fn make_sure_can_allocate(size: u64) -> bool {
+    STABLE_MEMORY_ALLOCATOR.with(|it| {
+        auto_init(it);
+
+        let res = if let Some(alloc) = &mut *it.borrow_mut() {
+            alloc.make_sure_can_allocate(size)
+        } else {
+            unreachable!("StableMemoryAllocator is not initialized");
+        };
+
+        #[cfg(feature = "alloc_trace")]
+        mem::alloc_trace::record(mem::alloc_trace::AllocEvent::MakeSureCanAllocate {
+            size,
+            result: res,
+        });
+
+        res
+    })
+}
+
+/// Checks if it would be possible to make all of the provided [allocate_slot()] calls right now.
+///
+/// Unlike [make_sure_can_allocate()], takes into account, that small objects are stored in slots of
+/// shared slabs. Free slots are reused, while new slabs (and blocks for objects which are too big
+/// for a slot) are reserved by growing stable memory, if needed. Useful for operations, which
+/// should either make all of their allocations or none of them.
+///
+/// Internally calls [StableMemoryAllocator::make_sure_can_allocate_slots](mem::allocator::StableMemoryAllocator::make_sure_can_allocate_slots).
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::{make_sure_can_allocate_slots, stable_memory_init};
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// if make_sure_can_allocate_slots(&[100, 100, 5000]) {
+///     println!("It is possible to allocate two small objects and a big one");
+/// }
+/// ```
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator.
+#[inline]
+pub fn make_sure_can_allocate_slots(sizes: &[u64]) -> bool {
+    with_current_arena(|alloc| alloc.make_sure_can_allocate_slots(sizes))
+}
+
+/// Returns the amount of stable memory in bytes which is under the allocator's management.
+///
+/// Always equals to [stable64_size()](ic_cdk::api::stable::stable64_size) - `8`.
+///
+/// Internally calls [StableMemoryAllocator::get_available_size](mem::allocator::StableMemoryAllocator::get_available_size).
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator.
+#[inline]
+pub fn get_available_size() -> u64 {
+    STABLE_MEMORY_ALLOCATOR.with(|it| {
+        auto_init(it);
+
+        if let Some(alloc) = &*it.borrow() {
+            alloc.get_available_size()
+        } else {
+            unreachable!("StableMemoryAllocator is not initialized");
+        }
+    })
+}
+
+/// Returns the amount of free stable memory in bytes.
+///
+/// Internally calls [StableMemoryAllocator::get_free_size](mem::allocator::StableMemoryAllocator::get_free_size).
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator.
+#[inline]
+pub fn get_free_size() -> u64 {
+    STABLE_MEMORY_ALLOCATOR.with(|it| {
+        auto_init(it);
+
+        if let Some(alloc) = &*it.borrow() {
+            alloc.get_free_size()
+        } else {
+            unreachable!("StableMemoryAllocator is not initialized");
+        }
+    })
+}
+
+/// Returns the amount of allocated stable memory in bytes.
+///
+/// Always equal to [get_available_size()] - [get_free_size()].
+///
+/// Internally calls [StableMemoryAllocator::get_allocated_size](mem::allocator::StableMemoryAllocator::get_allocated_size).
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator.
+#[inline]
+pub fn get_allocated_size() -> u64 {
+    STABLE_MEMORY_ALLOCATOR.with(|it| {
+        auto_init(it);
+
+        if let Some(alloc) = &*it.borrow() {
+            alloc.get_allocated_size()
+        } else {
+            unreachable!("StableMemoryAllocator is not initialized");
+        }
+    })
+}
+
+/// Creates a new allocator arena of (at least) `size` bytes and returns its identifier.
+///
+/// An arena is a separate allocator, which manages a memory block, allocated by the main allocator.
+/// Memory blocks allocated inside an arena never mix with blocks of other arenas, so hot and cold data
+/// (or data of two independent libraries) don't fragment each other's memory. Arenas never grow - once
+/// an arena is full, allocations inside it return [OutOfMemory].
+///
+/// To allocate inside an arena, use [with_arena()]. Deallocation and reallocation automatically
+/// use the arena, which owns the memory block. Arenas are persisted between canister upgrades by
+/// [stable_memory_pre_upgrade()] and keep their identifiers.
+///
+/// Arena blocks are counted as allocated memory of the main allocator, so functions like [get_free_size()]
+/// or [get_allocator_stats()] don't include memory, which is free inside arenas.
+///
+/// Internally calls [StableMemoryAllocator::create_arena](mem::allocator::StableMemoryAllocator::create_arena).
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator.
+pub fn create_arena(size: u64) -> Result<ArenaId, OutOfMemory> {
+    let arena = STABLE_MEMORY_ALLOCATOR.with(|it| {
+        auto_init(it);
+
+        if let Some(alloc) = &mut *it.borrow_mut() {
+            alloc.create_arena(size)
+        } else {
+            unreachable!("StableMemoryAllocator is not initialized");
+        }
+    })?;
+
+    ARENAS.with(|it| {
+        let mut arenas = it.borrow_mut();
+        arenas.push(arena);
+
+        Ok(arenas.len())
+    })
+}
+
+/// Executes the closure, making all allocations inside it use the arena.
+///
+/// This effectively binds collections to the arena: stable collections, created or grown inside
+/// the closure, allocate their memory inside the arena. Calls can be nested. Passing [DEFAULT_ARENA](mem::allocator::DEFAULT_ARENA)
+/// makes allocations use the main allocator.
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::{create_arena, with_arena, stable_memory_init};
+/// # use ic_stable_memory::collections::SVec;
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// let cold = create_arena(1024 * 1024).expect("Out of memory");
+///
+/// let log = with_arena(cold, || {
+///     let mut log = SVec::<u64>::new_with_capacity(100).expect("Out of memory");
+///     log.push(10).expect("Out of memory");
+///
+///     log
+/// });
+/// ```
+///
+/// # Panics
+/// Panics if there is no arena with such identifier.
+pub fn with_arena<R, F: FnOnce() -> R>(arena: ArenaId, f: F) -> R {
+    assert!(
+        arena == DEFAULT_ARENA || ARENAS.with(|it| arena <= it.borrow().len()),
+        "Arena {} does not exist",
+        arena
+    );
+
+    let prev = CURRENT_ARENA.with(|it| it.replace(arena));
+    let res = f();
+    CURRENT_ARENA.with(|it| it.set(prev));
+
+    res
+}
+
+fn with_current_arena<R, F: FnOnce(&mut StableMemoryAllocator) -> R>(f: F) -> R {
+    let arena = CURRENT_ARENA.with(|it| it.get());
+
+    if arena != DEFAULT_ARENA {
+        return ARENAS.with(|it| f(&mut it.borrow_mut()[arena - 1]));
+    }
+
+    STABLE_MEMORY_ALLOCATOR.with(|it| {
+        auto_init(it);
+
+        if let Some(alloc) = &mut *it.borrow_mut() {
+            f(alloc)
+        } else {
+            unreachable!("StableMemoryAllocator is not initialized");
+        }
+    })
+}
+
+#[cfg(feature = "orphan_collector")]
+fn with_main_allocator<R, F: FnOnce(&mut StableMemoryAllocator) -> R>(f: F) -> R {
+    STABLE_MEMORY_ALLOCATOR.with(|it| {
+        auto_init(it);
+
+        if let Some(alloc) = &mut *it.borrow_mut() {
+            f(alloc)
+        } else {
+            unreachable!("StableMemoryAllocator is not initialized");
+        }
+    })
+}
+
+// arenas are memory blocks of the main allocator, so they're checked first
+fn with_owner_arena<R, F: FnOnce(&mut StableMemoryAllocator) -> R>(ptr: StablePtr, f: F) -> R {
+    let mut f = Some(f);
+
+    let res = ARENAS.with(|it| {
+        it.borrow_mut()
+            .iter_mut()
+            .find(|arena| arena.contains_ptr(ptr))
+            .map(|arena| (f.take().unwrap())(arena))
+    });
+
+    if let Some(res) = res {
+        return res;
+    }
+
+    STABLE_MEMORY_ALLOCATOR.with(|it| {
+        auto_init(it);
+
+        if let Some(alloc) = &mut *it.borrow_mut() {
+            (f.take().unwrap())(alloc)
+        } else {
+            unreachable!("StableMemoryAllocator is not initialized");
+        }
+    })
+}
+
+/// Returns an iterator over every memory block (both allocated and free), managed by the stable
+/// memory allocator, in ascending order of their pointers.
+///
+/// Useful for diagnostics and external tooling. Blocks are read from stable memory lazily, so don't
+/// allocate, deallocate or reallocate stable memory while iterating.
+///
+/// Internally calls [StableMemoryAllocator::walk](mem::allocator::StableMemoryAllocator::walk).
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator.
+pub fn walk_heap() -> HeapWalker {
+    STABLE_MEMORY_ALLOCATOR.with(|it| {
+        auto_init(it);
+
+        if let Some(alloc) = &*it.borrow() {
+            alloc.walk()
+        } else {
+            unreachable!("StableMemoryAllocator is not initialized");
+        }
+    })
+}
+
+/// Registers a function, which is called by [compact()] and [defrag_step()] for each memory block they move.
+///
+/// The function receives the old and the new pointer of the block (as returned by [SSlice::as_ptr]).
+/// Use it to fix pointers to moved blocks, stored inside your data structures. The callback is not
+/// persisted between canister upgrades - register it in both `#[init]` and `#[post_upgrade]`.
+///
+/// The callback is invoked while the allocator is busy, so it can read and write stable memory, but
+/// can't allocate or deallocate it.
+#[inline]
+pub fn set_relocation_callback(callback: fn(StablePtr, StablePtr)) {
+    RELOCATION_CALLBACK.with(|it| *it.borrow_mut() = Some(callback));
+}
+
+/// Defragments stable memory, moving all allocated memory blocks towards its beginning.
+///
+/// After this call all free stable memory is a single block at the end, so any allocation that fits
+/// into [get_free_size()] bytes succeeds without growing stable memory. Blocks are moved in ascending
+/// order of their pointers and for each moved block the callback registered with [set_relocation_callback()]
+/// is called. Pointers to custom data (see [store_custom_data()]) are fixed automatically.
+///
+/// Takes O(N) in both time and stable memory reads/writes, where N is the total size of allocated memory.
+///
+/// Internally calls [StableMemoryAllocator::compact](mem::allocator::StableMemoryAllocator::compact).
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator.
+///
+/// # Safety
+/// Every pointer to a moved block, stored anywhere (including pointers inside collections of this crate),
+/// becomes invalid. Make sure the relocation callback fixes all of them, otherwise using these pointers
+/// afterwards is undefined behavior.
+pub unsafe fn compact() {
+    assert!(
+        ARENAS.with(|it| it.borrow().is_empty()),
+        "Unable to compact stable memory with arenas"
+    );
+
+    let callback = RELOCATION_CALLBACK.with(|it| *it.borrow());
+
+    // pointers, collected by the current cycle, are invalidated by relocations
+    #[cfg(feature = "orphan_collector")]
+    mem::orphan_collector::abort_orphan_collection();
+
+    STABLE_MEMORY_ALLOCATOR.with(|it| {
+        auto_init(it);
+
+        if let Some(alloc) = &mut *it.borrow_mut() {
+            alloc.compact(|old, new| {
+                if let Some(f) = callback {
+                    f(old, new);
+                }
+            });
+        } else {
+            unreachable!("StableMemoryAllocator is not initialized");
+        }
+    })
+}
+
+/// Incrementally defragments stable memory, moving a limited number of memory blocks per call.
+///
+/// Works like [compact()], but can be spread across many messages (e.g. called from a timer or a
+/// heartbeat), so a big heap is defragmented without a single call, which exceeds the instruction
+/// limit. Each moved block makes free memory closer to a single block at the end of stable memory.
+/// For each moved block the callback registered with [set_relocation_callback()] is called. Stable
+/// memory can be used as usual between the calls.
+///
+/// Internally calls [StableMemoryAllocator::defrag_step](mem::allocator::StableMemoryAllocator::defrag_step).
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::{allocate, deallocate, defrag_step, stable_memory_init};
+/// # use ic_stable_memory::mem::allocator::DefragBudget;
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// # let slices = (0..10).map(|_| unsafe { allocate(100).unwrap() }).collect::<Vec<_>>();
+/// # deallocate(slices[0]);
+/// // somewhere in a timer
+/// let progress = unsafe { defrag_step(DefragBudget::Blocks(100)) };
+///
+/// if progress.done {
+///     // stop the timer
+/// }
+/// ```
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator or if there are arenas.
+///
+/// # Safety
+/// Same as [compact()].
+pub unsafe fn defrag_step(budget: DefragBudget) -> DefragProgress {
+    assert!(
+        ARENAS.with(|it| it.borrow().is_empty()),
+        "Unable to defragment stable memory with arenas"
+    );
+
+    let callback = RELOCATION_CALLBACK.with(|it| *it.borrow());
+
+    #[cfg(feature = "orphan_collector")]
+    mem::orphan_collector::abort_orphan_collection();
+
+    STABLE_MEMORY_ALLOCATOR.with(|it| {
+        auto_init(it);
+
+        if let Some(alloc) = &mut *it.borrow_mut() {
+            alloc.defrag_step(budget, |old, new| {
+                if let Some(f) = callback {
+                    f(old, new);
+                }
+            })
+        } else {
+            unreachable!("StableMemoryAllocator is not initialized");
+        }
+    })
+}
+
+/// Finds allocated memory blocks, which are not reachable from any root.
+///
+/// Roots are `roots` (pointers to [SBox]-es, [SSlice]-s or any other memory blocks, holding your
+/// collections) and everything stored with [store_custom_data()]. A block is reachable, if it is a root
+/// or if some reachable block contains a pointer (to its beginning or inside of it). This check is
+/// conservative - a leaked block may remain unnoticed, if some reachable block contains bytes that
+/// look like a pointer to it.
+///
+/// Takes O(N * logM) time, where N is the total size of allocated memory and M is the number of
+/// allocated blocks. Intended for diagnostics only (e.g. after an aborted migration), not for regular use.
+///
+/// Available only with the `leak_detection` feature enabled.
+///
+/// Internally calls [StableMemoryAllocator::leak_report](mem::allocator::StableMemoryAllocator::leak_report).
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator.
+#[cfg(feature = "leak_detection")]
+pub fn get_leak_report(roots: &[StablePtr]) -> mem::allocator::LeakReport {
+    STABLE_MEMORY_ALLOCATOR.with(|it| {
+        auto_init(it);
+
+        if let Some(alloc) = &*it.borrow() {
+            alloc.leak_report(roots.iter().copied())
+        } else {
+            unreachable!("StableMemoryAllocator is not initialized");
+        }
+    })
+}
+
+/// Returns detailed statistics of the stable memory allocator.
+///
+/// Includes the number of allocated and free blocks, the size of the biggest free block, the total
+/// size of blocks' metadata and a breakdown of free blocks by size classes. [AllocatorStats](mem::allocator::AllocatorStats)
+/// implements [CandidType](candid::CandidType), so it can be returned right from a metrics endpoint.
+///
+/// Takes O(N), where N is the number of distinct sizes of free blocks.
+///
+/// Internally calls [StableMemoryAllocator::stats](mem::allocator::StableMemoryAllocator::stats).
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator.
+#[inline]
+pub fn get_allocator_stats() -> AllocatorStats {
+    STABLE_MEMORY_ALLOCATOR.with(|it| {
+        auto_init(it);
+
+        if let Some(alloc) = &*it.borrow() {
+            alloc.stats()
+        } else {
+            unreachable!("StableMemoryAllocator is not initialized");
+        }
+    })
+}
+
+/// Returns a report on fragmentation of free stable memory.
+///
+/// The report contains a fragmentation ratio (how much of free memory is *not* in the biggest free
+/// block) and a histogram of free block sizes. A high ratio means that the canister's stable heap is too
+/// fragmented to satisfy large allocations without growing stable memory.
+///
+/// Internally calls [StableMemoryAllocator::fragmentation_report](mem::allocator::StableMemoryAllocator::fragmentation_report).
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator.
+#[inline]
+pub fn get_fragmentation_report() -> FragmentationReport {
+    STABLE_MEMORY_ALLOCATOR.with(|it| {
+        auto_init(it);
+
+        if let Some(alloc) = &*it.borrow() {
+            alloc.fragmentation_report()
+        } else {
+            unreachable!("StableMemoryAllocator is not initialized");
+        }
+    })
+}
+
+/// Verifies the allocator's free-list and size counters against a full walk of the heap
+///
+/// Returns the first found inconsistency, e.g. a free-list entry, which is not actually free, or a
+/// free block, which is missing from the free-list. Reads the header of every memory block, so it is
+/// meant for diagnostics (e.g. in a `#[post_upgrade]` of a debug build or on a downloaded stable
+/// memory image), not for every call.
+///
+/// Internally calls [StableMemoryAllocator::check_consistency](mem::allocator::StableMemoryAllocator::check_consistency).
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::{check_consistency, stable_memory_init};
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// assert!(check_consistency().is_ok());
+/// ```
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator.
+#[inline]
+pub fn check_consistency() -> Result<(), CorruptData> {
+    STABLE_MEMORY_ALLOCATOR.with(|it| {
+        auto_init(it);
+
+        if let Some(alloc) = &*it.borrow() {
+            alloc.check_consistency()
+        } else {
+            unreachable!("StableMemoryAllocator is not initialized");
+        }
+    })
+}
+
+/// Returns the range of stable memory, managed by the allocator
+///
+/// Starts at the [base offset](AllocatorBuilder::base_offset) and ends after the last memory block
+/// (free or not). Arenas are inside this range.
+///
+/// Internally calls [StableMemoryAllocator::get_heap_range](mem::allocator::StableMemoryAllocator::get_heap_range).
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator.
+#[inline]
+pub fn get_heap_range() -> Range<StablePtr> {
+    STABLE_MEMORY_ALLOCATOR.with(|it| {
+        auto_init(it);
+
+        if let Some(alloc) = &*it.borrow() {
+            alloc.get_heap_range()
+        } else {
+            unreachable!("StableMemoryAllocator is not initialized");
+        }
+    })
+}
+
+/// Returns `max_pages` parameter.
+///
+/// See [init_allocator] for more details.
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator.
+#[inline]
+pub fn get_max_pages() -> u64 {
+    STABLE_MEMORY_ALLOCATOR.with(|it| {
+        auto_init(it);
+
+        if let Some(alloc) = &*it.borrow() {
+            alloc.get_max_pages()
+        } else {
+            unreachable!("StableMemoryAllocator is not initialized");
+        }
+    })
+}
+
+/// Sets `max_pages` parameter - a quota on the total number of stable memory pages.
+///
+/// When the allocator needs to grow stable memory beyond this limit, it returns [OutOfMemory] instead,
+/// leaving the rest of stable memory for upgrades and other subsystems of the canister. Passing a
+/// `0` removes the limit. If stable memory has already grown beyond the limit, the current number
+/// of stable memory pages is used as the limit instead.
+///
+/// See also [init_allocator].
+///
+/// Internally calls [StableMemoryAllocator::set_max_pages](mem::allocator::StableMemoryAllocator::set_max_pages).
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator.
+#[inline]
+pub fn set_max_pages(max_pages: u64) {
+    STABLE_MEMORY_ALLOCATOR.with(|it| {
+        auto_init(it);
+
+        if let Some(alloc) = &mut *it.borrow_mut() {
+            alloc.set_max_pages(max_pages)
+        } else {
+            unreachable!("StableMemoryAllocator is not initialized");
+        }
+    })
+}
+
+/// Grows stable memory by `pages` pages ahead of time
+///
+/// The new pages become a part of the free block at the end of the heap, so allocations, made after
+/// this call, don't pay for growing stable memory until this free block is exhausted. Useful to keep
+/// the instruction cost of latency-sensitive update calls predictable. Returns [OutOfMemory], if
+/// stable memory can't grow by this many pages (including the case when `max_pages` quota is exceeded).
+///
+/// Internally calls [StableMemoryAllocator::reserve_pages](mem::allocator::StableMemoryAllocator::reserve_pages).
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::{reserve_pages, stable_memory_init};
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// reserve_pages(10).expect("Not enough stable memory");
+/// ```
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator.
+#[inline]
+pub fn reserve_pages(pages: u64) -> Result<(), OutOfMemory> {
+    STABLE_MEMORY_ALLOCATOR.with(|it| {
+        auto_init(it);
+
+        if let Some(alloc) = &mut *it.borrow_mut() {
+            alloc.reserve_pages(pages)
+        } else {
+            unreachable!("StableMemoryAllocator is not initialized");
+        }
+    })
+}
+
+/// Returns the strategy, which is used to pick a free block for a new allocation in the current arena
+///
+/// See [set_fit_policy].
+///
+/// Internally calls [StableMemoryAllocator::get_fit_policy](mem::allocator::StableMemoryAllocator::get_fit_policy).
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator.
+#[inline]
+pub fn get_fit_policy() -> FitPolicy {
+    with_current_arena(|alloc| alloc.get_fit_policy())
+}
+
+/// Sets the strategy, which is used to pick a free block for a new allocation in the current arena
+///
+/// [FitPolicy::BestFit] is used by default. The policy is persisted between canister upgrades.
+/// Changing it doesn't move any existing allocations.
+///
+/// Internally calls [StableMemoryAllocator::set_fit_policy](mem::allocator::StableMemoryAllocator::set_fit_policy).
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator.
+#[inline]
+pub fn set_fit_policy(fit_policy: FitPolicy) {
+    with_current_arena(|alloc| alloc.set_fit_policy(fit_policy))
+}
+
+#[inline]
+pub fn _debug_validate_allocator() {
+    STABLE_MEMORY_ALLOCATOR.with(|it: &RefCell<Option<StableMemoryAllocator>>| {
+        auto_init(it);
+
+        if let Some(alloc) = &*it.borrow() {
+            alloc.debug_validate_free_blocks();
+        } else {
+            unreachable!("StableMemoryAllocator is not initialized");
+        }
+    })
+}
+
+#[inline]
+pub fn _debug_print_allocator() {
+    STABLE_MEMORY_ALLOCATOR.with(|it| {
+        auto_init(it);
+
+        if let Some(alloc) = &*it.borrow_mut() {
+            isoprint(format!("{alloc:?}").as_str());
+        } else {
             unreachable!("StableMemoryAllocator is not initialized");
         }
     })
@@ -618,12 +1830,17 @@ pub fn _debug_print_allocator() {
 
 #[cfg(test)]
 mod tests {
+    use crate::collections::SVec;
+    use crate::mem::allocator::type_fingerprint;
     use crate::{
-        _debug_print_allocator, allocate, deallocate, get_allocated_size, get_free_size,
-        init_allocator, reallocate, retrieve_custom_data, stable_memory_init,
-        stable_memory_post_upgrade, stable_memory_pre_upgrade, store_custom_data, SBox,
+        _debug_print_allocator, allocate, compact, create_arena, deallocate, get_allocated_size,
+        get_free_size, init_allocator, reallocate, retrieve_custom_data, set_relocation_callback,
+        stable_memory_init, stable_memory_post_upgrade, stable_memory_pre_upgrade,
+        store_custom_data, SBox,
     };
-    use crate::{deinit_allocator, reinit_allocator, SSlice};
+    use crate::{declare_root, disable_auto_init, enable_auto_init, get_root, persist, restore};
+    use crate::{deinit_allocator, reinit_allocator, with_arena, SSlice, ARENAS};
+    use crate::{stable, try_retrieve_custom_data};
 
     #[test]
     fn basic_flow_works_fine() {
@@ -647,6 +1864,148 @@ mod tests {
         _debug_print_allocator();
     }
 
+    #[test]
+    fn persist_restore_works_fine() {
+        stable::clear();
+        restore();
+
+        let mut vec = SVec::<u64>::new();
+        vec.push(10).unwrap();
+
+        let mut b = SBox::new(vec).unwrap();
+        unsafe { b.stable_drop_flag_off() };
+        declare_root("vec", b.as_ptr());
+
+        persist();
+        restore();
+
+        let ptr = get_root("vec").unwrap();
+        let vec = unsafe { SBox::<SVec<u64>>::from_ptr(ptr) }.into_inner();
+        assert_eq!(*vec.get(0).unwrap(), 10);
+    }
+
+    #[test]
+    fn auto_init_works_fine() {
+        stable::clear();
+        enable_auto_init(0);
+
+        let mut vec = SVec::<u64>::new();
+        vec.push(10).unwrap();
+
+        let mut b = SBox::new(vec).unwrap();
+        unsafe { b.stable_drop_flag_off() };
+        declare_root("vec", b.as_ptr());
+
+        deinit_allocator().unwrap();
+
+        let ptr = get_root("vec").unwrap();
+        let vec = unsafe { SBox::<SVec<u64>>::from_ptr(ptr) }.into_inner();
+        assert_eq!(*vec.get(0).unwrap(), 10);
+
+        disable_auto_init();
+        deinit_allocator().unwrap();
+
+        let res = std::panic::catch_unwind(|| get_root("vec"));
+        assert!(res.is_err());
+
+        reinit_allocator();
+    }
+
+    #[test]
+    fn custom_data_fingerprints_work_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut vec = SVec::<u64>::new();
+            vec.push(10).unwrap();
+
+            store_custom_data(1, SBox::new(vec).unwrap());
+            stable_memory_pre_upgrade().unwrap();
+            stable_memory_post_upgrade();
+
+            let err = try_retrieve_custom_data::<SVec<u32>>(1).err().unwrap();
+            assert_eq!(err.idx, 1);
+            assert_eq!(err.expected, type_fingerprint::<SVec<u32>>());
+            assert_eq!(err.found, type_fingerprint::<SVec<u64>>());
+
+            let vec = try_retrieve_custom_data::<SVec<u64>>(1)
+                .unwrap()
+                .unwrap()
+                .into_inner();
+            assert_eq!(*vec.get(0).unwrap(), 10);
+
+            assert!(try_retrieve_custom_data::<SVec<u32>>(1).unwrap().is_none());
+        }
+
+        assert_eq!(get_allocated_size(), 0);
+
+        // module paths don't matter
+        mod a {
+            pub struct T;
+        }
+        mod b {
+            pub struct T;
+        }
+
+        assert_eq!(type_fingerprint::<a::T>(), type_fingerprint::<b::T>());
+        assert_eq!(
+            type_fingerprint::<Vec<a::T>>(),
+            type_fingerprint::<Vec<b::T>>()
+        );
+        assert_ne!(type_fingerprint::<u64>(), type_fingerprint::<i64>());
+        assert_ne!(type_fingerprint::<[u8; 1]>(), type_fingerprint::<[u8; 2]>());
+    }
+
+    #[test]
+    fn arenas_work_fine() {
+        stable_memory_init();
+
+        let arena = create_arena(10_000).unwrap();
+        let allocated_size = get_allocated_size();
+
+        let b = with_arena(arena, || SBox::new(10u64).unwrap());
+        let c = SBox::new(20u64).unwrap();
+
+        assert!(ARENAS.with(|it| it.borrow()[0].contains_ptr(b.as_ptr())));
+        assert!(!ARENAS.with(|it| it.borrow()[0].contains_ptr(c.as_ptr())));
+        assert!(with_arena(arena, || unsafe { allocate(10_000) }).is_err());
+
+        store_custom_data(1, b);
+        stable_memory_pre_upgrade().unwrap();
+        stable_memory_post_upgrade();
+
+        let b = retrieve_custom_data::<u64>(1).unwrap();
+        assert_eq!(*b, 10);
+        assert!(ARENAS.with(|it| it.borrow()[0].contains_ptr(b.as_ptr())));
+
+        drop(b);
+        drop(c);
+
+        assert_eq!(ARENAS.with(|it| it.borrow()[0].get_allocated_size()), 0);
+        assert_eq!(get_allocated_size(), allocated_size);
+    }
+
+    #[test]
+    fn compaction_works_fine() {
+        thread_local! {
+            static RELOCATIONS: std::cell::RefCell<Vec<(u64, u64)>> = Default::default();
+        }
+
+        stable_memory_init();
+        set_relocation_callback(|old, new| RELOCATIONS.with(|it| it.borrow_mut().push((old, new))));
+
+        let a = unsafe { allocate(100).unwrap() };
+        store_custom_data(1, SBox::new(100u64).unwrap());
+
+        deallocate(a);
+        unsafe { compact() };
+
+        assert_eq!(RELOCATIONS.with(|it| it.borrow().len()), 1);
+        assert_eq!(retrieve_custom_data::<u64>(1).unwrap().into_inner(), 100);
+        assert_eq!(get_allocated_size(), 0);
+    }
+
     #[test]
     #[should_panic]
     fn init_allocator_twice_should_panic() {
@@ -0,0 +1,280 @@
+//! Recording and replaying of allocation traces
+//!
+//! While recording is on, each call to [allocate](crate::allocate), [deallocate](crate::deallocate),
+//! [try_deallocate](crate::try_deallocate), [reallocate](crate::reallocate),
+//! [allocate_slot](crate::allocate_slot), [deallocate_slot](crate::deallocate_slot) and
+//! [make_sure_can_allocate](crate::make_sure_can_allocate) (and, therefore,
+//! each operation of every collection) is appended to an [AllocTrace] in heap memory, together
+//! with its result. The trace is [CandidType], so it can be returned from a query method or put into
+//! an [SCandid](crate::SCandid) to survive an upgrade.
+//!
+//! [AllocTrace::replay] performs the same calls on the current allocator. A trace, recorded right
+//! after [stable_memory_init](crate::stable_memory_init), and replayed on freshly initialized
+//! stable memory, reproduces the exact same layout - this is how a pathological fragmentation,
+//! reported by a user, can be reproduced and inspected (e.g. with [walk_heap](crate::walk_heap)).
+//!
+//! Only available with the `alloc_trace` feature.
+
+use crate::mem::s_slice::SSlice;
+use crate::mem::StablePtr;
+use crate::{
+    allocate, allocate_slot, deallocate, deallocate_slot, make_sure_can_allocate, reallocate,
+};
+use candid::{CandidType, Deserialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+thread_local! {
+    static RECORDER: RefCell<Option<AllocTrace>> = RefCell::new(None);
+}
+
+/// A single call to the allocator, see [AllocTrace]
+///
+/// Results are pointers to allocated memory blocks (or slots), [None] means `OutOfMemory`.
+#[derive(Debug, Copy, Clone, CandidType, Deserialize, Eq, PartialEq)]
+pub enum AllocEvent {
+    Allocate {
+        size: u64,
+        result: Option<StablePtr>,
+    },
+    Deallocate {
+        ptr: StablePtr,
+    },
+    Reallocate {
+        ptr: StablePtr,
+        size: u64,
+        result: Option<StablePtr>,
+    },
+    AllocateSlot {
+        size: u64,
+        result: Option<StablePtr>,
+    },
+    DeallocateSlot {
+        ptr: StablePtr,
+    },
+    MakeSureCanAllocate {
+        size: u64,
+        result: bool,
+    },
+}
+
+/// A sequence of calls to the allocator, in the order they were made
+///
+/// See [start_recording].
+#[derive(Debug, Clone, Default, CandidType, Deserialize, Eq, PartialEq)]
+pub struct AllocTrace {
+    pub events: Vec<AllocEvent>,
+}
+
+/// Result of [AllocTrace::replay]
+#[derive(Debug, Copy, Clone, CandidType, Deserialize, Eq, PartialEq)]
+pub struct ReplayReport {
+    /// Number of replayed events
+    pub events_count: u64,
+    /// Number of events, which returned a different result, than the recorded one
+    pub diverged_count: u64,
+    /// Index of the first such event
+    pub first_divergence: Option<u64>,
+}
+
+impl ReplayReport {
+    /// Returns [true], if every event returned exactly the recorded result
+    #[inline]
+    pub fn is_exact(&self) -> bool {
+        self.diverged_count == 0
+    }
+}
+
+/// Indicates that a trace can't be replayed
+///
+/// See [AllocTrace::replay].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct InvalidTrace {
+    /// Index of the event
+    pub event_idx: u64,
+    /// The event releases (or reallocates) a pointer, which is not allocated by preceding events
+    pub ptr: StablePtr,
+}
+
+impl AllocTrace {
+    /// Performs the recorded calls on the current allocator
+    ///
+    /// Recorded pointers are translated into the replayed ones, so a trace can be replayed even if
+    /// the allocator returns different pointers (e.g. because its initial state differs). Memory
+    /// blocks, which are not released by the trace, stay allocated, so the resulting layout can be
+    /// inspected afterwards. Events, which ran out of memory during recording, are replayed too.
+    ///
+    /// If recording is on, the replayed calls are recorded as well.
+    ///
+    /// # Errors
+    /// Returns [InvalidTrace], if the trace releases a pointer, which was not allocated by it (e.g.
+    /// if recording was started when some memory was already allocated). Events, preceding the
+    /// invalid one, stay replayed.
+    pub fn replay(&self) -> Result<ReplayReport, InvalidTrace> {
+        let mut slices: HashMap<StablePtr, SSlice> = HashMap::new();
+        let mut slots: HashMap<StablePtr, StablePtr> = HashMap::new();
+
+        let mut report = ReplayReport {
+            events_count: 0,
+            diverged_count: 0,
+            first_divergence: None,
+        };
+
+        for (idx, event) in self.events.iter().enumerate() {
+            let idx = idx as u64;
+            let invalid = |ptr| InvalidTrace {
+                event_idx: idx,
+                ptr,
+            };
+
+            let diverged = match *event {
+                AllocEvent::Allocate { size, result } => {
+                    let res = unsafe { allocate(size) }.ok();
+
+                    if let (Some(recorded), Some(slice)) = (result, res) {
+                        slices.insert(recorded, slice);
+                    }
+
+                    result != res.map(|it| it.as_ptr())
+                }
+                AllocEvent::Deallocate { ptr } => {
+                    let slice = slices.remove(&ptr).ok_or_else(|| invalid(ptr))?;
+                    let diverged = slice.as_ptr() != ptr;
+                    deallocate(slice);
+
+                    diverged
+                }
+                AllocEvent::Reallocate { ptr, size, result } => {
+                    let slice = *slices.get(&ptr).ok_or_else(|| invalid(ptr))?;
+                    let res = unsafe { reallocate(slice, size) }.ok();
+
+                    if let Some(new_slice) = res {
+                        slices.remove(&ptr);
+                        slices.insert(result.unwrap_or(ptr), new_slice);
+                    }
+
+                    result != res.map(|it| it.as_ptr())
+                }
+                AllocEvent::AllocateSlot { size, result } => {
+                    let res = unsafe { allocate_slot(size) }.ok();
+
+                    if let (Some(recorded), Some(slot)) = (result, res) {
+                        slots.insert(recorded, slot);
+                    }
+
+                    result != res
+                }
+                AllocEvent::DeallocateSlot { ptr } => {
+                    let slot = slots.remove(&ptr).ok_or_else(|| invalid(ptr))?;
+                    deallocate_slot(slot);
+
+                    slot != ptr
+                }
+                AllocEvent::MakeSureCanAllocate { size, result } => {
+                    make_sure_can_allocate(size) != result
+                }
+            };
+
+            if diverged {
+                report.diverged_count += 1;
+                report.first_divergence.get_or_insert(idx);
+            }
+
+            report.events_count += 1;
+        }
+
+        Ok(report)
+    }
+}
+
+/// Starts recording calls to the allocator into a new [AllocTrace]
+///
+/// Returns the previous trace, if recording was already on.
+pub fn start_recording() -> Option<AllocTrace> {
+    RECORDER.with(|it| it.borrow_mut().replace(AllocTrace::default()))
+}
+
+/// Stops recording and returns the recorded trace, if recording was on
+pub fn stop_recording() -> Option<AllocTrace> {
+    RECORDER.with(|it| it.borrow_mut().take())
+}
+
+/// Returns [true], if calls to the allocator are being recorded
+pub fn is_recording() -> bool {
+    RECORDER.with(|it| it.borrow().is_some())
+}
+
+#[inline]
+pub(crate) fn record(event: AllocEvent) {
+    RECORDER.with(|it| {
+        if let Some(trace) = &mut *it.borrow_mut() {
+            trace.events.push(event);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::{SBTreeMap, SVec};
+    use crate::mem::alloc_trace::{
+        is_recording, start_recording, stop_recording, AllocEvent, AllocTrace,
+    };
+    use crate::{
+        _debug_validate_allocator, allocate, deallocate, get_allocated_size, stable,
+        stable_memory_init, walk_heap,
+    };
+
+    #[test]
+    fn works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        assert!(!is_recording());
+        assert!(start_recording().is_none());
+        assert!(is_recording());
+
+        let leftover = {
+            let mut map = SBTreeMap::new();
+            let mut vec = SVec::new();
+
+            for i in 0..1000u64 {
+                map.insert(i, i).unwrap();
+                vec.push(i).unwrap();
+
+                if i % 3 == 0 {
+                    map.remove(&(i / 2));
+                }
+            }
+
+            unsafe { allocate(100).unwrap() }
+        };
+
+        let trace = stop_recording().unwrap();
+        assert!(!is_recording());
+
+        assert!(matches!(
+            trace.events.last(),
+            Some(AllocEvent::Allocate { size: 100, result: Some(p) }) if *p == leftover.as_ptr()
+        ));
+
+        let layout = walk_heap().collect::<Vec<_>>();
+
+        stable::clear();
+        stable_memory_init();
+
+        let report = trace.replay().unwrap();
+        assert!(report.is_exact());
+        assert_eq!(report.events_count, trace.events.len() as u64);
+        assert_eq!(walk_heap().collect::<Vec<_>>(), layout);
+
+        deallocate(leftover);
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+
+        let invalid = AllocTrace {
+            events: vec![AllocEvent::Deallocate { ptr: 8 }],
+        };
+        assert_eq!(invalid.replay().unwrap_err().ptr, 8);
+    }
+}
@@ -1,50 +1,590 @@
 //! Stable memory allocator used by every data collection in this crate.
 //!
 //! `O(logN)` in both: allocation and deallocation, where `N` is the number of free blocks.
-//! Free-list is simply a [BTreeMap](std::collections::BTreeMap). Custom data storage is simply a
+//! Free-list is simply a [BTreeMap](std::collections::BTreeMap) of sizes to [BTreeSet](std::collections::BTreeSet)s
+//! of free blocks of that size, so neither of them depends on how many free blocks of the same size
+//! there are. Neighboring free blocks are found by their boundary tags (the size word is written
+//! at both ends of each block), so merging them is `O(logN)` too. Custom data storage is simply a
 //! [HashMap](std::collections::HashMap).
 //!
 //! Persisted between canister upgrades by serializing itself with [CandidType](candid::CandidType),
 //! putting itself in an [SBox] and writing a pointer to that [SBox] into stable memory at location (0..8).
+//! The upper byte of that pointer holds the [layout mode](layout_mode) of the build, which wrote it.
 //!
 //! This allocator shouldn't be used directly - instead use top-level functions exposed by this crate.
 
 use crate::encoding::dyn_size::candid_decode_one_allow_trailing;
-use crate::encoding::{AsDynSizeBytes, AsFixedSizeBytes, Buffer};
+use crate::encoding::{AsDynSizeBytes, AsFixedSizeBytes, Buffer, CorruptData};
 use crate::mem::free_block::FreeBlock;
-use crate::mem::s_slice::SSlice;
-use crate::mem::StablePtr;
+use crate::mem::s_slice::{decode_size_word, SSlice, CANARY_SIZE};
+use crate::mem::slab::Slabs;
+use crate::mem::{StablePtr, StablePtrBuf};
 use crate::primitive::s_box::SBox;
 use crate::primitive::StableType;
 use crate::utils::math::ceil_div;
 use crate::{stable, OutOfMemory, PAGE_SIZE_BYTES};
 use candid::{encode_one, CandidType, Deserialize};
-use std::collections::{BTreeMap, HashMap};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::Range;
 
 pub(crate) const ALLOCATOR_PTR: StablePtr = 0;
 pub(crate) const MIN_PTR: StablePtr = u64::SIZE as u64;
 pub(crate) const EMPTY_PTR: StablePtr = u64::MAX;
+/// Version of the stable memory layout, used by this version of the crate
+///
+/// Stored inside allocator's metadata. Older layouts are migrated on [reinit](crate::reinit_allocator),
+/// if it is possible.
+pub const LAYOUT_VERSION: u32 = 1;
+/// [Layout mode](layout_mode) bit, set if memory blocks were written with `checksummed_headers` feature
+pub const MODE_CHECKSUMMED_HEADERS: u8 = 1;
+/// [Layout mode](layout_mode) bit, set if memory blocks were written with `debug_canaries` feature
+pub const MODE_DEBUG_CANARIES: u8 = 1 << 1;
+/// [Layout mode](layout_mode) bit, set if [usize] and [isize] were encoded as 8 bytes - on 64-bit
+/// targets or with `portable_usize` feature
+pub const MODE_WIDE_USIZE: u8 = 1 << 2;
+// set for every heap, written since layout modes are recorded
+const MODE_RECORDED: u8 = 1 << 7;
+// the word at the metadata offset holds a pointer to the metadata block in its lower 56 bits and
+// the layout mode in its upper 8 bits
+const MODE_SHIFT: u32 = 56;
+const META_PTR_MASK: u64 = (1 << MODE_SHIFT) - 1;
+// metadata offset + a free block of minimum size
+const MIN_ARENA_SIZE: u64 = u64::SIZE as u64 + (StablePtr::SIZE * 4) as u64;
+
+/// Identifier of an allocator arena
+///
+/// See [create_arena](crate::create_arena).
+pub type ArenaId = usize;
+
+/// Identifier of the main allocator, which is used when no other arena is selected
+pub const DEFAULT_ARENA: ArenaId = 0;
+
+#[cfg(feature = "debug_canaries")]
+const CANARY: u8 = 0xCA;
+#[cfg(feature = "debug_canaries")]
+const POISON: u8 = 0xDE;
+
+/// Free blocks of sizes in `[min_size, min_size * 2)` bytes
+#[derive(Debug, Clone, CandidType, Deserialize, Eq, PartialEq)]
+pub struct SizeClassStats {
+    /// Lower bound of this size class, always a power of two
+    pub min_size: u64,
+    /// Number of free blocks in this size class
+    pub blocks_count: u64,
+    /// Total size of free blocks in this size class (excluding metadata)
+    pub total_size: u64,
+}
+
+/// A snapshot of the stable memory allocator's state
+///
+/// See [get_allocator_stats](crate::get_allocator_stats).
+#[derive(Debug, Clone, CandidType, Deserialize, Eq, PartialEq)]
+pub struct AllocatorStats {
+    /// Number of grown stable memory pages
+    pub pages: u64,
+    /// See [get_available_size](crate::get_available_size)
+    pub available_size: u64,
+    /// See [get_allocated_size](crate::get_allocated_size)
+    pub allocated_size: u64,
+    /// See [get_free_size](crate::get_free_size)
+    pub free_size: u64,
+    /// Number of allocated memory blocks
+    pub allocated_blocks_count: u64,
+    /// Number of free memory blocks
+    pub free_blocks_count: u64,
+    /// Size of the biggest free block (excluding metadata)
+    pub largest_free_block_size: u64,
+    /// Total size of metadata of all memory blocks, both allocated and free
+    pub blocks_overhead_size: u64,
+    /// Free blocks grouped by size class, in ascending order
+    pub free_size_classes: Vec<SizeClassStats>,
+}
+
+/// Indicates that a pointer, passed to a checked allocator function, is invalid
+///
+/// See [try_deallocate](crate::try_deallocate).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AllocError {
+    /// The pointer points to a memory block, which is already free
+    DoubleFree(StablePtr),
+    /// The pointer doesn't point to the beginning of any memory block
+    InvalidPointer(StablePtr),
+}
+
+/// Indicates that stable memory was written by a version of this crate with an incompatible layout
+///
+/// See [try_reinit_allocator](crate::try_reinit_allocator).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct IncompatibleVersion {
+    /// Layout version, found in stable memory
+    pub found: u32,
+    /// See [LAYOUT_VERSION]
+    pub supported: u32,
+}
+
+/// Returns the layout mode of this build - a set of `MODE_*` bits for enabled features, which change
+/// the way memory blocks are laid out in stable memory
+///
+/// The mode is persisted together with the allocator's metadata and checked on
+/// [reinit](crate::reinit_allocator), so toggling such a feature on a canister with existing data is
+/// reported as [ReinitError::LayoutMismatch], instead of misreading every memory block.
+pub const fn layout_mode() -> u8 {
+    let mut mode = MODE_RECORDED;
+
+    if cfg!(feature = "checksummed_headers") {
+        mode |= MODE_CHECKSUMMED_HEADERS;
+    }
+
+    if cfg!(feature = "debug_canaries") {
+        mode |= MODE_DEBUG_CANARIES;
+    }
+
+    if usize::SIZE == 8 {
+        mode |= MODE_WIDE_USIZE;
+    }
+
+    mode
+}
+
+/// Indicates that stable memory was written with another [layout mode](layout_mode)
+///
+/// See [try_reinit_allocator](crate::try_reinit_allocator).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct LayoutMismatch {
+    /// Layout mode, found in stable memory (`0` for heaps, written before layout modes were recorded)
+    pub found: u8,
+    /// See [layout_mode]
+    pub expected: u8,
+}
+
+/// Indicates that the allocator can't be re-attached to stable memory
+///
+/// See [try_reinit_allocator](crate::try_reinit_allocator).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ReinitError {
+    /// See [IncompatibleVersion]
+    IncompatibleVersion(IncompatibleVersion),
+    /// See [LayoutMismatch]
+    LayoutMismatch(LayoutMismatch),
+    /// The free-list doesn't match the free space summary, persisted together with it, or points to
+    /// memory, which is not a free block
+    Corrupted(CorruptData),
+}
+
+impl From<IncompatibleVersion> for ReinitError {
+    #[inline]
+    fn from(err: IncompatibleVersion) -> Self {
+        Self::IncompatibleVersion(err)
+    }
+}
+
+impl From<LayoutMismatch> for ReinitError {
+    #[inline]
+    fn from(err: LayoutMismatch) -> Self {
+        Self::LayoutMismatch(err)
+    }
+}
+
+impl From<CorruptData> for ReinitError {
+    #[inline]
+    fn from(err: CorruptData) -> Self {
+        Self::Corrupted(err)
+    }
+}
+
+// persisted by StableMemoryAllocator::store() and verified on retrieval
+#[derive(Debug, Clone, CandidType, Deserialize, Eq, PartialEq)]
+struct FreeSpaceSummary {
+    free_size_classes: Vec<SizeClassStats>,
+    free_size: u64,
+    allocated_size: u64,
+}
+
+/// Indicates that custom data was stored as a value of one type, but is being retrieved as a value of
+/// another one
+///
+/// See [try_retrieve_custom_data](crate::try_retrieve_custom_data).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct SchemaMismatch {
+    /// Index of the custom data
+    pub idx: usize,
+    /// Fingerprint of the type, the data was stored as
+    pub found: u64,
+    /// Fingerprint of the type, the data is being retrieved as
+    pub expected: u64,
+}
+
+/// Returns a fingerprint of the type, used to verify custom data on retrieval
+///
+/// The fingerprint is a hash of the type's name (with module paths stripped, so moving a type to
+/// another module doesn't change it) and of its size. E.g. `SBTreeMap<u64, SBox<User>>` and
+/// `SBTreeMap<u64, SBox<Account>>` have different fingerprints, but renaming `User` to `Account`
+/// also changes the fingerprint.
+pub fn type_fingerprint<T>() -> u64 {
+    let name = std::any::type_name::<T>();
+    let mut short_name = String::with_capacity(name.len());
+
+    // "a::b::C<d::E>" -> "C<E>"
+    for (i, segment) in name.split("::").enumerate() {
+        if i > 0 {
+            // drop the module name, preceding this segment
+            let module_start = short_name
+                .rfind(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .map_or(0, |it| it + 1);
+
+            short_name.truncate(module_start);
+        }
+
+        short_name.push_str(segment);
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.update(short_name.as_bytes());
+    hasher.update((std::mem::size_of::<T>() as u64).to_le_bytes());
+
+    u64::from_le_bytes(hasher.finalize()[..u64::SIZE].try_into().unwrap())
+}
+
+/// A single memory block, yielded by [HeapWalker]
+#[derive(Debug, Clone, Copy, CandidType, Deserialize, Eq, PartialEq)]
+pub struct HeapBlock {
+    /// Offset of the block in stable memory (the same as [SSlice::as_ptr], for allocated blocks)
+    pub ptr: StablePtr,
+    /// Size of the block (excluding size metadata, but including canaries, if they're enabled)
+    pub size: u64,
+    /// `true` if the block is allocated, `false` if it is free
+    pub allocated: bool,
+}
+
+/// Iterator over every memory block managed by the allocator, in ascending order of their pointers
+///
+/// See [walk_heap](crate::walk_heap).
+pub struct HeapWalker {
+    ptr: StablePtr,
+    max_ptr: StablePtr,
+}
+
+impl Iterator for HeapWalker {
+    type Item = HeapBlock;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.ptr >= self.max_ptr {
+            return None;
+        }
+
+        let block = match unsafe { SSlice::from_ptr(self.ptr) } {
+            Some(slice) => HeapBlock {
+                ptr: self.ptr,
+                size: slice.get_block_size_bytes(),
+                allocated: true,
+            },
+            None => HeapBlock {
+                ptr: self.ptr,
+                size: FreeBlock::from_ptr(self.ptr).unwrap().get_size_bytes(),
+                allocated: false,
+            },
+        };
+
+        self.ptr += FreeBlock::to_total_size(block.size);
+
+        Some(block)
+    }
+}
+
+/// Allocated memory blocks, which can't be reached from any root
+///
+/// See [get_leak_report](crate::get_leak_report).
+#[cfg(feature = "leak_detection")]
+#[derive(Debug, Clone, CandidType, Deserialize, Eq, PartialEq)]
+pub struct LeakReport {
+    /// Unreachable allocated blocks, in ascending order of their pointers
+    pub leaked_blocks: Vec<HeapBlock>,
+    /// Total size of unreachable blocks (excluding metadata)
+    pub leaked_size: u64,
+    /// Number of allocated blocks, which are reachable from roots
+    pub reachable_blocks_count: u64,
+}
+
+/// Limits the amount of work, done by a single [defrag_step](crate::defrag_step) call
+#[derive(Debug, Copy, Clone, CandidType, Deserialize, Eq, PartialEq)]
+pub enum DefragBudget {
+    /// Move at most this many memory blocks
+    Blocks(u64),
+    /// Stop moving memory blocks, once this many instructions are spent by the call
+    ///
+    /// Checked before each move, so a single move of a big block can exceed the budget. Outside of
+    /// a canister, the number of moved bytes is counted instead.
+    Instructions(u64),
+}
+
+/// Result of [defrag_step](crate::defrag_step)
+#[derive(Debug, Default, Copy, Clone, CandidType, Deserialize, Eq, PartialEq)]
+pub struct DefragProgress {
+    /// Number of memory blocks, moved by this call
+    pub moved_blocks: u64,
+    /// Total size of moved memory blocks
+    pub moved_size: u64,
+    /// `true`, if all free memory is in a single block at the end (or there is no free memory)
+    pub done: bool,
+}
+
+/// Shows how fragmented the free stable memory is
+///
+/// See [get_fragmentation_report](crate::get_fragmentation_report).
+#[derive(Debug, Clone, CandidType, Deserialize, PartialEq)]
+pub struct FragmentationReport {
+    /// `1 - largest_free_block / free_size`, in `[0, 1)`
+    ///
+    /// `0` means that all free memory is in a single block. Values close to `1` mean that free memory
+    /// is split into many small blocks, and big allocations will have to grow stable memory, even
+    /// though [free_size](FragmentationReport::free_size) is big enough for them.
+    pub fragmentation: f64,
+    /// See [get_free_size](crate::get_free_size)
+    pub free_size: u64,
+    /// Size of the biggest block, which can be allocated without growing stable memory
+    pub largest_free_block_size: u64,
+    /// Free blocks grouped by size class, in ascending order
+    pub histogram: Vec<SizeClassStats>,
+}
+
+/// Strategy, the allocator uses to pick a free block for a new allocation
+///
+/// See [set_fit_policy](crate::set_fit_policy).
+#[derive(Debug, Default, Copy, Clone, CandidType, Deserialize, Eq, PartialEq)]
+pub enum FitPolicy {
+    /// Picks the smallest free block, which is big enough. `O(logN)`.
+    ///
+    /// Keeps big free blocks intact for big allocations. This is the default.
+    #[default]
+    BestFit,
+    /// Picks the free block with the lowest address, which is big enough. `O(K * logN)`, where `K` is
+    /// the number of different sizes of free blocks.
+    ///
+    /// Keeps allocations close to the beginning of stable memory, so the free space at the end stays
+    /// in one piece.
+    FirstFit,
+    /// Same as [FirstFit](FitPolicy::FirstFit), but continues searching from the address, where the
+    /// previous allocation ended, wrapping around to the beginning. `O(K * logN)`.
+    ///
+    /// Spreads allocations of the same size across the heap instead of piling them up at its beginning.
+    NextFit,
+}
+
+/// Settings of a new stable memory allocator
+///
+/// See [init_allocator_with](crate::init_allocator_with). Everything, except the base offset, is
+/// persisted together with the allocator, so only the base offset should be passed again after an
+/// upgrade (see [reinit_allocator_at](crate::reinit_allocator_at)).
+///
+/// Debug checks (canaries, checksummed headers, leak detection) are not runtime settings - they are
+/// enabled with cargo features of this crate.
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::mem::allocator::{AllocatorBuilder, FitPolicy};
+/// # use ic_stable_memory::{init_allocator_with, PAGE_SIZE_BYTES};
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// init_allocator_with(
+///     AllocatorBuilder::new()
+///         .base_offset(PAGE_SIZE_BYTES)
+///         .max_pages(100)
+///         .min_grow_pages(10)
+///         .fit_policy(FitPolicy::FirstFit),
+/// );
+/// ```
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct AllocatorBuilder {
+    base_offset: u64,
+    max_pages: u64,
+    min_block_size: u64,
+    min_grow_pages: u64,
+    fit_policy: FitPolicy,
+}
+
+impl AllocatorBuilder {
+    /// Creates a builder with default settings, the same as [init_allocator(0)](crate::init_allocator) uses
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the offset in stable memory, the allocator is located at (`0` by default)
+    ///
+    /// Stable memory before this offset is never touched by the allocator and can be used for any
+    /// other purpose. The pointer to the allocator's metadata is stored at `base_offset..base_offset + 8`.
+    ///
+    /// # Panics
+    /// Panics if the offset is not a multiple of [PAGE_SIZE_BYTES].
+    #[inline]
+    pub fn base_offset(mut self, base_offset: u64) -> Self {
+        assert_eq!(
+            base_offset % PAGE_SIZE_BYTES,
+            0,
+            "Base offset should be a multiple of page size"
+        );
+
+        self.base_offset = base_offset;
+        self
+    }
+
+    /// Sets the maximum number of stable memory pages, the allocator can grow to (`0`, no limit, by default)
+    ///
+    /// Pages before the base offset are counted too.
+    #[inline]
+    pub fn max_pages(mut self, max_pages: u64) -> Self {
+        self.max_pages = max_pages;
+        self
+    }
+
+    /// Sets the minimum size of an allocated memory block in bytes (`16` by default)
+    ///
+    /// Bigger minimum blocks waste more memory on small allocations, but lower fragmentation, since
+    /// freed blocks are more likely to be reused.
+    #[inline]
+    pub fn min_block_size(mut self, min_block_size: u64) -> Self {
+        self.min_block_size = min_block_size;
+        self
+    }
+
+    /// Sets the minimum number of pages stable memory grows by at once (`0`, as many as required, by default)
+    ///
+    /// Growing in bigger steps makes it less likely for an allocation to grow stable memory. If
+    /// there are not enough pages left, stable memory is grown by the required number of pages.
+    #[inline]
+    pub fn min_grow_pages(mut self, min_grow_pages: u64) -> Self {
+        self.min_grow_pages = min_grow_pages;
+        self
+    }
+
+    /// Sets the strategy of picking a free block for a new allocation ([FitPolicy::BestFit] by default)
+    #[inline]
+    pub fn fit_policy(mut self, fit_policy: FitPolicy) -> Self {
+        self.fit_policy = fit_policy;
+        self
+    }
+}
 
 #[doc(hidden)]
 #[derive(Debug, CandidType, Deserialize, Eq, PartialEq)]
 pub struct StableMemoryAllocator {
-    free_blocks: BTreeMap<u64, Vec<FreeBlock>>,
+    // size -> free blocks of this size, ordered by their pointers
+    free_blocks: BTreeMap<u64, BTreeSet<FreeBlock>>,
     custom_data_pointers: HashMap<usize, StablePtr>,
+    // custom data stored before fingerprints were introduced is retrieved unchecked
+    #[serde(default)]
+    custom_data_fingerprints: HashMap<usize, u64>,
     free_size: u64,
     available_size: u64,
     max_ptr: StablePtr,
     max_pages: u64,
+    // metadata written before the counter was introduced has it recomputed on retrieval
+    #[serde(default)]
+    allocated_blocks: u64,
+    #[serde(default = "default_min_ptr")]
+    min_ptr: StablePtr,
+    #[serde(default)]
+    fixed_size: bool,
+    #[serde(default)]
+    arenas: Vec<StablePtr>,
+    #[serde(default)]
+    slabs: Slabs,
+    #[serde(default)]
+    fit_policy: FitPolicy,
+    #[serde(default)]
+    next_fit_ptr: StablePtr,
+    // layouts written before versioning was introduced have version 0
+    #[serde(default)]
+    layout_version: u32,
+    #[serde(default)]
+    roots: BTreeMap<String, StablePtr>,
+    #[serde(default)]
+    min_block_size: u64,
+    #[serde(default)]
+    min_grow_pages: u64,
+    // layouts written before summaries were introduced are retrieved unchecked
+    #[serde(default)]
+    free_space_summary: Option<FreeSpaceSummary>,
+    // pointer -> number of owners of a shared memory block, blocks with a single owner are not listed
+    #[serde(default)]
+    ref_counts: BTreeMap<StablePtr, u64>,
+    // metadata written before layout modes were recorded is assumed to match this build
+    #[serde(default)]
+    layout_mode: Option<u8>,
+}
+
+#[cfg(target_family = "wasm")]
+#[inline]
+fn instruction_counter() -> u64 {
+    ic_cdk::api::performance_counter(0)
+}
+
+#[cfg(not(target_family = "wasm"))]
+#[inline]
+fn instruction_counter() -> u64 {
+    0
+}
+
+#[cfg(target_family = "wasm")]
+#[inline]
+fn spent_instructions(start: u64, _progress: &DefragProgress) -> u64 {
+    instruction_counter() - start
+}
+
+// there is no instruction counter outside of a canister
+#[cfg(not(target_family = "wasm"))]
+#[inline]
+fn spent_instructions(_start: u64, progress: &DefragProgress) -> u64 {
+    progress.moved_size
+}
+
+fn default_min_ptr() -> StablePtr {
+    MIN_PTR
 }
 
 impl StableMemoryAllocator {
     pub fn init(max_pages: u64) -> Self {
+        Self::init_with(AllocatorBuilder::new().max_pages(max_pages))
+    }
+
+    /// Initializes a new allocator at [AllocatorBuilder::base_offset]
+    ///
+    /// Grows stable memory to cover the base offset, if it is not grown yet.
+    ///
+    /// # Panics
+    /// Panics if it is impossible to grow stable memory to the base offset.
+    pub fn init_with(builder: AllocatorBuilder) -> Self {
+        let min_ptr = builder.base_offset + MIN_PTR;
+
+        let reserved_pages = builder.base_offset / PAGE_SIZE_BYTES;
+        let grown_pages = stable::size_pages();
+        if grown_pages < reserved_pages {
+            stable::grow(reserved_pages - grown_pages)
+                .expect("Unable to grow stable memory to the base offset");
+        }
+
         let mut it = Self {
-            max_ptr: MIN_PTR,
+            max_ptr: min_ptr,
             free_blocks: BTreeMap::default(),
             custom_data_pointers: HashMap::default(),
+            custom_data_fingerprints: HashMap::default(),
             free_size: 0,
             available_size: 0,
-            max_pages,
+            max_pages: builder.max_pages,
+            allocated_blocks: 0,
+            min_ptr,
+            fixed_size: false,
+            arenas: Vec::new(),
+            slabs: Slabs::default(),
+            fit_policy: builder.fit_policy,
+            next_fit_ptr: min_ptr,
+            layout_version: LAYOUT_VERSION,
+            roots: BTreeMap::default(),
+            min_block_size: builder.min_block_size,
+            min_grow_pages: builder.min_grow_pages,
+            free_space_summary: None,
+            ref_counts: BTreeMap::default(),
+            layout_mode: Some(layout_mode()),
         };
 
         let available_pages = stable::size_pages();
@@ -65,14 +605,88 @@ impl StableMemoryAllocator {
         it
     }
 
+    /// Allocates a memory block of at least `size` bytes and initializes a new allocator (an arena) inside it
+    ///
+    /// The first 8 bytes of the block are the arena's metadata offset (an analog of `0..8` bytes of
+    /// stable memory for this allocator), the rest is managed by the arena. Arenas never grow, instead
+    /// they return [OutOfMemory], when the block is full. Metadata offsets of all arenas are persisted
+    /// together with this allocator, see [StableMemoryAllocator::retrieve_arenas].
+    pub fn create_arena(&mut self, size: u64) -> Result<StableMemoryAllocator, OutOfMemory> {
+        let slice = self.allocate(size.max(MIN_ARENA_SIZE))?;
+        let meta_ptr = slice.offset(0);
+
+        self.arenas.push(meta_ptr);
+
+        Ok(Self::init_arena(
+            meta_ptr,
+            meta_ptr + slice.get_size_bytes(),
+        ))
+    }
+
+    /// Retrieves all arenas, created by [StableMemoryAllocator::create_arena], which were stored
+    /// with [StableMemoryAllocator::store], in order of their creation
+    pub fn retrieve_arenas(&self) -> Vec<StableMemoryAllocator> {
+        (0..self.arenas.len())
+            .map(|idx| self.retrieve_arena(idx))
+            .collect()
+    }
+
+    /// Retrieves a single arena (by its index in order of creation), stored with [StableMemoryAllocator::store]
+    #[inline]
+    pub fn retrieve_arena(&self, idx: usize) -> StableMemoryAllocator {
+        // arenas are always stored together with the main allocator, so they have the same version
+        Self::try_retrieve_at(self.arenas[idx]).unwrap()
+    }
+
+    /// Returns `true` if the pointer is inside the memory range, managed by this allocator
+    #[inline]
+    pub fn contains_ptr(&self, ptr: StablePtr) -> bool {
+        ptr >= self.min_ptr && ptr < self.max_ptr
+    }
+
+    fn init_arena(meta_ptr: StablePtr, end_ptr: StablePtr) -> Self {
+        let min_ptr = meta_ptr + StablePtr::SIZE as u64;
+
+        let mut it = Self {
+            max_ptr: end_ptr,
+            free_blocks: BTreeMap::default(),
+            custom_data_pointers: HashMap::default(),
+            custom_data_fingerprints: HashMap::default(),
+            free_size: 0,
+            available_size: 0,
+            max_pages: 0,
+            allocated_blocks: 0,
+            min_ptr,
+            fixed_size: true,
+            arenas: Vec::new(),
+            slabs: Slabs::default(),
+            fit_policy: FitPolicy::default(),
+            next_fit_ptr: min_ptr,
+            layout_version: LAYOUT_VERSION,
+            roots: BTreeMap::default(),
+            min_block_size: 0,
+            min_grow_pages: 0,
+            free_space_summary: None,
+            ref_counts: BTreeMap::default(),
+            layout_mode: Some(layout_mode()),
+        };
+
+        let free_block = FreeBlock::new_total_size(min_ptr, end_ptr - min_ptr);
+        it.more_free_size(free_block.get_total_size_bytes());
+        it.more_available_size(free_block.get_total_size_bytes());
+        it.push_free_block(free_block);
+
+        it
+    }
+
     pub fn make_sure_can_allocate(&mut self, mut size: u64) -> bool {
-        size = Self::pad_size(size);
+        size = self.block_size(size);
 
         if self.free_blocks.range(size..).next().is_some() {
             return true;
         }
 
-        if self.max_ptr > MIN_PTR {
+        if self.max_ptr > self.min_ptr {
             if let Some(last_free_block) =
                 FreeBlock::from_rear_ptr(self.max_ptr - StablePtr::SIZE as u64)
             {
@@ -93,16 +707,47 @@ impl StableMemoryAllocator {
         }
     }
 
+    /// Same as [StableMemoryAllocator::make_sure_can_allocate], but for a batch of allocations,
+    /// made with [StableMemoryAllocator::allocate_slot]
+    ///
+    /// Free slots of existing slabs are taken into account, while everything else (new slabs and
+    /// objects which are too big for a slot) is reserved as a single free block, so all of them
+    /// can be allocated afterwards without running out of memory, as long as nothing else is
+    /// allocated in between.
+    pub fn make_sure_can_allocate_slots(&mut self, sizes: &[u64]) -> bool {
+        let mut slots = BTreeMap::<u64, u64>::new();
+        let mut size = 0;
+
+        for &it in sizes {
+            match Slabs::slot_size(it) {
+                Some(slot_size) => *slots.entry(slot_size).or_default() += 1,
+                None => size += FreeBlock::to_total_size(self.block_size(it)),
+            }
+        }
+
+        for (slot_size, count) in slots {
+            let missing = count.saturating_sub(self.slabs.free_slots(slot_size));
+            let slabs = ceil_div(missing, Slabs::slots_per_slab(slot_size));
+
+            size += slabs * FreeBlock::to_total_size(self.block_size(Slabs::slab_size(slot_size)));
+        }
+
+        size == 0 || self.make_sure_can_allocate(size)
+    }
+
     #[allow(clippy::never_loop)]
     pub fn allocate(&mut self, mut size: u64) -> Result<SSlice, OutOfMemory> {
-        size = Self::pad_size(size);
+        size = self.block_size(size);
 
         // searching for a free block that is equal or bigger in size, than asked
         let free_block = loop {
             if let Some(fb) = self.pop_free_block(size) {
+                #[cfg(feature = "debug_canaries")]
+                Self::check_poison(&fb);
+
                 break fb;
             } else {
-                if self.max_ptr > MIN_PTR {
+                if self.max_ptr > self.min_ptr {
                     if let Some(last_free_block) =
                         FreeBlock::from_rear_ptr(self.max_ptr - StablePtr::SIZE as u64)
                     {
@@ -139,50 +784,204 @@ impl StableMemoryAllocator {
         };
 
         self.less_free_size(slice.get_total_size_bytes());
+        self.allocated_blocks += 1;
+
+        if self.fit_policy == FitPolicy::NextFit {
+            self.next_fit_ptr = slice.as_ptr() + slice.get_total_size_bytes();
+        }
+
+        #[cfg(feature = "debug_canaries")]
+        Self::write_canaries(&slice);
 
         Ok(slice)
     }
 
     #[inline]
     pub fn deallocate(&mut self, slice: SSlice) {
+        #[cfg(feature = "debug_canaries")]
+        Self::check_canaries(&slice);
+
+        self.allocated_blocks -= 1;
+
+        // a released block has no owners left, even if it was shared (e.g. an orphan)
+        self.ref_counts.remove(&slice.as_ptr());
+
         let free_block = slice.to_free_block();
 
         self.more_free_size(free_block.get_total_size_bytes());
         self.push_free_block(free_block);
     }
 
-    pub fn reallocate(&mut self, slice: SSlice, mut new_size: u64) -> Result<SSlice, OutOfMemory> {
-        new_size = Self::pad_size(new_size);
+    /// Allocates memory for a small fixed-size object and returns a pointer to it
+    ///
+    /// Objects of the same (padded) size are stored in slots of shared memory blocks (slabs), so they
+    /// don't have any per-object metadata. Objects bigger than 2KB are stored in regular memory blocks.
+    /// Either way, the returned pointer can be used with [SSlice::_offset], but *can't* be used with
+    /// [SSlice::from_ptr] and should only be released with [StableMemoryAllocator::deallocate_slot].
+    pub fn allocate_slot(&mut self, size: u64) -> Result<StablePtr, OutOfMemory> {
+        let slot_size = match Slabs::slot_size(size) {
+            Some(s) => s,
+            None => return self.allocate(size).map(|it| it.as_ptr()),
+        };
+
+        let slab_ptr = match self.slabs.find_partial(slot_size) {
+            Some(ptr) => ptr,
+            None => {
+                let slab = self.allocate(Slabs::slab_size(slot_size))?;
+                self.slabs.add(&slab, slot_size);
+
+                slab.as_ptr()
+            }
+        };
+
+        Ok(self.slabs.take_slot(slab_ptr, slot_size))
+    }
+
+    /// Releases memory, allocated with [StableMemoryAllocator::allocate_slot]
+    ///
+    /// Slabs are deallocated, once all their slots are free. Shared memory blocks are only released
+    /// by their last owner.
+    pub fn deallocate_slot(&mut self, ptr: StablePtr) {
+        match self.slabs.find(ptr) {
+            Some((slab_ptr, slot_size)) => {
+                if self.slabs.release_slot(slab_ptr, slot_size, ptr) {
+                    let slab = unsafe { SSlice::from_ptr(slab_ptr).unwrap() };
+                    self.deallocate(slab);
+                }
+            }
+            None => {
+                if self.unshare(ptr).is_some() {
+                    return;
+                }
 
-        if new_size <= slice.get_size_bytes() {
-            return Ok(slice);
+                let slice = unsafe { SSlice::from_ptr(ptr).unwrap() };
+                self.deallocate(slice);
+            }
         }
+    }
 
-        let free_block = slice.to_free_block();
+    /// Same as [StableMemoryAllocator::deallocate], but checks the pointer first
+    ///
+    /// If the memory block is shared, only detaches one owner from it (see [StableMemoryAllocator::release]).
+    pub fn try_deallocate(&mut self, ptr: StablePtr) -> Result<(), AllocError> {
+        self.release(ptr).map(|_| ())
+    }
+
+    /// Attaches one more owner to an allocated memory block, returns the new number of owners
+    ///
+    /// A memory block with multiple owners is only released, once each of them releases it.
+    pub fn retain(&mut self, ptr: StablePtr) -> Result<u64, AllocError> {
+        self.check_ptr(ptr)?;
+
+        let count = self.ref_counts.entry(ptr).or_insert(1);
+        *count += 1;
+
+        Ok(*count)
+    }
+
+    /// Detaches an owner from an allocated memory block, releasing the block, if it was the last one
+    ///
+    /// Returns the number of owners left.
+    pub fn release(&mut self, ptr: StablePtr) -> Result<u64, AllocError> {
+        let slice = self.check_ptr(ptr)?;
 
-        // if it is possible to simply "grow" the slice, by merging it with the next neighbor - do that
-        if let Ok(fb) = self.try_reallocate_in_place(free_block, new_size) {
-            return Ok(fb);
+        if let Some(left) = self.unshare(ptr) {
+            return Ok(left);
         }
 
-        // FIXME: can be more accurate by checking, if can merge with back first
-        if !self.make_sure_can_allocate(new_size) {
-            return Err(OutOfMemory);
+        self.deallocate(slice);
+
+        Ok(0)
+    }
+
+    /// Returns the number of owners of an allocated memory block
+    #[inline]
+    pub fn get_ref_count(&self, ptr: StablePtr) -> u64 {
+        self.ref_counts.get(&ptr).copied().unwrap_or(1)
+    }
+
+    /// Detaches an owner from a shared memory block, returns [None], if the block is not shared
+    pub(crate) fn unshare(&mut self, ptr: StablePtr) -> Option<u64> {
+        let count = self.ref_counts.get_mut(&ptr)?;
+        *count -= 1;
+
+        let left = *count;
+        if left == 1 {
+            self.ref_counts.remove(&ptr);
+        }
+
+        Some(left)
+    }
+
+    /// Checks that the pointer points to the beginning of an allocated memory block
+    ///
+    /// The check is performed in `O(1)` by comparing size metadata on both sides of the block, so
+    /// it can be fooled by data, that looks like a memory block.
+    pub fn check_ptr(&self, ptr: StablePtr) -> Result<SSlice, AllocError> {
+        let meta_size = StablePtr::SIZE as u64;
+
+        if ptr < self.min_ptr || ptr == EMPTY_PTR || ptr + meta_size * 2 > self.max_ptr {
+            return Err(AllocError::InvalidPointer(ptr));
+        }
+
+        let front = unsafe { crate::mem::read_fixed_for_reference::<u64>(ptr) };
+        let (size, allocated) = decode_size_word(front).ok_or(AllocError::InvalidPointer(ptr))?;
+
+        if ptr + meta_size * 2 + size > self.max_ptr {
+            return Err(AllocError::InvalidPointer(ptr));
+        }
+
+        let rear = unsafe { crate::mem::read_fixed_for_reference::<u64>(ptr + meta_size + size) };
+
+        if front != rear {
+            return Err(AllocError::InvalidPointer(ptr));
+        }
+
+        if !allocated {
+            return Err(AllocError::DoubleFree(ptr));
         }
 
-        // othewise, get ready for move and copy the data
-        let mut b = vec![0u8; slice.get_size_bytes().try_into().unwrap()];
-        unsafe { crate::mem::read_bytes(slice.offset(0), &mut b) };
+        Ok(SSlice::new(ptr, size, false))
+    }
+
+    pub fn reallocate(&mut self, slice: SSlice, new_size: u64) -> Result<SSlice, OutOfMemory> {
+        assert!(
+            !self.ref_counts.contains_key(&slice.as_ptr()),
+            "Unable to reallocate a shared memory block {}",
+            slice.as_ptr()
+        );
+
+        #[cfg(feature = "debug_canaries")]
+        Self::check_canaries(&slice);
+
+        let block_size = self.block_size(new_size);
+
+        let resized = if block_size <= slice.get_block_size_bytes() {
+            Ok(self.shrink(slice, block_size))
+        } else {
+            // if it is possible to simply "grow" the slice, by merging it with the next neighbor - do that
+            self.try_reallocate_in_place(slice.to_free_block(), block_size)
+        };
+
+        let free_block = match resized {
+            Ok(slice) => {
+                #[cfg(feature = "debug_canaries")]
+                Self::write_canaries(&slice);
+
+                return Ok(slice);
+            }
+            Err(fb) => fb,
+        };
+
+        // othewise, allocate a new slice and move the data there, without staging it on the heap;
+        // the old slice is only released afterwards, so it can't be overwritten before it's copied
+        let new_slice = self.allocate(new_size)?;
+        unsafe { slice.copy_chunk_to(0, &new_slice, 0, slice.get_size_bytes()) };
 
         // deallocate the slice
         self.more_free_size(free_block.get_total_size_bytes());
         self.push_free_block(free_block);
-
-        // allocate a new one; unwrapping since we've just checked we can allocate that size
-        let new_slice = self.allocate(new_size).unwrap();
-
-        // put the data back
-        unsafe { crate::mem::write_bytes(new_slice.offset(0), &b) };
+        self.allocated_blocks -= 1;
 
         Ok(new_slice)
     }
@@ -191,62 +990,577 @@ impl StableMemoryAllocator {
         // first encode is simply to calculate the required size
         let buf = self.as_dyn_size_bytes();
 
-        // reserving 100 extra bytes in order for the allocator to grow while allocating memory for itself
-        let slice = self.allocate(buf.len() as u64 + 100)?;
+        // reserving extra bytes in order for the allocator to grow while allocating memory for itself
+        // and for the free space summary
+        let summary_size = (self.free_blocks.len() as u64 + 1) * (u64::SIZE * 3) as u64;
+        let slice = self.allocate(buf.len() as u64 + summary_size + 100)?;
 
+        self.free_space_summary = Some(FreeSpaceSummary {
+            free_size_classes: self.free_size_classes(),
+            free_size: self.free_size,
+            allocated_size: self.get_allocated_size(),
+        });
         let buf = self.as_dyn_size_bytes();
+        self.free_space_summary = None;
 
         unsafe { crate::mem::write_bytes(slice.offset(0), &buf) };
-        unsafe { crate::mem::write_fixed(0, &mut slice.as_ptr()) };
+        unsafe {
+            crate::mem::write_fixed(
+                self.min_ptr - StablePtr::SIZE as u64,
+                &mut (slice.as_ptr() | ((layout_mode() as u64) << MODE_SHIFT)),
+            )
+        };
 
         Ok(())
     }
 
     pub fn retrieve() -> Self {
-        let slice_ptr = unsafe { crate::mem::read_fixed_for_reference(0) };
+        Self::try_retrieve().expect("Incompatible or corrupted stable memory layout")
+    }
+
+    /// Same as [StableMemoryAllocator::retrieve], but returns an error instead of panicking, if stable
+    /// memory was written with a layout, which can't be migrated to [LAYOUT_VERSION], or if the
+    /// free-list doesn't match the free space summary, persisted by [StableMemoryAllocator::store]
+    ///
+    /// The summary holds the number and the total size of free blocks of each size class, as well as
+    /// free and allocated sizes. Each free block is also checked to actually be a free block of its
+    /// size. `O(N)`, where `N` is the number of free blocks - memory blocks in use are not visited,
+    /// see [StableMemoryAllocator::check_consistency] for a full check.
+    ///
+    /// Stable memory is left untouched, if an error is returned.
+    pub fn try_retrieve() -> Result<Self, ReinitError> {
+        Self::try_retrieve_at(ALLOCATOR_PTR)
+    }
+
+    /// Same as [StableMemoryAllocator::try_retrieve], but for an allocator, initialized at
+    /// [AllocatorBuilder::base_offset]
+    pub fn try_retrieve_at_offset(base_offset: u64) -> Result<Self, ReinitError> {
+        Self::try_retrieve_at(ALLOCATOR_PTR + base_offset)
+    }
+
+    fn try_retrieve_at(meta_ptr: StablePtr) -> Result<Self, ReinitError> {
+        let (slice_ptr, mode) = Self::read_meta_ptr(meta_ptr);
+        let mismatch = LayoutMismatch {
+            found: mode,
+            expected: layout_mode(),
+        };
+
+        if mode & MODE_RECORDED == MODE_RECORDED && mode != layout_mode() {
+            return Err(mismatch.into());
+        }
+
+        // heaps written before layout modes were recorded are checked by their metadata block's
+        // header instead, which is only readable with the same layout
+        let front = unsafe { crate::mem::read_fixed_for_reference::<u64>(slice_ptr) };
+        match decode_size_word(front) {
+            Some((size, true)) if slice_ptr + size <= stable::size_pages() * PAGE_SIZE_BYTES => {}
+            _ if mode == 0 => return Err(mismatch.into()),
+            _ => {
+                return Err(CorruptData::new(slice_ptr, "Invalid allocator metadata block").into())
+            }
+        }
+
         let slice = unsafe { SSlice::from_ptr(slice_ptr).unwrap() };
 
         let mut buf = vec![0u8; slice.get_size_bytes() as usize];
         unsafe { crate::mem::read_bytes(slice.offset(0), &mut buf) };
 
-        let mut it = Self::from_dyn_size_bytes(&buf);
+        // canaries shift the data of every memory block, so without them (or with them, when there
+        // are none) the metadata is read from a wrong offset and doesn't start with Candid's magic
+        if mode == 0 && !buf.starts_with(b"DIDL") {
+            return Err(mismatch.into());
+        }
+
+        let mut it = Self::import_meta(&buf)?;
+
+        // the summary was taken with the metadata block allocated
+        if let Some(summary) = it.free_space_summary.take() {
+            it.verify_free_space_summary(&summary)?;
+        }
+
+        // metadata written before the counter was introduced has it at 0, while a stored allocator
+        // always has at least its metadata block allocated, so the counter is recomputed once
+        if it.allocated_blocks == 0 {
+            it.allocated_blocks = it.walk().filter(|block| block.allocated).count() as u64;
+        }
+
         it.deallocate(slice);
 
-        it
+        Ok(it)
     }
 
+    /// Encodes allocator's metadata (free-list, counters, custom data pointers, named roots etc.)
+    ///
+    /// Stable memory is not touched, so this can be used to checkpoint the allocator's state or to
+    /// inspect it offline.
     #[inline]
-    pub fn get_allocated_size(&self) -> u64 {
-        self.available_size - self.free_size
+    pub fn export_meta(&self) -> Vec<u8> {
+        self.as_dyn_size_bytes()
     }
 
-    #[inline]
-    pub fn get_available_size(&self) -> u64 {
-        self.available_size
+    // returns the pointer to the metadata block and the layout mode, written by store()
+    fn read_meta_ptr(meta_ptr: StablePtr) -> (StablePtr, u8) {
+        let word = unsafe { crate::mem::read_fixed_for_reference::<u64>(meta_ptr) };
+
+        (word & META_PTR_MASK, (word >> MODE_SHIFT) as u8)
     }
 
-    #[inline]
-    pub fn get_free_size(&self) -> u64 {
-        self.free_size
+    /// Decodes metadata, previously encoded with [StableMemoryAllocator::export_meta], migrating it to
+    /// [LAYOUT_VERSION], if needed
+    ///
+    /// Returns [ReinitError::LayoutMismatch], if the metadata was encoded by a build with another
+    /// [layout mode](layout_mode).
+    pub fn import_meta(buf: &[u8]) -> Result<Self, ReinitError> {
+        let mut it = Self::from_dyn_size_bytes(buf);
+        it.migrate()?;
+
+        match it.layout_mode {
+            Some(mode) if mode != layout_mode() => {
+                return Err(LayoutMismatch {
+                    found: mode,
+                    expected: layout_mode(),
+                }
+                .into())
+            }
+            _ => it.layout_mode = Some(layout_mode()),
+        }
+
+        Ok(it)
     }
 
-    #[inline]
-    fn more_available_size(&mut self, additional: u64) {
-        self.available_size += additional;
+    // each step migrates the layout one version up
+    fn migrate(&mut self) -> Result<(), IncompatibleVersion> {
+        let err = IncompatibleVersion {
+            found: self.layout_version,
+            supported: LAYOUT_VERSION,
+        };
+
+        if self.layout_version > LAYOUT_VERSION {
+            return Err(err);
+        }
+
+        // 0 -> 1: only the version itself is added, pointer-sized integers keep their native width;
+        // with `portable_usize` feature they are 8 bytes on every target, so values of a 32-bit
+        // layout 0 can't be re-encoded in place
+        if self.layout_version == 0 {
+            if cfg!(all(feature = "portable_usize", target_pointer_width = "32")) {
+                return Err(err);
+            }
+
+            self.layout_version = 1;
+        }
+
+        Ok(())
     }
 
     #[inline]
-    fn more_free_size(&mut self, additional: u64) {
-        self.free_size += additional;
+    pub fn get_layout_version(&self) -> u32 {
+        self.layout_version
     }
 
     #[inline]
-    fn less_free_size(&mut self, additional: u64) {
-        self.free_size -= additional;
+    pub fn get_allocated_size(&self) -> u64 {
+        self.available_size - self.free_size
     }
 
     #[inline]
-    pub fn store_custom_data<T: AsDynSizeBytes + StableType>(
+    pub fn get_available_size(&self) -> u64 {
+        self.available_size
+    }
+
+    #[inline]
+    pub fn get_free_size(&self) -> u64 {
+        self.free_size
+    }
+
+    /// Moves all allocated blocks towards the beginning of stable memory, closing gaps between them,
+    /// so all free memory ends up in a single block at the end
+    ///
+    /// Calls `on_relocate(old_ptr, new_ptr)` for each moved block, right after it was moved. Blocks
+    /// are moved in ascending order of their pointers. For moved slabs `on_relocate` is called for each
+    /// occupied slot instead (see [StableMemoryAllocator::allocate_slot]). Pointers to custom data are
+    /// fixed automatically.
+    pub fn compact<F: FnMut(StablePtr, StablePtr)>(&mut self, mut on_relocate: F) {
+        let mut target_ptr = self.min_ptr;
+
+        for block in self.walk() {
+            if !block.allocated {
+                continue;
+            }
+
+            if block.ptr != target_ptr {
+                // target_ptr < block.ptr, so the data is copied front to back in chunks
+                // canaries (if any) are moved together with the data
+                unsafe {
+                    crate::mem::copy_bytes(
+                        block.ptr + StablePtr::SIZE as u64,
+                        target_ptr + StablePtr::SIZE as u64,
+                        block.size,
+                    )
+                };
+
+                SSlice::new(target_ptr, block.size, true);
+
+                self.relocate_block(block.ptr, target_ptr, &mut on_relocate);
+            }
+
+            target_ptr += FreeBlock::to_total_size(block.size);
+        }
+
+        self.free_blocks.clear();
+
+        if target_ptr < self.max_ptr {
+            let mut free_block = FreeBlock::new_total_size(target_ptr, self.max_ptr - target_ptr);
+            free_block.persist();
+
+            #[cfg(feature = "debug_canaries")]
+            Self::poison(&free_block);
+
+            self.free_blocks
+                .insert(free_block.get_size_bytes(), BTreeSet::from([free_block]));
+        }
+    }
+
+    /// Same as [StableMemoryAllocator::compact], but moves a limited number of memory blocks per call
+    ///
+    /// Each move swaps the lowest free block with the allocated block right after it, so free memory
+    /// gradually "floats" to the end of the heap, merging with other free blocks on its way. Allocations
+    /// and deallocations between calls are fine - each call starts from the lowest free block.
+    pub fn defrag_step<F: FnMut(StablePtr, StablePtr)>(
+        &mut self,
+        budget: DefragBudget,
+        mut on_relocate: F,
+    ) -> DefragProgress {
+        let start = instruction_counter();
+        let mut progress = DefragProgress::default();
+
+        loop {
+            let free_block = match self.find_free_block_from(0, self.min_ptr) {
+                Some(it) if it.get_next_neighbor_ptr() < self.max_ptr => it,
+                _ => {
+                    progress.done = true;
+                    break;
+                }
+            };
+
+            let spent = match budget {
+                DefragBudget::Blocks(max_blocks) => progress.moved_blocks >= max_blocks,
+                DefragBudget::Instructions(max_instructions) => {
+                    spent_instructions(start, &progress) >= max_instructions
+                }
+            };
+
+            if spent {
+                break;
+            }
+
+            // free blocks are always merged, so the next neighbor is allocated
+            let block_ptr = free_block.get_next_neighbor_ptr();
+            let size = unsafe { SSlice::from_ptr(block_ptr).unwrap() }.get_block_size_bytes();
+
+            self.remove_free_block(&free_block);
+
+            // canaries (if any) are moved together with the data
+            unsafe {
+                crate::mem::copy_bytes(
+                    block_ptr + StablePtr::SIZE as u64,
+                    free_block.as_ptr() + StablePtr::SIZE as u64,
+                    size,
+                )
+            };
+
+            SSlice::new(free_block.as_ptr(), size, true);
+            self.relocate_block(block_ptr, free_block.as_ptr(), &mut on_relocate);
+
+            // the free block is now right after the moved one, it may merge with the next free block
+            self.push_free_block(FreeBlock::new_total_size(
+                free_block.as_ptr() + FreeBlock::to_total_size(size),
+                free_block.get_total_size_bytes(),
+            ));
+
+            progress.moved_blocks += 1;
+            progress.moved_size += size;
+        }
+
+        progress
+    }
+
+    // fixes pointers to a moved memory block, known to the allocator, and calls `on_relocate`
+    fn relocate_block<F: FnMut(StablePtr, StablePtr)>(
+        &mut self,
+        old_ptr: StablePtr,
+        new_ptr: StablePtr,
+        on_relocate: &mut F,
+    ) {
+        for it in self
+            .custom_data_pointers
+            .values_mut()
+            .chain(self.roots.values_mut())
+        {
+            if *it == old_ptr {
+                *it = new_ptr;
+            }
+        }
+
+        // blocks are only moved into free memory, so nothing is shared at the new pointer yet
+        if let Some(count) = self.ref_counts.remove(&old_ptr) {
+            self.ref_counts.insert(new_ptr, count);
+        }
+
+        // slots of slabs are relocated individually
+        if !self.slabs.relocate(old_ptr, new_ptr, on_relocate) {
+            on_relocate(old_ptr, new_ptr);
+        }
+    }
+
+    /// Returns an iterator over every memory block (both allocated and free), starting from the beginning
+    /// of stable memory
+    #[inline]
+    pub fn walk(&self) -> HeapWalker {
+        HeapWalker {
+            ptr: self.min_ptr,
+            max_ptr: self.max_ptr,
+        }
+    }
+
+    pub fn stats(&self) -> AllocatorStats {
+        let free_size_classes = self.free_size_classes();
+        let free_blocks_count = free_size_classes
+            .iter()
+            .map(|it| it.blocks_count)
+            .sum::<u64>();
+
+        AllocatorStats {
+            pages: stable::size_pages(),
+            available_size: self.get_available_size(),
+            allocated_size: self.get_allocated_size(),
+            free_size: self.get_free_size(),
+            allocated_blocks_count: self.allocated_blocks,
+            free_blocks_count,
+            largest_free_block_size: self.get_largest_free_block_size(),
+            blocks_overhead_size: (self.allocated_blocks + free_blocks_count)
+                * (StablePtr::SIZE * 2) as u64,
+            free_size_classes,
+        }
+    }
+
+    pub fn fragmentation_report(&self) -> FragmentationReport {
+        let largest_free_block_size = self.get_largest_free_block_size();
+
+        // metadata of the largest block can also be used by an allocation
+        let fragmentation = if self.free_size == 0 {
+            0.0
+        } else {
+            1.0 - FreeBlock::to_total_size(largest_free_block_size) as f64 / self.free_size as f64
+        };
+
+        FragmentationReport {
+            fragmentation,
+            free_size: self.free_size,
+            largest_free_block_size,
+            histogram: self.free_size_classes(),
+        }
+    }
+
+    /// Finds allocated blocks, which are not reachable from `roots` and custom data
+    ///
+    /// Reachability is conservative: each 8 bytes of a reachable block (at any offset), that point
+    /// inside another allocated block, make that block reachable. So some leaked blocks may be missed,
+    /// but a reachable block is never reported as leaked.
+    #[cfg(feature = "leak_detection")]
+    pub fn leak_report<I: IntoIterator<Item = StablePtr>>(&self, roots: I) -> LeakReport {
+        let allocated = self
+            .walk()
+            .filter(|it| it.allocated)
+            .map(|it| (it.ptr, it))
+            .collect::<BTreeMap<_, _>>();
+
+        let find_block = |ptr: StablePtr| {
+            allocated
+                .range(..=ptr)
+                .next_back()
+                .map(|(_, block)| *block)
+                .filter(|block| ptr < block.ptr + FreeBlock::to_total_size(block.size))
+        };
+
+        let mut reachable = std::collections::BTreeSet::new();
+        let mut stack = roots
+            .into_iter()
+            .chain(self.custom_data_pointers.values().copied())
+            .chain(self.roots.values().copied())
+            .chain(self.arenas.iter().copied())
+            .filter_map(find_block)
+            .collect::<Vec<_>>();
+
+        while let Some(block) = stack.pop() {
+            if !reachable.insert(block.ptr) {
+                continue;
+            }
+
+            let mut buf = vec![0u8; block.size as usize];
+            unsafe { crate::mem::read_bytes(block.ptr + StablePtr::SIZE as u64, &mut buf) };
+
+            for window in buf.windows(u64::SIZE) {
+                let ptr = StablePtr::from_le_bytes(window.try_into().unwrap());
+
+                if let Some(it) = find_block(ptr) {
+                    if !reachable.contains(&it.ptr) {
+                        stack.push(it);
+                    }
+                }
+            }
+        }
+
+        let leaked_blocks = allocated
+            .into_values()
+            .filter(|it| !reachable.contains(&it.ptr))
+            .collect::<Vec<_>>();
+
+        LeakReport {
+            leaked_size: leaked_blocks.iter().map(|it| it.size).sum(),
+            leaked_blocks,
+            reachable_blocks_count: reachable.len() as u64,
+        }
+    }
+
+    /// Returns allocated blocks and slots, which don't contain any of the `live` pointers
+    ///
+    /// Slabs, arenas, named roots and custom data are never returned. Used by the
+    /// [orphan collector](crate::mem::orphan_collector).
+    #[cfg(feature = "orphan_collector")]
+    pub(crate) fn orphan_candidates(&self, live: &BTreeSet<StablePtr>) -> Vec<StablePtr> {
+        let mut candidates = self
+            .walk()
+            .filter(|it| it.allocated && !self.slabs.is_slab(it.ptr) && !self.is_root(it.ptr))
+            .filter(|it| {
+                live.range(it.ptr..it.ptr + FreeBlock::to_total_size(it.size))
+                    .next()
+                    .is_none()
+            })
+            .map(|it| it.ptr)
+            .collect::<Vec<_>>();
+
+        candidates.extend(
+            self.slabs
+                .occupied_slots()
+                .into_iter()
+                .filter(|it| !live.contains(it)),
+        );
+
+        candidates
+    }
+
+    /// Releases a block or a slot, returned by [StableMemoryAllocator::orphan_candidates] earlier
+    ///
+    /// Does nothing, if the candidate is not allocated anymore, or if it now contains any of the
+    /// `live` pointers. Returns the released size.
+    #[cfg(feature = "orphan_collector")]
+    pub(crate) fn free_orphan(
+        &mut self,
+        ptr: StablePtr,
+        live: &BTreeSet<StablePtr>,
+    ) -> Option<u64> {
+        if let Some((slab_ptr, slot_size)) = self.slabs.find(ptr) {
+            if live.contains(&ptr) || !self.slabs.is_occupied(slab_ptr, slot_size, ptr) {
+                return None;
+            }
+
+            self.deallocate_slot(ptr);
+
+            return Some(slot_size);
+        }
+
+        if self.slabs.is_slab(ptr) || self.is_root(ptr) {
+            return None;
+        }
+
+        let slice = self.check_ptr(ptr).ok()?;
+        if live
+            .range(ptr..ptr + slice.get_total_size_bytes())
+            .next()
+            .is_some()
+        {
+            return None;
+        }
+
+        let size = slice.get_size_bytes();
+        self.deallocate(slice);
+
+        Some(size)
+    }
+
+    /// Returns the size of an allocated block or slot, and the size of memory it occupies
+    ///
+    /// For blocks, the occupied size includes size metadata. Returns [None], if the pointer is not
+    /// a valid pointer to a block or a slot.
+    #[cfg(feature = "orphan_collector")]
+    pub(crate) fn occupied_size(&self, ptr: StablePtr) -> Option<(u64, u64)> {
+        if let Some((_, slot_size)) = self.slabs.find(ptr) {
+            return Some((slot_size, slot_size));
+        }
+
+        let slice = self.check_ptr(ptr).ok()?;
+
+        Some((slice.get_size_bytes(), slice.get_total_size_bytes()))
+    }
+
+    #[cfg(feature = "orphan_collector")]
+    fn is_root(&self, ptr: StablePtr) -> bool {
+        self.arenas.contains(&ptr)
+            || self.roots.values().any(|it| *it == ptr)
+            || self.custom_data_pointers.values().any(|it| *it == ptr)
+    }
+
+    #[inline]
+    pub fn get_largest_free_block_size(&self) -> u64 {
+        self.free_blocks
+            .keys()
+            .next_back()
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn free_size_classes(&self) -> Vec<SizeClassStats> {
+        let mut classes: Vec<SizeClassStats> = Vec::new();
+
+        // free blocks are sorted by size, so size classes are filled one by one
+        for (size, blocks) in &self.free_blocks {
+            let min_size = 1u64 << (u64::BITS - 1 - size.leading_zeros());
+            let count = blocks.len() as u64;
+
+            match classes.last_mut() {
+                Some(class) if class.min_size == min_size => {
+                    class.blocks_count += count;
+                    class.total_size += size * count;
+                }
+                _ => classes.push(SizeClassStats {
+                    min_size,
+                    blocks_count: count,
+                    total_size: size * count,
+                }),
+            }
+        }
+
+        classes
+    }
+
+    #[inline]
+    fn more_available_size(&mut self, additional: u64) {
+        self.available_size += additional;
+    }
+
+    #[inline]
+    fn more_free_size(&mut self, additional: u64) {
+        self.free_size += additional;
+    }
+
+    #[inline]
+    fn less_free_size(&mut self, additional: u64) {
+        self.free_size -= additional;
+    }
+
+    #[inline]
+    pub fn store_custom_data<T: AsDynSizeBytes + StableType>(
         &mut self,
         idx: usize,
         mut data: SBox<T>,
@@ -254,6 +1568,8 @@ impl StableMemoryAllocator {
         unsafe { data.stable_drop_flag_off() };
 
         self.custom_data_pointers.insert(idx, data.as_ptr());
+        self.custom_data_fingerprints
+            .insert(idx, type_fingerprint::<T>());
     }
 
     #[inline]
@@ -261,10 +1577,73 @@ impl StableMemoryAllocator {
         &mut self,
         idx: usize,
     ) -> Option<SBox<T>> {
-        let mut b = unsafe { SBox::from_ptr(self.custom_data_pointers.remove(&idx)?) };
+        match self.try_retrieve_custom_data(idx) {
+            Ok(it) => it,
+            Err(e) => panic!("Custom data type mismatch: {:?}", e),
+        }
+    }
+
+    /// Same as [StableMemoryAllocator::retrieve_custom_data], but returns a [SchemaMismatch] error,
+    /// if the data was stored as a value of another type
+    ///
+    /// The data is left stored, if an error is returned.
+    pub fn try_retrieve_custom_data<T: AsDynSizeBytes + StableType>(
+        &mut self,
+        idx: usize,
+    ) -> Result<Option<SBox<T>>, SchemaMismatch> {
+        let ptr = match self.custom_data_pointers.get(&idx) {
+            Some(ptr) => *ptr,
+            None => return Ok(None),
+        };
+
+        if let Some(found) = self.custom_data_fingerprints.get(&idx).copied() {
+            let expected = type_fingerprint::<T>();
+
+            if found != expected {
+                return Err(SchemaMismatch {
+                    idx,
+                    found,
+                    expected,
+                });
+            }
+        }
+
+        self.custom_data_pointers.remove(&idx);
+        self.custom_data_fingerprints.remove(&idx);
+
+        let mut b = unsafe { SBox::from_ptr(ptr) };
         unsafe { SBox::<T>::stable_drop_flag_on(&mut b) };
 
-        Some(b)
+        Ok(Some(b))
+    }
+
+    /// Assigns a name to a pointer, returns the pointer, previously assigned to this name
+    #[inline]
+    pub fn declare_root(&mut self, id: &str, ptr: StablePtr) -> Option<StablePtr> {
+        self.roots.insert(id.to_string(), ptr)
+    }
+
+    #[inline]
+    pub fn get_root(&self, id: &str) -> Option<StablePtr> {
+        self.roots.get(id).copied()
+    }
+
+    #[inline]
+    pub fn remove_root(&mut self, id: &str) -> Option<StablePtr> {
+        self.roots.remove(id)
+    }
+
+    /// Returns all named roots, in ascending order of their names
+    #[inline]
+    pub fn roots(&self) -> &BTreeMap<String, StablePtr> {
+        &self.roots
+    }
+
+    /// Returns the range of stable memory, managed by this allocator - from its
+    /// [base offset](AllocatorBuilder::base_offset) to the end of its last memory block
+    #[inline]
+    pub fn get_heap_range(&self) -> Range<StablePtr> {
+        (self.min_ptr - StablePtr::SIZE as u64)..self.max_ptr
     }
 
     #[inline]
@@ -272,54 +1651,118 @@ impl StableMemoryAllocator {
         self.max_pages
     }
 
-    fn try_reallocate_in_place(
-        &mut self,
-        mut free_block: FreeBlock,
-        new_size: u64,
-    ) -> Result<SSlice, Result<FreeBlock, OutOfMemory>> {
-        if let Some(mut next_neighbor) = free_block.next_neighbor_is_free(self.max_ptr) {
-            let mut merged_size = FreeBlock::merged_size(&free_block, &next_neighbor);
+    /// Sets the maximum number of stable memory pages, this allocator can grow to (`0` means no limit)
+    ///
+    /// If stable memory has already grown beyond this number, it is clamped to the current number of pages.
+    #[inline]
+    pub fn set_max_pages(&mut self, max_pages: u64) {
+        let available_pages = stable::size_pages();
 
-            if merged_size < new_size {
-                if next_neighbor.get_next_neighbor_ptr() != self.max_ptr {
-                    return Err(Ok(free_block));
-                }
+        self.max_pages = if max_pages != 0 && available_pages > max_pages {
+            available_pages
+        } else {
+            max_pages
+        };
+    }
+
+    #[inline]
+    pub fn get_fit_policy(&self) -> FitPolicy {
+        self.fit_policy
+    }
 
-                let fb = self.grow(new_size).map_err(Err)?;
+    #[inline]
+    pub fn set_fit_policy(&mut self, fit_policy: FitPolicy) {
+        self.fit_policy = fit_policy;
+    }
 
-                self.more_available_size(fb.get_total_size_bytes());
+    /// Grows stable memory by `pages` pages ahead of time, adding them to the free block at the end of the heap
+    ///
+    /// Subsequent allocations are served from this free block and don't have to grow stable memory
+    /// themselves, until it is exhausted.
+    pub fn reserve_pages(&mut self, pages: u64) -> Result<(), OutOfMemory> {
+        if pages == 0 {
+            return Ok(());
+        }
 
-                self.less_free_size(next_neighbor.get_total_size_bytes());
-                self.remove_free_block(&next_neighbor);
+        let fb = self.grow_pages(pages)?;
 
-                next_neighbor = FreeBlock::merge(next_neighbor, fb);
-                merged_size = FreeBlock::merged_size(&free_block, &next_neighbor);
-            } else {
-                self.less_free_size(next_neighbor.get_total_size_bytes());
-                self.remove_free_block(&next_neighbor);
-            }
+        self.more_available_size(fb.get_total_size_bytes());
+        self.more_free_size(fb.get_total_size_bytes());
 
-            free_block = FreeBlock::merge(free_block, next_neighbor);
+        self.push_free_block(fb);
+
+        Ok(())
+    }
+
+    // splits the slice, releasing its tail, if the tail is big enough to become a free block
+    fn shrink(&mut self, slice: SSlice, new_size: u64) -> SSlice {
+        if !FreeBlock::can_split(slice.get_block_size_bytes(), new_size) {
+            return slice;
+        }
+
+        let (a, b) = slice.to_free_block().split(new_size);
+        let slice = a.to_allocated();
+
+        self.more_free_size(b.get_total_size_bytes());
+        self.push_free_block(b);
+
+        slice
+    }
+
+    // extends the slice into its free next neighbor (and into newly grown memory, if the slice
+    // is at the end of the heap), without moving the data
+    fn try_reallocate_in_place(
+        &mut self,
+        free_block: FreeBlock,
+        new_size: u64,
+    ) -> Result<SSlice, FreeBlock> {
+        let next_neighbor = free_block.next_neighbor_is_free(self.max_ptr);
+
+        let (mut merged, reaches_end) = match &next_neighbor {
+            Some(n) => (
+                FreeBlock::merge(free_block, *n),
+                n.get_next_neighbor_ptr() == self.max_ptr,
+            ),
+            None => (
+                free_block,
+                free_block.get_next_neighbor_ptr() == self.max_ptr,
+            ),
+        };
 
-            if !FreeBlock::can_split(merged_size, new_size) {
-                return Ok(free_block.to_allocated());
+        if merged.get_size_bytes() < new_size {
+            if !reaches_end {
+                return Err(free_block);
             }
 
-            let (free_block, b) = free_block.split(new_size);
+            // grow only by the missing amount
+            let fb = self
+                .grow(new_size - merged.get_size_bytes())
+                .map_err(|_| free_block)?;
 
-            let slice = free_block.to_allocated();
+            self.more_available_size(fb.get_total_size_bytes());
+            merged = FreeBlock::merge(merged, fb);
+        }
 
-            self.more_free_size(b.get_total_size_bytes());
-            self.push_free_block(b);
+        if let Some(n) = next_neighbor {
+            self.less_free_size(n.get_total_size_bytes());
+            self.remove_free_block(&n);
+        }
 
-            return Ok(slice);
+        if !FreeBlock::can_split(merged.get_size_bytes(), new_size) {
+            return Ok(merged.to_allocated());
         }
 
-        Err(Ok(free_block))
+        let (a, b) = merged.split(new_size);
+        let slice = a.to_allocated();
+
+        self.more_free_size(b.get_total_size_bytes());
+        self.push_free_block(b);
+
+        Ok(slice)
     }
 
     fn try_merge_with_neighbors(&mut self, mut free_block: FreeBlock) -> FreeBlock {
-        if let Some(prev_neighbor) = free_block.prev_neighbor_is_free() {
+        if let Some(prev_neighbor) = free_block.prev_neighbor_is_free(self.min_ptr) {
             self.remove_free_block(&prev_neighbor);
 
             free_block = FreeBlock::merge(prev_neighbor, free_block);
@@ -339,59 +1782,84 @@ impl StableMemoryAllocator {
 
         free_block.persist();
 
-        let blocks = self
+        #[cfg(feature = "debug_canaries")]
+        Self::poison(&free_block);
+
+        let is_new = self
             .free_blocks
             .entry(free_block.get_size_bytes())
-            .or_default();
-
-        let idx = match blocks.binary_search(&free_block) {
-            Ok(_) => unreachable!("there can't be two blocks of the same ptr"),
-            Err(idx) => idx,
-        };
+            .or_default()
+            .insert(free_block);
 
-        blocks.insert(idx, free_block);
+        debug_assert!(is_new, "there can't be two blocks of the same ptr");
     }
 
     fn pop_free_block(&mut self, size: u64) -> Option<FreeBlock> {
-        let (&actual_size, blocks) = self.free_blocks.range_mut(size..).next()?;
+        let free_block = match self.fit_policy {
+            FitPolicy::BestFit => {
+                let (_, blocks) = self.free_blocks.range(size..).next()?;
 
-        let free_block = unsafe { blocks.pop().unwrap_unchecked() };
+                *blocks.iter().next_back()?
+            }
+            FitPolicy::FirstFit => self.find_free_block_from(size, self.min_ptr)?,
+            FitPolicy::NextFit => self
+                .find_free_block_from(size, self.next_fit_ptr)
+                .or_else(|| self.find_free_block_from(size, self.min_ptr))?,
+        };
 
-        if blocks.is_empty() {
-            self.free_blocks.remove(&actual_size);
-        }
+        self.remove_free_block(&free_block);
 
         Some(free_block)
     }
 
+    fn find_free_block_from(&self, size: u64, from_ptr: StablePtr) -> Option<FreeBlock> {
+        self.free_blocks
+            .range(size..)
+            .filter_map(|(_, blocks)| blocks.range(FreeBlock::new(from_ptr, 0)..).next())
+            .min()
+            .copied()
+    }
+
     fn remove_free_block(&mut self, block: &FreeBlock) {
         let blocks = self.free_blocks.get_mut(&block.get_size_bytes()).unwrap();
 
-        match blocks.binary_search(block) {
-            Ok(idx) => {
-                blocks.remove(idx);
+        if !blocks.remove(block) {
+            unreachable!("Free block not found {:?} {:?}", block, self.free_blocks);
+        }
 
-                if blocks.is_empty() {
-                    self.free_blocks.remove(&block.get_size_bytes());
-                }
-            }
-            Err(_) => unreachable!("Free block not found {:?} {:?}", block, self.free_blocks),
-        };
+        if blocks.is_empty() {
+            self.free_blocks.remove(&block.get_size_bytes());
+        }
     }
 
     fn grow(&mut self, mut size: u64) -> Result<FreeBlock, OutOfMemory> {
         size = FreeBlock::to_total_size(size);
-        let pages_to_grow = ceil_div(size, PAGE_SIZE_BYTES);
-        let available_pages = stable::size_pages();
 
-        if self.max_pages != 0 && available_pages + pages_to_grow > self.max_pages {
-            return Err(OutOfMemory);
-        }
+        self.grow_pages(ceil_div(size, PAGE_SIZE_BYTES))
+    }
 
-        if stable::grow(pages_to_grow).is_err() {
+    fn grow_pages(&mut self, pages_to_grow: u64) -> Result<FreeBlock, OutOfMemory> {
+        if self.fixed_size {
             return Err(OutOfMemory);
         }
 
+        let available_pages = stable::size_pages();
+        let fits = |pages: u64| self.max_pages == 0 || available_pages + pages <= self.max_pages;
+
+        // growing by at least `min_grow_pages`, if the quota allows it
+        let pages_to_grow = if pages_to_grow < self.min_grow_pages
+            && fits(self.min_grow_pages)
+            && stable::grow(self.min_grow_pages).is_ok()
+        {
+            self.min_grow_pages
+        } else {
+            if !fits(pages_to_grow) || stable::grow(pages_to_grow).is_err() {
+                return Err(OutOfMemory);
+            }
+
+            pages_to_grow
+        };
+
         let new_max_ptr = (available_pages + pages_to_grow) * PAGE_SIZE_BYTES;
         let it = FreeBlock::new_total_size(self.max_ptr, new_max_ptr - self.max_ptr);
 
@@ -400,46 +1868,281 @@ impl StableMemoryAllocator {
         Ok(it)
     }
 
-    pub fn debug_validate_free_blocks(&self) {
-        assert!(
-            self.available_size == 0
-                || self.available_size == stable::size_pages() * PAGE_SIZE_BYTES - MIN_PTR
-        );
+    /// Verifies the free-list and size counters against stable memory, returning the first found
+    /// inconsistency
+    ///
+    /// Checks, that:
+    /// 1. Each free-list entry is filed under its own size and both its size words say, that it is a
+    /// free block of that size.
+    /// 2. Memory blocks of the heap lay back to back, from the first one to the last one, and both
+    /// size words of each block match.
+    /// 3. Each free block of the heap is in the free-list and each free-list entry is a block of the
+    /// heap.
+    /// 4. Free size, allocated size and the number of allocated blocks match the heap.
+    ///
+    /// The free-list is a map of sizes to sets of free blocks, so there are no `prev`/`next` pointers,
+    /// which could go out of sync. `O(N)`, where `N` is the number of memory blocks.
+    pub fn check_consistency(&self) -> Result<(), CorruptData> {
+        let mut listed = self.check_free_list()?;
+
+        let mut free_size = 0u64;
+        let mut allocated_size = 0u64;
+        let mut allocated_blocks = 0u64;
+        let mut ptr = self.min_ptr;
+
+        while ptr < self.max_ptr {
+            let (size, allocated) = Self::read_size_word(ptr)?;
+            let total_size = FreeBlock::to_total_size(size);
+
+            if ptr + total_size > self.max_ptr {
+                return Err(CorruptData::new(ptr, "Memory block is out of the heap"));
+            }
 
-        let mut total_free_size = 0u64;
-        for blocks in self.free_blocks.values() {
-            for free_block in blocks {
-                free_block.debug_validate();
+            if Self::read_size_word(ptr + total_size - StablePtr::SIZE as u64)? != (size, allocated)
+            {
+                return Err(CorruptData::new(
+                    ptr,
+                    "Size words of the memory block don't match",
+                ));
+            }
 
-                total_free_size += free_block.get_total_size_bytes();
+            if allocated {
+                allocated_size += total_size;
+                allocated_blocks += 1;
+            } else {
+                if !listed.remove(&ptr) {
+                    return Err(CorruptData::new(
+                        ptr,
+                        "Free block is missing from the free-list",
+                    ));
+                }
+
+                free_size += total_size;
             }
+
+            ptr += total_size;
         }
 
-        assert_eq!(total_free_size, self.free_size);
-    }
+        if let Some(ptr) = listed.into_iter().next() {
+            return Err(CorruptData::new(
+                ptr,
+                "Free-list entry is not a block of the heap",
+            ));
+        }
 
-    pub fn _free_blocks_count(&self) -> usize {
-        let mut count = 0;
+        for (&ptr, &count) in &self.ref_counts {
+            if self.check_ptr(ptr).is_err() {
+                return Err(CorruptData::new(
+                    ptr,
+                    "Shared memory block is not allocated",
+                ));
+            }
 
-        for blocks in self.free_blocks.values() {
-            for _ in blocks {
-                count += 1;
+            if count < 2 {
+                return Err(CorruptData::new(
+                    ptr,
+                    "Shared memory block has less than 2 owners",
+                ));
             }
         }
 
-        count
-    }
+        if free_size != self.free_size {
+            return Err(CorruptData::new(
+                self.min_ptr,
+                "Free size doesn't match the heap",
+            ));
+        }
 
-    // minimum size is 16 bytes (32 bytes total size)
-    // otherwise size is ceiled to the nearest multiple of 8
-    #[inline]
-    fn pad_size(size: u64) -> u64 {
+        if allocated_size != self.get_allocated_size() {
+            return Err(CorruptData::new(
+                self.min_ptr,
+                "Allocated size doesn't match the heap",
+            ));
+        }
+
+        if allocated_blocks != self.allocated_blocks {
+            return Err(CorruptData::new(
+                self.min_ptr,
+                "Number of allocated blocks doesn't match the heap",
+            ));
+        }
+
+        Ok(())
+    }
+
+    // checks, that each free-list entry is a free block of its size, returns pointers of all entries
+    fn check_free_list(&self) -> Result<BTreeSet<StablePtr>, CorruptData> {
+        let mut listed = BTreeSet::new();
+
+        for (size, blocks) in &self.free_blocks {
+            for block in blocks {
+                if block.get_size_bytes() != *size {
+                    return Err(CorruptData::new(
+                        block.as_ptr(),
+                        "Free block is filed under a wrong size",
+                    ));
+                }
+
+                if block.as_ptr() < self.min_ptr
+                    || block.as_ptr() + block.get_total_size_bytes() > self.max_ptr
+                {
+                    return Err(CorruptData::new(
+                        block.as_ptr(),
+                        "Free block is out of the heap",
+                    ));
+                }
+
+                if Self::read_size_word(block.as_ptr())? != (*size, false)
+                    || Self::read_size_word(block.as_rear_ptr())? != (*size, false)
+                {
+                    return Err(CorruptData::new(
+                        block.as_ptr(),
+                        "Free-list entry is not a free block of its size",
+                    ));
+                }
+
+                listed.insert(block.as_ptr());
+            }
+        }
+
+        Ok(listed)
+    }
+
+    fn verify_free_space_summary(&self, summary: &FreeSpaceSummary) -> Result<(), CorruptData> {
+        if self.free_size_classes() != summary.free_size_classes {
+            return Err(CorruptData::new(
+                self.min_ptr,
+                "Free-list doesn't match the persisted free space summary",
+            ));
+        }
+
+        if self.free_size != summary.free_size
+            || self.get_allocated_size() != summary.allocated_size
+        {
+            return Err(CorruptData::new(
+                self.min_ptr,
+                "Free or allocated size doesn't match the persisted free space summary",
+            ));
+        }
+
+        let listed_free_size = self
+            .free_blocks
+            .iter()
+            .map(|(size, blocks)| FreeBlock::to_total_size(*size) * blocks.len() as u64)
+            .sum::<u64>();
+
+        if listed_free_size != self.free_size {
+            return Err(CorruptData::new(
+                self.min_ptr,
+                "Free size doesn't match the free-list",
+            ));
+        }
+
+        self.check_free_list().map(|_| ())
+    }
+
+    fn read_size_word(ptr: StablePtr) -> Result<(u64, bool), CorruptData> {
+        let mut buf = StablePtrBuf::new(StablePtr::SIZE);
+        stable::read(ptr, &mut buf);
+
+        decode_size_word(u64::from_le_bytes(buf))
+            .ok_or_else(|| CorruptData::new(ptr, "Corrupted memory block header"))
+    }
+
+    pub fn debug_validate_free_blocks(&self) {
+        assert!(
+            self.fixed_size
+                || self.available_size == 0
+                || self.available_size == stable::size_pages() * PAGE_SIZE_BYTES - self.min_ptr
+        );
+
+        let mut total_free_size = 0u64;
+        for blocks in self.free_blocks.values() {
+            for free_block in blocks {
+                free_block.debug_validate();
+
+                total_free_size += free_block.get_total_size_bytes();
+            }
+        }
+
+        assert_eq!(total_free_size, self.free_size);
+    }
+
+    pub fn _free_blocks_count(&self) -> usize {
+        let mut count = 0;
+
+        for blocks in self.free_blocks.values() {
+            for _ in blocks {
+                count += 1;
+            }
+        }
+
+        count
+    }
+
+    // minimum size is 16 bytes (32 bytes total size)
+    // otherwise size is ceiled to the nearest multiple of 8
+    #[inline]
+    fn pad_size(size: u64) -> u64 {
         if size < (StablePtr::SIZE * 2) as u64 {
             return (StablePtr::SIZE * 2) as u64;
         }
 
         (size + 7) & !7
     }
+
+    // the size of a memory block (between its size words), required to fit `size` bytes of data
+    #[inline]
+    fn block_size(&self, size: u64) -> u64 {
+        Self::pad_size(size.max(self.min_block_size)) + CANARY_SIZE * 2
+    }
+
+    #[cfg(feature = "debug_canaries")]
+    fn write_canaries(slice: &SSlice) {
+        let canary = [CANARY; CANARY_SIZE as usize];
+
+        unsafe {
+            crate::mem::write_bytes(slice.as_ptr() + StablePtr::SIZE as u64, &canary);
+            crate::mem::write_bytes(slice.offset(slice.get_size_bytes()), &canary);
+        }
+    }
+
+    #[cfg(feature = "debug_canaries")]
+    fn check_canaries(slice: &SSlice) {
+        let mut front = [0u8; CANARY_SIZE as usize];
+        let mut rear = [0u8; CANARY_SIZE as usize];
+
+        unsafe {
+            crate::mem::read_bytes(slice.as_ptr() + StablePtr::SIZE as u64, &mut front);
+            crate::mem::read_bytes(slice.offset(slice.get_size_bytes()), &mut rear);
+        }
+
+        if front.iter().chain(rear.iter()).any(|it| *it != CANARY) {
+            panic!(
+                "Out-of-bounds write detected: canaries of the memory block {} are corrupted",
+                slice.as_ptr()
+            );
+        }
+    }
+
+    #[cfg(feature = "debug_canaries")]
+    fn poison(free_block: &FreeBlock) {
+        let buf = vec![POISON; free_block.get_size_bytes() as usize];
+        unsafe { crate::mem::write_bytes(free_block.as_ptr() + StablePtr::SIZE as u64, &buf) };
+    }
+
+    #[cfg(feature = "debug_canaries")]
+    fn check_poison(free_block: &FreeBlock) {
+        let mut buf = vec![0u8; free_block.get_size_bytes() as usize];
+        unsafe { crate::mem::read_bytes(free_block.as_ptr() + StablePtr::SIZE as u64, &mut buf) };
+
+        if buf.iter().any(|it| *it != POISON) {
+            panic!(
+                "Use-after-free detected: the free memory block {} was modified",
+                free_block.as_ptr()
+            );
+        }
+    }
 }
 
 impl AsDynSizeBytes for StableMemoryAllocator {
@@ -456,14 +2159,62 @@ impl AsDynSizeBytes for StableMemoryAllocator {
 
 #[cfg(test)]
 mod tests {
-    use crate::encoding::AsDynSizeBytes;
-    use crate::mem::allocator::StableMemoryAllocator;
+    use crate::encoding::{AsDynSizeBytes, AsFixedSizeBytes, CorruptData};
+    use crate::mem::allocator::{
+        layout_mode, AllocError, AllocatorBuilder, DefragBudget, FitPolicy, IncompatibleVersion,
+        LayoutMismatch, ReinitError, StableMemoryAllocator, ALLOCATOR_PTR, EMPTY_PTR,
+        LAYOUT_VERSION, MIN_PTR, MODE_CHECKSUMMED_HEADERS, MODE_DEBUG_CANARIES, MODE_SHIFT,
+    };
+    use crate::mem::free_block::FreeBlock;
+    use crate::mem::slab::Slabs;
+    use crate::mem::StablePtr;
     use crate::primitive::s_box::SBox;
     use crate::utils::mem_context::stable;
-    use crate::SSlice;
+    use crate::{SSlice, PAGE_SIZE_BYTES};
+    use candid::{encode_one, CandidType};
     use rand::rngs::ThreadRng;
     use rand::seq::SliceRandom;
     use rand::{thread_rng, Rng};
+    use std::collections::{BTreeMap, HashMap};
+
+    // allocator's metadata, as it was encoded by the first version of the crate, before any of the
+    // `#[serde(default)]` fields were introduced
+    #[derive(CandidType)]
+    struct BaselineAllocator {
+        free_blocks: BTreeMap<u64, Vec<FreeBlock>>,
+        custom_data_pointers: HashMap<usize, StablePtr>,
+        free_size: u64,
+        available_size: u64,
+        max_ptr: StablePtr,
+        max_pages: u64,
+    }
+
+    impl BaselineAllocator {
+        fn new(sma: &StableMemoryAllocator) -> Self {
+            Self {
+                free_blocks: sma
+                    .free_blocks
+                    .iter()
+                    .map(|(size, blocks)| (*size, blocks.iter().copied().collect()))
+                    .collect(),
+                custom_data_pointers: sma.custom_data_pointers.clone(),
+                free_size: sma.free_size,
+                available_size: sma.available_size,
+                max_ptr: sma.max_ptr,
+                max_pages: sma.max_pages,
+            }
+        }
+    }
+
+    // stores the allocator exactly the way the first version of the crate did
+    fn store_as_baseline(sma: &mut StableMemoryAllocator) {
+        let buf = encode_one(BaselineAllocator::new(sma)).unwrap();
+        let slice = sma.allocate(buf.len() as u64 + 100).unwrap();
+        let buf = encode_one(BaselineAllocator::new(sma)).unwrap();
+
+        unsafe { crate::mem::write_bytes(slice.offset(0), &buf) };
+        unsafe { crate::mem::write_fixed(ALLOCATOR_PTR, &mut slice.as_ptr()) };
+    }
 
     #[test]
     fn encoding_works_fine() {
@@ -724,6 +2475,1279 @@ mod tests {
         sma.debug_validate_free_blocks();
     }
 
+    #[test]
+    fn stats_work_fine() {
+        stable::clear();
+
+        let mut sma = StableMemoryAllocator::init(0);
+
+        let a = sma.allocate(100).unwrap();
+        let b = sma.allocate(1000).unwrap();
+        let c = sma.allocate(100).unwrap();
+        sma.deallocate(b);
+
+        let stats = sma.stats();
+
+        assert_eq!(stats.pages, 1);
+        assert_eq!(stats.allocated_blocks_count, 2);
+        assert_eq!(stats.free_blocks_count, 2);
+        assert_eq!(stats.allocated_size, sma.get_allocated_size());
+        assert_eq!(stats.free_size, sma.get_free_size());
+        assert_eq!(stats.blocks_overhead_size, 4 * 16);
+        assert!(stats.largest_free_block_size > 60_000);
+
+        assert_eq!(stats.free_size_classes.len(), 2);
+        assert_eq!(stats.free_size_classes[0].min_size, 512);
+        assert_eq!(stats.free_size_classes[0].total_size, 1000);
+        assert_eq!(
+            stats.free_size_classes[1].total_size,
+            stats.largest_free_block_size
+        );
+
+        sma.deallocate(a);
+        sma.deallocate(c);
+
+        let stats = sma.stats();
+        assert_eq!(stats.allocated_blocks_count, 0);
+        assert_eq!(stats.free_blocks_count, 1);
+        assert_eq!(stats.free_size_classes[0].blocks_count, 1);
+    }
+
+    #[test]
+    fn fragmentation_report_works_fine() {
+        stable::clear();
+
+        let mut sma = StableMemoryAllocator::init(0);
+
+        let report = sma.fragmentation_report();
+        assert_eq!(report.fragmentation, 0.0);
+        assert!(report.histogram.is_empty());
+
+        let slices = (0..100)
+            .map(|_| sma.allocate(100).unwrap())
+            .collect::<Vec<_>>();
+
+        let report = sma.fragmentation_report();
+        assert_eq!(report.fragmentation, 0.0);
+        assert_eq!(report.histogram.len(), 1);
+
+        // every second block is free, but none of them can be merged
+        for slice in slices.iter().step_by(2) {
+            sma.deallocate(*slice);
+        }
+
+        let report = sma.fragmentation_report();
+        assert!(report.fragmentation > 0.0 && report.fragmentation < 1.0);
+        assert_eq!(report.histogram[0].min_size, 64);
+        assert_eq!(report.histogram[0].blocks_count, 50);
+        assert_eq!(report.free_size, sma.get_free_size());
+
+        for slice in slices.iter().skip(1).step_by(2) {
+            sma.deallocate(*slice);
+        }
+
+        let report = sma.fragmentation_report();
+        assert_eq!(report.fragmentation, 0.0);
+        assert_eq!(report.histogram.len(), 1);
+    }
+
+    #[test]
+    fn heap_walk_works_fine() {
+        stable::clear();
+
+        let mut sma = StableMemoryAllocator::init(0);
+        assert_eq!(sma.walk().count(), 0);
+
+        let slices = (0..10)
+            .map(|i| sma.allocate(100 + i * 10).unwrap())
+            .collect::<Vec<_>>();
+
+        for slice in slices.iter().skip(1).step_by(3) {
+            sma.deallocate(*slice);
+        }
+
+        let blocks = sma.walk().collect::<Vec<_>>();
+
+        assert_eq!(
+            blocks.iter().filter(|it| it.allocated).count() as u64,
+            sma.stats().allocated_blocks_count
+        );
+        assert_eq!(
+            blocks.iter().filter(|it| !it.allocated).count() as u64,
+            sma.stats().free_blocks_count
+        );
+        assert_eq!(
+            blocks
+                .iter()
+                .filter(|it| !it.allocated)
+                .map(|it| it.size)
+                .sum::<u64>(),
+            sma.get_free_size()
+        );
+
+        let last = blocks.last().unwrap();
+        assert_eq!(last.ptr + FreeBlock::to_total_size(last.size), sma.max_ptr);
+
+        for (idx, slice) in slices.iter().enumerate() {
+            let block = blocks.iter().find(|it| it.ptr == slice.as_ptr()).unwrap();
+
+            assert_eq!(block.allocated, idx % 3 != 1);
+            assert_eq!(block.size, slice.get_block_size_bytes());
+        }
+    }
+
+    #[test]
+    fn consistency_check_works_fine() {
+        stable::clear();
+
+        let mut sma = StableMemoryAllocator::init(0);
+        assert!(sma.check_consistency().is_ok());
+
+        let slices = (0..10)
+            .map(|i| sma.allocate(100 + i * 10).unwrap())
+            .collect::<Vec<_>>();
+
+        for slice in slices.iter().skip(1).step_by(3) {
+            sma.deallocate(*slice);
+        }
+
+        let slice = unsafe { sma.reallocate(slices[9], 1000).unwrap() };
+        assert!(sma.check_consistency().is_ok());
+
+        // counters are out of sync
+        sma.allocated_blocks += 1;
+        let e = sma.check_consistency().unwrap_err();
+        assert_eq!(
+            e.reason,
+            "Number of allocated blocks doesn't match the heap"
+        );
+        sma.allocated_blocks -= 1;
+
+        // a free block is marked as allocated
+        let free_ptr = slices[1].as_ptr();
+        let size = slices[1].get_block_size_bytes();
+        crate::mem::s_slice::write_size_words(free_ptr, size, true);
+
+        let e = sma.check_consistency().unwrap_err();
+        assert_eq!(e.offset, free_ptr);
+
+        crate::mem::s_slice::write_size_words(free_ptr, size, false);
+        assert!(sma.check_consistency().is_ok());
+
+        // size words don't match
+        let rear_ptr = slice.as_ptr() + slice.get_total_size_bytes() - 8;
+        let mut buf = [0u8; 8];
+        unsafe { crate::mem::read_bytes(rear_ptr, &mut buf) };
+        unsafe { crate::mem::write_bytes(rear_ptr, &[0u8; 8]) };
+
+        assert!(sma.check_consistency().is_err());
+
+        unsafe { crate::mem::write_bytes(rear_ptr, &buf) };
+        assert!(sma.check_consistency().is_ok());
+    }
+
+    #[cfg(feature = "leak_detection")]
+    #[test]
+    fn leak_report_works_fine() {
+        stable::clear();
+
+        let mut sma = StableMemoryAllocator::init(0);
+
+        let root = sma.allocate(8).unwrap();
+        let a = sma.allocate(16).unwrap();
+        let b = sma.allocate(100).unwrap();
+        let leaked_a = sma.allocate(8).unwrap();
+        let leaked_b = sma.allocate(30).unwrap();
+        let custom_ptr = sma.allocate(8).unwrap().as_ptr();
+
+        sma.custom_data_pointers.insert(1, custom_ptr);
+
+        // root -> a (by block pointer), a -> b (by an unaligned interior pointer)
+        unsafe {
+            crate::mem::write_bytes(root.offset(0), &a.as_ptr().to_le_bytes());
+            crate::mem::write_bytes(a.offset(3), &b.offset(50).to_le_bytes());
+            crate::mem::write_bytes(leaked_a.offset(0), &leaked_b.as_ptr().to_le_bytes());
+        }
+
+        let report = sma.leak_report([root.as_ptr()]);
+
+        assert_eq!(report.reachable_blocks_count, 4);
+        assert_eq!(
+            report
+                .leaked_blocks
+                .iter()
+                .map(|it| it.ptr)
+                .collect::<Vec<_>>(),
+            vec![leaked_a.as_ptr(), leaked_b.as_ptr()]
+        );
+        assert_eq!(
+            report.leaked_size,
+            leaked_a.get_block_size_bytes() + leaked_b.get_block_size_bytes()
+        );
+        assert!(report.leaked_blocks.iter().all(|it| it.allocated));
+
+        let report = sma.leak_report([]);
+        assert_eq!(report.reachable_blocks_count, 1);
+        assert!(!report.leaked_blocks.iter().any(|it| it.ptr == custom_ptr));
+    }
+
+    #[cfg(feature = "debug_canaries")]
+    #[test]
+    fn canaries_work_fine() {
+        stable::clear();
+
+        let mut sma = StableMemoryAllocator::init(0);
+
+        let a = sma.allocate(100).unwrap();
+        unsafe { crate::mem::write_bytes(a.offset(0), &[1u8; 104]) };
+
+        let a = sma.reallocate(a, 1000).unwrap();
+        unsafe { crate::mem::write_bytes(a.offset(0), &[1u8; 1000]) };
+
+        let a = sma.reallocate(a, 10).unwrap();
+        unsafe { crate::mem::write_bytes(a.offset(0), &[1u8; 16]) };
+
+        sma.deallocate(a);
+
+        let b = sma.allocate(1000).unwrap();
+        sma.deallocate(b);
+
+        sma.debug_validate_free_blocks();
+        assert_eq!(sma.get_allocated_size(), 0);
+    }
+
+    #[cfg(feature = "debug_canaries")]
+    #[test]
+    #[should_panic(expected = "Out-of-bounds write detected")]
+    fn canaries_detect_out_of_bounds_writes() {
+        stable::clear();
+
+        let mut sma = StableMemoryAllocator::init(0);
+
+        let a = sma.allocate(100).unwrap();
+        unsafe { crate::mem::write_bytes(a.offset(100), &[1u8; 5]) };
+
+        sma.deallocate(a);
+    }
+
+    #[cfg(feature = "debug_canaries")]
+    #[test]
+    #[should_panic(expected = "Use-after-free detected")]
+    fn poisoning_detects_use_after_free() {
+        stable::clear();
+
+        let mut sma = StableMemoryAllocator::init(0);
+
+        let a = sma.allocate(100).unwrap();
+        let _b = sma.allocate(100).unwrap();
+
+        sma.deallocate(a);
+        unsafe { crate::mem::write_bytes(a.offset(0), &[1u8; 10]) };
+
+        sma.allocate(100).unwrap();
+    }
+
+    #[test]
+    fn arenas_work_fine() {
+        stable::clear();
+
+        let mut sma = StableMemoryAllocator::init(0);
+        let mut arena = sma.create_arena(1000).unwrap();
+        let a = sma.allocate(100).unwrap();
+
+        let mut slices = Vec::new();
+        while let Ok(slice) = arena.allocate(100) {
+            assert!(arena.contains_ptr(slice.as_ptr()));
+            slices.push(slice);
+        }
+
+        assert!(slices.len() > 1);
+        assert!(!arena.contains_ptr(a.as_ptr()));
+        assert!(!arena.make_sure_can_allocate(100));
+        assert_eq!(arena.walk().filter(|it| it.allocated).count(), slices.len());
+
+        sma.debug_validate_free_blocks();
+        arena.debug_validate_free_blocks();
+
+        for slice in slices {
+            arena.deallocate(slice);
+        }
+
+        assert_eq!(arena.get_allocated_size(), 0);
+        assert_eq!(arena._free_blocks_count(), 1);
+
+        let b = arena.allocate(10).unwrap();
+
+        arena.store().unwrap();
+        sma.store().unwrap();
+
+        let sma = StableMemoryAllocator::retrieve();
+        let mut arenas = sma.retrieve_arenas();
+
+        assert_eq!(arenas.len(), 1);
+        assert_eq!(arenas[0].get_allocated_size(), b.get_total_size_bytes());
+
+        arenas[0].deallocate(b);
+        assert_eq!(arenas[0].get_allocated_size(), 0);
+        arenas[0].debug_validate_free_blocks();
+    }
+
+    #[test]
+    fn slots_work_fine() {
+        stable::clear();
+
+        let mut sma = StableMemoryAllocator::init(0);
+
+        let mut ptrs = Vec::new();
+        for i in 0..300u64 {
+            let size = [8, 100, 200, 5000][i as usize % 4];
+            let ptr = sma.allocate_slot(size).unwrap();
+
+            unsafe {
+                crate::mem::write_bytes(SSlice::_offset(ptr, 0), &vec![i as u8; size as usize])
+            };
+            ptrs.push((ptr, size, i as u8));
+        }
+
+        // slots don't overlap
+        for (ptr, size, i) in &ptrs {
+            let mut buf = vec![0u8; *size as usize];
+            unsafe { crate::mem::read_bytes(SSlice::_offset(*ptr, 0), &mut buf) };
+
+            assert_eq!(buf, vec![*i; *size as usize]);
+        }
+
+        // slabs are fewer than objects
+        assert!(sma.stats().allocated_blocks_count < 100 + 75);
+
+        ptrs.shuffle(&mut thread_rng());
+        let (first, rest) = ptrs.split_at(150);
+
+        for (ptr, _, _) in first {
+            sma.deallocate_slot(*ptr);
+        }
+
+        let mut relocations = Vec::new();
+        sma.compact(|old, new| relocations.push((old, new)));
+
+        for (ptr, size, i) in rest {
+            let ptr = relocations
+                .iter()
+                .find(|(old, _)| old == ptr)
+                .map(|(_, new)| *new)
+                .unwrap_or(*ptr);
+
+            let mut buf = vec![0u8; *size as usize];
+            unsafe { crate::mem::read_bytes(SSlice::_offset(ptr, 0), &mut buf) };
+            assert_eq!(buf, vec![*i; *size as usize]);
+
+            sma.deallocate_slot(ptr);
+        }
+
+        assert_eq!(sma.get_allocated_size(), 0);
+        assert_eq!(sma.slabs, Slabs::default());
+        sma.debug_validate_free_blocks();
+    }
+
+    #[test]
+    fn make_sure_can_allocate_slots_works_fine() {
+        stable::clear();
+
+        let mut sma = StableMemoryAllocator::init(0);
+        sma.set_max_pages(1);
+
+        let mut ptrs = Vec::new();
+        while let Ok(ptr) = sma.allocate_slot(100) {
+            ptrs.push(ptr);
+        }
+
+        assert!(sma.make_sure_can_allocate_slots(&[]));
+        assert!(!sma.make_sure_can_allocate_slots(&[100]));
+
+        // both slots belong to the first slab, which stays non-empty
+        sma.deallocate_slot(ptrs.remove(0));
+        sma.deallocate_slot(ptrs.remove(0));
+
+        assert!(sma.make_sure_can_allocate_slots(&[100, 100]));
+        assert!(!sma.make_sure_can_allocate_slots(&[100, 100, 100]));
+
+        ptrs.push(sma.allocate_slot(100).unwrap());
+        ptrs.push(sma.allocate_slot(100).unwrap());
+
+        sma.set_max_pages(0);
+        assert!(sma.make_sure_can_allocate_slots(&[100; 200]));
+
+        for _ in 0..200 {
+            ptrs.push(sma.allocate_slot(100).unwrap());
+        }
+
+        for ptr in ptrs {
+            sma.deallocate_slot(ptr);
+        }
+
+        assert_eq!(sma.get_allocated_size(), 0);
+        sma.debug_validate_free_blocks();
+    }
+
+    #[test]
+    fn max_pages_works_fine() {
+        stable::clear();
+
+        let mut sma = StableMemoryAllocator::init(0);
+        sma.set_max_pages(2);
+        assert_eq!(sma.get_max_pages(), 2);
+
+        let a = sma.allocate(PAGE_SIZE_BYTES).unwrap();
+        assert!(sma.allocate(PAGE_SIZE_BYTES).is_err());
+        assert!(!sma.make_sure_can_allocate(PAGE_SIZE_BYTES));
+        assert_eq!(stable::size_pages(), 2);
+
+        sma.set_max_pages(1);
+        assert_eq!(sma.get_max_pages(), 2);
+
+        sma.set_max_pages(0);
+        let b = sma.allocate(PAGE_SIZE_BYTES).unwrap();
+
+        sma.deallocate(a);
+        sma.deallocate(b);
+        assert_eq!(sma.get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn builder_works_fine() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        stable::write(0, &[1, 2, 3]);
+
+        let mut sma = StableMemoryAllocator::init_with(
+            AllocatorBuilder::new()
+                .base_offset(PAGE_SIZE_BYTES)
+                .max_pages(7)
+                .min_block_size(128)
+                .min_grow_pages(4)
+                .fit_policy(FitPolicy::FirstFit),
+        );
+        assert_eq!(sma.get_fit_policy(), FitPolicy::FirstFit);
+        assert_eq!(sma.get_max_pages(), 7);
+
+        // the first page is left untouched
+        let a = sma.allocate(10).unwrap();
+        assert_eq!(a.as_ptr(), PAGE_SIZE_BYTES + MIN_PTR);
+        assert_eq!(a.get_size_bytes(), 128);
+        assert_eq!(stable::size_pages(), 5);
+
+        // not enough pages left for the whole step
+        let b = sma.allocate(PAGE_SIZE_BYTES * 4).unwrap();
+        assert_eq!(stable::size_pages(), 6);
+        assert!(sma.allocate(PAGE_SIZE_BYTES * 5).is_err());
+
+        sma.debug_validate_free_blocks();
+        sma.store().unwrap();
+
+        let mut sma = StableMemoryAllocator::try_retrieve_at_offset(PAGE_SIZE_BYTES).unwrap();
+        assert_eq!(sma.get_fit_policy(), FitPolicy::FirstFit);
+
+        sma.deallocate(a);
+        sma.deallocate(b);
+        sma.debug_validate_free_blocks();
+
+        let mut buf = [0u8; 3];
+        stable::read(0, &mut buf);
+        assert_eq!(buf, [1, 2, 3]);
+    }
+
+    #[test]
+    fn fit_policies_work_fine() {
+        for policy in [FitPolicy::BestFit, FitPolicy::FirstFit, FitPolicy::NextFit] {
+            stable::clear();
+
+            let mut sma = StableMemoryAllocator::init(0);
+
+            // three free blocks of different sizes, separated by allocated ones
+            let a = sma.allocate(200).unwrap();
+            let s1 = sma.allocate(8).unwrap();
+            let b = sma.allocate(100).unwrap();
+            let s2 = sma.allocate(8).unwrap();
+            let c = sma.allocate(300).unwrap();
+            let s3 = sma.allocate(8).unwrap();
+
+            sma.deallocate(a);
+            sma.deallocate(b);
+            sma.deallocate(c);
+
+            sma.set_fit_policy(policy);
+            assert_eq!(sma.get_fit_policy(), policy);
+
+            let x = sma.allocate(100).unwrap();
+            let y = sma.allocate(100).unwrap();
+            let z = sma.allocate(100).unwrap();
+
+            sma.deallocate(x);
+            let w = sma.allocate(100).unwrap();
+
+            match policy {
+                FitPolicy::BestFit => {
+                    assert_eq!(x.as_ptr(), b.as_ptr());
+                    assert_eq!(y.as_ptr(), a.as_ptr());
+                    assert_eq!(z.as_ptr(), c.as_ptr());
+                    assert_eq!(w.as_ptr(), b.as_ptr());
+                }
+                FitPolicy::FirstFit => {
+                    assert_eq!(x.as_ptr(), a.as_ptr());
+                    assert_eq!(y.as_ptr(), b.as_ptr());
+                    assert_eq!(z.as_ptr(), c.as_ptr());
+                    assert_eq!(w.as_ptr(), a.as_ptr());
+                }
+                FitPolicy::NextFit => {
+                    assert_eq!(x.as_ptr(), a.as_ptr());
+                    assert_eq!(y.as_ptr(), b.as_ptr());
+                    assert_eq!(z.as_ptr(), c.as_ptr());
+                    assert_eq!(w.as_ptr(), z.as_ptr() + z.get_total_size_bytes());
+                }
+            }
+
+            for slice in [y, z, w, s1, s2, s3] {
+                sma.deallocate(slice);
+            }
+
+            sma.debug_validate_free_blocks();
+            assert_eq!(sma.get_allocated_size(), 0);
+        }
+    }
+
+    #[test]
+    fn many_free_blocks_of_same_size_work_fine() {
+        stable::clear();
+
+        let mut sma = StableMemoryAllocator::init(0);
+
+        let slices = (0..1000)
+            .map(|_| sma.allocate(100).unwrap())
+            .collect::<Vec<_>>();
+
+        let (mut even, mut odd): (Vec<_>, Vec<_>) = slices
+            .into_iter()
+            .enumerate()
+            .partition(|(i, _)| i % 2 == 0);
+
+        // none of them have free neighbors, so they all stay in the same size class
+        even.shuffle(&mut thread_rng());
+        for (_, slice) in even.drain(..) {
+            sma.deallocate(slice);
+        }
+        assert_eq!(sma._free_blocks_count(), 501);
+
+        sma.store().unwrap();
+        let mut sma = StableMemoryAllocator::retrieve();
+        assert_eq!(sma._free_blocks_count(), 501);
+        sma.debug_validate_free_blocks();
+
+        odd.shuffle(&mut thread_rng());
+        for (_, slice) in odd.drain(..) {
+            sma.deallocate(slice);
+        }
+
+        assert_eq!(sma._free_blocks_count(), 1);
+        sma.debug_validate_free_blocks();
+        assert_eq!(sma.get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn reserve_pages_works_fine() {
+        stable::clear();
+
+        let mut sma = StableMemoryAllocator::init(3);
+
+        let a = sma.allocate(100).unwrap();
+        assert_eq!(stable::size_pages(), 1);
+
+        sma.reserve_pages(2).unwrap();
+        assert_eq!(stable::size_pages(), 3);
+        assert_eq!(sma._free_blocks_count(), 1);
+        assert_eq!(sma.get_largest_free_block_size(), sma.get_free_size() - 16);
+        sma.debug_validate_free_blocks();
+
+        assert!(sma.reserve_pages(1).is_err());
+        assert_eq!(stable::size_pages(), 3);
+
+        let b = sma.allocate(PAGE_SIZE_BYTES * 2).unwrap();
+        assert_eq!(stable::size_pages(), 3);
+
+        sma.deallocate(a);
+        sma.deallocate(b);
+
+        sma.debug_validate_free_blocks();
+        assert_eq!(sma.get_allocated_size(), 0);
+    }
+
+    // poisoning would write the whole 4GB
+    #[cfg(not(feature = "debug_canaries"))]
+    #[test]
+    fn above_4gb_works_fine() {
+        stable::clear();
+
+        let mut sma = StableMemoryAllocator::init(0);
+
+        let big = sma.allocate(u32::MAX as u64).unwrap();
+        let small = sma.allocate(100).unwrap();
+
+        assert!(small.as_ptr() > u32::MAX as u64);
+        assert!(stable::size_pages() > (u32::MAX as u64 + 1) / PAGE_SIZE_BYTES);
+
+        unsafe { crate::mem::write_fixed(small.offset(0), &mut 10u64) };
+        unsafe { crate::mem::write_fixed(big.offset(u32::MAX as u64 - 8), &mut 20u64) };
+
+        let small = unsafe { SSlice::from_ptr(small.as_ptr()).unwrap() };
+        assert_eq!(small.get_size_bytes(), 100);
+        assert_eq!(
+            unsafe { crate::mem::read_fixed_for_reference::<u64>(small.offset(0)) },
+            10
+        );
+        assert_eq!(
+            unsafe { crate::mem::read_fixed_for_reference::<u64>(big.offset(u32::MAX as u64 - 8)) },
+            20
+        );
+
+        sma.store().unwrap();
+        let mut sma = StableMemoryAllocator::retrieve();
+
+        sma.deallocate(big);
+        sma.deallocate(small);
+
+        sma.debug_validate_free_blocks();
+        assert_eq!(sma.get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn layout_versions_work_fine() {
+        stable::clear();
+
+        let mut sma = StableMemoryAllocator::init(0);
+        assert_eq!(sma.get_layout_version(), LAYOUT_VERSION);
+
+        // written by a newer version of the crate
+        sma.layout_version = LAYOUT_VERSION + 1;
+        sma.store().unwrap();
+
+        let err = IncompatibleVersion {
+            found: LAYOUT_VERSION + 1,
+            supported: LAYOUT_VERSION,
+        };
+        assert_eq!(StableMemoryAllocator::try_retrieve(), Err(err.into()));
+
+        // stable memory is not modified by a failed attempt
+        assert_eq!(StableMemoryAllocator::try_retrieve(), Err(err.into()));
+
+        stable::clear();
+
+        let mut sma = StableMemoryAllocator::init(0);
+        let a = sma.allocate(100).unwrap();
+
+        // written before versioning was introduced
+        sma.layout_version = 0;
+        sma.store().unwrap();
+
+        let mut sma = StableMemoryAllocator::try_retrieve().unwrap();
+        assert_eq!(sma.get_layout_version(), 1);
+
+        sma.deallocate(a);
+        sma.debug_validate_free_blocks();
+        assert_eq!(sma.get_allocated_size(), 0);
+    }
+
+    // the first version of the crate had no layout-changing features
+    #[cfg(not(any(feature = "debug_canaries", feature = "checksummed_headers")))]
+    #[test]
+    fn baseline_metadata_is_retrieved_with_defaults() {
+        stable::clear();
+
+        let mut sma = StableMemoryAllocator::init(0);
+        let a = sma.allocate(100).unwrap();
+        let b = sma.allocate(200).unwrap();
+        let c = sma.allocate(300).unwrap();
+        sma.deallocate(b);
+        sma.custom_data_pointers.insert(1, c.as_ptr());
+
+        store_as_baseline(&mut sma);
+
+        let mut sma = StableMemoryAllocator::try_retrieve().unwrap();
+
+        // the counter is recomputed, instead of underflowing on the first deallocation
+        assert_eq!(sma.allocated_blocks, 2);
+        assert_eq!(sma.stats().allocated_blocks_count, 2);
+
+        assert!(sma.custom_data_fingerprints.is_empty());
+        assert_eq!(sma.min_ptr, MIN_PTR);
+        assert!(!sma.fixed_size);
+        assert!(sma.arenas.is_empty());
+        assert_eq!(sma.slabs, Slabs::default());
+        assert_eq!(sma.fit_policy, FitPolicy::default());
+        assert_eq!(sma.next_fit_ptr, 0);
+        assert_eq!(sma.layout_version, LAYOUT_VERSION);
+        assert!(sma.roots.is_empty());
+        assert_eq!(sma.min_block_size, 0);
+        assert_eq!(sma.min_grow_pages, 0);
+        assert_eq!(sma.free_space_summary, None);
+        assert!(sma.ref_counts.is_empty());
+        assert_eq!(sma.layout_mode, Some(layout_mode()));
+        assert_eq!(sma.custom_data_pointers.get(&1), Some(&c.as_ptr()));
+
+        // the next fit search starts from the beginning of the heap
+        sma.set_fit_policy(FitPolicy::NextFit);
+        let d = sma.allocate(100).unwrap();
+        assert_eq!(d.as_ptr(), b.as_ptr());
+
+        sma.deallocate(a);
+        sma.deallocate(c);
+        sma.deallocate(d);
+        sma.debug_validate_free_blocks();
+        assert_eq!(sma.get_allocated_size(), 0);
+        assert_eq!(sma.stats().allocated_blocks_count, 0);
+    }
+
+    #[cfg(not(any(feature = "debug_canaries", feature = "checksummed_headers")))]
+    #[test]
+    fn baseline_layout_is_migrated() {
+        stable::clear();
+
+        let mut sma = StableMemoryAllocator::init(0);
+        let a = sma.allocate(usize::SIZE as u64).unwrap();
+        unsafe { crate::mem::write_fixed(a.offset(0), &mut 12345usize) };
+
+        store_as_baseline(&mut sma);
+
+        let mut sma = StableMemoryAllocator::try_retrieve().unwrap();
+        assert_eq!(sma.get_layout_version(), LAYOUT_VERSION);
+
+        // values, written with layout 0, are read as is
+        let it = unsafe { crate::mem::read_fixed_for_reference::<usize>(a.offset(0)) };
+        assert_eq!(it, 12345);
+
+        // the migrated layout is persisted on the next store
+        sma.store().unwrap();
+        let mut sma = StableMemoryAllocator::try_retrieve().unwrap();
+        assert_eq!(sma.get_layout_version(), LAYOUT_VERSION);
+        assert_eq!(
+            StableMemoryAllocator::read_meta_ptr(ALLOCATOR_PTR).1,
+            layout_mode()
+        );
+
+        sma.deallocate(a);
+        sma.debug_validate_free_blocks();
+        assert_eq!(sma.get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn layout_modes_work_fine() {
+        stable::clear();
+
+        let mut sma = StableMemoryAllocator::init(0);
+        let a = sma.allocate(100).unwrap();
+        sma.store().unwrap();
+
+        let (slice_ptr, mode) = StableMemoryAllocator::read_meta_ptr(ALLOCATOR_PTR);
+        assert_eq!(mode, layout_mode());
+
+        // written with `checksummed_headers` or `debug_canaries` feature toggled
+        for flag in [MODE_CHECKSUMMED_HEADERS, MODE_DEBUG_CANARIES] {
+            let other_mode = mode ^ flag;
+            let mut word = slice_ptr | ((other_mode as u64) << MODE_SHIFT);
+            unsafe { crate::mem::write_fixed(ALLOCATOR_PTR, &mut word) };
+
+            let err = LayoutMismatch {
+                found: other_mode,
+                expected: layout_mode(),
+            };
+            assert_eq!(StableMemoryAllocator::try_retrieve(), Err(err.into()));
+        }
+
+        // written before layout modes were recorded
+        let mut word = slice_ptr;
+        unsafe { crate::mem::write_fixed(ALLOCATOR_PTR, &mut word) };
+
+        // the metadata is read from a wrong offset, e.g. because of canaries
+        let slice = unsafe { SSlice::from_ptr(slice_ptr).unwrap() };
+        let mut magic = [0u8; 4];
+        unsafe { crate::mem::read_bytes(slice.offset(0), &mut magic) };
+        unsafe { crate::mem::write_bytes(slice.offset(0), &[0xCA; 4]) };
+
+        let legacy_err = LayoutMismatch {
+            found: 0,
+            expected: layout_mode(),
+        };
+        assert_eq!(
+            StableMemoryAllocator::try_retrieve(),
+            Err(legacy_err.into())
+        );
+
+        unsafe { crate::mem::write_bytes(slice.offset(0), &magic) };
+
+        let mut sma = StableMemoryAllocator::try_retrieve().unwrap();
+
+        // the metadata itself is checked too
+        let other_mode = layout_mode() ^ MODE_DEBUG_CANARIES;
+        let err = LayoutMismatch {
+            found: other_mode,
+            expected: layout_mode(),
+        };
+        sma.layout_mode = Some(other_mode);
+        assert_eq!(
+            StableMemoryAllocator::import_meta(&sma.export_meta()),
+            Err(err.into())
+        );
+
+        sma.layout_mode = None;
+        let copy = StableMemoryAllocator::import_meta(&sma.export_meta()).unwrap();
+        assert_eq!(copy.layout_mode, Some(layout_mode()));
+
+        sma.layout_mode = Some(layout_mode());
+        sma.deallocate(a);
+        sma.debug_validate_free_blocks();
+        assert_eq!(sma.get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn free_space_summary_works_fine() {
+        stable::clear();
+
+        let mut sma = StableMemoryAllocator::init(0);
+        for (i, slice) in (0..20)
+            .map(|i| sma.allocate(100 + i * 10).unwrap())
+            .collect::<Vec<_>>()
+            .into_iter()
+            .enumerate()
+        {
+            if i % 2 == 0 {
+                sma.deallocate(slice);
+            }
+        }
+
+        let allocated_size = sma.get_allocated_size();
+        sma.store().unwrap();
+
+        let mut sma = StableMemoryAllocator::try_retrieve().unwrap();
+        assert!(sma.free_space_summary.is_none());
+        assert_eq!(sma.get_allocated_size(), allocated_size);
+        sma.check_consistency().unwrap();
+
+        // a free-list entry, which is not a free block
+        sma.store().unwrap();
+        let block = sma.free_blocks.values().flatten().next().copied().unwrap();
+
+        let mut size_word = [0u8; u64::SIZE];
+        stable::read(block.as_ptr(), &mut size_word);
+        stable::write(block.as_ptr(), &[0u8; u64::SIZE]);
+
+        let err = StableMemoryAllocator::try_retrieve().unwrap_err();
+        assert!(matches!(err, ReinitError::Corrupted(it) if it.offset == block.as_ptr()));
+
+        stable::write(block.as_ptr(), &size_word);
+        let mut sma = StableMemoryAllocator::try_retrieve().unwrap();
+
+        // a free-list, which doesn't match the summary
+        sma.store().unwrap();
+
+        let (slice_ptr, _) = StableMemoryAllocator::read_meta_ptr(ALLOCATOR_PTR);
+        let slice = unsafe { SSlice::from_ptr(slice_ptr).unwrap() };
+        let mut buf = vec![0u8; slice.get_size_bytes() as usize];
+        unsafe { crate::mem::read_bytes(slice.offset(0), &mut buf) };
+
+        let mut meta = StableMemoryAllocator::import_meta(&buf).unwrap();
+        assert!(meta.free_space_summary.is_some());
+
+        let size = *meta.free_blocks.keys().next().unwrap();
+        meta.free_blocks.remove(&size);
+        unsafe { crate::mem::write_bytes(slice.offset(0), &meta.export_meta()) };
+
+        let err = StableMemoryAllocator::try_retrieve().unwrap_err();
+        assert_eq!(
+            err,
+            ReinitError::Corrupted(CorruptData::new(
+                sma.min_ptr,
+                "Free-list doesn't match the persisted free space summary"
+            ))
+        );
+    }
+
+    #[test]
+    fn roots_work_fine() {
+        stable::clear();
+
+        let mut sma = StableMemoryAllocator::init(0);
+
+        let a = sma.allocate(100).unwrap();
+        let b = sma.allocate(100).unwrap();
+
+        assert_eq!(sma.declare_root("a", a.as_ptr()), None);
+        assert_eq!(sma.declare_root("b", a.as_ptr()), None);
+        assert_eq!(sma.declare_root("b", b.as_ptr()), Some(a.as_ptr()));
+
+        sma.store().unwrap();
+        let mut sma = StableMemoryAllocator::retrieve();
+
+        assert_eq!(sma.get_root("a"), Some(a.as_ptr()));
+        assert_eq!(sma.get_root("b"), Some(b.as_ptr()));
+        assert_eq!(sma.get_root("c"), None);
+
+        // roots follow their blocks during compaction
+        sma.deallocate(a);
+        sma.compact(|_, _| {});
+
+        let b = unsafe { SSlice::from_ptr(sma.get_root("b").unwrap()).unwrap() };
+        assert_eq!(b.as_ptr(), MIN_PTR);
+
+        assert_eq!(sma.remove_root("a"), Some(a.as_ptr()));
+        assert_eq!(sma.remove_root("a"), None);
+        assert_eq!(sma.remove_root("b"), Some(b.as_ptr()));
+
+        sma.deallocate(b);
+        sma.debug_validate_free_blocks();
+        assert_eq!(sma.get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn meta_export_import_works_fine() {
+        stable::clear();
+
+        let mut sma = StableMemoryAllocator::init(0);
+
+        let a = sma.allocate(100).unwrap();
+        let b = sma.allocate(200).unwrap();
+        sma.deallocate(a);
+        sma.declare_root("b", b.as_ptr());
+
+        let meta = sma.export_meta();
+        let mut sma = StableMemoryAllocator::import_meta(&meta).unwrap();
+        assert_eq!(sma.export_meta(), meta);
+        sma.debug_validate_free_blocks();
+
+        // older metadata is migrated
+        sma.layout_version = 0;
+        let old_meta = sma.export_meta();
+        sma.layout_version = LAYOUT_VERSION;
+
+        let copy = StableMemoryAllocator::import_meta(&old_meta).unwrap();
+        assert_eq!(copy, sma);
+
+        sma.layout_version = LAYOUT_VERSION + 1;
+        assert!(StableMemoryAllocator::import_meta(&sma.export_meta()).is_err());
+        sma.layout_version = LAYOUT_VERSION;
+
+        assert_eq!(sma.get_root("b"), Some(b.as_ptr()));
+        sma.deallocate(b);
+        assert_eq!(sma.get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn try_deallocate_works_fine() {
+        stable::clear();
+
+        let mut sma = StableMemoryAllocator::init(0);
+
+        let a = sma.allocate(100).unwrap();
+        let b = sma.allocate(100).unwrap();
+
+        assert_eq!(
+            sma.check_ptr(a.as_ptr()).unwrap().get_size_bytes(),
+            a.get_size_bytes()
+        );
+        assert_eq!(
+            sma.try_deallocate(a.offset(8)),
+            Err(AllocError::InvalidPointer(a.offset(8)))
+        );
+        assert_eq!(sma.try_deallocate(0), Err(AllocError::InvalidPointer(0)));
+        assert_eq!(
+            sma.try_deallocate(EMPTY_PTR),
+            Err(AllocError::InvalidPointer(EMPTY_PTR))
+        );
+        assert_eq!(
+            sma.try_deallocate(sma.max_ptr),
+            Err(AllocError::InvalidPointer(sma.max_ptr))
+        );
+
+        assert_eq!(sma.try_deallocate(a.as_ptr()), Ok(()));
+        assert_eq!(
+            sma.try_deallocate(a.as_ptr()),
+            Err(AllocError::DoubleFree(a.as_ptr()))
+        );
+
+        assert_eq!(sma.try_deallocate(b.as_ptr()), Ok(()));
+        assert_eq!(sma.get_allocated_size(), 0);
+        sma.debug_validate_free_blocks();
+    }
+
+    #[test]
+    fn ref_counts_work_fine() {
+        stable::clear();
+
+        let mut sma = StableMemoryAllocator::init(0);
+
+        let a = sma.allocate(100).unwrap();
+        let b = sma.allocate(100).unwrap();
+
+        assert_eq!(sma.get_ref_count(b.as_ptr()), 1);
+        assert_eq!(sma.retain(b.as_ptr()), Ok(2));
+        assert_eq!(sma.retain(b.as_ptr()), Ok(3));
+        assert_eq!(
+            sma.retain(b.offset(8)),
+            Err(AllocError::InvalidPointer(b.offset(8)))
+        );
+        assert!(sma.check_consistency().is_ok());
+
+        // the shared block is moved and its reference count follows it
+        sma.deallocate(a);
+        sma.compact(|_, _| {});
+
+        let b = unsafe { SSlice::from_ptr(a.as_ptr()).unwrap() };
+        assert_eq!(sma.get_ref_count(b.as_ptr()), 3);
+
+        sma.store().unwrap();
+        let mut sma = StableMemoryAllocator::retrieve();
+        assert_eq!(sma.get_ref_count(b.as_ptr()), 3);
+        assert!(sma.check_consistency().is_ok());
+
+        assert_eq!(sma.release(b.as_ptr()), Ok(2));
+        assert_eq!(sma.try_deallocate(b.as_ptr()), Ok(()));
+        assert_eq!(sma.get_ref_count(b.as_ptr()), 1);
+        assert_eq!(sma.get_allocated_size(), b.get_total_size_bytes());
+
+        assert_eq!(sma.release(b.as_ptr()), Ok(0));
+        assert_eq!(
+            sma.release(b.as_ptr()),
+            Err(AllocError::DoubleFree(b.as_ptr()))
+        );
+        assert_eq!(sma.get_allocated_size(), 0);
+        sma.debug_validate_free_blocks();
+    }
+
+    #[test]
+    #[should_panic(expected = "Unable to reallocate a shared memory block")]
+    fn shared_blocks_are_not_reallocated() {
+        stable::clear();
+
+        let mut sma = StableMemoryAllocator::init(0);
+
+        let a = sma.allocate(100).unwrap();
+        sma.retain(a.as_ptr()).unwrap();
+
+        let _ = sma.reallocate(a, 200);
+    }
+
+    #[test]
+    fn compaction_works_fine() {
+        stable::clear();
+
+        let mut sma = StableMemoryAllocator::init(0);
+
+        let slices = (0..100u8)
+            .map(|i| {
+                let slice = sma.allocate(100 + i as u64).unwrap();
+                unsafe { crate::mem::write_bytes(slice.offset(0), &[i; 100]) };
+
+                slice
+            })
+            .collect::<Vec<_>>();
+
+        for slice in slices.iter().step_by(2) {
+            sma.deallocate(*slice);
+        }
+
+        let allocated_size = sma.get_allocated_size();
+        let free_size = sma.get_free_size();
+        let mut relocations = Vec::new();
+
+        sma.compact(|old, new| relocations.push((old, new)));
+
+        assert_eq!(relocations.len(), 50);
+        assert_eq!(sma._free_blocks_count(), 1);
+        assert_eq!(sma.get_allocated_size(), allocated_size);
+        assert_eq!(sma.get_free_size(), free_size);
+        sma.debug_validate_free_blocks();
+
+        for (idx, (old, new)) in relocations.into_iter().enumerate() {
+            let i = idx as u8 * 2 + 1;
+            assert_eq!(old, slices[i as usize].as_ptr());
+            assert!(new < old);
+
+            let slice = unsafe { SSlice::from_ptr(new).unwrap() };
+            let mut buf = [0u8; 100];
+            unsafe { crate::mem::read_bytes(slice.offset(0), &mut buf) };
+
+            assert_eq!(buf, [i; 100]);
+
+            sma.deallocate(slice);
+        }
+
+        assert_eq!(sma.get_allocated_size(), 0);
+        sma.debug_validate_free_blocks();
+    }
+
+    #[test]
+    fn defrag_step_works_fine() {
+        stable::clear();
+
+        let mut sma = StableMemoryAllocator::init(0);
+
+        let slices = (0..100u8)
+            .map(|i| {
+                let slice = sma.allocate(100 + i as u64).unwrap();
+                unsafe { crate::mem::write_bytes(slice.offset(0), &[i; 100]) };
+
+                slice
+            })
+            .collect::<Vec<_>>();
+
+        sma.declare_root("last", slices[99].as_ptr());
+
+        for slice in slices.iter().step_by(2) {
+            sma.deallocate(*slice);
+        }
+
+        let allocated_size = sma.get_allocated_size();
+        let free_size = sma.get_free_size();
+        let mut ptrs = slices
+            .iter()
+            .skip(1)
+            .step_by(2)
+            .map(|it| it.as_ptr())
+            .collect::<Vec<_>>();
+
+        let mut steps = 0;
+        loop {
+            let progress = sma.defrag_step(DefragBudget::Blocks(7), |old, new| {
+                let idx = ptrs.iter().position(|it| *it == old).unwrap();
+                ptrs[idx] = new;
+            });
+
+            assert_eq!(sma.get_allocated_size(), allocated_size);
+            assert_eq!(sma.get_free_size(), free_size);
+            sma.debug_validate_free_blocks();
+            assert!(sma.check_consistency().is_ok());
+
+            if progress.done {
+                break;
+            }
+
+            assert!(progress.moved_blocks <= 7);
+            steps += 1;
+
+            // stable memory can be used between steps
+            let slice = sma.allocate(10).unwrap();
+            sma.deallocate(slice);
+        }
+
+        assert!(steps >= 7);
+        assert_eq!(sma._free_blocks_count(), 1);
+        assert_eq!(sma.get_root("last"), Some(ptrs[49]));
+        assert!(
+            sma.defrag_step(DefragBudget::Instructions(0), |_, _| {})
+                .done
+        );
+
+        for (idx, ptr) in ptrs.into_iter().enumerate() {
+            let i = idx as u8 * 2 + 1;
+
+            let slice = unsafe { SSlice::from_ptr(ptr).unwrap() };
+            let mut buf = [0u8; 100];
+            unsafe { crate::mem::read_bytes(slice.offset(0), &mut buf) };
+
+            assert_eq!(buf, [i; 100]);
+
+            sma.deallocate(slice);
+        }
+
+        assert_eq!(sma.get_allocated_size(), 0);
+        sma.debug_validate_free_blocks();
+    }
+
+    #[test]
+    fn reallocation_in_place_works_fine() {
+        stable::clear();
+
+        let mut sma = StableMemoryAllocator::init(0);
+
+        unsafe {
+            let a = sma.allocate(100).unwrap();
+            let b = sma.allocate(1000).unwrap();
+            let c = sma.allocate(100).unwrap();
+
+            crate::mem::write_bytes(a.offset(0), &[1u8; 100]);
+
+            // the next neighbor is free
+            sma.deallocate(b);
+            let a1 = sma.reallocate(a, 500).unwrap();
+            assert_eq!(a1.as_ptr(), a.as_ptr());
+            assert!(a1.get_size_bytes() >= 500);
+
+            let mut buf = [0u8; 100];
+            crate::mem::read_bytes(a1.offset(0), &mut buf);
+            assert_eq!(buf, [1u8; 100]);
+
+            // the next neighbor is too small, the data moves
+            let a2 = sma.reallocate(a1, 2000).unwrap();
+            assert_ne!(a2.as_ptr(), a1.as_ptr());
+
+            crate::mem::read_bytes(a2.offset(0), &mut buf);
+            assert_eq!(buf, [1u8; 100]);
+
+            // the slice is at the end of the heap, the heap grows under it
+            let max_ptr = sma.max_ptr;
+            let a3 = sma.reallocate(a2, 200_000).unwrap();
+            assert_eq!(a3.as_ptr(), a2.as_ptr());
+            assert!(sma.max_ptr > max_ptr);
+
+            crate::mem::read_bytes(a3.offset(0), &mut buf);
+            assert_eq!(buf, [1u8; 100]);
+
+            sma.deallocate(a3);
+            sma.deallocate(c);
+
+            assert_eq!(sma.get_allocated_size(), 0);
+            sma.debug_validate_free_blocks();
+        }
+    }
+
+    #[test]
+    fn shrinking_reallocation_works_fine() {
+        stable::clear();
+
+        let mut sma = StableMemoryAllocator::init(0);
+
+        unsafe {
+            let a = sma.allocate(1000).unwrap();
+            let b = sma.allocate(100).unwrap();
+
+            crate::mem::write_bytes(a.offset(0), &[1u8; 100]);
+            let allocated_size = sma.get_allocated_size();
+
+            let a1 = sma.reallocate(a, 100).unwrap();
+            assert_eq!(a1.as_ptr(), a.as_ptr());
+            assert_eq!(a1.get_size_bytes(), 104);
+            assert_eq!(sma.get_allocated_size(), allocated_size - 896);
+
+            let mut buf = [0u8; 100];
+            crate::mem::read_bytes(a1.offset(0), &mut buf);
+            assert_eq!(buf, [1u8; 100]);
+
+            // the tail is too small to be a free block
+            let a2 = sma.reallocate(a1, 90).unwrap();
+            assert_eq!(a2.get_size_bytes(), a1.get_size_bytes());
+
+            sma.debug_validate_free_blocks();
+
+            // the released tail is reused
+            let c = sma.allocate(800).unwrap();
+            assert!(c.as_ptr() < b.as_ptr());
+
+            sma.deallocate(a2);
+            sma.deallocate(b);
+            sma.deallocate(c);
+
+            assert_eq!(sma.get_allocated_size(), 0);
+            sma.debug_validate_free_blocks();
+        }
+    }
+
     #[test]
     fn basic_flow_works_fine() {
         unsafe {
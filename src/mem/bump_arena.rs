@@ -0,0 +1,141 @@
+//! A bump allocator for short-lived stable memory buffers.
+//!
+//! [BumpArena] allocates a single memory block from the main allocator and then hands out consecutive
+//! chunks of this block, only moving an offset forward. Chunks can't be freed one by one - instead all
+//! of them are released at once with [BumpArena::reset]. This makes allocations `O(1)` and free
+//! of any metadata, which is handy for temporary buffers (e.g. during a data migration).
+
+use crate::mem::s_slice::SSlice;
+use crate::mem::StablePtr;
+use crate::{allocate, deallocate, OutOfMemory};
+
+/// A bump allocator over a single memory block.
+///
+/// Chunks returned by [BumpArena::allocate] are raw pointers, which should be used with [read_bytes](crate::mem::read_bytes)
+/// and [write_bytes](crate::mem::write_bytes). They are *not* [SSlice]-s, so don't pass them to
+/// [deallocate](crate::deallocate) and don't use them as a memory of stable collections.
+///
+/// The underlying memory block is deallocated, when the arena is dropped. All chunks become invalid
+/// after [BumpArena::reset] or after the arena is dropped.
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::{mem, stable_memory_init};
+/// # use ic_stable_memory::mem::bump_arena::BumpArena;
+/// # unsafe { mem::clear(); }
+/// # stable_memory_init();
+/// let mut arena = BumpArena::new(1024).expect("Out of memory");
+///
+/// let ptr = arena.allocate(100).expect("Arena is full");
+/// unsafe { mem::write_bytes(ptr, &[1u8; 100]) };
+///
+/// arena.reset();
+/// assert_eq!(arena.used(), 0);
+/// ```
+pub struct BumpArena {
+    slice: SSlice,
+    offset: u64,
+}
+
+impl BumpArena {
+    /// Allocates a memory block of at least `capacity` bytes for the arena.
+    ///
+    /// Returns [OutOfMemory] if the canister is out of stable memory.
+    #[inline]
+    pub fn new(capacity: u64) -> Result<Self, OutOfMemory> {
+        let slice = unsafe { allocate(capacity)? };
+
+        Ok(Self { slice, offset: 0 })
+    }
+
+    /// Returns a pointer to a chunk of `size` bytes (rounded up to the multiple of 8).
+    ///
+    /// Returns [OutOfMemory], if there is not enough space left in this arena. Never allocates any
+    /// new stable memory.
+    pub fn allocate(&mut self, size: u64) -> Result<StablePtr, OutOfMemory> {
+        let size = (size + 7) & !7;
+
+        if self.remaining() < size {
+            return Err(OutOfMemory);
+        }
+
+        let ptr = self.slice.offset(self.offset);
+        self.offset += size;
+
+        Ok(ptr)
+    }
+
+    /// Releases all chunks at once, in `O(1)`.
+    #[inline]
+    pub fn reset(&mut self) {
+        self.offset = 0;
+    }
+
+    /// Returns the total size of this arena in bytes.
+    #[inline]
+    pub fn capacity(&self) -> u64 {
+        self.slice.get_size_bytes()
+    }
+
+    /// Returns the number of bytes, occupied by chunks.
+    #[inline]
+    pub fn used(&self) -> u64 {
+        self.offset
+    }
+
+    /// Returns the number of bytes, which can still be allocated.
+    #[inline]
+    pub fn remaining(&self) -> u64 {
+        self.capacity() - self.offset
+    }
+}
+
+impl Drop for BumpArena {
+    fn drop(&mut self) {
+        deallocate(self.slice);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mem::bump_arena::BumpArena;
+    use crate::{_debug_validate_allocator, get_allocated_size, stable, stable_memory_init};
+
+    #[test]
+    fn works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut arena = BumpArena::new(1000).unwrap();
+            assert!(arena.capacity() >= 1000);
+
+            let a = arena.allocate(10).unwrap();
+            let b = arena.allocate(100).unwrap();
+            assert_eq!(b - a, 16);
+            assert_eq!(arena.used(), 16 + 104);
+
+            unsafe {
+                crate::mem::write_bytes(a, &[1u8; 10]);
+                crate::mem::write_bytes(b, &[2u8; 100]);
+            }
+
+            let mut buf = [0u8; 10];
+            unsafe { crate::mem::read_bytes(a, &mut buf) };
+            assert_eq!(buf, [1u8; 10]);
+
+            let allocated_size = get_allocated_size();
+            while arena.allocate(100).is_ok() {}
+
+            assert!(arena.remaining() < 104);
+            assert_eq!(get_allocated_size(), allocated_size);
+
+            arena.reset();
+            assert_eq!(arena.used(), 0);
+            assert_eq!(arena.allocate(10).unwrap(), a);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
@@ -0,0 +1,190 @@
+//! A [std::io] cursor over the data inside a memory block.
+//!
+//! [SSliceCursor] implements [Read], [Write] and [Seek], so a big memory block can be streamed in
+//! chunks of any size (e.g. with [std::io::copy]), without ever holding its whole content in heap.
+
+use crate::mem::s_slice::SSlice;
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+
+/// A cursor over the data inside an [SSlice].
+///
+/// Reads and writes start at the current position and move it forward. Reads stop at the end of
+/// the memory block, writes never go beyond it (so [Write::write_all] returns an error, if the data
+/// doesn't fit).
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::{allocate, deallocate, mem, stable_memory_init};
+/// # use ic_stable_memory::mem::cursor::SSliceCursor;
+/// # use std::io::{Read, Write};
+/// # unsafe { mem::clear(); }
+/// # stable_memory_init();
+/// let slice = unsafe { allocate(1024 * 1024).expect("Out of memory") };
+/// let mut cursor = unsafe { SSliceCursor::new(slice) };
+///
+/// // write the block in 4KB chunks
+/// for i in 0..256 {
+///     cursor.write_all(&[i as u8; 4096]).unwrap();
+/// }
+///
+/// // and read it back the same way
+/// cursor.set_position(0);
+/// let mut chunk = [0u8; 4096];
+/// for i in 0..256 {
+///     cursor.read_exact(&mut chunk).unwrap();
+///     assert_eq!(chunk, [i as u8; 4096]);
+/// }
+///
+/// deallocate(slice);
+/// ```
+#[derive(Debug, Copy, Clone)]
+pub struct SSliceCursor {
+    slice: SSlice,
+    position: u64,
+}
+
+impl SSliceCursor {
+    /// Creates a cursor, positioned at the start of the memory block.
+    ///
+    /// # Safety
+    /// The cursor reads and writes the memory block directly, so the same rules as for
+    /// [read_bytes](crate::mem::read_bytes) and [write_bytes](crate::mem::write_bytes) apply. Don't use
+    /// the cursor after the memory block is deallocated.
+    #[inline]
+    pub unsafe fn new(slice: SSlice) -> Self {
+        Self { slice, position: 0 }
+    }
+
+    #[inline]
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Moves the cursor, the position may be set beyond the end of the memory block.
+    #[inline]
+    pub fn set_position(&mut self, position: u64) {
+        self.position = position;
+    }
+
+    /// Returns the number of bytes between the current position and the end of the memory block.
+    #[inline]
+    pub fn remaining(&self) -> u64 {
+        self.slice.get_size_bytes().saturating_sub(self.position)
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> SSlice {
+        self.slice
+    }
+}
+
+impl Read for SSliceCursor {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.remaining().min(buf.len() as u64) as usize;
+
+        unsafe { self.slice.read_chunk(self.position, &mut buf[..len]) };
+        self.position += len as u64;
+
+        Ok(len)
+    }
+}
+
+impl Write for SSliceCursor {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = self.remaining().min(buf.len() as u64) as usize;
+
+        unsafe { self.slice.write_chunk(self.position, &buf[..len]) };
+        self.position += len as u64;
+
+        Ok(len)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for SSliceCursor {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(it) => Some(it),
+            SeekFrom::End(it) => self.slice.get_size_bytes().checked_add_signed(it),
+            SeekFrom::Current(it) => self.position.checked_add_signed(it),
+        };
+
+        match position {
+            Some(it) => {
+                self.position = it;
+
+                Ok(it)
+            }
+            None => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mem::cursor::SSliceCursor;
+    use crate::{allocate, deallocate, get_allocated_size, stable, stable_memory_init};
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    #[test]
+    fn works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        let slice = unsafe { allocate(100_000).unwrap() };
+        let size = slice.get_size_bytes();
+        let mut cursor = unsafe { SSliceCursor::new(slice) };
+
+        let data = (0..size).map(|it| (it % 251) as u8).collect::<Vec<_>>();
+        for chunk in data.chunks(999) {
+            cursor.write_all(chunk).unwrap();
+        }
+        assert_eq!(cursor.remaining(), 0);
+        assert_eq!(cursor.write(&[1]).unwrap(), 0);
+        assert!(cursor.write_all(&[1]).is_err());
+
+        cursor.rewind().unwrap();
+        let mut copy = Vec::new();
+        let mut chunk = [0u8; 777];
+        loop {
+            let n = cursor.read(&mut chunk).unwrap();
+            if n == 0 {
+                break;
+            }
+
+            copy.extend_from_slice(&chunk[..n]);
+        }
+        assert_eq!(copy, data);
+
+        assert_eq!(cursor.seek(SeekFrom::End(-10)).unwrap(), size - 10);
+        let mut tail = Vec::new();
+        cursor.read_to_end(&mut tail).unwrap();
+        assert_eq!(tail, &data[(size as usize - 10)..]);
+
+        assert_eq!(cursor.seek(SeekFrom::Current(-20)).unwrap(), size - 20);
+        assert!(cursor.seek(SeekFrom::Current(-(size as i64) - 1)).is_err());
+
+        cursor.seek(SeekFrom::Start(size + 100)).unwrap();
+        assert_eq!(cursor.read(&mut chunk).unwrap(), 0);
+
+        let mut buf = [0u8; 3];
+        unsafe { slice.read_chunk(10, &mut buf) };
+        assert_eq!(buf, [10, 11, 12]);
+        unsafe { slice.write_chunk(size - 3, &[1, 2, 3]) };
+        unsafe { slice.read_chunk(size - 3, &mut buf) };
+        assert_eq!(buf, [1, 2, 3]);
+
+        let res = std::panic::catch_unwind(|| unsafe { slice.read_chunk(size - 2, &mut [0u8; 3]) });
+        assert!(res.is_err());
+
+        deallocate(slice);
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
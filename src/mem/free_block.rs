@@ -6,10 +6,8 @@
 //! Only used by the allocator itself. Not for public use.
 
 use crate::encoding::{AsFixedSizeBytes, Buffer};
-use crate::mem::allocator::MIN_PTR;
-use crate::mem::s_slice::{SSlice, ALLOCATED, FREE};
+use crate::mem::s_slice::{read_size_word, write_size_words, SSlice};
 use crate::mem::StablePtr;
-use crate::stable;
 use candid::{CandidType, Deserialize};
 use std::cmp::Ordering;
 
@@ -83,10 +81,10 @@ impl FreeBlock {
     }
 
     #[inline]
-    pub fn prev_neighbor_is_free(&self) -> Option<FreeBlock> {
+    pub fn prev_neighbor_is_free(&self, min_ptr: StablePtr) -> Option<FreeBlock> {
         let prev_neighbor_rear_ptr = self.get_prev_neighbor_rear_ptr();
 
-        if prev_neighbor_rear_ptr >= MIN_PTR {
+        if prev_neighbor_rear_ptr >= min_ptr {
             Self::read_size(prev_neighbor_rear_ptr).map(|size| {
                 let it_ptr = prev_neighbor_rear_ptr - (StablePtr::SIZE as u64) - size;
 
@@ -166,18 +164,7 @@ impl FreeBlock {
     }
 
     fn read_size(ptr: StablePtr) -> Option<u64> {
-        let mut meta = [0u8; u64::SIZE];
-        stable::read(ptr, &mut meta);
-
-        let encoded_size = u64::from_le_bytes(meta);
-        let mut size = encoded_size;
-
-        let allocated = if encoded_size & ALLOCATED == ALLOCATED {
-            size &= FREE;
-            true
-        } else {
-            false
-        };
+        let (size, allocated) = read_size_word(ptr);
 
         if allocated {
             None
@@ -186,13 +173,9 @@ impl FreeBlock {
         }
     }
 
+    #[inline]
     fn write_size(ptr: StablePtr, size: u64) {
-        let encoded_size = size & FREE;
-
-        let meta = encoded_size.to_le_bytes();
-
-        stable::write(ptr, &meta);
-        stable::write(ptr + (StablePtr::SIZE as u64) + size, &meta);
+        write_size_words(ptr, size, false);
     }
 }
 
@@ -233,7 +216,7 @@ mod tests {
         let mut m1 = FreeBlock::new(MIN_PTR, 100);
         m1.persist();
 
-        assert!(m1.prev_neighbor_is_free().is_none());
+        assert!(m1.prev_neighbor_is_free(MIN_PTR).is_none());
         assert!(m1
             .next_neighbor_is_free(m1.get_next_neighbor_ptr())
             .is_none());
@@ -253,7 +236,7 @@ mod tests {
         assert_eq!(m2.get_prev_neighbor_rear_ptr(), 116);
         assert_eq!(m2.get_next_neighbor_ptr(), 240);
 
-        assert!(m1.prev_neighbor_is_free().is_none());
+        assert!(m1.prev_neighbor_is_free(MIN_PTR).is_none());
         let m1_next = m1
             .next_neighbor_is_free(m2.get_next_neighbor_ptr())
             .unwrap();
@@ -263,7 +246,7 @@ mod tests {
         assert!(m2
             .next_neighbor_is_free(m2.get_next_neighbor_ptr())
             .is_none());
-        let m2_prev = m2.prev_neighbor_is_free().unwrap();
+        let m2_prev = m2.prev_neighbor_is_free(MIN_PTR).unwrap();
         assert_eq!(m2_prev.as_ptr(), m1.as_ptr());
         assert_eq!(m2_prev.get_size_bytes(), m1.get_size_bytes());
 
@@ -293,7 +276,7 @@ mod tests {
         assert_eq!(m2.get_size_bytes(), 150);
         assert_eq!(m2.get_total_size_bytes(), 166);
 
-        assert!(m1.prev_neighbor_is_free().is_none());
+        assert!(m1.prev_neighbor_is_free(MIN_PTR).is_none());
         let m1_next = m1
             .next_neighbor_is_free(m2.get_next_neighbor_ptr())
             .unwrap();
@@ -303,7 +286,7 @@ mod tests {
         assert!(m2
             .next_neighbor_is_free(m2.get_next_neighbor_ptr())
             .is_none());
-        let m2_prev = m2.prev_neighbor_is_free().unwrap();
+        let m2_prev = m2.prev_neighbor_is_free(MIN_PTR).unwrap();
         assert_eq!(m2_prev.as_ptr(), m1.as_ptr());
         assert_eq!(m2_prev.get_size_bytes(), m1.get_size_bytes());
     }
@@ -22,13 +22,29 @@
 //! If you're thinking of implementing your own data structure using this crate, check [this](https://github.com/seniorjoinu/ic-stable-memory/docs/user-defined-data-structures.md)
 //! document for more info on this topic.
 
-use crate::encoding::{AsFixedSizeBytes, Buffer};
+use crate::encoding::{AsFixedSizeBytes, Buffer, CorruptData};
 use crate::primitive::StableType;
-use crate::stable;
+use crate::{stable, PAGE_SIZE_BYTES};
+use std::cmp::min;
+use std::io::IoSlice;
 
+#[cfg(feature = "alloc_trace")]
+pub mod alloc_trace;
 pub mod allocator;
+pub mod bump_arena;
+pub mod cursor;
 pub mod free_block;
+#[cfg(feature = "orphan_collector")]
+pub mod orphan_collector;
+pub mod read_guard;
+pub mod region;
 pub mod s_slice;
+pub mod slab;
+#[cfg(feature = "snapshots")]
+pub mod snapshot;
+pub mod typed_slice;
+#[cfg(feature = "wal")]
+pub mod wal;
 
 /// A pointer to something is stable memory.
 ///
@@ -67,6 +83,48 @@ pub unsafe fn write_bytes(ptr: StablePtr, buf: &[u8]) {
     stable::write(ptr, buf);
 }
 
+/// Writes several buffers of raw bytes to stable memory one after another, as a single write.
+///
+/// Prefer it to several [write_bytes] calls on adjacent ranges (like a header and a payload) - on
+/// wasm it makes a single system API call. See [MemContext::write_vectored](crate::utils::mem_context::MemContext::write_vectored).
+///
+/// # Safety
+/// Same as for [write_bytes].
+#[inline]
+pub unsafe fn write_bytes_vectored(ptr: StablePtr, bufs: &[IoSlice]) {
+    stable::write_vectored(ptr, bufs);
+}
+
+/// Copies `len` raw bytes of stable memory from `src` to `dst`.
+///
+/// The data is moved through a heap buffer of at most [PAGE_SIZE_BYTES](crate::PAGE_SIZE_BYTES)
+/// bytes, so copying a big memory block doesn't make the heap grow by its whole size. Like
+/// [std::ptr::copy], works fine for overlapping ranges.
+///
+/// # Safety
+/// Same as for [read_bytes] and [write_bytes].
+pub unsafe fn copy_bytes(src: StablePtr, dst: StablePtr, len: u64) {
+    if src == dst || len == 0 {
+        return;
+    }
+
+    let mut buf = vec![0u8; min(len, PAGE_SIZE_BYTES) as usize];
+    let mut done = 0u64;
+
+    while done < len {
+        let size = min(len - done, buf.len() as u64);
+
+        // when moving forward, chunks are copied from the end, so the source is read before it's overwritten
+        let offset = if dst > src { len - done - size } else { done };
+        let chunk = &mut buf[..size as usize];
+
+        stable::read(src + offset, chunk);
+        stable::write(dst + offset, chunk);
+
+        done += size;
+    }
+}
+
 fn read_fixed<T: AsFixedSizeBytes>(ptr: StablePtr) -> T {
     let mut b = T::Buf::new(T::SIZE);
     stable::read(ptr, b._deref_mut());
@@ -114,6 +172,47 @@ pub unsafe fn read_fixed_for_move<T: AsFixedSizeBytes + StableType>(ptr: StableP
     it
 }
 
+fn try_read_fixed<T: AsFixedSizeBytes>(ptr: StablePtr) -> Result<T, CorruptData> {
+    let mut b = T::Buf::new(T::SIZE);
+    stable::read(ptr, b._deref_mut());
+
+    T::try_from_fixed_size_bytes(b._deref()).map_err(|e| e.shifted(ptr))
+}
+
+/// Same as [read_fixed_for_reference], but validates the data, returning a [CorruptData] error
+/// instead of panicking, if it is not a valid encoding of `T`.
+///
+/// The offset of the error is a stable memory pointer to the invalid bytes.
+///
+/// # Safety
+/// Same as for [read_fixed_for_reference].
+#[inline]
+pub unsafe fn try_read_fixed_for_reference<T: AsFixedSizeBytes + StableType>(
+    ptr: StablePtr,
+) -> Result<T, CorruptData> {
+    let mut it = try_read_fixed::<T>(ptr)?;
+    it.stable_drop_flag_off();
+
+    Ok(it)
+}
+
+/// Same as [read_fixed_for_move], but validates the data, returning a [CorruptData] error
+/// instead of panicking, if it is not a valid encoding of `T`.
+///
+/// The offset of the error is a stable memory pointer to the invalid bytes.
+///
+/// # Safety
+/// Same as for [read_fixed_for_move].
+#[inline]
+pub unsafe fn try_read_fixed_for_move<T: AsFixedSizeBytes + StableType>(
+    ptr: StablePtr,
+) -> Result<T, CorruptData> {
+    let mut it = try_read_fixed::<T>(ptr)?;
+    it.stable_drop_flag_on();
+
+    Ok(it)
+}
+
 /// Writes a [StableType](crate::StableType) value implementing [AsFixedSizeBytes](crate::AsFixedSizeBytes) trait to stable memory.
 ///
 /// This function creates an intermediate buffer of [AsFixedSizeBytes::SIZE](crate::AsFixedSizeBytes::SIZE) bytes,
@@ -0,0 +1,651 @@
+//! Budgeted mark-and-sweep collector of orphaned memory blocks
+//!
+//! Memory, which was allocated by an aborted operation (e.g. a trap in the middle of an update) or
+//! forgotten by an application bug, is never released by this crate's ownership rules - nothing
+//! points to it anymore. The orphan collector finds and releases such memory.
+//!
+//! Live memory is found by *tracing*: each collection implements [Trace], which reports every
+//! memory block (and slot) it owns, including the ones owned by its elements. Root collections are
+//! registered with [register_root_tracer]. Everything else, allocated by the main allocator and not
+//! reported by any tracer, is an orphan and is released by [collect_orphans].
+//!
+//! A collection cycle starts with marking - all tracers are called at once. Then orphans are released
+//! in portions of `budget` candidates per [collect_orphans] call, so a big heap can be swept across
+//! multiple canister messages. Memory, allocated or released between the calls, is never touched by
+//! the current cycle. [compact](crate::compact) and [defrag_step](crate::defrag_step) abort the
+//! current cycle.
+//!
+//! Tracing is also used for memory accounting - [memory_usage] reports how much memory a value
+//! owns, so applications can charge their users for storage.
+//!
+//! Named roots (e.g. [stable variables](crate::stable_var) themselves), custom data, arenas and
+//! slabs are always considered live, but memory, owned by the values they store, is not - it has to
+//! be reported by a tracer. Memory of arenas is never collected.
+//!
+//! # Example
+//! ```rust
+//! # use ic_stable_memory::collections::SVec;
+//! # use ic_stable_memory::mem::orphan_collector::{collect_orphans, register_root_tracer, Trace};
+//! # use ic_stable_memory::{stable_memory_init, stable_var};
+//! # unsafe { ic_stable_memory::mem::clear(); }
+//! # stable_memory_init();
+//! stable_var!(NUMBERS: SVec<u64>);
+//!
+//! register_root_tracer(|ptrs| NUMBERS::with(|it| it.trace(ptrs)));
+//!
+//! NUMBERS::with_mut(|it| it.push(10)).unwrap().expect("Out of memory");
+//!
+//! // an application bug
+//! let mut orphan = SVec::<u64>::new();
+//! orphan.push(20).expect("Out of memory");
+//! std::mem::forget(orphan);
+//!
+//! while !collect_orphans(100).done {}
+//!
+//! assert_eq!(NUMBERS::with(|it| *it.get(0).unwrap()), 10);
+//! ```
+//!
+//! # Warning
+//! Every piece of live memory *must* be reachable from a registered tracer. Collections, created
+//! with [allocate](crate::allocate) by hand or stored without a tracer, will be released by the
+//! collector. Only available with the `orphan_collector` feature.
+
+use crate::mem::StablePtr;
+use crate::with_main_allocator;
+use candid::{Int, Nat, Principal};
+use ic_ledger_types::Subaccount;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+/// Reports memory blocks, owned by a value, to the [orphan collector](crate::mem::orphan_collector)
+///
+/// Implementations should push a pointer to each memory block or slot, owned by the value (as
+/// returned by [allocate](crate::allocate) or [allocate_slot](crate::allocate_slot)), and trace their
+/// elements.
+pub trait Trace {
+    /// `false`, if values of this type never own any stable memory
+    ///
+    /// Collections don't trace their elements, if it is `false`.
+    const OWNS_MEMORY: bool = true;
+
+    /// Pushes pointers to all memory blocks, owned by this value, into `ptrs`
+    fn trace(&self, ptrs: &mut Vec<StablePtr>);
+}
+
+macro_rules! impl_trace_for_plain {
+    ($($ty:ty),*) => {
+        $(
+            impl Trace for $ty {
+                const OWNS_MEMORY: bool = false;
+
+                #[inline]
+                fn trace(&self, _ptrs: &mut Vec<StablePtr>) {}
+            }
+        )*
+    };
+}
+
+impl_trace_for_plain!(
+    u8,
+    u16,
+    u32,
+    u64,
+    u128,
+    usize,
+    i8,
+    i16,
+    i32,
+    i64,
+    i128,
+    isize,
+    f32,
+    f64,
+    bool,
+    char,
+    (),
+    String,
+    Duration,
+    Principal,
+    Subaccount,
+    Nat,
+    Int
+);
+
+impl<T> Trace for Vec<T> {
+    const OWNS_MEMORY: bool = false;
+
+    #[inline]
+    fn trace(&self, _ptrs: &mut Vec<StablePtr>) {}
+}
+
+impl<T, const N: usize> Trace for [T; N] {
+    const OWNS_MEMORY: bool = false;
+
+    #[inline]
+    fn trace(&self, _ptrs: &mut Vec<StablePtr>) {}
+}
+
+impl<T: Trace> Trace for Option<T> {
+    const OWNS_MEMORY: bool = T::OWNS_MEMORY;
+
+    #[inline]
+    fn trace(&self, ptrs: &mut Vec<StablePtr>) {
+        if let Some(it) = self {
+            it.trace(ptrs);
+        }
+    }
+}
+
+macro_rules! impl_trace_for_tuple {
+    ($($t:ident $idx:tt),+) => {
+        impl<$($t: Trace),+> Trace for ($($t,)+) {
+            const OWNS_MEMORY: bool = $($t::OWNS_MEMORY)||+;
+
+            #[inline]
+            fn trace(&self, ptrs: &mut Vec<StablePtr>) {
+                $(
+                    if $t::OWNS_MEMORY {
+                        self.$idx.trace(ptrs);
+                    }
+                )+
+            }
+        }
+    };
+}
+
+impl_trace_for_tuple!(A 0);
+impl_trace_for_tuple!(A 0, B 1);
+impl_trace_for_tuple!(A 0, B 1, C 2);
+impl_trace_for_tuple!(A 0, B 1, C 2, D 3);
+impl_trace_for_tuple!(A 0, B 1, C 2, D 3, E 4);
+impl_trace_for_tuple!(A 0, B 1, C 2, D 3, E 4, F 5);
+impl_trace_for_tuple!(A 0, B 1, C 2, D 3, E 4, F 5, G 6);
+impl_trace_for_tuple!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
+
+/// Progress of [collect_orphans]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct OrphanCollectionProgress {
+    /// Number of blocks and slots, released by this call
+    pub freed_count: u64,
+    /// Size of released blocks and slots (excluding size metadata)
+    pub freed_size: u64,
+    /// Number of candidates left to check in the current cycle
+    pub remaining: u64,
+    /// `true`, if the cycle is over - the next call will start a new one
+    pub done: bool,
+}
+
+/// Memory, owned by a value, as reported by its [Trace] implementation
+///
+/// Returned by `memory_usage()` of collections, or by [memory_usage].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// Number of blocks and slots, owned by the value
+    pub blocks: u64,
+    /// Size of owned blocks and slots (excluding size metadata)
+    pub size: u64,
+    /// Size of stable memory, occupied by owned blocks and slots (including size metadata)
+    pub occupied_size: u64,
+    /// Number of entries in the value (e.g. the length of a collection)
+    pub entries: u64,
+}
+
+impl MemoryUsage {
+    /// Returns the average occupied size per entry, rounded up, or `0`, if there are no entries
+    ///
+    /// This is an estimate - the memory of a collection is shared between its entries (e.g. B-tree
+    /// nodes are never full), but the memory owned by an entry itself (e.g. an [SBox](crate::SBox)) is
+    /// not.
+    #[inline]
+    pub fn per_entry(&self) -> u64 {
+        if self.entries == 0 {
+            0
+        } else {
+            (self.occupied_size + self.entries - 1) / self.entries
+        }
+    }
+}
+
+struct Cycle {
+    // traced pointers, plus everything allocated or released since marking
+    live: BTreeSet<StablePtr>,
+    // in descending order, so candidates are popped in ascending order
+    candidates: Vec<StablePtr>,
+}
+
+type Tracer = Box<dyn Fn(&mut Vec<StablePtr>)>;
+
+thread_local! {
+    static TRACERS: RefCell<Vec<Tracer>> = RefCell::new(Vec::new());
+    static CYCLE: RefCell<Option<Cycle>> = RefCell::new(None);
+}
+
+/// Registers a function, which traces a root collection (e.g. a [stable variable](crate::stable_var))
+///
+/// Tracers are kept in heap memory, so they have to be registered again after each canister upgrade,
+/// before [collect_orphans] is called.
+pub fn register_root_tracer<F: Fn(&mut Vec<StablePtr>) + 'static>(tracer: F) {
+    TRACERS.with(|it| it.borrow_mut().push(Box::new(tracer)));
+}
+
+/// Removes all registered tracers and aborts the current cycle
+pub fn clear_root_tracers() {
+    TRACERS.with(|it| it.borrow_mut().clear());
+    abort_orphan_collection();
+}
+
+/// Aborts the current cycle, the next [collect_orphans] call will start a new one
+pub fn abort_orphan_collection() {
+    CYCLE.with(|it| *it.borrow_mut() = None);
+}
+
+/// Checks up to `budget` orphan candidates, releasing the ones which are still orphaned
+///
+/// If there is no cycle in progress, starts a new one by calling all registered tracers. Does nothing
+/// and reports the cycle as done, if there are no registered tracers.
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator.
+pub fn collect_orphans(budget: u64) -> OrphanCollectionProgress {
+    let in_progress = CYCLE.with(|it| it.borrow().is_some());
+
+    if !in_progress && !start_cycle() {
+        return OrphanCollectionProgress {
+            done: true,
+            ..Default::default()
+        };
+    }
+
+    CYCLE.with(|it| {
+        let mut cycle_ref = it.borrow_mut();
+        let cycle = cycle_ref.as_mut().unwrap();
+        let mut progress = OrphanCollectionProgress::default();
+
+        with_main_allocator(|alloc| {
+            for _ in 0..budget {
+                let ptr = match cycle.candidates.pop() {
+                    Some(ptr) => ptr,
+                    None => break,
+                };
+
+                if let Some(size) = alloc.free_orphan(ptr, &cycle.live) {
+                    progress.freed_count += 1;
+                    progress.freed_size += size;
+                }
+            }
+        });
+
+        progress.remaining = cycle.candidates.len() as u64;
+
+        if cycle.candidates.is_empty() {
+            progress.done = true;
+            *cycle_ref = None;
+        }
+
+        progress
+    })
+}
+
+/// Traces the value and sums up the sizes of all memory blocks and slots it owns
+///
+/// `entries` is only used to estimate [MemoryUsage::per_entry]. Pointers, which don't point to an
+/// allocated block or slot, are ignored. The value itself is not counted, unless it is stored in
+/// stable memory by its owner (e.g. inside an [SBox](crate::SBox)).
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator.
+pub fn memory_usage<T: Trace + ?Sized>(value: &T, entries: u64) -> MemoryUsage {
+    let mut ptrs = Vec::new();
+    value.trace(&mut ptrs);
+
+    let mut usage = MemoryUsage {
+        entries,
+        ..Default::default()
+    };
+
+    with_main_allocator(|alloc| {
+        for ptr in ptrs {
+            if let Some((size, occupied_size)) = alloc.occupied_size(ptr) {
+                usage.blocks += 1;
+                usage.size += size;
+                usage.occupied_size += occupied_size;
+            }
+        }
+    });
+
+    usage
+}
+
+// tracers may access stable variables, so they are called before the allocator is borrowed
+fn start_cycle() -> bool {
+    let mut ptrs = Vec::new();
+
+    let has_tracers = TRACERS.with(|it| {
+        let tracers = it.borrow();
+        for tracer in tracers.iter() {
+            tracer(&mut ptrs);
+        }
+
+        !tracers.is_empty()
+    });
+
+    if !has_tracers {
+        return false;
+    }
+
+    let live = ptrs.into_iter().collect::<BTreeSet<_>>();
+    let mut candidates = with_main_allocator(|alloc| alloc.orphan_candidates(&live));
+    candidates.sort_unstable_by(|a, b| b.cmp(a));
+
+    CYCLE.with(|it| *it.borrow_mut() = Some(Cycle { live, candidates }));
+
+    true
+}
+
+// memory, allocated or released during a cycle, is not collected by it
+#[inline]
+pub(crate) fn on_touch(ptr: Option<StablePtr>) {
+    if let Some(ptr) = ptr {
+        CYCLE.with(|it| {
+            if let Some(cycle) = &mut *it.borrow_mut() {
+                cycle.live.insert(ptr);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::{SBTreeMap, SHashMap, SLog, SRadixTree, SVec, SVersionedMap};
+    use crate::mem::orphan_collector::{
+        abort_orphan_collection, clear_root_tracers, collect_orphans, memory_usage,
+        register_root_tracer, MemoryUsage, Trace,
+    };
+    use crate::primitive::s_box::SBox;
+    use crate::{
+        _debug_validate_allocator, check_consistency, get_allocated_size, get_ref_count, stable,
+        stable_memory_init, stable_var,
+    };
+
+    stable_var!(GC_TEST_VEC: SVec<SBox<String>>);
+    stable_var!(GC_TEST_MAP: SBTreeMap<u64, SVec<u64>>);
+    stable_var!(GC_TEST_HASH_MAP: SHashMap<u64, u64>);
+    stable_var!(GC_TEST_LOG: SLog<u64>);
+    stable_var!(GC_TEST_RADIX_TREE: SRadixTree<SBox<String>>);
+    stable_var!(GC_TEST_VERSIONED_MAP: SVersionedMap<u64, SBox<String>>);
+
+    #[test]
+    fn works_fine() {
+        stable::clear();
+        stable_memory_init();
+        clear_root_tracers();
+
+        assert!(collect_orphans(100).done);
+
+        register_root_tracer(|ptrs| GC_TEST_VEC::with(|it| it.trace(ptrs)));
+        register_root_tracer(|ptrs| GC_TEST_MAP::with(|it| it.trace(ptrs)));
+        register_root_tracer(|ptrs| GC_TEST_HASH_MAP::with(|it| it.trace(ptrs)));
+        register_root_tracer(|ptrs| GC_TEST_LOG::with(|it| it.trace(ptrs)));
+
+        for i in 0..100u64 {
+            GC_TEST_VEC::with_mut(|it| it.push(SBox::new(format!("str {}", i)).unwrap()))
+                .unwrap()
+                .unwrap();
+
+            GC_TEST_MAP::with_mut(|it| {
+                let mut vec = SVec::new();
+                vec.push(i).unwrap();
+
+                it.insert(i, vec).unwrap();
+            })
+            .unwrap();
+
+            GC_TEST_HASH_MAP::with_mut(|it| it.insert(i, i).unwrap()).unwrap();
+            GC_TEST_LOG::with_mut(|it| it.push(i).unwrap()).unwrap();
+        }
+
+        let live_size = get_allocated_size();
+
+        for i in 0..50u64 {
+            let mut map = SBTreeMap::new();
+            map.insert(i, i).unwrap();
+            std::mem::forget(map);
+
+            std::mem::forget(SBox::new(format!("orphan {}", i)).unwrap());
+        }
+
+        assert!(get_allocated_size() > live_size);
+
+        let mut freed_count = 0;
+        loop {
+            let progress = collect_orphans(10);
+            freed_count += progress.freed_count;
+
+            // memory, allocated in the middle of a cycle, survives it
+            let mut vec = SVec::<u64>::new();
+            vec.push(1).unwrap();
+            std::mem::forget(vec);
+
+            if progress.done {
+                break;
+            }
+        }
+
+        assert_eq!(freed_count, 100);
+
+        // collect what was allocated during the previous cycle
+        while !collect_orphans(10).done {}
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), live_size);
+
+        for i in 0..100u64 {
+            assert_eq!(
+                GC_TEST_VEC::with(|it| it.get(i as usize).unwrap().to_string()),
+                format!("str {}", i)
+            );
+            assert_eq!(
+                GC_TEST_MAP::with(|it| *it.get(&i).unwrap().get(0).unwrap()),
+                i
+            );
+            assert_eq!(GC_TEST_HASH_MAP::with(|it| *it.get(&i).unwrap()), i);
+            assert_eq!(GC_TEST_LOG::with(|it| *it.get(i).unwrap()), i);
+        }
+
+        while !collect_orphans(10).done {}
+        assert_eq!(get_allocated_size(), live_size);
+
+        clear_root_tracers();
+
+        GC_TEST_VEC::take();
+        GC_TEST_MAP::take();
+        GC_TEST_HASH_MAP::take();
+        GC_TEST_LOG::take();
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn other_collections_are_traced() {
+        stable::clear();
+        stable_memory_init();
+        clear_root_tracers();
+
+        register_root_tracer(|ptrs| GC_TEST_RADIX_TREE::with(|it| it.trace(ptrs)));
+        register_root_tracer(|ptrs| GC_TEST_VERSIONED_MAP::with(|it| it.trace(ptrs)));
+
+        for i in 0..100u64 {
+            GC_TEST_RADIX_TREE::with_mut(|it| {
+                let value = SBox::new(format!("str {}", i)).unwrap();
+                it.insert(format!("key {}", i).as_bytes(), value).unwrap();
+            })
+            .unwrap();
+
+            GC_TEST_VERSIONED_MAP::with_mut(|it| {
+                it.insert(i, SBox::new(format!("str {}", i)).unwrap())
+                    .unwrap();
+            })
+            .unwrap();
+        }
+
+        let live_size = get_allocated_size();
+
+        for i in 0..10u64 {
+            std::mem::forget(SBox::new(format!("orphan {}", i)).unwrap());
+        }
+
+        let mut freed_count = 0;
+        loop {
+            let progress = collect_orphans(10);
+            freed_count += progress.freed_count;
+
+            if progress.done {
+                break;
+            }
+        }
+
+        assert_eq!(freed_count, 10);
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), live_size);
+
+        for i in 0..100u64 {
+            assert_eq!(
+                GC_TEST_RADIX_TREE::with(|it| {
+                    it.get(format!("key {}", i).as_bytes()).unwrap().to_string()
+                }),
+                format!("str {}", i)
+            );
+            assert_eq!(
+                GC_TEST_VERSIONED_MAP::with(|it| it.get(&i).unwrap().to_string()),
+                format!("str {}", i)
+            );
+        }
+
+        clear_root_tracers();
+
+        GC_TEST_RADIX_TREE::take();
+        GC_TEST_VERSIONED_MAP::take();
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn released_candidates_are_skipped() {
+        stable::clear();
+        stable_memory_init();
+        clear_root_tracers();
+
+        register_root_tracer(|ptrs| GC_TEST_VEC::with(|it| it.trace(ptrs)));
+
+        let mut orphans = Vec::new();
+        for i in 0..10u64 {
+            orphans.push(SBox::new(format!("orphan {}", i)).unwrap());
+        }
+
+        assert_eq!(collect_orphans(0).remaining, 10);
+
+        // the application releases the orphans in the middle of a cycle
+        orphans.clear();
+
+        let progress = collect_orphans(100);
+        assert!(progress.done);
+        assert_eq!(progress.freed_count, 0);
+
+        abort_orphan_collection();
+        clear_root_tracers();
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn orphaned_shared_blocks_are_collected() {
+        stable::clear();
+        stable_memory_init();
+        clear_root_tracers();
+
+        register_root_tracer(|ptrs| GC_TEST_VEC::with(|it| it.trace(ptrs)));
+
+        let orphan = SBox::new(String::from("shared orphan")).unwrap();
+        let ptr = orphan.as_ptr();
+
+        std::mem::forget(orphan.share());
+        std::mem::forget(orphan);
+        assert_eq!(get_ref_count(ptr), 2);
+
+        let progress = collect_orphans(100);
+        assert!(progress.done);
+        assert_eq!(progress.freed_count, 1);
+
+        clear_root_tracers();
+
+        assert!(check_consistency().is_ok());
+        assert_eq!(get_allocated_size(), 0);
+
+        // a new block, likely at the same pointer, is not shared and is released by its only owner
+        let b = SBox::new(String::from("shared orphan")).unwrap();
+        assert_eq!(get_ref_count(b.as_ptr()), 1);
+
+        drop(b);
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn memory_usage_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let vec = SVec::<SBox<String>>::new();
+            assert_eq!(vec.memory_usage(), MemoryUsage::default());
+            assert_eq!(vec.memory_usage().per_entry(), 0);
+        }
+
+        {
+            let mut vec = SVec::new();
+            for i in 0..100u64 {
+                vec.push(SBox::new(format!("str {}", i)).unwrap()).unwrap();
+            }
+
+            let usage = vec.memory_usage();
+            assert_eq!(usage.blocks, 101);
+            assert_eq!(usage.entries, 100);
+            assert_eq!(usage.occupied_size, get_allocated_size());
+            assert!(usage.size < usage.occupied_size);
+            assert_eq!(usage.per_entry(), (usage.occupied_size + 99) / 100);
+            assert_eq!(memory_usage(&vec, 100), usage);
+        }
+
+        {
+            let mut map = SHashMap::new();
+            for i in 0..100u64 {
+                map.insert(i, i).unwrap();
+            }
+
+            let usage = map.memory_usage();
+            assert_eq!(usage.blocks, 1);
+            assert_eq!(usage.occupied_size, get_allocated_size());
+        }
+
+        {
+            let mut map = SBTreeMap::new();
+            for i in 0..1000u64 {
+                map.insert(i, i).unwrap();
+            }
+
+            let usage = map.memory_usage();
+            assert!(usage.blocks > 1);
+            assert!(usage.occupied_size <= get_allocated_size());
+            assert!(usage.per_entry() >= 16);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
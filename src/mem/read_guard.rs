@@ -0,0 +1,156 @@
+//! Read guards, exposing a chunk of stable memory as `&[u8]` without allocating a new buffer each time.
+//!
+//! Stable memory can't be addressed directly, so the data is still copied - but into a page-sized
+//! buffer, borrowed from a small thread-local pool and returned back to it, once the [ReadGuard] is
+//! dropped. This makes hot-path readers, which only need to look at the bytes, allocation-free.
+//!
+//! For a decoded `&T` of a fixed size type use [SRef](crate::primitive::s_ref::SRef), which reads
+//! the data into a stack buffer.
+
+use crate::mem::StablePtr;
+use crate::{stable, PAGE_SIZE_BYTES};
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::ops::Deref;
+
+// guards are rarely nested deeply, so a few buffers are enough
+const MAX_POOLED_BUFFERS: usize = 8;
+
+thread_local! {
+    static POOL: RefCell<Vec<Vec<u8>>> = RefCell::new(Vec::new());
+}
+
+/// A chunk of stable memory, copied into a pooled heap buffer.
+///
+/// Dereferences to `&[u8]`. Chunks bigger than [PAGE_SIZE_BYTES] are read into a buffer of their
+/// own, which is not pooled.
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::{allocate, mem, stable_memory_init};
+/// # unsafe { mem::clear(); }
+/// # stable_memory_init();
+/// let slice = unsafe { allocate(100).expect("Out of memory") };
+/// unsafe { slice.write_chunk(0, &[1, 2, 3]) };
+///
+/// for _ in 0..1000 {
+///     // no heap allocations after the first iteration
+///     let guard = unsafe { slice.read_guard(0, 3) };
+///     assert_eq!(&*guard, &[1, 2, 3]);
+/// }
+/// ```
+pub struct ReadGuard<'o> {
+    buf: Vec<u8>,
+    _marker: PhantomData<&'o [u8]>,
+}
+
+impl<'o> ReadGuard<'o> {
+    /// Reads `len` bytes of stable memory, starting from `ptr`.
+    ///
+    /// # Safety
+    /// Same as for [read_bytes](crate::mem::read_bytes).
+    pub unsafe fn new(ptr: StablePtr, len: usize) -> Self {
+        let mut buf = if len as u64 <= PAGE_SIZE_BYTES {
+            POOL.with(|it| it.borrow_mut().pop())
+                .unwrap_or_else(|| Vec::with_capacity(PAGE_SIZE_BYTES as usize))
+        } else {
+            Vec::with_capacity(len)
+        };
+
+        buf.resize(len, 0);
+        stable::read(ptr, &mut buf);
+
+        Self {
+            buf,
+            _marker: PhantomData::default(),
+        }
+    }
+}
+
+impl<'o> Deref for ReadGuard<'o> {
+    type Target = [u8];
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        &self.buf
+    }
+}
+
+impl<'o> AsRef<[u8]> for ReadGuard<'o> {
+    #[inline]
+    fn as_ref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl<'o> Drop for ReadGuard<'o> {
+    fn drop(&mut self) {
+        if self.buf.capacity() as u64 != PAGE_SIZE_BYTES {
+            return;
+        }
+
+        let mut buf = std::mem::take(&mut self.buf);
+        buf.clear();
+
+        POOL.with(|it| {
+            let mut pool = it.borrow_mut();
+
+            if pool.len() < MAX_POOLED_BUFFERS {
+                pool.push(buf);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mem::read_guard::ReadGuard;
+    use crate::{allocate, deallocate, stable, stable_memory_init, PAGE_SIZE_BYTES};
+
+    #[test]
+    fn works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        let slice = unsafe { allocate(PAGE_SIZE_BYTES * 2).unwrap() };
+        let data = (0..slice.get_size_bytes())
+            .map(|it| (it % 256) as u8)
+            .collect::<Vec<_>>();
+        unsafe { slice.write_chunk(0, &data) };
+
+        let ptr = {
+            let guard = unsafe { slice.read_guard(10, 100) };
+            assert_eq!(&*guard, &data[10..110]);
+
+            guard.as_ptr()
+        };
+
+        {
+            // the buffer is reused
+            let guard1 = unsafe { slice.read_guard(20, 5) };
+            assert_eq!(&*guard1, &data[20..25]);
+            assert_eq!(guard1.as_ptr(), ptr);
+
+            // but not shared
+            let guard2 = unsafe { slice.read_guard(0, 5) };
+            assert_eq!(&*guard2, &data[0..5]);
+            assert_ne!(guard2.as_ptr(), ptr);
+            assert_eq!(&*guard1, &data[20..25]);
+        }
+
+        let big = unsafe { slice.read_guard(0, data.len()) };
+        assert_eq!(&*big, &data[..]);
+        drop(big);
+
+        let raw = unsafe { ReadGuard::new(slice.offset(1), 2) };
+        assert_eq!(raw.as_ref(), &data[1..3]);
+        drop(raw);
+
+        let res = std::panic::catch_unwind(|| unsafe {
+            slice.read_guard(slice.get_size_bytes() - 1, 2);
+        });
+        assert!(res.is_err());
+
+        deallocate(slice);
+    }
+}
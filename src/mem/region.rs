@@ -0,0 +1,488 @@
+//! Named regions of stable memory, for canisters which share it with other libraries
+//!
+//! By default this crate assumes it owns the whole stable memory - from offset `0` to the end. A
+//! [RegionDirectory] splits stable memory into named, versioned [Region]-s instead:
+//! * an [allocator region](RegionKind::Allocator), where [the allocator](crate::init_allocator_with)
+//! lives;
+//! * [raw regions](RegionKind::Raw) of a fixed size, which are handed to other libraries as is;
+//! * [scratch regions](RegionKind::Scratch), reserved for temporary data during upgrades.
+//!
+//! The directory itself occupies the first page of stable memory. Regions follow it in order of
+//! declaration. Since the allocator grows stable memory, the allocator region is always the last one -
+//! all other regions should be declared before it.
+//!
+//! # Example
+//! ```rust
+//! # use ic_stable_memory::{init_allocator_with, mem};
+//! # use ic_stable_memory::mem::region::{RegionDirectory, RegionKind};
+//! # unsafe { mem::clear(); }
+//! let mut dir = RegionDirectory::open().expect("Unable to open the region directory");
+//!
+//! let raw = dir.declare("other-library", RegionKind::Raw, 1, 10).unwrap();
+//! raw.write(0, &[1, 2, 3]);
+//!
+//! let alloc = dir.declare("allocator", RegionKind::Allocator, 1, 0).unwrap();
+//! init_allocator_with(alloc.allocator_builder());
+//!
+//! // after an upgrade
+//! // let dir = RegionDirectory::open().unwrap();
+//! // reinit_allocator_at(dir.get("allocator").unwrap().offset());
+//! ```
+
+use crate::encoding::dyn_size::candid_decode_one_allow_trailing;
+use crate::encoding::CorruptData;
+use crate::mem::allocator::{AllocatorBuilder, IncompatibleVersion};
+use crate::mem::StablePtr;
+use crate::{stable, OutOfMemory, PAGE_SIZE_BYTES};
+use candid::{encode_one, CandidType, Deserialize};
+
+/// Current version of the directory's layout
+pub const DIRECTORY_VERSION: u32 = 1;
+
+const DIRECTORY_MAGIC: [u8; 8] = *b"ICSMRDIR";
+// header of the directory page: [magic, version, encoded regions length], followed by encoded regions
+const HEADER_SIZE: u64 = 16;
+
+/// What a [Region] is used for
+#[derive(Debug, Copy, Clone, CandidType, Deserialize, Eq, PartialEq)]
+pub enum RegionKind {
+    /// Managed by the allocator of this crate, see [Region::allocator_builder]
+    Allocator,
+    /// Unmanaged memory of a fixed size, e.g. for other libraries
+    Raw,
+    /// Unmanaged memory of a fixed size, reserved for temporary data during upgrades
+    Scratch,
+}
+
+/// A named, versioned range of stable memory pages, see [RegionDirectory::declare]
+#[derive(Debug, Clone, CandidType, Deserialize, Eq, PartialEq)]
+pub struct Region {
+    name: String,
+    kind: RegionKind,
+    version: u32,
+    offset: StablePtr,
+    // 0 for an unbounded allocator region
+    pages: u64,
+}
+
+impl Region {
+    /// Name of this region, unique within the directory
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// See [RegionKind]
+    #[inline]
+    pub fn kind(&self) -> RegionKind {
+        self.kind
+    }
+
+    /// Version of the data inside this region, as it was set by the owner of the region
+    #[inline]
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Offset of this region in stable memory, always a multiple of [PAGE_SIZE_BYTES]
+    #[inline]
+    pub fn offset(&self) -> StablePtr {
+        self.offset
+    }
+
+    /// Size of this region in pages, `0` for an allocator region without a limit
+    #[inline]
+    pub fn size_pages(&self) -> u64 {
+        self.pages
+    }
+
+    /// Size of this region in bytes, `0` for an allocator region without a limit
+    #[inline]
+    pub fn size_bytes(&self) -> u64 {
+        self.pages * PAGE_SIZE_BYTES
+    }
+
+    /// Returns `true`, if the pointer is inside this region
+    pub fn contains(&self, ptr: StablePtr) -> bool {
+        ptr >= self.offset && (self.pages == 0 || ptr < self.offset + self.size_bytes())
+    }
+
+    /// Returns an [AllocatorBuilder], which places the allocator inside this region
+    ///
+    /// The allocator is not allowed to grow beyond the region. After an upgrade, the allocator
+    /// should be retrieved with [reinit_allocator_at(region.offset())](crate::reinit_allocator_at).
+    ///
+    /// # Panics
+    /// Panics if this is not an [allocator region](RegionKind::Allocator).
+    pub fn allocator_builder(&self) -> AllocatorBuilder {
+        assert_eq!(self.kind, RegionKind::Allocator, "Not an allocator region");
+
+        let max_pages = if self.pages == 0 {
+            0
+        } else {
+            self.offset / PAGE_SIZE_BYTES + self.pages
+        };
+
+        AllocatorBuilder::new()
+            .base_offset(self.offset)
+            .max_pages(max_pages)
+    }
+
+    /// Reads bytes at `offset`, relative to the beginning of this region
+    ///
+    /// # Panics
+    /// Panics if this is an [allocator region](RegionKind::Allocator) or if the range is out of bounds.
+    pub fn read(&self, offset: u64, buf: &mut [u8]) {
+        self.check_bounds(offset, buf.len() as u64);
+
+        unsafe { crate::mem::read_bytes(self.offset + offset, buf) };
+    }
+
+    /// Writes bytes at `offset`, relative to the beginning of this region
+    ///
+    /// # Panics
+    /// Panics if this is an [allocator region](RegionKind::Allocator) or if the range is out of bounds.
+    pub fn write(&self, offset: u64, buf: &[u8]) {
+        self.check_bounds(offset, buf.len() as u64);
+
+        unsafe { crate::mem::write_bytes(self.offset + offset, buf) };
+    }
+
+    fn check_bounds(&self, offset: u64, len: u64) {
+        assert_ne!(
+            self.kind,
+            RegionKind::Allocator,
+            "Allocator region is managed by the allocator"
+        );
+        assert!(
+            offset + len <= self.size_bytes(),
+            "Out of bounds of region {}",
+            self.name
+        );
+    }
+}
+
+/// Indicates that a region directory can't be opened or a region can't be declared
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum RegionError {
+    /// Stable memory is not empty, but there is no directory in its first page
+    NoDirectory,
+    /// The directory was written by a version of this crate with a newer layout
+    IncompatibleVersion(IncompatibleVersion),
+    /// The directory can't be decoded
+    Corrupted(CorruptData),
+    /// A region with this name is already declared with another kind
+    KindMismatch {
+        /// Kind of the declared region
+        found: RegionKind,
+    },
+    /// A region with this name is already declared with another version, see [RegionDirectory::set_version]
+    VersionMismatch {
+        /// Version of the declared region
+        found: u32,
+    },
+    /// There is no region with this name
+    NotFound,
+    /// An allocator region is already declared, so no other region can be placed after it
+    AfterAllocator,
+    /// The directory doesn't fit into its page anymore
+    DirectoryFull,
+    /// It is impossible to grow stable memory to fit the region
+    OutOfMemory,
+}
+
+impl From<OutOfMemory> for RegionError {
+    #[inline]
+    fn from(_: OutOfMemory) -> Self {
+        Self::OutOfMemory
+    }
+}
+
+/// A list of named regions, persisted in the first page of stable memory
+///
+/// Every change is persisted immediately, so the directory doesn't need to be stored in
+/// `#[pre_upgrade]`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RegionDirectory {
+    regions: Vec<Region>,
+}
+
+impl RegionDirectory {
+    /// Creates a new directory, if stable memory is empty, or retrieves an existing one
+    pub fn open() -> Result<Self, RegionError> {
+        if stable::size_pages() == 0 {
+            Self::init()
+        } else {
+            Self::retrieve()
+        }
+    }
+
+    /// Creates an empty directory in the first page of stable memory
+    ///
+    /// # Panics
+    /// Panics if stable memory is not empty.
+    pub fn init() -> Result<Self, RegionError> {
+        assert_eq!(stable::size_pages(), 0, "Stable memory is not empty");
+
+        stable::grow(1)?;
+
+        let it = Self {
+            regions: Vec::new(),
+        };
+        it.store()?;
+
+        Ok(it)
+    }
+
+    /// Retrieves a directory, created by [RegionDirectory::init]
+    ///
+    /// Returns [RegionError::NoDirectory], if stable memory was written without a directory - e.g.
+    /// by [init_allocator](crate::init_allocator) at offset `0`.
+    pub fn retrieve() -> Result<Self, RegionError> {
+        if stable::size_pages() == 0 {
+            return Err(RegionError::NoDirectory);
+        }
+
+        let mut header = [0u8; HEADER_SIZE as usize];
+        unsafe { crate::mem::read_bytes(0, &mut header) };
+
+        if header[0..8] != DIRECTORY_MAGIC {
+            return Err(RegionError::NoDirectory);
+        }
+
+        let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        if version > DIRECTORY_VERSION {
+            return Err(RegionError::IncompatibleVersion(IncompatibleVersion {
+                found: version,
+                supported: DIRECTORY_VERSION,
+            }));
+        }
+
+        let len = u32::from_le_bytes(header[12..16].try_into().unwrap()) as u64;
+        if HEADER_SIZE + len > PAGE_SIZE_BYTES {
+            return Err(RegionError::Corrupted(CorruptData::new(
+                12,
+                "Directory is bigger than its page",
+            )));
+        }
+
+        let mut buf = vec![0u8; len as usize];
+        unsafe { crate::mem::read_bytes(HEADER_SIZE, &mut buf) };
+
+        let regions = candid_decode_one_allow_trailing(&buf).map_err(|_| {
+            RegionError::Corrupted(CorruptData::new(HEADER_SIZE, "Invalid directory encoding"))
+        })?;
+
+        Ok(Self { regions })
+    }
+
+    /// Returns a region with this name, declaring it first, if it doesn't exist yet
+    ///
+    /// A new region is placed right after the last one and stable memory is grown to fit it. An
+    /// [allocator region](RegionKind::Allocator) is only grown to its offset - the allocator grows it
+    /// further by itself, up to `pages` pages (`0` means no limit). Only one allocator region can be
+    /// declared and no regions can be declared after it.
+    ///
+    /// An existing region is returned as is, if its kind and version match - `pages` are ignored in
+    /// this case. Once the data inside a region is migrated, bump its version with
+    /// [RegionDirectory::set_version].
+    pub fn declare(
+        &mut self,
+        name: &str,
+        kind: RegionKind,
+        version: u32,
+        pages: u64,
+    ) -> Result<Region, RegionError> {
+        if let Some(region) = self.get(name) {
+            if region.kind != kind {
+                return Err(RegionError::KindMismatch { found: region.kind });
+            }
+
+            if region.version != version {
+                return Err(RegionError::VersionMismatch {
+                    found: region.version,
+                });
+            }
+
+            return Ok(region.clone());
+        }
+
+        let offset = match self.regions.last() {
+            Some(last) if last.kind == RegionKind::Allocator => {
+                return Err(RegionError::AfterAllocator)
+            }
+            Some(last) => last.offset + last.size_bytes(),
+            None => PAGE_SIZE_BYTES,
+        };
+
+        let region = Region {
+            name: String::from(name),
+            kind,
+            version,
+            offset,
+            pages,
+        };
+
+        let required_pages = if kind == RegionKind::Allocator {
+            offset / PAGE_SIZE_BYTES
+        } else {
+            offset / PAGE_SIZE_BYTES + pages
+        };
+
+        let grown_pages = stable::size_pages();
+        if grown_pages < required_pages {
+            stable::grow(required_pages - grown_pages)?;
+        }
+
+        self.regions.push(region.clone());
+        if let Err(e) = self.store() {
+            self.regions.pop();
+            return Err(e);
+        }
+
+        Ok(region)
+    }
+
+    /// Sets a new version of a region
+    pub fn set_version(&mut self, name: &str, version: u32) -> Result<(), RegionError> {
+        let region = self
+            .regions
+            .iter_mut()
+            .find(|it| it.name == name)
+            .ok_or(RegionError::NotFound)?;
+
+        region.version = version;
+
+        self.store()
+    }
+
+    /// Returns a region with this name, if it is declared
+    #[inline]
+    pub fn get(&self, name: &str) -> Option<&Region> {
+        self.regions.iter().find(|it| it.name == name)
+    }
+
+    /// Returns all regions in order of their offsets
+    #[inline]
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    /// Returns the region, the pointer belongs to
+    pub fn find(&self, ptr: StablePtr) -> Option<&Region> {
+        self.regions.iter().find(|it| it.contains(ptr))
+    }
+
+    fn store(&self) -> Result<(), RegionError> {
+        let buf = encode_one(&self.regions).unwrap();
+        if HEADER_SIZE + buf.len() as u64 > PAGE_SIZE_BYTES {
+            return Err(RegionError::DirectoryFull);
+        }
+
+        let mut header = [0u8; HEADER_SIZE as usize];
+        header[0..8].copy_from_slice(&DIRECTORY_MAGIC);
+        header[8..12].copy_from_slice(&DIRECTORY_VERSION.to_le_bytes());
+        header[12..16].copy_from_slice(&(buf.len() as u32).to_le_bytes());
+
+        unsafe {
+            crate::mem::write_bytes(0, &header);
+            crate::mem::write_bytes(HEADER_SIZE, &buf);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mem::allocator::StableMemoryAllocator;
+    use crate::mem::region::{RegionDirectory, RegionError, RegionKind};
+    use crate::{stable, PAGE_SIZE_BYTES};
+
+    #[test]
+    fn works_fine() {
+        stable::clear();
+
+        let mut dir = RegionDirectory::open().unwrap();
+        assert!(dir.regions().is_empty());
+
+        let raw = dir.declare("raw", RegionKind::Raw, 1, 2).unwrap();
+        assert_eq!(raw.offset(), PAGE_SIZE_BYTES);
+        assert_eq!(raw.size_bytes(), PAGE_SIZE_BYTES * 2);
+        assert_eq!(stable::size_pages(), 3);
+
+        let scratch = dir.declare("scratch", RegionKind::Scratch, 1, 1).unwrap();
+        assert_eq!(scratch.offset(), PAGE_SIZE_BYTES * 3);
+
+        let alloc = dir.declare("alloc", RegionKind::Allocator, 1, 10).unwrap();
+        assert_eq!(alloc.offset(), PAGE_SIZE_BYTES * 4);
+
+        assert_eq!(
+            dir.declare("other", RegionKind::Raw, 1, 1),
+            Err(RegionError::AfterAllocator)
+        );
+        assert_eq!(
+            dir.declare("raw", RegionKind::Scratch, 1, 2),
+            Err(RegionError::KindMismatch {
+                found: RegionKind::Raw
+            })
+        );
+        assert_eq!(
+            dir.declare("raw", RegionKind::Raw, 2, 2),
+            Err(RegionError::VersionMismatch { found: 1 })
+        );
+
+        raw.write(PAGE_SIZE_BYTES * 2 - 3, &[1, 2, 3]);
+
+        let mut sma = StableMemoryAllocator::init_with(alloc.allocator_builder());
+        let mut slices = Vec::new();
+        while let Ok(slice) = sma.allocate(1000) {
+            assert!(alloc.contains(slice.as_ptr()));
+            slices.push(slice);
+        }
+        assert_eq!(stable::size_pages(), 14);
+
+        for slice in slices {
+            sma.deallocate(slice);
+        }
+
+        let mut buf = [0u8; 3];
+        raw.read(PAGE_SIZE_BYTES * 2 - 3, &mut buf);
+        assert_eq!(buf, [1, 2, 3]);
+
+        dir.set_version("raw", 2).unwrap();
+        assert_eq!(dir.set_version("unknown", 1), Err(RegionError::NotFound));
+
+        let mut dir = RegionDirectory::open().unwrap();
+        assert_eq!(dir.regions().len(), 3);
+        assert_eq!(
+            dir.find(PAGE_SIZE_BYTES * 3 + 10).unwrap().name(),
+            "scratch"
+        );
+        assert!(dir.find(10).is_none());
+
+        let raw = dir.declare("raw", RegionKind::Raw, 2, 100).unwrap();
+        assert_eq!(raw.size_pages(), 2);
+    }
+
+    #[test]
+    fn no_directory_is_detected() {
+        stable::clear();
+        assert_eq!(RegionDirectory::retrieve(), Err(RegionError::NoDirectory));
+
+        stable::grow(1).unwrap();
+
+        assert_eq!(RegionDirectory::open(), Err(RegionError::NoDirectory));
+    }
+
+    #[test]
+    #[should_panic]
+    fn raw_access_is_bounded() {
+        stable::clear();
+
+        let mut dir = RegionDirectory::open().unwrap();
+        let raw = dir.declare("raw", RegionKind::Raw, 1, 1).unwrap();
+
+        raw.write(PAGE_SIZE_BYTES - 1, &[1, 2]);
+    }
+}
@@ -8,12 +8,84 @@
 use crate::encoding::{AsFixedSizeBytes, Buffer};
 use crate::mem::allocator::EMPTY_PTR;
 use crate::mem::free_block::FreeBlock;
+use crate::mem::read_guard::ReadGuard;
 use crate::mem::{StablePtr, StablePtrBuf};
 use crate::utils::mem_context::stable;
 
 pub(crate) const ALLOCATED: u64 = 2u64.pow(u64::BITS - 1); // first biggest bit set to 1, other set to 0
 pub(crate) const FREE: u64 = ALLOCATED - 1; // first biggest bit set to 0, other set to 1
 
+// with `debug_canaries` feature the data of each allocated block is surrounded by canaries, the
+// feature is recorded in the allocator's layout mode
+#[cfg(feature = "debug_canaries")]
+pub(crate) const CANARY_SIZE: u64 = 8;
+#[cfg(not(feature = "debug_canaries"))]
+pub(crate) const CANARY_SIZE: u64 = 0;
+
+// with `checksummed_headers` feature, 15 bits below the allocated flag hold a checksum of the rest
+// of the size word
+#[cfg(feature = "checksummed_headers")]
+const CHECKSUM_SHIFT: u32 = 48;
+#[cfg(feature = "checksummed_headers")]
+const SIZE_MASK: u64 = (1 << CHECKSUM_SHIFT) - 1;
+#[cfg(not(feature = "checksummed_headers"))]
+const SIZE_MASK: u64 = FREE;
+
+#[inline]
+pub(crate) fn encode_size_word(size: u64, allocated: bool) -> u64 {
+    debug_assert_eq!(size & !SIZE_MASK, 0);
+
+    let word = if allocated { size | ALLOCATED } else { size };
+
+    #[cfg(feature = "checksummed_headers")]
+    let word = word | (size_word_checksum(word) << CHECKSUM_SHIFT);
+
+    word
+}
+
+/// Returns the size and the allocated flag, or [None] if the checksum doesn't match
+#[inline]
+pub(crate) fn decode_size_word(word: u64) -> Option<(u64, bool)> {
+    let size = word & SIZE_MASK;
+    let allocated = word & ALLOCATED == ALLOCATED;
+
+    #[cfg(feature = "checksummed_headers")]
+    if (word & FREE) >> CHECKSUM_SHIFT != size_word_checksum(word & (SIZE_MASK | ALLOCATED)) {
+        return None;
+    }
+
+    Some((size, allocated))
+}
+
+/// Reads a size word of a memory block (either allocated or free)
+///
+/// # Panics
+/// Panics, if `checksummed_headers` feature is enabled and the size word is corrupted.
+#[inline]
+pub(crate) fn read_size_word(ptr: StablePtr) -> (u64, bool) {
+    let mut meta = StablePtrBuf::new(StablePtr::SIZE);
+    stable::read(ptr, &mut meta);
+
+    decode_size_word(u64::from_le_bytes(meta))
+        .unwrap_or_else(|| panic!("Corrupted memory block header at {}", ptr))
+}
+
+/// Writes both size words of a memory block (either allocated or free)
+#[inline]
+pub(crate) fn write_size_words(ptr: StablePtr, size: u64, allocated: bool) {
+    let meta = encode_size_word(size, allocated).to_le_bytes();
+
+    stable::write(ptr, &meta);
+    stable::write(ptr + (StablePtr::SIZE as u64) + size, &meta);
+}
+
+// zero words (untouched memory) should not pass the check
+#[cfg(feature = "checksummed_headers")]
+#[inline]
+fn size_word_checksum(word: u64) -> u64 {
+    (word ^ 0x5A5A_5A5A_5A5A_5A5A).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> (u64::BITS - 15)
+}
+
 /// An allocated block of stable memory.
 ///
 /// Represented by a pointer to the first byte of the memory block and a [u64] size of this block in
@@ -24,6 +96,16 @@ pub(crate) const FREE: u64 = ALLOCATED - 1; // first biggest bit set to 0, other
 /// - bytes `8..(size + 8)` - the data
 /// - bytes `(size + 8)..(size + 16)` - another `size` + `allocated bit flag`
 /// So, a memory block is simply `size` bytes of data wrapped with some metadata from both sides.
+///
+/// With `debug_canaries` feature enabled, the data is additionally surrounded by 8 bytes of canaries
+/// from both sides. These canaries are not included into [SSlice::get_size_bytes] and are skipped
+/// by [SSlice::offset].
+/// With `checksummed_headers` feature enabled, 15 bits right below the `allocated bit flag` hold a
+/// checksum of the rest of the `size` word. Reading a size word with a wrong checksum panics, so a stray
+/// write over a block's metadata is detected the next time this block is touched by the allocator.
+/// Both features change the layout of every memory block, so they are recorded together with the
+/// allocator's metadata and a reinit with any of them toggled fails, see
+/// [layout_mode](crate::mem::allocator::layout_mode).
 /// [FreeBlock](mem::free_block::FreeBlock) is stored exactly in a same way.
 #[derive(Debug, Copy, Clone)]
 pub struct SSlice {
@@ -91,13 +173,19 @@ impl SSlice {
     /// Returns the size of the data in this memory block in bytes.
     #[inline]
     pub fn get_size_bytes(&self) -> u64 {
-        self.size
+        self.size - CANARY_SIZE * 2
     }
 
     /// Returns the size of the whole memory block in bytes (including metadata).
     #[inline]
     pub fn get_total_size_bytes(&self) -> u64 {
-        self.get_size_bytes() + StablePtr::SIZE as u64 * 2
+        self.size + StablePtr::SIZE as u64 * 2
+    }
+
+    // the size of the memory block between its size words (including canaries)
+    #[inline]
+    pub(crate) fn get_block_size_bytes(&self) -> u64 {
+        self.size
     }
 
     /// Static analog of [SSlice::offset].
@@ -107,7 +195,7 @@ impl SSlice {
     pub fn _offset(self_ptr: u64, offset: u64) -> StablePtr {
         debug_assert_ne!(self_ptr, EMPTY_PTR);
 
-        self_ptr + (StablePtr::SIZE as u64) + offset
+        self_ptr + (StablePtr::SIZE as u64) + CANARY_SIZE + offset
     }
 
     /// Returns a pointer to the data inside [SSlice].
@@ -133,29 +221,102 @@ impl SSlice {
     #[inline]
     pub fn offset(&self, offset: u64) -> StablePtr {
         let ptr = Self::_offset(self.as_ptr(), offset);
-        assert!(ptr <= self.as_ptr() + StablePtr::SIZE as u64 + self.get_size_bytes());
+        assert!(ptr <= Self::_offset(self.as_ptr(), self.get_size_bytes()));
 
         ptr
     }
 
+    /// Reads `buf.len()` bytes of the data inside this memory block, starting from `offset`.
+    ///
+    /// Unlike reading the whole block at once, lets big blocks be processed chunk by chunk, with
+    /// a buffer of a fixed size. See also [SSliceCursor](crate::mem::cursor::SSliceCursor).
+    ///
+    /// # Panics
+    /// Panics if the chunk is not entirely inside the memory block.
+    ///
+    /// # Safety
+    /// Same as for [mem::read_bytes](crate::mem::read_bytes).
+    #[inline]
+    pub unsafe fn read_chunk(&self, offset: u64, buf: &mut [u8]) {
+        self.check_chunk(offset, buf.len() as u64);
+
+        crate::mem::read_bytes(Self::_offset(self.as_ptr(), offset), buf);
+    }
+
+    /// Writes `buf` into the data inside this memory block, starting from `offset`.
+    ///
+    /// See also [SSlice::read_chunk].
+    ///
+    /// # Panics
+    /// Panics if the chunk is not entirely inside the memory block.
+    ///
+    /// # Safety
+    /// Same as for [mem::write_bytes](crate::mem::write_bytes).
+    #[inline]
+    pub unsafe fn write_chunk(&self, offset: u64, buf: &[u8]) {
+        self.check_chunk(offset, buf.len() as u64);
+
+        crate::mem::write_bytes(Self::_offset(self.as_ptr(), offset), buf);
+    }
+
+    /// Copies `len` bytes of the data inside this memory block, starting from `offset`, into the
+    /// data inside `dst`, starting from `dst_offset`.
+    ///
+    /// The data never leaves stable memory as a whole, see [mem::copy_bytes](crate::mem::copy_bytes).
+    /// `dst` may be this same memory block, overlapping ranges are handled correctly.
+    ///
+    /// # Panics
+    /// Panics if any of the chunks is not entirely inside its memory block.
+    ///
+    /// # Safety
+    /// Same as for [mem::copy_bytes](crate::mem::copy_bytes).
+    #[inline]
+    pub unsafe fn copy_chunk_to(&self, offset: u64, dst: &SSlice, dst_offset: u64, len: u64) {
+        self.check_chunk(offset, len);
+        dst.check_chunk(dst_offset, len);
+
+        crate::mem::copy_bytes(
+            Self::_offset(self.as_ptr(), offset),
+            Self::_offset(dst.as_ptr(), dst_offset),
+            len,
+        );
+    }
+
+    /// Reads `len` bytes of the data inside this memory block, starting from `offset`, into a
+    /// pooled buffer.
+    ///
+    /// See [ReadGuard](crate::mem::read_guard::ReadGuard).
+    ///
+    /// # Panics
+    /// Panics if the chunk is not entirely inside the memory block.
+    ///
+    /// # Safety
+    /// Same as for [mem::read_bytes](crate::mem::read_bytes).
+    #[inline]
+    pub unsafe fn read_guard(&self, offset: u64, len: usize) -> ReadGuard<'_> {
+        self.check_chunk(offset, len as u64);
+
+        ReadGuard::new(Self::_offset(self.as_ptr(), offset), len)
+    }
+
+    #[inline]
+    fn check_chunk(&self, offset: u64, len: u64) {
+        assert!(
+            offset
+                .checked_add(len)
+                .map(|end| end <= self.get_size_bytes())
+                .unwrap_or_default(),
+            "Chunk is out of the memory block's bounds"
+        );
+    }
+
     #[inline]
     pub(crate) fn to_free_block(self) -> FreeBlock {
         FreeBlock::new(self.ptr, self.size)
     }
 
     fn read_size(ptr: StablePtr) -> Option<u64> {
-        let mut meta = StablePtrBuf::new(StablePtr::SIZE);
-        stable::read(ptr, &mut meta);
-
-        let encoded_size = u64::from_le_bytes(meta);
-        let mut size = encoded_size;
-
-        let allocated = if encoded_size & ALLOCATED == ALLOCATED {
-            size &= FREE;
-            true
-        } else {
-            false
-        };
+        let (size, allocated) = read_size_word(ptr);
 
         if allocated {
             Some(size)
@@ -164,13 +325,9 @@ impl SSlice {
         }
     }
 
+    #[inline]
     fn write_size(ptr: StablePtr, size: u64) {
-        let encoded_size = size | ALLOCATED;
-
-        let meta = encoded_size.to_le_bytes();
-
-        stable::write(ptr, &meta);
-        stable::write(ptr + (StablePtr::SIZE as u64) + size, &meta);
+        write_size_words(ptr, size, true);
     }
 }
 
@@ -181,6 +338,7 @@ mod tests {
     use crate::mem::s_slice::SSlice;
     use crate::mem::StablePtr;
     use crate::utils::mem_context::stable;
+    use crate::{allocate, deallocate, stable_memory_init, PAGE_SIZE_BYTES};
 
     #[test]
     fn read_write_work_fine() {
@@ -215,4 +373,59 @@ mod tests {
         assert_eq!(&b, &b1);
         assert_eq!(&c, &c1);
     }
+
+    #[test]
+    fn copy_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        let size = PAGE_SIZE_BYTES * 2 + 10;
+        let a = unsafe { allocate(size).unwrap() };
+        let b = unsafe { allocate(size).unwrap() };
+
+        let data = (0..size).map(|it| (it % 253) as u8).collect::<Vec<_>>();
+        unsafe { a.write_chunk(0, &data) };
+
+        let mut buf = vec![0u8; size as usize];
+
+        // between blocks, in several chunks
+        unsafe { a.copy_chunk_to(0, &b, 0, size) };
+        unsafe { b.read_chunk(0, &mut buf) };
+        assert_eq!(buf, data);
+
+        // overlapping, moving forward
+        unsafe { b.copy_chunk_to(0, &b, 100, size - 100) };
+        unsafe { b.read_chunk(100, &mut buf[..(size as usize - 100)]) };
+        assert_eq!(
+            &buf[..(size as usize - 100)],
+            &data[..(size as usize - 100)]
+        );
+
+        // overlapping, moving backward
+        unsafe { a.copy_chunk_to(100, &a, 0, size - 100) };
+        unsafe { a.read_chunk(0, &mut buf[..(size as usize - 100)]) };
+        assert_eq!(&buf[..(size as usize - 100)], &data[100..]);
+
+        let res = std::panic::catch_unwind(|| unsafe { a.copy_chunk_to(1, &b, 0, size) });
+        assert!(res.is_err());
+
+        deallocate(a);
+        deallocate(b);
+    }
+
+    #[cfg(feature = "checksummed_headers")]
+    #[test]
+    #[should_panic(expected = "Corrupted memory block header")]
+    fn corrupted_header_is_detected() {
+        stable::clear();
+        stable::grow(1).expect("Unable to grow");
+
+        let m1 = SSlice::new(MIN_PTR, 100, true);
+        assert!(unsafe { SSlice::from_ptr(m1.as_ptr()) }.is_some());
+
+        // a stray write, that changes the size
+        stable::write(MIN_PTR, &[200u8]);
+
+        unsafe { SSlice::from_ptr(m1.as_ptr()) };
+    }
 }
@@ -0,0 +1,246 @@
+//! Registry of slabs, used by [StableMemoryAllocator](crate::mem::allocator::StableMemoryAllocator)
+//! to store small fixed-size objects.
+//!
+//! A slab is an ordinary allocated memory block, which is carved into equal slots. Slots don't have
+//! their own metadata - instead, a slab keeps a bitmap of occupied slots in its first 8 bytes. So
+//! a slab can hold up to 64 slots.
+//!
+//! Pointers to slots are shifted in a way, that [SSlice::_offset] works for them the same way as it works
+//! for pointers to regular memory blocks. This way the code, which accesses an object, doesn't depend
+//! on whether the object is stored in a slot or in a regular memory block.
+//!
+//! Only used by the allocator itself. Not for public use.
+
+use crate::encoding::AsFixedSizeBytes;
+use crate::mem::s_slice::{SSlice, CANARY_SIZE};
+use crate::mem::StablePtr;
+use candid::{CandidType, Deserialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+// objects bigger than this are stored in regular memory blocks
+const MAX_SLOT_SIZE: u64 = 2048;
+const SLAB_SIZE: u64 = 8 * 1024;
+const MAX_SLOTS: u64 = u64::BITS as u64;
+const BITMAP_SIZE: u64 = u64::SIZE as u64;
+
+#[derive(Debug, Default, CandidType, Deserialize, Eq, PartialEq)]
+pub(crate) struct Slabs {
+    // slab pointer -> slot size
+    slabs: BTreeMap<StablePtr, u64>,
+    // slot size -> slabs with at least one free slot
+    partial: BTreeMap<u64, BTreeSet<StablePtr>>,
+}
+
+impl Slabs {
+    /// Returns [None], if objects of this size should be stored in regular memory blocks
+    #[inline]
+    pub fn slot_size(size: u64) -> Option<u64> {
+        if size > MAX_SLOT_SIZE {
+            None
+        } else {
+            Some(((size + 7) & !7).max(u64::SIZE as u64))
+        }
+    }
+
+    /// Size of a memory block, which should be allocated for a new slab
+    #[inline]
+    pub fn slab_size(slot_size: u64) -> u64 {
+        BITMAP_SIZE + Self::slots_per_slab(slot_size) * slot_size
+    }
+
+    #[inline]
+    pub fn find_partial(&self, slot_size: u64) -> Option<StablePtr> {
+        self.partial
+            .get(&slot_size)
+            .and_then(|it| it.iter().next().copied())
+    }
+
+    /// Total number of free slots of this size in all partial slabs
+    pub fn free_slots(&self, slot_size: u64) -> u64 {
+        self.partial.get(&slot_size).map_or(0, |it| {
+            it.iter()
+                .map(|&slab_ptr| {
+                    Self::slots_per_slab(slot_size)
+                        - Self::read_bitmap(slab_ptr).count_ones() as u64
+                })
+                .sum()
+        })
+    }
+
+    pub fn add(&mut self, slab: &SSlice, slot_size: u64) {
+        unsafe { crate::mem::write_fixed(slab.offset(0), &mut 0u64) };
+
+        self.slabs.insert(slab.as_ptr(), slot_size);
+        self.partial
+            .entry(slot_size)
+            .or_default()
+            .insert(slab.as_ptr());
+    }
+
+    /// Occupies a free slot of a partial slab and returns a pointer to it
+    pub fn take_slot(&mut self, slab_ptr: StablePtr, slot_size: u64) -> StablePtr {
+        let mut bitmap = Self::read_bitmap(slab_ptr);
+
+        let idx = (!bitmap).trailing_zeros() as u64;
+        debug_assert!(idx < Self::slots_per_slab(slot_size));
+
+        bitmap |= 1 << idx;
+        Self::write_bitmap(slab_ptr, bitmap);
+
+        if bitmap == Self::full_bitmap(slot_size) {
+            self.remove_partial(slab_ptr, slot_size);
+        }
+
+        Self::slot_ptr(slab_ptr, slot_size, idx)
+    }
+
+    /// Returns the slab and its slot size, if the pointer points to a slot
+    pub fn find(&self, ptr: StablePtr) -> Option<(StablePtr, u64)> {
+        let (&slab_ptr, &slot_size) = self.slabs.range(..=ptr).next_back()?;
+
+        Self::slot_idx(slab_ptr, slot_size, ptr).map(|_| (slab_ptr, slot_size))
+    }
+
+    /// Returns `true`, if the memory block is a slab
+    #[cfg(feature = "orphan_collector")]
+    #[inline]
+    pub fn is_slab(&self, ptr: StablePtr) -> bool {
+        self.slabs.contains_key(&ptr)
+    }
+
+    /// Returns `true`, if the slot is occupied
+    #[cfg(feature = "orphan_collector")]
+    pub fn is_occupied(&self, slab_ptr: StablePtr, slot_size: u64, ptr: StablePtr) -> bool {
+        match Self::slot_idx(slab_ptr, slot_size, ptr) {
+            Some(idx) => Self::read_bitmap(slab_ptr) & (1 << idx) != 0,
+            None => false,
+        }
+    }
+
+    /// Returns pointers to all occupied slots of all slabs
+    #[cfg(feature = "orphan_collector")]
+    pub fn occupied_slots(&self) -> Vec<StablePtr> {
+        let mut res = Vec::new();
+
+        for (&slab_ptr, &slot_size) in &self.slabs {
+            let bitmap = Self::read_bitmap(slab_ptr);
+
+            for idx in 0..Self::slots_per_slab(slot_size) {
+                if bitmap & (1 << idx) != 0 {
+                    res.push(Self::slot_ptr(slab_ptr, slot_size, idx));
+                }
+            }
+        }
+
+        res
+    }
+
+    /// Frees the slot, returns `true` if the slab became empty and was removed from the registry
+    pub fn release_slot(&mut self, slab_ptr: StablePtr, slot_size: u64, ptr: StablePtr) -> bool {
+        let idx = Self::slot_idx(slab_ptr, slot_size, ptr).unwrap();
+        let mut bitmap = Self::read_bitmap(slab_ptr);
+
+        assert_ne!(bitmap & (1 << idx), 0, "Slot {} is already free", ptr);
+
+        bitmap &= !(1 << idx);
+
+        if bitmap == 0 {
+            self.remove_partial(slab_ptr, slot_size);
+            self.slabs.remove(&slab_ptr);
+
+            return true;
+        }
+
+        Self::write_bitmap(slab_ptr, bitmap);
+        self.partial.entry(slot_size).or_default().insert(slab_ptr);
+
+        false
+    }
+
+    /// Updates the registry after the slab was moved, calls `on_relocate` for each occupied slot
+    ///
+    /// Returns `false`, if the moved memory block is not a slab.
+    pub fn relocate<F: FnMut(StablePtr, StablePtr)>(
+        &mut self,
+        old_ptr: StablePtr,
+        new_ptr: StablePtr,
+        on_relocate: &mut F,
+    ) -> bool {
+        let slot_size = match self.slabs.remove(&old_ptr) {
+            Some(s) => s,
+            None => return false,
+        };
+
+        self.slabs.insert(new_ptr, slot_size);
+
+        if let Some(partial) = self.partial.get_mut(&slot_size) {
+            if partial.remove(&old_ptr) {
+                partial.insert(new_ptr);
+            }
+        }
+
+        let bitmap = Self::read_bitmap(new_ptr);
+        for idx in 0..Self::slots_per_slab(slot_size) {
+            if bitmap & (1 << idx) != 0 {
+                on_relocate(
+                    Self::slot_ptr(old_ptr, slot_size, idx),
+                    Self::slot_ptr(new_ptr, slot_size, idx),
+                );
+            }
+        }
+
+        true
+    }
+
+    #[inline]
+    pub fn slots_per_slab(slot_size: u64) -> u64 {
+        (SLAB_SIZE / slot_size).clamp(1, MAX_SLOTS)
+    }
+
+    #[inline]
+    fn full_bitmap(slot_size: u64) -> u64 {
+        u64::MAX >> (MAX_SLOTS - Self::slots_per_slab(slot_size))
+    }
+
+    // SSlice::_offset(slot_ptr, 0) == address of the slot's first byte
+    #[inline]
+    fn slot_ptr(slab_ptr: StablePtr, slot_size: u64, idx: u64) -> StablePtr {
+        SSlice::_offset(slab_ptr, BITMAP_SIZE + idx * slot_size)
+            - StablePtr::SIZE as u64
+            - CANARY_SIZE
+    }
+
+    fn slot_idx(slab_ptr: StablePtr, slot_size: u64, ptr: StablePtr) -> Option<u64> {
+        let first = Self::slot_ptr(slab_ptr, slot_size, 0);
+        if ptr < first || (ptr - first) % slot_size != 0 {
+            return None;
+        }
+
+        let idx = (ptr - first) / slot_size;
+        if idx < Self::slots_per_slab(slot_size) {
+            Some(idx)
+        } else {
+            None
+        }
+    }
+
+    fn remove_partial(&mut self, slab_ptr: StablePtr, slot_size: u64) {
+        if let Some(partial) = self.partial.get_mut(&slot_size) {
+            partial.remove(&slab_ptr);
+
+            if partial.is_empty() {
+                self.partial.remove(&slot_size);
+            }
+        }
+    }
+
+    #[inline]
+    fn read_bitmap(slab_ptr: StablePtr) -> u64 {
+        unsafe { crate::mem::read_fixed_for_reference(SSlice::_offset(slab_ptr, 0)) }
+    }
+
+    #[inline]
+    fn write_bitmap(slab_ptr: StablePtr, mut bitmap: u64) {
+        unsafe { crate::mem::write_fixed(SSlice::_offset(slab_ptr, 0), &mut bitmap) };
+    }
+}
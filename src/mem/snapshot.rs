@@ -0,0 +1,405 @@
+//! Copy-on-write snapshots of the whole stable memory, for reads which take many messages
+//!
+//! A long export or an audit may need a consistent view of the heap, while update methods keep
+//! modifying it in between. A [HeapSnapshot] freezes stable memory as it was at
+//! [HeapSnapshot::take] without copying it: the memory is split into blocks of [SNAPSHOT_BLOCK_SIZE]
+//! bytes and only when a block is written for the first time, its original content is copied to a
+//! reserved memory block, the copy area. Reads through the snapshot return these copies for diverged
+//! blocks and live memory for all other ones. [HeapSnapshot::dispose] (or dropping the snapshot)
+//! releases the copy area, reclaiming all diverged blocks at once.
+//!
+//! Once the copy area is full, the snapshot is invalidated - updates always proceed, but any further
+//! read through the snapshot returns [SnapshotError::Invalidated].
+//!
+//! Only stable memory is frozen. Collections keep some of their state on the heap (e.g. their
+//! length), so inside [HeapSnapshot::view] collections should be re-read from stable memory, e.g.
+//! from [SBox](crate::SBox)-es, whose pointers were obtained before the snapshot was taken. Only one
+//! snapshot can exist at a time and snapshots don't survive upgrades - a copy area, left after an
+//! upgrade, is released by [release_stale].
+//!
+//! Only available with the `snapshots` feature.
+
+use crate::mem::s_slice::SSlice;
+use crate::mem::StablePtr;
+use crate::{allocate, deallocate, declare_root, get_root, remove_root, stable, PAGE_SIZE_BYTES};
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+
+/// Name of the root, the copy area of the current snapshot is stored under
+pub const SNAPSHOT_ROOT: &str = "__ic_stable_memory_snapshot";
+
+/// Size of a block, which is copied, when it diverges from the snapshot
+pub const SNAPSHOT_BLOCK_SIZE: u64 = 4096;
+
+struct ActiveSnapshot {
+    area: SSlice,
+    used: u64,
+    // size of stable memory at the moment the snapshot was taken, memory above is not tracked
+    size: u64,
+    // block index -> offset of its copy inside the copy area
+    blocks: BTreeMap<u64, u64>,
+    invalidated: bool,
+}
+
+thread_local! {
+    static SNAPSHOT: RefCell<Option<ActiveSnapshot>> = RefCell::new(None);
+    static TRACKING: Cell<bool> = Cell::new(false);
+    static VIEWING: Cell<bool> = Cell::new(false);
+}
+
+/// Indicates that a snapshot can't be taken or read
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SnapshotError {
+    /// Another snapshot is not disposed yet, see [release_stale]
+    InProgress,
+    /// There is not enough stable memory for the copy area
+    OutOfMemory,
+    /// The copy area got full, so the snapshot doesn't hold the original content of some blocks anymore
+    Invalidated,
+}
+
+/// A frozen view of stable memory, see the [module-level docs](crate::mem::snapshot)
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::mem::snapshot::HeapSnapshot;
+/// # use ic_stable_memory::{allocate, stable_memory_init};
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// let slice = unsafe { allocate(8).unwrap() };
+/// unsafe { slice.write_chunk(0, &100u64.to_le_bytes()) };
+///
+/// let snapshot = HeapSnapshot::take(1024 * 1024).expect("Unable to take a snapshot");
+/// unsafe { slice.write_chunk(0, &0u64.to_le_bytes()) };
+///
+/// let mut buf = [0u8; 8];
+/// snapshot.view(|| unsafe { slice.read_chunk(0, &mut buf) }).unwrap();
+/// assert_eq!(u64::from_le_bytes(buf), 100);
+///
+/// snapshot.dispose();
+/// ```
+#[derive(Debug)]
+pub struct HeapSnapshot {
+    disposed: bool,
+}
+
+impl HeapSnapshot {
+    /// Takes a snapshot of stable memory
+    ///
+    /// Allocates the copy area of `capacity` bytes (rounded up to the multiple of
+    /// [SNAPSHOT_BLOCK_SIZE]) and starts tracking every write to stable memory.
+    ///
+    /// # Errors
+    /// Returns [SnapshotError::InProgress], if there is another snapshot, and
+    /// [SnapshotError::OutOfMemory], if the copy area can't be allocated.
+    ///
+    /// # Panics
+    /// Panics if there is no initialized stable memory allocator.
+    pub fn take(capacity: u64) -> Result<Self, SnapshotError> {
+        if is_taken() {
+            return Err(SnapshotError::InProgress);
+        }
+
+        let capacity =
+            (capacity + SNAPSHOT_BLOCK_SIZE - 1) / SNAPSHOT_BLOCK_SIZE * SNAPSHOT_BLOCK_SIZE;
+        let area = unsafe { allocate(capacity) }.map_err(|_| SnapshotError::OutOfMemory)?;
+        declare_root(SNAPSHOT_ROOT, area.as_ptr());
+
+        SNAPSHOT.with(|it| {
+            *it.borrow_mut() = Some(ActiveSnapshot {
+                area,
+                used: 0,
+                size: stable::size_pages() * PAGE_SIZE_BYTES,
+                blocks: BTreeMap::new(),
+                invalidated: false,
+            })
+        });
+        TRACKING.with(|it| it.set(true));
+
+        Ok(Self { disposed: false })
+    }
+
+    /// Reads bytes at `ptr`, as they were when the snapshot was taken
+    ///
+    /// # Panics
+    /// Panics if the range is beyond the size of stable memory at the moment the snapshot was taken.
+    pub fn read(&self, ptr: StablePtr, buf: &mut [u8]) -> Result<(), SnapshotError> {
+        SNAPSHOT.with(|it| {
+            let it = it.borrow();
+            let snapshot = it.as_ref().unwrap();
+
+            if snapshot.invalidated {
+                return Err(SnapshotError::Invalidated);
+            }
+
+            snapshot.read(ptr, buf);
+
+            Ok(())
+        })
+    }
+
+    /// Executes `f`, making every read from stable memory inside it return the content of the
+    /// snapshot
+    ///
+    /// Stable memory is [read-only](crate::stable::read_only) inside `f`.
+    pub fn view<R, F: FnOnce() -> R>(&self, f: F) -> Result<R, SnapshotError> {
+        struct RestoreViewing(bool);
+
+        impl Drop for RestoreViewing {
+            fn drop(&mut self) {
+                VIEWING.with(|it| it.set(self.0));
+            }
+        }
+
+        if self.is_invalidated() {
+            return Err(SnapshotError::Invalidated);
+        }
+
+        let _restore = RestoreViewing(VIEWING.with(|it| it.replace(true)));
+
+        Ok(stable::read_only(f))
+    }
+
+    /// Returns `true`, if the copy area got full and the snapshot can't be read anymore
+    pub fn is_invalidated(&self) -> bool {
+        SNAPSHOT.with(|it| it.borrow().as_ref().unwrap().invalidated)
+    }
+
+    /// Returns the number of bytes, occupied by copies of diverged blocks
+    pub fn diverged_size(&self) -> u64 {
+        SNAPSHOT.with(|it| it.borrow().as_ref().unwrap().used)
+    }
+
+    /// Stops tracking writes and releases the copy area
+    #[inline]
+    pub fn dispose(mut self) {
+        self.disposed = true;
+        dispose();
+    }
+}
+
+impl Drop for HeapSnapshot {
+    fn drop(&mut self) {
+        if !self.disposed {
+            dispose();
+        }
+    }
+}
+
+impl ActiveSnapshot {
+    fn read(&self, ptr: StablePtr, buf: &mut [u8]) {
+        assert!(
+            ptr + buf.len() as u64 <= self.size,
+            "Out of bounds of the snapshot"
+        );
+
+        let mut read = 0usize;
+        while read < buf.len() {
+            let offset = ptr + read as u64;
+            let block = offset / SNAPSHOT_BLOCK_SIZE;
+            let in_block = offset % SNAPSHOT_BLOCK_SIZE;
+            let len = ((SNAPSHOT_BLOCK_SIZE - in_block) as usize).min(buf.len() - read);
+
+            let chunk = &mut buf[read..read + len];
+            match self.blocks.get(&block) {
+                Some(copy_offset) => unsafe { self.area.read_chunk(copy_offset + in_block, chunk) },
+                None => stable::read(offset, chunk),
+            }
+
+            read += len;
+        }
+    }
+
+    fn contains_area(&self, offset: u64, len: u64) -> bool {
+        let area_start = self.area.offset(0);
+
+        offset >= area_start && offset + len <= area_start + self.area.get_size_bytes()
+    }
+}
+
+/// Returns `true`, if there is a snapshot, which is not disposed yet
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator.
+pub fn is_taken() -> bool {
+    SNAPSHOT.with(|it| it.borrow().is_some()) || get_root(SNAPSHOT_ROOT).is_some()
+}
+
+/// Releases the copy area of a snapshot, which was not disposed before an upgrade
+///
+/// Should be called in `#[post_upgrade]`, right after [restore](crate::restore). Returns `true`, if
+/// a copy area was released.
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator.
+pub fn release_stale() -> bool {
+    if SNAPSHOT.with(|it| it.borrow().is_some()) {
+        return false;
+    }
+
+    match get_root(SNAPSHOT_ROOT) {
+        Some(ptr) => {
+            remove_root(SNAPSHOT_ROOT);
+            deallocate(unsafe { SSlice::from_ptr(ptr).expect("Corrupted snapshot copy area") });
+
+            true
+        }
+        None => false,
+    }
+}
+
+fn dispose() {
+    TRACKING.with(|it| it.set(false));
+
+    if let Some(snapshot) = SNAPSHOT.with(|it| it.borrow_mut().take()) {
+        remove_root(SNAPSHOT_ROOT);
+        deallocate(snapshot.area);
+    }
+}
+
+/// Copies blocks, overlapping `[offset, offset + len)`, to the copy area, before they are overwritten
+#[inline]
+pub(crate) fn before_write(offset: u64, len: u64) {
+    if len == 0 || !TRACKING.with(|it| it.get()) {
+        return;
+    }
+
+    SNAPSHOT.with(|it| {
+        let mut snapshot = it.borrow_mut();
+        let snapshot = match &mut *snapshot {
+            Some(snapshot) => snapshot,
+            None => return,
+        };
+
+        // writes to the copy area itself and to memory, grown after the snapshot, are not tracked
+        if offset >= snapshot.size || snapshot.contains_area(offset, len) {
+            return;
+        }
+
+        let first_block = offset / SNAPSHOT_BLOCK_SIZE;
+        let last_block = (offset + len - 1).min(snapshot.size - 1) / SNAPSHOT_BLOCK_SIZE;
+
+        TRACKING.with(|it| it.set(false));
+        for block in first_block..=last_block {
+            if snapshot.blocks.contains_key(&block) {
+                continue;
+            }
+
+            if snapshot.used + SNAPSHOT_BLOCK_SIZE > snapshot.area.get_size_bytes() {
+                // updates should never fail because of a snapshot, so the snapshot gives up instead
+                // and stops tracking writes
+                snapshot.invalidated = true;
+                snapshot.blocks.clear();
+
+                return;
+            }
+
+            let block_start = block * SNAPSHOT_BLOCK_SIZE;
+            let mut buf =
+                vec![0u8; (SNAPSHOT_BLOCK_SIZE.min(snapshot.size - block_start)) as usize];
+            stable::read(block_start, &mut buf);
+
+            unsafe { snapshot.area.write_chunk(snapshot.used, &buf) };
+            snapshot.blocks.insert(block, snapshot.used);
+            snapshot.used += SNAPSHOT_BLOCK_SIZE;
+        }
+        TRACKING.with(|it| it.set(true));
+    })
+}
+
+/// Reads bytes from the snapshot instead of live memory, if called inside [HeapSnapshot::view]
+///
+/// Returns `false`, if the read should go to live memory.
+#[inline]
+pub(crate) fn read_frozen(offset: u64, buf: &mut [u8]) -> bool {
+    if !VIEWING.with(|it| it.get()) {
+        return false;
+    }
+
+    VIEWING.with(|it| it.set(false));
+    SNAPSHOT.with(|it| it.borrow().as_ref().unwrap().read(offset, buf));
+    VIEWING.with(|it| it.set(true));
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mem::snapshot::{
+        is_taken, release_stale, HeapSnapshot, SnapshotError, SNAPSHOT_BLOCK_SIZE,
+    };
+    use crate::{
+        _debug_validate_allocator, allocate, deallocate, get_allocated_size, stable,
+        stable_memory_init,
+    };
+
+    #[test]
+    fn works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let a = unsafe { allocate(SNAPSHOT_BLOCK_SIZE * 3).unwrap() };
+            unsafe { a.write_chunk(0, &[1u8; 4096 * 3]) };
+
+            let snapshot = HeapSnapshot::take(SNAPSHOT_BLOCK_SIZE * 10).unwrap();
+            assert!(is_taken());
+            assert_eq!(
+                HeapSnapshot::take(100).unwrap_err(),
+                SnapshotError::InProgress
+            );
+
+            unsafe { a.write_chunk(10, &[2u8; 100]) };
+            let b = unsafe { allocate(100).unwrap() };
+            deallocate(a);
+
+            let mut buf = [0u8; 100];
+            snapshot.read(a.offset(10), &mut buf).unwrap();
+            assert_eq!(buf, [1u8; 100]);
+
+            let frozen = snapshot
+                .view(|| {
+                    let mut buf = [0u8; 4096 * 3];
+                    unsafe { a.read_chunk(0, &mut buf) };
+                    buf
+                })
+                .unwrap();
+            assert_eq!(frozen, [1u8; 4096 * 3]);
+            assert!(snapshot.diverged_size() > 0);
+
+            snapshot.dispose();
+            assert!(!is_taken());
+            deallocate(b);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn full_area_invalidates_snapshot() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let a = unsafe { allocate(SNAPSHOT_BLOCK_SIZE * 4).unwrap() };
+
+            let snapshot = HeapSnapshot::take(SNAPSHOT_BLOCK_SIZE).unwrap();
+            unsafe { a.write_chunk(0, &[1u8; 4096 * 4]) };
+
+            assert!(snapshot.is_invalidated());
+            assert_eq!(
+                snapshot.read(a.offset(0), &mut [0u8; 10]),
+                Err(SnapshotError::Invalidated)
+            );
+            assert_eq!(snapshot.view(|| ()), Err(SnapshotError::Invalidated));
+
+            drop(snapshot);
+            assert!(!release_stale());
+            deallocate(a);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
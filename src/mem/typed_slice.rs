@@ -0,0 +1,294 @@
+//! Typed views over memory blocks with a fixed layout.
+//!
+//! Instead of hand-computing byte offsets (like `KEYS_OFFSET + idx * K::SIZE`), a layout is declared
+//! once as a chain of [Field]-s and [ArrayField]-s, each of which knows its type and offset. Fields
+//! are then accessed with [TypedSlice::read_field] and [TypedSlice::write_field].
+//!
+//! # Example
+//! ```rust
+//! # use ic_stable_memory::{allocate, deallocate, mem, stable_memory_init};
+//! # use ic_stable_memory::mem::typed_slice::{ArrayField, Field, TypedSlice};
+//! # unsafe { mem::clear(); }
+//! # stable_memory_init();
+//! struct Node;
+//!
+//! // the layout is: [len: u32, is_leaf: bool, keys: [u64; 8]]
+//! const LEN: Field<u32> = Field::first();
+//! const IS_LEAF: Field<bool> = LEN.next();
+//! const KEYS: ArrayField<u64> = IS_LEAF.next_array();
+//! const NODE_SIZE: u64 = KEYS.end(8);
+//!
+//! let slice = unsafe { allocate(NODE_SIZE).expect("Out of memory") };
+//! let node = unsafe { TypedSlice::<Node>::from_ptr(slice.as_ptr()) };
+//!
+//! node.write_field(LEN, 1);
+//! node.write_field(IS_LEAF, true);
+//! node.write_field(KEYS.get(0), 42);
+//!
+//! assert_eq!(node.read_field(LEN), 1);
+//! assert_eq!(*node.field_ref(KEYS.get(0)), 42);
+//!
+//! deallocate(slice);
+//! ```
+
+use crate::encoding::AsFixedSizeBytes;
+use crate::mem::s_slice::SSlice;
+use crate::mem::StablePtr;
+use crate::primitive::s_ref::SRef;
+use crate::primitive::s_ref_mut::SRefMut;
+use crate::primitive::StableType;
+use std::marker::PhantomData;
+
+/// A field of type `T` at a fixed offset of a layout.
+pub struct Field<T> {
+    offset: u64,
+    _marker: PhantomData<T>,
+}
+
+impl<T> Clone for Field<T> {
+    #[inline]
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Field<T> {}
+
+impl<T: AsFixedSizeBytes> Field<T> {
+    /// The field at the very beginning of the layout.
+    #[inline]
+    pub const fn first() -> Self {
+        Self::at(0)
+    }
+
+    #[inline]
+    pub const fn at(offset: u64) -> Self {
+        Self {
+            offset,
+            _marker: PhantomData,
+        }
+    }
+
+    /// The field right after this one.
+    #[inline]
+    pub const fn next<U: AsFixedSizeBytes>(self) -> Field<U> {
+        Field::at(self.end())
+    }
+
+    /// The array right after this field.
+    #[inline]
+    pub const fn next_array<U: AsFixedSizeBytes>(self) -> ArrayField<U> {
+        ArrayField::at(self.end())
+    }
+
+    #[inline]
+    pub const fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Returns the offset of the first byte after this field.
+    #[inline]
+    pub const fn end(&self) -> u64 {
+        self.offset + T::SIZE as u64
+    }
+}
+
+/// An array of fields of type `T`, starting at a fixed offset of a layout.
+///
+/// The length of the array is not a part of the layout, so it can be used for variable-sized tails.
+pub struct ArrayField<T> {
+    offset: u64,
+    _marker: PhantomData<T>,
+}
+
+impl<T> Clone for ArrayField<T> {
+    #[inline]
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for ArrayField<T> {}
+
+impl<T: AsFixedSizeBytes> ArrayField<T> {
+    #[inline]
+    pub const fn at(offset: u64) -> Self {
+        Self {
+            offset,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the `idx`-th element of this array.
+    #[inline]
+    pub const fn get(&self, idx: u64) -> Field<T> {
+        Field::at(self.offset + idx * T::SIZE as u64)
+    }
+
+    /// The array right after the `len` elements of this one.
+    #[inline]
+    pub const fn next_array<U: AsFixedSizeBytes>(self, len: u64) -> ArrayField<U> {
+        ArrayField::at(self.end(len))
+    }
+
+    /// The field right after the `len` elements of this array.
+    #[inline]
+    pub const fn next<U: AsFixedSizeBytes>(self, len: u64) -> Field<U> {
+        Field::at(self.end(len))
+    }
+
+    #[inline]
+    pub const fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Returns the offset of the first byte after the `len` elements of this array.
+    #[inline]
+    pub const fn end(&self, len: u64) -> u64 {
+        self.offset + len * T::SIZE as u64
+    }
+}
+
+/// A memory block, the data of which has the layout of `L`.
+///
+/// `L` is only a marker (usually the type, that owns the memory block), which prevents typed slices
+/// of different layouts from being mixed up. Like an [SSlice], it's a simple [Copy] pointer, so
+/// using it after the memory block is deallocated is undefined behavior.
+pub struct TypedSlice<L> {
+    ptr: StablePtr,
+    _marker: PhantomData<L>,
+}
+
+impl<L> Clone for TypedSlice<L> {
+    #[inline]
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<L> Copy for TypedSlice<L> {}
+
+impl<L> TypedSlice<L> {
+    /// Creates a typed slice from a pointer to a memory block.
+    ///
+    /// # Safety
+    /// Make sure the pointer points to a memory block (see [SSlice::as_ptr]), big enough for the
+    /// layout.
+    #[inline]
+    pub unsafe fn from_ptr(ptr: StablePtr) -> Self {
+        Self {
+            ptr,
+            _marker: PhantomData,
+        }
+    }
+
+    #[inline]
+    pub fn as_ptr(&self) -> StablePtr {
+        self.ptr
+    }
+
+    /// Returns a pointer to the field, which can be used with [read_bytes](crate::mem::read_bytes)
+    /// and other raw functions.
+    #[inline]
+    pub fn field_ptr<T: AsFixedSizeBytes>(&self, field: Field<T>) -> StablePtr {
+        SSlice::_offset(self.ptr, field.offset())
+    }
+
+    /// Reads the field, without taking the ownership of its data.
+    ///
+    /// See [read_fixed_for_reference](crate::mem::read_fixed_for_reference).
+    #[inline]
+    pub fn read_field<T: StableType + AsFixedSizeBytes>(&self, field: Field<T>) -> T {
+        unsafe { crate::mem::read_fixed_for_reference(self.field_ptr(field)) }
+    }
+
+    /// Writes the field, transferring the ownership of `it` to stable memory.
+    ///
+    /// The previous value of the field is not dropped.
+    #[inline]
+    pub fn write_field<T: StableType + AsFixedSizeBytes>(&self, field: Field<T>, mut it: T) {
+        unsafe { crate::mem::write_fixed(self.field_ptr(field), &mut it) };
+    }
+
+    /// Reads the field, taking the ownership of its data.
+    ///
+    /// # Safety
+    /// Same as for [read_fixed_for_move](crate::mem::read_fixed_for_move).
+    #[inline]
+    pub unsafe fn take_field<T: StableType + AsFixedSizeBytes>(&self, field: Field<T>) -> T {
+        crate::mem::read_fixed_for_move(self.field_ptr(field))
+    }
+
+    /// Returns a lazy immutable reference to the field.
+    #[inline]
+    pub fn field_ref<T: StableType + AsFixedSizeBytes>(&self, field: Field<T>) -> SRef<'_, T> {
+        unsafe { SRef::new(self.field_ptr(field)) }
+    }
+
+    /// Returns a lazy mutable reference to the field.
+    #[inline]
+    pub fn field_mut<T: StableType + AsFixedSizeBytes>(
+        &mut self,
+        field: Field<T>,
+    ) -> SRefMut<'_, T> {
+        unsafe { SRefMut::new(self.field_ptr(field)) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::SVec;
+    use crate::mem::typed_slice::{ArrayField, Field, TypedSlice};
+    use crate::mem::StablePtr;
+    use crate::{allocate, deallocate, get_allocated_size, stable, stable_memory_init};
+
+    struct Header;
+
+    const FLAG: Field<u8> = Field::first();
+    const PTR: Field<StablePtr> = FLAG.next();
+    const VALUES: ArrayField<u32> = PTR.next_array();
+    const TAIL: Field<SVec<u64>> = VALUES.next(3);
+
+    #[test]
+    fn works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        assert_eq!(PTR.offset(), 1);
+        assert_eq!(VALUES.get(2).offset(), 17);
+        assert_eq!(TAIL.offset(), 21);
+
+        let slice = unsafe { allocate(TAIL.end()).unwrap() };
+        let mut header = unsafe { TypedSlice::<Header>::from_ptr(slice.as_ptr()) };
+
+        header.write_field(FLAG, 7);
+        header.write_field(PTR, 100);
+        for i in 0..3 {
+            header.write_field(VALUES.get(i), i as u32 * 10);
+        }
+
+        let mut vec = SVec::new();
+        vec.push(1u64).unwrap();
+        header.write_field(TAIL, vec);
+
+        assert_eq!(header.read_field(FLAG), 7);
+        assert_eq!(header.read_field(PTR), 100);
+        assert_eq!(*header.field_ref(VALUES.get(1)), 10);
+
+        *header.field_mut(VALUES.get(2)) = 25;
+        assert_eq!(header.read_field(VALUES.get(2)), 25);
+
+        assert_eq!(*header.field_ref(TAIL).get(0).unwrap(), 1);
+
+        let mut buf = [0u8; 4];
+        unsafe { crate::mem::read_bytes(header.field_ptr(VALUES.get(2)), &mut buf) };
+        assert_eq!(u32::from_le_bytes(buf), 25);
+
+        let vec = unsafe { header.take_field(TAIL) };
+        assert_eq!(vec.len(), 1);
+        drop(vec);
+
+        deallocate(slice);
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
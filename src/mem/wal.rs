@@ -0,0 +1,514 @@
+//! Write-ahead log, which makes multi-collection updates atomic across awaits
+//!
+//! A trap rolls back only the current message. If an update method modifies some collections,
+//! awaits an inter-canister call and then traps in the callback, changes made before the `await`
+//! stay. A [Txn] fixes that: while it is open, each write to stable memory first appends the
+//! overwritten bytes (an undo entry) to a reserved memory block, the log. Together with the state of
+//! the allocator at the beginning of the transaction, the log is enough to bring stable memory back
+//! to that point. [Txn::commit] simply releases the log, so a transaction is durable at once.
+//!
+//! An open transaction locks stable memory. Only writes made inside [Txn::run] belong to the
+//! transaction and get logged. Any other write - e.g. by another message, which runs while the
+//! transaction awaits - panics, so that message traps and is rolled back by the IC, instead of having
+//! its update silently reverted by a later rollback of the transaction. Update methods, which should
+//! fail gracefully, can check [is_in_progress] first. Reads are not locked and see uncommitted data.
+//!
+//! A transaction, which was never committed, is rolled back automatically:
+//! * when its [Txn] is dropped (including when `ic-cdk` cleans up the future of a trapped callback);
+//! * by [persist](crate::persist), since the new code can't resume it after an upgrade.
+//!
+//! [recover] does the same manually, e.g. for a [Txn], which was leaked with [std::mem::forget].
+//!
+//! Only stable memory and the allocator are rolled back, heap memory is not. So the state, changed
+//! within a transaction, should be re-read from stable memory afterwards - e.g. kept in
+//! [stable variables](crate::stable_var), rather than in `thread_local!` collections. Arenas (see
+//! [create_arena](crate::create_arena)) are not supported.
+//!
+//! Only available with the `wal` feature.
+
+use crate::encoding::AsFixedSizeBytes;
+use crate::mem::s_slice::SSlice;
+use crate::mem::StablePtr;
+use crate::{
+    allocate, deallocate, declare_root, export_meta, get_root, import_meta, remove_root, stable,
+};
+use std::cell::{Cell, RefCell};
+
+/// Name of the root, the log of an unfinished transaction is stored under
+pub const WAL_ROOT: &str = "__ic_stable_memory_wal";
+
+/// Default capacity of the log, see [set_wal_capacity]
+pub const DEFAULT_WAL_CAPACITY: u64 = 1024 * 1024;
+
+// header of the log: [used bytes, meta size, meta], followed by [offset, len, bytes] entries
+const HEADER_SIZE: u64 = (u64::SIZE * 2) as u64;
+const ENTRY_HEADER_SIZE: u64 = (u64::SIZE * 2) as u64;
+
+struct ActiveLog {
+    slice: SSlice,
+    used: u64,
+}
+
+thread_local! {
+    static LOG: RefCell<Option<ActiveLog>> = RefCell::new(None);
+    static LOGGING: Cell<bool> = Cell::new(false);
+    static ENTERED: Cell<bool> = Cell::new(false);
+    static CAPACITY: Cell<u64> = Cell::new(DEFAULT_WAL_CAPACITY);
+}
+
+/// Indicates that a transaction can't be started
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TxnError {
+    /// Another transaction is not finished yet, see [recover]
+    InProgress,
+    /// There is not enough stable memory for the log
+    OutOfMemory,
+}
+
+/// An open transaction
+///
+/// Locks stable memory until it is committed or rolled back, see the
+/// [module-level docs](crate::mem::wal).
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::collections::SBTreeMap;
+/// # use ic_stable_memory::mem::wal::Txn;
+/// # use ic_stable_memory::{stable_memory_init, stable_var};
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// stable_var!(BALANCES: SBTreeMap<u64, u64>);
+///
+/// BALANCES::with_mut(|it| it.insert(1, 100)).unwrap().unwrap();
+///
+/// let mut txn = Txn::begin().expect("Unable to begin a transaction");
+/// txn.run(|| {
+///     BALANCES::with_mut(|it| it.insert(1, 0)).unwrap().unwrap();
+///     BALANCES::with_mut(|it| it.insert(2, 100)).unwrap().unwrap();
+/// });
+///
+/// // an inter-canister call fails, or the callback traps
+/// txn.abort();
+///
+/// assert_eq!(BALANCES::with(|it| *it.get(&1).unwrap()), 100);
+/// assert!(BALANCES::with(|it| !it.contains_key(&2)));
+/// ```
+#[derive(Debug)]
+pub struct Txn {
+    finished: bool,
+}
+
+impl Txn {
+    /// Starts a transaction
+    ///
+    /// Allocates the log of [set_wal_capacity] bytes (plus the allocator state) and locks stable
+    /// memory for writes, made outside of [Txn::run].
+    ///
+    /// # Errors
+    /// Returns [TxnError::InProgress], if there is an unfinished transaction, and
+    /// [TxnError::OutOfMemory], if the log can't be allocated.
+    ///
+    /// # Panics
+    /// Panics if there is no initialized stable memory allocator.
+    pub fn begin() -> Result<Self, TxnError> {
+        if is_in_progress() {
+            return Err(TxnError::InProgress);
+        }
+
+        let capacity = CAPACITY.with(|it| it.get());
+        let mut meta_size = export_meta().len() as u64;
+
+        // the log itself changes the allocator state, so its size is only known after it is allocated
+        let (slice, meta) = loop {
+            let slice = unsafe { allocate(HEADER_SIZE + meta_size * 2 + capacity) }
+                .map_err(|_| TxnError::OutOfMemory)?;
+            declare_root(WAL_ROOT, slice.as_ptr());
+
+            let meta = export_meta();
+            if (meta.len() as u64) <= meta_size * 2 {
+                break (slice, meta);
+            }
+
+            remove_root(WAL_ROOT);
+            deallocate(slice);
+            meta_size = meta.len() as u64;
+        };
+
+        let used = HEADER_SIZE + meta.len() as u64;
+
+        unsafe {
+            slice.write_chunk(0, &used.to_le_bytes());
+            slice.write_chunk(u64::SIZE as u64, &(meta.len() as u64).to_le_bytes());
+            slice.write_chunk(HEADER_SIZE, &meta);
+        }
+
+        LOG.with(|it| *it.borrow_mut() = Some(ActiveLog { slice, used }));
+        LOGGING.with(|it| it.set(true));
+
+        Ok(Self { finished: false })
+    }
+
+    /// Runs the function as a part of this transaction
+    ///
+    /// Writes, made by the function, are logged, so they can be rolled back later. The function should
+    /// not await - the next part of the transaction should be run with another call.
+    ///
+    /// # Panics
+    /// Panics if the log is full, see [set_wal_capacity].
+    pub fn run<R, F: FnOnce() -> R>(&mut self, f: F) -> R {
+        let entered = ENTERED.with(|it| it.replace(true));
+        let res = f();
+        ENTERED.with(|it| it.set(entered));
+
+        res
+    }
+
+    /// Makes all changes, made since [Txn::begin], permanent
+    pub fn commit(mut self) {
+        self.finished = true;
+
+        if let Some(log) = stop_logging() {
+            remove_root(WAL_ROOT);
+            deallocate(log.slice);
+        }
+    }
+
+    /// Rolls back all changes, made since [Txn::begin]
+    pub fn abort(mut self) {
+        self.finished = true;
+        recover();
+    }
+}
+
+impl Drop for Txn {
+    fn drop(&mut self) {
+        if !self.finished {
+            recover();
+        }
+    }
+}
+
+/// Runs the function inside a transaction, which is committed, if the function returns [Ok], and
+/// rolled back, if it returns [Err]
+///
+/// Useful to make several fallible operations atomic, e.g. insertions into several collections,
+/// any of which can fail with [OutOfMemory](crate::OutOfMemory).
+///
+/// # Errors
+/// Returns [TxnError], if the transaction can't be started. In that case the function is not called.
+pub fn txn<R, E, F: FnOnce() -> Result<R, E>>(f: F) -> Result<Result<R, E>, TxnError> {
+    let mut t = Txn::begin()?;
+
+    let res = t.run(f);
+    if res.is_ok() {
+        t.commit();
+    } else {
+        t.abort();
+    }
+
+    Ok(res)
+}
+
+/// Rolls back an unfinished transaction, if there is one
+///
+/// Returns `true`, if a transaction was rolled back.
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator, or if the log is corrupted.
+pub fn recover() -> bool {
+    let slice = match stop_logging() {
+        Some(log) => log.slice,
+        None => match get_root(WAL_ROOT) {
+            Some(ptr) => unsafe { SSlice::from_ptr(ptr).expect("Corrupted write-ahead log") },
+            None => return false,
+        },
+    };
+
+    let mut word = [0u8; u64::SIZE];
+
+    unsafe { slice.read_chunk(0, &mut word) };
+    let used = u64::from_le_bytes(word);
+    unsafe { slice.read_chunk(u64::SIZE as u64, &mut word) };
+    let meta_size = u64::from_le_bytes(word);
+
+    let mut meta = vec![0u8; meta_size as usize];
+    unsafe { slice.read_chunk(HEADER_SIZE, &mut meta) };
+
+    let mut entries = Vec::new();
+    let mut offset = HEADER_SIZE + meta_size;
+
+    while offset < used {
+        unsafe { slice.read_chunk(offset, &mut word) };
+        let ptr = StablePtr::from_le_bytes(word);
+        unsafe { slice.read_chunk(offset + u64::SIZE as u64, &mut word) };
+        let len = u64::from_le_bytes(word);
+
+        entries.push((ptr, offset + ENTRY_HEADER_SIZE, len));
+        offset += ENTRY_HEADER_SIZE + len;
+    }
+
+    // the same bytes may be overwritten several times, the oldest version is applied last
+    for (ptr, entry_offset, len) in entries.into_iter().rev() {
+        let mut buf = vec![0u8; len as usize];
+        unsafe { slice.read_chunk(entry_offset, &mut buf) };
+
+        stable::write(ptr, &buf);
+    }
+
+    unsafe { import_meta(&meta).expect("Corrupted write-ahead log") };
+
+    // the restored allocator still has the log allocated
+    remove_root(WAL_ROOT);
+    deallocate(slice);
+
+    true
+}
+
+/// Returns `true`, if there is an unfinished transaction
+///
+/// Stable memory can't be written outside of [Txn::run], while this function returns `true`.
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator.
+pub fn is_in_progress() -> bool {
+    LOG.with(|it| it.borrow().is_some()) || get_root(WAL_ROOT).is_some()
+}
+
+/// Sets the capacity of the log (in bytes) for the next transactions
+///
+/// Each write to stable memory inside a transaction takes 16 bytes of the log plus its own size.
+/// Once the log is full, the next write inside [Txn::run] panics. Only the message, which runs the
+/// transaction, traps this way - the transaction itself is then rolled back, once its [Txn] is
+/// dropped.
+///
+/// Default is [DEFAULT_WAL_CAPACITY].
+pub fn set_wal_capacity(bytes: u64) {
+    CAPACITY.with(|it| it.set(bytes));
+}
+
+/// Appends the bytes at `[offset, offset + len)` to the log, before they are overwritten
+///
+/// Panics, if the write is made outside of [Txn::run], while a transaction is open.
+#[inline]
+pub(crate) fn before_write(offset: u64, len: u64) {
+    if len == 0 || !LOGGING.with(|it| it.get()) {
+        return;
+    }
+
+    // the log can't tell messages apart, so a write of another message is rejected, instead of
+    // being rolled back together with the transaction
+    if !ENTERED.with(|it| it.get()) {
+        panic!("Stable memory is locked by an unfinished transaction, see is_in_progress()");
+    }
+
+    LOG.with(|it| {
+        let mut log = it.borrow_mut();
+        let log = match &mut *log {
+            Some(log) => log,
+            None => return,
+        };
+
+        // writes to the log itself are not logged
+        let log_start = log.slice.offset(0);
+        if offset >= log_start && offset + len <= log_start + log.slice.get_size_bytes() {
+            return;
+        }
+
+        let new_used = log.used + ENTRY_HEADER_SIZE + len;
+        if new_used > log.slice.get_size_bytes() {
+            panic!("Write-ahead log is full, increase its capacity with set_wal_capacity()");
+        }
+
+        let mut buf = vec![0u8; len as usize];
+        stable::read(offset, &mut buf);
+
+        LOGGING.with(|it| it.set(false));
+        unsafe {
+            log.slice.write_chunk(log.used, &offset.to_le_bytes());
+            log.slice
+                .write_chunk(log.used + u64::SIZE as u64, &len.to_le_bytes());
+            log.slice.write_chunk(log.used + ENTRY_HEADER_SIZE, &buf);
+
+            // the entry is only counted, once it is complete
+            log.slice.write_chunk(0, &new_used.to_le_bytes());
+        }
+        LOGGING.with(|it| it.set(true));
+
+        log.used = new_used;
+    })
+}
+
+fn stop_logging() -> Option<ActiveLog> {
+    LOGGING.with(|it| it.set(false));
+    ENTERED.with(|it| it.set(false));
+    LOG.with(|it| it.borrow_mut().take())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::{SBTreeMap, SVec};
+    use crate::mem::wal::{is_in_progress, recover, set_wal_capacity, txn, Txn, TxnError};
+    use crate::{
+        _debug_validate_allocator, check_consistency, get_allocated_size, persist, restore, stable,
+        stable_memory_init, stable_var,
+    };
+    use std::panic::catch_unwind;
+
+    stable_var!(WAL_TEST_MAP: SBTreeMap<u64, u64>);
+    stable_var!(WAL_TEST_VEC: SVec<u64>);
+
+    fn snapshot() -> (Vec<(u64, u64)>, Vec<u64>) {
+        (
+            WAL_TEST_MAP::with(|it| it.iter().map(|(k, v)| (*k, *v)).collect()),
+            WAL_TEST_VEC::with(|it| it.iter().map(|it| *it).collect()),
+        )
+    }
+
+    fn update(from: u64, to: u64) {
+        for i in from..to {
+            WAL_TEST_MAP::with_mut(|it| it.insert(i, i * 2).unwrap()).unwrap();
+            WAL_TEST_VEC::with_mut(|it| it.push(i).unwrap()).unwrap();
+        }
+    }
+
+    #[test]
+    fn works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        update(0, 100);
+        let before = snapshot();
+        let allocated_size = get_allocated_size();
+
+        // aborted
+        let mut t = Txn::begin().unwrap();
+        assert!(is_in_progress());
+        assert_eq!(Txn::begin().unwrap_err(), TxnError::InProgress);
+
+        t.run(|| update(100, 1000));
+        t.run(|| {
+            update(1000, 2000);
+            WAL_TEST_MAP::with_mut(|it| it.remove(&5)).unwrap();
+        });
+        t.abort();
+
+        assert!(!is_in_progress());
+        assert_eq!(snapshot(), before);
+        assert_eq!(get_allocated_size(), allocated_size);
+        check_consistency().unwrap();
+        WAL_TEST_MAP::with(|it| it.validate()).unwrap();
+
+        // dropped
+        {
+            let mut t = Txn::begin().unwrap();
+            t.run(|| update(100, 200));
+        }
+        assert_eq!(snapshot(), before);
+
+        // committed
+        let mut t = Txn::begin().unwrap();
+        t.run(|| update(100, 200));
+        t.commit();
+
+        assert!(!is_in_progress());
+        let after = snapshot();
+        assert_eq!(after.0.len(), 200);
+
+        // txn()
+        let res = txn(|| {
+            update(200, 300);
+            Err::<(), _>("insufficient funds")
+        });
+        assert_eq!(res, Ok(Err("insufficient funds")));
+        assert_eq!(snapshot(), after);
+
+        let res = txn(|| {
+            update(200, 300);
+            Ok::<_, ()>(())
+        });
+        assert_eq!(res, Ok(Ok(())));
+        assert_eq!(snapshot().0.len(), 300);
+
+        WAL_TEST_MAP::take();
+        WAL_TEST_VEC::take();
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn rolled_back_on_upgrade() {
+        stable::clear();
+        restore();
+
+        update(0, 100);
+        let before = snapshot();
+
+        // the transaction is left unfinished, e.g. an upgrade happens during an inter-canister call
+        let mut t = Txn::begin().unwrap();
+        t.run(|| update(100, 1000));
+        std::mem::forget(t);
+
+        persist();
+        restore();
+
+        assert!(!is_in_progress());
+        assert!(!recover());
+
+        assert_eq!(snapshot(), before);
+        check_consistency().unwrap();
+
+        WAL_TEST_MAP::take();
+        WAL_TEST_VEC::take();
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn rejects_writes_of_other_messages() {
+        stable::clear();
+        stable_memory_init();
+
+        update(0, 10);
+        let before = snapshot();
+
+        let mut t = Txn::begin().unwrap();
+        t.run(|| update(10, 20));
+
+        // another message runs during an `await` of the transaction and writes stable memory
+        assert!(is_in_progress());
+        assert!(catch_unwind(|| WAL_TEST_VEC::with_mut(|it| it.push(1000).unwrap())).is_err());
+
+        t.abort();
+        assert_eq!(snapshot(), before);
+        check_consistency().unwrap();
+
+        // once the transaction is finished, other messages can write again
+        WAL_TEST_VEC::with_mut(|it| it.push(1000).unwrap()).unwrap();
+        assert!(WAL_TEST_VEC::with(|it| it.iter().any(|it| *it == 1000)));
+
+        WAL_TEST_MAP::take();
+        WAL_TEST_VEC::take();
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn overflow_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        update(0, 10);
+        let before = snapshot();
+
+        set_wal_capacity(1024);
+
+        let mut t = Txn::begin().unwrap();
+        assert!(catch_unwind(move || t.run(|| update(10, 1000))).is_err());
+
+        assert_eq!(snapshot(), before);
+        check_consistency().unwrap();
+    }
+}
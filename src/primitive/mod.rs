@@ -1,16 +1,34 @@
 //! Smart-pointers and [StableType] trait
 
+use crate::OutOfMemory;
 use candid::{Int, Nat, Principal};
 use serde_bytes::ByteBuf;
 use std::collections::{BTreeSet, HashSet};
 use ic_ledger_types::Subaccount;
+use std::time::Duration;
 
 /// [SBox] smart-pointer that allows storing dynamically-sized data to stable memory
 pub mod s_box;
 
+/// [SCandid](s_candid::SCandid) smart-pointer that allows storing any Candid value to stable memory
+pub mod s_candid;
+
+/// [SRc] smart-pointer that allows sharing dynamically-sized data between several stable structures
+pub mod s_rc;
+
+/// [SStrKey](s_str_key::SStrKey) fixed-capacity string, that can be used as a key of stable maps
+pub mod s_str_key;
+
 /// Immutable reference to fixed size data on stable memory
 pub mod s_ref;
 
+/// [SPtr](s_ptr::SPtr) typed pointer to a fixed size value on stable memory
+pub mod s_ptr;
+
+/// [SRefCell](s_ref_cell::SRefCell) runtime-borrow-checked cell, that allows sharing a stable structure
+/// between several call paths
+pub mod s_ref_cell;
+
 /// Mutable reference to fixed size data on stable memory
 pub mod s_ref_mut;
 
@@ -162,6 +180,31 @@ pub trait StableType {
     unsafe fn stable_drop(&mut self) {}
 }
 
+/// Creates an independent copy of a value, including all the stable memory it owns.
+///
+/// Stable collections and [SBox] are simply pointers to stable memory, so copying their bytes (e.g.
+/// with [AsFixedSizeBytes::as_new_fixed_size_bytes](crate::AsFixedSizeBytes::as_new_fixed_size_bytes))
+/// produces a second owner of the same memory, which leads to a double free, once both are dropped.
+/// Deep copy allocates new memory blocks instead and copies every element into them, also deep
+/// copying the elements. Useful for keeping a backup of a collection before a risky migration.
+///
+/// Implemented for any [Clone] type, for [SBox] and for all collections, which elements implement
+/// this trait. [SRc](crate::SRc) is [Clone], so its deep copy shares the same value.
+pub trait DeepCopy: Sized {
+    /// Returns a deep copy of this value
+    ///
+    /// If there is not enough stable memory, returns [OutOfMemory]. All the memory allocated for the
+    /// copy so far is released in that case.
+    fn deep_copy(&self) -> Result<Self, OutOfMemory>;
+}
+
+impl<T: Clone> DeepCopy for T {
+    #[inline]
+    fn deep_copy(&self) -> Result<Self, OutOfMemory> {
+        Ok(self.clone())
+    }
+}
+
 impl StableType for () {}
 impl StableType for bool {}
 impl StableType for u8 {}
@@ -182,6 +225,7 @@ impl StableType for char {}
 impl StableType for Principal {}
 impl StableType for Nat {}
 impl StableType for Int {}
+impl StableType for Duration {}
 
 impl<const N: usize> StableType for [(); N] {}
 impl<const N: usize> StableType for [bool; N] {}
@@ -329,6 +373,75 @@ impl<A: StableType, B: StableType, C: StableType, D: StableType, E: StableType,
     }
 }
 
+impl<
+        A: StableType,
+        B: StableType,
+        C: StableType,
+        D: StableType,
+        E: StableType,
+        F: StableType,
+        G: StableType,
+    > StableType for (A, B, C, D, E, F, G)
+{
+    #[inline]
+    unsafe fn stable_drop_flag_on(&mut self) {
+        self.0.stable_drop_flag_on();
+        self.1.stable_drop_flag_on();
+        self.2.stable_drop_flag_on();
+        self.3.stable_drop_flag_on();
+        self.4.stable_drop_flag_on();
+        self.5.stable_drop_flag_on();
+        self.6.stable_drop_flag_on();
+    }
+
+    #[inline]
+    unsafe fn stable_drop_flag_off(&mut self) {
+        self.0.stable_drop_flag_off();
+        self.1.stable_drop_flag_off();
+        self.2.stable_drop_flag_off();
+        self.3.stable_drop_flag_off();
+        self.4.stable_drop_flag_off();
+        self.5.stable_drop_flag_off();
+        self.6.stable_drop_flag_off();
+    }
+}
+
+impl<
+        A: StableType,
+        B: StableType,
+        C: StableType,
+        D: StableType,
+        E: StableType,
+        F: StableType,
+        G: StableType,
+        H: StableType,
+    > StableType for (A, B, C, D, E, F, G, H)
+{
+    #[inline]
+    unsafe fn stable_drop_flag_on(&mut self) {
+        self.0.stable_drop_flag_on();
+        self.1.stable_drop_flag_on();
+        self.2.stable_drop_flag_on();
+        self.3.stable_drop_flag_on();
+        self.4.stable_drop_flag_on();
+        self.5.stable_drop_flag_on();
+        self.6.stable_drop_flag_on();
+        self.7.stable_drop_flag_on();
+    }
+
+    #[inline]
+    unsafe fn stable_drop_flag_off(&mut self) {
+        self.0.stable_drop_flag_off();
+        self.1.stable_drop_flag_off();
+        self.2.stable_drop_flag_off();
+        self.3.stable_drop_flag_off();
+        self.4.stable_drop_flag_off();
+        self.5.stable_drop_flag_off();
+        self.6.stable_drop_flag_off();
+        self.7.stable_drop_flag_off();
+    }
+}
+
 impl StableType for String {}
 impl StableType for Vec<u8> {}
 impl StableType for Vec<i8> {}
@@ -1,8 +1,12 @@
 use crate::encoding::{AsDynSizeBytes, AsFixedSizeBytes};
+#[cfg(feature = "orphan_collector")]
+use crate::mem::orphan_collector::Trace;
 use crate::mem::s_slice::SSlice;
-use crate::primitive::StableType;
+#[cfg(feature = "orphan_collector")]
+use crate::mem::StablePtr;
+use crate::primitive::{DeepCopy, StableType};
 use crate::utils::certification::{AsHashTree, AsHashableBytes, HashTree};
-use crate::{allocate, deallocate, reallocate, OutOfMemory};
+use crate::{allocate, deallocate, reallocate, retain, OutOfMemory};
 use candid::types::{Serializer, Type, TypeId};
 use candid::CandidType;
 use serde::{Deserialize, Deserializer};
@@ -58,7 +62,7 @@ impl<T: AsDynSizeBytes + StableType> SBox<T> {
     /// Returns `Err` and the data, if the canister is `OutOfMemory`.
     #[inline]
     pub fn new(mut it: T) -> Result<Self, T> {
-        let buf = it.as_dyn_size_bytes();
+        let buf = pack(it.as_dyn_size_bytes());
         if let Ok(slice) = unsafe { allocate(buf.len() as u64) } {
             unsafe {
                 crate::mem::write_bytes(slice.offset(0), &buf);
@@ -83,6 +87,43 @@ impl<T: AsDynSizeBytes + StableType> SBox<T> {
         self.slice.unwrap().as_ptr()
     }
 
+    /// Returns one more owner of the same stable memory, without copying the data
+    ///
+    /// The underlying memory block is only released, once all its owners are dropped (see
+    /// [retain](crate::retain)). Useful to store the same large value in multiple collections, e.g.
+    /// with [SBTreeMap::insert_shared](crate::collections::SBTreeMap::insert_shared).
+    ///
+    /// A shared [SBox] should not be mutated: changes are visible to all owners and growing the
+    /// value panics.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use ic_stable_memory::{get_ref_count, SBox, stable_memory_init};
+    /// # unsafe { ic_stable_memory::mem::clear(); }
+    /// # stable_memory_init();
+    /// let a = SBox::new(String::from("big value")).expect("Out of memory");
+    /// let b = a.share();
+    ///
+    /// drop(a);
+    ///
+    /// assert_eq!(get_ref_count(b.as_ptr()), 1);
+    /// assert_eq!(b.as_str(), "big value");
+    /// ```
+    ///
+    /// # Panics
+    /// Panics if there is no initialized stable memory allocator.
+    pub fn share(&self) -> Self {
+        let ptr = self.as_ptr();
+        retain(ptr).expect("SBox points to a memory block, which is not allocated");
+
+        unsafe {
+            let mut it = Self::from_ptr(ptr);
+            it.stable_drop_flag_on();
+
+            it
+        }
+    }
+
     /// Returns the underlying data, releasing occupied stable memory.
     #[inline]
     pub fn into_inner(mut self) -> T {
@@ -183,7 +224,7 @@ impl<T: AsDynSizeBytes + StableType> SBox<T> {
         let mut buf = vec![0u8; slice.get_size_bytes() as usize];
         unsafe { crate::mem::read_bytes(slice.offset(0), &mut buf) };
 
-        let mut inner = T::from_dyn_size_bytes(&buf);
+        let mut inner = T::from_dyn_size_bytes(&unpack(buf));
         if drop_flag {
             inner.stable_drop_flag_on();
         } else {
@@ -195,7 +236,7 @@ impl<T: AsDynSizeBytes + StableType> SBox<T> {
 
     fn repersist(&mut self) -> Result<(), OutOfMemory> {
         let mut slice = self.slice.take().unwrap();
-        let buf = self.inner.get_mut().as_ref().unwrap().as_dyn_size_bytes();
+        let buf = pack(self.inner.get_mut().as_ref().unwrap().as_dyn_size_bytes());
 
         unsafe { self.inner.get_mut().stable_drop_flag_off() };
 
@@ -219,6 +260,59 @@ impl<T: AsDynSizeBytes + StableType> SBox<T> {
     }
 }
 
+/// Values, which encoding is bigger than this number of bytes, get compressed before they are
+/// written to stable memory.
+///
+/// Only available with the `compression` feature.
+#[cfg(feature = "compression")]
+pub const COMPRESSION_THRESHOLD_BYTES: usize = 256;
+
+// with `compression` feature each payload starts with a u32 header, holding the length of the
+// LZ4-compressed data that follows it, or 0, if the payload is stored as is
+#[cfg(feature = "compression")]
+fn pack(buf: Vec<u8>) -> Vec<u8> {
+    let compressed = if buf.len() > COMPRESSION_THRESHOLD_BYTES {
+        Some(lz4_flex::compress_prepend_size(&buf)).filter(|it| it.len() < buf.len())
+    } else {
+        None
+    };
+
+    let (len, data) = match compressed {
+        Some(it) => (it.len() as u32, it),
+        None => (0u32, buf),
+    };
+
+    let mut res = Vec::with_capacity(u32::SIZE + data.len());
+    res.extend_from_slice(&len.as_new_fixed_size_bytes());
+    res.extend(data);
+
+    res
+}
+
+#[cfg(feature = "compression")]
+fn unpack(buf: Vec<u8>) -> Vec<u8> {
+    let len = u32::from_fixed_size_bytes(&buf[..u32::SIZE]) as usize;
+    let data = &buf[u32::SIZE..];
+
+    if len == 0 {
+        return data.to_vec();
+    }
+
+    lz4_flex::decompress_size_prepended(&data[..len]).expect("Unable to decompress a value")
+}
+
+#[cfg(not(feature = "compression"))]
+#[inline]
+fn pack(buf: Vec<u8>) -> Vec<u8> {
+    buf
+}
+
+#[cfg(not(feature = "compression"))]
+#[inline]
+fn unpack(buf: Vec<u8>) -> Vec<u8> {
+    buf
+}
+
 impl<T: AsDynSizeBytes + StableType> AsFixedSizeBytes for SBox<T> {
     const SIZE: usize = u64::SIZE;
     type Buf = [u8; u64::SIZE];
@@ -258,6 +352,13 @@ impl<T: AsDynSizeBytes + StableType> StableType for SBox<T> {
     }
 }
 
+impl<T: AsDynSizeBytes + StableType + DeepCopy> DeepCopy for SBox<T> {
+    #[inline]
+    fn deep_copy(&self) -> Result<Self, OutOfMemory> {
+        Self::new(self.deref().deep_copy()?).map_err(|_| OutOfMemory)
+    }
+}
+
 impl<T: AsDynSizeBytes + StableType> Drop for SBox<T> {
     fn drop(&mut self) {
         unsafe {
@@ -408,6 +509,19 @@ impl<T: AsDynSizeBytes + StableType> Deref for SBox<T> {
     }
 }
 
+#[cfg(feature = "orphan_collector")]
+impl<T: AsDynSizeBytes + StableType + Trace> Trace for SBox<T> {
+    fn trace(&self, ptrs: &mut Vec<StablePtr>) {
+        if let Some(slice) = &self.slice {
+            ptrs.push(slice.as_ptr());
+        }
+
+        if T::OWNS_MEMORY {
+            self.deref().trace(ptrs);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::collections::SVec;
@@ -529,4 +643,41 @@ mod tests {
             assert_eq!(bytes.len(), 17);
         }
     }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compression_works_fine() {
+        use crate::primitive::s_box::COMPRESSION_THRESHOLD_BYTES;
+
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let text = "Some pretty repetitive text. ".repeat(100);
+            let mut b = SBox::new(text.clone()).unwrap();
+
+            assert!(get_allocated_size() < text.len() as u64 / 2);
+
+            store_custom_data(0, b);
+            b = retrieve_custom_data(0).unwrap();
+
+            assert_eq!(*b, text);
+
+            b.with(|it| *it = String::from("short")).unwrap();
+            assert_eq!(b.as_str(), "short");
+
+            store_custom_data(0, b);
+            b = retrieve_custom_data(0).unwrap();
+
+            assert_eq!(b.as_str(), "short");
+
+            let long = "a".repeat(COMPRESSION_THRESHOLD_BYTES * 10);
+            b.with(|it| *it = long.clone()).unwrap();
+
+            assert_eq!(b.into_inner(), long);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
 }
@@ -0,0 +1,292 @@
+use crate::encoding::dyn_size::candid_decode_one_allow_trailing;
+use crate::encoding::{AsDynSizeBytes, AsFixedSizeBytes};
+#[cfg(feature = "orphan_collector")]
+use crate::mem::orphan_collector::Trace;
+#[cfg(feature = "orphan_collector")]
+use crate::mem::StablePtr;
+use crate::primitive::s_box::SBox;
+use crate::primitive::StableType;
+use crate::OutOfMemory;
+use candid::types::{Serializer, Type};
+use candid::CandidType;
+use serde::de::DeserializeOwned;
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::fmt::{Debug, Formatter};
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+
+/// Smart-pointer that stores any [CandidType] value on stable memory, encoding it with Candid.
+///
+/// Works exactly like [SBox], but doesn't require `T` to implement neither [StableType] nor
+/// [AsDynSizeBytes] - the value is Candid-encoded into an allocated block of stable memory, when
+/// [SCandid] is created or updated, and lazily decoded back, when it is accessed. [SCandid] implements
+/// [AsFixedSizeBytes] and [StableType], so you can put your application structs into any other stable
+/// structure (e.g. [SBTreeMap](crate::collections::SBTreeMap)) without deriving fixed-size layouts for them.
+///
+/// Since `T` can't own any stable memory itself, it should not contain other stable structures.
+///
+/// # Examples
+/// ```rust
+/// # use ic_stable_memory::{stable_memory_init, SCandid};
+/// # use ic_stable_memory::collections::SBTreeMap;
+/// # use candid::{CandidType, Deserialize};
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// #[derive(CandidType, Deserialize)]
+/// struct User {
+///     name: String,
+///     tags: Vec<String>,
+/// }
+///
+/// let mut users = SBTreeMap::<u64, SCandid<User>>::new();
+///
+/// let user = User { name: String::from("Sasha"), tags: vec![] };
+/// users.insert(1, SCandid::new(user).expect("Out of memory")).expect("Out of memory");
+///
+/// let mut user = users.get_mut(&1).unwrap();
+/// user.with(|it| it.tags.push(String::from("admin"))).expect("Out of memory");
+///
+/// assert_eq!(users.get(&1).unwrap().tags, vec![String::from("admin")]);
+/// ```
+pub struct SCandid<T: CandidType + DeserializeOwned>(SBox<CandidValue<T>>);
+
+impl<T: CandidType + DeserializeOwned> SCandid<T> {
+    /// Candid-encodes the value and stores it on stable memory.
+    ///
+    /// Returns `Err` and the value, if the canister is `OutOfMemory`.
+    ///
+    /// # Panics
+    /// Panics if the value can't be Candid-encoded.
+    #[inline]
+    pub fn new(it: T) -> Result<Self, T> {
+        SBox::new(CandidValue(it)).map(Self).map_err(|it| it.0)
+    }
+
+    /// Returns a pointer to the underlying block of stable memory.
+    ///
+    /// See also [SCandid::from_ptr].
+    #[inline]
+    pub fn as_ptr(&self) -> u64 {
+        self.0.as_ptr()
+    }
+
+    /// Returns the decoded value, releasing occupied stable memory.
+    #[inline]
+    pub fn into_inner(self) -> T {
+        self.0.into_inner().0
+    }
+
+    /// Creates [SCandid] from a pointer to the underlying block of stable memory.
+    ///
+    /// See also [SCandid::as_ptr].
+    ///
+    /// # Safety
+    /// Same as for [SBox::from_ptr].
+    #[inline]
+    pub unsafe fn from_ptr(ptr: u64) -> Self {
+        Self(SBox::from_ptr(ptr))
+    }
+
+    /// Provides mutable access to the decoded value, by accepting a lambda function.
+    ///
+    /// The value gets re-encoded after the function returns. Returns [OutOfMemory] error if it
+    /// was impossible to reallocate the underlying block of stable memory to make it bigger.
+    #[inline]
+    pub fn with<R, F: FnOnce(&mut T) -> R>(&mut self, func: F) -> Result<R, OutOfMemory> {
+        self.0.with(|it| func(&mut it.0))
+    }
+}
+
+impl<T: CandidType + DeserializeOwned> AsFixedSizeBytes for SCandid<T> {
+    const SIZE: usize = u64::SIZE;
+    type Buf = [u8; u64::SIZE];
+
+    #[inline]
+    fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+        self.0.as_fixed_size_bytes(buf)
+    }
+
+    #[inline]
+    fn from_fixed_size_bytes(arr: &[u8]) -> Self {
+        Self(SBox::from_fixed_size_bytes(arr))
+    }
+}
+
+impl<T: CandidType + DeserializeOwned> StableType for SCandid<T> {
+    #[inline]
+    fn should_stable_drop(&self) -> bool {
+        self.0.should_stable_drop()
+    }
+
+    #[inline]
+    unsafe fn stable_drop_flag_off(&mut self) {
+        self.0.stable_drop_flag_off();
+    }
+
+    #[inline]
+    unsafe fn stable_drop_flag_on(&mut self) {
+        self.0.stable_drop_flag_on();
+    }
+
+    #[inline]
+    unsafe fn stable_drop(&mut self) {
+        self.0.stable_drop();
+    }
+}
+
+impl<T: CandidType + DeserializeOwned> CandidType for SCandid<T> {
+    #[inline]
+    fn _ty() -> Type {
+        T::_ty()
+    }
+
+    #[inline]
+    fn idl_serialize<S>(&self, serializer: S) -> Result<(), S::Error>
+    where
+        S: Serializer,
+    {
+        self.deref().idl_serialize(serializer)
+    }
+}
+
+impl<T: PartialEq + CandidType + DeserializeOwned> PartialEq for SCandid<T> {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        self.deref().eq(other.deref())
+    }
+}
+
+impl<T: PartialOrd + CandidType + DeserializeOwned> PartialOrd for SCandid<T> {
+    #[inline]
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.deref().partial_cmp(other.deref())
+    }
+}
+
+impl<T: Eq + PartialEq + CandidType + DeserializeOwned> Eq for SCandid<T> {}
+
+impl<T: Ord + PartialOrd + CandidType + DeserializeOwned> Ord for SCandid<T> {
+    #[inline]
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.deref().cmp(other.deref())
+    }
+}
+
+impl<T: Hash + CandidType + DeserializeOwned> Hash for SCandid<T> {
+    #[inline]
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.deref().hash(state)
+    }
+}
+
+impl<T: Debug + CandidType + DeserializeOwned> Debug for SCandid<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SCandid(")?;
+        self.deref().fmt(f)?;
+        f.write_str(")")
+    }
+}
+
+impl<T: CandidType + DeserializeOwned> Borrow<T> for SCandid<T> {
+    #[inline]
+    fn borrow(&self) -> &T {
+        self.deref()
+    }
+}
+
+impl<T: CandidType + DeserializeOwned> Deref for SCandid<T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        &self.0.deref().0
+    }
+}
+
+/// Candid-encoded value, as it is stored inside [SCandid]'s [SBox]
+struct CandidValue<T>(T);
+
+impl<T: CandidType + DeserializeOwned> AsDynSizeBytes for CandidValue<T> {
+    #[inline]
+    fn as_dyn_size_bytes(&self) -> Vec<u8> {
+        candid::encode_one(&self.0).expect("Unable to encode a Candid value")
+    }
+
+    #[inline]
+    fn from_dyn_size_bytes(buf: &[u8]) -> Self {
+        Self(candid_decode_one_allow_trailing(buf).expect("Unable to decode a Candid value"))
+    }
+}
+
+impl<T> StableType for CandidValue<T> {}
+
+// Candid values never own stable memory
+#[cfg(feature = "orphan_collector")]
+impl<T: CandidType + DeserializeOwned> Trace for SCandid<T> {
+    #[inline]
+    fn trace(&self, ptrs: &mut Vec<StablePtr>) {
+        ptrs.push(self.as_ptr());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::SBTreeMap;
+    use crate::primitive::s_candid::SCandid;
+    use crate::{_debug_validate_allocator, get_allocated_size, stable, stable_memory_init};
+    use candid::{CandidType, Deserialize};
+
+    #[derive(CandidType, Deserialize, Debug, PartialEq)]
+    struct User {
+        name: String,
+        tags: Vec<String>,
+        balance: Option<u128>,
+    }
+
+    #[test]
+    fn works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut map = SBTreeMap::<u64, SCandid<User>>::new();
+
+            for i in 0..100u64 {
+                let user = User {
+                    name: format!("user {}", i),
+                    tags: vec![],
+                    balance: None,
+                };
+
+                map.insert(i, SCandid::new(user).unwrap()).unwrap();
+            }
+
+            for i in 0..100u64 {
+                let mut user = map.get_mut(&i).unwrap();
+                user.with(|it| {
+                    it.tags
+                        .push(String::from("some pretty long tag to force reallocation"));
+                    it.balance = Some(i as u128);
+                })
+                .unwrap();
+            }
+
+            for i in 0..100u64 {
+                let user = map.get(&i).unwrap();
+
+                assert_eq!(user.name, format!("user {}", i));
+                assert_eq!(user.tags.len(), 1);
+                assert_eq!(user.balance, Some(i as u128));
+            }
+
+            let user = map.remove(&10).unwrap().into_inner();
+            assert_eq!(user.name, "user 10");
+
+            _debug_validate_allocator();
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
@@ -0,0 +1,258 @@
+use crate::encoding::AsFixedSizeBytes;
+use crate::mem::allocator::AllocError;
+#[cfg(feature = "orphan_collector")]
+use crate::mem::orphan_collector::Trace;
+use crate::mem::s_slice::SSlice;
+use crate::mem::StablePtr;
+use crate::primitive::StableType;
+use crate::{allocate, check_ptr, deallocate};
+use std::fmt::{Debug, Formatter};
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+
+/// Typed pointer to a fixed size value in stable memory
+///
+/// Unlike a raw [StablePtr], it knows the type of the value and checks, that the memory block it
+/// points to is still allocated (see [check_ptr](crate::check_ptr)), before every access. Access to
+/// the value is only possible inside a closure (or by copying it out with [SPtr::read]), so no
+/// decoded handle outlives the access. This makes it suitable for storing cross-references between
+/// collections: [SPtr] is [Copy] and implements [AsFixedSizeBytes] and [StableType].
+///
+/// [SPtr] doesn't own the value - it is only released with [SPtr::free].
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::collections::SBTreeMap;
+/// # use ic_stable_memory::{stable_memory_init, SPtr};
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// let balance = SPtr::new(100u64).expect("Out of memory");
+///
+/// let mut by_user = SBTreeMap::<u64, SPtr<u64>>::new();
+/// by_user.insert(1, balance).expect("Out of memory");
+///
+/// by_user.get(&1).unwrap().map_mut(|it| *it += 10).unwrap();
+/// assert_eq!(balance.read().unwrap(), 110);
+///
+/// assert_eq!(balance.free().unwrap(), 110);
+/// assert!(by_user.get(&1).unwrap().read().is_err());
+/// ```
+pub struct SPtr<T> {
+    ptr: StablePtr,
+    _marker: PhantomData<T>,
+}
+
+impl<T> SPtr<T> {
+    /// Creates a typed pointer from a raw one
+    ///
+    /// The pointer is checked on each access, so this function is safe.
+    #[inline]
+    pub const fn from_raw(ptr: StablePtr) -> Self {
+        Self {
+            ptr,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the raw pointer
+    #[inline]
+    pub const fn as_raw(&self) -> StablePtr {
+        self.ptr
+    }
+}
+
+impl<T: StableType + AsFixedSizeBytes> SPtr<T> {
+    /// Allocates a memory block and moves the value into it
+    ///
+    /// Returns `Err` and the value, if the canister is `OutOfMemory`.
+    pub fn new(mut it: T) -> Result<Self, T> {
+        if let Ok(slice) = unsafe { allocate(T::SIZE as u64) } {
+            unsafe { crate::mem::write_fixed(slice.offset(0), &mut it) };
+
+            Ok(Self::from_raw(slice.as_ptr()))
+        } else {
+            Err(it)
+        }
+    }
+
+    /// Returns [true], if the pointer points to an allocated memory block, which can fit the value
+    #[inline]
+    pub fn is_valid(&self) -> bool {
+        self.check().is_ok()
+    }
+
+    /// Returns a copy of the value
+    #[inline]
+    pub fn read(&self) -> Result<T, AllocError>
+    where
+        T: Clone,
+    {
+        self.with(|it| it.clone())
+    }
+
+    /// Provides immutable access to the value, by accepting a lambda function
+    pub fn with<R, F: FnOnce(&T) -> R>(&self, func: F) -> Result<R, AllocError> {
+        let slice = self.check()?;
+        let it = unsafe { crate::mem::read_fixed_for_reference::<T>(slice.offset(0)) };
+
+        Ok(func(&it))
+    }
+
+    /// Provides mutable access to the value, by accepting a lambda function
+    ///
+    /// The value is written back to stable memory after the function returns.
+    pub fn map_mut<R, F: FnOnce(&mut T) -> R>(&self, func: F) -> Result<R, AllocError> {
+        let slice = self.check()?;
+        let mut it = unsafe { crate::mem::read_fixed_for_reference::<T>(slice.offset(0)) };
+
+        let res = func(&mut it);
+        unsafe { crate::mem::write_fixed(slice.offset(0), &mut it) };
+
+        Ok(res)
+    }
+
+    /// Replaces the value, stable-dropping the previous one
+    ///
+    /// If the pointer is invalid, the new value is dropped and an error is returned.
+    pub fn write(&self, mut it: T) -> Result<(), AllocError> {
+        let slice = self.check()?;
+
+        let prev = unsafe { crate::mem::read_fixed_for_move::<T>(slice.offset(0)) };
+        unsafe { crate::mem::write_fixed(slice.offset(0), &mut it) };
+        drop(prev);
+
+        Ok(())
+    }
+
+    /// Returns the value, releasing the memory block
+    ///
+    /// All copies of this pointer become invalid.
+    pub fn free(self) -> Result<T, AllocError> {
+        let slice = self.check()?;
+
+        let it = unsafe { crate::mem::read_fixed_for_move::<T>(slice.offset(0)) };
+        deallocate(slice);
+
+        Ok(it)
+    }
+
+    fn check(&self) -> Result<SSlice, AllocError> {
+        let slice = check_ptr(self.ptr)?;
+
+        if slice.get_size_bytes() < T::SIZE as u64 {
+            return Err(AllocError::InvalidPointer(self.ptr));
+        }
+
+        Ok(slice)
+    }
+}
+
+impl<T> Clone for SPtr<T> {
+    #[inline]
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for SPtr<T> {}
+
+impl<T> PartialEq for SPtr<T> {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        self.ptr == other.ptr
+    }
+}
+
+impl<T> Eq for SPtr<T> {}
+
+impl<T> Hash for SPtr<T> {
+    #[inline]
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.ptr.hash(state)
+    }
+}
+
+impl<T> Debug for SPtr<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("SPtr").field(&self.ptr).finish()
+    }
+}
+
+impl<T> AsFixedSizeBytes for SPtr<T> {
+    const SIZE: usize = StablePtr::SIZE;
+    type Buf = [u8; StablePtr::SIZE];
+
+    #[inline]
+    fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+        self.ptr.as_fixed_size_bytes(buf)
+    }
+
+    #[inline]
+    fn from_fixed_size_bytes(arr: &[u8]) -> Self {
+        Self::from_raw(StablePtr::from_fixed_size_bytes(arr))
+    }
+}
+
+impl<T> StableType for SPtr<T> {}
+
+// the pointed memory block is kept alive, while there is at least one copy of the pointer
+#[cfg(feature = "orphan_collector")]
+impl<T: StableType + AsFixedSizeBytes + Trace> Trace for SPtr<T> {
+    fn trace(&self, ptrs: &mut Vec<StablePtr>) {
+        ptrs.push(self.ptr);
+
+        if T::OWNS_MEMORY {
+            let _ = self.with(|it| it.trace(ptrs));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::SVec;
+    use crate::mem::allocator::AllocError;
+    use crate::primitive::s_ptr::SPtr;
+    use crate::{_debug_validate_allocator, get_allocated_size, stable, stable_memory_init};
+
+    #[test]
+    fn works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let p = SPtr::new(SVec::<u64>::new()).unwrap();
+            assert!(p.is_valid());
+
+            for i in 0..100 {
+                p.map_mut(|it| it.push(i)).unwrap().unwrap();
+            }
+
+            assert_eq!(p.with(|it| it.len()).unwrap(), 100);
+
+            let mut vec = SVec::new();
+            vec.push(p).unwrap();
+            let q = *vec.get(0).unwrap();
+            assert_eq!(p, q);
+
+            q.write(SVec::new()).unwrap();
+            assert_eq!(p.with(|it| it.len()).unwrap(), 0);
+
+            let n = SPtr::new(10u64).unwrap();
+            assert_eq!(n.read().unwrap(), 10);
+
+            let raw = n.as_raw();
+            assert_eq!(n.free().unwrap(), 10);
+            assert!(SPtr::<u64>::from_raw(raw).read().is_err());
+            assert_eq!(
+                SPtr::<u64>::from_raw(u64::MAX).read(),
+                Err(AllocError::InvalidPointer(u64::MAX))
+            );
+
+            p.free().unwrap();
+            assert!(!q.is_valid());
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
@@ -0,0 +1,369 @@
+use crate::encoding::{AsDynSizeBytes, AsFixedSizeBytes};
+#[cfg(feature = "orphan_collector")]
+use crate::mem::orphan_collector::Trace;
+use crate::mem::s_slice::SSlice;
+#[cfg(feature = "orphan_collector")]
+use crate::mem::StablePtr;
+use crate::primitive::StableType;
+use crate::{allocate, deallocate};
+use std::borrow::Borrow;
+use std::cell::UnsafeCell;
+use std::cmp::Ordering;
+use std::fmt::{Debug, Formatter};
+use std::hash::{Hash, Hasher};
+use std::io::IoSlice;
+use std::ops::Deref;
+
+const COUNTER_OFFSET: u64 = 0;
+const DATA_OFFSET: u64 = u64::SIZE as u64;
+
+/// Reference-counted smart-pointer that allows sharing the same dynamic sized data between several
+/// stable data structures.
+///
+/// See also [SBox](crate::SBox).
+///
+/// Stores the reference counter in stable memory right before the data itself, so the counter
+/// survives canister upgrades together with the data. Each [Clone] of an [SRc] increments that
+/// counter, each stable-drop decrements it. The underlying memory block (and the value inside) is only
+/// released when the last reference is stable-dropped.
+///
+/// Unlike [SBox](crate::SBox), [SRc] provides no mutable access to the underlying data - other references
+/// may point to the same memory block. Like [SBox](crate::SBox), it is eager on writes and lazy on reads.
+///
+/// `T` should implement both [StableType] and [AsDynSizeBytes]. [SRc] itself implements [StableType]
+/// and [AsFixedSizeBytes], so you can put it in any other stable structure.
+///
+/// # Examples
+/// ```rust
+/// # use ic_stable_memory::collections::SVec;
+/// # use ic_stable_memory::{stable_memory_init, SRc};
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// let asset = SRc::new(vec![0u8; 1000]).expect("Out of memory");
+///
+/// let mut by_name = SVec::new();
+/// let mut by_date = SVec::new();
+///
+/// by_name.push(asset.clone()).expect("Out of memory");
+/// by_date.push(asset).expect("Out of memory");
+///
+/// assert_eq!(by_name.get(0).unwrap().strong_count(), 2);
+///
+/// drop(by_name);
+///
+/// // still there
+/// assert_eq!(by_date.get(0).unwrap().len(), 1000);
+/// ```
+pub struct SRc<T: AsDynSizeBytes + StableType> {
+    slice: Option<SSlice>,
+    inner: UnsafeCell<Option<T>>,
+    stable_drop_flag: bool,
+}
+
+impl<T: AsDynSizeBytes + StableType> SRc<T> {
+    /// Stores dynamic sized data on stable memory with the reference counter set to `1`.
+    ///
+    /// Returns `Err` and the data, if the canister is `OutOfMemory`.
+    #[inline]
+    pub fn new(mut it: T) -> Result<Self, T> {
+        let buf = it.as_dyn_size_bytes();
+        if let Ok(slice) = unsafe { allocate(DATA_OFFSET + buf.len() as u64) } {
+            let counter = 1u64.as_new_fixed_size_bytes();
+
+            unsafe {
+                crate::mem::write_bytes_vectored(
+                    slice.offset(COUNTER_OFFSET),
+                    &[IoSlice::new(&counter), IoSlice::new(&buf)],
+                );
+                it.stable_drop_flag_off();
+            }
+
+            Ok(Self {
+                slice: Some(slice),
+                inner: UnsafeCell::new(Some(it)),
+                stable_drop_flag: true,
+            })
+        } else {
+            Err(it)
+        }
+    }
+
+    /// Returns a pointer to the underlying [SSlice] of stable memory.
+    ///
+    /// See also [SRc::from_ptr].
+    #[inline]
+    pub fn as_ptr(&self) -> u64 {
+        self.slice.unwrap().as_ptr()
+    }
+
+    /// Returns the number of references pointing to the same data.
+    #[inline]
+    pub fn strong_count(&self) -> u64 {
+        unsafe { crate::mem::read_fixed_for_reference(self.slice.unwrap().offset(COUNTER_OFFSET)) }
+    }
+
+    /// Returns `true` if both references point to the same memory block.
+    #[inline]
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        self.as_ptr() == other.as_ptr()
+    }
+
+    /// Creates [SRc] from a pointer to the underlying [SSlice] of stable memory.
+    ///
+    /// See also [SRc::as_ptr].
+    ///
+    /// # Panics
+    /// Panics if the pointer points to an invalid (or free) block of stable memory.
+    ///
+    /// # Safety
+    /// This method does not increment the reference counter, which breaks ownership and stable-drop
+    /// rules. Always make sure you restore stable-drop rules manually.
+    pub unsafe fn from_ptr(ptr: u64) -> Self {
+        let slice = SSlice::from_ptr(ptr).unwrap();
+
+        Self {
+            stable_drop_flag: false,
+            slice: Some(slice),
+            inner: UnsafeCell::default(),
+        }
+    }
+
+    unsafe fn lazy_read(&self, drop_flag: bool) {
+        if let Some(it) = (*self.inner.get()).as_mut() {
+            if drop_flag {
+                it.stable_drop_flag_on();
+            } else {
+                it.stable_drop_flag_off();
+            }
+
+            return;
+        }
+
+        let slice = self.slice.as_ref().unwrap();
+        let mut buf = vec![0u8; (slice.get_size_bytes() - DATA_OFFSET) as usize];
+        unsafe { crate::mem::read_bytes(slice.offset(DATA_OFFSET), &mut buf) };
+
+        let mut inner = T::from_dyn_size_bytes(&buf);
+        if drop_flag {
+            inner.stable_drop_flag_on();
+        } else {
+            inner.stable_drop_flag_off();
+        }
+
+        *self.inner.get() = Some(inner);
+    }
+
+    #[inline]
+    fn write_strong_count(&self, mut count: u64) {
+        unsafe { crate::mem::write_fixed(self.slice.unwrap().offset(COUNTER_OFFSET), &mut count) };
+    }
+}
+
+impl<T: AsDynSizeBytes + StableType> Clone for SRc<T> {
+    /// Creates another reference to the same data, incrementing the reference counter.
+    ///
+    /// Does not allocate any stable memory.
+    #[inline]
+    fn clone(&self) -> Self {
+        self.write_strong_count(self.strong_count() + 1);
+
+        Self {
+            slice: self.slice,
+            inner: UnsafeCell::default(),
+            stable_drop_flag: true,
+        }
+    }
+}
+
+impl<T: AsDynSizeBytes + StableType> AsFixedSizeBytes for SRc<T> {
+    const SIZE: usize = u64::SIZE;
+    type Buf = [u8; u64::SIZE];
+
+    #[inline]
+    fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+        self.as_ptr().as_fixed_size_bytes(buf)
+    }
+
+    #[inline]
+    fn from_fixed_size_bytes(arr: &[u8]) -> Self {
+        let ptr = u64::from_fixed_size_bytes(arr);
+
+        unsafe { Self::from_ptr(ptr) }
+    }
+}
+
+impl<T: AsDynSizeBytes + StableType> StableType for SRc<T> {
+    #[inline]
+    fn should_stable_drop(&self) -> bool {
+        self.stable_drop_flag
+    }
+
+    #[inline]
+    unsafe fn stable_drop_flag_off(&mut self) {
+        self.stable_drop_flag = false;
+    }
+
+    #[inline]
+    unsafe fn stable_drop_flag_on(&mut self) {
+        self.stable_drop_flag = true;
+    }
+
+    unsafe fn stable_drop(&mut self) {
+        let count = self.strong_count();
+
+        if count > 1 {
+            self.write_strong_count(count - 1);
+            self.slice = None;
+
+            return;
+        }
+
+        self.lazy_read(true);
+        *self.inner.get_mut() = None;
+
+        deallocate(self.slice.take().unwrap());
+    }
+}
+
+impl<T: AsDynSizeBytes + StableType> Drop for SRc<T> {
+    fn drop(&mut self) {
+        unsafe {
+            if self.should_stable_drop() {
+                self.stable_drop();
+            }
+        }
+    }
+}
+
+impl<T: PartialEq + AsDynSizeBytes + StableType> PartialEq for SRc<T> {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        self.deref().eq(other.deref())
+    }
+}
+
+impl<T: Eq + PartialEq + AsDynSizeBytes + StableType> Eq for SRc<T> {}
+
+impl<T: PartialOrd + AsDynSizeBytes + StableType> PartialOrd for SRc<T> {
+    #[inline]
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.deref().partial_cmp(other.deref())
+    }
+}
+
+impl<T: Ord + PartialOrd + AsDynSizeBytes + StableType> Ord for SRc<T> {
+    #[inline]
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.deref().cmp(other.deref())
+    }
+}
+
+impl<T: Hash + AsDynSizeBytes + StableType> Hash for SRc<T> {
+    #[inline]
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.deref().hash(state);
+    }
+}
+
+impl<T: Debug + AsDynSizeBytes + StableType> Debug for SRc<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SRc(")?;
+        self.deref().fmt(f)?;
+        f.write_str(")")
+    }
+}
+
+impl<T: AsDynSizeBytes + StableType> Borrow<T> for SRc<T> {
+    #[inline]
+    fn borrow(&self) -> &T {
+        self.deref()
+    }
+}
+
+impl<T: AsDynSizeBytes + StableType> Deref for SRc<T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        unsafe {
+            self.lazy_read(false);
+
+            (*self.inner.get()).as_ref().unwrap()
+        }
+    }
+}
+
+#[cfg(feature = "orphan_collector")]
+impl<T: AsDynSizeBytes + StableType + Trace> Trace for SRc<T> {
+    fn trace(&self, ptrs: &mut Vec<StablePtr>) {
+        if let Some(slice) = &self.slice {
+            ptrs.push(slice.as_ptr());
+        }
+
+        if T::OWNS_MEMORY {
+            self.deref().trace(ptrs);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::SVec;
+    use crate::primitive::s_rc::SRc;
+    use crate::{
+        _debug_validate_allocator, get_allocated_size, retrieve_custom_data, stable,
+        stable_memory_init, store_custom_data, SBox,
+    };
+
+    #[test]
+    fn srcs_work_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let rc = SRc::new(String::from("shared")).unwrap();
+            assert_eq!(rc.strong_count(), 1);
+
+            let rc1 = rc.clone();
+            assert_eq!(rc.strong_count(), 2);
+            assert!(rc.ptr_eq(&rc1));
+            assert_eq!(*rc1, "shared");
+
+            drop(rc);
+            assert_eq!(rc1.strong_count(), 1);
+            assert!(get_allocated_size() > 0);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+
+        {
+            let rc = SRc::new(SBox::new(100u64).unwrap()).unwrap();
+
+            let mut a = SVec::new();
+            let mut b = SVec::new();
+
+            a.push(rc.clone()).unwrap();
+            b.push(rc).unwrap();
+
+            assert_eq!(a.get(0).unwrap().strong_count(), 2);
+
+            let cloned = b.get(0).unwrap().clone();
+            assert_eq!(cloned.strong_count(), 3);
+            drop(cloned);
+
+            store_custom_data(0, SBox::new(a).unwrap());
+            let a = retrieve_custom_data::<SVec<SRc<SBox<u64>>>>(0)
+                .unwrap()
+                .into_inner();
+
+            drop(a);
+
+            assert_eq!(b.get(0).unwrap().strong_count(), 1);
+            assert_eq!(***b.get(0).unwrap(), 100);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
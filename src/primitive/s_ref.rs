@@ -33,6 +33,11 @@ impl<'o, T> SRef<'o, T> {
             _marker: PhantomData::default(),
         }
     }
+
+    #[inline]
+    pub(crate) fn _ptr(&self) -> u64 {
+        self.ptr
+    }
 }
 
 impl<'o, T: StableType + AsFixedSizeBytes> SRef<'o, T> {
@@ -0,0 +1,382 @@
+use crate::encoding::AsFixedSizeBytes;
+#[cfg(feature = "orphan_collector")]
+use crate::mem::orphan_collector::Trace;
+use crate::mem::s_slice::SSlice;
+use crate::mem::StablePtr;
+use crate::primitive::StableType;
+use crate::{allocate, deallocate};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+
+// pointer -> number of active shared borrows, or MUT_BORROWED
+thread_local! {
+    static BORROWS: RefCell<HashMap<StablePtr, isize>> = RefCell::new(HashMap::new());
+}
+
+const MUT_BORROWED: isize = -1;
+
+fn try_acquire(ptr: StablePtr, mutable: bool) -> bool {
+    BORROWS.with(|it| {
+        let mut borrows = it.borrow_mut();
+        let state = borrows.entry(ptr).or_insert(0);
+
+        match (*state, mutable) {
+            (0, true) => *state = MUT_BORROWED,
+            (MUT_BORROWED, _) | (_, true) => return false,
+            (_, false) => *state += 1,
+        }
+
+        true
+    })
+}
+
+fn release(ptr: StablePtr) {
+    BORROWS.with(|it| {
+        let mut borrows = it.borrow_mut();
+        let state = borrows.get_mut(&ptr).unwrap();
+
+        if *state == MUT_BORROWED || *state == 1 {
+            borrows.remove(&ptr);
+        } else {
+            *state -= 1;
+        }
+    })
+}
+
+/// A fixed size value (e.g. a collection) in stable memory with runtime-checked borrows
+///
+/// Stable collections are simply pointers to stable memory, so handles to the same collection can be
+/// created in different places of the canister (e.g. with [SRefCell::from_ptr] from a named root or
+/// by reading the same value from some other collection twice). Mutating the collection through one
+/// of them, while another one is in use, silently corrupts it. [SRefCell] tracks borrows by the
+/// value's pointer, so all handles to the same value share the same borrow state, and, similar to
+/// [RefCell], panics on aliasing violations: a mutable borrow while any other borrow is active, or
+/// an immutable borrow while a mutable one is active.
+///
+/// Changes, made through [SRefCell::borrow_mut], are written back to stable memory, once the guard
+/// is dropped.
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::collections::SVec;
+/// # use ic_stable_memory::{stable_memory_init, SRefCell};
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// let cell = SRefCell::new(SVec::<u64>::new()).expect("Out of memory");
+/// cell.borrow_mut().push(10).expect("Out of memory");
+///
+/// // some other call path, which knows only the pointer
+/// let other = unsafe { SRefCell::<SVec<u64>>::from_ptr(cell.as_ptr()) };
+///
+/// let _guard = cell.borrow();
+/// assert_eq!(*other.borrow().get(0).unwrap(), 10);
+/// assert!(other.try_borrow_mut().is_none());
+/// ```
+pub struct SRefCell<T: StableType + AsFixedSizeBytes> {
+    slice: Option<SSlice>,
+    stable_drop_flag: bool,
+    _marker: PhantomData<T>,
+}
+
+impl<T: StableType + AsFixedSizeBytes> SRefCell<T> {
+    /// Allocates a memory block and moves the value into it.
+    ///
+    /// Returns `Err` and the value, if the canister is `OutOfMemory`.
+    pub fn new(mut it: T) -> Result<Self, T> {
+        if let Ok(slice) = unsafe { allocate(T::SIZE as u64) } {
+            unsafe { crate::mem::write_fixed(slice.offset(0), &mut it) };
+
+            Ok(Self {
+                slice: Some(slice),
+                stable_drop_flag: true,
+                _marker: PhantomData,
+            })
+        } else {
+            Err(it)
+        }
+    }
+
+    /// Returns a pointer to the underlying [SSlice] of stable memory.
+    ///
+    /// See also [SRefCell::from_ptr].
+    #[inline]
+    pub fn as_ptr(&self) -> StablePtr {
+        self.slice.unwrap().as_ptr()
+    }
+
+    /// Creates another handle to the [SRefCell], located at the pointer
+    ///
+    /// See also [SRefCell::as_ptr]. The handle shares borrows with all other handles to the same
+    /// value and doesn't release the memory, when dropped.
+    ///
+    /// # Panics
+    /// Panics if the pointer points to an invalid (or free) block of stable memory.
+    ///
+    /// # Safety
+    /// Make sure, the pointer points to an [SRefCell] of the same type and that it is not released,
+    /// while this handle is alive.
+    pub unsafe fn from_ptr(ptr: StablePtr) -> Self {
+        let slice = SSlice::from_ptr(ptr).expect("Invalid SRefCell pointer");
+
+        Self {
+            slice: Some(slice),
+            stable_drop_flag: false,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Immutably borrows the value
+    ///
+    /// # Panics
+    /// Panics if the value is currently mutably borrowed through any of its handles.
+    #[inline]
+    pub fn borrow(&self) -> SRefCellRef<'_, T> {
+        self.try_borrow()
+            .expect("SRefCell is already mutably borrowed")
+    }
+
+    /// Mutably borrows the value
+    ///
+    /// # Panics
+    /// Panics if the value is currently borrowed through any of its handles.
+    #[inline]
+    pub fn borrow_mut(&self) -> SRefCellRefMut<'_, T> {
+        self.try_borrow_mut().expect("SRefCell is already borrowed")
+    }
+
+    /// Same as [SRefCell::borrow], but returns [None] instead of panicking
+    pub fn try_borrow(&self) -> Option<SRefCellRef<'_, T>> {
+        let ptr = self.data_ptr();
+
+        if !try_acquire(ptr, false) {
+            return None;
+        }
+
+        Some(SRefCellRef {
+            ptr,
+            inner: unsafe { crate::mem::read_fixed_for_reference(ptr) },
+            _marker: PhantomData,
+        })
+    }
+
+    /// Same as [SRefCell::borrow_mut], but returns [None] instead of panicking
+    pub fn try_borrow_mut(&self) -> Option<SRefCellRefMut<'_, T>> {
+        let ptr = self.data_ptr();
+
+        if !try_acquire(ptr, true) {
+            return None;
+        }
+
+        Some(SRefCellRefMut {
+            ptr,
+            inner: unsafe { crate::mem::read_fixed_for_reference(ptr) },
+            _marker: PhantomData,
+        })
+    }
+
+    /// Returns the value, releasing the memory block
+    ///
+    /// # Panics
+    /// Panics if the value is currently borrowed through any other handle.
+    pub fn into_inner(mut self) -> T {
+        let ptr = self.data_ptr();
+        assert!(try_acquire(ptr, true), "SRefCell is already borrowed");
+        release(ptr);
+
+        let it = unsafe { crate::mem::read_fixed_for_move(ptr) };
+        deallocate(self.slice.take().unwrap());
+
+        it
+    }
+
+    #[inline]
+    fn data_ptr(&self) -> StablePtr {
+        self.slice.unwrap().offset(0)
+    }
+}
+
+/// Immutable borrow of an [SRefCell], see [SRefCell::borrow]
+pub struct SRefCellRef<'a, T: StableType + AsFixedSizeBytes> {
+    ptr: StablePtr,
+    inner: T,
+    _marker: PhantomData<&'a T>,
+}
+
+impl<'a, T: StableType + AsFixedSizeBytes> Deref for SRefCellRef<'a, T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<'a, T: StableType + AsFixedSizeBytes> Drop for SRefCellRef<'a, T> {
+    #[inline]
+    fn drop(&mut self) {
+        release(self.ptr);
+    }
+}
+
+/// Mutable borrow of an [SRefCell], see [SRefCell::borrow_mut]
+///
+/// The value is written back to stable memory, when this guard is dropped.
+pub struct SRefCellRefMut<'a, T: StableType + AsFixedSizeBytes> {
+    ptr: StablePtr,
+    inner: T,
+    _marker: PhantomData<&'a mut T>,
+}
+
+impl<'a, T: StableType + AsFixedSizeBytes> Deref for SRefCellRefMut<'a, T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<'a, T: StableType + AsFixedSizeBytes> DerefMut for SRefCellRefMut<'a, T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+impl<'a, T: StableType + AsFixedSizeBytes> Drop for SRefCellRefMut<'a, T> {
+    #[inline]
+    fn drop(&mut self) {
+        unsafe { crate::mem::write_fixed(self.ptr, &mut self.inner) };
+        release(self.ptr);
+    }
+}
+
+impl<T: StableType + AsFixedSizeBytes> AsFixedSizeBytes for SRefCell<T> {
+    const SIZE: usize = u64::SIZE;
+    type Buf = [u8; u64::SIZE];
+
+    #[inline]
+    fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+        self.as_ptr().as_fixed_size_bytes(buf)
+    }
+
+    #[inline]
+    fn from_fixed_size_bytes(arr: &[u8]) -> Self {
+        let ptr = u64::from_fixed_size_bytes(arr);
+
+        unsafe { Self::from_ptr(ptr) }
+    }
+}
+
+impl<T: StableType + AsFixedSizeBytes> StableType for SRefCell<T> {
+    #[inline]
+    fn should_stable_drop(&self) -> bool {
+        self.stable_drop_flag
+    }
+
+    #[inline]
+    unsafe fn stable_drop_flag_off(&mut self) {
+        self.stable_drop_flag = false;
+    }
+
+    #[inline]
+    unsafe fn stable_drop_flag_on(&mut self) {
+        self.stable_drop_flag = true;
+    }
+
+    #[inline]
+    unsafe fn stable_drop(&mut self) {
+        drop(crate::mem::read_fixed_for_move::<T>(self.data_ptr()));
+
+        deallocate(self.slice.take().unwrap());
+    }
+}
+
+impl<T: StableType + AsFixedSizeBytes> Drop for SRefCell<T> {
+    fn drop(&mut self) {
+        if self.should_stable_drop() {
+            unsafe { self.stable_drop() };
+        }
+    }
+}
+
+impl<T: StableType + AsFixedSizeBytes + Debug> Debug for SRefCell<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.try_borrow() {
+            Some(it) => f.debug_tuple("SRefCell").field(&*it).finish(),
+            None => f.write_str("SRefCell(<borrowed>)"),
+        }
+    }
+}
+
+#[cfg(feature = "orphan_collector")]
+impl<T: StableType + AsFixedSizeBytes + Trace> Trace for SRefCell<T> {
+    fn trace(&self, ptrs: &mut Vec<StablePtr>) {
+        let slice = match &self.slice {
+            Some(it) => it,
+            None => return,
+        };
+
+        ptrs.push(slice.as_ptr());
+
+        if T::OWNS_MEMORY {
+            unsafe { crate::mem::read_fixed_for_reference::<T>(self.data_ptr()) }.trace(ptrs);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::SVec;
+    use crate::primitive::s_ref_cell::SRefCell;
+    use crate::{_debug_validate_allocator, get_allocated_size, stable, stable_memory_init};
+
+    #[test]
+    fn works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let cell = SRefCell::new(SVec::<u64>::new()).unwrap();
+
+            for i in 0..100 {
+                cell.borrow_mut().push(i).unwrap();
+            }
+
+            let other = unsafe { SRefCell::<SVec<u64>>::from_ptr(cell.as_ptr()) };
+            assert_eq!(other.borrow().len(), 100);
+
+            {
+                let a = cell.borrow();
+                let b = other.borrow();
+                assert_eq!(a.len(), b.len());
+
+                assert!(other.try_borrow_mut().is_none());
+                assert!(format!("{:?}", other).starts_with("SRefCell([0, 1, 2"));
+            }
+
+            {
+                let mut a = other.borrow_mut();
+                a.push(100).unwrap();
+
+                assert!(cell.try_borrow().is_none());
+                assert_eq!(format!("{:?}", cell), "SRefCell(<borrowed>)");
+
+                let res = std::panic::catch_unwind(|| cell.borrow().len());
+                assert!(res.is_err());
+            }
+
+            drop(other);
+            assert_eq!(*cell.borrow().get(100).unwrap(), 100);
+
+            let vec = cell.into_inner();
+            assert_eq!(vec.len(), 101);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
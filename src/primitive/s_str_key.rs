@@ -0,0 +1,274 @@
+use crate::encoding::AsFixedSizeBytes;
+#[cfg(feature = "orphan_collector")]
+use crate::mem::orphan_collector::Trace;
+#[cfg(feature = "orphan_collector")]
+use crate::mem::StablePtr;
+use crate::primitive::StableType;
+use candid::types::{Serializer, Type};
+use candid::CandidType;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::fmt::{Debug, Display, Formatter};
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+
+/// Fixed-capacity string, which stores up to `N` bytes of UTF-8 inline.
+///
+/// Implements [AsFixedSizeBytes] and [StableType], so it can be used as a key of
+/// [SBTreeMap](crate::collections::SBTreeMap) or [SHashMap](crate::collections::SHashMap) directly,
+/// without putting a [String] in [SBox](crate::SBox) and paying for an out-of-line allocation. Its
+/// fixed size encoding takes `N + 2` bytes - the data itself, padded with zeroes, and its length.
+///
+/// [Ord], [Eq] and [Hash] are the same as [str]'s, so maps keyed with [SStrKey] can be queried
+/// with a plain `&str`.
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::{stable_memory_init, SStrKey};
+/// # use ic_stable_memory::collections::SBTreeMap;
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// let mut balances = SBTreeMap::<SStrKey<32>, u64>::new();
+///
+/// balances.insert(SStrKey::new("alice").unwrap(), 100).expect("Out of memory");
+/// balances.insert(SStrKey::new("bob").unwrap(), 10).expect("Out of memory");
+///
+/// assert_eq!(*balances.get("alice").unwrap(), 100);
+/// assert!(SStrKey::<4>::new("too long").is_err());
+/// ```
+#[derive(Clone, Copy)]
+pub struct SStrKey<const N: usize> {
+    len: u16,
+    bytes: [u8; N],
+}
+
+impl<const N: usize> SStrKey<N> {
+    /// Creates a key, copying the string into it.
+    ///
+    /// Returns `Err` and the string, if it is longer than `N` bytes.
+    ///
+    /// # Panics
+    /// Panics if `N` is bigger than [u16::MAX].
+    pub fn new(it: &str) -> Result<Self, &str> {
+        assert!(N <= u16::MAX as usize, "SStrKey capacity is too big");
+
+        if it.len() > N {
+            return Err(it);
+        }
+
+        let mut bytes = [0u8; N];
+        bytes[..it.len()].copy_from_slice(it.as_bytes());
+
+        Ok(Self {
+            len: it.len() as u16,
+            bytes,
+        })
+    }
+
+    /// Returns the string
+    #[inline]
+    pub fn as_str(&self) -> &str {
+        // only valid UTF-8 is ever written by `new()` and `from_fixed_size_bytes()`
+        unsafe { std::str::from_utf8_unchecked(&self.bytes[..self.len as usize]) }
+    }
+
+    /// Returns the maximum length of the string in bytes
+    #[inline]
+    pub const fn capacity() -> usize {
+        N
+    }
+}
+
+impl<const N: usize> Default for SStrKey<N> {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            len: 0,
+            bytes: [0u8; N],
+        }
+    }
+}
+
+impl<const N: usize> AsFixedSizeBytes for SStrKey<N> {
+    const SIZE: usize = u16::SIZE + N;
+    type Buf = Vec<u8>;
+
+    #[inline]
+    fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+        self.len.as_fixed_size_bytes(&mut buf[..u16::SIZE]);
+        buf[u16::SIZE..Self::SIZE].copy_from_slice(&self.bytes);
+    }
+
+    fn from_fixed_size_bytes(arr: &[u8]) -> Self {
+        let len = u16::from_fixed_size_bytes(&arr[..u16::SIZE]);
+        let data = &arr[u16::SIZE..Self::SIZE];
+
+        let it = std::str::from_utf8(&data[..len as usize]).expect("Invalid UTF-8 in SStrKey");
+
+        Self::new(it).unwrap()
+    }
+}
+
+impl<const N: usize> StableType for SStrKey<N> {}
+
+impl<const N: usize> Deref for SStrKey<N> {
+    type Target = str;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        self.as_str()
+    }
+}
+
+impl<const N: usize> Borrow<str> for SStrKey<N> {
+    #[inline]
+    fn borrow(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl<const N: usize> PartialEq for SStrKey<N> {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        self.as_str().eq(other.as_str())
+    }
+}
+
+impl<const N: usize> Eq for SStrKey<N> {}
+
+impl<const N: usize> PartialOrd for SStrKey<N> {
+    #[inline]
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<const N: usize> Ord for SStrKey<N> {
+    #[inline]
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_str().cmp(other.as_str())
+    }
+}
+
+impl<const N: usize> Hash for SStrKey<N> {
+    #[inline]
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_str().hash(state)
+    }
+}
+
+impl<const N: usize> Debug for SStrKey<N> {
+    #[inline]
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl<const N: usize> Display for SStrKey<N> {
+    #[inline]
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self.as_str(), f)
+    }
+}
+
+impl<const N: usize> CandidType for SStrKey<N> {
+    #[inline]
+    fn _ty() -> Type {
+        String::_ty()
+    }
+
+    #[inline]
+    fn idl_serialize<S>(&self, serializer: S) -> Result<(), S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_text(self.as_str())
+    }
+}
+
+impl<'de, const N: usize> Deserialize<'de> for SStrKey<N> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let it = String::deserialize(deserializer)?;
+
+        Self::new(&it).map_err(|_| D::Error::custom("The string is too long for SStrKey"))
+    }
+}
+
+#[cfg(feature = "orphan_collector")]
+impl<const N: usize> Trace for SStrKey<N> {
+    const OWNS_MEMORY: bool = false;
+
+    #[inline]
+    fn trace(&self, _ptrs: &mut Vec<StablePtr>) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::SBTreeMap;
+    use crate::encoding::AsFixedSizeBytes;
+    use crate::primitive::s_str_key::SStrKey;
+    use crate::{_debug_validate_allocator, get_allocated_size, stable, stable_memory_init};
+    use candid::{decode_one, encode_one};
+
+    #[test]
+    fn works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        assert_eq!(SStrKey::<10>::SIZE, 12);
+        assert_eq!(SStrKey::<10>::capacity(), 10);
+        assert!(SStrKey::<3>::new("абв").is_err());
+
+        let k = SStrKey::<8>::new("абв").unwrap();
+        assert_eq!(k.as_str(), "абв");
+        assert_eq!(
+            SStrKey::<8>::from_fixed_size_bytes(&k.as_new_fixed_size_bytes()),
+            k
+        );
+
+        // shorter strings and strings with zero bytes keep str's order
+        let keys = ["b", "a\0", "", "ab", "a", "ba"];
+        let mut sorted = keys
+            .iter()
+            .map(|it| SStrKey::<4>::new(it).unwrap())
+            .collect::<Vec<_>>();
+        sorted.sort();
+
+        assert_eq!(
+            sorted.iter().map(|it| it.as_str()).collect::<Vec<_>>(),
+            vec!["", "a", "a\0", "ab", "b", "ba"]
+        );
+
+        let bytes = encode_one(k).unwrap();
+        assert_eq!(bytes, encode_one("абв").unwrap());
+        assert_eq!(decode_one::<SStrKey<8>>(&bytes).unwrap(), k);
+        assert!(decode_one::<SStrKey<2>>(&bytes).is_err());
+
+        {
+            let mut map = SBTreeMap::<SStrKey<16>, u64>::new();
+
+            for i in 0..100u64 {
+                map.insert(SStrKey::new(&format!("key {}", i)).unwrap(), i)
+                    .unwrap();
+            }
+
+            for i in 0..100u64 {
+                assert_eq!(*map.get(format!("key {}", i).as_str()).unwrap(), i);
+            }
+
+            let first = map.iter().next().map(|(k, _)| *k).unwrap();
+            assert_eq!(first.as_str(), "key 0");
+            assert_eq!(map.remove("key 50"), Some(50));
+
+            _debug_validate_allocator();
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
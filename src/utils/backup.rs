@@ -0,0 +1,342 @@
+//! Chunked backup of the managed heap, which can be downloaded with query calls
+//!
+//! A backup is a [BackupManifest] plus the raw bytes of the allocator's heap (see
+//! [get_heap_range](crate::get_heap_range)), split into chunks of [BACKUP_CHUNK_SIZE] bytes, each
+//! small enough to fit into a single query response. The manifest holds a hash of every chunk and
+//! the allocator's metadata, so a controller can verify each downloaded chunk and restore the heap
+//! later - in the same canister, for disaster recovery, or in another one, to clone it - with
+//! [restore_begin], [restore_chunk] and [restore_finish].
+//!
+//! Each chunk is hashed against its current content, so if the heap changes between downloads,
+//! some chunks won't match the manifest and the download should be started over. To get a
+//! consistent backup of a busy canister, take a [HeapSnapshot](crate::mem::snapshot::HeapSnapshot)
+//! and build the manifest in the same update call, then serve chunks from inside
+//! [HeapSnapshot::view](crate::mem::snapshot::HeapSnapshot::view).
+//!
+//! Building the manifest reads the whole heap, so for big heaps it may exceed the instruction limit
+//! of a single call. Arenas (see [create_arena](crate::create_arena)) are not supported - their
+//! memory is backed up as a part of the heap, but their metadata is not.
+//!
+//! Only available with the `backup` feature.
+
+use crate::mem::allocator::{ReinitError, StableMemoryAllocator, LAYOUT_VERSION};
+use crate::mem::StablePtr;
+use crate::utils::certification::Hash;
+use crate::{
+    declare_root, export_meta, get_heap_range, get_roots, import_meta, stable, PAGE_SIZE_BYTES,
+};
+use candid::{CandidType, Deserialize};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Size of a single backup chunk in bytes
+pub const BACKUP_CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Describes a backup, see the [module-level docs](crate::utils::backup)
+#[derive(Debug, Clone, CandidType, Deserialize, Eq, PartialEq)]
+pub struct BackupManifest {
+    /// See [LAYOUT_VERSION]
+    pub layout_version: u32,
+    /// Offset of the first chunk in stable memory
+    pub base_offset: StablePtr,
+    /// Total size of all chunks in bytes
+    pub size: u64,
+    /// Size of each chunk in bytes (the last one may be smaller)
+    pub chunk_size: u64,
+    /// SHA-256 hash of every chunk
+    pub chunk_hashes: Vec<Hash>,
+    /// Named roots, see [declare_root](crate::declare_root)
+    pub roots: BTreeMap<String, StablePtr>,
+    /// Allocator's metadata, see [export_meta]
+    pub meta: Vec<u8>,
+}
+
+struct RestoreState {
+    manifest: BackupManifest,
+    restored: BTreeSet<u64>,
+}
+
+thread_local! {
+    static RESTORE: RefCell<Option<RestoreState>> = RefCell::new(None);
+}
+
+/// Indicates that a backup can't be restored
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BackupError {
+    /// There is no chunk with this index in the manifest
+    UnknownChunk,
+    /// The chunk doesn't match its hash from the manifest
+    HashMismatch,
+    /// The backup was taken by a version of this crate with a newer layout
+    IncompatibleVersion,
+    /// The backup was taken by a build with another [layout mode](crate::mem::allocator::layout_mode)
+    LayoutMismatch,
+    /// The allocator is initialized at another [base offset](crate::mem::allocator::AllocatorBuilder::base_offset)
+    BaseOffsetMismatch,
+    /// It is impossible to grow stable memory to fit the backup
+    OutOfMemory,
+    /// Another restore is not finished yet
+    InProgress,
+    /// There is no restore in progress, see [restore_begin]
+    NotStarted,
+    /// Some chunks of the manifest are not restored yet
+    MissingChunks {
+        /// Number of chunks, which are not restored yet
+        count: u64,
+    },
+}
+
+impl From<ReinitError> for BackupError {
+    #[inline]
+    fn from(err: ReinitError) -> Self {
+        match err {
+            ReinitError::LayoutMismatch(_) => Self::LayoutMismatch,
+            _ => Self::IncompatibleVersion,
+        }
+    }
+}
+
+impl BackupManifest {
+    /// Returns the number of chunks in this backup
+    #[inline]
+    pub fn chunks_count(&self) -> u64 {
+        self.chunk_hashes.len() as u64
+    }
+
+    /// Returns `Ok`, if the chunk matches its hash from this manifest
+    pub fn verify_chunk(&self, idx: u64, chunk: &[u8]) -> Result<(), BackupError> {
+        let expected = self
+            .chunk_hashes
+            .get(idx as usize)
+            .ok_or(BackupError::UnknownChunk)?;
+
+        if hash_chunk(chunk) != *expected {
+            return Err(BackupError::HashMismatch);
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads the whole heap and builds a [BackupManifest] of it
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator.
+pub fn backup_manifest() -> BackupManifest {
+    let range = get_heap_range();
+    let size = range.end - range.start;
+    let chunks_count = (size + BACKUP_CHUNK_SIZE - 1) / BACKUP_CHUNK_SIZE;
+
+    BackupManifest {
+        layout_version: LAYOUT_VERSION,
+        base_offset: range.start,
+        size,
+        chunk_size: BACKUP_CHUNK_SIZE,
+        chunk_hashes: (0..chunks_count)
+            .map(|idx| hash_chunk(&backup_chunk(idx)))
+            .collect(),
+        roots: get_roots(),
+        meta: export_meta(),
+    }
+}
+
+/// Returns the current content of the chunk with this index
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator or if the chunk is beyond the heap.
+pub fn backup_chunk(idx: u64) -> Vec<u8> {
+    let range = get_heap_range();
+    let offset = range.start + idx * BACKUP_CHUNK_SIZE;
+    assert!(offset < range.end, "Chunk {} is beyond the heap", idx);
+
+    let mut buf = vec![0u8; BACKUP_CHUNK_SIZE.min(range.end - offset) as usize];
+    stable::read(offset, &mut buf);
+
+    buf
+}
+
+/// Starts restoring the backup, described by the manifest
+///
+/// The allocator should be initialized at the same [base offset](crate::mem::allocator::AllocatorBuilder::base_offset),
+/// as the one the backup was taken at, and should not be used until [restore_finish] - the whole
+/// heap is overwritten.
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator.
+pub fn restore_begin(manifest: BackupManifest) -> Result<(), BackupError> {
+    if manifest.layout_version > LAYOUT_VERSION {
+        return Err(BackupError::IncompatibleVersion);
+    }
+
+    // nothing is written, until the metadata is known to be compatible with this build
+    StableMemoryAllocator::import_meta(&manifest.meta)?;
+
+    if get_heap_range().start != manifest.base_offset {
+        return Err(BackupError::BaseOffsetMismatch);
+    }
+
+    RESTORE.with(|it| {
+        let mut it = it.borrow_mut();
+        if it.is_some() {
+            return Err(BackupError::InProgress);
+        }
+
+        *it = Some(RestoreState {
+            manifest,
+            restored: BTreeSet::new(),
+        });
+
+        Ok(())
+    })
+}
+
+/// Verifies the chunk against the manifest and writes it back to stable memory, growing it, if needed
+///
+/// Chunks can be restored in any order, restoring the same chunk again is allowed.
+pub fn restore_chunk(idx: u64, chunk: &[u8]) -> Result<(), BackupError> {
+    RESTORE.with(|it| {
+        let mut it = it.borrow_mut();
+        let state = it.as_mut().ok_or(BackupError::NotStarted)?;
+
+        state.manifest.verify_chunk(idx, chunk)?;
+
+        let offset = state.manifest.base_offset + idx * state.manifest.chunk_size;
+        let end = offset + chunk.len() as u64;
+
+        let required_pages = (end + PAGE_SIZE_BYTES - 1) / PAGE_SIZE_BYTES;
+        let grown_pages = stable::size_pages();
+        if grown_pages < required_pages {
+            stable::grow(required_pages - grown_pages).map_err(|_| BackupError::OutOfMemory)?;
+        }
+
+        stable::write(offset, chunk);
+        state.restored.insert(idx);
+
+        Ok(())
+    })
+}
+
+/// Finishes the restore, once every chunk of the manifest is restored
+///
+/// Replaces the allocator's metadata with the one from the manifest and re-declares all named roots
+/// of the manifest. If some chunks are missing, the restore stays in progress.
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator.
+pub fn restore_finish() -> Result<(), BackupError> {
+    let state = RESTORE.with(|it| {
+        let mut it = it.borrow_mut();
+        let state = it.as_ref().ok_or(BackupError::NotStarted)?;
+
+        let missing = state.manifest.chunks_count() - state.restored.len() as u64;
+        if missing > 0 {
+            return Err(BackupError::MissingChunks { count: missing });
+        }
+
+        Ok(it.take().unwrap())
+    })?;
+
+    // every chunk is verified, so the metadata describes the current state of stable memory
+    unsafe { import_meta(&state.manifest.meta) }?;
+
+    for (id, ptr) in state.manifest.roots {
+        declare_root(&id, ptr);
+    }
+
+    Ok(())
+}
+
+/// Aborts the restore in progress, if any
+///
+/// Chunks, which are already restored, stay in stable memory, so the allocator should not be used
+/// afterwards, until another restore is finished.
+pub fn restore_abort() {
+    RESTORE.with(|it| it.borrow_mut().take());
+}
+
+fn hash_chunk(chunk: &[u8]) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(chunk);
+
+    hasher.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::SVec;
+    use crate::utils::backup::{
+        backup_chunk, backup_manifest, restore_begin, restore_chunk, restore_finish, BackupError,
+    };
+    use crate::{
+        _debug_validate_allocator, declare_root, deinit_allocator, get_allocated_size, get_root,
+        remove_root, retrieve_custom_data, stable, stable_memory_init, store_custom_data, SBox,
+    };
+
+    #[test]
+    fn works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut vec = SVec::new();
+            for i in 0..300_000u64 {
+                vec.push(i).unwrap();
+            }
+
+            store_custom_data(1, SBox::new(vec).unwrap());
+        }
+
+        declare_root("backup", 10);
+
+        let manifest = backup_manifest();
+        assert!(manifest.chunks_count() > 1);
+
+        let chunks = (0..manifest.chunks_count())
+            .map(backup_chunk)
+            .collect::<Vec<_>>();
+
+        assert_eq!(manifest.verify_chunk(1, &chunks[1]), Ok(()));
+        assert_eq!(restore_chunk(0, &chunks[0]), Err(BackupError::NotStarted));
+
+        deinit_allocator().unwrap();
+        stable::clear();
+        stable_memory_init();
+
+        restore_begin(manifest.clone()).unwrap();
+        assert_eq!(
+            restore_begin(manifest.clone()),
+            Err(BackupError::InProgress)
+        );
+        assert_eq!(restore_chunk(0, &chunks[1]), Err(BackupError::HashMismatch));
+        assert_eq!(
+            restore_chunk(manifest.chunks_count(), &chunks[0]),
+            Err(BackupError::UnknownChunk)
+        );
+
+        for (idx, chunk) in chunks.iter().enumerate().skip(1).rev() {
+            restore_chunk(idx as u64, chunk).unwrap();
+        }
+        assert_eq!(
+            restore_finish(),
+            Err(BackupError::MissingChunks { count: 1 })
+        );
+
+        restore_chunk(0, &chunks[0]).unwrap();
+        restore_finish().unwrap();
+        assert_eq!(get_root("backup"), Some(10));
+
+        {
+            let vec = retrieve_custom_data::<SVec<u64>>(1).unwrap().into_inner();
+            assert_eq!(vec.len(), 300_000);
+
+            for (i, it) in vec.iter().enumerate() {
+                assert_eq!(*it, i as u64);
+            }
+        }
+
+        remove_root("backup");
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
@@ -0,0 +1,165 @@
+//! Budget-aware resumable iteration, see [Budget] and [ResumableIter].
+//!
+//! Allows background jobs (timers, heartbeat) to process a huge collection across multiple calls
+//! without exceeding the per-message instruction limit. Each call of an `iter_resumable()` method
+//! visits as much as the budget allows and returns a continuation token for the next call - its
+//! kind depends on the collection.
+
+use crate::utils::instruction_counter;
+
+/// Limits the amount of work, a single call of a background job does over a collection
+///
+/// Each resumable iteration yields at least one element (if there is any), so a job always makes
+/// progress, even if the budget is already spent when it starts.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Budget {
+    /// Stop after yielding this number of elements
+    Elements(u64),
+    /// Stop once the canister has executed this number of wasm instructions in the current message
+    ///
+    /// Instructions are counted with [ic_cdk::api::performance_counter]. Outside of a canister
+    /// there is no instruction counter, so this budget is never exhausted there.
+    Instructions(u64),
+}
+
+impl Budget {
+    /// Returns [true] if no more elements should be yielded, after `spent` elements were yielded
+    pub fn is_exhausted(&self, spent: u64) -> bool {
+        match self {
+            Budget::Elements(max) => spent >= *max,
+            Budget::Instructions(max) => {
+                instruction_counter().map(|it| it >= *max).unwrap_or(false)
+            }
+        }
+    }
+}
+
+/// An iterator that stops once its [Budget] is exhausted, remembering where to continue from
+///
+/// Created by `iter_resumable()` methods of collections. Once the iterator returns [None], call
+/// [ResumableIter::continuation] to get a token to pass into the next `iter_resumable()` call. The
+/// token is [None], if the whole collection was visited.
+pub struct ResumableIter<I: Iterator, C, F: Fn(&I::Item) -> C> {
+    inner: I,
+    budget: Budget,
+    spent: u64,
+    token_of: F,
+    continuation: Option<C>,
+    done: bool,
+}
+
+impl<I: Iterator, C, F: Fn(&I::Item) -> C> ResumableIter<I, C, F> {
+    #[inline]
+    pub(crate) fn new(inner: I, budget: Budget, token_of: F) -> Self {
+        Self {
+            inner,
+            budget,
+            spent: 0,
+            token_of,
+            continuation: None,
+            done: false,
+        }
+    }
+
+    /// Returns the number of elements, yielded by this iterator so far
+    #[inline]
+    pub fn spent(&self) -> u64 {
+        self.spent
+    }
+
+    /// Returns [true] if this iterator has stopped, either because its budget is exhausted or
+    /// because there are no more elements
+    #[inline]
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Returns the token the next iteration should start from
+    ///
+    /// [None] means there is nothing left to visit. Only meaningful after this iterator has
+    /// stopped.
+    #[inline]
+    pub fn continuation(&self) -> Option<&C> {
+        self.continuation.as_ref()
+    }
+
+    /// Consumes this iterator, returning the token the next iteration should start from
+    #[inline]
+    pub fn into_continuation(self) -> Option<C> {
+        self.continuation
+    }
+}
+
+impl<I: Iterator, C, F: Fn(&I::Item) -> C> Iterator for ResumableIter<I, C, F> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let it = self.inner.next();
+
+        match it {
+            None => {
+                self.done = true;
+
+                None
+            }
+            Some(it) if self.spent > 0 && self.budget.is_exhausted(self.spent) => {
+                self.continuation = Some((self.token_of)(&it));
+                self.done = true;
+
+                None
+            }
+            Some(it) => {
+                self.spent += 1;
+
+                Some(it)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::utils::budget::{Budget, ResumableIter};
+
+    #[test]
+    fn works_fine() {
+        let mut from = 0;
+        let mut calls = 0;
+        let mut visited = Vec::new();
+
+        loop {
+            let mut iter = ResumableIter::new(from..10, Budget::Elements(3), |it| *it);
+            visited.extend(&mut iter);
+            calls += 1;
+
+            assert!(iter.is_done());
+            assert!(iter.spent() <= 3);
+
+            match iter.into_continuation() {
+                Some(next) => from = next,
+                None => break,
+            }
+        }
+
+        assert_eq!(calls, 4);
+        assert_eq!(visited, (0..10).collect::<Vec<_>>());
+
+        let mut iter = ResumableIter::new(0..10, Budget::Elements(0), |it| *it);
+        assert_eq!(iter.next(), Some(0));
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.continuation(), Some(&1));
+
+        let mut iter = ResumableIter::new(0..10, Budget::Instructions(0), |it| *it);
+        assert_eq!(iter.by_ref().count(), 10);
+        assert!(iter.continuation().is_none());
+
+        let mut iter = ResumableIter::new(0..0, Budget::Elements(10), |it| *it);
+        assert_eq!(iter.next(), None);
+        assert!(iter.is_done());
+        assert!(iter.continuation().is_none());
+    }
+}
@@ -0,0 +1,57 @@
+//! Candid export and import of whole collections.
+//!
+//! Collections, supporting it ([SVec](crate::collections::SVec), [SBTreeMap](crate::collections::SBTreeMap)),
+//! can be encoded into a sequence of Candid-encoded batches with `to_candid_chunks()` and rebuilt
+//! from such a sequence with `from_candid_chunks()`. Each chunk is a self-contained Candid message
+//! (a `vec` of elements or of `(key, value)` tuples), so chunks can be sent to another
+//! canister one per call, or downloaded and inspected with any Candid tool.
+//!
+//! Only available with the `candid_chunks` feature.
+
+use crate::OutOfMemory;
+
+/// An error, returned when a collection can't be rebuilt from Candid chunks
+#[derive(Debug)]
+pub enum CandidChunksError {
+    /// One of the chunks is not a valid Candid encoding of the collection's elements
+    Candid(candid::Error),
+    /// There is not enough stable memory to hold the collection
+    OutOfMemory,
+}
+
+impl From<candid::Error> for CandidChunksError {
+    #[inline]
+    fn from(e: candid::Error) -> Self {
+        Self::Candid(e)
+    }
+}
+
+impl From<OutOfMemory> for CandidChunksError {
+    #[inline]
+    fn from(_: OutOfMemory) -> Self {
+        Self::OutOfMemory
+    }
+}
+
+/// Splits `iter` into chunks of `chunk_len` items and encodes each of them with `encode`
+pub(crate) fn encode_chunks<I, F>(
+    mut iter: I,
+    chunk_len: usize,
+    mut encode: F,
+) -> impl Iterator<Item = Vec<u8>>
+where
+    I: Iterator,
+    F: FnMut(&[I::Item]) -> candid::Result<Vec<u8>>,
+{
+    assert!(chunk_len > 0, "Chunk length should be greater than zero");
+
+    std::iter::from_fn(move || {
+        let chunk = iter.by_ref().take(chunk_len).collect::<Vec<_>>();
+
+        if chunk.is_empty() {
+            None
+        } else {
+            Some(encode(&chunk).expect("Unable to encode a chunk"))
+        }
+    })
+}
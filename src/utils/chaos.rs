@@ -0,0 +1,235 @@
+//! Trap-atomicity chaos testing
+//!
+//! On the IC, a call, which traps, changes nothing: both stable memory and heap memory are restored
+//! to their state before the call. A stable collection, which is correct, when its operations
+//! complete, is therefore also correct, when they trap - unless its design relies on the order of
+//! its writes surviving a trap (e.g. some heap-side cache, which is not rolled back together with
+//! stable memory).
+//!
+//! [call_with_trap] simulates such a trap at a random write of an operation and rolls everything
+//! back, so a test can then check, that the collection still passes its `validate()` and still
+//! contains the same elements.
+//!
+//! Only available for targets other than `wasm`.
+
+use crate::encoding::AsFixedSizeBytes;
+use crate::primitive::StableType;
+use crate::utils::mem_context::FaultInjection;
+use crate::{export_meta, import_meta, stable};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+/// Result of [call_with_trap]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CallOutcome<R> {
+    /// The operation didn't write to stable memory, so it couldn't trap
+    Completed(R),
+    /// The operation trapped at a write and everything was rolled back
+    Trapped {
+        /// Index of the write, the operation trapped at
+        at_write: u64,
+        /// Number of writes, the operation performs, when it doesn't trap
+        writes_count: u64,
+    },
+}
+
+/// Runs the operation on the state, as a canister call, which traps at a write, picked by the seed
+///
+/// The operation is executed twice. The first time - to count its writes. Then everything is rolled
+/// back and the operation is executed again, but this time the `seed % writes_count`-th write
+/// panics. The panic is caught and everything is rolled back again, as the IC does for a trapped
+/// call, so after this function returns, the state (and all the stable memory) is exactly the same,
+/// as it was before it was called.
+///
+/// "Everything" is stable memory, the allocator and the state. Other heap memory is not rolled back,
+/// so the operation should only change the state. The operation may be called twice and may panic
+/// in the middle, so it should be [FnMut] and should not rely on heap values it changes.
+///
+/// Faults, injected with [stable::inject_faults] before the call, are reset.
+///
+/// # Panics
+/// Panics, if the operation panics by itself, or if a custom [MemContext](crate::utils::mem_context::MemContext)
+/// is set (see [stable::set_context]) - only the default stable memory emulation can be rolled back.
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::collections::SBTreeMap;
+/// # use ic_stable_memory::stable_memory_init;
+/// # use ic_stable_memory::utils::chaos::call_with_trap;
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// let mut map = SBTreeMap::new();
+/// for i in 0..100u64 {
+///     map.insert(i, i).expect("Out of memory");
+/// }
+///
+/// call_with_trap(&mut map, 12345, |map| map.insert(1000, 1000));
+///
+/// assert!(map.validate().is_ok());
+/// assert!(!map.contains_key(&1000));
+/// assert_eq!(map.len(), 100);
+/// ```
+pub fn call_with_trap<S, R, F>(state: &mut S, seed: u64, mut op: F) -> CallOutcome<R>
+where
+    S: StableType + AsFixedSizeBytes,
+    F: FnMut(&mut S) -> R,
+{
+    assert!(
+        !stable::has_custom_context(),
+        "Can't roll back a custom MemContext"
+    );
+
+    stable::inject_faults(FaultInjection::default());
+    let checkpoint = Checkpoint::new(state);
+
+    let res = op(state);
+    let writes_count = stable::writes_count();
+
+    if writes_count == 0 {
+        return CallOutcome::Completed(res);
+    }
+
+    drop(res);
+    checkpoint.rollback(state);
+
+    let at_write = seed % writes_count;
+    stable::inject_faults(FaultInjection {
+        max_pages: None,
+        panic_after_writes: Some(at_write),
+    });
+
+    let trapped = catch_unwind(AssertUnwindSafe(|| drop(op(state)))).is_err();
+    stable::inject_faults(FaultInjection::default());
+
+    assert!(
+        trapped,
+        "The operation performed less writes, than during the first run"
+    );
+
+    checkpoint.rollback(state);
+
+    CallOutcome::Trapped {
+        at_write,
+        writes_count,
+    }
+}
+
+struct Checkpoint {
+    memory: crate::utils::mem_context::TestMemContext,
+    meta: Vec<u8>,
+    state: Vec<u8>,
+    state_should_stable_drop: bool,
+}
+
+impl Checkpoint {
+    fn new<S: StableType + AsFixedSizeBytes>(state: &S) -> Self {
+        let mut buf = vec![0u8; S::SIZE];
+        state.as_fixed_size_bytes(&mut buf);
+
+        Self {
+            memory: stable::checkpoint(),
+            meta: export_meta(),
+            state: buf,
+            state_should_stable_drop: state.should_stable_drop(),
+        }
+    }
+
+    fn rollback<S: StableType + AsFixedSizeBytes>(&self, state: &mut S) {
+        stable::rollback(self.memory.clone());
+        unsafe { import_meta(&self.meta).expect("Unable to restore the allocator") };
+
+        let mut restored = S::from_fixed_size_bytes(&self.state);
+        unsafe {
+            if self.state_should_stable_drop {
+                restored.stable_drop_flag_on();
+            } else {
+                restored.stable_drop_flag_off();
+            }
+        }
+
+        // the changed state refers to the memory, which is already rolled back
+        let mut changed = std::mem::replace(state, restored);
+        unsafe { changed.stable_drop_flag_off() };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::{SBTreeMap, SVec};
+    use crate::utils::chaos::{call_with_trap, CallOutcome};
+    use crate::{_debug_validate_allocator, get_allocated_size, stable, stable_memory_init};
+    use rand::{thread_rng, Rng};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn btree_map_survives_traps() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut rng = thread_rng();
+            let mut map = SBTreeMap::<u64, u64>::new();
+            let mut model = BTreeMap::new();
+
+            for _ in 0..300 {
+                let key = rng.gen_range(0..200u64);
+                let allocated_size = get_allocated_size();
+
+                let outcome = if rng.gen_bool(0.6) {
+                    call_with_trap(&mut map, rng.gen(), |map| {
+                        map.insert(key, key).unwrap();
+                    })
+                } else {
+                    call_with_trap(&mut map, rng.gen(), |map| {
+                        map.remove(&key);
+                    })
+                };
+
+                if let CallOutcome::Trapped { .. } = outcome {
+                    assert_eq!(get_allocated_size(), allocated_size);
+                }
+
+                map.validate().unwrap();
+                _debug_validate_allocator();
+                assert!(map
+                    .iter()
+                    .map(|(k, v)| (*k, *v))
+                    .eq(model.iter().map(|(k, v)| (*k, *v))));
+
+                // now the same operation completes
+                if rng.gen_bool(0.5) {
+                    map.insert(key, key).unwrap();
+                    model.insert(key, key);
+                } else {
+                    map.remove(&key);
+                    model.remove(&key);
+                }
+            }
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn vec_survives_traps() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut vec = SVec::<u64>::new();
+
+            for i in 0..200 {
+                let outcome = call_with_trap(&mut vec, i * 7, |vec| vec.push(i).unwrap());
+                assert!(matches!(outcome, CallOutcome::Trapped { .. }));
+                assert_eq!(vec.len() as u64, i);
+
+                vec.push(i).unwrap();
+            }
+
+            assert!(vec.iter().map(|it| *it).eq(0..200));
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
@@ -0,0 +1,105 @@
+//! Entry points for coverage-guided fuzzers (cargo-fuzz, AFL)
+//!
+//! [fuzz_step] interprets an arbitrary byte string as a sequence of operations on the allocator or
+//! on a collection, applies them in lockstep to the model from [model_test](crate::utils::model_test)
+//! and panics, once the result differs or some invariant breaks. The same input always produces the
+//! same sequence, so a crashing input found by a fuzzer is also a reproducible test case.
+//!
+//! A `cargo-fuzz` target is simply:
+//! ```text
+//! fuzz_target!(|data: &[u8]| {
+//!     ic_stable_memory::utils::fuzz::fuzz_step(FuzzTarget::BTreeMap, data);
+//! });
+//! ```
+//!
+//! Only available with the `fuzzing` feature and only for targets other than `wasm`.
+
+use crate::utils::model_test::{AllocatorModel, BTreeMapModel, Model, ModelRng, VecModel};
+use crate::{_debug_validate_allocator, get_allocated_size, stable, stable_memory_init};
+
+/// Structure exercised by [fuzz_step]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FuzzTarget {
+    /// Allocation, deallocation and reallocation of memory blocks up to 2KB
+    Allocator,
+    /// [SBTreeMap](crate::collections::SBTreeMap) with keys from a small range, to make node splits and merges frequent
+    BTreeMap,
+    /// [SVec](crate::collections::SVec)
+    Vec,
+}
+
+impl FuzzTarget {
+    /// Picks a target by a byte, e.g. the first byte of a fuzzer's input
+    #[inline]
+    pub fn from_byte(byte: u8) -> Self {
+        match byte % 3 {
+            0 => FuzzTarget::Allocator,
+            1 => FuzzTarget::BTreeMap,
+            _ => FuzzTarget::Vec,
+        }
+    }
+}
+
+/// Interprets the bytes as a sequence of operations on the target and checks it against the model
+///
+/// Stable memory is cleared before the sequence is applied, so each call is independent from the
+/// previous ones. Each operation consumes a few bytes of the input - the sequence ends, when the
+/// input is exhausted.
+///
+/// # Panics
+/// Panics if the target diverges from the model, if some invariant is broken, or if there is a memory
+/// leak after all structures are dropped.
+pub fn fuzz_step(target: FuzzTarget, data: &[u8]) {
+    match target {
+        FuzzTarget::Allocator => fuzz_model(data, || AllocatorModel::new(2048)),
+        FuzzTarget::BTreeMap => fuzz_model(data, || BTreeMapModel::new(64)),
+        FuzzTarget::Vec => fuzz_model(data, || VecModel::new(256)),
+    }
+}
+
+/// Same as [fuzz_step], but for a custom [Model]
+pub fn fuzz_model<M: Model, F: FnOnce() -> M>(data: &[u8], new_model: F) {
+    stable::clear();
+    stable_memory_init();
+    let base_allocated_size = get_allocated_size();
+
+    {
+        let mut rng = ModelRng::from_bytes(data);
+        let mut model = new_model();
+
+        while !rng.is_exhausted() {
+            let op = model.gen_op(&mut rng);
+
+            model.apply(&op);
+            model.check();
+        }
+    }
+
+    _debug_validate_allocator();
+    assert_eq!(
+        get_allocated_size(),
+        base_allocated_size,
+        "Memory leak after drop"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::utils::fuzz::{fuzz_step, FuzzTarget};
+    use crate::utils::model_test::ModelRng;
+
+    #[test]
+    fn works_fine() {
+        fuzz_step(FuzzTarget::Allocator, &[]);
+        fuzz_step(FuzzTarget::BTreeMap, &[0xff; 10]);
+
+        let mut rng = ModelRng::new(7);
+        let data = (0..20_000)
+            .map(|_| rng.next_u64() as u8)
+            .collect::<Vec<_>>();
+
+        for byte in 0..3 {
+            fuzz_step(FuzzTarget::from_byte(byte), &data);
+        }
+    }
+}
@@ -0,0 +1,343 @@
+//! Dumps the state of stable memory in formats, readable by external tools
+//!
+//! [HeapDump] contains every memory block (see [walk_heap](crate::walk_heap)), free blocks grouped
+//! by size class, named roots (see [declare_root](crate::declare_root)) and the topology of
+//! selected [SBTreeMap]s. It can be rendered as JSON, for custom scripts, or as Graphviz DOT, to
+//! visualize fragmentation and tree shape.
+//!
+//! To inspect a stable memory image, downloaded from a canister, load it into the stable memory
+//! emulation (e.g. with [stable::set_context](crate::stable::set_context)), call
+//! [reinit_allocator](crate::reinit_allocator) and capture a dump.
+//!
+//! Only available with the `debug_structure` feature.
+
+use crate::collections::SBTreeMap;
+use crate::encoding::AsFixedSizeBytes;
+use crate::mem::allocator::{HeapBlock, SizeClassStats};
+use crate::mem::StablePtr;
+use crate::primitive::StableType;
+use crate::{get_allocator_stats, get_roots, walk_heap};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// A single node of an [SBTreeMap], see [SBTreeMap::dump_nodes]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BTreeNodeDump {
+    /// Pointer to the node
+    pub ptr: StablePtr,
+    /// Number of keys in the node
+    pub len: usize,
+    /// Pointers to child nodes, empty for leaves
+    pub children: Vec<StablePtr>,
+}
+
+/// A snapshot of stable memory layout
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::collections::SBTreeMap;
+/// # use ic_stable_memory::stable_memory_init;
+/// # use ic_stable_memory::utils::heap_dump::HeapDump;
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// let mut map = SBTreeMap::new();
+/// for i in 0..100u64 {
+///     map.insert(i, i).expect("Out of memory");
+/// }
+///
+/// let mut dump = HeapDump::capture();
+/// dump.add_btree_map("users", &map);
+///
+/// let json = dump.to_json();
+/// let dot = dump.to_dot();
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeapDump {
+    /// Every memory block, in ascending order of their pointers
+    pub blocks: Vec<HeapBlock>,
+    /// Free blocks, grouped by size class
+    pub free_size_classes: Vec<SizeClassStats>,
+    /// Named roots
+    pub roots: BTreeMap<String, StablePtr>,
+    /// Nodes of added [SBTreeMap]s, by their names
+    pub btree_maps: BTreeMap<String, Vec<BTreeNodeDump>>,
+}
+
+impl HeapDump {
+    /// Walks the heap and captures blocks, free size classes and named roots
+    ///
+    /// # Panics
+    /// Panics if there is no initialized stable memory allocator.
+    pub fn capture() -> Self {
+        Self {
+            blocks: walk_heap().collect(),
+            free_size_classes: get_allocator_stats().free_size_classes,
+            roots: get_roots(),
+            btree_maps: BTreeMap::new(),
+        }
+    }
+
+    /// Adds the topology of the [SBTreeMap] to this dump
+    pub fn add_btree_map<
+        K: StableType + AsFixedSizeBytes + Ord,
+        V: StableType + AsFixedSizeBytes,
+    >(
+        &mut self,
+        name: &str,
+        map: &SBTreeMap<K, V>,
+    ) {
+        self.btree_maps.insert(name.to_string(), map.dump_nodes());
+    }
+
+    /// Renders this dump as a JSON object
+    ///
+    /// ```text
+    /// {
+    ///   "blocks": [{"ptr": 8, "size": 16, "allocated": true}, ...],
+    ///   "free_size_classes": [{"min_size": 16, "blocks_count": 1, "total_size": 24}, ...],
+    ///   "roots": {"name": 8, ...},
+    ///   "btree_maps": {"name": [{"ptr": 8, "len": 3, "children": [...]}, ...], ...}
+    /// }
+    /// ```
+    pub fn to_json(&self) -> String {
+        let mut res = String::from("{\"blocks\":[");
+
+        for (i, b) in self.blocks.iter().enumerate() {
+            if i > 0 {
+                res.push(',');
+            }
+
+            write!(
+                res,
+                "{{\"ptr\":{},\"size\":{},\"allocated\":{}}}",
+                b.ptr, b.size, b.allocated
+            )
+            .unwrap();
+        }
+
+        res.push_str("],\"free_size_classes\":[");
+
+        for (i, c) in self.free_size_classes.iter().enumerate() {
+            if i > 0 {
+                res.push(',');
+            }
+
+            write!(
+                res,
+                "{{\"min_size\":{},\"blocks_count\":{},\"total_size\":{}}}",
+                c.min_size, c.blocks_count, c.total_size
+            )
+            .unwrap();
+        }
+
+        res.push_str("],\"roots\":{");
+
+        for (i, (name, ptr)) in self.roots.iter().enumerate() {
+            if i > 0 {
+                res.push(',');
+            }
+
+            write!(res, "{}:{}", json_string(name), ptr).unwrap();
+        }
+
+        res.push_str("},\"btree_maps\":{");
+
+        for (i, (name, nodes)) in self.btree_maps.iter().enumerate() {
+            if i > 0 {
+                res.push(',');
+            }
+
+            write!(res, "{}:[", json_string(name)).unwrap();
+
+            for (j, node) in nodes.iter().enumerate() {
+                if j > 0 {
+                    res.push(',');
+                }
+
+                let children = node
+                    .children
+                    .iter()
+                    .map(|it| it.to_string())
+                    .collect::<Vec<_>>()
+                    .join(",");
+
+                write!(
+                    res,
+                    "{{\"ptr\":{},\"len\":{},\"children\":[{}]}}",
+                    node.ptr, node.len, children
+                )
+                .unwrap();
+            }
+
+            res.push(']');
+        }
+
+        res.push_str("}}");
+
+        res
+    }
+
+    /// Renders this dump as a Graphviz DOT digraph
+    ///
+    /// The heap is a chain of blocks (allocated ones are filled), named roots point to their blocks,
+    /// each [SBTreeMap] is a separate cluster of its nodes.
+    pub fn to_dot(&self) -> String {
+        let mut res = String::from("digraph stable_memory {\n  node [shape=box];\n");
+
+        res.push_str("  subgraph cluster_heap {\n    label=\"heap\";\n");
+        for b in &self.blocks {
+            let style = if b.allocated {
+                "style=filled, fillcolor=lightblue"
+            } else {
+                "style=dashed"
+            };
+
+            writeln!(
+                res,
+                "    b{} [label=\"{}\\n{} bytes\", {}];",
+                b.ptr, b.ptr, b.size, style
+            )
+            .unwrap();
+        }
+
+        for pair in self.blocks.windows(2) {
+            writeln!(
+                res,
+                "    b{} -> b{} [style=invis];",
+                pair[0].ptr, pair[1].ptr
+            )
+            .unwrap();
+        }
+        res.push_str("  }\n");
+
+        for (i, (name, ptr)) in self.roots.iter().enumerate() {
+            writeln!(res, "  r{} [label={}, shape=ellipse];", i, dot_string(name)).unwrap();
+
+            if self.blocks.iter().any(|b| b.ptr == *ptr) {
+                writeln!(res, "  r{} -> b{};", i, ptr).unwrap();
+            }
+        }
+
+        for (i, (name, nodes)) in self.btree_maps.iter().enumerate() {
+            writeln!(
+                res,
+                "  subgraph cluster_btree_{} {{\n    label={};",
+                i,
+                dot_string(name)
+            )
+            .unwrap();
+
+            for node in nodes {
+                let shape = if node.children.is_empty() {
+                    "box"
+                } else {
+                    "box, style=rounded"
+                };
+
+                writeln!(
+                    res,
+                    "    t{}_{} [label=\"{}\\nlen = {}\", shape={}];",
+                    i, node.ptr, node.ptr, node.len, shape
+                )
+                .unwrap();
+
+                for child in &node.children {
+                    writeln!(res, "    t{}_{} -> t{}_{};", i, node.ptr, i, child).unwrap();
+                }
+            }
+
+            res.push_str("  }\n");
+        }
+
+        res.push_str("}\n");
+
+        res
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut res = String::with_capacity(s.len() + 2);
+    res.push('"');
+
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(res, "\\u{:04x}", c as u32).unwrap(),
+            c => res.push(c),
+        }
+    }
+
+    res.push('"');
+    res
+}
+
+fn dot_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::SBTreeMap;
+    use crate::utils::heap_dump::{json_string, HeapDump};
+    use crate::{
+        _debug_validate_allocator, allocate, deallocate, declare_root, get_allocated_size, stable,
+        stable_memory_init,
+    };
+
+    #[test]
+    fn works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let slice = unsafe { allocate(100).unwrap() };
+            declare_root("my \"slice\"", slice.as_ptr());
+
+            let mut map = SBTreeMap::new();
+            for i in 0..100u64 {
+                map.insert(i, i).unwrap();
+            }
+
+            let mut dump = HeapDump::capture();
+            dump.add_btree_map("map", &map);
+
+            assert!(dump
+                .blocks
+                .iter()
+                .any(|b| b.ptr == slice.as_ptr() && b.allocated));
+            assert_eq!(dump.roots.get("my \"slice\""), Some(&slice.as_ptr()));
+
+            let nodes = &dump.btree_maps["map"];
+            let leaves_len = nodes
+                .iter()
+                .filter(|n| n.children.is_empty())
+                .map(|n| n.len)
+                .sum::<usize>();
+            let children_count = nodes.iter().map(|n| n.children.len()).sum::<usize>();
+
+            assert_eq!(leaves_len, 100);
+            assert_eq!(children_count, nodes.len() - 1);
+
+            let json = dump.to_json();
+            assert!(json.starts_with("{\"blocks\":[{\"ptr\":"));
+            assert!(json.contains("\"roots\":{\"my \\\"slice\\\"\":"));
+            assert!(json.ends_with("]}}"));
+
+            let dot = dump.to_dot();
+            assert!(dot.starts_with("digraph stable_memory {"));
+            assert!(dot.contains(&format!("-> b{};", slice.as_ptr())));
+            assert!(dot.ends_with("}\n"));
+
+            deallocate(slice);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+
+        assert_eq!(json_string("a\n\u{1}"), "\"a\\n\\u0001\"");
+    }
+}
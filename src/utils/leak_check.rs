@@ -0,0 +1,296 @@
+//! Memory leak checks for tests
+//!
+//! [assert_no_leaks] runs a test body and checks, that the allocated size of stable memory returns
+//! to what it was before the body, once everything created inside of it is dropped. If it doesn't,
+//! the panic message lists leaked memory blocks. Allocations can be labeled with
+//! [tag_allocations], so it is easier to tell which part of the code leaked them.
+//!
+//! ```rust
+//! # use ic_stable_memory::collections::SVec;
+//! # use ic_stable_memory::stable_memory_init;
+//! # use ic_stable_memory::utils::leak_check::{assert_no_leaks, tag_allocations};
+//! # unsafe { ic_stable_memory::mem::clear(); }
+//! # stable_memory_init();
+//! assert_no_leaks(|| {
+//!     let mut vec = tag_allocations("my vec", || SVec::new_with_capacity(10).expect("Out of memory"));
+//!     vec.push(10u64).expect("Out of memory");
+//! });
+//! ```
+//!
+//! Only available for targets other than `wasm`.
+
+use crate::mem::allocator::HeapBlock;
+use crate::mem::StablePtr;
+use crate::{get_allocated_size, walk_heap};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Display, Formatter};
+
+#[derive(Default)]
+struct Tracker {
+    depth: usize,
+    current_tag: Option<&'static str>,
+    tags: BTreeMap<StablePtr, &'static str>,
+}
+
+thread_local! {
+    static TRACKER: RefCell<Tracker> = RefCell::new(Tracker::default());
+}
+
+/// A memory block, allocated inside [assert_no_leaks] and never released
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LeakedBlock {
+    /// Pointer to the block
+    pub ptr: StablePtr,
+    /// Size of the block (excluding size metadata)
+    pub size: u64,
+    /// Tag of the allocation, see [tag_allocations]
+    pub tag: Option<&'static str>,
+}
+
+/// Difference between the heap before and after a test body, see [leak_diff]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeakDiff {
+    /// Allocated size before the body
+    pub baseline_size: u64,
+    /// Allocated size after the body
+    pub allocated_size: u64,
+    /// Blocks, which are allocated now, but were not allocated before, in ascending order of their pointers
+    pub leaked: Vec<LeakedBlock>,
+    /// Blocks, which were allocated before, but are not allocated now
+    pub released: Vec<HeapBlock>,
+}
+
+impl Display for LeakDiff {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Allocated size changed from {} to {} bytes",
+            self.baseline_size, self.allocated_size
+        )?;
+
+        for block in &self.leaked {
+            writeln!(
+                f,
+                "  leaked {} bytes at {} ({})",
+                block.size,
+                block.ptr,
+                block.tag.unwrap_or("untagged")
+            )?;
+        }
+
+        for block in &self.released {
+            writeln!(
+                f,
+                "  released {} bytes at {}, allocated before",
+                block.size, block.ptr
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Runs the function and panics, if the allocated size of stable memory has changed after it
+///
+/// Everything, created inside the function, should be dropped by the time it returns. Returns the
+/// result of the function, so it should not contain stable collections either.
+///
+/// See also [leak_diff].
+///
+/// # Panics
+/// Panics with a list of leaked blocks, if there is a leak. Panics if there is no initialized stable
+/// memory allocator.
+pub fn assert_no_leaks<R, F: FnOnce() -> R>(f: F) -> R {
+    let (res, diff) = leak_diff(f);
+
+    if let Some(diff) = diff {
+        panic!("Memory leak detected\n{}", diff);
+    }
+
+    res
+}
+
+/// Same as [assert_no_leaks], but returns the [LeakDiff] instead of panicking
+///
+/// Returns [None], if the allocated size after the function is the same as before it.
+pub fn leak_diff<R, F: FnOnce() -> R>(f: F) -> (R, Option<LeakDiff>) {
+    let baseline_size = get_allocated_size();
+    let baseline = allocated_blocks();
+
+    TRACKER.with(|it| it.borrow_mut().depth += 1);
+    let res = f();
+
+    let allocated_size = get_allocated_size();
+    let diff = if allocated_size == baseline_size {
+        None
+    } else {
+        let current = allocated_blocks();
+
+        let leaked = TRACKER.with(|it| {
+            let tracker = it.borrow();
+
+            current
+                .difference(&baseline)
+                .map(|(ptr, size)| LeakedBlock {
+                    ptr: *ptr,
+                    size: *size,
+                    tag: tracker.tags.get(ptr).copied(),
+                })
+                .collect()
+        });
+
+        let released = baseline
+            .difference(&current)
+            .map(|(ptr, size)| HeapBlock {
+                ptr: *ptr,
+                size: *size,
+                allocated: false,
+            })
+            .collect();
+
+        Some(LeakDiff {
+            baseline_size,
+            allocated_size,
+            leaked,
+            released,
+        })
+    };
+
+    TRACKER.with(|it| {
+        let mut tracker = it.borrow_mut();
+        tracker.depth -= 1;
+
+        if tracker.depth == 0 {
+            tracker.tags.clear();
+        }
+    });
+
+    (res, diff)
+}
+
+/// Tags all memory blocks, allocated (or reallocated) inside the function
+///
+/// Tags are only recorded inside [assert_no_leaks] and [leak_diff] and are only reported for leaked
+/// blocks. Can be nested, the innermost tag wins. Slabs, which hold small slots (see
+/// [allocate_slot](crate::allocate_slot)), are shared between allocations, so they are not tagged.
+pub fn tag_allocations<R, F: FnOnce() -> R>(tag: &'static str, f: F) -> R {
+    let prev = TRACKER.with(|it| it.borrow_mut().current_tag.replace(tag));
+    let res = f();
+    TRACKER.with(|it| it.borrow_mut().current_tag = prev);
+
+    res
+}
+
+#[inline]
+pub(crate) fn on_allocate(ptr: Option<StablePtr>) {
+    TRACKER.with(|it| {
+        let mut tracker = it.borrow_mut();
+
+        if tracker.depth == 0 {
+            return;
+        }
+
+        if let Some(ptr) = ptr {
+            match tracker.current_tag {
+                Some(tag) => tracker.tags.insert(ptr, tag),
+                None => tracker.tags.remove(&ptr),
+            };
+        }
+    })
+}
+
+#[inline]
+pub(crate) fn on_reallocate(old_ptr: StablePtr, new_ptr: Option<StablePtr>) {
+    TRACKER.with(|it| {
+        let mut tracker = it.borrow_mut();
+
+        if tracker.depth == 0 {
+            return;
+        }
+
+        if let Some(new_ptr) = new_ptr {
+            let tag = tracker
+                .current_tag
+                .or_else(|| tracker.tags.get(&old_ptr).copied());
+
+            tracker.tags.remove(&old_ptr);
+            if let Some(tag) = tag {
+                tracker.tags.insert(new_ptr, tag);
+            }
+        }
+    })
+}
+
+fn allocated_blocks() -> BTreeSet<(StablePtr, u64)> {
+    walk_heap()
+        .filter(|it| it.allocated)
+        .map(|it| (it.ptr, it.size))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::{SBTreeMap, SVec};
+    use crate::utils::leak_check::{assert_no_leaks, leak_diff, tag_allocations};
+    use crate::{allocate, deallocate, stable, stable_memory_init};
+
+    #[test]
+    fn works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        let len = assert_no_leaks(|| {
+            let mut map = SBTreeMap::new();
+            for i in 0..100u64 {
+                map.insert(i, i).unwrap();
+            }
+
+            map.len()
+        });
+        assert_eq!(len, 100);
+
+        let (leaked, diff) = leak_diff(|| {
+            let _vec = tag_allocations("vec", || {
+                let mut vec = SVec::new();
+                vec.push(1u64).unwrap();
+                vec
+            });
+
+            let a = unsafe { allocate(100).unwrap() };
+            let b = tag_allocations("b", || unsafe { allocate(200).unwrap() });
+
+            (a, b)
+        });
+
+        let diff = diff.unwrap();
+        assert!(diff.allocated_size - diff.baseline_size >= 300);
+        assert!(diff.released.is_empty());
+        assert_eq!(diff.leaked.len(), 2);
+        assert_eq!(diff.leaked[0].ptr, leaked.0.as_ptr());
+        assert_eq!(diff.leaked[0].tag, None);
+        assert_eq!(diff.leaked[1].tag, Some("b"));
+        assert!(diff.leaked[1].size >= 200);
+        assert!(diff.to_string().contains("(b)"));
+
+        deallocate(leaked.0);
+        deallocate(leaked.1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Memory leak detected")]
+    fn panics_on_leaks() {
+        stable::clear();
+        stable_memory_init();
+
+        assert_no_leaks(|| {
+            let vec = tag_allocations("vec", || {
+                let mut vec = SVec::new();
+                vec.push(1u64).unwrap();
+                vec
+            });
+
+            std::mem::forget(vec);
+        });
+    }
+}
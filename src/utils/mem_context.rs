@@ -4,14 +4,53 @@
 //! For example, [MemContext::size_pages()] on wasm simply transforms into [ic_cdk::api::stable::stable64_size()].
 //!
 //! But when compiled to something else, a stable memory emulation is enabled, which allows all APIs
-//! continue to work even when running inside a `cargo test`, allocating stable memory on heap. This
-//! emulation is pretty accurate in terms of performance. If your algorithm is 4 times slower in stable
-//! memory tests, than in heap, than it is pretty likely that it will be 4 times expensive inside a read
-//! canister's stable memory, than in its heap.
+//! continue to work even when running inside a `cargo test`, allocating stable memory on heap (page
+//! by page, when a page is written to for the first time, so the whole 64-bit address space can be
+//! used in tests). This emulation is pretty accurate in terms of performance. If your algorithm is
+//! 4 times slower in stable memory tests, than in heap, than it is pretty likely that it will be 4
+//! times expensive inside a read canister's stable memory, than in its heap.
 //!
 //! This makes it possible to write full-scale tests which use stable memory as their main memory.
+//!
+//! Both defaults can be replaced with a custom [MemContext] by calling [stable::set_context]. For
+//! example, [FileMemContext] allows off-chain tools to work with a downloaded stable memory dump
+//! using the same code paths, as the canister does, while [CountingMemContext] measures the stable
+//! memory traffic of any other context and [RestrictedMemContext] confines this crate to a region
+//! of it. [CachingMemContext] buffers writes in heap until [stable::flush] is called, and
+//! [TracingMemContext] records every access, so it can be inspected or replayed later.
+//!
+//! There is no multi-memory [MemContext] and none is planned: the Internet Computer exposes a single
+//! stable memory per canister (there are no separate stable memories or multiple wasm memories to
+//! target in `ic_cdk`). To isolate this crate's data from other users of stable memory, confine it to a region of the
+//! stable memory with [RestrictedMemContext], or to a virtual memory of `ic-stable-structures`'
+//! `MemoryManager` (see below).
+//!
+//! With the `stable_structures` feature enabled, this crate can coexist with `ic-stable-structures`
+//! in both directions: `StableStructuresMemContext` runs this crate on top of any of its `Memory`
+//! implementations, while `AllocatorMemory` gives it a `Memory`, allocated by this crate.
 
 use std::cmp::min;
+use std::io::IoSlice;
+
+mod caching;
+mod counting;
+#[cfg(not(target_family = "wasm"))]
+mod file;
+mod restricted;
+#[cfg(feature = "stable_structures")]
+mod stable_structures;
+mod tracing;
+
+pub use caching::CachingMemContext;
+pub use counting::{CountingMemContext, MemAccessStats};
+pub use restricted::RestrictedMemContext;
+pub use tracing::{MemAccess, TraceRing, TraceSink, TracingMemContext};
+
+#[cfg(not(target_family = "wasm"))]
+pub use file::FileMemContext;
+
+#[cfg(feature = "stable_structures")]
+pub use stable_structures::{AllocatorMemory, StableStructuresMemContext};
 
 /// Each wasm memory page is 64K in size
 pub const PAGE_SIZE_BYTES: u64 = 64 * 1024;
@@ -20,15 +59,37 @@ pub const PAGE_SIZE_BYTES: u64 = 64 * 1024;
 #[derive(Debug, Copy, Clone)]
 pub struct OutOfMemory;
 
-pub(crate) trait MemContext {
+/// A backend for stable memory, used by every other part of this crate
+///
+/// See [stable::set_context].
+pub trait MemContext {
+    /// Returns the current size of the memory in pages of [PAGE_SIZE_BYTES]
     fn size_pages(&self) -> u64;
+    /// Grows the memory by `new_pages` pages, returns the previous size in pages
     fn grow(&mut self, new_pages: u64) -> Result<u64, OutOfMemory>;
+    /// Reads `buf.len()` bytes starting from `offset`, panics if out of bounds
     fn read(&self, offset: u64, buf: &mut [u8]);
+    /// Writes `buf` starting from `offset`, panics if out of bounds
     fn write(&mut self, offset: u64, buf: &[u8]);
+    /// Writes `bufs` one after another starting from `offset`, as a single logical write
+    ///
+    /// By default simply writes each buffer separately. Contexts, for which a call is expensive,
+    /// should override it.
+    fn write_vectored(&mut self, offset: u64, bufs: &[IoSlice]) {
+        let mut offset = offset;
+
+        for buf in bufs {
+            self.write(offset, buf);
+            offset += buf.len() as u64;
+        }
+    }
+    /// Makes sure all previous writes have reached the memory, does nothing by default
+    fn flush(&mut self) {}
 }
 
+/// Canister's stable memory, the default [MemContext] on wasm
 #[derive(Clone)]
-pub(crate) struct StableMemContext;
+pub struct StableMemContext;
 
 #[cfg(target_family = "wasm")]
 use ic_cdk::api::stable::{stable64_grow, stable64_read, stable64_size, stable64_write};
@@ -54,16 +115,71 @@ impl MemContext for StableMemContext {
     fn write(&mut self, offset: u64, buf: &[u8]) {
         stable64_write(offset, buf)
     }
+
+    // a system API call costs much more, than copying a few buffers together
+    fn write_vectored(&mut self, offset: u64, bufs: &[IoSlice]) {
+        if bufs.len() == 1 {
+            stable64_write(offset, &bufs[0]);
+            return;
+        }
+
+        let mut buf = Vec::with_capacity(bufs.iter().map(|it| it.len()).sum());
+        for it in bufs {
+            buf.extend_from_slice(it);
+        }
+
+        stable64_write(offset, &buf)
+    }
 }
 
-#[derive(Clone)]
-pub(crate) struct TestMemContext {
-    pub pages: Vec<[u8; PAGE_SIZE_BYTES as usize]>,
+/// Failures, which [TestMemContext] can be configured to inject, in order to test how the code
+/// handles them
+///
+/// See [stable::inject_faults].
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct FaultInjection {
+    /// `grow` returns [OutOfMemory], if the memory would become bigger than this number of pages
+    pub max_pages: Option<u64>,
+    /// `write` panics, if this number of writes was already performed since the faults were injected
+    ///
+    /// The fault fires only once and is then disarmed, so destructors, which run while the panic
+    /// unwinds, can still write - like a trap, which happens at the moment of the write.
+    pub panic_after_writes: Option<u64>,
+}
+
+// pages are only materialized on the first write, untouched pages read as zeroes - this way tests
+// can address the whole 64-bit stable memory without actually holding gigabytes in heap
+/// Stable memory emulation on heap, the default [MemContext] everywhere, except wasm
+#[derive(Clone, Default)]
+pub struct TestMemContext {
+    pages: Vec<Option<Box<[u8]>>>,
+    faults: FaultInjection,
+    writes_count: u64,
 }
 
 impl TestMemContext {
-    const fn default() -> Self {
-        Self { pages: Vec::new() }
+    pub const fn new() -> Self {
+        Self {
+            pages: Vec::new(),
+            faults: FaultInjection {
+                max_pages: None,
+                panic_after_writes: None,
+            },
+            writes_count: 0,
+        }
+    }
+
+    /// Replaces injected faults and resets the writes counter
+    #[inline]
+    pub fn inject_faults(&mut self, faults: FaultInjection) {
+        self.faults = faults;
+        self.writes_count = 0;
+    }
+
+    /// Returns the number of writes, performed since the faults were injected (or since creation)
+    #[inline]
+    pub fn writes_count(&self) -> u64 {
+        self.writes_count
     }
 }
 
@@ -76,153 +192,309 @@ impl MemContext for TestMemContext {
     fn grow(&mut self, new_pages: u64) -> Result<u64, OutOfMemory> {
         let prev_pages = self.size_pages();
 
-        for _ in 0..new_pages {
-            self.pages.push([0u8; PAGE_SIZE_BYTES as usize]);
+        if let Some(max_pages) = self.faults.max_pages {
+            if prev_pages + new_pages > max_pages {
+                return Err(OutOfMemory);
+            }
         }
 
+        self.pages
+            .resize(self.pages.len() + new_pages as usize, None);
+
         Ok(prev_pages)
     }
 
     fn read(&self, offset: u64, buf: &mut [u8]) {
-        let start_page_idx = (offset / PAGE_SIZE_BYTES) as usize;
-        let start_page_inner_idx = (offset % PAGE_SIZE_BYTES) as usize;
-        let start_page_size = min(PAGE_SIZE_BYTES as usize - start_page_inner_idx, buf.len());
-
-        let (pages_in_between, last_page_size) = if start_page_size == buf.len() {
-            (0usize, 0usize)
-        } else {
-            (
-                (buf.len() - start_page_size) / PAGE_SIZE_BYTES as usize,
-                (buf.len() - start_page_size) % PAGE_SIZE_BYTES as usize,
-            )
-        };
-
-        // read first page
-        buf[0..start_page_size].copy_from_slice(
-            &self.pages[start_page_idx]
-                [start_page_inner_idx..(start_page_inner_idx + start_page_size)],
-        );
-
-        // read pages in-between
-        for i in 0..pages_in_between {
-            buf[(start_page_size + i * PAGE_SIZE_BYTES as usize)
-                ..(start_page_size + (i + 1) * PAGE_SIZE_BYTES as usize)]
-                .copy_from_slice(&self.pages[start_page_idx + i + 1]);
-        }
+        let mut done = 0usize;
+
+        while done < buf.len() {
+            let ptr = offset + done as u64;
+            let page_idx = (ptr / PAGE_SIZE_BYTES) as usize;
+            let page_inner_idx = (ptr % PAGE_SIZE_BYTES) as usize;
+            let size = min(PAGE_SIZE_BYTES as usize - page_inner_idx, buf.len() - done);
+
+            match &self.pages[page_idx] {
+                Some(page) => buf[done..(done + size)]
+                    .copy_from_slice(&page[page_inner_idx..(page_inner_idx + size)]),
+                None => buf[done..(done + size)].fill(0),
+            }
 
-        // read last pages
-        if last_page_size == 0 {
-            return;
+            done += size;
         }
-
-        buf[(start_page_size + pages_in_between * PAGE_SIZE_BYTES as usize)
-            ..(start_page_size + pages_in_between * PAGE_SIZE_BYTES as usize + last_page_size)]
-            .copy_from_slice(&self.pages[start_page_idx + pages_in_between + 1][0..last_page_size]);
     }
 
     fn write(&mut self, offset: u64, buf: &[u8]) {
-        let start_page_idx = (offset / PAGE_SIZE_BYTES) as usize;
-        let start_page_inner_idx = (offset % PAGE_SIZE_BYTES) as usize;
-        let start_page_size = min(PAGE_SIZE_BYTES as usize - start_page_inner_idx, buf.len());
-
-        let (pages_in_between, last_page_size) = if start_page_size == buf.len() {
-            (0usize, 0usize)
-        } else {
-            (
-                (buf.len() - start_page_size) / PAGE_SIZE_BYTES as usize,
-                (buf.len() - start_page_size) % PAGE_SIZE_BYTES as usize,
-            )
-        };
-
-        // write to first page
-        self.pages[start_page_idx][start_page_inner_idx..(start_page_inner_idx + start_page_size)]
-            .copy_from_slice(&buf[0..start_page_size]);
-
-        // write to pages in-between
-        for i in 0..pages_in_between {
-            self.pages[start_page_idx + i + 1].copy_from_slice(
-                &buf[(start_page_size + i * PAGE_SIZE_BYTES as usize)
-                    ..(start_page_size + (i + 1) * PAGE_SIZE_BYTES as usize)],
-            );
+        self.count_write(offset);
+        self.write_pages(offset, buf);
+    }
+
+    // counted as a single write by fault injection
+    fn write_vectored(&mut self, offset: u64, bufs: &[IoSlice]) {
+        self.count_write(offset);
+
+        let mut offset = offset;
+        for buf in bufs {
+            self.write_pages(offset, buf);
+            offset += buf.len() as u64;
         }
+    }
+}
 
-        // write to last page
-        if last_page_size == 0 {
-            return;
+impl TestMemContext {
+    fn count_write(&mut self, offset: u64) {
+        if let Some(writes) = self.faults.panic_after_writes {
+            if self.writes_count >= writes {
+                self.faults.panic_after_writes = None;
+
+                panic!(
+                    "Injected fault: write #{} at {}",
+                    self.writes_count + 1,
+                    offset
+                );
+            }
         }
 
-        self.pages[start_page_idx + pages_in_between + 1][0..last_page_size].copy_from_slice(
-            &buf[(start_page_size + pages_in_between * PAGE_SIZE_BYTES as usize)
-                ..(start_page_size + pages_in_between * PAGE_SIZE_BYTES as usize + last_page_size)],
-        );
+        self.writes_count += 1;
+    }
+
+    fn write_pages(&mut self, offset: u64, buf: &[u8]) {
+        let mut done = 0usize;
+
+        while done < buf.len() {
+            let ptr = offset + done as u64;
+            let page_idx = (ptr / PAGE_SIZE_BYTES) as usize;
+            let page_inner_idx = (ptr % PAGE_SIZE_BYTES) as usize;
+            let size = min(PAGE_SIZE_BYTES as usize - page_inner_idx, buf.len() - done);
+
+            let page = self.pages[page_idx]
+                .get_or_insert_with(|| vec![0u8; PAGE_SIZE_BYTES as usize].into_boxed_slice());
+
+            page[page_inner_idx..(page_inner_idx + size)]
+                .copy_from_slice(&buf[done..(done + size)]);
+
+            done += size;
+        }
     }
 }
 
-#[cfg(target_family = "wasm")]
 pub mod stable {
-    use crate::utils::mem_context::{MemContext, OutOfMemory, StableMemContext};
+    #[cfg(target_family = "wasm")]
+    use crate::utils::mem_context::StableMemContext;
+    #[cfg(not(target_family = "wasm"))]
+    use crate::utils::mem_context::{FaultInjection, TestMemContext};
+    use crate::utils::mem_context::{MemContext, OutOfMemory, PAGE_SIZE_BYTES};
+    use std::cell::{Cell, RefCell};
+    use std::io::IoSlice;
+
+    #[cfg(not(target_family = "wasm"))]
+    thread_local! {
+        static CONTEXT: RefCell<TestMemContext> = RefCell::new(TestMemContext::new());
+    }
 
-    #[inline]
-    pub fn size_pages() -> u64 {
-        MemContext::size_pages(&StableMemContext)
+    thread_local! {
+        static CUSTOM_CONTEXT: RefCell<Option<Box<dyn MemContext>>> = RefCell::new(None);
+        static READ_ONLY: Cell<bool> = Cell::new(false);
     }
 
+    #[cfg(target_family = "wasm")]
     #[inline]
-    pub fn grow(new_pages: u64) -> Result<u64, OutOfMemory> {
-        MemContext::grow(&mut StableMemContext, new_pages)
+    fn with_default_context<R, F: FnOnce(&mut dyn MemContext) -> R>(f: F) -> R {
+        f(&mut StableMemContext)
     }
 
+    #[cfg(not(target_family = "wasm"))]
     #[inline]
-    pub fn read(offset: u64, buf: &mut [u8]) {
-        MemContext::read(&StableMemContext, offset, buf)
+    fn with_default_context<R, F: FnOnce(&mut dyn MemContext) -> R>(f: F) -> R {
+        CONTEXT.with(|it| f(&mut *it.borrow_mut()))
     }
 
     #[inline]
-    pub fn write(offset: u64, buf: &[u8]) {
-        MemContext::write(&mut StableMemContext, offset, buf)
+    fn with_context<R, F: FnOnce(&mut dyn MemContext) -> R>(f: F) -> R {
+        CUSTOM_CONTEXT.with(|it| match &mut *it.borrow_mut() {
+            Some(context) => f(context.as_mut()),
+            None => with_default_context(f),
+        })
     }
-}
 
-#[cfg(not(target_family = "wasm"))]
-pub mod stable {
-    use crate::utils::mem_context::{MemContext, OutOfMemory, TestMemContext};
-    use std::cell::RefCell;
+    /// Makes every function of this module (and, therefore, the whole crate) use the provided
+    /// [MemContext], instead of the default one
+    ///
+    /// Returns the custom context, that was set previously, if any. Custom contexts are stored in a
+    /// `thread_local!` variable, so they should not call functions of this module themselves - wrap
+    /// another context instead.
+    pub fn set_context<C: MemContext + 'static>(context: C) -> Option<Box<dyn MemContext>> {
+        CUSTOM_CONTEXT.with(|it| it.borrow_mut().replace(Box::new(context)))
+    }
 
-    thread_local! {
-        static CONTEXT: RefCell<TestMemContext> = RefCell::new(TestMemContext::default());
+    /// Switches back to the default [MemContext], returns the custom one, if any
+    pub fn reset_context() -> Option<Box<dyn MemContext>> {
+        CUSTOM_CONTEXT.with(|it| it.borrow_mut().take())
+    }
+
+    /// Returns [true], if a custom [MemContext] is set
+    #[inline]
+    pub fn has_custom_context() -> bool {
+        CUSTOM_CONTEXT.with(|it| it.borrow().is_some())
     }
 
+    /// Clears the stable memory emulation and switches back to it, if a custom context was set
+    #[cfg(not(target_family = "wasm"))]
     #[inline]
     pub fn clear() {
-        CONTEXT.with(|it| it.borrow_mut().pages.clear())
+        reset_context();
+        CONTEXT.with(|it| *it.borrow_mut() = TestMemContext::new())
+    }
+
+    /// Makes the stable memory emulation inject faults, resets its writes counter
+    ///
+    /// Affects only the default context (see [TestMemContext::inject_faults]). Passing
+    /// [FaultInjection::default()] disables all faults.
+    #[cfg(not(target_family = "wasm"))]
+    #[inline]
+    pub fn inject_faults(faults: FaultInjection) {
+        CONTEXT.with(|it| it.borrow_mut().inject_faults(faults))
+    }
+
+    /// Returns the number of writes to the stable memory emulation, since faults were injected
+    /// (or since it was cleared)
+    #[cfg(not(target_family = "wasm"))]
+    #[inline]
+    pub fn writes_count() -> u64 {
+        CONTEXT.with(|it| it.borrow().writes_count())
+    }
+
+    /// Returns a copy of the stable memory emulation, which can be restored with [rollback]
+    ///
+    /// Only the default context is copied.
+    #[cfg(not(target_family = "wasm"))]
+    #[inline]
+    pub fn checkpoint() -> TestMemContext {
+        CONTEXT.with(|it| it.borrow().clone())
+    }
+
+    /// Replaces the stable memory emulation with a copy, made by [checkpoint]
+    #[cfg(not(target_family = "wasm"))]
+    #[inline]
+    pub fn rollback(checkpoint: TestMemContext) {
+        CONTEXT.with(|it| *it.borrow_mut() = checkpoint)
+    }
+
+    /// Executes `f` in read-only mode, where any attempt to write or grow stable memory panics
+    ///
+    /// Meant to wrap query methods (for example, certified query handlers), so an accidental
+    /// mutation of a stable collection traps with a clear message, instead of being silently
+    /// discarded at the end of the query. Can be nested, the previous mode is restored afterwards,
+    /// even if `f` panics.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use ic_stable_memory::collections::SVec;
+    /// # use ic_stable_memory::{stable, stable_memory_init};
+    /// # stable::clear();
+    /// # stable_memory_init();
+    /// let mut vec = SVec::<u64>::new();
+    /// vec.push(10).unwrap();
+    ///
+    /// let first = stable::read_only(|| *vec.get(0).unwrap());
+    /// assert_eq!(first, 10);
+    /// ```
+    pub fn read_only<R, F: FnOnce() -> R>(f: F) -> R {
+        struct RestoreMode(bool);
+
+        impl Drop for RestoreMode {
+            fn drop(&mut self) {
+                READ_ONLY.with(|it| it.set(self.0));
+            }
+        }
+
+        let _restore = RestoreMode(READ_ONLY.with(|it| it.replace(true)));
+
+        f()
+    }
+
+    /// Returns `true`, if called inside [read_only]
+    #[inline]
+    pub fn is_read_only() -> bool {
+        READ_ONLY.with(|it| it.get())
+    }
+
+    #[inline]
+    fn check_writable(action: &str, offset: u64, len: u64) {
+        if is_read_only() {
+            panic!(
+                "Stable memory is read-only: attempted to {} {} bytes at {}",
+                action, len, offset
+            );
+        }
     }
 
     #[inline]
     pub fn size_pages() -> u64 {
-        CONTEXT.with(|it| it.borrow().size_pages())
+        with_context(|it| it.size_pages())
     }
 
     #[inline]
     pub fn grow(new_pages: u64) -> Result<u64, OutOfMemory> {
-        CONTEXT.with(|it| it.borrow_mut().grow(new_pages))
+        check_writable(
+            "grow by",
+            size_pages() * PAGE_SIZE_BYTES,
+            new_pages * PAGE_SIZE_BYTES,
+        );
+
+        with_context(|it| it.grow(new_pages))
     }
 
     #[inline]
     pub fn read(offset: u64, buf: &mut [u8]) {
-        CONTEXT.with(|it| it.borrow().read(offset, buf))
+        #[cfg(feature = "snapshots")]
+        if crate::mem::snapshot::read_frozen(offset, buf) {
+            return;
+        }
+
+        with_context(|it| it.read(offset, buf))
     }
 
     #[inline]
     pub fn write(offset: u64, buf: &[u8]) {
-        CONTEXT.with(|it| it.borrow_mut().write(offset, buf))
+        check_writable("write", offset, buf.len() as u64);
+        #[cfg(feature = "wal")]
+        crate::mem::wal::before_write(offset, buf.len() as u64);
+        #[cfg(feature = "snapshots")]
+        crate::mem::snapshot::before_write(offset, buf.len() as u64);
+
+        with_context(|it| it.write(offset, buf))
+    }
+
+    /// Writes `bufs` one after another starting from `offset`, see [MemContext::write_vectored]
+    #[inline]
+    pub fn write_vectored(offset: u64, bufs: &[IoSlice]) {
+        let len = bufs.iter().map(|it| it.len() as u64).sum();
+        check_writable("write", offset, len);
+        #[cfg(feature = "wal")]
+        crate::mem::wal::before_write(offset, len);
+        #[cfg(feature = "snapshots")]
+        crate::mem::snapshot::before_write(offset, len);
+
+        with_context(|it| it.write_vectored(offset, bufs))
+    }
+
+    /// Flushes writes, buffered by the current [MemContext] (see [CachingMemContext](crate::utils::mem_context::CachingMemContext))
+    ///
+    /// Does nothing for the default contexts.
+    #[inline]
+    pub fn flush() {
+        with_context(|it| it.flush())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{stable, PAGE_SIZE_BYTES};
+    use crate::collections::{SBTreeMap, SGraph, SPriorityQueue, SVec};
+    use crate::utils::mem_context::FaultInjection;
+    use crate::{stable, stable_memory_init, PAGE_SIZE_BYTES};
     use rand::seq::SliceRandom;
     use rand::{thread_rng, Rng};
+    use std::io::IoSlice;
 
     #[test]
     fn random_works_fine() {
@@ -273,4 +545,173 @@ mod tests {
 
         assert_eq!(buf[25..PAGE_SIZE_BYTES as usize * 10 - 25], buf1);
     }
+
+    #[test]
+    fn above_4gb_works_fine() {
+        stable::clear();
+
+        let pages_4gb = (u32::MAX as u64 + 1) / PAGE_SIZE_BYTES;
+        stable::grow(pages_4gb + 2).unwrap();
+        assert_eq!(stable::size_pages(), pages_4gb + 2);
+
+        // crosses the 4GB boundary
+        let ptr = u32::MAX as u64 - 10;
+        let buf = [7u8; 100];
+        stable::write(ptr, &buf);
+
+        let mut buf1 = [0u8; 120];
+        stable::read(ptr - 10, &mut buf1);
+
+        assert_eq!(buf1[0..10], [0u8; 10]);
+        assert_eq!(buf1[10..110], buf);
+        assert_eq!(buf1[110..120], [0u8; 10]);
+
+        let mut buf2 = [1u8; 8];
+        stable::read(ptr + PAGE_SIZE_BYTES, &mut buf2);
+        assert_eq!(buf2, [0u8; 8]);
+    }
+
+    #[test]
+    fn fault_injection_works_fine() {
+        stable::clear();
+        stable::grow(1).unwrap();
+
+        stable::inject_faults(FaultInjection {
+            max_pages: Some(2),
+            panic_after_writes: None,
+        });
+
+        assert!(stable::grow(2).is_err());
+        assert_eq!(stable::grow(1).unwrap(), 1);
+        assert!(stable::grow(1).is_err());
+
+        stable::write(0, &[1, 2, 3]);
+        stable::write(10, &[1, 2, 3]);
+        assert_eq!(stable::writes_count(), 2);
+
+        stable::inject_faults(FaultInjection {
+            max_pages: None,
+            panic_after_writes: Some(1),
+        });
+        assert_eq!(stable::writes_count(), 0);
+
+        stable::write(0, &[1, 2, 3]);
+        let res = std::panic::catch_unwind(|| stable::write(0, &[1, 2, 3]));
+        assert!(res.is_err());
+        assert_eq!(stable::writes_count(), 1);
+
+        stable::inject_faults(FaultInjection::default());
+        stable::write(0, &[1, 2, 3]);
+    }
+
+    #[test]
+    fn out_of_memory_is_handled_by_collections() {
+        stable::clear();
+        stable_memory_init();
+
+        stable::inject_faults(FaultInjection {
+            max_pages: Some(stable::size_pages()),
+            panic_after_writes: None,
+        });
+
+        let mut vec = SVec::<u64>::new();
+        let mut pushed = 0;
+        while vec.push(pushed).is_ok() {
+            pushed += 1;
+        }
+
+        assert!(pushed > 0);
+        assert_eq!(vec.len() as u64, pushed);
+        assert!(vec.push(pushed).is_err());
+        assert_eq!(vec.len() as u64, pushed);
+        for i in 0..pushed {
+            assert_eq!(*vec.get(i as usize).unwrap(), i);
+        }
+
+        assert!(vec.try_push(pushed).is_err());
+        assert!(vec.try_insert(0, pushed).is_err());
+        assert_eq!(vec.len() as u64, pushed);
+
+        let mut map = SBTreeMap::<u64, u64>::new();
+        let mut inserted = 0;
+        while map.try_insert(inserted, inserted).is_ok() {
+            inserted += 1;
+        }
+
+        assert_eq!(map.len(), inserted);
+        assert!(map.try_insert(inserted, inserted).is_err());
+        assert!(map.get(&inserted).is_none());
+
+        let mut queue = SPriorityQueue::<u64, u64>::new();
+        let mut pushed = 0;
+        while queue.try_push(pushed, pushed).is_ok() {
+            pushed += 1;
+        }
+
+        assert_eq!(queue.len() as u64, pushed);
+        assert!(queue.try_push(pushed, pushed).is_err());
+        assert_eq!(queue.len() as u64, pushed);
+
+        let mut graph = SGraph::<u64, u64>::new();
+        while graph.try_add_node(0).is_ok() {}
+
+        let nodes = graph.node_count();
+        assert!(graph.try_add_node(0).is_err());
+        assert_eq!(graph.node_count(), nodes);
+    }
+
+    #[test]
+    fn read_only_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        let mut vec = SVec::<u64>::new();
+        vec.push(10).unwrap();
+
+        assert!(!stable::is_read_only());
+
+        let it = stable::read_only(|| {
+            assert!(stable::is_read_only());
+            stable::read_only(|| assert!(stable::is_read_only()));
+            assert!(stable::is_read_only());
+
+            *vec.get(0).unwrap()
+        });
+        assert_eq!(it, 10);
+        assert!(!stable::is_read_only());
+
+        let res = std::panic::catch_unwind(|| stable::read_only(|| stable::write(0, &[1])));
+        assert!(res.is_err());
+        assert!(!stable::is_read_only());
+
+        let res = std::panic::catch_unwind(|| stable::read_only(|| stable::grow(1)));
+        assert!(res.is_err());
+
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            stable::read_only(|| vec.push(20))
+        }));
+        assert!(res.is_err());
+
+        vec.push(20).unwrap();
+        assert_eq!(*vec.get(1).unwrap(), 20);
+    }
+
+    #[test]
+    fn write_vectored_works_fine() {
+        stable::clear();
+        stable::grow(2).unwrap();
+
+        let a = [1u8, 2, 3];
+        let b = vec![4u8; PAGE_SIZE_BYTES as usize];
+        stable::write_vectored(10, &[IoSlice::new(&a), IoSlice::new(&[]), IoSlice::new(&b)]);
+
+        // a single logical write
+        assert_eq!(stable::writes_count(), 1);
+
+        let mut buf = vec![0u8; 3 + PAGE_SIZE_BYTES as usize + 1];
+        stable::read(10, &mut buf);
+        assert_eq!(&buf[..3], &a);
+        assert_eq!(&buf[3..(buf.len() - 1)], &b[..]);
+        assert_eq!(buf[buf.len() - 1], 0);
+    }
 }
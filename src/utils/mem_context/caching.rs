@@ -0,0 +1,227 @@
+use crate::utils::mem_context::{MemContext, OutOfMemory, PAGE_SIZE_BYTES};
+use std::cmp::min;
+use std::collections::BTreeMap;
+
+/// [MemContext], which buffers writes to another context in heap and writes them back on
+/// [flush](MemContext::flush)
+///
+/// Each page written to is copied to heap once and then modified there, so the many small writes,
+/// that a single operation (like B-tree rebalancing) produces, collapse into a few big writes of
+/// contiguous runs of pages. Reads see the buffered data.
+///
+/// Buffered writes only reach the underlying memory on flush, so [stable::flush](crate::stable::flush)
+/// should be called at the end of every update call. [stable_memory_pre_upgrade](crate::stable_memory_pre_upgrade)
+/// flushes automatically.
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::utils::mem_context::{CachingMemContext, TestMemContext};
+/// # use ic_stable_memory::stable;
+/// stable::set_context(CachingMemContext::new(TestMemContext::new()));
+///
+/// stable::grow(1).unwrap();
+/// stable::write(0, &[1, 2, 3]);
+/// stable::write(3, &[4, 5, 6]);
+///
+/// // a single write of the whole page
+/// stable::flush();
+/// # stable::reset_context();
+/// ```
+pub struct CachingMemContext<C> {
+    inner: C,
+    dirty_pages: BTreeMap<u64, Box<[u8]>>,
+}
+
+impl<C: MemContext> CachingMemContext<C> {
+    pub fn new(inner: C) -> Self {
+        Self {
+            inner,
+            dirty_pages: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the number of pages, that are buffered, but not yet flushed
+    #[inline]
+    pub fn dirty_pages(&self) -> usize {
+        self.dirty_pages.len()
+    }
+
+    /// Flushes buffered writes and returns the underlying context
+    #[inline]
+    pub fn into_inner(mut self) -> C {
+        self.flush();
+
+        self.inner
+    }
+}
+
+impl<C: MemContext> MemContext for CachingMemContext<C> {
+    #[inline]
+    fn size_pages(&self) -> u64 {
+        self.inner.size_pages()
+    }
+
+    #[inline]
+    fn grow(&mut self, new_pages: u64) -> Result<u64, OutOfMemory> {
+        self.inner.grow(new_pages)
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) {
+        if self.dirty_pages.is_empty() {
+            self.inner.read(offset, buf);
+            return;
+        }
+
+        let mut done = 0usize;
+
+        while done < buf.len() {
+            let ptr = offset + done as u64;
+            let page_idx = ptr / PAGE_SIZE_BYTES;
+            let page_inner_idx = (ptr % PAGE_SIZE_BYTES) as usize;
+            let size = min(PAGE_SIZE_BYTES as usize - page_inner_idx, buf.len() - done);
+
+            match self.dirty_pages.get(&page_idx) {
+                Some(page) => buf[done..(done + size)]
+                    .copy_from_slice(&page[page_inner_idx..(page_inner_idx + size)]),
+                None => self.inner.read(ptr, &mut buf[done..(done + size)]),
+            }
+
+            done += size;
+        }
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) {
+        assert!(
+            offset + buf.len() as u64 <= self.size_pages() * PAGE_SIZE_BYTES,
+            "Out of bounds write"
+        );
+
+        let mut done = 0usize;
+
+        while done < buf.len() {
+            let ptr = offset + done as u64;
+            let page_idx = ptr / PAGE_SIZE_BYTES;
+            let page_inner_idx = (ptr % PAGE_SIZE_BYTES) as usize;
+            let size = min(PAGE_SIZE_BYTES as usize - page_inner_idx, buf.len() - done);
+
+            let inner = &self.inner;
+            let page = self.dirty_pages.entry(page_idx).or_insert_with(|| {
+                let mut page = vec![0u8; PAGE_SIZE_BYTES as usize].into_boxed_slice();
+                inner.read(page_idx * PAGE_SIZE_BYTES, &mut page);
+
+                page
+            });
+
+            page[page_inner_idx..(page_inner_idx + size)]
+                .copy_from_slice(&buf[done..(done + size)]);
+
+            done += size;
+        }
+    }
+
+    // contiguous runs of dirty pages are written with a single write each
+    fn flush(&mut self) {
+        let dirty_pages = std::mem::take(&mut self.dirty_pages);
+
+        let mut run_start = 0u64;
+        let mut run = Vec::new();
+
+        for (page_idx, page) in dirty_pages {
+            if !run.is_empty() && run_start + (run.len() as u64 / PAGE_SIZE_BYTES) != page_idx {
+                self.inner.write(run_start * PAGE_SIZE_BYTES, &run);
+                run.clear();
+            }
+
+            if run.is_empty() {
+                run_start = page_idx;
+            }
+
+            run.extend_from_slice(&page);
+        }
+
+        if !run.is_empty() {
+            self.inner.write(run_start * PAGE_SIZE_BYTES, &run);
+        }
+
+        self.inner.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::SBTreeMap;
+    use crate::utils::mem_context::{
+        CachingMemContext, CountingMemContext, MemContext, TestMemContext,
+    };
+    use crate::{stable, stable_memory_init, stable_memory_pre_upgrade, PAGE_SIZE_BYTES};
+
+    #[test]
+    fn works_fine() {
+        let counting = CountingMemContext::new(TestMemContext::new());
+        let stats = counting.stats();
+        let mut context = CachingMemContext::new(counting);
+
+        context.grow(4).unwrap();
+        context.write(PAGE_SIZE_BYTES - 2, &[1, 2, 3, 4]);
+        context.write(10, &[5, 6]);
+        context.write(3 * PAGE_SIZE_BYTES, &[7]);
+
+        assert_eq!(context.dirty_pages(), 3);
+        assert_eq!(stats.borrow().writes, 0);
+
+        let mut buf = [0u8; 4];
+        context.read(PAGE_SIZE_BYTES - 2, &mut buf);
+        assert_eq!(buf, [1, 2, 3, 4]);
+        context.read(2 * PAGE_SIZE_BYTES - 2, &mut buf);
+        assert_eq!(buf, [0, 0, 0, 0]);
+
+        context.flush();
+        assert_eq!(context.dirty_pages(), 0);
+
+        // pages 0 and 1 form a single run
+        assert_eq!(stats.borrow().writes, 2);
+        assert_eq!(stats.borrow().bytes_written, 3 * PAGE_SIZE_BYTES);
+
+        let inner = context.into_inner().into_inner();
+
+        inner.read(PAGE_SIZE_BYTES - 2, &mut buf);
+        assert_eq!(buf, [1, 2, 3, 4]);
+        inner.read(9, &mut buf);
+        assert_eq!(buf, [0, 5, 6, 0]);
+        inner.read(3 * PAGE_SIZE_BYTES, &mut buf);
+        assert_eq!(buf, [7, 0, 0, 0]);
+    }
+
+    #[test]
+    fn collapses_writes_of_collections() {
+        stable::clear();
+
+        let counting = CountingMemContext::new(TestMemContext::new());
+        let stats = counting.stats();
+        stable::set_context(CachingMemContext::new(counting));
+
+        stable_memory_init();
+
+        let mut map = SBTreeMap::<u64, u64>::new();
+        for i in 0..1000 {
+            map.insert(i, i).unwrap();
+        }
+
+        for i in 0..1000 {
+            assert_eq!(*map.get(&i).unwrap(), i);
+        }
+
+        stable::flush();
+        let writes = stats.borrow().writes;
+        assert!(writes > 0 && writes < 1000);
+
+        for i in 0..1000 {
+            assert_eq!(map.remove(&i).unwrap(), i);
+        }
+        drop(map);
+
+        // the allocator's metadata is flushed by the pre-upgrade hook
+        stable_memory_pre_upgrade().unwrap();
+        assert!(stats.borrow().writes > writes);
+    }
+}
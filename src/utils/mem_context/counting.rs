@@ -0,0 +1,202 @@
+use crate::utils::mem_context::{MemContext, OutOfMemory, PAGE_SIZE_BYTES};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io::IoSlice;
+use std::rc::Rc;
+
+/// Stable memory traffic, collected by [CountingMemContext]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MemAccessStats {
+    /// Number of `read` calls
+    pub reads: u64,
+    /// Number of `write` calls
+    pub writes: u64,
+    /// Number of `grow` calls
+    pub grows: u64,
+    /// Total number of bytes read
+    pub bytes_read: u64,
+    /// Total number of bytes written
+    pub bytes_written: u64,
+    /// For each page index - the number of `read` calls, which touched that page
+    pub page_reads: BTreeMap<u64, u64>,
+    /// For each page index - the number of `write` calls, which touched that page
+    pub page_writes: BTreeMap<u64, u64>,
+}
+
+impl MemAccessStats {
+    /// Returns the number of distinct pages, which were either read or written
+    pub fn pages_touched(&self) -> usize {
+        self.page_reads.len()
+            + self
+                .page_writes
+                .keys()
+                .filter(|it| !self.page_reads.contains_key(it))
+                .count()
+    }
+
+    fn count_pages(pages: &mut BTreeMap<u64, u64>, offset: u64, len: usize) {
+        if len == 0 {
+            return;
+        }
+
+        let first_page = offset / PAGE_SIZE_BYTES;
+        let last_page = (offset + len as u64 - 1) / PAGE_SIZE_BYTES;
+
+        for page in first_page..=last_page {
+            *pages.entry(page).or_default() += 1;
+        }
+    }
+}
+
+/// [MemContext], which wraps another one and counts every access to it
+///
+/// Useful to evaluate data layouts by the amount of stable memory traffic they produce, rather
+/// than by wall time. The counters are shared with the handle returned by [CountingMemContext::stats],
+/// so they remain accessible after the context is passed to [stable::set_context](crate::stable::set_context).
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::utils::mem_context::{CountingMemContext, TestMemContext};
+/// # use ic_stable_memory::stable;
+/// let context = CountingMemContext::new(TestMemContext::new());
+/// let stats = context.stats();
+/// stable::set_context(context);
+///
+/// stable::grow(1).unwrap();
+/// stable::write(0, &[1, 2, 3]);
+///
+/// assert_eq!(stats.borrow().bytes_written, 3);
+/// # stable::reset_context();
+/// ```
+pub struct CountingMemContext<C> {
+    inner: C,
+    stats: Rc<RefCell<MemAccessStats>>,
+}
+
+impl<C: MemContext> CountingMemContext<C> {
+    pub fn new(inner: C) -> Self {
+        Self {
+            inner,
+            stats: Rc::new(RefCell::new(MemAccessStats::default())),
+        }
+    }
+
+    /// Returns a handle to the counters of this context
+    ///
+    /// To measure a particular operation, `std::mem::take` the stats before it.
+    #[inline]
+    pub fn stats(&self) -> Rc<RefCell<MemAccessStats>> {
+        self.stats.clone()
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+impl<C: MemContext> MemContext for CountingMemContext<C> {
+    #[inline]
+    fn size_pages(&self) -> u64 {
+        self.inner.size_pages()
+    }
+
+    fn grow(&mut self, new_pages: u64) -> Result<u64, OutOfMemory> {
+        self.stats.borrow_mut().grows += 1;
+
+        self.inner.grow(new_pages)
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) {
+        {
+            let mut stats = self.stats.borrow_mut();
+
+            stats.reads += 1;
+            stats.bytes_read += buf.len() as u64;
+            MemAccessStats::count_pages(&mut stats.page_reads, offset, buf.len());
+        }
+
+        self.inner.read(offset, buf)
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) {
+        {
+            let mut stats = self.stats.borrow_mut();
+
+            stats.writes += 1;
+            stats.bytes_written += buf.len() as u64;
+            MemAccessStats::count_pages(&mut stats.page_writes, offset, buf.len());
+        }
+
+        self.inner.write(offset, buf)
+    }
+
+    fn write_vectored(&mut self, offset: u64, bufs: &[IoSlice]) {
+        {
+            let mut stats = self.stats.borrow_mut();
+            let len = bufs.iter().map(|it| it.len()).sum();
+
+            stats.writes += 1;
+            stats.bytes_written += len as u64;
+            MemAccessStats::count_pages(&mut stats.page_writes, offset, len);
+        }
+
+        self.inner.write_vectored(offset, bufs)
+    }
+
+    #[inline]
+    fn flush(&mut self) {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::SVec;
+    use crate::utils::mem_context::{CountingMemContext, TestMemContext};
+    use crate::{stable, stable_memory_init, PAGE_SIZE_BYTES};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn works_fine() {
+        stable::clear();
+
+        let context = CountingMemContext::new(TestMemContext::new());
+        let stats = context.stats();
+        stable::set_context(context);
+
+        stable::grow(3).unwrap();
+        stable::write(PAGE_SIZE_BYTES - 2, &[1, 2, 3, 4]);
+        stable::write(10, &[]);
+
+        let mut buf = [0u8; 4];
+        stable::read(PAGE_SIZE_BYTES - 2, &mut buf);
+        assert_eq!(buf, [1, 2, 3, 4]);
+
+        stable::read(PAGE_SIZE_BYTES * 2, &mut buf);
+
+        {
+            let stats = stats.borrow();
+
+            assert_eq!(stats.grows, 1);
+            assert_eq!(stats.writes, 2);
+            assert_eq!(stats.reads, 2);
+            assert_eq!(stats.bytes_written, 4);
+            assert_eq!(stats.bytes_read, 8);
+            assert_eq!(stats.page_writes, BTreeMap::from([(0, 1), (1, 1)]));
+            assert_eq!(stats.page_reads, BTreeMap::from([(0, 1), (1, 1), (2, 1)]));
+            assert_eq!(stats.pages_touched(), 3);
+        }
+
+        std::mem::take(&mut *stats.borrow_mut());
+
+        stable_memory_init();
+        let mut vec = SVec::<u64>::new();
+        for i in 0..100 {
+            vec.push(i).unwrap();
+        }
+
+        assert!(stats.borrow().writes > 0);
+        assert!(stats.borrow().bytes_written >= 100 * 8);
+    }
+}
@@ -0,0 +1,153 @@
+use crate::utils::math::ceil_div;
+use crate::utils::mem_context::{MemContext, OutOfMemory, PAGE_SIZE_BYTES};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// [MemContext] backed by a file, where the file's contents are the contents of stable memory
+///
+/// Allows off-chain tools and tests to open a downloaded stable memory dump and inspect (or repair)
+/// it with the same code, that runs inside the canister. Growing the memory extends the file with
+/// zeroes. Not available on wasm.
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::utils::mem_context::FileMemContext;
+/// # use ic_stable_memory::stable;
+/// # let path = std::env::temp_dir().join("file_mem_context_doc_example");
+/// let context = FileMemContext::open(&path).expect("Unable to open the dump");
+/// stable::set_context(context);
+///
+/// // from now on the whole crate reads and writes the file
+/// # stable::reset_context();
+/// # std::fs::remove_file(&path).unwrap();
+/// ```
+pub struct FileMemContext {
+    file: File,
+}
+
+impl FileMemContext {
+    /// Opens the file for both reading and writing, creates it, if it doesn't exist
+    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)?;
+
+        Ok(Self { file })
+    }
+
+    /// Flushes all written data to the disk
+    pub fn sync(&self) -> std::io::Result<()> {
+        self.file.sync_all()
+    }
+
+    fn len(&self) -> u64 {
+        self.file
+            .metadata()
+            .expect("Unable to read file metadata")
+            .len()
+    }
+}
+
+impl MemContext for FileMemContext {
+    // a dump, which size is not a multiple of the page size, is treated as if its last page was full
+    fn size_pages(&self) -> u64 {
+        ceil_div(self.len(), PAGE_SIZE_BYTES)
+    }
+
+    fn grow(&mut self, new_pages: u64) -> Result<u64, OutOfMemory> {
+        let prev_pages = self.size_pages();
+
+        self.file
+            .set_len((prev_pages + new_pages) * PAGE_SIZE_BYTES)
+            .map_err(|_| OutOfMemory)?;
+
+        Ok(prev_pages)
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) {
+        assert!(
+            offset + buf.len() as u64 <= self.size_pages() * PAGE_SIZE_BYTES,
+            "Out of bounds read"
+        );
+
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(offset))
+            .expect("Unable to seek the file");
+
+        // the tail of a partial last page is read as zeroes
+        let available = self.len().saturating_sub(offset).min(buf.len() as u64) as usize;
+        file.read_exact(&mut buf[..available])
+            .expect("Unable to read the file");
+        buf[available..].fill(0);
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) {
+        assert!(
+            offset + buf.len() as u64 <= self.size_pages() * PAGE_SIZE_BYTES,
+            "Out of bounds write"
+        );
+
+        self.file
+            .seek(SeekFrom::Start(offset))
+            .expect("Unable to seek the file");
+        self.file.write_all(buf).expect("Unable to write the file");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::SVec;
+    use crate::utils::mem_context::FileMemContext;
+    use crate::utils::DebuglessUnwrap;
+    use crate::{retrieve_custom_data, store_custom_data, SBox, PAGE_SIZE_BYTES};
+    use crate::{
+        stable, stable_memory_init, stable_memory_post_upgrade, stable_memory_pre_upgrade,
+    };
+
+    #[test]
+    fn works_fine() {
+        stable::clear();
+
+        let path = std::env::temp_dir().join(format!(
+            "ic_stable_memory_file_mem_context_{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        stable::set_context(FileMemContext::open(&path).unwrap());
+        assert_eq!(stable::size_pages(), 0);
+
+        stable_memory_init();
+
+        let mut vec = SVec::<u64>::new();
+        for i in 0..1000 {
+            vec.push(i).unwrap();
+        }
+        store_custom_data(1, SBox::new(vec).debugless_unwrap());
+
+        stable_memory_pre_upgrade().unwrap();
+        let pages = stable::size_pages();
+
+        // the same dump, opened by another tool
+        stable::set_context(FileMemContext::open(&path).unwrap());
+        assert_eq!(stable::size_pages(), pages);
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            pages * PAGE_SIZE_BYTES
+        );
+
+        stable_memory_post_upgrade();
+
+        let vec = retrieve_custom_data::<SVec<u64>>(1).unwrap().into_inner();
+        for i in 0..1000 {
+            assert_eq!(*vec.get(i as usize).unwrap(), i);
+        }
+
+        drop(vec);
+        stable::reset_context();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,194 @@
+use crate::utils::mem_context::{MemContext, OutOfMemory, PAGE_SIZE_BYTES};
+use std::io::IoSlice;
+
+/// [MemContext], which exposes only the `[base, base + len)` window of another context as
+/// `[0, len)`
+///
+/// Allows confining this crate to a region of stable memory, while other subsystems of the canister
+/// own the rest of it. Both `base` and `len` are in bytes and should be multiples of
+/// [PAGE_SIZE_BYTES]. Pages of the window, which already exist in the underlying memory, are
+/// reported as the window's size; growing the window grows the underlying memory only if it is
+/// not big enough yet, and fails, when the window is full.
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::utils::mem_context::{RestrictedMemContext, TestMemContext};
+/// # use ic_stable_memory::{stable, stable_memory_init, PAGE_SIZE_BYTES};
+/// // the first 10 pages are owned by something else
+/// let context = RestrictedMemContext::new(
+///     TestMemContext::new(),
+///     10 * PAGE_SIZE_BYTES,
+///     100 * PAGE_SIZE_BYTES,
+/// );
+/// stable::set_context(context);
+///
+/// // this crate now only uses pages [10, 110)
+/// stable_memory_init();
+/// # stable::reset_context();
+/// ```
+pub struct RestrictedMemContext<C> {
+    inner: C,
+    base_pages: u64,
+    len_pages: u64,
+}
+
+impl<C: MemContext> RestrictedMemContext<C> {
+    /// # Panics
+    /// Panics if `base` or `len` are not multiples of [PAGE_SIZE_BYTES].
+    pub fn new(inner: C, base: u64, len: u64) -> Self {
+        assert_eq!(
+            base % PAGE_SIZE_BYTES,
+            0,
+            "The window base should be a multiple of the page size"
+        );
+        assert_eq!(
+            len % PAGE_SIZE_BYTES,
+            0,
+            "The window length should be a multiple of the page size"
+        );
+
+        Self {
+            inner,
+            base_pages: base / PAGE_SIZE_BYTES,
+            len_pages: len / PAGE_SIZE_BYTES,
+        }
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+
+    #[inline]
+    fn check_bounds(&self, offset: u64, len: usize) -> bool {
+        offset
+            .checked_add(len as u64)
+            .map(|end| end <= self.size_pages() * PAGE_SIZE_BYTES)
+            .unwrap_or_default()
+    }
+}
+
+impl<C: MemContext> MemContext for RestrictedMemContext<C> {
+    #[inline]
+    fn size_pages(&self) -> u64 {
+        self.inner
+            .size_pages()
+            .saturating_sub(self.base_pages)
+            .min(self.len_pages)
+    }
+
+    fn grow(&mut self, new_pages: u64) -> Result<u64, OutOfMemory> {
+        let prev_pages = self.size_pages();
+
+        if prev_pages + new_pages > self.len_pages {
+            return Err(OutOfMemory);
+        }
+
+        let required_pages = self.base_pages + prev_pages + new_pages;
+        let inner_pages = self.inner.size_pages();
+
+        if required_pages > inner_pages {
+            self.inner.grow(required_pages - inner_pages)?;
+        }
+
+        Ok(prev_pages)
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) {
+        assert!(self.check_bounds(offset, buf.len()), "Out of bounds read");
+
+        self.inner
+            .read(self.base_pages * PAGE_SIZE_BYTES + offset, buf)
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) {
+        assert!(self.check_bounds(offset, buf.len()), "Out of bounds write");
+
+        self.inner
+            .write(self.base_pages * PAGE_SIZE_BYTES + offset, buf)
+    }
+
+    fn write_vectored(&mut self, offset: u64, bufs: &[IoSlice]) {
+        let len = bufs.iter().map(|it| it.len()).sum();
+        assert!(self.check_bounds(offset, len), "Out of bounds write");
+
+        self.inner
+            .write_vectored(self.base_pages * PAGE_SIZE_BYTES + offset, bufs)
+    }
+
+    #[inline]
+    fn flush(&mut self) {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::SVec;
+    use crate::utils::mem_context::{
+        CountingMemContext, MemContext, RestrictedMemContext, TestMemContext,
+    };
+    use crate::{stable, stable_memory_init, PAGE_SIZE_BYTES};
+
+    #[test]
+    fn works_fine() {
+        let mut inner = TestMemContext::new();
+        inner.grow(3).unwrap();
+
+        let mut context =
+            RestrictedMemContext::new(inner, 2 * PAGE_SIZE_BYTES, 4 * PAGE_SIZE_BYTES);
+        assert_eq!(context.size_pages(), 1);
+
+        context.write(10, &[1, 2, 3]);
+        assert_eq!(context.grow(2).unwrap(), 1);
+        assert_eq!(context.size_pages(), 3);
+        assert!(context.grow(2).is_err());
+        assert_eq!(context.grow(1).unwrap(), 3);
+
+        let res = std::panic::catch_unwind(move || {
+            context.write(4 * PAGE_SIZE_BYTES - 1, &[1, 2]);
+        });
+        assert!(res.is_err());
+
+        let mut context =
+            RestrictedMemContext::new(TestMemContext::new(), PAGE_SIZE_BYTES, PAGE_SIZE_BYTES);
+        context.grow(1).unwrap();
+        context.write(10, &[1, 2, 3]);
+
+        let inner = context.into_inner();
+        assert_eq!(inner.size_pages(), 2);
+
+        let mut buf = [0u8; 3];
+        inner.read(PAGE_SIZE_BYTES + 10, &mut buf);
+        assert_eq!(buf, [1, 2, 3]);
+        inner.read(10, &mut buf);
+        assert_eq!(buf, [0, 0, 0]);
+    }
+
+    #[test]
+    fn allocator_is_confined() {
+        stable::clear();
+
+        let counting = CountingMemContext::new(TestMemContext::new());
+        let stats = counting.stats();
+        stable::set_context(RestrictedMemContext::new(
+            counting,
+            10 * PAGE_SIZE_BYTES,
+            20 * PAGE_SIZE_BYTES,
+        ));
+
+        stable_memory_init();
+
+        let mut vec = SVec::<u64>::new();
+        while vec.push(vec.len() as u64).is_ok() {}
+
+        assert!(vec.len() > 0);
+        for i in 0..vec.len() {
+            assert_eq!(*vec.get(i).unwrap(), i as u64);
+        }
+
+        let stats = stats.borrow();
+        assert!(stats.page_writes.keys().all(|it| (10..30).contains(it)));
+        assert!(stats.page_reads.keys().all(|it| (10..30).contains(it)));
+    }
+}
@@ -0,0 +1,258 @@
+use crate::mem::s_slice::SSlice;
+use crate::utils::mem_context::{MemContext, OutOfMemory, PAGE_SIZE_BYTES};
+use crate::{allocate, deallocate, declare_root, get_root, reallocate, remove_root};
+use ic_stable_structures::Memory;
+
+/// [MemContext] on top of any [ic_stable_structures::Memory]
+///
+/// Makes it possible for this crate to share a canister with `ic-stable-structures` - for example,
+/// by giving it one of the virtual memories of `ic_stable_structures::memory_manager::MemoryManager`.
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::utils::mem_context::StableStructuresMemContext;
+/// # use ic_stable_memory::{stable, stable_memory_init};
+/// use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
+/// use ic_stable_structures::DefaultMemoryImpl;
+///
+/// let manager = MemoryManager::init(DefaultMemoryImpl::default());
+/// stable::set_context(StableStructuresMemContext::new(manager.get(MemoryId::new(0))));
+///
+/// // this crate now only uses the virtual memory #0
+/// stable_memory_init();
+/// # stable::reset_context();
+/// ```
+pub struct StableStructuresMemContext<M> {
+    memory: M,
+}
+
+impl<M: Memory> StableStructuresMemContext<M> {
+    pub fn new(memory: M) -> Self {
+        Self { memory }
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> M {
+        self.memory
+    }
+}
+
+impl<M: Memory> MemContext for StableStructuresMemContext<M> {
+    #[inline]
+    fn size_pages(&self) -> u64 {
+        self.memory.size()
+    }
+
+    #[inline]
+    fn grow(&mut self, new_pages: u64) -> Result<u64, OutOfMemory> {
+        let prev_pages = self.memory.grow(new_pages);
+
+        if prev_pages < 0 {
+            Err(OutOfMemory)
+        } else {
+            Ok(prev_pages as u64)
+        }
+    }
+
+    #[inline]
+    fn read(&self, offset: u64, buf: &mut [u8]) {
+        self.memory.read(offset, buf)
+    }
+
+    #[inline]
+    fn write(&mut self, offset: u64, buf: &[u8]) {
+        self.memory.write(offset, buf)
+    }
+}
+
+/// [ic_stable_structures::Memory], which lives inside a memory block, managed by this crate's allocator
+///
+/// Makes it possible for `ic-stable-structures` to share a canister with this crate. The memory
+/// block is registered as a named root (see [declare_root]), so the memory is found again after
+/// an upgrade by simply calling [AllocatorMemory::new] with the same name. Growing the memory
+/// reallocates the block, which may copy its content.
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::utils::mem_context::AllocatorMemory;
+/// # use ic_stable_memory::{stable, stable_memory_init};
+/// use ic_stable_structures::StableBTreeMap;
+///
+/// # stable::clear();
+/// stable_memory_init();
+///
+/// let mut map: StableBTreeMap<u64, u64, _> = StableBTreeMap::init(AllocatorMemory::new("map"));
+/// map.insert(1, 10);
+/// ```
+///
+/// # Panics
+/// All methods panic, if there is no initialized stable memory allocator. Just like the block
+/// itself, this memory can't grow bigger, than [u32::MAX] bytes.
+pub struct AllocatorMemory {
+    root: String,
+}
+
+impl AllocatorMemory {
+    /// Returns the memory, registered under this name, or an empty one, if there is no such memory
+    pub fn new(root: &str) -> Self {
+        Self {
+            root: String::from(root),
+        }
+    }
+
+    /// Deallocates the memory block and forgets its name
+    pub fn free(self) {
+        if let Some(slice) = self.slice() {
+            remove_root(&self.root);
+            deallocate(slice);
+        }
+    }
+
+    // the block is looked up each time, because the allocator may move it (see compact)
+    fn slice(&self) -> Option<SSlice> {
+        get_root(&self.root).map(|ptr| unsafe { SSlice::from_ptr(ptr).unwrap() })
+    }
+
+    fn check_bounds(slice: &Option<SSlice>, offset: u64, len: usize) {
+        let size = slice
+            .as_ref()
+            .map(|it| it.get_size_bytes())
+            .unwrap_or_default();
+
+        assert!(offset + len as u64 <= size, "Out of bounds memory access");
+    }
+}
+
+impl Memory for AllocatorMemory {
+    fn size(&self) -> u64 {
+        self.slice()
+            .map(|it| it.get_size_bytes() / PAGE_SIZE_BYTES)
+            .unwrap_or_default()
+    }
+
+    fn grow(&self, pages: u64) -> i64 {
+        let slice = self.slice();
+        let prev_pages = slice
+            .as_ref()
+            .map(|it| it.get_size_bytes() / PAGE_SIZE_BYTES)
+            .unwrap_or_default();
+
+        if pages == 0 {
+            return prev_pages as i64;
+        }
+
+        let new_size = (prev_pages + pages) * PAGE_SIZE_BYTES;
+        let res = unsafe {
+            match slice {
+                Some(slice) => reallocate(slice, new_size),
+                None => allocate(new_size),
+            }
+        };
+
+        let slice = match res {
+            Ok(it) => it,
+            Err(_) => return -1,
+        };
+
+        declare_root(&self.root, slice.as_ptr());
+
+        // new pages of a Memory should be zeroed
+        let zeroes = vec![0u8; PAGE_SIZE_BYTES as usize];
+        for page in prev_pages..(prev_pages + pages) {
+            unsafe { crate::mem::write_bytes(slice.offset(page * PAGE_SIZE_BYTES), &zeroes) };
+        }
+
+        prev_pages as i64
+    }
+
+    fn read(&self, offset: u64, dst: &mut [u8]) {
+        let slice = self.slice();
+        Self::check_bounds(&slice, offset, dst.len());
+
+        if let Some(slice) = slice {
+            unsafe { crate::mem::read_bytes(slice.offset(offset), dst) };
+        }
+    }
+
+    fn write(&self, offset: u64, src: &[u8]) {
+        let slice = self.slice();
+        Self::check_bounds(&slice, offset, src.len());
+
+        if let Some(slice) = slice {
+            unsafe { crate::mem::write_bytes(slice.offset(offset), src) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::SVec;
+    use crate::utils::mem_context::{AllocatorMemory, StableStructuresMemContext};
+    use crate::{get_allocated_size, stable, stable_memory_init};
+    use ic_stable_structures::{Memory, VectorMemory};
+
+    #[test]
+    fn context_works_fine() {
+        stable::clear();
+
+        let memory = VectorMemory::default();
+        stable::set_context(StableStructuresMemContext::new(memory.clone()));
+
+        stable_memory_init();
+
+        let mut vec = SVec::<u64>::new();
+        for i in 0..1000 {
+            vec.push(i).unwrap();
+        }
+
+        assert_eq!(stable::size_pages(), memory.size());
+        assert!(memory.size() > 0);
+
+        for i in 0..1000 {
+            assert_eq!(*vec.get(i as usize).unwrap(), i);
+        }
+
+        drop(vec);
+        stable::reset_context();
+    }
+
+    #[test]
+    fn allocator_memory_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        let memory = AllocatorMemory::new("memory");
+        assert_eq!(memory.size(), 0);
+        assert_eq!(memory.grow(0), 0);
+
+        assert_eq!(memory.grow(1), 0);
+        memory.write(10, &[1, 2, 3]);
+
+        // some other allocation, so the block can't grow in place
+        let vec = SVec::<u64>::new_with_capacity(10).unwrap();
+
+        assert_eq!(memory.grow(2), 1);
+        assert_eq!(memory.size(), 3);
+
+        let mut buf = [0u8; 5];
+        memory.read(9, &mut buf);
+        assert_eq!(buf, [0, 1, 2, 3, 0]);
+
+        // found again by name
+        let memory = AllocatorMemory::new("memory");
+        assert_eq!(memory.size(), 3);
+        memory.read(9, &mut buf);
+        assert_eq!(buf, [0, 1, 2, 3, 0]);
+
+        let res = std::panic::catch_unwind(|| {
+            AllocatorMemory::new("memory").read(3 * 65536 - 2, &mut [0u8; 3])
+        });
+        assert!(res.is_err());
+
+        memory.free();
+        assert_eq!(AllocatorMemory::new("memory").size(), 0);
+
+        drop(vec);
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
@@ -0,0 +1,269 @@
+use crate::utils::mem_context::{MemContext, OutOfMemory};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+/// A single access to stable memory, recorded by [TracingMemContext]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemAccess {
+    Grow { pages: u64 },
+    Read { offset: u64, len: u64 },
+    Write { offset: u64, data: Vec<u8> },
+}
+
+impl MemAccess {
+    /// Performs this access once again on the provided context
+    ///
+    /// Reads are performed too (and their result is discarded), so the replay hits the same code
+    /// paths of the context, as the original access did.
+    pub fn replay(&self, context: &mut dyn MemContext) {
+        match self {
+            MemAccess::Grow { pages } => {
+                let _ = context.grow(*pages);
+            }
+            MemAccess::Read { offset, len } => {
+                let mut buf = vec![0u8; *len as usize];
+                context.read(*offset, &mut buf);
+            }
+            MemAccess::Write { offset, data } => context.write(*offset, data),
+        }
+    }
+}
+
+/// A destination for the accesses, recorded by [TracingMemContext]
+///
+/// Implemented for any `FnMut(&MemAccess)`, for [TraceRing] and for `Rc<RefCell<S>>`, so the sink
+/// remains accessible, after the context is passed to [stable::set_context](crate::stable::set_context).
+pub trait TraceSink {
+    fn record(&mut self, access: &MemAccess);
+}
+
+impl<F: FnMut(&MemAccess)> TraceSink for F {
+    #[inline]
+    fn record(&mut self, access: &MemAccess) {
+        self(access)
+    }
+}
+
+impl<S: TraceSink> TraceSink for Rc<RefCell<S>> {
+    #[inline]
+    fn record(&mut self, access: &MemAccess) {
+        self.borrow_mut().record(access)
+    }
+}
+
+/// [TraceSink], which keeps the last `capacity` accesses in heap
+#[derive(Debug, Clone)]
+pub struct TraceRing {
+    capacity: usize,
+    accesses: VecDeque<MemAccess>,
+}
+
+impl TraceRing {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            accesses: VecDeque::new(),
+        }
+    }
+
+    /// Returns recorded accesses, from the oldest to the newest
+    #[inline]
+    pub fn iter(&self) -> impl Iterator<Item = &MemAccess> {
+        self.accesses.iter()
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.accesses.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.accesses.is_empty()
+    }
+
+    #[inline]
+    pub fn clear(&mut self) {
+        self.accesses.clear()
+    }
+
+    /// Replays all recorded accesses on the provided context (see [MemAccess::replay])
+    pub fn replay(&self, context: &mut dyn MemContext) {
+        for access in self.iter() {
+            access.replay(context);
+        }
+    }
+}
+
+impl TraceSink for TraceRing {
+    fn record(&mut self, access: &MemAccess) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if self.accesses.len() == self.capacity {
+            self.accesses.pop_front();
+        }
+
+        self.accesses.push_back(access.clone());
+    }
+}
+
+/// [MemContext], which wraps another one and records every access to it into a [TraceSink]
+///
+/// Allows capturing the exact stable memory access pattern of an operation (for example, of a
+/// failing one) and replaying it later with [MemAccess::replay].
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::utils::mem_context::{MemAccess, TestMemContext, TraceRing, TracingMemContext};
+/// # use ic_stable_memory::stable;
+/// # use std::cell::RefCell;
+/// # use std::rc::Rc;
+/// let ring = Rc::new(RefCell::new(TraceRing::new(1000)));
+/// stable::set_context(TracingMemContext::new(TestMemContext::new(), ring.clone()));
+///
+/// stable::grow(1).unwrap();
+/// stable::write(0, &[1, 2, 3]);
+///
+/// // the same memory, rebuilt from the trace
+/// let mut copy = TestMemContext::new();
+/// ring.borrow().replay(&mut copy);
+///
+/// // or simply print everything
+/// let print = |it: &MemAccess| println!("{:?}", it);
+/// stable::set_context(TracingMemContext::new(TestMemContext::new(), print));
+/// # stable::reset_context();
+/// ```
+pub struct TracingMemContext<C, S> {
+    inner: C,
+    sink: RefCell<S>,
+}
+
+impl<C: MemContext, S: TraceSink> TracingMemContext<C, S> {
+    pub fn new(inner: C, sink: S) -> Self {
+        Self {
+            inner,
+            sink: RefCell::new(sink),
+        }
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> (C, S) {
+        (self.inner, self.sink.into_inner())
+    }
+}
+
+impl<C: MemContext, S: TraceSink> MemContext for TracingMemContext<C, S> {
+    #[inline]
+    fn size_pages(&self) -> u64 {
+        self.inner.size_pages()
+    }
+
+    fn grow(&mut self, new_pages: u64) -> Result<u64, OutOfMemory> {
+        self.sink
+            .get_mut()
+            .record(&MemAccess::Grow { pages: new_pages });
+
+        self.inner.grow(new_pages)
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) {
+        self.sink.borrow_mut().record(&MemAccess::Read {
+            offset,
+            len: buf.len() as u64,
+        });
+
+        self.inner.read(offset, buf)
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) {
+        self.sink.get_mut().record(&MemAccess::Write {
+            offset,
+            data: buf.to_vec(),
+        });
+
+        self.inner.write(offset, buf)
+    }
+
+    #[inline]
+    fn flush(&mut self) {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::SVec;
+    use crate::utils::mem_context::{
+        MemAccess, MemContext, TestMemContext, TraceRing, TracingMemContext,
+    };
+    use crate::{stable, stable_memory_init, PAGE_SIZE_BYTES};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn works_fine() {
+        let mut context = TracingMemContext::new(TestMemContext::new(), TraceRing::new(3));
+
+        context.grow(1).unwrap();
+        context.write(10, &[1, 2, 3]);
+        context.read(9, &mut [0u8; 2]);
+        context.write(20, &[4]);
+
+        let (_, ring) = context.into_inner();
+        assert_eq!(ring.len(), 3);
+        assert_eq!(
+            ring.iter().cloned().collect::<Vec<_>>(),
+            vec![
+                MemAccess::Write {
+                    offset: 10,
+                    data: vec![1, 2, 3]
+                },
+                MemAccess::Read { offset: 9, len: 2 },
+                MemAccess::Write {
+                    offset: 20,
+                    data: vec![4]
+                },
+            ]
+        );
+
+        let mut log = Vec::new();
+        let mut context =
+            TracingMemContext::new(TestMemContext::new(), |it: &MemAccess| log.push(it.clone()));
+        context.grow(2).unwrap();
+        drop(context);
+
+        assert_eq!(log, vec![MemAccess::Grow { pages: 2 }]);
+    }
+
+    #[test]
+    fn replay_works_fine() {
+        stable::clear();
+
+        let ring = Rc::new(RefCell::new(TraceRing::new(usize::MAX)));
+        stable::set_context(TracingMemContext::new(TestMemContext::new(), ring.clone()));
+
+        stable_memory_init();
+
+        let mut vec = SVec::<u64>::new();
+        for i in 0..1000 {
+            vec.push(i).unwrap();
+        }
+
+        let mut copy = TestMemContext::new();
+        ring.borrow().replay(&mut copy);
+
+        assert_eq!(copy.size_pages(), stable::size_pages());
+
+        let mut expected = vec![0u8; 100];
+        let mut actual = vec![0u8; 100];
+        for offset in (0..(copy.size_pages() * PAGE_SIZE_BYTES - 100)).step_by(1000) {
+            stable::read(offset, &mut expected);
+            copy.read(offset, &mut actual);
+
+            assert_eq!(expected, actual);
+        }
+    }
+}
@@ -1,12 +1,30 @@
 //! Various utilities used by this crate
 
+#[cfg(feature = "backup")]
+pub mod backup;
+pub mod budget;
+#[cfg(feature = "candid_chunks")]
+pub mod candid_chunks;
 #[doc(hidden)]
 pub mod certification;
+#[cfg(not(target_family = "wasm"))]
+pub mod chaos;
+#[cfg(all(feature = "fuzzing", not(target_family = "wasm")))]
+pub mod fuzz;
+#[cfg(feature = "debug_structure")]
+pub mod heap_dump;
+#[cfg(not(target_family = "wasm"))]
+pub mod leak_check;
 #[doc(hidden)]
 pub mod math;
 pub mod mem_context;
+#[cfg(all(feature = "model_testing", not(target_family = "wasm")))]
+pub mod model_test;
+pub mod stable_var;
 #[cfg(test)]
 pub mod test;
+#[cfg(feature = "upgrade_test")]
+pub mod upgrade_test;
 
 #[cfg(target_family = "wasm")]
 use ic_cdk::print;
@@ -24,6 +42,27 @@ pub fn isoprint(str: &str) {
     println!("{}", str)
 }
 
+/// Returns the number of wasm instructions, executed by the canister in the current message
+///
+/// Returns [None] outside of a canister.
+#[cfg(target_family = "wasm")]
+#[inline]
+pub fn instruction_counter() -> Option<u64> {
+    Some(ic_cdk::api::performance_counter(0))
+}
+
+#[cfg(not(target_family = "wasm"))]
+#[inline]
+pub fn instruction_counter() -> Option<u64> {
+    None
+}
+
+/// Maximum number of elements, printed by [Debug] implementations of collections
+///
+/// The rest of the elements is replaced with a `...N more` mark, so printing a big collection doesn't
+/// read all of it from stable memory.
+pub const DEBUG_ELEMENTS_LIMIT: usize = 100;
+
 /// Unwraps a [Result], but does not require [Debug] to be implemented on `T`
 pub trait DebuglessUnwrap<T> {
     #[doc(hidden)]
@@ -0,0 +1,635 @@
+//! Model-based property testing harness
+//!
+//! Runs randomized sequences of operations against stable collections and the allocator and, in
+//! lockstep, against a simple model ([BTreeMap], [Vec] and a reference allocator, which only
+//! remembers live blocks and their contents). Results of each operation are compared with the model
+//! and invariants are checked after every step.
+//!
+//! Each run is fully determined by its seed. When a run fails, the panic message contains the seed,
+//! the step and the operation, so the failure can be reproduced by setting the `MODEL_TEST_SEED`
+//! environment variable:
+//! ```text
+//! MODEL_TEST_SEED=12345 cargo test --features model_testing model_test
+//! ```
+//!
+//! Only available with the `model_testing` feature and only for targets other than `wasm`.
+
+use crate::collections::{SBTreeMap, SVec};
+use crate::encoding::AsFixedSizeBytes;
+use crate::mem::s_slice::SSlice;
+use crate::mem::StablePtr;
+use crate::utils::math::ceil_div;
+use crate::{
+    _debug_validate_allocator, allocate, deallocate, get_allocated_size, reallocate, stable,
+    stable_memory_init,
+};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Name of the environment variable, which makes [seeds] return a single seed
+pub const SEED_ENV_VAR: &str = "MODEL_TEST_SEED";
+
+/// Small deterministic pseudo-random number generator (splitmix64)
+///
+/// Used instead of `rand`, so sequences of operations only depend on the seed and not on the
+/// version of an external crate. Can also be created [from bytes](ModelRng::from_bytes), in which
+/// case it returns the bytes themselves - this is how fuzzers drive the models (see
+/// [fuzz](crate::utils::fuzz)).
+#[derive(Debug, Clone)]
+pub struct ModelRng {
+    state: u64,
+    // input bytes and the number of consumed ones
+    input: Option<(Vec<u8>, usize)>,
+}
+
+impl ModelRng {
+    #[inline]
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: seed,
+            input: None,
+        }
+    }
+
+    /// Creates a generator, which consumes the provided bytes instead of generating them
+    ///
+    /// Once the bytes are exhausted, it returns zeroes and [ModelRng::is_exhausted] returns [true].
+    #[inline]
+    pub fn from_bytes(data: &[u8]) -> Self {
+        Self {
+            state: 0,
+            input: Some((data.to_vec(), 0)),
+        }
+    }
+
+    /// Returns [true], if the generator was created from bytes and all of them are consumed
+    #[inline]
+    pub fn is_exhausted(&self) -> bool {
+        matches!(&self.input, Some((data, pos)) if *pos >= data.len())
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        if self.input.is_some() {
+            return self.consume(u64::SIZE);
+        }
+
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+
+        z ^ (z >> 31)
+    }
+
+    /// Returns a number in `0..upper` range
+    ///
+    /// Generators, created from bytes, only consume as many bytes, as needed to represent `upper`.
+    ///
+    /// # Panics
+    /// Panics if `upper` is `0`.
+    #[inline]
+    pub fn below(&mut self, upper: u64) -> u64 {
+        assert!(upper > 0, "Empty range");
+
+        if self.input.is_some() {
+            let bits = (u64::BITS - (upper - 1).leading_zeros()).max(1) as u64;
+            let len = ceil_div(bits, u8::BITS as u64) as usize;
+
+            return self.consume(len) % upper;
+        }
+
+        self.next_u64() % upper
+    }
+
+    /// Returns [true] with the probability of `percent`%
+    #[inline]
+    pub fn chance(&mut self, percent: u64) -> bool {
+        self.below(100) < percent
+    }
+
+    fn consume(&mut self, len: usize) -> u64 {
+        let (data, pos) = self.input.as_mut().unwrap();
+        let mut buf = [0u8; u64::SIZE];
+
+        let from = (*pos).min(data.len());
+        let to = (*pos + len).min(data.len());
+        buf[..(to - from)].copy_from_slice(&data[from..to]);
+        *pos += len;
+
+        u64::from_le_bytes(buf)
+    }
+}
+
+/// Returns seeds to run model tests with
+///
+/// If `MODEL_TEST_SEED` environment variable is set, returns only this seed. Otherwise returns
+/// `count` seeds, derived from the current time, so different runs explore different sequences.
+///
+/// # Panics
+/// Panics if `MODEL_TEST_SEED` is not a valid [u64].
+pub fn seeds(count: usize) -> Vec<u64> {
+    if let Ok(seed) = std::env::var(SEED_ENV_VAR) {
+        let seed = seed
+            .trim()
+            .parse()
+            .unwrap_or_else(|_| panic!("Invalid {}: {}", SEED_ENV_VAR, seed));
+
+        return vec![seed];
+    }
+
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|it| it.as_nanos() as u64)
+        .unwrap_or_default();
+
+    let mut rng = ModelRng::new(nanos);
+
+    (0..count).map(|_| rng.next_u64()).collect()
+}
+
+/// A pair of a tested structure and its model, driven by random operations
+pub trait Model {
+    /// A single operation, applied to both: the tested structure and the model
+    type Op: Debug;
+
+    /// Generates the next operation
+    fn gen_op(&self, rng: &mut ModelRng) -> Self::Op;
+
+    /// Applies the operation to both sides, panicking if results differ
+    fn apply(&mut self, op: &Self::Op);
+
+    /// Checks invariants of the tested structure and compares its state with the model
+    fn check(&self);
+}
+
+/// Runs `steps` random operations against a fresh model for each seed
+///
+/// Stable memory is cleared and the allocator is reinitialized before each seed. After each seed the
+/// model is dropped and the allocator is checked for leaks.
+///
+/// # Panics
+/// Panics if the tested structure diverges from the model, or if some invariant is broken. The
+/// panic message contains the seed, the step and the operation, which caused the failure.
+pub fn run<M: Model, F: Fn() -> M>(name: &str, seeds: &[u64], steps: usize, new_model: F) {
+    for &seed in seeds {
+        let last_op = RefCell::new(None);
+
+        let res = catch_unwind(AssertUnwindSafe(|| {
+            stable::clear();
+            stable_memory_init();
+            let base_allocated_size = get_allocated_size();
+
+            {
+                let mut rng = ModelRng::new(seed);
+                let mut model = new_model();
+
+                for step in 0..steps {
+                    let op = model.gen_op(&mut rng);
+                    *last_op.borrow_mut() = Some((step, format!("{:?}", op)));
+
+                    model.apply(&op);
+                    model.check();
+                }
+
+                *last_op.borrow_mut() = None;
+            }
+
+            _debug_validate_allocator();
+            assert_eq!(
+                get_allocated_size(),
+                base_allocated_size,
+                "Memory leak after drop"
+            );
+        }));
+
+        if let Err(e) = res {
+            let msg = e
+                .downcast_ref::<String>()
+                .cloned()
+                .or_else(|| e.downcast_ref::<&str>().map(|it| it.to_string()))
+                .unwrap_or_default();
+
+            match last_op.take() {
+                Some((step, op)) => crate::utils::isoprint(&format!(
+                    "Model test '{}' failed (seed = {}, step = {}, op = {}): {}\nReproduce with {}={}",
+                    name, seed, step, op, msg, SEED_ENV_VAR, seed
+                )),
+                None => crate::utils::isoprint(&format!(
+                    "Model test '{}' failed (seed = {}): {}\nReproduce with {}={}",
+                    name, seed, msg, SEED_ENV_VAR, seed
+                )),
+            }
+
+            resume_unwind(e);
+        }
+    }
+}
+
+/// Operations of [BTreeMapModel]
+#[derive(Debug)]
+pub enum BTreeMapOp {
+    Insert(u64, u64),
+    Remove(u64),
+    Get(u64),
+    ContainsKey(u64),
+    Range(u64, u64),
+    Clear,
+}
+
+/// [SBTreeMap] against [BTreeMap]
+///
+/// Keys are taken from a small range, so the same keys get inserted and removed many times. The map
+/// is kept around a half of the key range full and is cleared from time to time, so node splits,
+/// merges and rotations all happen.
+pub struct BTreeMapModel {
+    pub map: SBTreeMap<u64, u64>,
+    pub model: BTreeMap<u64, u64>,
+    pub key_range: u64,
+}
+
+impl BTreeMapModel {
+    pub fn new(key_range: u64) -> Self {
+        Self {
+            map: SBTreeMap::new(),
+            model: BTreeMap::new(),
+            key_range,
+        }
+    }
+}
+
+impl Model for BTreeMapModel {
+    type Op = BTreeMapOp;
+
+    fn gen_op(&self, rng: &mut ModelRng) -> Self::Op {
+        let key = rng.below(self.key_range);
+        // grow while less than a half of the key range is used, shrink otherwise
+        let insert_chance = if self.model.len() as u64 * 2 < self.key_range {
+            70
+        } else {
+            30
+        };
+
+        match rng.below(100) {
+            0 => BTreeMapOp::Clear,
+            1..=10 => BTreeMapOp::Get(key),
+            11..=15 => BTreeMapOp::ContainsKey(key),
+            16..=20 => {
+                let to = key + rng.below(self.key_range / 4 + 1);
+
+                BTreeMapOp::Range(key, to)
+            }
+            _ if rng.chance(insert_chance) => BTreeMapOp::Insert(key, rng.next_u64()),
+            _ => BTreeMapOp::Remove(key),
+        }
+    }
+
+    fn apply(&mut self, op: &Self::Op) {
+        match *op {
+            BTreeMapOp::Insert(k, v) => {
+                let res = self.map.insert(k, v).expect("Out of memory");
+                assert_eq!(res, self.model.insert(k, v));
+            }
+            BTreeMapOp::Remove(k) => {
+                assert_eq!(self.map.remove(&k), self.model.remove(&k));
+            }
+            BTreeMapOp::Get(k) => {
+                assert_eq!(self.map.get(&k).map(|it| *it), self.model.get(&k).copied());
+            }
+            BTreeMapOp::ContainsKey(k) => {
+                assert_eq!(self.map.contains_key(&k), self.model.contains_key(&k));
+            }
+            BTreeMapOp::Range(from, to) => {
+                let actual = self
+                    .map
+                    .range(from..to)
+                    .map(|(k, v)| (*k, *v))
+                    .collect::<Vec<_>>();
+                let expected = self
+                    .model
+                    .range(from..to)
+                    .map(|(k, v)| (*k, *v))
+                    .collect::<Vec<_>>();
+
+                assert_eq!(actual, expected);
+            }
+            BTreeMapOp::Clear => {
+                self.map.clear();
+                self.model.clear();
+            }
+        }
+    }
+
+    fn check(&self) {
+        assert_eq!(self.map.len(), self.model.len() as u64);
+        self.map.validate().expect("Invalid B-tree");
+
+        let actual = self.map.iter().map(|(k, v)| (*k, *v));
+        assert!(actual.eq(self.model.iter().map(|(k, v)| (*k, *v))));
+    }
+}
+
+/// Operations of [VecModel]
+#[derive(Debug)]
+pub enum VecOp {
+    Push(u64),
+    Pop,
+    Insert(usize, u64),
+    Remove(usize),
+    Replace(usize, u64),
+    Swap(usize, usize),
+    Get(usize),
+    Clear,
+}
+
+/// [SVec] against [Vec]
+pub struct VecModel {
+    pub vec: SVec<u64>,
+    pub model: Vec<u64>,
+    pub max_len: usize,
+}
+
+impl VecModel {
+    pub fn new(max_len: usize) -> Self {
+        Self {
+            vec: SVec::new(),
+            model: Vec::new(),
+            max_len,
+        }
+    }
+}
+
+impl Model for VecModel {
+    type Op = VecOp;
+
+    fn gen_op(&self, rng: &mut ModelRng) -> Self::Op {
+        let len = self.model.len();
+        let growing = len < self.max_len / 2;
+
+        if len == 0 {
+            return VecOp::Push(rng.next_u64());
+        }
+
+        let idx = rng.below(len as u64) as usize;
+
+        match rng.below(100) {
+            0 => VecOp::Clear,
+            1..=10 => VecOp::Get(idx),
+            11..=15 => VecOp::Swap(idx, rng.below(len as u64) as usize),
+            16..=25 => VecOp::Replace(idx, rng.next_u64()),
+            26..=40 if growing => VecOp::Insert(rng.below(len as u64 + 1) as usize, rng.next_u64()),
+            26..=40 => VecOp::Remove(idx),
+            _ if rng.chance(if growing { 80 } else { 20 }) => VecOp::Push(rng.next_u64()),
+            _ => VecOp::Pop,
+        }
+    }
+
+    fn apply(&mut self, op: &Self::Op) {
+        match *op {
+            VecOp::Push(it) => {
+                self.vec.push(it).expect("Out of memory");
+                self.model.push(it);
+            }
+            VecOp::Pop => {
+                assert_eq!(self.vec.pop(), self.model.pop());
+            }
+            VecOp::Insert(idx, it) => {
+                self.vec.insert(idx, it).expect("Out of memory");
+                self.model.insert(idx, it);
+            }
+            VecOp::Remove(idx) => {
+                assert_eq!(self.vec.remove(idx), self.model.remove(idx));
+            }
+            VecOp::Replace(idx, it) => {
+                let prev = std::mem::replace(&mut self.model[idx], it);
+                assert_eq!(self.vec.replace(idx, it), prev);
+            }
+            VecOp::Swap(idx1, idx2) => {
+                self.vec.swap(idx1, idx2);
+                self.model.swap(idx1, idx2);
+            }
+            VecOp::Get(idx) => {
+                assert_eq!(
+                    self.vec.get(idx).map(|it| *it),
+                    self.model.get(idx).copied()
+                );
+            }
+            VecOp::Clear => {
+                self.vec.clear();
+                self.model.clear();
+            }
+        }
+    }
+
+    fn check(&self) {
+        assert_eq!(self.vec.len(), self.model.len());
+        assert!(self.vec.capacity() >= self.vec.len());
+
+        assert!(self.vec.iter().map(|it| *it).eq(self.model.iter().copied()));
+    }
+}
+
+/// Operations of [AllocatorModel]
+#[derive(Debug)]
+pub enum AllocatorOp {
+    Allocate(u64),
+    Deallocate(StablePtr),
+    Reallocate(StablePtr, u64),
+}
+
+/// The allocator against a reference allocator
+///
+/// The reference allocator remembers each live block and a byte, which fills it. It checks that
+/// blocks don't overlap and are big enough, that their contents survive other operations and
+/// reallocations, and that the allocator's accounting of allocated memory matches the live blocks.
+pub struct AllocatorModel {
+    // block pointer -> (block, filler)
+    pub blocks: BTreeMap<StablePtr, (SSlice, u8)>,
+    pub max_size: u64,
+    base_allocated_size: u64,
+    next_filler: u8,
+}
+
+impl AllocatorModel {
+    pub fn new(max_size: u64) -> Self {
+        Self {
+            blocks: BTreeMap::new(),
+            max_size,
+            base_allocated_size: get_allocated_size(),
+            next_filler: 0,
+        }
+    }
+
+    fn fill(&mut self, slice: SSlice) {
+        self.next_filler = self.next_filler.wrapping_add(1);
+
+        let buf = vec![self.next_filler; slice.get_size_bytes() as usize];
+        unsafe { slice.write_chunk(0, &buf) };
+
+        self.insert_checked(slice, self.next_filler);
+    }
+
+    fn insert_checked(&mut self, slice: SSlice, filler: u8) {
+        let start = slice.as_ptr();
+        let end = start + slice.get_total_size_bytes();
+
+        if let Some((_, (prev, _))) = self.blocks.range(..start).next_back() {
+            assert!(
+                prev.as_ptr() + prev.get_total_size_bytes() <= start,
+                "Block {:?} overlaps with {:?}",
+                slice,
+                prev
+            );
+        }
+
+        if let Some((&next, _)) = self.blocks.range(start..).next() {
+            assert!(end <= next, "Block {:?} overlaps with {}", slice, next);
+        }
+
+        self.blocks.insert(start, (slice, filler));
+    }
+
+    fn assert_filled(slice: &SSlice, filler: u8, len: u64) {
+        let mut buf = vec![0u8; len as usize];
+        unsafe { slice.read_chunk(0, &mut buf) };
+
+        assert!(
+            buf.iter().all(|it| *it == filler),
+            "Contents of {:?} are corrupted",
+            slice
+        );
+    }
+}
+
+impl Model for AllocatorModel {
+    type Op = AllocatorOp;
+
+    fn gen_op(&self, rng: &mut ModelRng) -> Self::Op {
+        let size = rng.below(self.max_size + 1);
+
+        if self.blocks.is_empty() || rng.chance(40) {
+            return AllocatorOp::Allocate(size);
+        }
+
+        let idx = rng.below(self.blocks.len() as u64) as usize;
+        let ptr = *self.blocks.keys().nth(idx).unwrap();
+
+        if rng.chance(60) {
+            AllocatorOp::Deallocate(ptr)
+        } else {
+            AllocatorOp::Reallocate(ptr, size)
+        }
+    }
+
+    fn apply(&mut self, op: &Self::Op) {
+        match *op {
+            AllocatorOp::Allocate(size) => {
+                let slice = unsafe { allocate(size) }.expect("Out of memory");
+                assert!(slice.get_size_bytes() >= size);
+
+                self.fill(slice);
+            }
+            AllocatorOp::Deallocate(ptr) => {
+                let (slice, filler) = self.blocks.remove(&ptr).unwrap();
+                Self::assert_filled(&slice, filler, slice.get_size_bytes());
+
+                deallocate(slice);
+            }
+            AllocatorOp::Reallocate(ptr, size) => {
+                let (slice, filler) = self.blocks.remove(&ptr).unwrap();
+                let new_slice = unsafe { reallocate(slice, size) }.expect("Out of memory");
+                assert!(new_slice.get_size_bytes() >= size);
+
+                let preserved = slice.get_size_bytes().min(new_slice.get_size_bytes());
+                Self::assert_filled(&new_slice, filler, preserved);
+
+                self.fill(new_slice);
+            }
+        }
+    }
+
+    fn check(&self) {
+        _debug_validate_allocator();
+
+        let live_size: u64 = self
+            .blocks
+            .values()
+            .map(|(slice, _)| slice.get_total_size_bytes())
+            .sum();
+
+        assert_eq!(get_allocated_size(), self.base_allocated_size + live_size);
+    }
+}
+
+impl Drop for AllocatorModel {
+    fn drop(&mut self) {
+        for (_, (slice, _)) in std::mem::take(&mut self.blocks) {
+            deallocate(slice);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::utils::model_test::{run, seeds, AllocatorModel, BTreeMapModel, ModelRng, VecModel};
+
+    #[test]
+    fn rng_is_deterministic() {
+        let mut a = ModelRng::new(42);
+        let mut b = ModelRng::new(42);
+
+        for _ in 0..100 {
+            assert_eq!(a.next_u64(), b.next_u64());
+        }
+
+        assert!((0..1000).all(|_| a.below(10) < 10));
+
+        let mut c = ModelRng::from_bytes(&[1, 2, 3, 4]);
+        assert!(!c.is_exhausted());
+        assert_eq!(c.below(200), 1);
+        assert_eq!(c.below(1000), 3 * 256 + 2);
+        assert_eq!(c.next_u64(), 4);
+        assert!(c.is_exhausted());
+        assert_eq!(c.below(10), 0);
+    }
+
+    #[test]
+    fn btree_map_matches_model() {
+        run("SBTreeMap", &seeds(5), 5_000, || BTreeMapModel::new(300));
+    }
+
+    #[test]
+    fn vec_matches_model() {
+        run("SVec", &seeds(5), 5_000, || VecModel::new(500));
+    }
+
+    #[test]
+    fn allocator_matches_model() {
+        run("allocator", &seeds(5), 5_000, || AllocatorModel::new(2048));
+    }
+
+    #[test]
+    fn failure_is_propagated() {
+        struct Broken;
+
+        impl crate::utils::model_test::Model for Broken {
+            type Op = u64;
+
+            fn gen_op(&self, rng: &mut ModelRng) -> Self::Op {
+                rng.below(10)
+            }
+
+            fn apply(&mut self, op: &Self::Op) {
+                assert_ne!(*op, 7);
+            }
+
+            fn check(&self) {}
+        }
+
+        let res = std::panic::catch_unwind(|| run("broken", &[1, 2, 3], 1_000, || Broken));
+        assert!(res.is_err());
+    }
+}
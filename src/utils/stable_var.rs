@@ -0,0 +1,178 @@
+//! Named stable variables, see [stable_var](crate::stable_var).
+
+use crate::encoding::AsDynSizeBytes;
+use crate::primitive::s_box::SBox;
+use crate::primitive::StableType;
+use crate::{declare_root, get_root, remove_root, OutOfMemory};
+
+/// Declares a named stable variable
+///
+/// The value of the variable is stored in an [SBox], which pointer is assigned to the name of the
+/// variable with [declare_root](crate::declare_root). So the variable survives canister upgrades
+/// without any [store_custom_data](crate::store_custom_data) calls in `#[pre_upgrade]` and
+/// [retrieve_custom_data](crate::retrieve_custom_data) calls in `#[post_upgrade]` - it is
+/// re-discovered by its name, when it is accessed for the first time after an upgrade.
+///
+/// The variable's type should implement [StableType], [AsDynSizeBytes] and [Default]. The value is
+/// lazily initialized with [Default::default] on the first mutable access.
+///
+/// The macro generates a unit struct with the following associated functions:
+/// * `with(|it: &T| ...) -> R` - provides immutable access to the value;
+/// * `with_mut(|it: &mut T| ...) -> Result<R, OutOfMemory>` - provides mutable access to the value,
+/// persisting it after the function returns;
+/// * `take() -> Option<T>` - removes the variable, returning its value;
+/// * `NAME` - the name of the root, the variable is stored under.
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::collections::SBTreeMap;
+/// # use ic_stable_memory::{stable_memory_init, stable_memory_post_upgrade, stable_memory_pre_upgrade, stable_var};
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// stable_var!(BALANCES: SBTreeMap<u64, u64>);
+///
+/// BALANCES::with_mut(|it| it.insert(1, 100))
+///     .expect("Out of memory")
+///     .expect("Out of memory");
+///
+/// stable_memory_pre_upgrade().expect("Out of memory");
+/// stable_memory_post_upgrade();
+///
+/// assert_eq!(BALANCES::with(|it| *it.get(&1).unwrap()), 100);
+/// ```
+#[macro_export]
+macro_rules! stable_var {
+    ($(#[$meta:meta])* $vis:vis $name:ident : $ty:ty $(;)?) => {
+        $(#[$meta])*
+        #[allow(non_camel_case_types)]
+        $vis struct $name;
+
+        #[allow(dead_code)]
+        impl $name {
+            /// Name of the root, this variable is stored under
+            pub const NAME: &'static str = stringify!($name);
+
+            /// Provides immutable access to the value
+            #[inline]
+            pub fn with<R, F: FnOnce(&$ty) -> R>(func: F) -> R {
+                $crate::utils::stable_var::with_stable_var::<$ty, R, F>(Self::NAME, func)
+            }
+
+            /// Provides mutable access to the value, persisting it after the function returns
+            #[inline]
+            pub fn with_mut<R, F: FnOnce(&mut $ty) -> R>(
+                func: F,
+            ) -> Result<R, $crate::OutOfMemory> {
+                $crate::utils::stable_var::with_stable_var_mut::<$ty, R, F>(Self::NAME, func)
+            }
+
+            /// Removes this variable, returning its value
+            #[inline]
+            pub fn take() -> Option<$ty> {
+                $crate::utils::stable_var::take_stable_var::<$ty>(Self::NAME)
+            }
+        }
+    };
+}
+
+#[doc(hidden)]
+pub fn with_stable_var<T, R, F>(name: &str, func: F) -> R
+where
+    T: StableType + AsDynSizeBytes + Default,
+    F: FnOnce(&T) -> R,
+{
+    match get_root(name) {
+        Some(ptr) => {
+            // stable drop flag of the box is off, so it won't release the memory
+            let b = unsafe { SBox::<T>::from_ptr(ptr) };
+
+            func(&*b)
+        }
+        None => func(&T::default()),
+    }
+}
+
+#[doc(hidden)]
+pub fn with_stable_var_mut<T, R, F>(name: &str, func: F) -> Result<R, OutOfMemory>
+where
+    T: StableType + AsDynSizeBytes + Default,
+    F: FnOnce(&mut T) -> R,
+{
+    let mut b = match get_root(name) {
+        Some(ptr) => unsafe { SBox::<T>::from_ptr(ptr) },
+        None => {
+            let mut b = SBox::new(T::default()).map_err(|_| OutOfMemory)?;
+            unsafe { b.stable_drop_flag_off() };
+
+            b
+        }
+    };
+
+    let res = b.with(func);
+
+    // the box could have been reallocated
+    declare_root(name, b.as_ptr());
+
+    res
+}
+
+#[doc(hidden)]
+pub fn take_stable_var<T: StableType + AsDynSizeBytes>(name: &str) -> Option<T> {
+    let mut b = unsafe { SBox::<T>::from_ptr(remove_root(name)?) };
+    unsafe { b.stable_drop_flag_on() };
+
+    Some(b.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::{SBTreeMap, SVec};
+    use crate::{
+        _debug_validate_allocator, get_allocated_size, get_root, stable, stable_memory_init,
+        stable_memory_post_upgrade, stable_memory_pre_upgrade, SBox,
+    };
+
+    stable_var!(
+        /// Some numbers
+        NUMBERS: SVec<u64>
+    );
+    stable_var!(pub(crate) NAMES: SBTreeMap<u64, SBox<String>>);
+
+    #[test]
+    fn works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        assert_eq!(NUMBERS::with(|it| it.len()), 0);
+        assert!(get_root(NUMBERS::NAME).is_none());
+
+        for i in 0..100u64 {
+            NUMBERS::with_mut(|it| it.push(i)).unwrap().unwrap();
+            NAMES::with_mut(|it| it.insert(i, SBox::new(format!("name {}", i)).unwrap()))
+                .unwrap()
+                .unwrap();
+        }
+
+        assert!(get_root("NUMBERS").is_some());
+
+        stable_memory_pre_upgrade().unwrap();
+        stable_memory_post_upgrade();
+
+        assert_eq!(NUMBERS::with(|it| it.len()), 100);
+        assert_eq!(NUMBERS::with(|it| *it.get(50).unwrap()), 50);
+        assert_eq!(
+            NAMES::with(|it| it.get(&10).unwrap().as_str().to_string()),
+            "name 10"
+        );
+
+        let numbers = NUMBERS::take().unwrap();
+        assert_eq!(numbers.len(), 100);
+        assert!(NUMBERS::take().is_none());
+        drop(numbers);
+
+        drop(NAMES::take());
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
@@ -0,0 +1,228 @@
+//! Canister-side part of the upgrade round-trip test kit
+//!
+//! The whole point of this crate is that stable collections survive canister upgrades. This module
+//! lets a test canister prove it on a real replica (e.g. in PocketIC): [fill] applies a
+//! deterministic pseudo-random sequence of operations to a set of stable collections, stored in
+//! [stable variables](crate::stable_var), and [fingerprint] summarizes their contents. A
+//! fingerprint, taken before an upgrade, should be exactly the same after it, and [verify] should
+//! still find no corruption.
+//!
+//! The test canister is located at `examples/upgrade_test`, the PocketIC harness, which installs
+//! it and drives it through upgrades, at `ic-stable-memory-upgrade-tests`. The canister's
+//! `#[init]`, `#[pre_upgrade]` and `#[post_upgrade]` methods are simply [restore](crate::restore),
+//! [persist](crate::persist) and [restore](crate::restore) again.
+//!
+//! Only available with the `upgrade_test` feature.
+
+use crate::collections::{SBTreeMap, SBTreeSet, SHashMap, SHashSet, SLog, SVec};
+use crate::{check_consistency, get_allocated_size, stable_var};
+use candid::{CandidType, Deserialize};
+use sha2::{Digest, Sha256};
+
+stable_var!(UPGRADE_TEST_VEC: SVec<u64>);
+stable_var!(UPGRADE_TEST_LOG: SLog<u64>);
+stable_var!(UPGRADE_TEST_BTREE_MAP: SBTreeMap<u64, u64>);
+stable_var!(UPGRADE_TEST_BTREE_SET: SBTreeSet<u64>);
+stable_var!(UPGRADE_TEST_HASH_MAP: SHashMap<u64, u64>);
+stable_var!(UPGRADE_TEST_HASH_SET: SHashSet<u64>);
+
+/// Keys are taken from this range, so removals hit existing entries often enough
+const KEY_RANGE: u64 = 1000;
+
+/// Summary of the contents of all test collections, see [fingerprint]
+#[derive(Debug, Clone, CandidType, Deserialize, PartialEq, Eq)]
+pub struct StateFingerprint {
+    pub vec_len: u64,
+    pub log_len: u64,
+    pub btree_map_len: u64,
+    pub btree_set_len: u64,
+    pub hash_map_len: u64,
+    pub hash_set_len: u64,
+    /// SHA-256 of all elements, in the order of the fields above (hash-based collections are sorted)
+    pub contents_hash: Vec<u8>,
+    /// See [get_allocated_size]
+    pub allocated_size: u64,
+}
+
+/// Applies `count` pseudo-random operations to the test collections
+///
+/// Operations are pushes and pops, insertions and removals. The same seed, applied to the same
+/// state, always produces the same result.
+///
+/// # Panics
+/// Panics if stable memory is over.
+pub fn fill(seed: u64, count: u64) {
+    let mut state = seed;
+
+    for _ in 0..count {
+        let op = splitmix64(&mut state) % 10;
+        let key = splitmix64(&mut state) % KEY_RANGE;
+        let value = splitmix64(&mut state);
+
+        match op {
+            0 | 1 => UPGRADE_TEST_VEC::with_mut(|it| it.push(value).expect("Out of memory")),
+            2 => UPGRADE_TEST_VEC::with_mut(|it| {
+                it.pop();
+            }),
+            3 => UPGRADE_TEST_LOG::with_mut(|it| it.push(value).expect("Out of memory")),
+            4 => UPGRADE_TEST_BTREE_MAP::with_mut(|it| {
+                it.insert(key, value).expect("Out of memory");
+            }),
+            5 => UPGRADE_TEST_BTREE_MAP::with_mut(|it| {
+                it.remove(&key);
+            }),
+            6 => UPGRADE_TEST_BTREE_SET::with_mut(|it| {
+                if !it.remove(&key) {
+                    it.insert(key).expect("Out of memory");
+                }
+            }),
+            7 => UPGRADE_TEST_HASH_MAP::with_mut(|it| {
+                it.insert(key, value).expect("Out of memory");
+            }),
+            8 => UPGRADE_TEST_HASH_MAP::with_mut(|it| {
+                it.remove(&key);
+            }),
+            _ => UPGRADE_TEST_HASH_SET::with_mut(|it| {
+                if !it.remove(&key) {
+                    it.insert(key).expect("Out of memory");
+                }
+            }),
+        }
+        .expect("Out of memory");
+    }
+}
+
+/// Summarizes the contents of the test collections
+pub fn fingerprint() -> StateFingerprint {
+    let mut hasher = Sha256::new();
+
+    let vec_len = UPGRADE_TEST_VEC::with(|it| {
+        for elem in it.iter() {
+            hasher.update(elem.to_le_bytes());
+        }
+
+        it.len() as u64
+    });
+
+    let log_len = UPGRADE_TEST_LOG::with(|it| {
+        for elem in it.rev_iter() {
+            hasher.update(elem.to_le_bytes());
+        }
+
+        it.len()
+    });
+
+    let btree_map_len = UPGRADE_TEST_BTREE_MAP::with(|it| {
+        for (k, v) in it.iter() {
+            hasher.update(k.to_le_bytes());
+            hasher.update(v.to_le_bytes());
+        }
+
+        it.len()
+    });
+
+    let btree_set_len = UPGRADE_TEST_BTREE_SET::with(|it| {
+        for elem in it.iter() {
+            hasher.update(elem.to_le_bytes());
+        }
+
+        it.len()
+    });
+
+    let hash_map_len = UPGRADE_TEST_HASH_MAP::with(|it| {
+        let mut entries = it.iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>();
+        entries.sort_unstable();
+
+        for (k, v) in entries {
+            hasher.update(k.to_le_bytes());
+            hasher.update(v.to_le_bytes());
+        }
+
+        it.len() as u64
+    });
+
+    let hash_set_len = UPGRADE_TEST_HASH_SET::with(|it| {
+        let mut elems = it.iter().map(|it| *it).collect::<Vec<_>>();
+        elems.sort_unstable();
+
+        for elem in elems {
+            hasher.update(elem.to_le_bytes());
+        }
+
+        it.len() as u64
+    });
+
+    StateFingerprint {
+        vec_len,
+        log_len,
+        btree_map_len,
+        btree_set_len,
+        hash_map_len,
+        hash_set_len,
+        contents_hash: hasher.finalize().to_vec(),
+        allocated_size: get_allocated_size(),
+    }
+}
+
+/// Checks the test collections and the allocator for corruption
+///
+/// # Errors
+/// Returns a description of the first problem found.
+pub fn verify() -> Result<(), String> {
+    UPGRADE_TEST_BTREE_MAP::with(|it| it.validate())
+        .map_err(|e| format!("SBTreeMap is corrupted: {:?}", e))?;
+
+    check_consistency().map_err(|e| format!("Allocator is corrupted: {:?}", e))
+}
+
+/// Drops all test collections, releasing their stable memory
+pub fn reset() {
+    UPGRADE_TEST_VEC::take();
+    UPGRADE_TEST_LOG::take();
+    UPGRADE_TEST_BTREE_MAP::take();
+    UPGRADE_TEST_BTREE_SET::take();
+    UPGRADE_TEST_HASH_MAP::take();
+    UPGRADE_TEST_HASH_SET::take();
+}
+
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::utils::upgrade_test::{fill, fingerprint, reset, verify};
+    use crate::{_debug_validate_allocator, get_allocated_size, persist, restore, stable};
+
+    #[test]
+    fn survives_upgrades() {
+        stable::clear();
+        restore();
+
+        let base_allocated_size = get_allocated_size();
+
+        for i in 0..10 {
+            fill(i, 500);
+
+            let before = fingerprint();
+            assert!(before.vec_len > 0);
+
+            persist();
+            restore();
+
+            assert_eq!(fingerprint(), before);
+            verify().unwrap();
+        }
+
+        reset();
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), base_allocated_size);
+    }
+}